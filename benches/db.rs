@@ -0,0 +1,78 @@
+//! Insert & query throughput across value types, tolerances and backends
+//!
+//! Benchmark IDs are `<operation>/<type>/<backend>/<tolerance>`, so one
+//! type's backends can be compared with ie
+//! `cargo bench --bench db -- 'query/u64/.*/4'` (see the README).
+
+#[macro_use]
+extern crate criterion;
+extern crate hammer;
+
+use criterion::{black_box, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use hammer::db::Factory;
+use hammer::db::bench::{self, Backend};
+use hammer::db::testing::Generate;
+
+/// Values in each DB queried
+const VALUES: usize = 10000;
+/// Values inserted, or queries made, per iteration
+const BATCH: usize = 100;
+
+fn insert_and_query<T: Factory + Generate + Clone>(c: &mut Criterion, name: &str, dimensions: usize, tolerances: &[usize]) {
+    let values: Vec<T> = bench::values(VALUES, dimensions, 1);
+    let inserted: Vec<T> = bench::values(BATCH, dimensions, 2);
+
+    let mut group = c.benchmark_group(format!("insert/{}", name));
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for &tolerance in tolerances.iter() {
+        for backend in Backend::all() {
+            group.bench_with_input(BenchmarkId::new(backend.name(), tolerance), &tolerance, |b, &tolerance| {
+                // Each batch goes into a fresh DB, which is dropped untimed
+                b.iter_batched(
+                    || bench::empty::<T>(dimensions, tolerance, backend),
+                    |mut db| {
+                        for value in inserted.iter() {
+                            db.insert(value.clone()).unwrap();
+                        }
+                        db
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group(format!("query/{}", name));
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for &tolerance in tolerances.iter() {
+        let queries = bench::queries(&values, BATCH, dimensions, tolerance, 3);
+        for backend in Backend::all() {
+            let db = bench::populated(dimensions, tolerance, backend, &values);
+            group.bench_with_input(BenchmarkId::new(backend.name(), tolerance), &tolerance, |b, _| {
+                b.iter(|| {
+                    for query in queries.iter() {
+                        black_box(db.get(query).unwrap());
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn u64_values(c: &mut Criterion) {
+    insert_and_query::<u64>(c, "u64", 64, &[2, 4, 8]);
+}
+
+fn u64x4_values(c: &mut Criterion) {
+    insert_and_query::<[u64; 4]>(c, "u64x4", 256, &[8, 16]);
+}
+
+fn vec_u64_values(c: &mut Criterion) {
+    insert_and_query::<Vec<u64>>(c, "vec_u64", 32, &[2, 4]);
+}
+
+criterion_group!(benches, u64_values, u64x4_values, vec_u64_values);
+criterion_main!(benches);
@@ -0,0 +1,128 @@
+//! Hot loops underneath the DBs: counting differing bits, transposing
+//! vectors into bit planes and concurrent writes to sharded map sets (and
+//! the in-memory DBs built over them)
+
+#[macro_use]
+extern crate criterion;
+extern crate hammer;
+
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use criterion::{black_box, BenchmarkId, Criterion};
+
+use hammer::bit_matrix::BitMatrix;
+use hammer::db::hamming::Hamming;
+use hammer::db::{Database, Factory, StorageBackend};
+use hammer::db::map_set::{MapSet, ShardedInMemoryHash};
+use hammer::db::popcount;
+use hammer::db::testing::Rng;
+
+// Verification cost for 256-bit keys, with whichever popcount path the CPU
+// supports (build with `--features simd` to compare against the scalar one)
+fn hamming_u64x4(c: &mut Criterion) {
+    let mut rng = Rng::new(1);
+    let x: [u64; 4] = [rng.next_u64(), rng.next_u64(), rng.next_u64(), rng.next_u64()];
+    let y: [u64; 4] = [rng.next_u64(), rng.next_u64(), rng.next_u64(), rng.next_u64()];
+
+    c.bench_function("hamming/u64x4", |b| b.iter(|| black_box(&x).hamming(black_box(&y))));
+    c.bench_function("hamming/u64x4_scalar", |b| b.iter(|| popcount::hamming_scalar(black_box(&x), black_box(&y))));
+
+    let zeros = [0u64; 4];
+    let ones = [!0u64; 4];
+    c.bench_function("hamming/u64x4_lte", |b| b.iter(|| black_box(&zeros).hamming_lte(black_box(&ones), 8)));
+}
+
+fn hamming_slice(c: &mut Criterion) {
+    let mut rng = Rng::new(2);
+    let x: Vec<u64> = (0..1024).map(|_| rng.next_u64()).collect();
+    let y: Vec<u64> = (0..1024).map(|_| rng.next_u64()).collect();
+
+    c.bench_function("hamming/1024_words", |b| b.iter(|| popcount::hamming(black_box(&x), black_box(&y))));
+    c.bench_function("hamming/1024_words_scalar", |b| b.iter(|| popcount::hamming_scalar(black_box(&x), black_box(&y))));
+}
+
+// Transposing a vector of 512 64-bit elements into 64 bit planes
+fn bit_matrix_transpose(c: &mut Criterion) {
+    let mut rng = Rng::new(3);
+    let mut m = BitMatrix::new(512, 64);
+    for r in 0..512 {
+        for col in 0..64 {
+            m.set(r, col, rng.below(2) == 1);
+        }
+    }
+
+    c.bench_function("bit_matrix/transpose_512x64", |b| b.iter(|| black_box(&m).transpose()));
+}
+
+// Concurrent writers into a single sharded map set.  Each thread inserts the
+// same number of distinct keys, so with enough shards the time per iteration
+// should stay roughly flat as threads are added (until we run out of cores)
+fn concurrent_sharded_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sharded_insert");
+    for &(threads, shards) in [(1usize, 64usize), (8, 64), (16, 64), (16, 1)].iter() {
+        let id = BenchmarkId::new(format!("{}_shards", shards), format!("{}_threads", threads));
+        group.bench_function(id, |b| {
+            let mut rng = Rng::new(4);
+            b.iter(|| {
+                let db: Arc<ShardedInMemoryHash<u64, u64>> = Arc::new(ShardedInMemoryHash::with_shards(shards));
+                let seed = rng.next_u64();
+
+                let handles: Vec<_> = (0..threads).map(|t| {
+                    let db = db.clone();
+                    thread::spawn(move || {
+                        for i in 0..10000u64 {
+                            db.insert_shared(seed ^ (i * threads as u64 + t as u64), t as u64).unwrap();
+                        }
+                    })
+                }).collect();
+
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// Concurrent adds to an in-memory DB, as the HTTP handlers make them: under a
+// read lock through `insert_shared`, or a write lock through `insert`.  Only
+// the former should scale with threads
+fn concurrent_db_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("db_insert");
+    for &threads in [1usize, 8, 16].iter() {
+        for &shared in [true, false].iter() {
+            let id = BenchmarkId::new(if shared { "read_locked" } else { "write_locked" }, format!("{}_threads", threads));
+            group.bench_function(id, |b| {
+                let mut rng = Rng::new(5);
+                b.iter(|| {
+                    let db: Arc<RwLock<Box<Database<u64>>>> = Arc::new(RwLock::new(u64::build(64, 4, StorageBackend::InMemory).unwrap()));
+                    let seed = rng.next_u64();
+
+                    let handles: Vec<_> = (0..threads).map(|t| {
+                        let db = db.clone();
+                        thread::spawn(move || {
+                            for i in 0..1000u64 {
+                                let value = seed ^ (i * threads as u64 + t as u64);
+                                if shared {
+                                    db.read().unwrap().insert_shared(value).unwrap();
+                                } else {
+                                    db.write().unwrap().insert(value).unwrap();
+                                }
+                            }
+                        })
+                    }).collect();
+
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hamming_u64x4, hamming_slice, bit_matrix_transpose, concurrent_sharded_insert, concurrent_db_insert);
+criterion_main!(benches);
@@ -0,0 +1,124 @@
+/*
+ * Every function taking a DB must be passed null or a DB returned by the
+ * matching create function which hasn't been freed; anything else is
+ * undefined behaviour, as hammer can't tell a dangling pointer from a live
+ * one.  Null DBs fail with "null DB".  A DB may be used from any thread, but
+ * not from two at once.  `out` buffers must hold `capacity` values, and
+ * paths must be null or NUL-terminated.
+ */
+
+/* Generated by cbindgen from ffi/src/lib.rs - don't edit */
+
+#ifndef HAMMER_H
+#define HAMMER_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A DB of 256-bit values
+//
+typedef struct HammerDb256 HammerDb256;
+
+// A DB of 64-bit values
+//
+typedef struct HammerDb64 HammerDb64;
+
+// A 256-bit value, most significant word first
+//
+typedef struct HammerKey256 {
+  uint64_t words[4];
+} HammerKey256;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The message of the last error on this thread, or null if there hasn't
+// been one
+//
+// The message is owned by hammer, and only valid until the next call on
+// this thread.
+//
+const char *hammer_last_error(void);
+
+// Creates a DB finding values within `tolerance` bits, stored at `path` or
+// in memory if it's null
+//
+// Returns null on failure, ie if `path` holds a DB created with a different
+// tolerance.  The DB must be freed with `hammer_db64_free`.
+//
+HammerDb64 *hammer_db64_create(size_t tolerance, const char *path);
+
+// Adds `value`, returning 1 if it was added, 0 if it was already present or
+// -1 on failure
+//
+// `db` must be null or a DB from `hammer_db64_create` which hasn't been
+// freed, and mustn't be in use on another thread.
+//
+int32_t hammer_db64_insert(HammerDb64 *db, uint64_t value);
+
+// Finds the values within tolerance of `key`, copying up to `capacity` of
+// them (smallest first) into `out`
+//
+// Returns how many values were found, which may exceed `capacity`, or -1 on
+// failure.  `db` must be null or a DB from `hammer_db64_create` which hasn't
+// been freed, and `out` must hold `capacity` values.
+//
+int64_t hammer_db64_query(const HammerDb64 *db, uint64_t key, uint64_t *out, size_t capacity);
+
+// Removes `value`, returning 1 if it was removed, 0 if it wasn't present or
+// -1 on failure
+//
+// `db` must be null or a DB from `hammer_db64_create` which hasn't been
+// freed, and mustn't be in use on another thread.
+//
+int32_t hammer_db64_remove(HammerDb64 *db, uint64_t value);
+
+// Closes a DB created with `hammer_db64_create`; null is ignored
+//
+void hammer_db64_free(HammerDb64 *db);
+
+// Creates a DB finding values within `tolerance` bits, stored at `path` or
+// in memory if it's null
+//
+// Returns null on failure, ie if `path` holds a DB created with a different
+// tolerance.  The DB must be freed with `hammer_db256_free`.
+//
+HammerDb256 *hammer_db256_create(size_t tolerance, const char *path);
+
+// Adds `value`, returning 1 if it was added, 0 if it was already present or
+// -1 on failure
+//
+// `db` must be null or a DB from `hammer_db256_create` which hasn't been
+// freed, and mustn't be in use on another thread.
+//
+int32_t hammer_db256_insert(HammerDb256 *db, HammerKey256 value);
+
+// Finds the values within tolerance of `key`, copying up to `capacity` of
+// them (smallest first) into `out`
+//
+// Returns how many values were found, which may exceed `capacity`, or -1 on
+// failure.  `db` must be null or a DB from `hammer_db256_create` which hasn't
+// been freed, and `out` must hold `capacity` values.
+//
+int64_t hammer_db256_query(const HammerDb256 *db, HammerKey256 key, HammerKey256 *out, size_t capacity);
+
+// Removes `value`, returning 1 if it was removed, 0 if it wasn't present or
+// -1 on failure
+//
+// `db` must be null or a DB from `hammer_db256_create` which hasn't been
+// freed, and mustn't be in use on another thread.
+//
+int32_t hammer_db256_remove(HammerDb256 *db, HammerKey256 value);
+
+// Closes a DB created with `hammer_db256_create`; null is ignored
+//
+void hammer_db256_free(HammerDb256 *db);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* HAMMER_H */
@@ -0,0 +1,316 @@
+//! C API
+//!
+//! Embeds hammer's substitution DBs in C & C++ programs without the HTTP
+//! server, over 64-bit values (`HammerDb64`) and 256-bit values
+//! (`HammerDb256`, each value four 64-bit words).  `build.rs` generates
+//! `include/hammer.h` from the functions below.
+//!
+//! ```c
+//! HammerDb64 *db = hammer_db64_create(4, NULL);
+//! hammer_db64_insert(db, 0xF);
+//!
+//! uint64_t found[16];
+//! int64_t count = hammer_db64_query(db, 0x7, found, 16);
+//! hammer_db64_free(db);
+//! ```
+//!
+//! DBs are kept in memory when created with a null path, otherwise they're
+//! stored in RocksDB at the path.  Functions which fail return null or -1 and
+//! set a message read with `hammer_last_error`.  A DB may be used from any
+//! thread, but not from two at once.
+//!
+//! # Safety
+//!
+//! Every function taking a DB must be passed null or a DB returned by the
+//! matching create function which hasn't been freed; anything else is
+//! undefined behaviour, as hammer can't tell a dangling pointer from a live
+//! one.  Null DBs fail with "null DB".  `out` buffers must hold `capacity`
+//! values, and paths must be null or NUL-terminated.
+
+extern crate hammer;
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+
+use hammer::db::{Database, Factory, RocksDBConfig, StorageBackend};
+use hammer::db::builder::DbBuilder;
+use hammer::db::hashing::State;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, recording its error (or panic) as the last error
+///
+/// Panics can't unwind into C, so they're reported like any other failure.
+///
+fn guard<T, F: FnOnce() -> Result<T, String>>(f: F) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            set_last_error(e);
+            None
+        },
+        Err(_) => {
+            set_last_error("hammer panicked".to_string());
+            None
+        },
+    }
+}
+
+/// The message of the last error on this thread, or null if there hasn't
+/// been one
+///
+/// The message is owned by hammer, and only valid until the next call on
+/// this thread.
+///
+#[no_mangle]
+pub extern "C" fn hammer_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+unsafe fn build<T: Factory>(bits: usize, tolerance: usize, path: *const c_char) -> Result<Box<Database<T>>, String> {
+    let backend = if path.is_null() {
+        StorageBackend::InMemory
+    } else {
+        let path = try!(CStr::from_ptr(path).to_str().map_err(|e| format!("path isn't UTF-8: {}", e)));
+        StorageBackend::RocksDB(PathBuf::from(path), RocksDBConfig::default())
+    };
+
+    DbBuilder::<T>::new()
+        .dimensions(bits)
+        .tolerance(tolerance)
+        .backend(backend)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Copies the values found, smallest first, into `out` (up to `capacity` of
+/// them), returning how many were found
+///
+unsafe fn copy_found<T: Ord + Copy>(found: Option<HashSet<T, State>>, out: *mut T, capacity: usize) -> Result<i64, String> {
+    let mut values: Vec<T> = found.map_or(Vec::new(), |found| found.into_iter().collect());
+    values.sort();
+
+    let copied = values.len().min(capacity);
+    if copied > 0 {
+        if out.is_null() {
+            return Err("null output buffer".to_string())
+        }
+        slice::from_raw_parts_mut(out, copied).copy_from_slice(&values[..copied]);
+    }
+    Ok(values.len() as i64)
+}
+
+/// `db` must be null or a DB returned by a create function and not yet freed
+///
+unsafe fn db_ref<'a, T>(db: *const T) -> Result<&'a T, String> {
+    db.as_ref().ok_or("null DB".to_string())
+}
+
+/// `db` must be null or a DB returned by a create function and not yet
+/// freed, which nothing else is using
+///
+unsafe fn db_mut<'a, T>(db: *mut T) -> Result<&'a mut T, String> {
+    db.as_mut().ok_or("null DB".to_string())
+}
+
+/// A DB of 64-bit values
+///
+pub struct HammerDb64(Box<Database<u64>>);
+
+/// Creates a DB finding values within `tolerance` bits, stored at `path` or
+/// in memory if it's null
+///
+/// Returns null on failure, ie if `path` holds a DB created with a different
+/// tolerance.  The DB must be freed with `hammer_db64_free`.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db64_create(tolerance: usize, path: *const c_char) -> *mut HammerDb64 {
+    guard(|| build(64, tolerance, path))
+        .map_or(ptr::null_mut(), |db| Box::into_raw(Box::new(HammerDb64(db))))
+}
+
+/// Adds `value`, returning 1 if it was added, 0 if it was already present or
+/// -1 on failure
+///
+/// `db` must be null or a DB from `hammer_db64_create` which hasn't been
+/// freed, and mustn't be in use on another thread.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db64_insert(db: *mut HammerDb64, value: u64) -> i32 {
+    guard(|| db_mut(db).and_then(|db| db.0.insert(value).map_err(|e| e.to_string())))
+        .map_or(-1, |inserted| inserted as i32)
+}
+
+/// Finds the values within tolerance of `key`, copying up to `capacity` of
+/// them (smallest first) into `out`
+///
+/// Returns how many values were found, which may exceed `capacity`, or -1 on
+/// failure.  `db` must be null or a DB from `hammer_db64_create` which hasn't
+/// been freed, and `out` must hold `capacity` values.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db64_query(db: *const HammerDb64, key: u64, out: *mut u64, capacity: usize) -> i64 {
+    guard(|| {
+        let found = try!(try!(db_ref(db)).0.get(&key).map_err(|e| e.to_string()));
+        copy_found(found, out, capacity)
+    }).unwrap_or(-1)
+}
+
+/// Removes `value`, returning 1 if it was removed, 0 if it wasn't present or
+/// -1 on failure
+///
+/// `db` must be null or a DB from `hammer_db64_create` which hasn't been
+/// freed, and mustn't be in use on another thread.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db64_remove(db: *mut HammerDb64, value: u64) -> i32 {
+    guard(|| db_mut(db).and_then(|db| db.0.remove(&value).map_err(|e| e.to_string())))
+        .map_or(-1, |removed| removed as i32)
+}
+
+/// Closes a DB created with `hammer_db64_create`; null is ignored
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db64_free(db: *mut HammerDb64) {
+    if !db.is_null() {
+        drop(Box::from_raw(db));
+    }
+}
+
+/// A 256-bit value, most significant word first
+///
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HammerKey256 {
+    pub words: [u64; 4],
+}
+
+/// A DB of 256-bit values
+///
+pub struct HammerDb256(Box<Database<[u64; 4]>>);
+
+/// Creates a DB finding values within `tolerance` bits, stored at `path` or
+/// in memory if it's null
+///
+/// Returns null on failure, ie if `path` holds a DB created with a different
+/// tolerance.  The DB must be freed with `hammer_db256_free`.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db256_create(tolerance: usize, path: *const c_char) -> *mut HammerDb256 {
+    guard(|| build(256, tolerance, path))
+        .map_or(ptr::null_mut(), |db| Box::into_raw(Box::new(HammerDb256(db))))
+}
+
+/// Adds `value`, returning 1 if it was added, 0 if it was already present or
+/// -1 on failure
+///
+/// `db` must be null or a DB from `hammer_db256_create` which hasn't been
+/// freed, and mustn't be in use on another thread.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db256_insert(db: *mut HammerDb256, value: HammerKey256) -> i32 {
+    guard(|| db_mut(db).and_then(|db| db.0.insert(value.words).map_err(|e| e.to_string())))
+        .map_or(-1, |inserted| inserted as i32)
+}
+
+/// Finds the values within tolerance of `key`, copying up to `capacity` of
+/// them (smallest first) into `out`
+///
+/// Returns how many values were found, which may exceed `capacity`, or -1 on
+/// failure.  `db` must be null or a DB from `hammer_db256_create` which hasn't
+/// been freed, and `out` must hold `capacity` values.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db256_query(db: *const HammerDb256, key: HammerKey256, out: *mut HammerKey256, capacity: usize) -> i64 {
+    guard(|| {
+        let found = try!(try!(db_ref(db)).0.get(&key.words).map_err(|e| e.to_string()));
+        let found = found.map(|found| found.into_iter().map(|words| HammerKey256{words: words}).collect());
+        copy_found(found, out, capacity)
+    }).unwrap_or(-1)
+}
+
+/// Removes `value`, returning 1 if it was removed, 0 if it wasn't present or
+/// -1 on failure
+///
+/// `db` must be null or a DB from `hammer_db256_create` which hasn't been
+/// freed, and mustn't be in use on another thread.
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db256_remove(db: *mut HammerDb256, value: HammerKey256) -> i32 {
+    guard(|| db_mut(db).and_then(|db| db.0.remove(&value.words).map_err(|e| e.to_string())))
+        .map_or(-1, |removed| removed as i32)
+}
+
+/// Closes a DB created with `hammer_db256_create`; null is ignored
+///
+#[no_mangle]
+pub unsafe extern "C" fn hammer_db256_free(db: *mut HammerDb256) {
+    if !db.is_null() {
+        drop(Box::from_raw(db));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn db64_round_trip() {
+        unsafe {
+            let db = hammer_db64_create(4, ptr::null());
+            assert_eq!(hammer_db64_insert(db, 0xF), 1);
+            assert_eq!(hammer_db64_insert(db, 0xF), 0);
+            assert_eq!(hammer_db64_insert(db, 0xF0), 1);
+
+            // Both are found, but only one fits
+            let mut found = [0u64; 1];
+            assert_eq!(hammer_db64_query(db, 0xFF, found.as_mut_ptr(), 1), 2);
+            assert_eq!(found, [0xF]);
+
+            assert_eq!(hammer_db64_remove(db, 0xF), 1);
+            assert_eq!(hammer_db64_query(db, 0xF, found.as_mut_ptr(), 1), 0);
+            hammer_db64_free(db);
+        }
+    }
+
+    #[test]
+    fn db256_round_trip() {
+        unsafe {
+            let db = hammer_db256_create(8, ptr::null());
+            let value = HammerKey256{words: [0, 0, 0, 1]};
+            assert_eq!(hammer_db256_insert(db, value), 1);
+
+            let mut found = [HammerKey256{words: [0; 4]}; 4];
+            assert_eq!(hammer_db256_query(db, HammerKey256{words: [0; 4]}, found.as_mut_ptr(), 4), 1);
+            assert_eq!(found[0], value);
+            hammer_db256_free(db);
+        }
+    }
+
+    #[test]
+    fn failures_set_the_last_error() {
+        unsafe {
+            assert!(hammer_db64_create(64, ptr::null()).is_null());
+            assert!(!hammer_last_error().is_null());
+            assert_eq!(hammer_db64_insert(ptr::null_mut(), 1), -1);
+            assert_eq!(hammer_db256_remove(ptr::null_mut(), HammerKey256{words: [0; 4]}), -1);
+            assert_eq!(CStr::from_ptr(hammer_last_error()).to_str().unwrap(), "null DB");
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Python bindings
+//!
+//! Exposes hammer's databases to Python without running the HTTP server:
+//!
+//! ```python
+//! import hammer
+//!
+//! db = hammer.SubstitutionDB(tolerance=4)
+//! db.insert(0b1011)
+//! db.get(0b1001)          # [11]
+//!
+//! db = hammer.SubstitutionDB(tolerance=8, bits=256, path="/tmp/fingerprints")
+//! db.insert(bytes(32))
+//!
+//! db = hammer.DeletionDB(dimensions=3, tolerance=1, bytes=True)
+//! db.insert(b"abc")
+//! db.get(b"abd")          # [b"abc"]
+//! ```
+//!
+//! `SubstitutionDB` indexes 64-bit values as ints, and 128 & 256-bit values as
+//! bytes holding big-endian 64-bit words (the encoding the HTTP API uses).
+//! `DeletionDB` indexes vectors, as lists of ints (each a 64-bit dimension)
+//! or, with `bytes=True`, as bytes (each byte a dimension).  Either is kept
+//! in memory unless a `path` is given, in which case it's stored in RocksDB
+//! there.
+//!
+//! The GIL is released while the database works, so other Python threads
+//! keep running.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use hammer::db::{Database, Factory, RocksDBConfig, StorageBackend};
+use hammer::db::builder::DbBuilder;
+use hammer::db::hashing::State;
+use hammer::Error;
+
+create_exception!(hammer, HammerError, PyException);
+
+fn db_error(e: Error) -> PyErr {
+    HammerError::new_err(e.to_string())
+}
+
+fn build<T: Factory>(dimensions: usize, tolerance: usize, path: Option<PathBuf>) -> PyResult<Box<dyn Database<T>>> {
+    let backend = match path {
+        Some(path) => StorageBackend::RocksDB(path, RocksDBConfig::default()),
+        None => StorageBackend::InMemory,
+    };
+    DbBuilder::<T>::new()
+        .dimensions(dimensions)
+        .tolerance(tolerance)
+        .backend(backend)
+        .build()
+        .map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+/// Values within tolerance, smallest first
+fn sorted<T: Ord>(found: Option<HashSet<T, State>>) -> Vec<T> {
+    let mut values: Vec<T> = found.map_or(Vec::new(), |found| found.into_iter().collect());
+    values.sort();
+    values
+}
+
+/// Reads `bytes` as `N` big-endian 64-bit words
+fn words<const N: usize>(bytes: &[u8]) -> PyResult<[u64; N]> {
+    if bytes.len() != N * 8 {
+        return Err(PyValueError::new_err(format!("expected {} bytes, got {}", N * 8, bytes.len())))
+    }
+    let mut words = [0u64; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(8)) {
+        let mut be = [0u8; 8];
+        be.copy_from_slice(chunk);
+        *word = u64::from_be_bytes(be);
+    }
+    Ok(words)
+}
+
+fn word_bytes<'py, const N: usize>(py: Python<'py>, words: &[u64; N]) -> &'py PyBytes {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+    PyBytes::new(py, &bytes)
+}
+
+enum Binary {
+    B64(Box<dyn Database<u64>>),
+    B128(Box<dyn Database<[u64; 2]>>),
+    B256(Box<dyn Database<[u64; 4]>>),
+}
+
+/// Indexes binary values by substitution
+///
+#[pyclass(module = "hammer")]
+pub struct SubstitutionDB {
+    db: Binary,
+}
+
+#[pymethods]
+impl SubstitutionDB {
+    #[new]
+    #[pyo3(signature = (tolerance, bits=64, path=None))]
+    fn new(tolerance: usize, bits: usize, path: Option<PathBuf>) -> PyResult<SubstitutionDB> {
+        let db = match bits {
+            64 => Binary::B64(build(bits, tolerance, path)?),
+            128 => Binary::B128(build(bits, tolerance, path)?),
+            256 => Binary::B256(build(bits, tolerance, path)?),
+            _ => return Err(PyValueError::new_err(format!("unsupported bitsize {} (expected 64, 128 or 256)", bits))),
+        };
+        Ok(SubstitutionDB{db: db})
+    }
+
+    /// Adds `value`, returning false if it was already present
+    fn insert(&mut self, py: Python, value: &PyAny) -> PyResult<bool> {
+        let inserted = match self.db {
+            Binary::B64(ref mut db) => { let v = value.extract::<u64>()?; py.allow_threads(|| db.insert(v)) },
+            Binary::B128(ref mut db) => { let v = words::<2>(value.extract()?)?; py.allow_threads(|| db.insert(v)) },
+            Binary::B256(ref mut db) => { let v = words::<4>(value.extract()?)?; py.allow_threads(|| db.insert(v)) },
+        };
+        inserted.map_err(db_error)
+    }
+
+    /// Returns the values within tolerance of `value`
+    fn get(&self, py: Python, value: &PyAny) -> PyResult<Vec<PyObject>> {
+        match self.db {
+            Binary::B64(ref db) => {
+                let v = value.extract::<u64>()?;
+                let found = py.allow_threads(|| db.get(&v)).map_err(db_error)?;
+                Ok(sorted(found).into_iter().map(|v| v.into_py(py)).collect())
+            },
+            Binary::B128(ref db) => {
+                let v = words::<2>(value.extract()?)?;
+                let found = py.allow_threads(|| db.get(&v)).map_err(db_error)?;
+                Ok(sorted(found).iter().map(|v| word_bytes(py, v).into_py(py)).collect())
+            },
+            Binary::B256(ref db) => {
+                let v = words::<4>(value.extract()?)?;
+                let found = py.allow_threads(|| db.get(&v)).map_err(db_error)?;
+                Ok(sorted(found).iter().map(|v| word_bytes(py, v).into_py(py)).collect())
+            },
+        }
+    }
+
+    /// Removes `value`, returning false if it wasn't present
+    fn remove(&mut self, py: Python, value: &PyAny) -> PyResult<bool> {
+        let removed = match self.db {
+            Binary::B64(ref mut db) => { let v = value.extract::<u64>()?; py.allow_threads(|| db.remove(&v)) },
+            Binary::B128(ref mut db) => { let v = words::<2>(value.extract()?)?; py.allow_threads(|| db.remove(&v)) },
+            Binary::B256(ref mut db) => { let v = words::<4>(value.extract()?)?; py.allow_threads(|| db.remove(&v)) },
+        };
+        removed.map_err(db_error)
+    }
+}
+
+enum Vector {
+    Ints(Box<dyn Database<Vec<u64>>>),
+    Bytes(Box<dyn Database<Vec<u8>>>),
+}
+
+/// Indexes vectors by deletion
+///
+#[pyclass(module = "hammer")]
+pub struct DeletionDB {
+    db: Vector,
+}
+
+impl DeletionDB {
+    fn ints(value: &PyAny) -> PyResult<Vec<u64>> {
+        if value.is_instance_of::<PyBytes>() {
+            return Err(PyTypeError::new_err("expected a list of ints (construct with bytes=True to index bytes)"))
+        }
+        value.extract()
+    }
+}
+
+#[pymethods]
+impl DeletionDB {
+    #[new]
+    #[pyo3(signature = (dimensions, tolerance, path=None, bytes=false))]
+    fn new(dimensions: usize, tolerance: usize, path: Option<PathBuf>, bytes: bool) -> PyResult<DeletionDB> {
+        let db = match bytes {
+            true => Vector::Bytes(build(dimensions, tolerance, path)?),
+            false => Vector::Ints(build(dimensions, tolerance, path)?),
+        };
+        Ok(DeletionDB{db: db})
+    }
+
+    /// Adds `value`, returning false if it was already present
+    fn insert(&mut self, py: Python, value: &PyAny) -> PyResult<bool> {
+        let inserted = match self.db {
+            Vector::Ints(ref mut db) => { let v = DeletionDB::ints(value)?; py.allow_threads(|| db.insert(v)) },
+            Vector::Bytes(ref mut db) => { let v = value.extract::<&[u8]>()?.to_vec(); py.allow_threads(|| db.insert(v)) },
+        };
+        inserted.map_err(db_error)
+    }
+
+    /// Returns the values within tolerance of `value`
+    fn get(&self, py: Python, value: &PyAny) -> PyResult<Vec<PyObject>> {
+        match self.db {
+            Vector::Ints(ref db) => {
+                let v = DeletionDB::ints(value)?;
+                let found = py.allow_threads(|| db.get(&v)).map_err(db_error)?;
+                Ok(sorted(found).into_iter().map(|v| v.into_py(py)).collect())
+            },
+            Vector::Bytes(ref db) => {
+                let v = value.extract::<&[u8]>()?.to_vec();
+                let found = py.allow_threads(|| db.get(&v)).map_err(db_error)?;
+                Ok(sorted(found).iter().map(|v| PyBytes::new(py, v).into_py(py)).collect())
+            },
+        }
+    }
+
+    /// Removes `value`, returning false if it wasn't present
+    fn remove(&mut self, py: Python, value: &PyAny) -> PyResult<bool> {
+        let removed = match self.db {
+            Vector::Ints(ref mut db) => { let v = DeletionDB::ints(value)?; py.allow_threads(|| db.remove(&v)) },
+            Vector::Bytes(ref mut db) => { let v = value.extract::<&[u8]>()?.to_vec(); py.allow_threads(|| db.remove(&v)) },
+        };
+        removed.map_err(db_error)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "hammer")]
+fn hammer_module(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<SubstitutionDB>()?;
+    m.add_class::<DeletionDB>()?;
+    m.add("HammerError", py.get_type::<HammerError>())?;
+    Ok(())
+}
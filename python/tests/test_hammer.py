@@ -0,0 +1,45 @@
+import pytest
+
+import hammer
+
+
+def test_substitution_ints():
+    db = hammer.SubstitutionDB(tolerance=2)
+    assert db.insert(0b1011)
+    assert not db.insert(0b1011)
+    assert db.get(0b1001) == [0b1011]
+    assert db.get(0b0100) == []
+    assert db.remove(0b1011)
+    assert db.get(0b1011) == []
+
+
+def test_substitution_bytes():
+    db = hammer.SubstitutionDB(tolerance=4, bits=256)
+    value = bytes(31) + b"\x01"
+    assert db.insert(value)
+    assert db.get(bytes(32)) == [value]
+
+    with pytest.raises(ValueError):
+        db.insert(bytes(8))
+
+
+def test_deletion_vectors():
+    db = hammer.DeletionDB(dimensions=3, tolerance=1)
+    assert db.insert([1, 2, 3])
+    assert db.get([1, 2, 4]) == [[1, 2, 3]]
+
+    db = hammer.DeletionDB(dimensions=3, tolerance=1, bytes=True)
+    assert db.insert(b"abc")
+    assert db.get(b"abd") == [b"abc"]
+    assert db.remove(b"abc")
+
+
+def test_persisted(tmp_path):
+    db = hammer.SubstitutionDB(tolerance=4, path=str(tmp_path))
+    db.insert(7)
+    del db
+
+    assert hammer.SubstitutionDB(tolerance=4, path=str(tmp_path)).get(7) == [7]
+    # The stores were created for another tolerance
+    with pytest.raises(ValueError):
+        hammer.SubstitutionDB(tolerance=6, path=str(tmp_path))
@@ -1,46 +1,258 @@
 extern crate docopt;
 #[macro_use]
 extern crate iron;
-extern crate bincode;
+extern crate byteorder;
 extern crate router;
 extern crate persistent;
-extern crate rustc_serialize;
+extern crate base64;
+extern crate hex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate rand;
+extern crate ctrlc;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+#[cfg(feature = "otlp")]
+extern crate hyper;
+#[cfg(feature = "grpc")]
+extern crate grpcio;
+#[cfg(feature = "grpc")]
+extern crate futures;
+#[cfg(feature = "grpc")]
+extern crate prost;
+#[cfg(feature = "grpc")]
+#[macro_use]
+extern crate prost_derive;
+#[cfg(feature = "grpc")]
+extern crate bytes;
 extern crate hammer;
 
 pub mod http;
+pub mod net;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::process;
+use std::io::{self, Write};
 
 use docopt::Docopt;
 
+use hammer::db::{RocksDBConfig, CompactionStyle};
+use hammer::db::column_family::SharedRocksDB;
+use hammer::db::compaction::StoreOptions;
+use hammer::db::disk_usage::DiskBudget;
+
 const USAGE: &'static str = "
 Hammer
 
 Usage:
-    hammerhttp [--data-dir=<path>] [--bind=<host:port>]
+    hammerhttp [--data-dir=<path>] [--bind=<host:port>] [--storage=<engine>] [--max-open-files=<n>] [--compaction-style=<style>] [--block-cache-bytes=<n>] [--bloom-filter-bits=<n>] [--variant-cache-sets=<n>] [--variant-filter-counters=<n>] [--compact-interval=<secs>] [--column-families] [--sync-writes] [--repair-on-open] [--max-namespace-bytes=<n>] [--max-namespace-sst-files=<n>] [--max-query-candidates=<n>] [--lru=<n>] [--otlp-endpoint=<url>] [--oplog] [--binary-bind=<host:port>] [--binary-socket=<path>] [--grpc-bind=<host:port>] [--grpc-threads=<n>] [--max-body-bytes=<n>] [--max-request-keys=<n>] [--rate-limit=<n>] [--slow-query-ms=<n>] [--config=<path>]
     hammerhttp (-h | --help)
 
 Options:
     --data-dir=<path>       If set, data will be persisted to the given path (if 
                             unset, data will be persisted to a temporary location)
     --bind=<host:port>      Host & port to bind to [default: localhost:3000]
+    --storage=<engine>      Store used for data under --data-dir: rocksdb, or
+                            sled (requires the `sled` feature) [default: rocksdb]
+    --max-open-files=<n>    Maximum file handles each RocksDB store may hold open
+                            (each namespace opens two stores) [default: -1]
+    --compaction-style=<style>
+                            RocksDB compaction style: level, universal or fifo
+                            [default: level]
+    --block-cache-bytes=<n> Size of each RocksDB store's block cache (RocksDB's
+                            default if unset)
+    --bloom-filter-bits=<n> Bits per key of each RocksDB store's bloom filter
+                            (no bloom filter if unset)
+    --variant-cache-sets=<n>
+                            Cache up to n of each namespace's variant sets in
+                            memory, in front of RocksDB (no cache if unset)
+    --variant-filter-counters=<n>
+                            Keep an in-memory bloom filter of n counters per
+                            partition over each namespace's variant keys, so
+                            most misses skip RocksDB (no filter if unset)
+    --compact-interval=<secs>
+                            Compact every namespace's stores, reclaiming the
+                            space held by removed values, every secs seconds
+    --column-families       Store new namespaces in column families of a single
+                            RocksDB under the data dir, rather than two RocksDB
+                            directories per namespace (requires --data-dir)
+    --sync-writes           Sync each add & delete request to disk before
+                            responding
+    --repair-on-open        Scan each re-opened namespace, removing index
+                            entries which reference missing values
+    --max-namespace-bytes=<n>
+                            Warn when a namespace uses more than n bytes on disk
+    --max-namespace-sst-files=<n>
+                            Warn when a namespace holds more than n SST files
+    --max-query-candidates=<n>
+                            Reject queries estimated to examine more than n
+                            candidates with 413 Payload Too Large
+    --lru=<n>               Cap each namespace at n values, evicting the least
+                            recently queried (or added) when it's exceeded
+    --otlp-endpoint=<url>   Export request spans to this OpenTelemetry collector
+                            (OTLP/HTTP, requires the `otlp` feature)
+    --oplog                 Log every add & delete to the data dir, so another
+                            server can tail and replay them (requires
+                            --data-dir)
+    --binary-bind=<host:port>
+                            Also serve the binary protocol (see `net`) on this
+                            TCP address
+    --binary-socket=<path>  Also serve the binary protocol on a unix socket at
+                            this path
+    --grpc-bind=<host:port> Also serve the gRPC service (see `grpc`) on this
+                            address (requires the `grpc` feature)
+    --grpc-threads=<n>      Completion queue threads serving gRPC calls; each
+                            call holds one for its duration [default: 8]
+    --max-body-bytes=<n>    Reject request bodies larger than n bytes with 413
+                            Payload Too Large
+    --max-request-keys=<n>  Reject requests for more than n values with 413
+                            Payload Too Large
+    --rate-limit=<n>        Allow each client IP n requests per second, rejecting
+                            the rest with 429 Too Many Requests
+    --slow-query-ms=<n>     Log queries taking longer than n milliseconds, with
+                            the time spent in each partition
+    --config=<path>         JSON file of settings overriding the limit, cache &
+                            slow query flags, re-read on POST /config/reload
     -h --help               Show this screen.
 ";
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
 struct Args {
     flag_data_dir: Option<String>,
     flag_bind: String,
+    flag_storage: String,
+    flag_max_open_files: i32,
+    flag_compaction_style: String,
+    flag_block_cache_bytes: Option<usize>,
+    flag_bloom_filter_bits: Option<i32>,
+    flag_variant_cache_sets: Option<usize>,
+    flag_variant_filter_counters: Option<usize>,
+    flag_compact_interval: Option<u64>,
+    flag_column_families: bool,
+    flag_sync_writes: bool,
+    flag_repair_on_open: bool,
+    flag_max_namespace_bytes: Option<u64>,
+    flag_max_namespace_sst_files: Option<usize>,
+    flag_max_query_candidates: Option<usize>,
+    flag_lru: Option<usize>,
+    flag_otlp_endpoint: Option<String>,
+    flag_oplog: bool,
+    flag_binary_bind: Option<String>,
+    flag_binary_socket: Option<String>,
+    flag_grpc_bind: Option<String>,
+    flag_grpc_threads: usize,
+    flag_max_body_bytes: Option<usize>,
+    flag_max_request_keys: Option<usize>,
+    flag_rate_limit: Option<f64>,
+    flag_slow_query_ms: Option<u64>,
+    flag_config: Option<String>,
 }
 
 pub fn main() {
+    // Startup & warnings are logged at info level unless RUST_LOG says
+    // otherwise; RUST_LOG=debug also logs every add & delete
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.decode())
+        .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    let storage = match http::StorageEngine::parse(&args.flag_storage) {
+        Ok(storage) => storage,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            process::exit(1);
+        },
+    };
+
+    let compaction_style = match CompactionStyle::parse(&args.flag_compaction_style) {
+        Ok(style) => style,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            process::exit(1);
+        },
+    };
+
+    if args.flag_oplog && args.flag_data_dir.is_none() {
+        let _ = writeln!(io::stderr(), "--oplog requires --data-dir");
+        process::exit(1);
+    }
+
+    if args.flag_grpc_bind.is_some() && !cfg!(feature = "grpc") {
+        let _ = writeln!(io::stderr(), "--grpc-bind requires the `grpc` feature");
+        process::exit(1);
+    }
+
+    if args.flag_rate_limit.map_or(false, |rate| !(rate > 0.0)) {
+        let _ = writeln!(io::stderr(), "--rate-limit must be positive");
+        process::exit(1);
+    }
+
+    if args.flag_column_families && (args.flag_data_dir.is_none() || storage != http::StorageEngine::RocksDB) {
+        let _ = writeln!(io::stderr(), "--column-families requires --data-dir and rocksdb storage");
+        process::exit(1);
+    }
+
+    let rocksdb = RocksDBConfig{
+        max_open_files: args.flag_max_open_files,
+        compaction_style: compaction_style,
+        block_cache_bytes: args.flag_block_cache_bytes,
+        bloom_filter_bits: args.flag_bloom_filter_bits,
+        variant_cache_sets: args.flag_variant_cache_sets,
+        variant_filter_counters: args.flag_variant_filter_counters,
+    };
+
+    let column_families = match args.flag_data_dir {
+        Some(ref dir) if args.flag_column_families => {
+            let path = PathBuf::from(dir).join(http::COLUMN_FAMILIES_DIR);
+            match SharedRocksDB::open(&path, StoreOptions::new(rocksdb.options())) {
+                Ok(shared) => Some(shared),
+                Err(e) => {
+                    let _ = writeln!(io::stderr(), "unable to open {:?}: {}", path, e);
+                    process::exit(1);
+                },
+            }
+        },
+        _ => None,
+    };
+
     let config = http::Config{
         data_dir: args.flag_data_dir.map(|d| PathBuf::from(d)),
         bind: args.flag_bind,
+        storage: storage,
+        rocksdb: rocksdb,
+        column_families: column_families,
+        compact_interval: args.flag_compact_interval,
+        sync_writes: args.flag_sync_writes,
+        repair_on_open: args.flag_repair_on_open,
+        disk_budget: DiskBudget{
+            max_bytes: args.flag_max_namespace_bytes,
+            max_sst_files: args.flag_max_namespace_sst_files,
+            max_open_files: if args.flag_max_open_files > 0 { Some(2 * args.flag_max_open_files as usize) } else { None },
+        },
+        max_query_candidates: args.flag_max_query_candidates,
+        lru: args.flag_lru,
+        otlp_endpoint: args.flag_otlp_endpoint,
+        oplog: args.flag_oplog,
+        binary_bind: args.flag_binary_bind,
+        binary_socket: args.flag_binary_socket.map(|s| PathBuf::from(s)),
+        grpc_bind: args.flag_grpc_bind,
+        grpc_threads: args.flag_grpc_threads,
+        limits: http::limits::Limits{
+            max_body_bytes: args.flag_max_body_bytes,
+            max_request_keys: args.flag_max_request_keys,
+            rate_limit: args.flag_rate_limit,
+        },
+        slow_query_ms: args.flag_slow_query_ms,
+        shadows: HashMap::new(),
+        config_file: args.flag_config.map(|c| PathBuf::from(c)),
     };
 
     http::server::serve(config)
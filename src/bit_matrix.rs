@@ -1,7 +1,25 @@
+//! Bit-level transposition
+//!
+//! `BitTranspose` transposes square blocks of bits in place, and `BitMatrix`
+//! holds matrices of any shape in one contiguous buffer, transposing them
+//! 64x64 bits at a time.
+//!
+//! `AsBitMatrix` lays a vector out with one row per element.  Transposed,
+//! each row is one bit of every element (a "bit plane"), which is how HmSearch
+//! verifies candidates for vectors of non-binary elements: two vectors differ
+//! at element `i` if any of their bit planes differ at column `i`, so
+//! `column_hamming` counts the differing elements a word of columns at a time
+//! rather than an element at a time.  The number of planes differing at column
+//! `i` is the bit-level distance between the elements, which
+//! `capped_column_hamming` sums (see `db::hamming::ElementHamming`).
+
 use std::mem::size_of;
 use std::ops::{BitAnd, BitXor, Shl, Shr, Not};
 use num::traits::Zero;
 
+/// Rows and columns in each block transposed by `BitMatrix::transpose`
+const BLOCK_BITS: usize = 64;
+
 
 /// Trait of objects whose bits can be transposed
 ///
@@ -95,6 +113,249 @@ T: Clone,
     }
 }
 
+/// Transposes a 64x64 block of bits in place
+///
+/// Column `c` of row `r` is bit `63 - c` of `block[r]`, as for
+/// `BitTranspose`.
+///
+fn transpose_block(block: &mut [u64; BLOCK_BITS]) {
+    let mut j = BLOCK_BITS / 2;
+    let mut m: u64 = !0 >> j;
+
+    while j != 0 {
+        let mut k = 0;
+        while k < BLOCK_BITS {
+            let t = (block[k] ^ (block[k | j] >> j)) & m;
+            block[k] ^= t;
+            block[k | j] ^= t << j;
+
+            k = ((k | j) + 1) & !j;
+        }
+
+        j = j >> 1;
+        m = m ^ (m << j);
+    }
+}
+
+/// A `rows` by `cols` matrix of bits
+///
+/// Rows are stored one after another in a single buffer, each padded to a
+/// whole number of 64-bit words, so a row can be read as a `&[u64]` and
+/// transposing is done in 64x64 blocks without allocating per row.  Padding
+/// bits are always zero.
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BitMatrix {
+    rows: usize,
+    cols: usize,
+    // Words per row
+    stride: usize,
+    words: Vec<u64>,
+}
+
+impl BitMatrix {
+    /// All-zero matrix
+    ///
+    pub fn new(rows: usize, cols: usize) -> BitMatrix {
+        let stride = (cols + BLOCK_BITS - 1) / BLOCK_BITS;
+        BitMatrix{
+            rows: rows,
+            cols: cols,
+            stride: stride,
+            words: vec![0; rows * stride],
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> bool {
+        assert!(row < self.rows && col < self.cols);
+        let word = self.words[row * self.stride + col / BLOCK_BITS];
+        (word >> (BLOCK_BITS - 1 - col % BLOCK_BITS)) & 1 == 1
+    }
+
+    pub fn set(&mut self, row: usize, col: usize, bit: bool) {
+        assert!(row < self.rows && col < self.cols);
+        let mask = 1 << (BLOCK_BITS - 1 - col % BLOCK_BITS);
+        let word = &mut self.words[row * self.stride + col / BLOCK_BITS];
+        if bit {
+            *word |= mask;
+        } else {
+            *word &= !mask;
+        }
+    }
+
+    /// The words of `row`, column 0 being the most significant bit of the
+    /// first word
+    ///
+    pub fn row(&self, row: usize) -> &[u64] {
+        &self.words[row * self.stride..(row + 1) * self.stride]
+    }
+
+    /// Number of columns which differ between `self` and `other` in any row
+    ///
+    /// Both matrices must have the same shape.
+    ///
+    pub fn column_hamming(&self, other: &BitMatrix) -> usize {
+        assert_eq!((self.rows, self.cols), (other.rows, other.cols));
+        (0..self.stride).fold(0, |h, i| h + self.column_diff(other, i).count_ones() as usize)
+    }
+
+    /// Returns true if no more than `bound` columns differ between `self` and
+    /// `other`, checking the bound after each word of columns
+    ///
+    pub fn column_hamming_lte(&self, other: &BitMatrix, bound: usize) -> bool {
+        assert_eq!((self.rows, self.cols), (other.rows, other.cols));
+        let mut h = 0;
+        for i in 0..self.stride {
+            h += self.column_diff(other, i).count_ones() as usize;
+            if h > bound {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Sum over columns of the number of rows in which `self` and `other`
+    /// differ, each column counting at most `cap`
+    ///
+    /// With `cap` 1 this is `column_hamming`.  Both matrices must have the
+    /// same shape.
+    ///
+    pub fn capped_column_hamming(&self, other: &BitMatrix, cap: usize) -> usize {
+        assert_eq!((self.rows, self.cols), (other.rows, other.cols));
+        let mut counts = vec![0; cap];
+        (0..self.stride).fold(0, |h, i| h + self.capped_column_diff(other, i, &mut counts))
+    }
+
+    /// Returns true if `capped_column_hamming` is no more than `bound`,
+    /// checking the bound after each word of columns
+    ///
+    pub fn capped_column_hamming_lte(&self, other: &BitMatrix, cap: usize, bound: usize) -> bool {
+        assert_eq!((self.rows, self.cols), (other.rows, other.cols));
+        let mut counts = vec![0; cap];
+        let mut h = 0;
+        for i in 0..self.stride {
+            h += self.capped_column_diff(other, i, &mut counts);
+            if h > bound {
+                return false
+            }
+        }
+        true
+    }
+
+    // Capped differing rows summed over the columns in word `i`.  Bit `c` of
+    // `counts[j]` is set once more than `j` rows differ at column `c`, so
+    // each row's differences are added to every column's count at once.
+    fn capped_column_diff(&self, other: &BitMatrix, i: usize, counts: &mut [u64]) -> usize {
+        for count in counts.iter_mut() {
+            *count = 0;
+        }
+        for row in 0..self.rows {
+            let offset = row * self.stride + i;
+            let diff = self.words[offset] ^ other.words[offset];
+            for j in (1..counts.len()).rev() {
+                counts[j] |= counts[j - 1] & diff;
+            }
+            if let Some(first) = counts.first_mut() {
+                *first |= diff;
+            }
+        }
+        counts.iter().fold(0, |h, count| h + count.count_ones() as usize)
+    }
+
+    // Bits set for the columns in word `i` of each row where any row differs
+    fn column_diff(&self, other: &BitMatrix, i: usize) -> u64 {
+        (0..self.rows).fold(0, |diff, row| {
+            let offset = row * self.stride + i;
+            diff | (self.words[offset] ^ other.words[offset])
+        })
+    }
+
+    /// The `cols` by `rows` matrix whose row `c` is column `c` of `self`
+    ///
+    pub fn transpose(&self) -> BitMatrix {
+        let mut out = BitMatrix::new(self.cols, self.rows);
+        let mut block = [0u64; BLOCK_BITS];
+
+        for block_row in 0..(self.rows + BLOCK_BITS - 1) / BLOCK_BITS {
+            for block_col in 0..self.stride {
+                for i in 0..BLOCK_BITS {
+                    let row = block_row * BLOCK_BITS + i;
+                    block[i] = if row < self.rows { self.words[row * self.stride + block_col] } else { 0 };
+                }
+
+                transpose_block(&mut block);
+
+                for i in 0..BLOCK_BITS {
+                    let col = block_col * BLOCK_BITS + i;
+                    if col >= self.cols {
+                        break
+                    }
+                    out.words[col * out.stride + block_row] = block[i];
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Values which can be laid out as a `BitMatrix` with one row per element
+///
+/// Column `c` of a row is bit `c` of the element counting from the most
+/// significant, so the transposed matrix's first row holds every element's
+/// top bit.
+///
+pub trait AsBitMatrix {
+    fn as_bit_matrix(&self) -> BitMatrix;
+
+    /// The transposed matrix, with one row per bit of the elements
+    ///
+    fn bit_planes(&self) -> BitMatrix {
+        self.as_bit_matrix().transpose()
+    }
+}
+
+macro_rules! vec_as_bit_matrix {
+    ($elem:ident) => {
+        impl AsBitMatrix for Vec<$elem> {
+            fn as_bit_matrix(&self) -> BitMatrix {
+                let bits = 8 * size_of::<$elem>();
+                let mut m = BitMatrix::new(self.len(), bits);
+                for (row, elem) in self.iter().enumerate() {
+                    m.words[row * m.stride] = (*elem as u64) << (BLOCK_BITS - bits);
+                }
+                m
+            }
+        }
+    }
+}
+vec_as_bit_matrix!(u8);
+vec_as_bit_matrix!(u16);
+vec_as_bit_matrix!(u32);
+vec_as_bit_matrix!(u64);
+
+macro_rules! array_as_bit_matrix {
+    ($elem:ty) => {
+        impl AsBitMatrix for $elem {
+            fn as_bit_matrix(&self) -> BitMatrix {
+                let mut m = BitMatrix::new(self.len(), BLOCK_BITS);
+                m.words.copy_from_slice(self);
+                m
+            }
+        }
+    }
+}
+array_as_bit_matrix!([u64; 2]);
+array_as_bit_matrix!([u64; 4]);
+
 
 #[cfg(test)] 
 mod test {
@@ -103,7 +364,8 @@ mod test {
 
     use self::quickcheck::quickcheck;
 
-    use bit_matrix::{BitTranspose};
+    use bit_matrix::{AsBitMatrix, BitMatrix, BitTranspose};
+    use db::hamming::{Hamming, ElementHamming};
 
     #[test]
     fn transpose_identity() {
@@ -114,4 +376,80 @@ mod test {
         }
         quickcheck(prop as fn(u8, u8, u8, u8, u8, u8, u8, u8) -> quickcheck::TestResult);
     }
+
+    fn matrix(rows: usize, cols: usize, bits: &[bool]) -> BitMatrix {
+        let mut m = BitMatrix::new(rows, cols);
+        for (i, bit) in bits.iter().cycle().take(rows * cols).enumerate() {
+            m.set(i / cols, i % cols, *bit);
+        }
+        m
+    }
+
+    #[test]
+    fn matrix_transpose_swaps_rows_and_columns() {
+        fn prop(rows: u8, cols: u8, bits: Vec<bool>) -> quickcheck::TestResult {
+            if bits.is_empty() {
+                return quickcheck::TestResult::discard()
+            }
+            let (rows, cols) = (rows as usize + 1, cols as usize + 1);
+            let m = matrix(rows, cols, &bits);
+            let t = m.transpose();
+
+            let swapped = (0..rows).all(|r| (0..cols).all(|c| m.get(r, c) == t.get(c, r)));
+            quickcheck::TestResult::from_bool(swapped && t.transpose() == m)
+        }
+        quickcheck(prop as fn(u8, u8, Vec<bool>) -> quickcheck::TestResult);
+    }
+
+    #[test]
+    fn matrix_transpose_spans_blocks() {
+        let mut m = BitMatrix::new(300, 70);
+        m.set(0, 69, true);
+        m.set(299, 0, true);
+        m.set(130, 64, true);
+
+        let t = m.transpose();
+
+        assert_eq!((t.rows(), t.cols()), (70, 300));
+        assert_eq!(t.row(69), &[1 << 63, 0, 0, 0, 0]);
+        assert_eq!(t.row(0), &[0, 0, 0, 0, 1 << (63 - 299 % 64)]);
+        assert!(t.get(64, 130));
+        assert_eq!(t.words.iter().fold(0, |n, w| n + w.count_ones()), 3);
+    }
+
+    #[test]
+    fn bit_plane_column_hamming_counts_differing_elements() {
+        fn prop(a: Vec<u16>, b: Vec<u16>, bound: usize) -> bool {
+            let len = if a.len() < b.len() { a.len() } else { b.len() };
+            let (a, b) = (a[..len].to_vec(), b[..len].to_vec());
+            let (planes_a, planes_b) = (a.bit_planes(), b.bit_planes());
+
+            planes_a.column_hamming(&planes_b) == a.hamming(&b) &&
+                planes_a.column_hamming_lte(&planes_b, bound) == a.hamming_lte(&b, bound)
+        }
+        quickcheck(prop as fn(Vec<u16>, Vec<u16>, usize) -> bool);
+    }
+
+    #[test]
+    fn capped_column_hamming_sums_capped_element_distances() {
+        fn prop(a: Vec<u16>, b: Vec<u16>, cap: u8, bound: usize) -> bool {
+            let len = if a.len() < b.len() { a.len() } else { b.len() };
+            let (a, b) = (a[..len].to_vec(), b[..len].to_vec());
+            let cap = cap as usize % 17 + 1;
+            let (planes_a, planes_b) = (a.bit_planes(), b.bit_planes());
+
+            planes_a.capped_column_hamming(&planes_b, cap) == a.element_hamming(&b, cap) &&
+                planes_a.capped_column_hamming_lte(&planes_b, cap, bound) == a.element_hamming_lte(&b, cap, bound) &&
+                planes_a.capped_column_hamming(&planes_b, 1) == planes_a.column_hamming(&planes_b)
+        }
+        quickcheck(prop as fn(Vec<u16>, Vec<u16>, u8, usize) -> bool);
+    }
+
+    #[test]
+    fn elements_are_rows_most_significant_bit_first() {
+        let m = vec![0b1000_0001u8, 0b0100_0000u8].as_bit_matrix();
+
+        assert!(m.get(0, 0) && m.get(0, 7) && m.get(1, 1));
+        assert_eq!([1u64, 1 << 63].bit_planes().row(0), &[0b01 << 62]);
+    }
 }
@@ -0,0 +1,352 @@
+//! Typed client for the HTTP API
+//!
+//! The server expects each value as base64-encoded bincode (vectors as arrays
+//! of encoded elements) and returns a result per value as a JSON string or
+//! array.  `Client` does that encoding and parsing, so Rust consumers work
+//! with the same types the server indexes.  Requires the `client` feature.
+//!
+//! Errors returned by the server's DB are returned as the `Error` it
+//! reported (ie `Error::TooExpensive` when a query is rejected by
+//! `--max-query-candidates`).  Failing to reach the server, or a response
+//! which can't be parsed, is an `Error::Storage`.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let client = Client::new("http://localhost:3000");
+//!
+//! let ns = client.binary::<u64>(4, "fingerprints");
+//! ns.add(&[0b1111, !0]);
+//! // Ok([AddResult::Ok, AddResult::Ok])
+//! ns.query(&[0b0111]);
+//! // Ok([QueryResult::Ok([0b1111])])
+//!
+//! let vectors = client.vector::<u64>(4, 1, "shingles");
+//! vectors.add(&[vec![1, 2, 3, 4]]);
+//! // Ok([AddResult::Ok])
+//! ```
+
+use std::io::Read;
+use std::marker::PhantomData;
+
+use hyper;
+use hyper::header::ContentType;
+use base64;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use db::cost::TooExpensive;
+use db::encoding::{encode, decode};
+use error::Error;
+
+/// Values (and vector elements) indexed by the server
+///
+pub trait Element: Serialize + DeserializeOwned {
+    /// Bits in each value, as used in namespace paths
+    fn bits() -> usize;
+}
+
+impl Element for u32 { fn bits() -> usize { 32 } }
+impl Element for u64 { fn bits() -> usize { 64 } }
+impl Element for [u64; 2] { fn bits() -> usize { 128 } }
+impl Element for [u64; 4] { fn bits() -> usize { 256 } }
+
+/// Values of a namespace, and their JSON representation
+///
+pub trait Key: Sized {
+    fn to_json(&self) -> Result<Value, Error>;
+
+    fn from_json(json: &Value) -> Result<Self, Error>;
+}
+
+macro_rules! element_key {
+    ($t:ty) => {
+        impl Key for $t {
+            fn to_json(&self) -> Result<Value, Error> {
+                encode_element(self)
+            }
+
+            fn from_json(json: &Value) -> Result<$t, Error> {
+                decode_element(json)
+            }
+        }
+    }
+}
+
+element_key!(u32);
+element_key!(u64);
+element_key!([u64; 2]);
+element_key!([u64; 4]);
+
+impl<T: Element> Key for Vec<T> {
+    fn to_json(&self) -> Result<Value, Error> {
+        let mut elements = Vec::with_capacity(self.len());
+        for element in self.iter() {
+            elements.push(try!(encode_element(element)));
+        }
+        Ok(Value::Array(elements))
+    }
+
+    fn from_json(json: &Value) -> Result<Vec<T>, Error> {
+        match *json {
+            Value::Array(ref elements) => elements.iter().map(decode_element).collect(),
+            _ => Err(unexpected("vector", json)),
+        }
+    }
+}
+
+fn encode_element<T: Element>(element: &T) -> Result<Value, Error> {
+    Ok(Value::String(base64::encode(&try!(encode(element)))))
+}
+
+fn decode_element<T: Element>(json: &Value) -> Result<T, Error> {
+    let bytes = match json.as_str().map(base64::decode) {
+        Some(Ok(bytes)) => bytes,
+        _ => return Err(unexpected("base64 value", json)),
+    };
+    decode(&bytes).map_err(|e| Error::Storage(format!("unable to decode response value: {}", e)))
+}
+
+fn unexpected(expected: &str, json: &Value) -> Error {
+    Error::Storage(format!("expected {} in response, found {}", expected, json))
+}
+
+/// Result of adding a value
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AddResult {
+    Ok,
+    /// The value was already indexed
+    Exists,
+    Err(String),
+}
+
+impl AddResult {
+    fn from_json(json: &Value) -> Result<AddResult, Error> {
+        match json.as_str() {
+            Some("ok") => Ok(AddResult::Ok),
+            Some("exists") => Ok(AddResult::Exists),
+            Some(s) if s.starts_with("err: ") => Ok(AddResult::Err(s[5..].to_string())),
+            _ => Err(unexpected("add result", json)),
+        }
+    }
+}
+
+/// Result of querying with a value
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryResult<T> {
+    /// Indexed values within the namespace's tolerance, nearest first
+    Ok(Vec<T>),
+    /// Nothing was found
+    None,
+    Err(String),
+}
+
+impl<T: Key> QueryResult<T> {
+    fn from_json(json: &Value) -> Result<QueryResult<T>, Error> {
+        match *json {
+            Value::Array(ref values) => {
+                let mut found = Vec::with_capacity(values.len());
+                for value in values.iter() {
+                    found.push(try!(T::from_json(value)));
+                }
+                Ok(QueryResult::Ok(found))
+            },
+            Value::String(ref s) if s == "none" => Ok(QueryResult::None),
+            Value::String(ref s) if s.starts_with("err: ") => Ok(QueryResult::Err(s[5..].to_string())),
+            _ => Err(unexpected("query result", json)),
+        }
+    }
+}
+
+/// Result of deleting a value
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeleteResult {
+    Ok,
+    /// The value wasn't indexed
+    NotFound,
+    Err(String),
+}
+
+impl DeleteResult {
+    fn from_json(json: &Value) -> Result<DeleteResult, Error> {
+        match json.as_str() {
+            Some("ok") => Ok(DeleteResult::Ok),
+            Some("not_found") => Ok(DeleteResult::NotFound),
+            Some(s) if s.starts_with("err: ") => Ok(DeleteResult::Err(s[5..].to_string())),
+            _ => Err(unexpected("delete result", json)),
+        }
+    }
+}
+
+/// Client of the server listening at a base URL
+///
+pub struct Client {
+    base: String,
+    http: hyper::Client,
+}
+
+impl Client {
+    /// Client of the server at `base` (ie `http://localhost:3000`)
+    ///
+    pub fn new(base: &str) -> Client {
+        Client{base: base.trim_end_matches('/').to_string(), http: hyper::Client::new()}
+    }
+
+    /// The binary namespace indexing `T` values within `tolerance`
+    ///
+    pub fn binary<T: Element>(&self, tolerance: usize, namespace: &str) -> Namespace<T> {
+        Namespace{
+            client: self,
+            path: format!("b/{}/{}/{}", T::bits(), tolerance, namespace),
+            marker: PhantomData,
+        }
+    }
+
+    /// The vector namespace indexing vectors of `dimensions` `T` elements
+    /// within `tolerance`
+    ///
+    pub fn vector<T: Element>(&self, dimensions: usize, tolerance: usize, namespace: &str) -> Namespace<Vec<T>> {
+        Namespace{
+            client: self,
+            path: format!("v/{}/{}/{}/{}", T::bits(), dimensions, tolerance, namespace),
+            marker: PhantomData,
+        }
+    }
+
+    /// POSTs `body` to `path`, returning the parsed response
+    ///
+    fn post(&self, path: &str, body: &Value) -> Result<Value, Error> {
+        let url = format!("{}/{}", self.base, path);
+        let body = try!(serde_json::to_string(body).map_err(|e| Error::Encoding(format!("{}", e))));
+
+        let mut res = try!(self.http.post(&url)
+            .header(ContentType::json())
+            .body(&body)
+            .send()
+            .map_err(|e| Error::Storage(format!("unable to reach {}: {}", url, e))));
+
+        let mut text = String::new();
+        try!(res.read_to_string(&mut text).map_err(|e| Error::Storage(format!("unable to read response from {}: {}", url, e))));
+
+        if !res.status.is_success() {
+            return Err(server_error(res.status.to_u16(), &text))
+        }
+        serde_json::from_str(&text).map_err(|e| Error::Storage(format!("unable to parse response from {}: {}", url, e)))
+    }
+}
+
+/// The error described by a failed response
+///
+/// DB errors are reported as JSON with an `error` and `message`; anything
+/// else (ie an unparseable request) is plain text.
+///
+fn server_error(status: u16, text: &str) -> Error {
+    let json: Value = match serde_json::from_str(text) {
+        Ok(json) => json,
+        Err(_) => return Error::Invalid(format!("server responded {}: {}", status, text)),
+    };
+    let message = json.get("message").and_then(|m| m.as_str()).unwrap_or(text).to_string();
+
+    match (json.get("error").and_then(|e| e.as_str()), status) {
+        (Some("too_expensive"), _) => {
+            let field = |name: &str| json.get(name).and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+            Error::TooExpensive(TooExpensive{estimated_candidates: field("estimated_candidates"), limit: field("limit")})
+        },
+        (_, 501) => Error::Unsupported(message),
+        (_, 500) | (_, 503) => Error::Storage(message),
+        _ => Error::Invalid(message),
+    }
+}
+
+/// A namespace of the server, indexing `T` values
+///
+/// Namespaces are created by the first value added to them.
+///
+pub struct Namespace<'a, T> {
+    client: &'a Client,
+    path: String,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T: Key> Namespace<'a, T> {
+    /// Adds `values`, returning the result for each in order
+    ///
+    pub fn add(&self, values: &[T]) -> Result<Vec<AddResult>, Error> {
+        let results = try!(self.post("add", values));
+        results.iter().map(AddResult::from_json).collect()
+    }
+
+    /// Queries with each of `values`, returning the results for each in order
+    ///
+    pub fn query(&self, values: &[T]) -> Result<Vec<QueryResult<T>>, Error> {
+        let results = try!(self.post("query", values));
+        results.iter().map(QueryResult::from_json).collect()
+    }
+
+    /// Deletes `values`, returning the result for each in order
+    ///
+    pub fn delete(&self, values: &[T]) -> Result<Vec<DeleteResult>, Error> {
+        let results = try!(self.post("delete", values));
+        results.iter().map(DeleteResult::from_json).collect()
+    }
+
+    /// POSTs `values` to the namespace's `action` endpoint, checking there's
+    /// a result for each
+    ///
+    fn post(&self, action: &str, values: &[T]) -> Result<Vec<Value>, Error> {
+        let mut body = Vec::with_capacity(values.len());
+        for value in values.iter() {
+            body.push(try!(value.to_json()));
+        }
+
+        match try!(self.client.post(&format!("{}/{}", action, self.path), &Value::Array(body))) {
+            Value::Array(results) if results.len() == values.len() => Ok(results),
+            json => Err(unexpected(&format!("{} {} results", values.len(), action), &json)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{self, Value};
+
+    use client::*;
+    use db::cost::TooExpensive;
+    use error::Error;
+
+    fn json(s: &str) -> Value {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        assert_eq!(0x0102u64.to_json(), Ok(json(r#""AAAAAAAAAQI=""#)));
+        assert_eq!(u64::from_json(&json(r#""AAAAAAAAAQI=""#)), Ok(0x0102));
+
+        let vector = vec![[1u64, 2], [3, 4]];
+        assert_eq!(Vec::<[u64; 2]>::from_json(&vector.to_json().unwrap()), Ok(vector));
+    }
+
+    #[test]
+    fn parses_results() {
+        assert_eq!(AddResult::from_json(&json(r#""exists""#)), Ok(AddResult::Exists));
+        assert_eq!(AddResult::from_json(&json(r#""err: bad value""#)), Ok(AddResult::Err("bad value".to_string())));
+        assert_eq!(DeleteResult::from_json(&json(r#""not_found""#)), Ok(DeleteResult::NotFound));
+
+        assert_eq!(QueryResult::<u64>::from_json(&json(r#"["AAAAAAAAAQI="]"#)), Ok(QueryResult::Ok(vec![0x0102])));
+        assert_eq!(QueryResult::<u64>::from_json(&json(r#""none""#)), Ok(QueryResult::None));
+        assert!(QueryResult::<u64>::from_json(&json("42")).is_err());
+    }
+
+    #[test]
+    fn maps_server_errors() {
+        let too_expensive = r#"{"error":"too_expensive","estimated_candidates":500,"limit":100,"message":"..."}"#;
+        assert_eq!(server_error(413, too_expensive), Error::TooExpensive(TooExpensive{estimated_candidates: 500, limit: 100}));
+        assert_eq!(server_error(500, r#"{"error":"storage","message":"disk full"}"#), Error::Storage("disk full".to_string()));
+        assert_eq!(server_error(400, "Unable to parse JSON"), Error::Invalid("server responded 400: Unable to parse JSON".to_string()));
+    }
+}
@@ -0,0 +1,271 @@
+//! Writing a value and its variants together
+//!
+//! An insert writes the value to the value store, then its variants to the
+//! variant store (and a remove deletes them in the same order), so a failure
+//! part way through used to leave variants referencing a missing value or a
+//! value no variant referenced.
+//!
+//! When both stores keep their entries in the same RocksDB (ie a namespace in
+//! the column families of a `SharedRocksDB`), every write is staged in one
+//! `Batch` and applied at once, so a crash leaves either all of them or none.
+//! Otherwise the writes are made one at a time, and undone if one of them
+//! fails.  Undoing is best-effort: a store which fails part way through
+//! usually fails again, and a crash during an insert or remove still leaves
+//! the stores inconsistent (see `Database::verify_and_repair`).  Without the
+//! `rocksdb` feature there's nothing to batch, so writes are always undone.
+
+use std::hash::Hash;
+
+#[cfg(feature = "rocksdb")]
+use db::column_family::Batch;
+use db::id_map::IDMap;
+use db::map_set::MapSet;
+use error::Error;
+
+/// Insert `value` under `id`, and `id` into the set at each variant of
+/// `groups`
+///
+/// Each group's first key is inserted, and if `id` wasn't already in its set
+/// so are the group's other keys.  Returns true if `id` was added to the set
+/// of any group's first key.
+///
+pub fn insert<I, ID, K, VS, MS>(value_store: &mut VS, variant_store: &mut MS, id: ID, value: I, groups: Vec<(K, Vec<K>)>) -> Result<bool, Error> where
+ID: Clone + Eq + Hash,
+K: Clone + Eq + Hash,
+VS: IDMap<ID, I>,
+MS: MapSet<K, ID>,
+{
+    #[cfg(feature = "rocksdb")]
+    {
+        let mut batch = Batch::new();
+        if value_store.join(&mut batch) && variant_store.join(&mut batch) {
+            try!(value_store.stage_insert(&batch, id.clone(), value));
+
+            let mut inserted = false;
+            for (head, rest) in groups.into_iter() {
+                if try!(variant_store.stage_insert(&batch, head, id.clone())) {
+                    for key in rest.into_iter() {
+                        try!(variant_store.stage_insert(&batch, key, id.clone()));
+                    }
+                    inserted = true;
+                }
+            }
+
+            try!(batch.commit());
+            return Ok(inserted)
+        }
+    }
+
+    let existed = try!(value_store.get(id.clone())).is_some();
+    try!(value_store.insert(id.clone(), value));
+
+    let mut added = Vec::new();
+    match insert_variants(variant_store, &id, groups, &mut added) {
+        Ok(inserted) => Ok(inserted),
+        Err(e) => {
+            // The original failure is reported whether or not undoing works
+            for key in added.iter() {
+                let _ = variant_store.remove(key, &id);
+            }
+            if !existed {
+                let _ = value_store.remove(&id);
+            }
+            Err(e)
+        },
+    }
+}
+
+/// Inserts `groups` one key at a time, recording the keys `id` was added to
+/// in `added`
+///
+fn insert_variants<ID, K, MS>(variant_store: &mut MS, id: &ID, groups: Vec<(K, Vec<K>)>, added: &mut Vec<K>) -> Result<bool, Error> where
+ID: Clone + Eq + Hash,
+K: Clone + Eq + Hash,
+MS: MapSet<K, ID>,
+{
+    let mut inserted = false;
+    for (head, rest) in groups.into_iter() {
+        if try!(variant_store.insert(head.clone(), id.clone())) {
+            added.push(head);
+            for key in rest.into_iter() {
+                if try!(variant_store.insert(key.clone(), id.clone())) {
+                    added.push(key);
+                }
+            }
+            inserted = true;
+        }
+    }
+    Ok(inserted)
+}
+
+/// Like `insert`, through shared references to stores which both support
+/// shared writes
+///
+/// Fails with `Error::Unsupported` before writing anything if either store
+/// doesn't.  The stores which do are in memory, and their writes can't fail
+/// part way, so nothing is ever rolled back.
+///
+pub fn insert_shared<I, ID, K, VS, MS>(value_store: &VS, variant_store: &MS, id: ID, value: I, groups: Vec<(K, Vec<K>)>) -> Result<bool, Error> where
+ID: Clone + Eq + Hash,
+K: Clone + Eq + Hash,
+VS: IDMap<ID, I>,
+MS: MapSet<K, ID>,
+{
+    if !value_store.shares_writes() || !variant_store.shares_writes() {
+        return Err(Error::Unsupported("stores don't support shared writes".to_string()))
+    }
+
+    try!(value_store.insert_shared(id.clone(), value));
+
+    let mut inserted = false;
+    for (head, rest) in groups.into_iter() {
+        if try!(variant_store.insert_shared(head, id.clone())) {
+            for key in rest.into_iter() {
+                try!(variant_store.insert_shared(key, id.clone()));
+            }
+            inserted = true;
+        }
+    }
+    Ok(inserted)
+}
+
+/// Remove the value under `id`, and `id` from the set at each of `keys`
+///
+/// Returns the number of sets `id` was removed from.
+///
+pub fn remove<I, ID, K, VS, MS>(value_store: &mut VS, variant_store: &mut MS, id: &ID, keys: &[K]) -> Result<usize, Error> where
+ID: Clone + Eq + Hash,
+K: Clone + Eq + Hash,
+VS: IDMap<ID, I>,
+MS: MapSet<K, ID>,
+{
+    #[cfg(feature = "rocksdb")]
+    {
+        let mut batch = Batch::new();
+        if value_store.join(&mut batch) && variant_store.join(&mut batch) {
+            try!(value_store.stage_remove(&batch, id));
+            let removed = try!(variant_store.stage_remove_many(&batch, keys, id));
+
+            try!(batch.commit());
+            return Ok(removed)
+        }
+    }
+
+    let existing = try!(value_store.get(id.clone()));
+    try!(value_store.remove(id));
+
+    let mut removed = Vec::new();
+    let mut failure = None;
+    for key in keys.iter() {
+        match variant_store.remove(key, id) {
+            Ok(true) => removed.push(key),
+            Ok(false) => (),
+            Err(e) => {
+                failure = Some(e);
+                break
+            },
+        }
+    }
+
+    match failure {
+        None => Ok(removed.len()),
+        Some(e) => {
+            // The original failure is reported whether or not undoing works
+            for key in removed.into_iter() {
+                let _ = variant_store.insert(key.clone(), id.clone());
+            }
+            if let Some(value) = existing {
+                let _ = value_store.insert(id.clone(), value);
+            }
+            Err(e)
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use db::atomic;
+    use db::column_family::SharedRocksDB;
+    use db::compaction::StoreOptions;
+    use db::id_map::{self, IDMap};
+    use db::map_set::{self, MapSet, InMemoryHash};
+    use db::temp_dir::TempDir;
+    use error::Error;
+    use db::hashing::State;
+
+    /// A variant store which fails every insert or remove at `broken`
+    ///
+    struct Failing {
+        inner: InMemoryHash<u8, u64>,
+        broken: Option<u8>,
+    }
+
+    impl MapSet<u8, u64> for Failing {
+        fn insert(&mut self, key: u8, value: u64) -> Result<bool, Error> {
+            if self.broken == Some(key) {
+                return Err(Error::Storage("disk full".to_string()))
+            }
+            self.inner.insert(key, value)
+        }
+
+        fn get(&self, key: &u8) -> Result<Option<HashSet<u64, State>>, Error> {
+            self.inner.get(key)
+        }
+
+        fn remove(&mut self, key: &u8, value: &u64) -> Result<bool, Error> {
+            if self.broken == Some(*key) {
+                return Err(Error::Storage("disk full".to_string()))
+            }
+            self.inner.remove(key, value)
+        }
+    }
+
+    #[test]
+    fn failed_inserts_are_rolled_back() {
+        let mut values: id_map::HashMap<u64, String> = id_map::HashMap::new();
+        let mut variants = Failing{inner: InMemoryHash::new(), broken: Some(2)};
+
+        let inserted = atomic::insert(&mut values, &mut variants, 1, "a".to_string(), vec![(0, vec![1, 2])]);
+
+        assert_eq!(inserted, Err(Error::Storage("disk full".to_string())));
+        assert_eq!(values.get(1), Ok(None));
+        assert_eq!(variants.get(&0), Ok(None));
+        assert_eq!(variants.get(&1), Ok(None));
+    }
+
+    #[test]
+    fn failed_removes_are_rolled_back() {
+        let mut values: id_map::HashMap<u64, String> = id_map::HashMap::new();
+        let mut variants = Failing{inner: InMemoryHash::new(), broken: None};
+        atomic::insert(&mut values, &mut variants, 1, "a".to_string(), vec![(0, vec![1, 2])]).unwrap();
+        variants.broken = Some(1);
+
+        let removed = atomic::remove(&mut values, &mut variants, &1, &[0, 1, 2]);
+
+        assert_eq!(removed, Err(Error::Storage("disk full".to_string())));
+        assert_eq!(values.get(1), Ok(Some("a".to_string())));
+        assert_eq!(variants.get(&0), Ok(Some(vec![1].into_iter().collect())));
+    }
+
+    #[test]
+    fn stores_sharing_a_rocksdb_write_together() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+
+        let mut values: id_map::RocksDB<u64, String> = id_map::RocksDB::in_family(shared.clone(), "ns/id_map").unwrap();
+        let mut variants: map_set::RocksDB<u8, u64> = map_set::RocksDB::in_family(shared.clone(), "ns/map_set").unwrap();
+
+        assert_eq!(atomic::insert(&mut values, &mut variants, 1, "a".to_string(), vec![(0, vec![1]), (2, vec![])]), Ok(true));
+        assert_eq!(atomic::insert(&mut values, &mut variants, 1, "a".to_string(), vec![(0, vec![1]), (2, vec![])]), Ok(false));
+        assert_eq!(values.get(1), Ok(Some("a".to_string())));
+        assert_eq!(variants.get(&1), Ok(Some(vec![1].into_iter().collect())));
+
+        assert_eq!(atomic::remove(&mut values, &mut variants, &1, &[0, 1, 2, 3]), Ok(3));
+        assert_eq!(values.get(1), Ok(None));
+        assert_eq!(variants.get(&0), Ok(None));
+    }
+}
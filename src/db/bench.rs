@@ -1,101 +1,100 @@
-extern crate test;
-extern crate rand;
-
-use self::rand::*;
-
-use db::*;
-use db::hashing::*;
-use db::deletion_db::*;
-use db::substitution_db::*;
-
-#[bench]
-fn insert_large_value(b: &mut test::Bencher) {
-    let mut p: DeletionDB<Vec<u8>, Hashed<Vec<u8>>> = Database::new(100, 4);
-
-    let mut rng = thread_rng();
-    let mut value = Vec::with_capacity(100);
-    for _ in (0..100) {
-        value.push(rng.gen());
-    }
-    let hashed_value = Hashed::new(value);
-
-    b.iter(|| {
-        p.insert(hashed_value.clone());
-    })
+//! Setup for benchmarks
+//!
+//! The benchmarks in `benches/` run on stable Rust with criterion (`cargo
+//! bench`).  These helpers build the data and databases they measure, so
+//! each value type, tolerance & backend is set up the same way - and so
+//! embedders can benchmark their own workloads alike.
+//!
+//! Data is generated from a seed (see `db::testing`), so every run measures
+//! the same values and queries.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let values: Vec<u64> = bench::values(10000, 64, 1);
+//! let queries = bench::queries(&values, 100, 64, 4, 2);
+//! let db = bench::populated(64, 4, Backend::InMemory, &values);
+//!
+//! for query in queries.iter() {
+//!     db.get(query).unwrap();
+//! }
+//! ```
+
+use db::{Database, Factory, StorageBackend};
+use db::testing::{self, Generate, Rng};
+
+/// Where a benchmarked DB keeps its index
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    InMemory,
+    /// A RocksDB in a temporary directory, removed when the DB is dropped
+    #[cfg(feature = "rocksdb")]
+    RocksDB,
 }
 
-#[bench]
-fn insert_new_value(b: &mut test::Bencher) {
-    let mut p: SubstitutionDB<u64, u64> = Database::new(64, 4);
-
-    let mut rng = thread_rng();
-    let value = rng.gen();
-
-    b.iter(|| {
-        p.insert(value);
-    })
-}
-
-#[bench]
-fn insert_existing_value(b: &mut test::Bencher) {
-    let mut p: SubstitutionDB<u64, u64> = Database::new(64, 4);
+impl Backend {
+    /// Every backend this build supports
+    ///
+    pub fn all() -> Vec<Backend> {
+        let mut all = vec![Backend::InMemory];
+        #[cfg(feature = "rocksdb")]
+        all.push(Backend::RocksDB);
+        all
+    }
 
-    let mut rng = thread_rng();
-    let value = rng.gen();
-    p.insert(value);
+    /// Name used in benchmark IDs
+    ///
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Backend::InMemory => "in-memory",
+            #[cfg(feature = "rocksdb")]
+            Backend::RocksDB => "rocksdb",
+        }
+    }
 
-    b.iter(|| {
-        p.insert(value);
-    })
+    pub fn storage(&self) -> StorageBackend {
+        match *self {
+            Backend::InMemory => StorageBackend::InMemory,
+            #[cfg(feature = "rocksdb")]
+            Backend::RocksDB => StorageBackend::TempRocksDB,
+        }
+    }
 }
 
-#[bench]
-fn find_existing_value(b: &mut test::Bencher) {
-    let mut p: SubstitutionDB<u64, u64> = Database::new(64, 4);
-
-    let mut rng = thread_rng();
-    let value = rng.gen();
-    p.insert(value);
-
-    b.iter(|| {
-        p.get(&value);
-    })
+/// `n` random values of `dimensions` dimensions
+///
+pub fn values<T: Generate>(n: usize, dimensions: usize, seed: u64) -> Vec<T> {
+    let mut rng = Rng::new(seed);
+    (0..n).map(|_| T::generate(&mut rng, dimensions)).collect()
 }
 
-#[bench]
-fn find_missing_value(b: &mut test::Bencher) {
-    let p: SubstitutionDB<u64, u64> = Database::new(64, 4);
-
-    let mut rng = thread_rng();
-    let value = rng.gen();
-
-    b.iter(|| {
-        p.get(&value);
-    })
+/// `n` queries, each within `tolerance` of one of `values`
+///
+/// Panics if `values` is empty.
+///
+pub fn queries<T: Generate + Clone>(values: &[T], n: usize, dimensions: usize, tolerance: usize, seed: u64) -> Vec<T> {
+    let mut rng = Rng::new(seed);
+    (0..n).map(|_| {
+        let value = rng.below(values.len());
+        testing::near(&mut rng, &values[value], dimensions, tolerance)
+    }).collect()
 }
 
-#[bench]
-fn remove_missing_value(b: &mut test::Bencher) {
-    let mut p: SubstitutionDB<u64, u64> = Database::new(64, 4);
-
-    let mut rng = thread_rng();
-    let value = rng.gen();
-
-    b.iter(|| {
-        p.remove(&value);
-    })
+/// An empty DB
+///
+/// Panics if its stores can't be opened.
+///
+pub fn empty<T: Factory>(dimensions: usize, tolerance: usize, backend: Backend) -> Box<Database<T>> {
+    T::build(dimensions, tolerance, backend.storage()).unwrap()
 }
 
-#[bench]
-fn remove_existing_value(b: &mut test::Bencher) {
-    let mut p: SubstitutionDB<u64, u64> = Database::new(64, 4);
-
-    let mut rng = thread_rng();
-    let value = rng.gen();
-    p.insert(value);
-
-    b.iter(|| {
-        p.remove(&value);
-    })
+/// A DB holding `values`
+///
+/// Panics if the values can't be inserted, as there's nothing to measure.
+///
+pub fn populated<T: Factory + Clone>(dimensions: usize, tolerance: usize, backend: Backend, values: &[T]) -> Box<Database<T>> {
+    let mut db = empty(dimensions, tolerance, backend);
+    db.bulk_insert(values.to_vec()).expect("unable to insert benchmark values");
+    db
 }
-
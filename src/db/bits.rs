@@ -0,0 +1,196 @@
+//! Dense bit vectors of arbitrary width
+//!
+//! `Vec<u8>` is indexed element-wise by default - each byte is one dimension.
+//! Wrapping the bytes in `Bits` instead treats every bit as a dimension, so
+//! for example a 512-bit fingerprint can be stored as `Bits::from(vec![..; 64])`.
+//! Windows over `Bits` are also `Bits`, so partitions may be wider than the
+//! 64 bits supported by the integer window types; up to 256 bits are stored
+//! inline without allocating.
+//!
+//! Bit `i` is bit `i % 8` (from least significant) of byte `i / 8`.
+
+use std::cmp::min;
+use std::iter::FromIterator;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use smallvec::SmallVec;
+
+use db::hamming::Hamming;
+use db::window::Windowable;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Bits {
+    bytes: SmallVec<[u8; 32]>,
+}
+
+impl Bits {
+    /// All-zero value of at least `dimensions` bits
+    pub fn zeros(dimensions: usize) -> Bits {
+        Bits{bytes: SmallVec::from_iter((0..(dimensions + 7) / 8).map(|_| 0u8))}
+    }
+
+    /// Number of dimensions (ie bits) in the value
+    pub fn dimensions(&self) -> usize {
+        8 * self.bytes.len()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        (self.bytes[i / 8] >> (i % 8)) & 1 == 1
+    }
+
+    pub fn flip(&mut self, i: usize) {
+        self.bytes[i / 8] ^= 1 << (i % 8);
+    }
+}
+
+impl From<Vec<u8>> for Bits {
+    fn from(bytes: Vec<u8>) -> Bits {
+        Bits{bytes: SmallVec::from_iter(bytes.into_iter())}
+    }
+}
+
+impl<'a> From<&'a [u8]> for Bits {
+    fn from(bytes: &'a [u8]) -> Bits {
+        Bits{bytes: SmallVec::from_iter(bytes.iter().cloned())}
+    }
+}
+
+impl Hamming for Bits {
+    fn hamming(&self, other: &Bits) -> usize {
+        self.bytes.iter().zip(other.bytes.iter()).fold(0, |h, (a, b)| h + (a ^ b).count_ones() as usize)
+    }
+
+    fn hamming_lte(&self, other: &Bits, bound: usize) -> bool {
+        let mut h = 0;
+        for (a, b) in self.bytes.iter().zip(other.bytes.iter()) {
+            h += (a ^ b).count_ones() as usize;
+            if h > bound {
+                return false
+            }
+        }
+        true
+    }
+
+    fn hamming_indices(&self, other: &Bits) -> Vec<usize> {
+        let dimensions = min(self.dimensions(), other.dimensions());
+        (0..dimensions).filter(|&i| self.get(i) != other.get(i)).collect()
+    }
+}
+
+impl Windowable<Bits> for Bits {
+    fn window(&self, start_dimension: usize, dimensions: usize) -> Bits {
+        // source end in range
+        assert!(start_dimension + dimensions <= self.dimensions());
+
+        let mut out = Bits::zeros(dimensions);
+        let byte_offset = start_dimension / 8;
+        let shift = start_dimension % 8;
+
+        for i in 0..out.bytes.len() {
+            let low = self.bytes[byte_offset + i] >> shift;
+            let high = match self.bytes.get(byte_offset + i + 1) {
+                Some(b) if shift > 0 => b << (8 - shift),
+                _ => 0,
+            };
+            out.bytes[i] = low | high;
+        }
+
+        // Clear any bits past the end of the window
+        let remainder = dimensions % 8;
+        if remainder > 0 {
+            let last = out.bytes.len() - 1;
+            out.bytes[last] &= (1u8 << remainder) - 1;
+        }
+
+        out
+    }
+}
+
+impl Serialize for Bits {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.bytes[..].serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bits {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Bits, D::Error> {
+        Vec::<u8>::deserialize(d).map(Bits::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::bits::*;
+    use db::{Database, Factory, StorageBackend};
+    use db::hamming::Hamming;
+    use db::window::Windowable;
+    use db::substitution::SubstitutionVariant;
+
+    #[test]
+    fn hamming_counts_bits() {
+        let a = Bits::from(vec![0b00000000u8, 0b00000000u8]);
+        let b = Bits::from(vec![0b00000011u8, 0b10000000u8]);
+
+        assert_eq!(a.hamming(&b), 3);
+        assert_eq!(a.hamming_indices(&b), vec![0, 1, 15]);
+        assert!(a.hamming_lte(&b, 3));
+        assert!(!a.hamming_lte(&b, 2));
+    }
+
+    #[test]
+    fn window_spans_bytes() {
+        let a = Bits::from(vec![0b11110000u8, 0b00001111u8, 0b11111111u8]);
+
+        assert_eq!(a.window(4, 8), Bits::from(vec![0b11111111u8]));
+        assert_eq!(a.window(4, 6), Bits::from(vec![0b00111111u8]));
+        assert_eq!(a.window(0, 24), a);
+        assert_eq!(a.window(8, 12), Bits::from(vec![0b00001111u8, 0b00001111u8]));
+    }
+
+    #[test]
+    fn window_wider_than_64_bits() {
+        let mut a = Bits::zeros(512);
+        a.flip(100);
+        a.flip(300);
+
+        let w = a.window(96, 256);
+        assert_eq!(w.dimensions(), 256);
+        assert!(w.get(4));
+        assert!(w.get(204));
+        assert_eq!(w.hamming(&Bits::zeros(256)), 2);
+    }
+
+    #[test]
+    fn substitution_variants_flip_each_bit() {
+        let a = Bits::zeros(128);
+        let variants: Vec<Bits> = a.substitution_variants(128).collect();
+
+        assert_eq!(variants.len(), 128);
+        for (i, v) in variants.iter().enumerate() {
+            assert_eq!(v.hamming_indices(&a), vec![i]);
+        }
+    }
+
+    #[test]
+    fn finds_512_bit_values() {
+        let mut db = Bits::build(512, 2, StorageBackend::InMemory).unwrap();
+        let mut a = Bits::zeros(512);
+        a.flip(7);
+        a.flip(400);
+        let mut b = a.clone();
+        b.flip(200);
+        b.flip(201);
+        b.flip(202);
+
+        db.insert(a.clone());
+        db.insert(b.clone());
+
+        let found = db.get(&Bits::zeros(512)).unwrap();
+        assert!(found.contains(&a));
+        assert!(!found.contains(&b));
+    }
+}
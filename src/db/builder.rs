@@ -0,0 +1,692 @@
+//! Checked construction of databases
+//!
+//! `Factory::build` selects the window type for a value type, tolerance and
+//! storage backend, failing only once it's asked for something it can't
+//! provide.  `DbBuilder` wraps the same selection behind named settings and
+//! validates them first, so a bad configuration is reported as what's wrong
+//! with it rather than as an unsupported window or store.  It's
+//! the intended way to construct a database; the typemap aliases
+//! (`U64wU16InMemory` etc) remain for callers who need a concrete DB type.
+//!
+//! Databases built over RocksDB record their strategy, dimensions and
+//! tolerance in their stores, and `DbBuilder::build` refuses to re-open them
+//! with different ones (see `db::params`).
+//!
+//! Every substitution `Factory` picks its window type from
+//! `substitution::partition_width`, the same partitioning the DB itself uses,
+//! so the choice can't drift from the partitions actually built.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let db = DbBuilder::<u64>::new()
+//!     .dimensions(64)
+//!     .tolerance(4)
+//!     .backend(StorageBackend::InMemory)
+//!     .strategy(Strategy::Substitution)
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use std::fmt;
+use std::error;
+use std::mem;
+use std::marker::PhantomData;
+
+use db::{Database, Factory, StorageBackend, RocksDBConfig};
+use db::deletion::Dvec;
+use db::id_map::{IDStrategy, ID128};
+use db::params::{self, Params, ParamsMismatch};
+use db::variant_budget::{VariantBudget, VariantEstimate, VariantExplosion};
+use error::Error;
+
+/// How values are indexed
+///
+/// Binary values (`u64`, `[u64; 2]`, ...) are indexed by substitution,
+/// vectors of values by deletion.  See the `db` module docs for the trade-offs.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Strategy {
+    Substitution,
+    Deletion,
+}
+
+/// Estimated cost of each insert into a DB of `T` indexed by `strategy`, to
+/// check against a `VariantBudget`
+///
+pub fn variant_estimate<T: Factory>(dimensions: usize, tolerance: usize, strategy: Strategy, id128: bool) -> VariantEstimate {
+    // Substitution windows and IDs are no larger than the value itself,
+    // deletion variants and IDs are both hashes (or for binary values, a
+    // window and the deleted bit's index)
+    let id_bytes = if id128 { mem::size_of::<ID128>() } else { mem::size_of::<u64>() };
+    let variant_bytes = match (T::strategy(), strategy) {
+        (Strategy::Substitution, Strategy::Deletion) => 2 * mem::size_of::<T>() + mem::size_of::<u8>(),
+        (Strategy::Substitution, Strategy::Substitution) => 2 * mem::size_of::<T>(),
+        (Strategy::Deletion, _) => mem::size_of::<Dvec>() + id_bytes,
+    };
+    VariantEstimate::new(dimensions, tolerance, variant_bytes)
+}
+
+/// Returned when a `DbBuilder` is asked to build an unsupported database
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    MissingDimensions,
+    MissingTolerance,
+    /// The value type can't be indexed with the requested strategy
+    UnsupportedStrategy{requested: Strategy, supported: Strategy},
+    /// The value type has fewer dimensions than requested
+    TooManyDimensions{requested: usize, max: usize},
+    /// Tolerance must be less than the number of dimensions
+    ToleranceTooLarge{tolerance: usize, dimensions: usize},
+    /// Inserts would write more variants than the builder's `VariantBudget`
+    VariantExplosion(VariantExplosion),
+    /// An ignored dimension is beyond the number of dimensions
+    IgnoredDimensionOutOfRange{dimension: usize, dimensions: usize},
+    /// The value type can't be identified with the requested strategy
+    UnsupportedIDStrategy(IDStrategy),
+    /// The value type can't be identified with 128-bit IDs
+    UnsupportedID128,
+    /// Only substitution databases can use compact variants
+    UnsupportedCompactVariants,
+    /// The value type can't be stored inline in the variant store
+    UnsupportedInlineValues,
+    /// Only RocksDB backends (dedicated or shared) cache or filter variant
+    /// sets
+    UnsupportedVariantLayers,
+    /// The backend's stores were created with other parameters (see
+    /// `db::params`)
+    ParamsMismatch(ParamsMismatch),
+    /// The backend's stores couldn't be opened
+    Storage(Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            BuildError::MissingDimensions => write!(f, "dimensions must be set"),
+            BuildError::MissingTolerance => write!(f, "tolerance must be set"),
+            BuildError::UnsupportedStrategy{requested, supported} => {
+                write!(f, "{:?} indexing is not supported for this type (use {:?})", requested, supported)
+            },
+            BuildError::TooManyDimensions{requested, max} => {
+                write!(f, "{} dimensions requested, type supports at most {}", requested, max)
+            },
+            BuildError::ToleranceTooLarge{tolerance, dimensions} => {
+                write!(f, "tolerance {} must be less than dimensions {}", tolerance, dimensions)
+            },
+            BuildError::VariantExplosion(ref e) => write!(f, "{}", e),
+            BuildError::IgnoredDimensionOutOfRange{dimension, dimensions} => {
+                write!(f, "ignored dimension {} must be less than dimensions {}", dimension, dimensions)
+            },
+            BuildError::UnsupportedIDStrategy(strategy) => {
+                write!(f, "{:?} identifiers are not supported for this type", strategy)
+            },
+            BuildError::UnsupportedID128 => write!(f, "128-bit identifiers are not supported for this type"),
+            BuildError::UnsupportedCompactVariants => write!(f, "compact variants are only supported by substitution indexing"),
+            BuildError::UnsupportedInlineValues => write!(f, "inline values are not supported for this type"),
+            BuildError::UnsupportedVariantLayers => write!(f, "variant caches and filters are only supported over RocksDB"),
+            BuildError::ParamsMismatch(ref e) => write!(f, "{}", e),
+            BuildError::Storage(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for BuildError {
+    fn description(&self) -> &str {
+        "unsupported database configuration"
+    }
+}
+
+/// Builder for databases over values of type `T`
+///
+/// `dimensions` and `tolerance` are required.  The backend defaults to
+/// `InMemory`, the strategy to the one supported by `T`, the variant budget
+/// to `VariantBudget::default()`, no dimensions are ignored and values are
+/// identified by their type's default 64-bit `ToID`.
+///
+pub struct DbBuilder<T: Factory> {
+    dimensions: Option<usize>,
+    tolerance: Option<usize>,
+    backend: StorageBackend,
+    strategy: Option<Strategy>,
+    variant_budget: VariantBudget,
+    ignored_dimensions: Vec<usize>,
+    id_strategy: Option<IDStrategy>,
+    id128: bool,
+    compact_variants: bool,
+    inline_values: bool,
+    variant_cache_sets: Option<usize>,
+    variant_filter_counters: Option<usize>,
+    marker: PhantomData<T>,
+}
+
+impl<T: Factory> DbBuilder<T> {
+    pub fn new() -> DbBuilder<T> {
+        DbBuilder {
+            dimensions: None,
+            tolerance: None,
+            backend: StorageBackend::InMemory,
+            strategy: None,
+            variant_budget: Default::default(),
+            ignored_dimensions: Vec::new(),
+            id_strategy: None,
+            id128: false,
+            compact_variants: false,
+            inline_values: false,
+            variant_cache_sets: None,
+            variant_filter_counters: None,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn dimensions(mut self, dimensions: usize) -> DbBuilder<T> {
+        self.dimensions = Some(dimensions);
+        self
+    }
+
+    pub fn tolerance(mut self, tolerance: usize) -> DbBuilder<T> {
+        self.tolerance = Some(tolerance);
+        self
+    }
+
+    pub fn backend(mut self, backend: StorageBackend) -> DbBuilder<T> {
+        self.backend = backend;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> DbBuilder<T> {
+        self.strategy = Some(strategy);
+        self
+    }
+
+    pub fn variant_budget(mut self, budget: VariantBudget) -> DbBuilder<T> {
+        self.variant_budget = budget;
+        self
+    }
+
+    /// Ignore differences in `dimensions` (see `db::dimension_mask`)
+    ///
+    pub fn ignored_dimensions(mut self, dimensions: Vec<usize>) -> DbBuilder<T> {
+        self.ignored_dimensions = dimensions;
+        self
+    }
+
+    /// Identify values with `strategy` (see `id_map::IDStrategy`)
+    ///
+    /// Whether the value type supports `strategy` is only checked by `build`.
+    ///
+    pub fn id_strategy(mut self, strategy: IDStrategy) -> DbBuilder<T> {
+        self.id_strategy = Some(strategy);
+        self
+    }
+
+    /// Identify values with 128-bit IDs (see `id_map::ID128`)
+    ///
+    /// Only vector (deletion) databases support them.
+    ///
+    pub fn id128(mut self) -> DbBuilder<T> {
+        self.id128 = true;
+        self
+    }
+
+    /// Derive the one variants of narrow partitions at query time rather than
+    /// storing them (see `Database::set_compact_variants`)
+    ///
+    pub fn compact_variants(mut self) -> DbBuilder<T> {
+        self.compact_variants = true;
+        self
+    }
+
+    /// Store values inline in the variant store, as their own identifiers
+    /// (see `Factory::build_inline`)
+    ///
+    /// Can't be combined with 128-bit IDs, an ID strategy or indexing binary
+    /// values by deletion.
+    ///
+    pub fn inline_values(mut self) -> DbBuilder<T> {
+        self.inline_values = true;
+        self
+    }
+
+    /// Cache up to `sets` of the variant store's sets in memory (see
+    /// `map_set::Cached`), overriding the backend's `RocksDBConfig`
+    ///
+    /// Only RocksDB backends support a cache.
+    ///
+    pub fn variant_cache(mut self, sets: usize) -> DbBuilder<T> {
+        self.variant_cache_sets = Some(sets);
+        self
+    }
+
+    /// Keep a bloom filter of `counters` counters per partition over the
+    /// variant store's keys (see `map_set::Bloom`), overriding the backend's
+    /// `RocksDBConfig`
+    ///
+    /// Only RocksDB backends support a filter.
+    ///
+    pub fn variant_filter(mut self, counters: usize) -> DbBuilder<T> {
+        self.variant_filter_counters = Some(counters);
+        self
+    }
+
+    /// The backend's RocksDB config, if it has one
+    ///
+    fn rocksdb_config(&mut self) -> Option<&mut RocksDBConfig> {
+        match self.backend {
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::RocksDB(_, ref mut config) => Some(config),
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::SharedRocksDB(_, _, ref mut config) => Some(config),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the backend's variant store can be cached or filtered
+    ///
+    fn layers_variants(&self) -> bool {
+        match self.backend {
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::RocksDB(..) | StorageBackend::SharedRocksDB(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if binary values are to be indexed by deletion (see
+    /// `Factory::build_deletion`)
+    ///
+    fn deletes_binary(&self) -> bool {
+        self.strategy == Some(Strategy::Deletion) && T::strategy() == Strategy::Substitution
+    }
+
+    /// Checks the settings without building anything
+    ///
+    pub fn validate(&self) -> Result<(), BuildError> {
+        let dimensions = try!(self.dimensions.ok_or(BuildError::MissingDimensions));
+        let tolerance = try!(self.tolerance.ok_or(BuildError::MissingTolerance));
+
+        if let Some(requested) = self.strategy {
+            let supported = requested == T::strategy() || (requested == Strategy::Deletion && T::supports_deletion());
+            if !supported {
+                return Err(BuildError::UnsupportedStrategy{requested: requested, supported: T::strategy()})
+            }
+        }
+
+        if let Some(max) = T::max_dimensions() {
+            if dimensions > max {
+                return Err(BuildError::TooManyDimensions{requested: dimensions, max: max})
+            }
+        }
+
+        if tolerance >= dimensions {
+            return Err(BuildError::ToleranceTooLarge{tolerance: tolerance, dimensions: dimensions})
+        }
+
+        if let Some(&dimension) = self.ignored_dimensions.iter().find(|&&d| d >= dimensions) {
+            return Err(BuildError::IgnoredDimensionOutOfRange{dimension: dimension, dimensions: dimensions})
+        }
+
+        if self.id128 && (!T::supports_id128() || self.deletes_binary()) {
+            return Err(BuildError::UnsupportedID128)
+        }
+
+        if self.compact_variants && (T::strategy() != Strategy::Substitution || self.deletes_binary()) {
+            return Err(BuildError::UnsupportedCompactVariants)
+        }
+
+        let inline_conflict = self.id128 || self.id_strategy.is_some() || self.deletes_binary();
+        if self.inline_values && (!T::supports_inline_values() || inline_conflict) {
+            return Err(BuildError::UnsupportedInlineValues)
+        }
+
+        let layered = self.variant_cache_sets.is_some() || self.variant_filter_counters.is_some();
+        if layered && !self.layers_variants() {
+            return Err(BuildError::UnsupportedVariantLayers)
+        }
+
+        let strategy = if self.deletes_binary() { Strategy::Deletion } else { T::strategy() };
+        let estimate = variant_estimate::<T>(dimensions, tolerance, strategy, self.id128);
+        try!(self.variant_budget.check(estimate).map_err(BuildError::VariantExplosion));
+
+        Ok(())
+    }
+
+    /// Builds the database, once the settings are validated and checked
+    /// against the parameters recorded by the backend's stores
+    ///
+    pub fn build(mut self) -> Result<Box<Database<T>>, BuildError> {
+        try!(self.validate());
+
+        if let Some(sets) = self.variant_cache_sets {
+            self.rocksdb_config().expect("variant cache was validated").variant_cache_sets = Some(sets);
+        }
+        if let Some(counters) = self.variant_filter_counters {
+            self.rocksdb_config().expect("variant filter was validated").variant_filter_counters = Some(counters);
+        }
+
+        let params = Params{
+            strategy: if self.deletes_binary() { Strategy::Deletion } else { T::strategy() },
+            dimensions: self.dimensions.unwrap(),
+            tolerance: self.tolerance.unwrap(),
+        };
+        match params::check(&self.backend, &params) {
+            Ok(()) => (),
+            Err(Error::ParamsMismatch(e)) => return Err(BuildError::ParamsMismatch(e)),
+            Err(e) => return Err(BuildError::Storage(e)),
+        }
+
+        let built = if self.id128 {
+            T::build_id128(self.dimensions.unwrap(), self.tolerance.unwrap(), self.backend)
+        } else if self.deletes_binary() {
+            T::build_deletion(self.dimensions.unwrap(), self.tolerance.unwrap(), self.backend)
+        } else if self.inline_values {
+            T::build_inline(self.dimensions.unwrap(), self.tolerance.unwrap(), self.backend)
+        } else {
+            T::build(self.dimensions.unwrap(), self.tolerance.unwrap(), self.backend)
+        };
+        let mut db = try!(built.map_err(BuildError::Storage));
+        if !self.ignored_dimensions.is_empty() {
+            db.set_ignored_dimensions(self.ignored_dimensions).expect("ignored dimensions were validated");
+        }
+        if let Some(strategy) = self.id_strategy {
+            try!(db.set_id_strategy(strategy).map_err(|_| BuildError::UnsupportedIDStrategy(strategy)));
+        }
+        if self.compact_variants {
+            try!(db.set_compact_variants().map_err(|_| BuildError::UnsupportedCompactVariants));
+        }
+        Ok(db)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, Factory, StorageBackend};
+    #[cfg(feature = "rocksdb")]
+    use db::RocksDBConfig;
+    use db::builder::*;
+    use db::id_map::IDStrategy;
+    #[cfg(feature = "rocksdb")]
+    use db::temp_dir::TempDir;
+    use db::variant_budget::VariantBudget;
+    use error::Error;
+
+    #[test]
+    fn builds_substitution_db() {
+        let mut db = DbBuilder::<u64>::new()
+            .dimensions(64)
+            .tolerance(4)
+            .backend(StorageBackend::InMemory)
+            .strategy(Strategy::Substitution)
+            .build()
+            .unwrap();
+
+        db.insert(0b0111).unwrap();
+        assert!(db.get(&0b0000).unwrap().unwrap().contains(&0b0111));
+    }
+
+    #[test]
+    fn builds_deletion_db() {
+        let mut db = DbBuilder::<Vec<u8>>::new()
+            .dimensions(8)
+            .tolerance(2)
+            .build()
+            .unwrap();
+
+        db.insert(vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert!(db.get(&vec![1, 2, 3, 4, 5, 6, 0, 0]).unwrap().unwrap().contains(&vec![1, 2, 3, 4, 5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn builds_binary_deletion_db() {
+        let mut db = DbBuilder::<u64>::new()
+            .dimensions(64)
+            .tolerance(4)
+            .strategy(Strategy::Deletion)
+            .build()
+            .unwrap();
+
+        db.insert(0b0111).unwrap();
+        db.insert(0b1111_0000).unwrap();
+        let found = db.get(&0b0000).unwrap().unwrap();
+        assert!(found.contains(&0b0111));
+        assert!(found.contains(&0b1111_0000));
+
+        db.insert(0b1111_1111).unwrap();
+        assert!(!db.get(&0b0000).unwrap().unwrap().contains(&0b1111_1111));
+    }
+
+    #[test]
+    fn builds_every_window_type() {
+        // Down to one 256-bit partition, so each window type is chosen
+        for &tolerance in &[0, 1, 3, 7, 15, 31] {
+            let mut backends = vec![StorageBackend::InMemory];
+            #[cfg(feature = "rocksdb")]
+            backends.push(StorageBackend::TempRocksDB);
+
+            for backend in backends {
+                let mut db = DbBuilder::<[u64; 4]>::new()
+                    .dimensions(256)
+                    .tolerance(tolerance)
+                    .backend(backend)
+                    .strategy(Strategy::Substitution)
+                    .build()
+                    .unwrap();
+
+                db.insert([0b0111, 0, 0, 1]).unwrap();
+                assert!(db.get(&[0b0111, 0, 0, 1]).unwrap().unwrap().contains(&[0b0111, 0, 0, 1]));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert_eq!(DbBuilder::<u64>::new().tolerance(4).validate(), Err(BuildError::MissingDimensions));
+        assert_eq!(DbBuilder::<u64>::new().dimensions(64).validate(), Err(BuildError::MissingTolerance));
+        assert_eq!(
+            DbBuilder::<u32>::new().dimensions(64).tolerance(4).validate(),
+            Err(BuildError::TooManyDimensions{requested: 64, max: 32})
+        );
+        assert_eq!(
+            DbBuilder::<u8>::new().dimensions(8).tolerance(8).validate(),
+            Err(BuildError::ToleranceTooLarge{tolerance: 8, dimensions: 8})
+        );
+        assert_eq!(
+            DbBuilder::<Vec<u8>>::new().dimensions(8).tolerance(2).strategy(Strategy::Substitution).validate(),
+            Err(BuildError::UnsupportedStrategy{requested: Strategy::Substitution, supported: Strategy::Deletion})
+        );
+        assert_eq!(
+            DbBuilder::<u64>::new().dimensions(64).tolerance(4).strategy(Strategy::Deletion).id128().validate(),
+            Err(BuildError::UnsupportedID128)
+        );
+        assert_eq!(
+            DbBuilder::<Vec<u8>>::new().dimensions(8).tolerance(2).compact_variants().validate(),
+            Err(BuildError::UnsupportedCompactVariants)
+        );
+    }
+
+    #[test]
+    fn builds_with_ignored_dimensions() {
+        let mut db = DbBuilder::<u64>::new()
+            .dimensions(64)
+            .tolerance(1)
+            .ignored_dimensions(vec![0, 1])
+            .build()
+            .unwrap();
+
+        db.insert(0b0111).unwrap();
+        assert!(db.get(&0b0000).unwrap().unwrap().contains(&0b0111));
+
+        assert_eq!(
+            DbBuilder::<u64>::new().dimensions(64).tolerance(1).ignored_dimensions(vec![64]).validate(),
+            Err(BuildError::IgnoredDimensionOutOfRange{dimension: 64, dimensions: 64})
+        );
+    }
+
+    #[test]
+    fn builds_with_id_strategy() {
+        let mut db = DbBuilder::<Vec<u8>>::new()
+            .dimensions(8)
+            .tolerance(2)
+            .id_strategy(IDStrategy::Fnv)
+            .build()
+            .unwrap();
+
+        db.insert(vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert!(db.get(&vec![1, 2, 3, 4, 5, 6, 0, 0]).unwrap().unwrap().contains(&vec![1, 2, 3, 4, 5, 6, 7, 8]));
+
+        match DbBuilder::<Vec<u8>>::new().dimensions(8).tolerance(2).id_strategy(IDStrategy::Echo).build() {
+            Err(e) => assert_eq!(e, BuildError::UnsupportedIDStrategy(IDStrategy::Echo)),
+            Ok(_) => panic!("vectors can't be their own identifiers"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn builds_with_id128() {
+        let mut db = DbBuilder::<Vec<u64>>::new()
+            .dimensions(8)
+            .tolerance(2)
+            .backend(StorageBackend::TempRocksDB)
+            .id128()
+            .build()
+            .unwrap();
+
+        db.insert(vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert!(db.get(&vec![1, 2, 3, 4, 5, 6, 0, 0]).unwrap().unwrap().contains(&vec![1, 2, 3, 4, 5, 6, 7, 8]));
+
+        assert_eq!(
+            DbBuilder::<u64>::new().dimensions(64).tolerance(4).id128().validate(),
+            Err(BuildError::UnsupportedID128)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn builds_with_inline_values() {
+        let mut db = DbBuilder::<Vec<u64>>::new()
+            .dimensions(8)
+            .tolerance(2)
+            .inline_values()
+            .build()
+            .unwrap();
+
+        db.insert(vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert!(db.get(&vec![1, 2, 3, 4, 5, 6, 0, 0]).unwrap().unwrap().contains(&vec![1, 2, 3, 4, 5, 6, 7, 8]));
+
+        let mut db = DbBuilder::<[u64; 2]>::new()
+            .dimensions(128)
+            .tolerance(4)
+            .backend(StorageBackend::TempRocksDB)
+            .inline_values()
+            .build()
+            .unwrap();
+
+        db.insert([0b0111, 1]).unwrap();
+        assert!(db.get(&[0b0000, 1]).unwrap().unwrap().contains(&[0b0111, 1]));
+        assert!(db.remove(&[0b0111, 1]).unwrap());
+        assert_eq!(db.get(&[0b0000, 1]).unwrap(), None);
+
+        assert_eq!(DbBuilder::<u64>::new().dimensions(64).tolerance(4).inline_values().validate(), Ok(()));
+        assert_eq!(
+            DbBuilder::<u64>::new().dimensions(64).tolerance(4).strategy(Strategy::Deletion).inline_values().validate(),
+            Err(BuildError::UnsupportedInlineValues)
+        );
+        assert_eq!(
+            DbBuilder::<Vec<u64>>::new().dimensions(8).tolerance(2).inline_values().id128().validate(),
+            Err(BuildError::UnsupportedInlineValues)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn builds_with_variant_cache() {
+        let dir = TempDir::new();
+        let mut db = DbBuilder::<u64>::new()
+            .dimensions(64)
+            .tolerance(4)
+            .backend(StorageBackend::RocksDB(dir.path().to_path_buf(), RocksDBConfig::default()))
+            .variant_cache(16)
+            .build()
+            .unwrap();
+
+        db.insert(0b0111).unwrap();
+        assert!(db.get(&0b0000).unwrap().unwrap().contains(&0b0111));
+        assert!(db.remove(&0b0111).unwrap());
+        assert_eq!(db.get(&0b0000).unwrap(), None);
+
+        assert_eq!(
+            DbBuilder::<u64>::new().dimensions(64).tolerance(4).variant_cache(16).validate(),
+            Err(BuildError::UnsupportedVariantLayers)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn builds_with_variant_filter() {
+        let dir = TempDir::new();
+        let mut db = DbBuilder::<u64>::new()
+            .dimensions(64)
+            .tolerance(4)
+            .backend(StorageBackend::RocksDB(dir.path().to_path_buf(), RocksDBConfig::default()))
+            .variant_cache(16)
+            .variant_filter(1024)
+            .build()
+            .unwrap();
+
+        assert_eq!(db.get(&0b0000).unwrap(), None);
+        db.insert(0b0111).unwrap();
+        assert!(db.get(&0b0000).unwrap().unwrap().contains(&0b0111));
+        assert!(db.remove(&0b0111).unwrap());
+        assert_eq!(db.get(&0b0000).unwrap(), None);
+
+        assert_eq!(
+            DbBuilder::<u64>::new().dimensions(64).tolerance(4).variant_filter(1024).validate(),
+            Err(BuildError::UnsupportedVariantLayers)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn refuses_stores_built_with_other_params() {
+        let dir = TempDir::new();
+        let backend = || StorageBackend::RocksDB(dir.path().to_path_buf(), RocksDBConfig::default());
+
+        {
+            let mut db = DbBuilder::<u64>::new().dimensions(64).tolerance(4).backend(backend()).build().unwrap();
+            db.insert(0b0111).unwrap();
+        }
+
+        match DbBuilder::<u64>::new().dimensions(64).tolerance(6).backend(backend()).build() {
+            Err(BuildError::ParamsMismatch(e)) => assert_eq!((e.stored.tolerance, e.requested.tolerance), (4, 6)),
+            Err(e) => panic!("expected a parameter mismatch, got {:?}", e),
+            Ok(_) => panic!("stores built with tolerance 4 can't be opened with tolerance 6"),
+        }
+
+        let db = DbBuilder::<u64>::new().dimensions(64).tolerance(4).backend(backend()).build().unwrap();
+        assert!(db.get(&0b0000).unwrap().unwrap().contains(&0b0111));
+    }
+
+    #[test]
+    fn rejects_oversized_variant_sets() {
+        let budget = VariantBudget{max_variants_per_insert: 1000, ..Default::default()};
+        let builder = DbBuilder::<Vec<u8>>::new().dimensions(10000).tolerance(4).variant_budget(budget);
+
+        match builder.validate() {
+            Err(BuildError::VariantExplosion(e)) => assert_eq!(e.estimate.variants_per_insert, 10003),
+            other => panic!("expected variant explosion, got {:?}", other),
+        }
+
+        let builder = builder.variant_budget(VariantBudget::unlimited());
+        assert_eq!(builder.validate(), Ok(()));
+    }
+
+    #[test]
+    fn open_checks_the_default_variant_budget() {
+        match Vec::<u8>::open(200000, 4, StorageBackend::InMemory) {
+            Err(Error::VariantExplosion(e)) => assert_eq!(e.estimate.variants_per_insert, 200003),
+            Err(e) => panic!("expected variant explosion, got {:?}", e),
+            Ok(_) => panic!("200000 dimensions exceed the default budget"),
+        }
+
+        assert!(Vec::<u8>::open(8, 2, StorageBackend::InMemory).is_ok());
+    }
+}
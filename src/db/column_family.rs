@@ -0,0 +1,746 @@
+//! Keeping many stores in one RocksDB
+//!
+//! Each namespace used to open two RocksDB directories (one per store), so a
+//! server with hundreds of namespaces held hundreds of RocksDBs - each with
+//! its own write-ahead log, background threads and open files.  A
+//! `SharedRocksDB` instead holds any number of stores, each in its own column
+//! family named `<namespace>/<store>#<generation>`.  The generation changes
+//! when a store is compacted (see `Store::compact`); the current generation
+//! of each store is recorded in the default column family.
+//!
+//! RocksDB only opens a database if it's given the name of every column
+//! family in it, and the bindings can't list them, so the names are kept in
+//! `HAMMER_FAMILIES` alongside RocksDB's own files.  A family being created or
+//! dropped is first written to `HAMMER_FAMILIES.pending`; if a crash leaves
+//! the list and the database disagreeing about it, `open` retries with and
+//! without the pending family.
+//!
+//! A store with a RocksDB to itself (as created by `Store::dedicated`) keeps
+//! its entries in the default column family, which is how stores were laid
+//! out before column families were used.
+//!
+//! Stores of the same RocksDB can write through a shared `Batch`, so a
+//! namespace's value and variant stores are updated together or not at all
+//! (see `db::atomic`).
+//!
+//! The bindings open existing column families with RocksDB's default
+//! options, so the compaction style, block cache and bloom filter set by
+//! `RocksDBConfig` only apply to families created since the server started.
+//!
+//! `Store::snapshot` returns a read-only store reading from a RocksDB
+//! snapshot, unaffected by later writes.  The snapshot holds its RocksDB
+//! open through an `Arc`, so it can outlive any lock; creating, dropping or
+//! compacting a column family (and compacting a dedicated store) waits for
+//! every snapshot of the RocksDB to be dropped first, as each of those can
+//! invalidate what a snapshot reads.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::mem;
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, RwLock};
+
+use rocksdb::{self, DB, DBVector, DBIterator, Writable, WriteBatch, IteratorMode};
+use rocksdb::rocksdb_ffi::DBCFHandle;
+
+use db::encoding::{encode, decode};
+use db::sync_rocksdb;
+use db::compaction::{self, StoreOptions};
+use db::disk_usage::disk_usage;
+
+/// Column family names, one per line
+const FAMILIES_FILE: &'static str = "HAMMER_FAMILIES";
+
+/// The column family being created or dropped, if any
+const PENDING_FILE: &'static str = "HAMMER_FAMILIES.pending";
+
+/// Prefixes the default column family's keys recording each store's current
+/// generation
+const GENERATION_PREFIX: &'static [u8] = b"generation:";
+
+/// Entries copied per `WriteBatch` while compacting a column family
+const COMPACTION_BATCH_SIZE: usize = 100000;
+
+/// How often a change to the RocksDB checks whether its snapshots have been
+/// dropped
+const SNAPSHOT_POLL_MS: u64 = 1;
+
+struct Inner {
+    // Shared with the snapshots taken of it
+    db: Arc<DB>,
+    families: Vec<String>,
+}
+
+impl Inner {
+    /// The RocksDB, once no snapshot holds it
+    ///
+    /// New snapshots can't be taken while the caller holds `Inner` for
+    /// writing, so this only waits for those already taken.
+    ///
+    fn db_mut(&mut self) -> &mut DB {
+        while Arc::strong_count(&self.db) > 1 {
+            thread::sleep(Duration::from_millis(SNAPSHOT_POLL_MS));
+        }
+        Arc::get_mut(&mut self.db).expect("snapshot taken during a write")
+    }
+}
+
+/// A RocksDB holding one or more stores
+///
+pub struct SharedRocksDB {
+    inner: RwLock<Inner>,
+    opts: StoreOptions,
+    path: PathBuf,
+}
+
+impl fmt::Debug for SharedRocksDB {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "SharedRocksDB({:?})", self.path)
+    }
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>, String> {
+    if !path.exists() {
+        return Ok(vec![])
+    }
+
+    let mut contents = String::new();
+    try!(File::open(path)
+         .and_then(|mut f| f.read_to_string(&mut contents))
+         .map_err(|e| format!("unable to read {:?}: {}", path, e)));
+
+    Ok(contents.lines().filter(|line| !line.is_empty()).map(|line| line.to_string()).collect())
+}
+
+/// Replaces `path` with `lines`, via a synced temporary file so a crash
+/// leaves either the old or the new list
+///
+fn write_lines(path: &Path, lines: &[String]) -> Result<(), String> {
+    let mut tmp_name = path.file_name().unwrap().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    {
+        let mut f = try!(File::create(&tmp).map_err(|e| format!("unable to create {:?}: {}", tmp, e)));
+        for line in lines {
+            try!(writeln!(f, "{}", line).map_err(|e| format!("unable to write {:?}: {}", tmp, e)));
+        }
+        try!(f.sync_all().map_err(|e| format!("unable to sync {:?}: {}", tmp, e)));
+    }
+    fs::rename(&tmp, path).map_err(|e| format!("unable to rename {:?}: {}", tmp, e))
+}
+
+fn generation_key(name: &str) -> Vec<u8> {
+    let mut key = GENERATION_PREFIX.to_vec();
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+fn family_name(name: &str, generation: u64) -> String {
+    format!("{}#{}", name, generation)
+}
+
+/// The store a column family belongs to
+///
+fn store_name(family: &str) -> &str {
+    match family.rfind('#') {
+        Some(i) => &family[..i],
+        None => family,
+    }
+}
+
+impl SharedRocksDB {
+    /// Opens (or creates) the RocksDB at `path` with every column family it
+    /// holds
+    ///
+    pub fn open(path: &Path, opts: StoreOptions) -> Result<Arc<SharedRocksDB>, String> {
+        let mut families = try!(read_lines(&path.join(FAMILIES_FILE)));
+        let pending = try!(read_lines(&path.join(PENDING_FILE))).into_iter().next();
+
+        let db = match (families.is_empty(), &pending) {
+            (true, &None) => try!(compaction::open(&opts, path)),
+            _ => {
+                let opened = {
+                    let names: Vec<&str> = families.iter().map(|name| &name[..]).collect();
+                    DB::open_cf(opts.options(), try!(compaction::path_str(path)), &names)
+                };
+
+                match (opened, &pending) {
+                    (Ok(db), _) => db,
+                    (Err(e), &None) => return Err(e),
+                    (Err(_), &Some(ref pending)) => {
+                        // The pending family's creation or removal didn't
+                        // reach the list
+                        match families.iter().position(|name| name == pending) {
+                            Some(i) => { families.remove(i); },
+                            None => families.push(pending.clone()),
+                        }
+                        let names: Vec<&str> = families.iter().map(|name| &name[..]).collect();
+                        try!(DB::open_cf(opts.options(), try!(compaction::path_str(path)), &names))
+                    },
+                }
+            },
+        };
+
+        if pending.is_some() {
+            try!(write_lines(&path.join(FAMILIES_FILE), &families));
+            try!(fs::remove_file(path.join(PENDING_FILE)).map_err(|e| format!("unable to remove pending family: {}", e)));
+        }
+
+        Ok(Arc::new(SharedRocksDB{
+            inner: RwLock::new(Inner{db: Arc::new(db), families: families}),
+            opts: opts,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn create_family(&self, family: &str) -> Result<(), String> {
+        let mut inner = self.inner.write().unwrap();
+        if inner.families.iter().any(|name| name == family) {
+            return Ok(())
+        }
+
+        try!(write_lines(&self.path.join(PENDING_FILE), &[family.to_string()]));
+        try!(inner.db_mut().create_cf(family, self.opts.options()));
+        inner.families.push(family.to_string());
+        try!(write_lines(&self.path.join(FAMILIES_FILE), &inner.families));
+        fs::remove_file(self.path.join(PENDING_FILE)).map_err(|e| format!("unable to remove pending family: {}", e))
+    }
+
+    fn drop_family(&self, inner: &mut Inner, family: &str) -> Result<(), String> {
+        try!(write_lines(&self.path.join(PENDING_FILE), &[family.to_string()]));
+        try!(inner.db_mut().drop_cf(family));
+        inner.families.retain(|name| name != family);
+        try!(write_lines(&self.path.join(FAMILIES_FILE), &inner.families));
+        fs::remove_file(self.path.join(PENDING_FILE)).map_err(|e| format!("unable to remove pending family: {}", e))
+    }
+
+    /// Drops every store whose name starts with `prefix` (ie every store of a
+    /// dropped namespace)
+    ///
+    pub fn drop_stores(&self, prefix: &str) -> Result<(), String> {
+        let mut inner = self.inner.write().unwrap();
+        let dropped: Vec<String> = inner.families.iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect();
+
+        for family in dropped.iter() {
+            try!(inner.db.delete(&generation_key(store_name(family))));
+            try!(self.drop_family(&mut inner, family));
+        }
+        Ok(())
+    }
+}
+
+/// Reads & writes a single store's entries
+///
+/// Obtained from `Store::with`; borrows the store's RocksDB for the duration
+/// of the call.  Views of a snapshot (see `Store::snapshot`) read from it,
+/// and fail to write.
+///
+pub struct View<'a> {
+    db: &'a DB,
+    family: Option<DBCFHandle>,
+    snapshot: Option<&'a rocksdb::Snapshot<'static>>,
+}
+
+fn read_only() -> String {
+    "snapshots are read-only".to_string()
+}
+
+impl<'a> View<'a> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<DBVector>, String> {
+        match (self.snapshot, self.family) {
+            (Some(snapshot), Some(family)) => snapshot.get_cf(family, key),
+            (Some(snapshot), None) => snapshot.get(key),
+            (None, Some(family)) => self.db.get_cf(family, key),
+            (None, None) => self.db.get(key),
+        }
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err(read_only())
+        }
+        match self.family {
+            Some(family) => self.db.put_cf(family, key, value),
+            None => self.db.put(key, value),
+        }
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err(read_only())
+        }
+        match self.family {
+            Some(family) => self.db.delete_cf(family, key),
+            None => self.db.delete(key),
+        }
+    }
+
+    pub fn iterator(&self, mode: IteratorMode) -> Result<DBIterator<'a>, String> {
+        match (self.snapshot, self.family) {
+            (Some(snapshot), Some(family)) => snapshot.iterator_cf(family, mode),
+            (Some(snapshot), None) => Ok(snapshot.iterator(mode)),
+            (None, Some(family)) => self.db.iterator_cf(family, mode),
+            (None, None) => Ok(self.db.iterator(mode)),
+        }
+    }
+
+    /// Adds a put of this store's `key` to `batch`
+    ///
+    pub fn batch_put(&self, batch: &WriteBatch, key: &[u8], value: &[u8]) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err(read_only())
+        }
+        match self.family {
+            Some(family) => batch.put_cf(family, key, value),
+            None => batch.put(key, value),
+        }
+    }
+
+    /// Adds a delete of this store's `key` to `batch`
+    ///
+    pub fn batch_delete(&self, batch: &WriteBatch, key: &[u8]) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err(read_only())
+        }
+        match self.family {
+            Some(family) => batch.delete_cf(family, key),
+            None => batch.delete(key),
+        }
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err(read_only())
+        }
+        self.db.write(batch)
+    }
+}
+
+/// A RocksDB snapshot, holding the RocksDB it was taken of
+///
+struct Snapshot {
+    // Borrows `db`, so is declared (and dropped) first
+    snapshot: rocksdb::Snapshot<'static>,
+    db: Arc<DB>,
+    family: Option<DBCFHandle>,
+}
+
+// RocksDB snapshots & column family handles are safe to read from any
+// thread, but the bindings hold them as raw pointers
+unsafe impl Send for Snapshot {}
+unsafe impl Sync for Snapshot {}
+
+/// Writes to any of the stores of one RocksDB, applied together by `commit`
+///
+/// A batch is bound to the RocksDB of the first store to join it (see
+/// `Store::join`); stores of other RocksDBs can't join.
+///
+pub struct Batch {
+    shared: Option<Arc<SharedRocksDB>>,
+    writes: WriteBatch,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch{shared: None, writes: WriteBatch::new()}
+    }
+
+    /// The batch staged writes are added to (see `View::batch_put`)
+    ///
+    pub fn writes(&self) -> &WriteBatch {
+        &self.writes
+    }
+
+    /// Applies every staged write at once
+    ///
+    /// Does nothing if no store joined the batch.
+    ///
+    pub fn commit(self) -> Result<(), String> {
+        match self.shared {
+            Some(shared) => shared.inner.read().unwrap().db.write(self.writes),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A store's place in a RocksDB - either the whole of a dedicated RocksDB,
+/// or a column family of a shared one
+///
+pub struct Store {
+    shared: Arc<SharedRocksDB>,
+    // Unset for dedicated stores
+    family: Option<(String, u64)>,
+    // Set for stores returned by `snapshot`
+    snapshot: Option<Arc<Snapshot>>,
+}
+
+impl Store {
+    /// A store occupying the whole RocksDB at `path`
+    ///
+    pub fn dedicated(path: &Path, opts: StoreOptions) -> Result<Store, String> {
+        Ok(Store{
+            shared: try!(SharedRocksDB::open(path, opts)),
+            family: None,
+            snapshot: None,
+        })
+    }
+
+    /// The store `name` in `shared`, created if it doesn't exist yet
+    ///
+    /// Column families left behind by an interrupted compaction are dropped.
+    ///
+    pub fn in_family(shared: Arc<SharedRocksDB>, name: &str) -> Result<Store, String> {
+        let generation: u64 = {
+            let inner = shared.inner.read().unwrap();
+            match try!(inner.db.get(&generation_key(name))) {
+                Some(encoded) => try!(decode(&encoded).map_err(|e| format!("undecodable generation for {}: {:?}", name, e))),
+                None => 0,
+            }
+        };
+
+        let current = family_name(name, generation);
+        try!(shared.create_family(&current));
+        {
+            let mut inner = shared.inner.write().unwrap();
+            let stale: Vec<String> = inner.families.iter()
+                .filter(|family| store_name(family) == name && **family != current)
+                .cloned()
+                .collect();
+            for family in stale.iter() {
+                try!(shared.drop_family(&mut inner, family));
+            }
+        }
+
+        Ok(Store{
+            shared: shared,
+            family: Some((name.to_string(), generation)),
+            snapshot: None,
+        })
+    }
+
+    /// Calls `f` with a view of the store's entries
+    ///
+    /// Fails without calling `f` if the store's column family has been
+    /// dropped, ie by `SharedRocksDB::drop_stores`.
+    ///
+    pub fn with<F, T, E>(&self, f: F) -> Result<T, E> where
+    F: FnOnce(View) -> Result<T, E>,
+    E: From<String>,
+    {
+        if let Some(ref snapshot) = self.snapshot {
+            return f(View{db: &snapshot.db, family: snapshot.family, snapshot: Some(&snapshot.snapshot)})
+        }
+
+        let inner = self.shared.inner.read().unwrap();
+        let family = try!(self.handle(&inner));
+
+        f(View{db: &inner.db, family: family, snapshot: None})
+    }
+
+    /// The handle of the store's column family, unset for dedicated stores
+    ///
+    fn handle(&self, inner: &Inner) -> Result<Option<DBCFHandle>, String> {
+        match self.family {
+            Some((ref name, generation)) => {
+                let family = family_name(name, generation);
+                match inner.db.cf_handle(&family) {
+                    Some(handle) => Ok(Some(*handle)),
+                    None => Err(format!("column family {} isn't open", family)),
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// A read-only copy of the store as it is now
+    ///
+    /// Taking a snapshot doesn't copy any entries.  Snapshots of a snapshot
+    /// share it.
+    ///
+    pub fn snapshot(&self) -> Result<Store, String> {
+        let snapshot = match self.snapshot {
+            Some(ref snapshot) => snapshot.clone(),
+            None => {
+                let inner = self.shared.inner.read().unwrap();
+                let family = try!(self.handle(&inner));
+
+                let db = inner.db.clone();
+                // The snapshot borrows the RocksDB the `Arc` keeps alive (and
+                // in place) for as long as the snapshot exists
+                let snapshot: rocksdb::Snapshot<'static> = unsafe { mem::transmute(db.snapshot()) };
+                Arc::new(Snapshot{snapshot: snapshot, db: db, family: family})
+            },
+        };
+
+        Ok(Store{
+            shared: self.shared.clone(),
+            family: self.family.clone(),
+            snapshot: Some(snapshot),
+        })
+    }
+
+    /// Binds `batch` to this store's RocksDB if no store has joined it yet
+    ///
+    /// Returns false if `batch` is bound to a different RocksDB, in which
+    /// case nothing this store writes can be staged in it.  Snapshots can't
+    /// join batches.
+    ///
+    pub fn join(&self, batch: &mut Batch) -> bool {
+        if self.snapshot.is_some() {
+            return false
+        }
+
+        if let Some(ref shared) = batch.shared {
+            return &**shared as *const SharedRocksDB == &*self.shared as *const SharedRocksDB
+        }
+
+        batch.shared = Some(self.shared.clone());
+        true
+    }
+
+    /// Bytes on disk, unknown for stores sharing their RocksDB
+    ///
+    pub fn disk_bytes(&self) -> Option<u64> {
+        match self.family {
+            Some(_) => None,
+            None => disk_usage(&self.shared.path).ok().map(|usage| usage.bytes),
+        }
+    }
+
+    /// Reads entries from the start of the store until about `fraction` of
+    /// its bytes on disk have been read, pulling their blocks into RocksDB's
+    /// block cache
+    ///
+    /// Returns the number of entries read.  Stores sharing their RocksDB
+    /// don't know their size, so are read in full.
+    ///
+    pub fn warmup(&self, fraction: f64) -> Result<usize, String> {
+        let budget = match self.disk_bytes() {
+            Some(bytes) if fraction < 1.0 => Some((bytes as f64 * fraction) as u64),
+            _ => None,
+        };
+
+        self.with(|view| -> Result<usize, String> {
+            let mut entries = 0;
+            let mut bytes = 0;
+            for (k, v) in try!(view.iterator(IteratorMode::Start)) {
+                entries += 1;
+                bytes += (k.len() + v.len()) as u64;
+                if budget.map_or(false, |budget| bytes >= budget) {
+                    break
+                }
+            }
+            Ok(entries)
+        })
+    }
+
+    /// Fsyncs the RocksDB's write-ahead logs (see `sync_rocksdb`)
+    ///
+    pub fn sync(&self) -> Result<(), String> {
+        sync_rocksdb(&self.shared.path)
+    }
+
+    /// Copies the store's live entries into a fresh store which replaces it
+    ///
+    /// Dedicated stores are compacted by `compaction::compact`.  Stores in a
+    /// column family are copied into a family of the next generation, which
+    /// becomes current when the default column family is updated to name it.
+    ///
+    pub fn compact(&mut self) -> Result<(), String> {
+        if self.snapshot.is_some() {
+            return Err(read_only())
+        }
+
+        let (name, generation) = match self.family {
+            Some((ref name, generation)) => (name.clone(), generation),
+            None => {
+                let mut inner = self.shared.inner.write().unwrap();
+                return compaction::compact(inner.db_mut(), &self.shared.opts, &self.shared.path)
+            },
+        };
+
+        let from = family_name(&name, generation);
+        let to = family_name(&name, generation + 1);
+        try!(self.shared.create_family(&to));
+
+        {
+            let inner = self.shared.inner.read().unwrap();
+            let from_handle = *try!(inner.db.cf_handle(&from).ok_or(format!("column family {} isn't open", from)));
+            let to_handle = *try!(inner.db.cf_handle(&to).ok_or(format!("column family {} isn't open", to)));
+
+            let mut batch = WriteBatch::new();
+            let mut batched = 0;
+            for (k, v) in try!(inner.db.iterator_cf(from_handle, IteratorMode::Start)) {
+                try!(batch.put_cf(to_handle, &k, &v));
+                batched += 1;
+
+                if batched == COMPACTION_BATCH_SIZE {
+                    try!(inner.db.write(batch));
+                    batch = WriteBatch::new();
+                    batched = 0;
+                }
+            }
+            try!(inner.db.write(batch));
+            try!(sync_rocksdb(&self.shared.path));
+
+            let encoded: Vec<u8> = try!(encode(&(generation + 1)));
+            try!(inner.db.put(&generation_key(&name), &encoded));
+            try!(sync_rocksdb(&self.shared.path));
+        }
+        self.family = Some((name, generation + 1));
+
+        let mut inner = self.shared.inner.write().unwrap();
+        self.shared.drop_family(&mut inner, &from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use rocksdb::IteratorMode;
+
+    use db::column_family::{Batch, SharedRocksDB, Store};
+    use db::compaction::StoreOptions;
+    use db::temp_dir::TempDir;
+
+    fn count(store: &Store) -> usize {
+        store.with(|view| view.iterator(IteratorMode::Start).map(|iter| iter.count())).unwrap()
+    }
+
+    #[test]
+    fn stores_in_families_are_separate_and_survive_reopening() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+
+        {
+            let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+            let a = Store::in_family(shared.clone(), "ns/a").unwrap();
+            let b = Store::in_family(shared.clone(), "ns/b").unwrap();
+
+            a.with(|view| view.put(b"key", b"a")).unwrap();
+            b.with(|view| view.put(b"key", b"b")).unwrap();
+            b.with(|view| view.put(b"other", b"b")).unwrap();
+            assert_eq!(count(&a), 1);
+            assert_eq!(count(&b), 2);
+        }
+
+        let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+        let a = Store::in_family(shared.clone(), "ns/a").unwrap();
+        assert_eq!(&*a.with(|view| view.get(b"key")).unwrap().unwrap(), b"a");
+    }
+
+    #[test]
+    fn compacting_a_family_keeps_its_entries() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+
+        {
+            let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+            let mut store = Store::in_family(shared.clone(), "ns/a").unwrap();
+            for i in 0..100u32 {
+                store.with(|view| view.put(format!("key-{}", i).as_bytes(), b"value")).unwrap();
+            }
+            for i in 0..90u32 {
+                store.with(|view| view.delete(format!("key-{}", i).as_bytes())).unwrap();
+            }
+
+            store.compact().unwrap();
+            assert_eq!(count(&store), 10);
+        }
+
+        let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+        let store = Store::in_family(shared.clone(), "ns/a").unwrap();
+        assert_eq!(count(&store), 10);
+        assert!(store.with(|view| view.get(b"key-99")).unwrap().is_some());
+    }
+
+    #[test]
+    fn batches_span_families_of_one_rocksdb() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let other_dir = TempDir::new();
+        fs::create_dir_all(other_dir.path()).unwrap();
+
+        let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+        let a = Store::in_family(shared.clone(), "ns/a").unwrap();
+        let b = Store::in_family(shared.clone(), "ns/b").unwrap();
+        let other = Store::dedicated(other_dir.path(), StoreOptions::default()).unwrap();
+
+        let mut batch = Batch::new();
+        assert!(a.join(&mut batch));
+        assert!(b.join(&mut batch));
+        assert!(!other.join(&mut batch));
+
+        a.with(|view| view.batch_put(batch.writes(), b"key", b"a")).unwrap();
+        b.with(|view| view.batch_put(batch.writes(), b"key", b"b")).unwrap();
+        assert_eq!(count(&a), 0);
+        assert_eq!(count(&b), 0);
+
+        batch.commit().unwrap();
+        assert_eq!(count(&a), 1);
+        assert_eq!(count(&b), 1);
+    }
+
+    #[test]
+    fn snapshots_ignore_later_writes() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+
+        let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+        let a = Store::in_family(shared.clone(), "ns/a").unwrap();
+        a.with(|view| view.put(b"key", b"before")).unwrap();
+
+        let snapshot = a.snapshot().unwrap();
+        a.with(|view| view.put(b"key", b"after")).unwrap();
+        a.with(|view| view.put(b"other", b"after")).unwrap();
+
+        assert_eq!(&*snapshot.with(|view| view.get(b"key")).unwrap().unwrap(), b"before");
+        assert_eq!(count(&snapshot), 1);
+        assert!(snapshot.with(|view| view.put(b"key", b"snapshot")).is_err());
+        assert_eq!(count(&a), 2);
+
+        // Dropping the family waits for its snapshots, so drop this one first
+        drop(snapshot);
+        shared.drop_stores("ns/").unwrap();
+    }
+
+    #[test]
+    fn dropped_stores_are_emptied() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+
+        let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+        {
+            let a = Store::in_family(shared.clone(), "ns/a").unwrap();
+            a.with(|view| view.put(b"key", b"a")).unwrap();
+        }
+        shared.drop_stores("ns/").unwrap();
+
+        let a = Store::in_family(shared.clone(), "ns/a").unwrap();
+        assert_eq!(count(&a), 0);
+    }
+
+    #[test]
+    fn stores_fail_once_dropped() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+
+        let shared = SharedRocksDB::open(dir.path(), StoreOptions::default()).unwrap();
+        let a = Store::in_family(shared.clone(), "ns/a").unwrap();
+        shared.drop_stores("ns/").unwrap();
+
+        assert!(a.with(|view| view.get(b"key")).is_err());
+        assert!(a.snapshot().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Reclaiming space from RocksDB stores
+//!
+//! RocksDB doesn't delete anything in place - a removed entry leaves a
+//! tombstone, and the space is only reclaimed when background compaction
+//! happens to merge the files holding the entry and its tombstone.  Under
+//! heavy churn a store can grow far beyond its live data.
+//!
+//! `Database::compact` reclaims that space on demand.  The rocksdb bindings
+//! don't expose manual compaction, so each store's live entries are copied
+//! into a fresh store which then replaces it.  Compacting needs room for a
+//! second copy of the live entries, and holds the namespace's write lock
+//! while it runs.
+//!
+//! The copy is written to `<store>.compacted` and swapped in with two renames.
+//! A compaction interrupted before the copy is complete leaves the original
+//! store in place (the partial copy is removed by the next compaction); one
+//! interrupted between the renames is finished by `open` when the store is
+//! next opened.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rocksdb::{DB, Options, Writable, WriteBatch, IteratorMode};
+
+use db::sync_rocksdb;
+use db::temp_dir::TempDir;
+
+/// Entries copied per `WriteBatch` while compacting
+const COMPACTION_BATCH_SIZE: usize = 100000;
+
+/// The options a store was opened with, kept so it can re-open itself after
+/// compacting
+///
+pub struct StoreOptions(Options);
+
+// The bindings' `Options` wrap a raw pointer, so aren't `Send`.  RocksDB only
+// reads them while opening a store, which only happens under `&mut self`.
+unsafe impl Send for StoreOptions {}
+unsafe impl Sync for StoreOptions {}
+
+impl StoreOptions {
+    pub fn new(opts: Options) -> StoreOptions {
+        StoreOptions(opts)
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.0
+    }
+}
+
+/// `DB::open_default`'s options
+///
+impl Default for StoreOptions {
+    fn default() -> StoreOptions {
+        let mut opts = Options::new();
+        opts.create_if_missing(true);
+        StoreOptions(opts)
+    }
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        try!(fs::remove_dir_all(path).map_err(|e| format!("unable to remove {:?}: {}", path, e)));
+    }
+    Ok(())
+}
+
+/// `path` as RocksDB takes it, failing if it isn't valid UTF-8
+///
+pub fn path_str(path: &Path) -> Result<&str, String> {
+    path.to_str().ok_or_else(|| format!("{:?} isn't a valid UTF-8 path", path))
+}
+
+/// Opens the RocksDB store at `path`, first finishing any compaction which
+/// was interrupted after the original store was moved aside
+///
+pub fn open(opts: &StoreOptions, path: &Path) -> Result<DB, String> {
+    let compacted = sibling(path, "compacted");
+    if !path.exists() && compacted.exists() {
+        try!(fs::rename(&compacted, path).map_err(|e| format!("unable to rename {:?}: {}", compacted, e)));
+    }
+    try!(remove_if_exists(&sibling(path, "replaced")));
+
+    DB::open(&opts.0, try!(path_str(path)))
+}
+
+/// Replaces the store `db` at `path` with a copy of its live entries
+///
+pub fn compact(db: &mut DB, opts: &StoreOptions, path: &Path) -> Result<(), String> {
+    let compacted = sibling(path, "compacted");
+    let replaced = sibling(path, "replaced");
+    try!(remove_if_exists(&compacted));
+    try!(remove_if_exists(&replaced));
+
+    {
+        let copy = try!(DB::open(&opts.0, try!(path_str(&compacted))));
+        let mut batch = WriteBatch::new();
+        let mut batched = 0;
+
+        for (k, v) in db.iterator(IteratorMode::Start) {
+            try!(batch.put(&k, &v));
+            batched += 1;
+
+            if batched == COMPACTION_BATCH_SIZE {
+                try!(copy.write(batch));
+                batch = WriteBatch::new();
+                batched = 0;
+            }
+        }
+        try!(copy.write(batch));
+    }
+    try!(sync_rocksdb(&compacted));
+
+    // The original must be closed before it's moved; an empty store stands in
+    // for it meanwhile
+    let placeholder = TempDir::new();
+    *db = try!(DB::open(&opts.0, placeholder.to_str()));
+
+    try!(fs::rename(path, &replaced).map_err(|e| format!("unable to rename {:?}: {}", path, e)));
+    try!(fs::rename(&compacted, path).map_err(|e| format!("unable to rename {:?}: {}", compacted, e)));
+    *db = try!(open(opts, path));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use rocksdb::{DB, Writable, IteratorMode};
+
+    use db::compaction::{self, StoreOptions};
+    use db::disk_usage::disk_usage;
+    use db::temp_dir::TempDir;
+
+    #[test]
+    fn compacting_keeps_live_entries_and_drops_removed_ones() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("store");
+        let opts = StoreOptions::default();
+
+        {
+            let mut db = compaction::open(&opts, &path).unwrap();
+            for i in 0..20000u32 {
+                db.put(format!("key-{}", i).as_bytes(), &[0; 100]).unwrap();
+            }
+            for i in 0..19000u32 {
+                db.delete(format!("key-{}", i).as_bytes()).unwrap();
+            }
+            let before = disk_usage(&path).unwrap().bytes;
+
+            compaction::compact(&mut db, &opts, &path).unwrap();
+            assert!(disk_usage(&path).unwrap().bytes < before);
+
+            assert!(db.get(b"key-0").unwrap().is_none());
+            assert!(db.get(b"key-19999").unwrap().is_some());
+            assert_eq!(db.iterator(IteratorMode::Start).count(), 1000);
+        }
+
+        // Re-opening finds the compacted store
+        let db: DB = compaction::open(&opts, &path).unwrap();
+        assert!(db.get(b"key-19999").unwrap().is_some());
+    }
+
+    #[test]
+    fn open_finishes_an_interrupted_swap() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("store");
+        let opts = StoreOptions::default();
+
+        {
+            let db = compaction::open(&opts, &dir.path().join("store.compacted")).unwrap();
+            db.put(b"key", b"value").unwrap();
+        }
+
+        let db = compaction::open(&opts, &path).unwrap();
+        assert_eq!(&*db.get(b"key").unwrap().unwrap(), b"value");
+    }
+}
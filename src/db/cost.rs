@@ -0,0 +1,34 @@
+//! Query cost estimation
+//!
+//! A query's cost is dominated by the number of candidates it has to fetch
+//! and verify, which is the total size of the variant buckets it touches.
+//! Bucket sizes can be read without fetching their contents, so
+//! `Database::estimate_candidates` gives a cheap upper bound on the work a
+//! query will do.  `Database::get_bounded` uses it to refuse queries into
+//! very dense regions of the keyspace before doing that work.
+
+use std::fmt;
+use std::error;
+
+/// Returned when a query's estimated candidate count exceeds the limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooExpensive {
+    pub estimated_candidates: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for TooExpensive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f,
+            "query would examine ~{} candidates (limit {}); try a DB with a lower tolerance",
+            self.estimated_candidates,
+            self.limit,
+        )
+    }
+}
+
+impl error::Error for TooExpensive {
+    fn description(&self) -> &str {
+        "query exceeds estimated cost limit"
+    }
+}
@@ -0,0 +1,261 @@
+//! Counting how many times each value was inserted
+//!
+//! Databases have set semantics: inserting a value which is already indexed
+//! does nothing.  `CountedDB` wraps a database of `Payload<T, u64>` and keeps
+//! the number of times each value has been inserted as its payload, so
+//! workloads which see the same digest many times can tell how often a match
+//! was seen.  Inserting a value increments its count, removing it decrements
+//! the count, and the value is only removed from the index once its count
+//! reaches zero.
+//!
+//! Counts are kept per value rather than per variant: every variant of a value
+//! would hold the same count, so a count per (variant, ID) would multiply
+//! each increment by the number of variants.  Kept in the value's entry, a
+//! count is written in the same batch as the value it counts (see
+//! `db::atomic`) and is returned by the read which verifies a match.
+//!
+//! Like payloads, counts aren't reachable from the HTTP server (see
+//! `db::payload`).
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db = CountedDB::new(Payload::<u64, u64>::build(64, 4, StorageBackend::InMemory).unwrap());
+//!
+//! db.insert(0b1111);
+//! db.insert(0b1111);
+//! db.get_with_counts(&0b0111, &QueryOptions::default());
+//! // Some([(0b1111, 2)])
+//! ```
+
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::{Database, QueryOptions, RemovalReport, Page, WarmupReport};
+use db::health::{HealthReport, RepairReport};
+use db::shadow::ShadowStats;
+use db::stats::Stats;
+use db::id_map::{ToID, IDStrategy};
+use db::payload::{Payload, PayloadDB};
+use db::hashing::State;
+use error::Error;
+
+pub struct CountedDB<T> {
+    db: PayloadDB<T, u64>,
+}
+
+impl<T> CountedDB<T> where
+T: Sync + Send + Clone + Eq + Hash + ToID<u64>,
+{
+    pub fn new(db: Box<Database<Payload<T, u64>>>) -> CountedDB<T> {
+        CountedDB {
+            db: PayloadDB::new(db),
+        }
+    }
+
+    /// Number of times `key` has been inserted and not removed
+    ///
+    pub fn count(&self, key: &T) -> Result<u64, Error> {
+        Ok(try!(self.db.payload(key)).unwrap_or(0))
+    }
+
+    /// Like `get_with`, returning each match with the number of times it was
+    /// inserted
+    ///
+    pub fn get_with_counts(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<(T, u64)>>, Error> {
+        self.db.get_with_payloads(key, options)
+    }
+
+    /// Stop counting, returning the wrapped database
+    ///
+    pub fn into_inner(self) -> Box<Database<Payload<T, u64>>> {
+        self.db.into_inner()
+    }
+
+    /// Decrements `key`'s count, returning true if it was inserted more than
+    /// once so should stay indexed
+    ///
+    fn decrement(&mut self, key: &T) -> Result<bool, Error> {
+        match try!(self.count(key)) {
+            count if count > 1 => {
+                try!(self.db.insert_with_payload(key.clone(), count - 1));
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<T> Database<T> for CountedDB<T> where
+T: Sync + Send + Clone + Eq + Hash + ToID<u64>,
+{
+    fn get_with(&self, key: &T, options: &QueryOptions) -> Result<Option<HashSet<T, State>>, Error> {
+        self.db.get_with(key, options)
+    }
+
+    fn get_many(&self, keys: &[T], options: &QueryOptions) -> Result<Vec<Option<HashSet<T, State>>>, Error> {
+        self.db.get_many(keys, options)
+    }
+
+    fn for_each_match(&self, key: &T, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(T)) -> Result<(), Error> where
+    T: Serialize + DeserializeOwned,
+    {
+        self.db.for_each_match(key, options, spill_threshold, f)
+    }
+
+    fn get_page_with(&self, key: &T, options: &QueryOptions, cursor: Option<&T>, limit: usize) -> Result<Page<T>, Error> {
+        self.db.get_page_with(key, options, cursor, limit)
+    }
+
+    fn get_recent_with(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<T>>, Error> {
+        self.db.get_recent_with(key, options)
+    }
+
+    fn sort_by_recency(&self, found: HashSet<T, State>) -> Vec<T> {
+        self.db.sort_by_recency(found)
+    }
+
+    fn get_topk(&self, key: &T, k: usize) -> Result<Option<Vec<T>>, Error> {
+        self.db.get_topk(key, k)
+    }
+
+    fn estimate_candidates(&self, key: &T) -> Result<usize, Error> {
+        self.db.estimate_candidates(key)
+    }
+
+    fn health_check(&self, sample: usize) -> HealthReport {
+        self.db.health_check(sample)
+    }
+
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        self.db.verify_and_repair()
+    }
+
+    fn set_ignored_dimensions(&mut self, dimensions: Vec<usize>) -> Result<(), Error> {
+        self.db.set_ignored_dimensions(dimensions)
+    }
+
+    fn set_id_strategy(&mut self, strategy: IDStrategy) -> Result<(), Error> {
+        self.db.set_id_strategy(strategy)
+    }
+
+    fn set_compact_variants(&mut self) -> Result<(), Error> {
+        self.db.set_compact_variants()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.db.compact()
+    }
+
+    fn set_variant_cache(&mut self, sets: usize) -> Result<(), Error> {
+        self.db.set_variant_cache(sets)
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        self.db.warmup(fraction)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        self.db.stats()
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        self.db.shadow_stats()
+    }
+
+    /// Increments `key`'s count, only indexing it the first time
+    ///
+    /// Returns true if `key` wasn't already present
+    ///
+    fn insert(&mut self, key: T) -> Result<bool, Error> {
+        let count = try!(self.count(&key));
+        self.db.insert_with_payload(key, count + 1)
+    }
+
+    /// Values repeated within `keys` are counted once per repeat
+    ///
+    fn bulk_insert(&mut self, keys: Vec<T>) -> Result<(), Error> {
+        let mut counts: HashMap<T, u64> = HashMap::new();
+        for key in keys.into_iter() {
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        let mut entries = Vec::with_capacity(counts.len());
+        for (key, added) in counts.into_iter() {
+            let count = try!(self.count(&key));
+            entries.push((key, count + added));
+        }
+        self.db.bulk_insert_with_payloads(entries)
+    }
+
+    /// Decrements `key`'s count, only removing it from the index once it
+    /// reaches zero
+    ///
+    /// Returns true if an insert of `key` was undone.
+    ///
+    fn remove(&mut self, key: &T) -> Result<bool, Error> {
+        if try!(self.decrement(key)) {
+            return Ok(true)
+        }
+        self.db.remove(key)
+    }
+
+    /// A value inserted more than once isn't removed from the index, so
+    /// reports no variants checked
+    ///
+    fn remove_verified(&mut self, key: &T) -> Result<RemovalReport, Error> {
+        if try!(self.decrement(key)) {
+            return Ok(Default::default())
+        }
+        self.db.remove_verified(key)
+    }
+
+    fn get_by_id(&self, id: &u64) -> Result<Option<T>, Error> {
+        self.db.get_by_id(id)
+    }
+
+    /// Each value is visited once, whatever its count
+    ///
+    fn for_each_value(&self, f: &mut FnMut(T) -> Result<(), Error>) -> Result<(), Error> {
+        self.db.for_each_value(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, Factory, QueryOptions, StorageBackend};
+    use db::payload::Payload;
+    use db::counted::*;
+
+    #[test]
+    fn counts_repeated_inserts() {
+        let mut db = CountedDB::new(Payload::<u64, u64>::build(64, 2, StorageBackend::TempRocksDB).unwrap());
+
+        assert!(db.insert(0b0011).unwrap());
+        assert!(!db.insert(0b0011).unwrap());
+        db.bulk_insert(vec![0b0011, 0b1100]).unwrap();
+        assert_eq!(db.count(&0b0011), Ok(3));
+
+        let mut found = db.get_with_counts(&0b0000, &QueryOptions::default()).unwrap().unwrap();
+        found.sort();
+        assert_eq!(found, vec![(0b0011, 3), (0b1100, 1)]);
+
+        // Values stay indexed until every insert is undone
+        assert!(db.remove(&0b0011).unwrap());
+        assert!(db.remove(&0b0011).unwrap());
+        assert_eq!(db.count(&0b0011), Ok(1));
+        assert!(db.get(&0b0011).unwrap().unwrap().contains(&0b0011));
+
+        assert!(db.remove(&0b0011).unwrap());
+        assert_eq!(db.count(&0b0011), Ok(0));
+        assert!(!db.get(&0b0000).unwrap().unwrap().contains(&0b0011));
+    }
+}
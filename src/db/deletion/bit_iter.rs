@@ -0,0 +1,111 @@
+use std::mem::size_of;
+use std::iter::Iterator;
+
+/// Deletion variants of binary windows
+///
+/// Each dimension of a binary window is a single bit, so a bit is "deleted" by
+/// clearing it and tagging the result with the bit's index.  Without the
+/// index, deleting a set bit would produce the same variant as deleting a
+/// clear bit of a value differing from the source in that one dimension.
+///
+/// Unlike `XORIter` no hashing is needed - the variants are no larger than the
+/// window itself, and can't collide.
+///
+pub struct BitIter<T> {
+    // The original value, which shouldn't be modified
+    source: T,
+    // Iteration cursor
+    index: usize,
+    // The number of dimensions to iterate over
+    dimensions: usize,
+}
+
+impl<T> BitIter<T> {
+    pub fn new(v: T, dimensions: usize) -> Self {
+        BitIter {
+            source: v,
+            index: 0,
+            dimensions: dimensions,
+        }
+    }
+}
+
+macro_rules! bit_iterator {
+    ($elem:ident) => {
+        impl Iterator for BitIter<$elem> {
+            type Item = ($elem, u8);
+
+            fn next(&mut self) -> Option<($elem, u8)> {
+                if self.index >= self.dimensions {
+                    None
+                } else {
+                    let next_value = (self.source & !(1 << self.index), self.index as u8);
+                    self.index += 1;
+                    Some(next_value)
+                }
+            }
+        }
+    }
+}
+bit_iterator!(u8);
+bit_iterator!(u16);
+bit_iterator!(u32);
+bit_iterator!(u64);
+
+macro_rules! bit_array_iterator {
+    ([$elem:ty; $elems:expr]) => {
+        impl Iterator for BitIter<[$elem; $elems]> {
+            type Item = ([$elem; $elems], u8);
+
+            fn next(&mut self) -> Option<([$elem; $elems], u8)> {
+                let offset = self.index / (8 * size_of::<$elem>());
+                let index = self.index % (8 * size_of::<$elem>());
+
+                if self.index >= self.dimensions {
+                    None
+                } else {
+                    let mut next_value = self.source.clone();
+                    next_value[offset] = next_value[offset] & !(1 << index);
+                    let deleted = self.index as u8;
+                    self.index += 1;
+                    Some((next_value, deleted))
+                }
+            }
+        }
+    }
+}
+bit_array_iterator!([u64; 4]);
+bit_array_iterator!([u64; 2]);
+
+#[cfg(test)]
+mod test {
+    use db::deletion::DeletionVariant;
+
+    #[test]
+    fn deletes_each_bit() {
+        let a = 0b00001010u8;
+        let expected = vec![
+            (0b00001010u8, 0),
+            (0b00001000u8, 1),
+            (0b00001010u8, 2),
+            (0b00000010u8, 3),
+        ];
+
+        assert_eq!(a.deletion_variants(4).collect::<Vec<(u8, u8)>>(), expected);
+    }
+
+    #[test]
+    fn variants_match_one_bit_away() {
+        let a = [0u64, 1 << 3];
+        let b = [0u64, 0];
+
+        let a_variants: Vec<([u64; 2], u8)> = a.deletion_variants(128).collect();
+        let b_variants: Vec<([u64; 2], u8)> = b.deletion_variants(128).collect();
+        let shared: Vec<u8> = a_variants.iter().zip(b_variants.iter())
+            .filter(|&(x, y)| x == y)
+            .map(|(x, _)| x.1)
+            .collect();
+
+        assert_eq!(shared, vec![67]);
+    }
+}
@@ -3,17 +3,43 @@ use std::cmp::*;
 use std::clone::*;
 use std::collections::*;
 use std::collections::hash_map::Entry::*;
+use std::hash::Hash;
+use std::borrow::Cow;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
 
 use num::rational::Ratio;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use bit_matrix::AsBitMatrix;
 
 use db::id_map;
+use db::atomic;
 use db::TypeMap;
-use db::Database;
-use db::result_accumulator::ResultAccumulator;
+#[cfg(not(target_arch = "wasm32"))]
+use db::Strategy;
+use db::{Database, QueryOptions, RemovalReport, Page, WarmupReport, count_references, order_by_recency, paginate, warmup_stores};
+use db::result_accumulator::{ResultAccumulator, Accumulator};
+#[cfg(feature = "rocksdb")]
+use db::spilling_accumulator::SpillingAccumulator;
+use db::read_only::ReadOnly;
+use db::sink::{Sink, TopK};
+use db::health::{self, HealthReport, RepairReport};
+use db::snapshot;
+#[cfg(not(target_arch = "wasm32"))]
+use db::frozen;
+use db::stats::{self, Stats};
 use db::map_set::{MapSet, InMemoryHash};
-use db::window::{Window, Windowable};
-use db::id_map::{ToID, IDMap};
-use db::deletion::{Key, DeletionVariant, Dvec};
+use db::window::Window;
+use db::id_map::{ToID, FromID, IDMap, IDStrategy};
+use db::hamming::{Hamming, ElementHamming};
+use db::hashing::State;
+use db::dimension_mask;
+use db::query_log::QueryTimer;
+use db::deletion::{Key, DeletionVariant, DeletionWindow, Dvec, ElementDistance};
+use db::variant_budget::{VariantBudget, VariantEstimate, VariantExplosion};
+use error::Error;
 
 type TypeMapVecU8 = (Vec<u8>, id_map::HashMap<u64, Vec<u8>>, InMemoryHash<Key<Dvec>, u64>);
 
@@ -38,6 +64,13 @@ pub struct DB<T = (Vec<u8>, id_map::HashMap<u64, Vec<u8>>, InMemoryHash<Key<Dvec
     pub partition_count: usize,
     pub partitions: Vec<Window>,
 
+    element_distance: ElementDistance,
+    element_verifier: Option<fn(&<T as TypeMap>::Input, &<T as TypeMap>::Input, usize, usize) -> bool>,
+    ignored: Vec<usize>,
+    id_strategy: Option<IDStrategy>,
+    // Set by the first insert, after which the settings above are fixed
+    written: bool,
+
     value_store: <T as TypeMap>::ValueStore,
     variant_store: <T as TypeMap>::VariantStore,
 }
@@ -46,6 +79,7 @@ impl<T: TypeMap> DB<T> where
 <T as TypeMap>::ValueStore: Default,
 <T as TypeMap>::VariantStore: Default,
 <T as TypeMap>::Window: DeletionVariant<<T as TypeMap>::Variant>,
+for<'a> <T as TypeMap>::Input: DeletionWindow<'a, <T as TypeMap>::Variant>,
 <T as TypeMap>::VariantStore: MapSet<Key<<T as TypeMap>::Variant>, <T as TypeMap>::Identifier>,
 {
 
@@ -57,12 +91,30 @@ impl<T: TypeMap> DB<T> where
     pub fn new(dimensions: usize, tolerance: usize) -> DB<T> {
         DB::with_stores(dimensions, tolerance, Default::default(), Default::default())
     }
+
+    /// Create a new DB with default backing store, failing if inserts would
+    /// exceed `budget`
+    ///
+    pub fn with_budget(dimensions: usize, tolerance: usize, budget: &VariantBudget) -> Result<DB<T>, VariantExplosion> {
+        DB::try_with_stores(dimensions, tolerance, Default::default(), Default::default(), budget)
+    }
 }
 
 impl<T: TypeMap> DB<T> where
 <T as TypeMap>::Window: DeletionVariant<<T as TypeMap>::Variant>,
+for<'a> <T as TypeMap>::Input: DeletionWindow<'a, <T as TypeMap>::Variant>,
 <T as TypeMap>::VariantStore: MapSet<Key<<T as TypeMap>::Variant>, <T as TypeMap>::Identifier>,
 {
+    /// Create a new DB with given backing store, failing if inserts would
+    /// exceed `budget`
+    ///
+    pub fn try_with_stores(dimensions: usize, tolerance: usize, value_store: <T as TypeMap>::ValueStore, variant_store: <T as TypeMap>::VariantStore, budget: &VariantBudget) -> Result<DB<T>, VariantExplosion> {
+        let estimate = VariantEstimate::for_types::<<T as TypeMap>::Variant, <T as TypeMap>::Identifier>(dimensions, tolerance);
+        try!(budget.check(estimate));
+
+        Ok(DB::with_stores(dimensions, tolerance, value_store, variant_store))
+    }
+
     /// Create a new DB with given backing store
     ///
     /// Partitions the keyspace as evenly as possible - all partitions
@@ -100,102 +152,532 @@ impl<T: TypeMap> DB<T> where
             partitions.push(Window{start_dimension: start_dimension, dimensions: dimensions});
         }
 
-        // Done!
-        return DB {
+        let db = DB {
             dimensions: dimensions,
             tolerance: tolerance,
             partition_count: partition_count,
             partitions: partitions,
 
+            element_distance: ElementDistance::Exact,
+            element_verifier: None,
+            ignored: Vec::new(),
+            id_strategy: None,
+            written: false,
+
             value_store: value_store,
             variant_store: variant_store,
         };
+        info!("Created {}", db);
+
+        db
+    }
+
+    /// Fails with `Error::Invalid` once anything has been inserted, as
+    /// changing `setting` would orphan the values already indexed
+    ///
+    fn check_unwritten(&self, setting: &str) -> Result<(), Error> {
+        if self.written {
+            return Err(Error::Invalid(format!("{} can't change once values are inserted", setting)))
+        }
+        Ok(())
+    }
+
+    /// `key` with the ignored dimensions cleared
+    ///
+    fn masked<'a>(&self, key: &'a <T as TypeMap>::Input) -> Cow<'a, <T as TypeMap>::Input> {
+        dimension_mask::apply(key, &self.ignored)
+    }
+
+    /// The identifier `key` is stored under
+    ///
+    fn id(&self, key: &<T as TypeMap>::Input) -> <T as TypeMap>::Identifier {
+        match self.id_strategy {
+            Some(strategy) => key.clone().to_id_with(strategy),
+            None => key.clone().to_id(),
+        }
+    }
+
+    /// Fails with `Error::Collision` if a value other than `key` is stored
+    /// under `id`
+    ///
+    fn check_collision(&self, id: &<T as TypeMap>::Identifier, key: &<T as TypeMap>::Input) -> Result<(), Error> {
+        match try!(self.value_store.get(id.clone())) {
+            Some(ref existing) if existing != key => Err(Error::Collision("a different value is stored under this value's identifier".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every deletion variant key `key` is indexed under
+    ///
+    fn variant_keys(&self, key: &<T as TypeMap>::Input) -> Vec<Key<<T as TypeMap>::Variant>> {
+        let key = self.masked(key);
+        let mut keys = Vec::new();
+        for window in self.partitions.iter() {
+            keys.extend(key.window_deletion_variants(window.start_dimension, window.dimensions).map(|deletion_variant| (window.clone(), deletion_variant)));
+        }
+        keys
+    }
+
+    /// The number of `key`'s deletion variants in `window` each candidate ID
+    /// shares (`key` is already masked)
+    ///
+    fn partition_counts(&self, key: &<T as TypeMap>::Input, window: &Window) -> Result<HashMap<<T as TypeMap>::Identifier, usize, State>, Error> {
+        let mut counts: HashMap<<T as TypeMap>::Identifier, usize, State> = HashMap::default();
+
+        for variant in key.window_deletion_variants(window.start_dimension, window.dimensions) {
+            match try!(self.variant_store.get(&(window.clone(), variant))) {
+                Some(ids) => {
+                    // Iterate through the values found in the deletion variant's set
+                    for id in ids.iter() {
+                        // Increment the key's count (this is sort of cumberson in Rust...)
+                        match counts.entry(id.clone()) {
+                            Occupied(mut entry) => { *entry.get_mut() += 1; },
+                            Vacant(entry) => { entry.insert(1); },
+                        }
+                    }
+                },
+                None => (),
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Feed the values found in each partition for `key` into `results`
+    ///
+    fn accumulate<A: Accumulator<<T as TypeMap>::Input>>(&self, key: &<T as TypeMap>::Input, results: &mut A, timer: &mut QueryTimer) -> Result<(), Error> {
+        let key = self.masked(key);
+
+        // Candidate IDs from every partition, and whether each matched a zero
+        // variant
+        let mut hits: Vec<(<T as TypeMap>::Identifier, bool)> = Vec::new();
+
+        // Split across tasks?
+        for (i, window) in self.partitions.iter().enumerate() {
+            let counts = try!(self.partition_counts(&key, window));
+            hits.extend(counts.into_iter().map(|(id, count)| (id, count > 2)));
+            timer.lap(i);
+        }
+
+        // Values matching in several partitions are only read once
+        let mut values: HashMap<<T as TypeMap>::Identifier, Option<<T as TypeMap>::Input>, State> = HashMap::default();
+        for (id, zero) in hits.into_iter() {
+            if !values.contains_key(&id) {
+                let value = try!(self.value_store.get(id.clone()));
+                values.insert(id.clone(), value);
+            }
+
+            // An interrupted remove can leave entries whose value is gone
+            // (see `Database::verify_and_repair`) - they can't match
+            let value = match values.get(&id) {
+                Some(&Some(ref value)) => value,
+                _ => continue,
+            };
+
+            if zero {
+                results.insert_zero_variant(value)
+            } else {
+                results.insert_one_variant(value)
+            }
+        }
+        timer.lap_shared();
+
+        Ok(())
+    }
+
+    /// Pushes each indexed value within `self.tolerance` (or
+    /// `options.max_distance`) of `key` into `sink`
+    ///
+    pub fn query_into<S: Sink<<T as TypeMap>::Input>>(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, sink: &mut S) -> Result<(), Error> {
+        let tolerance = try!(options.verification_tolerance(self.tolerance));
+
+        let mut results = ResultAccumulator::new(self.tolerance, key.clone());
+        results.set_exclude_exact(options.exclude_exact);
+
+        let mut timer = QueryTimer::start(self.partitions.len());
+        try!(self.accumulate(key, &mut results, &mut timer));
+
+        match (self.element_distance, self.element_verifier) {
+            (ElementDistance::Bitwise(cap), Some(verify)) => {
+                results.drain_into_with(|query, candidate| verify(&self.masked(query), &self.masked(candidate), cap, tolerance), sink)
+            },
+            (ElementDistance::Exact, Some(verify)) => {
+                results.drain_into_with(|query, candidate| verify(&self.masked(query), &self.masked(candidate), 1, tolerance), sink)
+            },
+            _ => results.drain_into_with(|query, candidate| self.masked(query).hamming_lte(&self.masked(candidate), tolerance), sink),
+        }
+        timer.finish(self);
+        Ok(())
+    }
+
+    /// Every value sharing a deletion variant with `key` in at least one
+    /// partition, scored as `(value, matching_partitions, hamming)`
+    ///
+    /// Unlike `get`, candidates aren't limited to `self.tolerance`: a value
+    /// is returned however far it is from `key`, along with the number of
+    /// partitions it was found in and its distance (counting differing
+    /// elements, ignoring `element_distance`).  Values within tolerance are
+    /// always found in at least one partition, and the more partitions a
+    /// value matches in, the more of `key` it shares.  Results are sorted by
+    /// most partitions, then nearest first.
+    ///
+    pub fn get_scored(&self, key: &<T as TypeMap>::Input) -> Result<Vec<(<T as TypeMap>::Input, usize, usize)>, Error> {
+        let key = self.masked(key);
+
+        let mut support: HashMap<<T as TypeMap>::Identifier, usize, State> = HashMap::default();
+        for window in self.partitions.iter() {
+            for (id, _) in try!(self.partition_counts(&key, window)).into_iter() {
+                *support.entry(id).or_insert(0) += 1;
+            }
+        }
+
+        let mut scored = Vec::with_capacity(support.len());
+        for (id, partitions) in support.into_iter() {
+            // Entries whose value is gone can't match (see `accumulate`)
+            if let Some(value) = try!(self.value_store.get(id)) {
+                let distance = key.hamming(&self.masked(&value));
+                scored.push((value, partitions, distance));
+            }
+        }
+
+        scored.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+        Ok(scored)
+    }
+
+    /// Write the database to a read-only file at `path`, which can be opened
+    /// with `FrozenDB::open_deletion` (see `db::frozen`)
+    ///
+    /// Element distances and verifiers aren't written, so the frozen
+    /// database compares elements exactly.
+    ///
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn finalize(&self, path: &Path) -> Result<(), Error> where
+    <T as TypeMap>::Input: Serialize,
+    <T as TypeMap>::Identifier: Serialize,
+    Key<<T as TypeMap>::Variant>: Serialize,
+    {
+        let header = frozen::Header{
+            strategy: Strategy::Deletion,
+            dimensions: self.dimensions,
+            tolerance: self.tolerance,
+            ignored: self.ignored.clone(),
+            compact_variants: false,
+        };
+        frozen::finalize(&header, &self.variant_store, &self.value_store, path)
     }
 }
 
-impl<T: TypeMap> Database<<T as TypeMap>::Input> for  DB<T> where
+fn element_hamming_lte<E: Hamming>(a: &Vec<E>, b: &Vec<E>, cap: usize, bound: usize) -> bool {
+    a.element_hamming_lte(b, cap, bound)
+}
+
+fn bit_plane_hamming_lte<E>(a: &Vec<E>, b: &Vec<E>, cap: usize, bound: usize) -> bool where
+E: Eq + Clone + Hash + Hamming,
+Vec<E>: AsBitMatrix,
+{
+    // Planes of different widths can't be compared column for column, so
+    // compare them as the element by element verifiers would
+    if a.len() != b.len() {
+        return match cap {
+            1 => a.hamming_lte(b, bound),
+            _ => a.element_hamming_lte(b, cap, bound),
+        }
+    }
+    a.bit_planes().capped_column_hamming_lte(&b.bit_planes(), cap, bound)
+}
+
+impl<T, E> DB<T> where
+T: TypeMap<Input = Vec<E>>,
+E: Hamming,
+{
+    /// Set how vector elements contribute to the distance used to verify
+    /// query results
+    ///
+    pub fn set_element_distance(&mut self, element_distance: ElementDistance) {
+        match element_distance {
+            ElementDistance::Exact => {
+                self.element_verifier = None;
+            },
+            ElementDistance::Bitwise(cap) => {
+                assert!(cap >= 1, "bitwise element distance cap must be at least 1");
+                self.element_verifier = Some(element_hamming_lte::<E>);
+            },
+        }
+        self.element_distance = element_distance;
+    }
+}
+
+impl<T, E> DB<T> where
+T: TypeMap<Input = Vec<E>>,
+E: Eq + Clone + Hash + Hamming,
+Vec<E>: AsBitMatrix,
+{
+    /// Verify query results by comparing the bit planes of each candidate and
+    /// the query (HmSearch's "HBVerify"), rather than element by element
+    ///
+    /// Applies to the current `element_distance`: differing elements are
+    /// counted a word of columns at a time, and with
+    /// `ElementDistance::Bitwise` each column counts the planes it differs in
+    /// (see `BitMatrix::capped_column_hamming`).  Results are the same as
+    /// comparing elements.  The next call to `set_element_distance` goes back
+    /// to comparing elements.
+    ///
+    pub fn set_bit_plane_verification(&mut self) {
+        self.element_verifier = Some(bit_plane_hamming_lte::<E>);
+    }
+}
+
+impl<T: TypeMap> DB<T> {
+    pub fn element_distance(&self) -> ElementDistance {
+        self.element_distance
+    }
+}
+
+impl<T: TypeMap + 'static> Database<<T as TypeMap>::Input> for  DB<T> where
 <T as TypeMap>::Window: DeletionVariant<<T as TypeMap>::Variant>,
+for<'a> <T as TypeMap>::Input: DeletionWindow<'a, <T as TypeMap>::Variant>,
 <T as TypeMap>::VariantStore: MapSet<Key<<T as TypeMap>::Variant>, <T as TypeMap>::Identifier>,
+<T as TypeMap>::Identifier: Serialize + DeserializeOwned,
 {
     /// Get all indexed values within `self.tolerance` hamming distance of `key`
     ///
-    fn get(&self, key: &<T as TypeMap>::Input) -> Option<HashSet<<T as TypeMap>::Input>> {
-        let mut results = ResultAccumulator::new(self.tolerance, key.clone());
+    fn get_with(&self, key: &<T as TypeMap>::Input, options: &QueryOptions) -> Result<Option<HashSet<<T as TypeMap>::Input, State>>, Error> {
+        let mut found = HashSet::default();
+        try!(self.query_into(key, options, &mut found));
 
-        // Split across tasks?
+        match found.len() {
+            0 => Ok(None),
+            _ => Ok(Some(found)),
+        }
+    }
+
+    /// Holds at most `spill_threshold` candidates in memory, spilling the rest
+    /// to a temporary RocksDB
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn for_each_match(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(<T as TypeMap>::Input)) -> Result<(), Error> where
+    <T as TypeMap>::Input: Serialize + DeserializeOwned,
+    {
+        let tolerance = try!(options.verification_tolerance(self.tolerance));
+
+        let mut results = SpillingAccumulator::new(self.tolerance, key.clone(), spill_threshold);
+        results.set_exclude_exact(options.exclude_exact);
+
+        let mut timer = QueryTimer::start(self.partitions.len());
+        try!(self.accumulate(key, &mut results, &mut timer));
+
+        try!(match (self.element_distance, self.element_verifier) {
+            (ElementDistance::Bitwise(cap), Some(verify)) => {
+                results.for_each_found(|query, candidate| verify(&self.masked(query), &self.masked(candidate), cap, tolerance), |value| f(value))
+            },
+            (ElementDistance::Exact, Some(verify)) => {
+                results.for_each_found(|query, candidate| verify(&self.masked(query), &self.masked(candidate), 1, tolerance), |value| f(value))
+            },
+            _ => results.for_each_found(|query, candidate| self.masked(query).hamming_lte(&self.masked(candidate), tolerance), |value| f(value)),
+        });
+        timer.finish(self);
+        Ok(())
+    }
+
+    fn get_page_with(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, cursor: Option<&<T as TypeMap>::Input>, limit: usize) -> Result<Page<<T as TypeMap>::Input>, Error> {
+        let found = try!(self.get_with(key, options)).unwrap_or(HashSet::default());
+        paginate(found, cursor, limit, |value| self.id(value))
+    }
+
+    fn get_recent_with(&self, key: &<T as TypeMap>::Input, options: &QueryOptions) -> Result<Option<Vec<<T as TypeMap>::Input>>, Error> {
+        Ok(try!(self.get_with(key, options)).map(|found| self.sort_by_recency(found)))
+    }
+
+    fn sort_by_recency(&self, found: HashSet<<T as TypeMap>::Input, State>) -> Vec<<T as TypeMap>::Input> {
+        order_by_recency(found, &self.value_store, |value| self.id(value))
+    }
+
+    fn get_topk(&self, key: &<T as TypeMap>::Input, k: usize) -> Result<Option<Vec<<T as TypeMap>::Input>>, Error> {
+        let mut top = TopK::new(key.clone(), k);
+        try!(self.query_into(key, &Default::default(), &mut top));
+
+        let nearest: Vec<<T as TypeMap>::Input> = top.into_sorted_vec().into_iter().map(|(_, v)| v).collect();
+        match nearest.len() {
+            0 => Ok(None),
+            _ => Ok(Some(nearest)),
+        }
+    }
+
+    fn estimate_candidates(&self, key: &<T as TypeMap>::Input) -> Result<usize, Error> {
+        let key = self.masked(key);
+        let mut count = 0;
         for window in self.partitions.iter() {
-            let mut counts: HashMap<<T as TypeMap>::Identifier, usize> = HashMap::new();
-            let transformed_key = key.window(window.start_dimension, window.dimensions);
-
-            for variant in transformed_key.deletion_variants(window.dimensions) {
-                match self.variant_store.get(&(window.clone(), variant)) {
-                    Some(ids) => {
-                        // Iterate through the values found in the deletion variant's set
-                        for id in ids.iter() {
-                            // Increment the key's count (this is sort of cumberson in Rust...)
-                            match counts.entry(id.clone()) {
-                                Occupied(mut entry) => { *entry.get_mut() += 1; },
-                                Vacant(entry) => { entry.insert(1); },
-                            }
-                        }
-                    },
-                    None => (),
-                }
+            for variant in key.window_deletion_variants(window.start_dimension, window.dimensions) {
+                count += try!(self.variant_store.count(&(window.clone(), variant)));
             }
+        }
+        Ok(count)
+    }
 
-            for (id, count) in counts {
-                if count > 2 {
-                    results.insert_zero_variant(&self.value_store.get(id))
-                } else {
-                    results.insert_one_variant(&self.value_store.get(id))
-                }
-            }
+    fn set_ignored_dimensions(&mut self, dimensions: Vec<usize>) -> Result<(), Error> {
+        try!(self.check_unwritten("ignored dimensions"));
+        if let Some(d) = dimensions.iter().find(|&&d| d >= self.dimensions) {
+            return Err(Error::Invalid(format!("dimension {} doesn't exist in a {}-dimension DB", d, self.dimensions)))
         }
+        self.ignored = dimensions;
+        Ok(())
+    }
+
+    fn set_id_strategy(&mut self, strategy: IDStrategy) -> Result<(), Error> {
+        try!(self.check_unwritten("the ID strategy"));
+        if !<<T as TypeMap>::Input as ToID<<T as TypeMap>::Identifier>>::supports_id_strategy(strategy) {
+            return Err(Error::Unsupported(format!("{:?} identifiers for this value type", strategy)))
+        }
+        self.id_strategy = Some(strategy);
+        Ok(())
+    }
+
+    fn health_check(&self, sample: usize) -> HealthReport {
+        health::check_stores(&self.variant_store, &self.value_store, sample)
+    }
+
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        health::repair_stores(&mut self.variant_store, &self.value_store)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        try!(self.variant_store.sync());
+        self.value_store.sync()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        try!(self.value_store.compact());
+        self.variant_store.compact()
+    }
+
+    fn set_variant_cache(&mut self, sets: usize) -> Result<(), Error> {
+        self.variant_store.set_cache_capacity(sets)
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        warmup_stores(&self.value_store, &self.variant_store, fraction)
+    }
 
-        results.found_values()
+    fn stats(&self) -> Result<Stats, Error> {
+        stats::collect(&self.variant_store, &self.value_store, &self.partitions, |&(ref window, _)| window)
+    }
+
+    fn for_each_value(&self, f: &mut FnMut(<T as TypeMap>::Input) -> Result<(), Error>) -> Result<(), Error> {
+        snapshot::scan_values(&self.variant_store, &self.value_store, &self.partitions[0], |&(ref window, _)| window, f)
+    }
+
+    /// Fails with `Error::Unsupported` unless both stores can take a
+    /// snapshot
+    ///
+    fn snapshot(&self) -> Result<ReadOnly<<T as TypeMap>::Input>, Error> {
+        let db: DB<T> = DB {
+            dimensions: self.dimensions,
+            tolerance: self.tolerance,
+            partition_count: self.partition_count,
+            partitions: self.partitions.clone(),
+            element_distance: self.element_distance,
+            element_verifier: self.element_verifier,
+            ignored: self.ignored.clone(),
+            id_strategy: self.id_strategy,
+            written: self.written,
+            value_store: try!(self.value_store.snapshot()),
+            variant_store: try!(self.variant_store.snapshot()),
+        };
+        Ok(ReadOnly::new(Box::new(db)))
     }
 
     /// Insert `key` into indices
     ///
-    /// Returns true if key was added to ANY index
+    /// Returns true if key was added to ANY index.  The value and its
+    /// variants are written together (see `db::atomic`).  Fails with
+    /// `Error::Collision` rather than overwriting a different value with the
+    /// same identifier.
     ///
-    fn insert(&mut self, key: <T as TypeMap>::Input) -> bool {
-        let id = key.clone().to_id();
-        self.value_store.insert(id.clone(), key.clone());
+    fn insert(&mut self, key: <T as TypeMap>::Input) -> Result<bool, Error> {
+        self.written = true;
+        let id = self.id(&key);
+        try!(self.check_collision(&id, &key));
 
-        // Split across tasks?
-        self.partitions.clone().into_iter().map(|window| {
-            let transformed_key = key.window(window.start_dimension, window.dimensions);
+        // NOTE: think about how to detect 'new' values
+        let groups = self.variant_keys(&key).into_iter().map(|k| (k, vec![])).collect();
 
-            // NOTE: think about how to detect 'new' values
-            transformed_key.deletion_variants(window.dimensions).map(|deletion_variant| {
-                self.variant_store.insert((window.clone(), deletion_variant.clone()), id.clone())
+        atomic::insert(&mut self.value_store, &mut self.variant_store, id, key, groups)
+    }
 
-            }).collect::<Vec<bool>>().iter().any(|i| *i)
+    /// Every variant of the batch is computed up front, and written to the
+    /// variant store in one `bulk_insert`.  A collision stops the batch, but
+    /// the values before it are still indexed.
+    ///
+    fn bulk_insert(&mut self, keys: Vec<<T as TypeMap>::Input>) -> Result<(), Error> {
+        self.written = true;
+        let mut entries = Vec::new();
+
+        for key in keys.into_iter() {
+            let id = self.id(&key);
+            if let Err(e) = self.check_collision(&id, &key) {
+                try!(self.variant_store.bulk_insert(entries));
+                return Err(e)
+            }
+            let masked = self.masked(&key).into_owned();
+
+            for window in self.partitions.iter() {
+                for deletion_variant in masked.window_deletion_variants(window.start_dimension, window.dimensions) {
+                    entries.push(((window.clone(), deletion_variant), id.clone()));
+                }
+            }
+
+            // Values are stored before any variant references them
+            try!(self.value_store.insert(id, key));
+        }
 
-            // Collecting first to force evaluation
-        }).collect::<Vec<bool>>().iter().any(|i| *i)
+        self.variant_store.bulk_insert(entries)
     }
 
     /// Remove `key` from indices
     ///
-    /// Returns true if key was removed from ANY index
+    /// Returns true if key was removed from ANY index.  The value and its
+    /// variants are removed together (see `db::atomic`).
     ///
-    fn remove(&mut self, key: &<T as TypeMap>::Input) -> bool {
-        let id = key.clone().to_id();
-        self.value_store.remove(&id);
+    fn remove(&mut self, key: &<T as TypeMap>::Input) -> Result<bool, Error> {
+        let id = self.id(key);
+        let keys = self.variant_keys(key);
 
-        // Split across tasks?
-        self.partitions.clone().into_iter().map(|window| {
-            let transformed_key = key.window(window.start_dimension, window.dimensions);
+        Ok(try!(atomic::remove(&mut self.value_store, &mut self.variant_store, &id, &keys)) > 0)
+    }
 
-            transformed_key.deletion_variants(window.dimensions).map(|ref deletion_variant| {
-                self.variant_store.remove(&(window.clone(), deletion_variant.clone()), &id)
+    fn remove_verified(&mut self, key: &<T as TypeMap>::Input) -> Result<RemovalReport, Error> {
+        let id = self.id(key);
+        let keys = self.variant_keys(key);
 
-            }).collect::<Vec<bool>>().iter().any(|i| *i)
+        let mut report: RemovalReport = Default::default();
+        report.variants_removed = try!(atomic::remove(&mut self.value_store, &mut self.variant_store, &id, &keys));
 
-            // Collecting first to force evaluation
-        }).collect::<Vec<bool>>().iter().any(|i| *i)
+        let (checked, remaining) = try!(count_references(&self.variant_store, &id));
+        report.variants_checked = checked;
+        report.variants_remaining = remaining;
+        Ok(report)
+    }
+
+    fn get_by_id(&self, id: &u64) -> Result<Option<<T as TypeMap>::Input>, Error> {
+        let id = match <T as TypeMap>::Identifier::from_id(*id) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let value = match try!(self.value_store.get(id.clone())) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        // Echo value stores resolve every ID, so check the value is indexed
+        let keys = self.variant_keys(&value);
+        let key = match keys.first() {
+            Some(key) => key,
+            None => return Ok(Some(value)),
+        };
+        match try!(self.variant_store.get(key)) {
+            Some(ref ids) if ids.contains(&id) => Ok(Some(value)),
+            _ => Ok(None),
+        }
     }
 }
 
@@ -205,6 +687,12 @@ impl<T: TypeMap> fmt::Debug for DB<T> {
     }
 }
 
+impl<T: TypeMap> fmt::Display for DB<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "deletion DB ({} dimensions, tolerance {}, {} partitions)", self.dimensions, self.tolerance, self.partition_count)
+    }
+}
+
 impl<T: TypeMap> PartialEq for DB<T> {
     fn eq(&self, other: &DB<T>) -> bool {
         return self.dimensions == other.dimensions &&
@@ -296,27 +784,26 @@ fn test_ddb_partition_with_no_bytes() {
 mod test {
     extern crate rand;
     extern crate quickcheck;
-    extern crate bincode;
-    extern crate rustc_serialize;
 
     use self::quickcheck::quickcheck;
 
     use std::collections::HashSet;
     use std::iter::repeat;
     use self::rand::{thread_rng, sample, Rng};
-    use bincode::SizeLimit;
-    use bincode::rustc_serialize::{encode};
-
+    use db::encoding::encode;
 
     use db::*;
-    use db::deletion::{DB};
+    use db::deletion::{DB, ElementDistance};
     use db::deletion::db::{TypeMapVecU8};
+    use db::id_map::{ToID, IDMap, IDStrategy};
+    use error::Error;
+    use db::hashing::State;
 
     #[test]
     fn find_missing_key() {
         let p: DB<TypeMapVecU8> = DB::new(8, 2);
         let a = vec![0,0,0,0,0,0,0,0];
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(None, keys);
     }
@@ -326,7 +813,110 @@ mod test {
         let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
         let a = vec![0,0,0,0,0,0,0,0];
 
-        assert!(p.insert(a.clone()));
+        assert!(p.insert(a.clone()).unwrap());
+    }
+
+    #[test]
+    fn get_and_remove_by_id() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,1];
+        let id: u64 = a.clone().to_id();
+        p.insert(a.clone()).unwrap();
+
+        assert_eq!(p.get_by_id(&id).unwrap(), Some(a.clone()));
+        assert_eq!(p.get_by_id(&(id ^ 1)).unwrap(), None);
+
+        assert!(p.remove_id(&id).unwrap());
+        assert_eq!(p.get_by_id(&id).unwrap(), None);
+        assert_eq!(p.get(&a).unwrap(), None);
+    }
+
+    #[test]
+    fn skips_ids_missing_from_value_store() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,1];
+        let b = vec![0,0,0,0,0,0,1,1];
+        p.insert(a.clone()).unwrap();
+        p.insert(b.clone()).unwrap();
+
+        // As if a remove of `a` was interrupted after its value was removed
+        let id: u64 = a.clone().to_id();
+        p.value_store.remove(&id).unwrap();
+
+        let expected: HashSet<Vec<u8>, State> = vec![b.clone()].into_iter().collect();
+        assert_eq!(p.get(&a).unwrap(), Some(expected));
+        assert_eq!(p.get_by_id(&id).unwrap(), None);
+    }
+
+    #[test]
+    fn inserts_detect_id_collisions() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,1];
+        let b = vec![0,0,0,0,0,0,1,1];
+
+        // As if `b` hashed to the same ID as `a`
+        let id: u64 = a.clone().to_id();
+        p.value_store.insert(id, b.clone()).unwrap();
+
+        match p.insert(a.clone()) {
+            Err(Error::Collision(..)) => (),
+            other => panic!("expected a collision, got {:?}", other),
+        }
+        assert_eq!(p.value_store.get(id).unwrap(), Some(b.clone()));
+        assert!(p.bulk_insert(vec![b.clone(), a.clone()]).is_err());
+    }
+
+    #[test]
+    fn values_are_identified_by_strategy() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        p.set_id_strategy(IDStrategy::Fnv).unwrap();
+        let a = vec![0,0,0,0,0,0,0,1];
+        let id: u64 = a.clone().to_id_with(IDStrategy::Fnv);
+        let default_id: u64 = a.clone().to_id();
+        p.insert(a.clone()).unwrap();
+
+        assert!(id != default_id);
+        assert_eq!(p.get_by_id(&id).unwrap(), Some(a.clone()));
+        assert!(p.remove(&a).unwrap());
+        assert_eq!(p.get_by_id(&id).unwrap(), None);
+
+        assert!(p.set_id_strategy(IDStrategy::Echo).is_err());
+    }
+
+    #[test]
+    fn ignored_dimensions_dont_count() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        p.set_ignored_dimensions(vec![0, 1]).unwrap();
+        let a = vec![9,9,1,0,0,0,0,0];
+        let b = vec![0,0,1,1,1,0,0,0];
+        p.insert(a.clone()).unwrap();
+        p.insert(b.clone()).unwrap();
+
+        let expected: HashSet<Vec<u8>, State> = vec![a.clone()].into_iter().collect();
+        assert_eq!(p.get(&vec![5,5,0,0,0,0,0,0]).unwrap(), Some(expected));
+        assert!(p.set_ignored_dimensions(vec![8]).is_err());
+    }
+
+    #[test]
+    fn ignored_dimensions_are_fixed_once_written() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        p.bulk_insert(vec![vec![0,0,0,0,0,0,0,1]]).unwrap();
+
+        match p.set_ignored_dimensions(vec![0, 1]) {
+            Err(Error::Invalid(_)) => {},
+            other => panic!("expected an invalid setting, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn id_strategy_is_fixed_once_written() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        p.insert(vec![0,0,0,0,0,0,0,1]).unwrap();
+
+        match p.set_id_strategy(IDStrategy::Fnv) {
+            Err(Error::Invalid(_)) => {},
+            other => panic!("expected an invalid setting, got {:?}", other),
+        }
     }
 
     #[test]
@@ -334,21 +924,36 @@ mod test {
         let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
         let a = vec![0,0,0,0,0,0,0,0];
 
-        p.insert(a.clone());
+        p.insert(a.clone()).unwrap();
 
-        assert!(!p.insert(a.clone()));
+        assert!(!p.insert(a.clone()).unwrap());
+    }
+
+    #[test]
+    fn get_within_narrows_the_tolerance() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,0];
+        let b = vec![0,0,0,0,0,0,1,1];
+        p.insert(a.clone()).unwrap();
+        p.insert(b.clone()).unwrap();
+
+        let mut expected = HashSet::default();
+        expected.insert(a.clone());
+        assert_eq!(p.get_within(&a, 1).unwrap(), Some(expected));
+        assert_eq!(p.get_within(&a, 2).unwrap().unwrap().len(), 2);
+        assert!(p.get_within(&a, 3).is_err());
     }
 
     #[test]
     fn find_inserted_key() {
         let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
         let a = vec![0,0,0,0,0,0,0,0];
-        let mut b = HashSet::new();
+        let mut b = HashSet::default();
         b.insert(a.clone());
 
-        assert!(p.insert(a.clone()));
+        assert!(p.insert(a.clone()).unwrap());
 
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(Some(b), keys);
     }
@@ -358,12 +963,12 @@ mod test {
         let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
         let a = vec![0,0,0,0,0,0,0,0];
         let b = vec![0,0,0,0,0,0,0,1];
-        let mut c = HashSet::new();
+        let mut c = HashSet::default();
         c.insert(a.clone());
 
-        p.insert(a.clone());
+        p.insert(a.clone()).unwrap();
 
-        let keys = p.get(&b);
+        let keys = p.get(&b).unwrap();
 
         assert_eq!(Some(c), keys);
     }
@@ -376,18 +981,18 @@ mod test {
         let c = vec![1,0,0,0,0,0,0,1];
         let d = vec![1,1,0,0,0,0,0,1];
         let e = vec![1,1,0,0,0,0,1,1];
-        let mut f = HashSet::new();
+        let mut f = HashSet::default();
         f.insert(b.clone());
         f.insert(c.clone());
         f.insert(d.clone());
         f.insert(e.clone());
 
-        p.insert(b.clone());
-        p.insert(c.clone());
-        p.insert(d.clone());
-        p.insert(e.clone());
+        p.insert(b.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+        p.insert(d.clone()).unwrap();
+        p.insert(e.clone()).unwrap();
 
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(Some(f), keys);
     }
@@ -410,17 +1015,36 @@ mod test {
                 b[*start_dimension] = 0;
             }
 
-            let mut c = HashSet::new();
+            let mut c = HashSet::default();
             c.insert(a.clone());
 
-            assert!(p.insert(a.clone()));
+            assert!(p.insert(a.clone()).unwrap());
 
-            let keys = p.get(&b);
+            let keys = p.get(&b).unwrap();
 
             assert_eq!(Some(c), keys);
         }
     }
 
+    #[test]
+    fn scores_partial_matches() {
+        // Two partitions of 4 dimensions each
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![1,1,1,1,1,1,1,1];
+        let b = vec![1,1,1,1,0,0,0,0];
+        let c = vec![0,0,0,0,0,0,0,0];
+        p.insert(a.clone()).unwrap();
+        p.insert(b.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+
+        // `b` is beyond tolerance, but shares the first partition; `c` shares
+        // neither
+        assert_eq!(p.get_scored(&a).unwrap(), vec![(a.clone(), 2, 0), (b.clone(), 1, 4)]);
+        assert_eq!(p.get(&a).unwrap(), Some(vec![a.clone()].into_iter().collect::<HashSet<Vec<u8>, State>>()));
+
+        assert_eq!(p.get_scored(&vec![1,1,1,1,0,0,0,1]).unwrap(), vec![(b.clone(), 2, 1), (a.clone(), 1, 3), (c.clone(), 1, 5)]);
+    }
+
     #[test]
     fn dont_find_permutation_of_inserted_key() {
         let mut rng1 = thread_rng();
@@ -443,27 +1067,101 @@ mod test {
                 b[*start_dimension] = 0;
             }
 
-            let mut c = HashSet::new();
+            let mut c = HashSet::default();
             c.insert(a.clone());
 
-            assert!(p.insert(a.clone()));
+            assert!(p.insert(a.clone()).unwrap());
 
-            let keys = p.get(&b);
+            let keys = p.get(&b).unwrap();
 
             assert_eq!(None, keys);
         }
     }
 
+    #[test]
+    fn bitwise_element_distance_filters_distant_elements() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,0];
+        let b = vec![0b111,0,0,0,0,0,0,0];
+        let c = vec![0b1,0,0,0,0,0,0,0];
+        let mut expected = HashSet::default();
+        expected.insert(c.clone());
+
+        p.insert(b.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+
+        assert_eq!(p.get(&a).unwrap().unwrap().len(), 2);
+
+        p.set_element_distance(ElementDistance::Bitwise(8));
+        assert_eq!(p.element_distance(), ElementDistance::Bitwise(8));
+        assert_eq!(Some(expected), p.get(&a).unwrap());
+
+        // Capping at 1 bit per element is equivalent to exact matching
+        p.set_element_distance(ElementDistance::Bitwise(1));
+        assert_eq!(p.get(&a).unwrap().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn bit_plane_verification_matches_exact() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,0];
+        let b = vec![0b111,0,0,0,0,0,0,5];
+        let c = vec![0b1,0,0,0,0,0,0,0];
+        let d = vec![1,1,1,0,0,0,0,0];
+
+        p.insert(b.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+        p.insert(d.clone()).unwrap();
+        let exact = p.get(&a).unwrap();
+
+        p.set_bit_plane_verification();
+        assert_eq!(p.element_distance(), ElementDistance::Exact);
+        assert_eq!(exact, p.get(&a).unwrap());
+        assert_eq!(p.get_within(&a, 1).unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn bit_plane_verification_matches_bitwise_element_distance() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,0];
+        for value in vec![vec![0b111,0,0,0,0,0,0,5], vec![0b1,0,0,0,0,0,0,0], vec![0b11,0b1,0,0,0,0,0,0], vec![1,1,1,0,0,0,0,0]] {
+            p.insert(value).unwrap();
+        }
+
+        for cap in 1..5 {
+            p.set_element_distance(ElementDistance::Bitwise(cap));
+            let folded = (p.get(&a).unwrap(), p.get_within(&a, 1).unwrap());
+
+            p.set_bit_plane_verification();
+            assert_eq!(p.element_distance(), ElementDistance::Bitwise(cap));
+            assert_eq!(folded, (p.get(&a).unwrap(), p.get_within(&a, 1).unwrap()));
+        }
+    }
+
+    #[test]
+    fn find_recent_permutations_newest_first() {
+        let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
+        let a = vec![0,0,0,0,0,0,0,0];
+        let b = vec![1,0,0,0,0,0,0,0];
+        let c = vec![0,0,0,0,0,0,0,1];
+
+        p.insert(b.clone()).unwrap();
+        p.insert(a.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+
+        assert_eq!(Some(vec![c, a, b]), p.get_recent(&vec![0,0,0,0,0,0,0,0]).unwrap());
+    }
+
     #[test]
     fn remove_inserted_key() {
         let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
         let a = vec![0,0,0,0,0,0,0,0];
 
-        p.insert(a.clone());
+        p.insert(a.clone()).unwrap();
 
-        assert!(p.remove(&a));
+        assert!(p.remove(&a).unwrap());
 
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(None, keys);
     }
@@ -473,7 +1171,7 @@ mod test {
         let mut p: DB<TypeMapVecU8> = DB::new(8, 2);
         let a = vec![0,0,0,0,0,0,0,0];
 
-        assert!(!p.remove(&a));
+        assert!(!p.remove(&a).unwrap());
     }
 
     /*
@@ -547,17 +1245,17 @@ mod test {
                     // Removing C should also remove A, if they are the same
                     return quickcheck::TestResult::discard()
                 }
-                let avec: Vec<u8> = encode(&a, SizeLimit::Infinite).unwrap();
-                let bvec: Vec<u8> = encode(&b, SizeLimit::Infinite).unwrap();
-                let cvec: Vec<u8> = encode(&c, SizeLimit::Infinite).unwrap();
+                let avec: Vec<u8> = encode(&a).unwrap();
+                let bvec: Vec<u8> = encode(&b).unwrap();
+                let cvec: Vec<u8> = encode(&c).unwrap();
 
                 let mut p: DB<TypeMapVecU8> = DB::new(8, 4);
-                p.insert(avec.clone());
-                p.insert(bvec.clone());
-                p.insert(cvec.clone());
-                p.remove(&cvec);
+                p.insert(avec.clone()).unwrap();
+                p.insert(bvec.clone()).unwrap();
+                p.insert(cvec.clone()).unwrap();
+                p.remove(&cvec).unwrap();
 
-                match p.get(&avec) {
+                match p.get(&avec).unwrap() {
                     Some(results) => quickcheck::TestResult::from_bool(results.contains(&avec)),
                     None => quickcheck::TestResult::failed(),
                 }
@@ -572,17 +1270,17 @@ mod test {
                     // Removing C should also remove A, if they are the same
                     return quickcheck::TestResult::discard()
                 }
-                let avec: Vec<u8> = encode(&a, SizeLimit::Infinite).unwrap();
-                let bvec: Vec<u8> = encode(&b, SizeLimit::Infinite).unwrap();
-                let cvec: Vec<u8> = encode(&c, SizeLimit::Infinite).unwrap();
+                let avec: Vec<u8> = encode(&a).unwrap();
+                let bvec: Vec<u8> = encode(&b).unwrap();
+                let cvec: Vec<u8> = encode(&c).unwrap();
 
                 let mut p: DB<TypeMapVecU8> = DB::new(8, 4);
-                p.insert(avec.clone());
-                p.insert(bvec.clone());
-                p.insert(cvec.clone());
-                p.remove(&cvec);
+                p.insert(avec.clone()).unwrap();
+                p.insert(bvec.clone()).unwrap();
+                p.insert(cvec.clone()).unwrap();
+                p.remove(&cvec).unwrap();
 
-                quickcheck::TestResult::from_bool(p.remove(&avec))
+                quickcheck::TestResult::from_bool(p.remove(&avec).unwrap())
             }
             quickcheck(prop as fn(u64, u64, u64) -> quickcheck::TestResult);
         }
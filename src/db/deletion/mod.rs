@@ -2,19 +2,51 @@ extern crate num;
 
 use std::clone::*;
 use std::hash::*;
+use std::default::Default;
 
 mod db;
 mod xor_iter;
+mod bit_iter;
 
 pub use self::db::DB;
 pub use self::xor_iter::XORIter;
+pub use self::bit_iter::BitIter;
 
-use db::window::Window;
+use db::map_set::Partitioned;
+use db::window::{Window, Windowable, WindowableRef};
 
 pub type Dvec = u64;
 
 pub type Key<T> = (Window, T);
 
+impl<T> Partitioned for Key<T> {
+    fn partition(&self) -> &Window {
+        &self.0
+    }
+}
+
+/// How vector elements contribute to the distance between two values
+///
+/// The index itself always treats each element as a single dimension; this
+/// only controls the final verification step.  Because `Bitwise` distances
+/// are never smaller than `Exact` distances, switching modes never loses
+/// recall for values within the DB's tolerance.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElementDistance {
+    /// Elements either match (distance 0) or don't (distance 1)
+    Exact,
+    /// Elements contribute the hamming distance of their bits, up to the given
+    /// cap (which must be at least 1)
+    Bitwise(usize),
+}
+
+impl Default for ElementDistance {
+    fn default() -> ElementDistance {
+        ElementDistance::Exact
+    }
+}
+
 pub trait DeletionVariant<T>: Sized {
     type Iter: Iterator<Item=T>;
 
@@ -28,6 +60,33 @@ pub trait DeletionVariant<T>: Sized {
     fn deletion_variants(&self, dimensions: usize) -> <Self as DeletionVariant<T>>::Iter;
 }
 
+/// Values whose windows' deletion variants can be computed without copying
+/// the window
+///
+/// `DeletionVariant` iterators own their source, so computing a window's
+/// variants that way means copying the window out of the value first.
+/// Borrowing it (see `window::WindowableRef`) avoids the copy.
+///
+pub trait DeletionWindow<'a, T> {
+    type Iter: Iterator<Item=T>;
+
+    /// The deletion variants of the window of `self` starting at
+    /// `start_dimension`
+    ///
+    fn window_deletion_variants(&'a self, start_dimension: usize, dimensions: usize) -> <Self as DeletionWindow<'a, T>>::Iter;
+}
+
+impl<'a, T, V> DeletionWindow<'a, V> for Vec<T> where
+T: Hash + 'a,
+XORIter<&'a [T]>: Iterator<Item = V>,
+{
+    type Iter = XORIter<&'a [T]>;
+
+    fn window_deletion_variants(&'a self, start_dimension: usize, dimensions: usize) -> XORIter<&'a [T]> {
+        XORIter::new(self.window_ref(start_dimension, dimensions), dimensions)
+    }
+}
+
 impl<T, V> DeletionVariant<V> for Vec<T> where
 T: Hash,
 Vec<T>: Clone,
@@ -40,3 +99,76 @@ XORIter<Vec<T>>: Iterator<Item = V>,
     }
 }
 
+// Binary windows' deletion variants are the window with one bit cleared,
+// tagged with the bit's index (see `BitIter`)
+macro_rules! deletion_variant_binary {
+    ($elem:ty) => {
+        impl DeletionVariant<($elem, u8)> for $elem {
+            type Iter = BitIter<$elem>;
+
+            fn deletion_variants(&self, dimensions: usize) -> BitIter<$elem> {
+                BitIter::new(self.clone(), dimensions)
+            }
+        }
+    }
+}
+deletion_variant_binary!(u8);
+deletion_variant_binary!(u16);
+deletion_variant_binary!(u32);
+deletion_variant_binary!(u64);
+deletion_variant_binary!([u64; 2]);
+deletion_variant_binary!([u64; 4]);
+
+// Binary windows are small enough that copying them out of the value is
+// cheaper than borrowing
+macro_rules! deletion_window_binary {
+    ($elem:ty) => {
+        impl<'a, W> DeletionWindow<'a, (W, u8)> for $elem where
+        $elem: Windowable<W>,
+        BitIter<W>: Iterator<Item = (W, u8)>,
+        {
+            type Iter = BitIter<W>;
+
+            fn window_deletion_variants(&'a self, start_dimension: usize, dimensions: usize) -> BitIter<W> {
+                BitIter::new(self.window(start_dimension, dimensions), dimensions)
+            }
+        }
+    }
+}
+deletion_window_binary!(u8);
+deletion_window_binary!(u16);
+deletion_window_binary!(u32);
+deletion_window_binary!(u64);
+deletion_window_binary!(i32);
+deletion_window_binary!(i64);
+deletion_window_binary!(usize);
+deletion_window_binary!([u64; 2]);
+deletion_window_binary!([u64; 4]);
+
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
+    use db::deletion::{Dvec, DeletionVariant, DeletionWindow};
+    use db::window::Windowable;
+
+    #[test]
+    fn borrowed_window_variants_match_copied() {
+        fn prop(v: Vec<u8>, start: usize, dimensions: usize) -> quickcheck::TestResult {
+            if v.is_empty() {
+                return quickcheck::TestResult::discard()
+            }
+            let start = start % v.len();
+            let dimensions = 1 + dimensions % (v.len() - start);
+
+            let copied: Vec<u8> = v.window(start, dimensions);
+            let expected: Vec<Dvec> = copied.deletion_variants(dimensions).collect();
+            let actual: Vec<Dvec> = v.window_deletion_variants(start, dimensions).collect();
+            quickcheck::TestResult::from_bool(actual == expected)
+        }
+        quickcheck(prop as fn(Vec<u8>, usize, usize) -> quickcheck::TestResult);
+    }
+}
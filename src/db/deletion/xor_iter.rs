@@ -2,6 +2,9 @@ use std::clone::*;
 use std::iter::*;
 use std::hash::*;
 use std::default::*;
+use std::ops::Deref;
+
+use siphasher::sip::SipHasher;
 
 use db::deletion::{Dvec};
 
@@ -36,6 +39,9 @@ use db::deletion::{Dvec};
 /// in this case don't affect the query's correctness and should have a 
 /// trivial impact on performance
 ///
+/// The source can be an owned vector or a borrowed slice (see
+/// `window::WindowableRef`); either way it's never copied.
+///
 pub struct XORIter<T> {
     // XOR-ed hash of each dimension index & value in `source`
     source_hash: u64,
@@ -49,29 +55,31 @@ pub struct XORIter<T> {
 
 // NOTE: Consider parameterizing on the hasher state so we ensure the dimension
 // hashes are always consistent
-impl<T> XORIter<Vec<T>> where 
+impl<S, T> XORIter<S> where 
+S: Deref<Target = [T]>,
 T: Hash,
-Vec<T>: Clone,
 {
-    pub fn new(v: Vec<T>, dimensions: usize) -> Self {
-        let mut dv = XORIter {
-            source_hash: 0,
-            source: v.clone(),
-            index: 1,
-            dimensions: dimensions,
-        };
+    pub fn new(v: S, dimensions: usize) -> Self {
+        let mut source_hash = 0;
         for (i, v_i) in v.iter().enumerate() {
             let mut hasher: SipHasher = Default::default();
             v_i.hash(&mut hasher);
             // start at index 1 to ensure that each element mutates the hash
             (i+1).hash(&mut hasher);
-            dv.source_hash = dv.source_hash ^ hasher.finish();
+            source_hash = source_hash ^ hasher.finish();
+        }
+
+        XORIter {
+            source_hash: source_hash,
+            source: v,
+            index: 1,
+            dimensions: dimensions,
         }
-        dv
     }
 }
 
-impl<T> Iterator for XORIter<Vec<T>> where
+impl<S, T> Iterator for XORIter<S> where
+S: Deref<Target = [T]>,
 T: Hash
 {
     type Item = Dvec;
@@ -0,0 +1,176 @@
+//! Dimensions a database ignores
+//!
+//! Some dimensions of a fingerprint may be known to be noise - bits which
+//! flip between near-identical inputs, or vector elements which aren't
+//! meaningful.  A database can be told to ignore them, in which case they're
+//! cleared from every value before it's windowed into variants, and from
+//! both the query and each candidate before verifying, so differences in
+//! them never count toward the distance.  Values are stored and returned
+//! unchanged.
+//!
+//! Clearing a dimension sets a bit to 0, or a vector element to its type's
+//! default.  Dimensions are numbered as they are for windowing (see
+//! `db::window`), so dimension 0 of a `[u64; 4]` is the lowest bit of its
+//! last element.
+//!
+//! Ignored dimensions must be set before anything is inserted - values
+//! indexed under a different set won't be found reliably.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+
+use db::bits::Bits;
+use db::string_key::{HexKey, Base64Key};
+use db::payload::Payload;
+
+/// Values whose dimensions can be cleared
+///
+pub trait MaskDimensions: Sized {
+    /// `self` with each of `dimensions` cleared
+    ///
+    /// Dimensions beyond the value's width are ignored.
+    ///
+    fn clear_dimensions(&self, dimensions: &[usize]) -> Self;
+}
+
+/// `value` with `ignored` cleared, borrowing `value` if there's nothing to
+/// clear
+///
+pub fn apply<'a, V: MaskDimensions + Clone>(value: &'a V, ignored: &[usize]) -> Cow<'a, V> {
+    if ignored.is_empty() {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(value.clear_dimensions(ignored))
+    }
+}
+
+macro_rules! uint_mask {
+    ($elem:ident) => {
+        impl MaskDimensions for $elem {
+            fn clear_dimensions(&self, dimensions: &[usize]) -> $elem {
+                dimensions.iter()
+                    .filter(|&&d| d < 8 * size_of::<$elem>())
+                    .fold(*self, |v, &d| v & !((1 as $elem) << d))
+            }
+        }
+    }
+}
+uint_mask!(u8);
+uint_mask!(u16);
+uint_mask!(u32);
+uint_mask!(u64);
+
+macro_rules! cast_mask {
+    ($elem:ident, $unsigned:ident) => {
+        impl MaskDimensions for $elem {
+            fn clear_dimensions(&self, dimensions: &[usize]) -> $elem {
+                (*self as $unsigned).clear_dimensions(dimensions) as $elem
+            }
+        }
+    }
+}
+cast_mask!(i8, u8);
+cast_mask!(i16, u16);
+cast_mask!(i32, u32);
+cast_mask!(i64, u64);
+cast_mask!(usize, u64);
+
+macro_rules! array_mask {
+    ($elems:expr) => {
+        impl MaskDimensions for [u64; $elems] {
+            fn clear_dimensions(&self, dimensions: &[usize]) -> [u64; $elems] {
+                let mut out = *self;
+                for &d in dimensions.iter().filter(|&&d| d < 64 * $elems) {
+                    out[$elems - 1 - d / 64] &= !(1 << (d % 64));
+                }
+                out
+            }
+        }
+    }
+}
+array_mask!(2);
+array_mask!(4);
+
+impl<T: Clone + Default> MaskDimensions for Vec<T> {
+    fn clear_dimensions(&self, dimensions: &[usize]) -> Vec<T> {
+        let mut out = self.clone();
+        for &d in dimensions.iter().filter(|&&d| d < out.len()) {
+            out[d] = T::default();
+        }
+        out
+    }
+}
+
+impl MaskDimensions for Bits {
+    fn clear_dimensions(&self, dimensions: &[usize]) -> Bits {
+        let mut out = self.clone();
+        for &d in dimensions.iter().filter(|&&d| d < out.dimensions()) {
+            if out.get(d) {
+                out.flip(d);
+            }
+        }
+        out
+    }
+}
+
+macro_rules! string_key_mask {
+    ($key:ident) => {
+        impl MaskDimensions for $key {
+            fn clear_dimensions(&self, dimensions: &[usize]) -> $key {
+                $key::from_value(self.value().clear_dimensions(dimensions))
+            }
+        }
+    }
+}
+string_key_mask!(HexKey);
+string_key_mask!(Base64Key);
+
+// Only the value is masked - payloads aren't dimensions
+impl<T: MaskDimensions, P: Clone> MaskDimensions for Payload<T, P> {
+    fn clear_dimensions(&self, dimensions: &[usize]) -> Payload<T, P> {
+        Payload::new(self.value.clear_dimensions(dimensions), self.payload.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
+    use db::dimension_mask::MaskDimensions;
+    use db::hamming::Hamming;
+    use db::window::Windowable;
+
+    #[test]
+    fn clears_bits() {
+        assert_eq!(0b1111u8.clear_dimensions(&[1, 3, 8]), 0b0101);
+        assert_eq!((-1i32).clear_dimensions(&[31]), ::std::i32::MAX);
+        assert_eq!([!0u64, !0].clear_dimensions(&[0, 64]), [!0 - 1, !0 - 1]);
+    }
+
+    #[test]
+    fn clears_elements() {
+        assert_eq!(vec![1u8, 2, 3].clear_dimensions(&[1, 5]), vec![1, 0, 3]);
+    }
+
+    #[test]
+    fn cleared_dimensions_window_as_zero() {
+        fn prop(a: u64, b: u64, c: u64, d: u64, dimension: u8) -> bool {
+            let dimension = dimension as usize;
+            let window: u8 = [a, b, c, d].clear_dimensions(&[dimension]).window(dimension, 1);
+            window == 0
+        }
+        quickcheck(prop as fn(u64, u64, u64, u64, u8) -> bool);
+    }
+
+    #[test]
+    fn cleared_dimensions_dont_count() {
+        fn prop(a: u64, b: u64, dimensions: Vec<u8>) -> bool {
+            let dimensions: Vec<usize> = dimensions.into_iter().map(|d| (d % 64) as usize).collect();
+            let (a, b) = (a.clear_dimensions(&dimensions), b.clear_dimensions(&dimensions));
+            a.hamming_indices(&b).iter().all(|d| !dimensions.contains(d))
+        }
+        quickcheck(prop as fn(u64, u64, Vec<u8>) -> bool);
+    }
+}
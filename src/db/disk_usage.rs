@@ -0,0 +1,153 @@
+//! Disk and file handle usage of persisted databases
+//!
+//! RocksDB-backed DBs store their data under a single directory.  `disk_usage`
+//! reports how much space that directory takes, how many SST files it holds
+//! and (where the platform makes it possible) how many file handles the
+//! current process has open within it.  `DiskBudget` checks usage against
+//! configurable limits so runaway namespaces can be flagged before they
+//! exhaust the process's file descriptors.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Usage of a single directory
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    /// Total size of all files in bytes
+    pub bytes: u64,
+    /// Number of files
+    pub files: usize,
+    /// Number of RocksDB SST files
+    pub sst_files: usize,
+    /// Number of file handles held open by this process, if known
+    pub open_files: Option<usize>,
+}
+
+/// Limits on a directory's usage - unset limits are not checked
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskBudget {
+    pub max_bytes: Option<u64>,
+    pub max_sst_files: Option<usize>,
+    pub max_open_files: Option<usize>,
+}
+
+impl DiskBudget {
+    /// Returns a description of each limit exceeded by `usage`
+    ///
+    pub fn violations(&self, usage: &DiskUsage) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        if let Some(max) = self.max_bytes {
+            if usage.bytes > max {
+                violations.push(format!("{} bytes exceeds budget of {}", usage.bytes, max));
+            }
+        }
+        if let Some(max) = self.max_sst_files {
+            if usage.sst_files > max {
+                violations.push(format!("{} SST files exceeds budget of {}", usage.sst_files, max));
+            }
+        }
+        if let (Some(max), Some(open)) = (self.max_open_files, usage.open_files) {
+            if open > max {
+                violations.push(format!("{} open files exceeds budget of {}", open, max));
+            }
+        }
+
+        violations
+    }
+}
+
+/// Measure the usage of `path` and everything below it
+///
+pub fn disk_usage(path: &Path) -> io::Result<DiskUsage> {
+    let mut usage: DiskUsage = Default::default();
+    try!(walk(path, &mut usage));
+    usage.open_files = open_files(path);
+    Ok(usage)
+}
+
+fn walk(path: &Path, usage: &mut DiskUsage) -> io::Result<()> {
+    for entry in try!(fs::read_dir(path)) {
+        let entry = try!(entry);
+        let metadata = try!(entry.metadata());
+
+        if metadata.is_dir() {
+            try!(walk(&entry.path(), usage));
+        } else {
+            usage.bytes += metadata.len();
+            usage.files += 1;
+            if entry.path().extension().map_or(false, |ext| ext == "sst") {
+                usage.sst_files += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts this process's open file descriptors pointing below `path`
+///
+/// Only supported where `/proc/self/fd` exists (ie Linux)
+///
+fn open_files(path: &Path) -> Option<usize> {
+    let path = match fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+
+    match fs::read_dir("/proc/self/fd") {
+        Ok(fds) => {
+            Some(fds
+                .filter_map(|fd| fd.ok())
+                .filter_map(|fd| fs::read_link(fd.path()).ok())
+                .filter(|target| target.starts_with(&path))
+                .count())
+        },
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    use uuid::Uuid;
+
+    use db::disk_usage::*;
+
+    #[test]
+    fn counts_files_and_bytes() {
+        let mut dir = env::temp_dir();
+        dir.push(&Uuid::new_v4().to_hyphenated_string());
+        let mut nested = dir.clone();
+        nested.push("map_set");
+        fs::create_dir_all(&nested).unwrap();
+
+        let mut sst = nested.clone();
+        sst.push("000001.sst");
+        fs::File::create(&sst).unwrap().write_all(&[0u8; 100]).unwrap();
+
+        let mut log = dir.clone();
+        log.push("LOG");
+        fs::File::create(&log).unwrap().write_all(&[0u8; 10]).unwrap();
+
+        let usage = disk_usage(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(usage.bytes, 110);
+        assert_eq!(usage.files, 2);
+        assert_eq!(usage.sst_files, 1);
+    }
+
+    #[test]
+    fn reports_budget_violations() {
+        let budget = DiskBudget{max_bytes: Some(100), max_sst_files: Some(10), max_open_files: Some(5)};
+        let usage = DiskUsage{bytes: 101, files: 3, sst_files: 2, open_files: Some(6)};
+
+        assert_eq!(budget.violations(&usage).len(), 2);
+        assert!(DiskBudget::default().violations(&usage).is_empty());
+    }
+}
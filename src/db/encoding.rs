@@ -0,0 +1,101 @@
+//! Binary encoding of stored keys & values
+//!
+//! Everything the stores write - variant keys, identifiers, values and the
+//! headers of snapshots & frozen files - is encoded with bincode through these
+//! functions, so any type implementing serde's `Serialize` & `Deserialize`
+//! can be stored.
+//!
+//! Integers are written big-endian and fixed-width, and sequences are prefixed
+//! with their length as a `u64`.  RocksDB orders keys by their bytes, so
+//! encoded keys sort the way their integer fields do, and an encoded key is a
+//! prefix of every encoded `(key, value)` entry - the RocksDB `MapSet` finds a
+//! key's values by seeking to its encoding.  This is also the layout written
+//! by earlier (rustc-serialize) versions, so existing data dirs & snapshots
+//! read back unchanged.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let encoded = encode(&(1u32, 2u64)).unwrap();
+//! assert_eq!(encoded, vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+//! assert_eq!(decode::<(u32, u64)>(&encoded), Ok((1, 2)));
+//! ```
+
+use std::io::{Read, Write};
+
+use bincode;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use error::Error;
+
+fn config() -> bincode::Config {
+    let mut config = bincode::config();
+    config.big_endian();
+    config
+}
+
+/// Encodes `value` for storage
+///
+pub fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    config().serialize(value).map_err(|e| Error::Encoding(format!("{:?}", e)))
+}
+
+/// Decodes a value written by `encode`
+///
+/// Stores only decode what they wrote, so anything undecodable is corrupt.
+///
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    config().deserialize(bytes).map_err(|e| Error::Corrupt(format!("{:?}", e)))
+}
+
+/// Encodes `value` to `writer`
+///
+pub fn encode_into<T: Serialize + ?Sized, W: Write>(value: &T, writer: &mut W) -> Result<(), Error> {
+    config().serialize_into(writer, value).map_err(|e| Error::Encoding(format!("{:?}", e)))
+}
+
+/// Decodes the next value from `reader`
+///
+/// Reads exactly the value's bytes, so consecutive values can be decoded from
+/// a stream.
+///
+pub fn decode_from<T: DeserializeOwned, R: Read>(reader: &mut R) -> Result<T, Error> {
+    config().deserialize_from(reader).map_err(|e| Error::Corrupt(format!("{:?}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use db::encoding::*;
+    use db::window::Window;
+    use db::substitution::Key;
+
+    #[test]
+    fn integers_are_big_endian() {
+        assert_eq!(encode(&0x0102u64).unwrap(), vec![0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(encode(&vec![7u8]).unwrap(), vec![0, 0, 0, 0, 0, 0, 0, 1, 7]);
+        assert_eq!(decode::<u64>(&[0, 0, 0, 0, 0, 0, 1, 2]), Ok(0x0102));
+    }
+
+    #[test]
+    fn keys_prefix_their_entries() {
+        let key = Key::One(Window{start_dimension: 16, dimensions: 16}, 0xbeefu16);
+        let prefix = encode(&key).unwrap();
+        let entry = encode(&(key.clone(), 42u64)).unwrap();
+
+        assert!(entry.starts_with(&prefix));
+        assert_eq!(decode::<(Key<u16>, u64)>(&entry), Ok((key, 42)));
+    }
+
+    #[test]
+    fn streams_consecutive_values() {
+        let mut buf = Vec::new();
+        encode_into(&Some(3u32), &mut buf).unwrap();
+        encode_into(&None::<u32>, &mut buf).unwrap();
+
+        let mut reader = &buf[..];
+        assert_eq!(decode_from::<Option<u32>, _>(&mut reader), Ok(Some(3)));
+        assert_eq!(decode_from::<Option<u32>, _>(&mut reader), Ok(None));
+        assert!(decode_from::<Option<u32>, _>(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Databases capped at a number of values
+//!
+//! `EvictingDB` wraps a database and tracks its values in an
+//! `EvictingStore`.  Inserting a value and having a query return it both count
+//! as uses; once more values are tracked than the store's capacity, the value
+//! it evicts is removed from the database along with all of its variants.
+//!
+//! `EvictingDB::new` only tracks values written through it, so values already
+//! in a re-opened database would never be evicted.  `EvictingDB::open` tracks
+//! them too, oldest first where the database records insertion order, so the
+//! cap holds across restarts.  Queries made before the restart aren't
+//! remembered.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let db = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+//! let mut db = EvictingDB::open(db, LRU::with_capacity(1000)).unwrap();
+//! ```
+
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::{Database, QueryOptions, RemovalReport, Page, WarmupReport};
+use db::health::{HealthReport, RepairReport};
+use db::shadow::ShadowStats;
+use db::stats::Stats;
+use db::id_map::IDStrategy;
+use db::hashing::State;
+use evicting_store::EvictingStore;
+use error::Error;
+
+pub struct EvictingDB<T, S> {
+    db: Box<Database<T>>,
+    // Queries only have `&self`, so recording their uses needs a lock
+    store: Mutex<S>,
+}
+
+impl<T, S> EvictingDB<T, S> where
+T: Clone + Eq + Hash,
+S: EvictingStore<T>,
+{
+    pub fn new(db: Box<Database<T>>, store: S) -> EvictingDB<T, S> {
+        EvictingDB {
+            db: db,
+            store: Mutex::new(store),
+        }
+    }
+
+    /// Wrap `db`, tracking the values it already holds
+    ///
+    /// Values are tracked in insertion order where `db` records it (see
+    /// `Database::get_recent`), and in no particular order otherwise.  If
+    /// `db` holds more values than `store` can track, the excess are evicted
+    /// straight away.  Fails if `db` can't enumerate its values.
+    ///
+    pub fn open(db: Box<Database<T>>, store: S) -> Result<EvictingDB<T, S>, Error> {
+        let mut found: HashSet<T, State> = HashSet::default();
+        try!(db.for_each_value(&mut |value| {
+            found.insert(value);
+            Ok(())
+        }));
+
+        let mut db = EvictingDB::new(db, store);
+        let mut evicted = Vec::new();
+        {
+            let ordered = db.db.sort_by_recency(found);
+            let store = db.store.get_mut().unwrap();
+            for value in ordered.into_iter().rev() {
+                if let Some(value) = store.touch(value) {
+                    evicted.push(value);
+                }
+            }
+        }
+        for value in evicted.iter() {
+            try!(db.db.remove(value));
+        }
+
+        Ok(db)
+    }
+
+    /// Number of values currently tracked
+    ///
+    pub fn tracked(&self) -> usize {
+        self.store.lock().unwrap().len()
+    }
+
+    /// Stop evicting, returning the wrapped database
+    ///
+    pub fn into_inner(self) -> Box<Database<T>> {
+        self.db
+    }
+
+    fn refresh<'a, I: IntoIterator<Item=&'a T>>(&self, found: I) where T: 'a {
+        let mut store = self.store.lock().unwrap();
+        for value in found {
+            store.refresh(value);
+        }
+    }
+}
+
+impl<T, S> Database<T> for EvictingDB<T, S> where
+T: Sync + Send + Clone + Eq + Hash,
+S: Send + EvictingStore<T>,
+{
+    fn get_with(&self, key: &T, options: &QueryOptions) -> Result<Option<HashSet<T, State>>, Error> {
+        let found = try!(self.db.get_with(key, options));
+        if let Some(ref found) = found {
+            self.refresh(found);
+        }
+        Ok(found)
+    }
+
+    fn get_many(&self, keys: &[T], options: &QueryOptions) -> Result<Vec<Option<HashSet<T, State>>>, Error> {
+        let found = try!(self.db.get_many(keys, options));
+        for found in found.iter() {
+            if let Some(ref found) = *found {
+                self.refresh(found);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Bulk queries don't count as uses, so a join doesn't churn the cache
+    ///
+    fn for_each_match(&self, key: &T, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(T)) -> Result<(), Error> where
+    T: Serialize + DeserializeOwned,
+    {
+        self.db.for_each_match(key, options, spill_threshold, f)
+    }
+
+    fn get_page_with(&self, key: &T, options: &QueryOptions, cursor: Option<&T>, limit: usize) -> Result<Page<T>, Error> {
+        let page = try!(self.db.get_page_with(key, options, cursor, limit));
+        self.refresh(&page.values);
+        Ok(page)
+    }
+
+    fn get_recent_with(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<T>>, Error> {
+        let found = try!(self.db.get_recent_with(key, options));
+        if let Some(ref found) = found {
+            self.refresh(found);
+        }
+        Ok(found)
+    }
+
+    fn sort_by_recency(&self, found: HashSet<T, State>) -> Vec<T> {
+        self.db.sort_by_recency(found)
+    }
+
+    fn get_topk(&self, key: &T, k: usize) -> Result<Option<Vec<T>>, Error> {
+        let found = try!(self.db.get_topk(key, k));
+        if let Some(ref found) = found {
+            self.refresh(found);
+        }
+        Ok(found)
+    }
+
+    fn estimate_candidates(&self, key: &T) -> Result<usize, Error> {
+        self.db.estimate_candidates(key)
+    }
+
+    fn set_ignored_dimensions(&mut self, dimensions: Vec<usize>) -> Result<(), Error> {
+        self.db.set_ignored_dimensions(dimensions)
+    }
+
+    fn set_id_strategy(&mut self, strategy: IDStrategy) -> Result<(), Error> {
+        self.db.set_id_strategy(strategy)
+    }
+
+    fn set_compact_variants(&mut self) -> Result<(), Error> {
+        self.db.set_compact_variants()
+    }
+
+    fn health_check(&self, sample: usize) -> HealthReport {
+        self.db.health_check(sample)
+    }
+
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        self.db.verify_and_repair()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.db.compact()
+    }
+
+    fn set_variant_cache(&mut self, sets: usize) -> Result<(), Error> {
+        self.db.set_variant_cache(sets)
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        self.db.warmup(fraction)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        self.db.stats()
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        self.db.shadow_stats()
+    }
+
+    /// Inserting a value already present counts as a use of it
+    ///
+    /// A value is only tracked once the wrapped database has stored it
+    ///
+    fn insert(&mut self, key: T) -> Result<bool, Error> {
+        let inserted = try!(self.db.insert(key.clone()));
+
+        let evicted = self.store.lock().unwrap().touch(key);
+        if let Some(evicted) = evicted {
+            try!(self.db.remove(&evicted));
+        }
+
+        Ok(inserted)
+    }
+
+    fn remove(&mut self, key: &T) -> Result<bool, Error> {
+        self.store.lock().unwrap().remove(key);
+        self.db.remove(key)
+    }
+
+    fn remove_verified(&mut self, key: &T) -> Result<RemovalReport, Error> {
+        self.store.lock().unwrap().remove(key);
+        self.db.remove_verified(key)
+    }
+
+    /// Looking a value up by ID doesn't count as a use of it
+    ///
+    fn get_by_id(&self, id: &u64) -> Result<Option<T>, Error> {
+        self.db.get_by_id(id)
+    }
+
+    /// Enumerating values doesn't count as a use of them
+    ///
+    fn for_each_value(&self, f: &mut FnMut(T) -> Result<(), Error>) -> Result<(), Error> {
+        self.db.for_each_value(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, Factory, StorageBackend, RocksDBConfig};
+    use db::evicting::*;
+    use db::temp_dir::TempDir;
+    use evicting_store::LRU;
+
+    fn count(db: &Database<u64>) -> usize {
+        let mut count = 0;
+        db.for_each_value(&mut |_| {
+            count += 1;
+            Ok(())
+        }).unwrap();
+        count
+    }
+
+    #[test]
+    fn evicts_least_recently_queried() {
+        let db = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+        let mut db = EvictingDB::new(db, LRU::with_capacity(2));
+
+        db.insert(0b0001).unwrap();
+        db.insert(0b1110_0000).unwrap();
+        assert!(db.get(&0b0000).unwrap().unwrap().contains(&0b0001));
+
+        // 0b1110_0000 was used least recently
+        db.insert(0b0111_0000_0000).unwrap();
+
+        assert_eq!(db.tracked(), 2);
+        assert_eq!(db.get(&0b1110_0000), Ok(None));
+        assert!(db.get(&0b0001).unwrap().is_some());
+        assert!(db.get(&0b0111_0000_0000).unwrap().is_some());
+    }
+
+    #[test]
+    fn reopening_keeps_the_cap() {
+        let dir = TempDir::new();
+        let backend = || StorageBackend::RocksDB(dir.path().to_path_buf(), RocksDBConfig::default());
+
+        {
+            let mut db = EvictingDB::open(u64::build(64, 2, backend()).unwrap(), LRU::with_capacity(2)).unwrap();
+            for value in vec![0b0001, 0b0011, 0b0111] {
+                db.insert(value).unwrap();
+            }
+            assert_eq!(count(&db), 2);
+        }
+
+        let mut db = EvictingDB::open(u64::build(64, 2, backend()).unwrap(), LRU::with_capacity(2)).unwrap();
+        assert_eq!(db.tracked(), 2);
+
+        db.insert(0b1111_0000).unwrap();
+        assert_eq!(count(&db), 2);
+        assert!(db.get(&0b1111_0000).unwrap().is_some());
+    }
+
+    #[test]
+    fn opening_over_capacity_evicts() {
+        let mut inner = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+        for value in vec![0b0001, 0b0011, 0b0111] {
+            inner.insert(value).unwrap();
+        }
+
+        let db = EvictingDB::open(inner, LRU::with_capacity(2)).unwrap();
+        assert_eq!(db.tracked(), 2);
+        assert_eq!(count(&db), 2);
+    }
+}
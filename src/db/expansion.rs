@@ -0,0 +1,168 @@
+//! Query expansion
+//!
+//! A database can only find values within the tolerance it was built with.
+//! When that isn't enough, `expand_query` can reach further by querying every
+//! value within `extra_distance` of the query and merging the results, so the
+//! effective tolerance becomes `tolerance + extra_distance`.
+//!
+//! This is NOT cheap.  The number of queries issued is the number of values
+//! within `extra_distance` of the query, or `sum(C(dimensions, i))` for
+//! `i` in `0..extra_distance+1` - for 64 dimensions that's 65 queries at
+//! distance 1, 2,081 at distance 2 and 43,745 at distance 3.  `max_expansions`
+//! bounds the number of queries that will be issued; requests exceeding it
+//! fail up-front rather than half-way through.  If you find yourself expanding
+//! regularly, rebuild the index with a larger tolerance instead.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+//! db.insert(0b1111).unwrap();
+//!
+//! assert_eq!(db.get(&0), Ok(None));
+//! assert!(expand_query(&*db, &0, 64, 2, DEFAULT_MAX_EXPANSIONS).unwrap().unwrap().contains(&0b1111));
+//! ```
+
+use std::fmt;
+use std::error;
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::clone::Clone;
+use std::collections::HashSet;
+
+use db::Database;
+use db::substitution::SubstitutionVariant;
+use db::hashing::State;
+use error::Error;
+
+/// Default upper bound on the number of queries `expand_query` will issue
+pub const DEFAULT_MAX_EXPANSIONS: usize = 10000;
+
+/// Returned when an expansion would issue more than `max_expansions` queries
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TooManyExpansions {
+    pub required: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for TooManyExpansions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "query expansion requires {} queries (limit {})", self.required, self.limit)
+    }
+}
+
+impl error::Error for TooManyExpansions {
+    fn description(&self) -> &str {
+        "query expansion exceeds maximum expansion count"
+    }
+}
+
+/// Number of values within `distance` of a value with `dimensions` dimensions
+///
+/// Saturates at `usize::MAX` rather than overflowing
+///
+pub fn expansion_count(dimensions: usize, distance: usize) -> usize {
+    let mut total: usize = 0;
+    let mut level: usize = 1;
+
+    for i in 0..(distance + 1) {
+        if i > dimensions {
+            break
+        }
+        if i > 0 {
+            // C(n, i) = C(n, i-1) * (n - i + 1) / i
+            level = match level.checked_mul(dimensions - i + 1) {
+                Some(l) => l / i,
+                None => return ::std::usize::MAX,
+            };
+        }
+        total = match total.checked_add(level) {
+            Some(t) => t,
+            None => return ::std::usize::MAX,
+        };
+    }
+
+    total
+}
+
+/// Query `db` for all values within `extra_distance` of `key` and merge the
+/// results
+///
+/// Returns `Error::TooManyExpansions` without querying if more than
+/// `max_expansions` queries would be needed
+///
+pub fn expand_query<T>(db: &Database<T>, key: &T, dimensions: usize, extra_distance: usize, max_expansions: usize) -> Result<Option<HashSet<T, State>>, Error> where
+T: Clone + Eq + Hash + SubstitutionVariant<T>,
+{
+    let required = expansion_count(dimensions, extra_distance);
+    if required > max_expansions {
+        return Err(Error::TooManyExpansions(TooManyExpansions{required: required, limit: max_expansions}));
+    }
+
+    let mut seen: HashSet<T, State> = HashSet::with_capacity_and_hasher(required, Default::default());
+    let mut frontier: Vec<T> = vec![key.clone()];
+    seen.insert(key.clone());
+
+    for _ in 0..extra_distance {
+        let mut next = Vec::new();
+        for value in frontier.iter() {
+            for neighbor in value.substitution_variants(dimensions) {
+                if seen.insert(neighbor.clone()) {
+                    next.push(neighbor);
+                }
+            }
+        }
+        frontier = next;
+    }
+
+    let mut results: HashSet<T, State> = HashSet::default();
+    for neighbor in seen.iter() {
+        match try!(db.get(neighbor)) {
+            Some(found) => results.extend(found.into_iter()),
+            None => {},
+        }
+    }
+
+    match results.len() {
+        0 => Ok(None),
+        _ => Ok(Some(results)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Factory, StorageBackend};
+    use db::expansion::*;
+    use error::Error;
+
+    #[test]
+    fn expansion_count_matches_binomial_sum() {
+        assert_eq!(expansion_count(64, 0), 1);
+        assert_eq!(expansion_count(64, 1), 65);
+        assert_eq!(expansion_count(64, 2), 2081);
+        assert_eq!(expansion_count(64, 3), 43745);
+        assert_eq!(expansion_count(4, 8), 16);
+    }
+
+    #[test]
+    fn finds_values_beyond_tolerance() {
+        let mut db = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+        let a = 0b1111u64;
+        db.insert(a).unwrap();
+
+        assert_eq!(db.get(&0), Ok(None));
+
+        let found = expand_query(&*db, &0, 64, 2, DEFAULT_MAX_EXPANSIONS).unwrap();
+        assert!(found.unwrap().contains(&a));
+    }
+
+    #[test]
+    fn rejects_expensive_expansions() {
+        let db = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+
+        assert_eq!(
+            expand_query(&*db, &0, 64, 3, 1000),
+            Err(Error::TooManyExpansions(TooManyExpansions{required: 43745, limit: 1000})),
+        );
+    }
+}
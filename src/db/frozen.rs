@@ -0,0 +1,634 @@
+//! Read-only databases in a memory-mapped file
+//!
+//! Reference datasets which never change don't need a write path, and
+//! opening a RocksDB-backed namespace means opening its files and replaying
+//! its logs.  `finalize` writes a database's variants and values to a single
+//! file of sorted tables, which `FrozenDB` maps into memory: opening one only
+//! reads the file's header, and queries binary search the mapped tables, so
+//! pages are read from disk as they're first touched.
+//!
+//! # Format
+//!
+//! Integers are little-endian.
+//!
+//! ```text
+//! magic       b"HAMMERFZ"
+//! header_len  u64
+//! header      bincode-encoded `Header`
+//! variants    table of (variant key, identifier) entries
+//! values      table of (identifier, value) entries
+//! ```
+//!
+//! Each table is
+//!
+//! ```text
+//! count       u64
+//! offsets     (count + 1) x u64 - where each entry starts, and the last ends
+//! entries     count x (key_len: u32, key, value)
+//! ```
+//!
+//! with offsets relative to the first entry.  Keys and values are encoded
+//! with bincode, as they are by the RocksDB stores, and entries are sorted
+//! by their encoded key then value, so a key's entries are adjacent.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db: substitution::DB<U64wU16InMemory> = substitution::DB::new(64, 4);
+//! db.insert(0b1111);
+//! db.finalize(Path::new("reference.frozen"));
+//!
+//! let frozen = FrozenDB::open_substitution::<U64wU16InMemory>(Path::new("reference.frozen"));
+//! frozen.get(&0b0111);
+//! // Some([0b1111])
+//! ```
+
+extern crate byteorder;
+
+use std::fs;
+use std::ptr;
+use std::io::{BufWriter, Write};
+use std::hash::Hash;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap::Mmap;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use db::encoding::{encode, decode};
+use db::{TypeMap, Database, QueryOptions, RemovalReport, Page, WarmupReport, Strategy};
+use db::health::{HealthReport, RepairReport};
+use db::shadow::ShadowStats;
+use db::stats::Stats;
+use db::id_map::{self, IDMap, IDStrategy};
+use db::map_set::{self, MapSet};
+use db::substitution::{self, SubstitutionVariant};
+use db::deletion::{self, DeletionVariant, DeletionWindow};
+use db::hashing::State;
+use error::Error;
+
+const MAGIC: &'static [u8] = b"HAMMERFZ";
+
+// Touching one byte of each page is enough to read it in
+const PAGE_SIZE: usize = 4096;
+
+/// How a finalized database was configured
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub strategy: Strategy,
+    pub dimensions: usize,
+    pub tolerance: usize,
+    pub ignored: Vec<usize>,
+    /// Whether narrow partitions' one variants were left out (see
+    /// `Database::set_compact_variants`)
+    pub compact_variants: bool,
+}
+
+/// `Header` as written before compact variants
+///
+#[derive(Deserialize)]
+struct LegacyHeader {
+    strategy: Strategy,
+    dimensions: usize,
+    tolerance: usize,
+    ignored: Vec<usize>,
+}
+
+impl From<LegacyHeader> for Header {
+    fn from(header: LegacyHeader) -> Header {
+        Header {
+            strategy: header.strategy,
+            dimensions: header.dimensions,
+            tolerance: header.tolerance,
+            ignored: header.ignored,
+            compact_variants: false,
+        }
+    }
+}
+
+/// A sorted table of encoded entries in a mapped file
+///
+#[derive(Clone)]
+pub struct Table {
+    map: Arc<Mmap>,
+    count: usize,
+    // Where the offsets start, and where the entries they index start
+    offsets: usize,
+    entries: usize,
+    // Bytes of entries
+    len: usize,
+}
+
+impl Table {
+    /// Reads the table starting at `start`, returning it and the position
+    /// following it
+    ///
+    fn open(map: Arc<Mmap>, start: usize) -> Result<(Table, usize), Error> {
+        let count = try!(read_u64(&map, start)) as usize;
+        let offsets = start + 8;
+        let entries = match count.checked_add(1).and_then(|n| n.checked_mul(8)).and_then(|n| n.checked_add(offsets)) {
+            Some(entries) if entries <= map.len() => entries,
+            _ => return Err(Error::Corrupt(format!("frozen table of {} entries is truncated", count))),
+        };
+
+        let mut table = Table{map: map, count: count, offsets: offsets, entries: entries, len: 0};
+        table.len = try!(table.offset(count));
+        if table.len > table.map.len() - entries {
+            return Err(Error::Corrupt(format!("frozen table of {} entries is truncated", count)))
+        }
+
+        let end = entries + table.len;
+        Ok((table, end))
+    }
+
+    fn offset(&self, i: usize) -> Result<usize, Error> {
+        Ok(try!(read_u64(&self.map, self.offsets + 8 * i)) as usize)
+    }
+
+    /// Number of entries in the table
+    ///
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// The encoded key and value of the `i`th entry
+    ///
+    pub fn entry(&self, i: usize) -> Result<(&[u8], &[u8]), Error> {
+        let (start, end) = (try!(self.offset(i)), try!(self.offset(i + 1)));
+        if start > end || end > self.len || end - start < 4 {
+            return Err(Error::Corrupt(format!("frozen entry {} has invalid bounds {}..{}", i, start, end)))
+        }
+
+        let bytes = &self.map[self.entries + start..self.entries + end];
+        let key_len = LittleEndian::read_u32(&bytes[..4]) as usize;
+        if key_len > bytes.len() - 4 {
+            return Err(Error::Corrupt(format!("frozen entry {} has a {} byte key but is {} bytes", i, key_len, bytes.len())))
+        }
+        Ok((&bytes[4..4 + key_len], &bytes[4 + key_len..]))
+    }
+
+    /// Indices of the entries whose key is `key`
+    ///
+    pub fn find(&self, key: &[u8]) -> Result<Range<usize>, Error> {
+        let start = try!(self.partition_point(|k| k < key));
+        let end = try!(self.partition_point(|k| k <= key));
+        Ok(start..end)
+    }
+
+    /// Index of the first entry whose key isn't `before` the one searched for
+    ///
+    fn partition_point<F: Fn(&[u8]) -> bool>(&self, before: F) -> Result<usize, Error> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if before(try!(self.entry(mid)).0) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(low)
+    }
+
+    /// Reads the first `fraction` of the table from disk, returning the
+    /// number of entries read
+    ///
+    pub fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        let budget = (self.len as f64 * fraction.min(1.0)) as usize;
+
+        let mut at = self.offsets;
+        while at < self.entries + budget {
+            unsafe { ptr::read_volatile(&self.map[at]); }
+            at += PAGE_SIZE;
+        }
+
+        let mut read = 0;
+        while read < self.count && try!(self.offset(read + 1)) <= budget {
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    /// Bytes of the file used by the table
+    ///
+    pub fn byte_len(&self) -> u64 {
+        (self.entries + self.len - self.offsets + 8) as u64
+    }
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Result<u64, Error> {
+    if at > bytes.len() || bytes.len() - at < 8 {
+        return Err(Error::Corrupt(format!("frozen file truncated at byte {}", at)))
+    }
+    Ok(LittleEndian::read_u64(&bytes[at..at + 8]))
+}
+
+/// The header and tables of a finalized database
+///
+pub struct FrozenFile {
+    pub header: Header,
+    pub variants: Table,
+    pub values: Table,
+}
+
+impl FrozenFile {
+    /// Maps the file at `path`
+    ///
+    /// Fails with `Error::Invalid` if it isn't a frozen database, and
+    /// `Error::Corrupt` if its header or table extents can't be read.
+    /// Entries are only checked as they're read.
+    ///
+    pub fn open(path: &Path) -> Result<FrozenFile, Error> {
+        let file = try!(fs::File::open(path).map_err(|e| format!("unable to open {}: {}", path.display(), e)));
+
+        // Finalized files are never written, so the mapping can't change
+        // under us
+        let map = try!(unsafe { Mmap::map(&file) }.map_err(|e| format!("unable to map {}: {}", path.display(), e)));
+        if map.len() < MAGIC.len() || &map[..MAGIC.len()] != MAGIC {
+            return Err(Error::Invalid(format!("{} isn't a frozen database", path.display())))
+        }
+
+        let header_len = try!(read_u64(&map, MAGIC.len())) as usize;
+        let header_start = MAGIC.len() + 8;
+        if header_len > map.len() - header_start {
+            return Err(Error::Corrupt(format!("frozen header of {} bytes is truncated", header_len)))
+        }
+        let header: Header = {
+            let header_bytes = &map[header_start..header_start + header_len];
+            match decode(header_bytes) {
+                Ok(header) => header,
+                Err(_) => try!(decode::<LegacyHeader>(header_bytes)).into(),
+            }
+        };
+
+        let map = Arc::new(map);
+        let (variants, end) = try!(Table::open(map.clone(), header_start + header_len));
+        let (values, _) = try!(Table::open(map, end));
+
+        Ok(FrozenFile{header: header, variants: variants, values: values})
+    }
+}
+
+/// Write the entries of `variant_store`, and the values of the identifiers
+/// they reference, to a frozen database file at `path`
+///
+/// The file is written next to `path` and renamed into place once it's
+/// synced, so a crash mid-write leaves any previous file intact.
+/// Identifiers without a value (left by an interrupted remove) are skipped.
+///
+pub fn finalize<K, ID, T, M, I>(header: &Header, variant_store: &M, value_store: &I, path: &Path) -> Result<(), Error> where
+K: Clone + Eq + Hash + Serialize,
+ID: Clone + Eq + Hash + Serialize,
+T: Serialize,
+M: MapSet<K, ID>,
+I: IDMap<ID, T>,
+{
+    let mut variants = Vec::new();
+    let mut ids = HashSet::default();
+    let mut failed = None;
+    try!(variant_store.scan(&mut |key, id| {
+        if failed.is_some() {
+            return
+        }
+        match (encode(key), encode(id)) {
+            (Ok(key), Ok(encoded_id)) => {
+                variants.push((key, encoded_id));
+                ids.insert(id.clone());
+            },
+            (Err(e), _) | (_, Err(e)) => failed = Some(e),
+        }
+    }));
+    if let Some(e) = failed {
+        return Err(e)
+    }
+
+    let mut values = Vec::with_capacity(ids.len());
+    for id in ids.into_iter() {
+        if let Some(value) = try!(value_store.get(id.clone())) {
+            values.push((try!(encode(&id)), try!(encode(&value))));
+        }
+    }
+
+    variants.sort();
+    variants.dedup();
+    values.sort();
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    {
+        let f = try!(fs::File::create(&tmp_path).map_err(|e| format!("{}", e)));
+        let mut w = BufWriter::new(f);
+
+        let header = try!(encode(header));
+        try!(w.write_all(MAGIC).map_err(|e| format!("{}", e)));
+        try!(write_u64(&mut w, header.len() as u64));
+        try!(w.write_all(&header).map_err(|e| format!("{}", e)));
+        try!(write_table(&mut w, &variants));
+        try!(write_table(&mut w, &values));
+
+        let f = try!(w.into_inner().map_err(|e| format!("{}", e)));
+        try!(f.sync_all().map_err(|e| format!("{}", e)));
+    }
+    Ok(try!(fs::rename(&tmp_path, path).map_err(|e| format!("{}", e))))
+}
+
+fn write_table<W: Write>(w: &mut W, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), Error> {
+    try!(write_u64(w, entries.len() as u64));
+
+    let mut offset = 0;
+    try!(write_u64(w, offset));
+    for &(ref key, ref value) in entries.iter() {
+        offset += (4 + key.len() + value.len()) as u64;
+        try!(write_u64(w, offset));
+    }
+
+    for &(ref key, ref value) in entries.iter() {
+        let mut key_len = [0; 4];
+        LittleEndian::write_u32(&mut key_len, key.len() as u32);
+        try!(w.write_all(&key_len).map_err(|e| format!("{}", e)));
+        try!(w.write_all(key).map_err(|e| format!("{}", e)));
+        try!(w.write_all(value).map_err(|e| format!("{}", e)));
+    }
+    Ok(())
+}
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> Result<(), Error> {
+    let mut buf = [0; 8];
+    LittleEndian::write_u64(&mut buf, n);
+    Ok(try!(w.write_all(&buf).map_err(|e| format!("{}", e))))
+}
+
+/// The types of a database opened from a file finalized by a `T` database
+///
+pub struct FrozenTypes<T>(PhantomData<T>);
+
+impl<T: TypeMap> TypeMap for FrozenTypes<T> where
+<T as TypeMap>::Input: DeserializeOwned,
+<T as TypeMap>::Identifier: Serialize,
+{
+    type Input = <T as TypeMap>::Input;
+    type Window = <T as TypeMap>::Window;
+    type Variant = <T as TypeMap>::Variant;
+    type Identifier = <T as TypeMap>::Identifier;
+    type ValueStore = id_map::Frozen;
+    type VariantStore = map_set::Frozen;
+}
+
+/// A database opened from a file written by `finalize`
+///
+/// Open it with the `TypeMap` of the database which was finalized.  The
+/// dimensions, tolerance and ignored dimensions are read from the file;
+/// verifiers, element distances and ID strategies aren't stored, so are left
+/// at their defaults.  Inserts and removes fail with `Error::Unsupported`.
+///
+pub struct FrozenDB<I> {
+    header: Header,
+    db: Box<Database<I>>,
+}
+
+impl<I> FrozenDB<I> where
+I: Sync + Send + Clone + Eq + Hash + DeserializeOwned + 'static,
+{
+    /// Open a file written by `substitution::DB::finalize`
+    ///
+    pub fn open_substitution<T>(path: &Path) -> Result<FrozenDB<I>, Error> where
+    T: TypeMap<Input = I> + 'static,
+    <T as TypeMap>::Identifier: Serialize + DeserializeOwned,
+    <T as TypeMap>::Window: SubstitutionVariant<<T as TypeMap>::Variant>,
+    substitution::Key<<T as TypeMap>::Variant>: Serialize + DeserializeOwned,
+    {
+        let file = try!(FrozenFile::open(path));
+        try!(expect_strategy(&file.header, Strategy::Substitution));
+
+        let mut db: substitution::DB<FrozenTypes<T>> = substitution::DB::with_stores(
+            file.header.dimensions,
+            file.header.tolerance,
+            id_map::Frozen::new(file.values),
+            map_set::Frozen::new(file.variants),
+        );
+        try!(db.set_ignored_dimensions(file.header.ignored.clone()));
+        if file.header.compact_variants {
+            try!(db.set_compact_variants());
+        }
+
+        Ok(FrozenDB{header: file.header, db: Box::new(db)})
+    }
+
+    /// Open a file written by `deletion::DB::finalize`
+    ///
+    pub fn open_deletion<T>(path: &Path) -> Result<FrozenDB<I>, Error> where
+    T: TypeMap<Input = I> + 'static,
+    <T as TypeMap>::Identifier: Serialize + DeserializeOwned,
+    <T as TypeMap>::Window: DeletionVariant<<T as TypeMap>::Variant>,
+    for<'a> I: DeletionWindow<'a, <T as TypeMap>::Variant>,
+    deletion::Key<<T as TypeMap>::Variant>: Serialize + DeserializeOwned,
+    {
+        let file = try!(FrozenFile::open(path));
+        try!(expect_strategy(&file.header, Strategy::Deletion));
+
+        let mut db: deletion::DB<FrozenTypes<T>> = deletion::DB::with_stores(
+            file.header.dimensions,
+            file.header.tolerance,
+            id_map::Frozen::new(file.values),
+            map_set::Frozen::new(file.variants),
+        );
+        try!(db.set_ignored_dimensions(file.header.ignored.clone()));
+
+        Ok(FrozenDB{header: file.header, db: Box::new(db)})
+    }
+
+    /// How the finalized database was configured
+    ///
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
+fn expect_strategy(header: &Header, strategy: Strategy) -> Result<(), Error> {
+    if header.strategy != strategy {
+        return Err(Error::Invalid(format!("frozen database was indexed by {:?}, not {:?}", header.strategy, strategy)))
+    }
+    Ok(())
+}
+
+fn read_only() -> Error {
+    Error::Unsupported("frozen databases are read-only".to_string())
+}
+
+impl<I> Database<I> for FrozenDB<I> where
+I: Sync + Send + Clone + Eq + Hash,
+{
+    fn get_with(&self, key: &I, options: &QueryOptions) -> Result<Option<HashSet<I, State>>, Error> {
+        self.db.get_with(key, options)
+    }
+
+    fn get_many(&self, keys: &[I], options: &QueryOptions) -> Result<Vec<Option<HashSet<I, State>>>, Error> {
+        self.db.get_many(keys, options)
+    }
+
+    fn for_each_match(&self, key: &I, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(I)) -> Result<(), Error> where
+    I: Serialize + DeserializeOwned,
+    {
+        self.db.for_each_match(key, options, spill_threshold, f)
+    }
+
+    fn get_page_with(&self, key: &I, options: &QueryOptions, cursor: Option<&I>, limit: usize) -> Result<Page<I>, Error> {
+        self.db.get_page_with(key, options, cursor, limit)
+    }
+
+    fn get_recent_with(&self, key: &I, options: &QueryOptions) -> Result<Option<Vec<I>>, Error> {
+        self.db.get_recent_with(key, options)
+    }
+
+    fn sort_by_recency(&self, found: HashSet<I, State>) -> Vec<I> {
+        self.db.sort_by_recency(found)
+    }
+
+    fn get_topk(&self, key: &I, k: usize) -> Result<Option<Vec<I>>, Error> {
+        self.db.get_topk(key, k)
+    }
+
+    fn estimate_candidates(&self, key: &I) -> Result<usize, Error> {
+        self.db.estimate_candidates(key)
+    }
+
+    fn health_check(&self, sample: usize) -> HealthReport {
+        self.db.health_check(sample)
+    }
+
+    /// Entries can't be removed from a frozen database, so this fails if
+    /// anything needs repairing
+    ///
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        self.db.verify_and_repair()
+    }
+
+    /// The ignored dimensions are fixed when the database is finalized
+    ///
+    fn set_ignored_dimensions(&mut self, _dimensions: Vec<usize>) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn set_id_strategy(&mut self, strategy: IDStrategy) -> Result<(), Error> {
+        self.db.set_id_strategy(strategy)
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        self.db.warmup(fraction)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        self.db.stats()
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        self.db.shadow_stats()
+    }
+
+    fn insert(&mut self, _key: I) -> Result<bool, Error> {
+        Err(read_only())
+    }
+
+    fn bulk_insert(&mut self, _keys: Vec<I>) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn remove(&mut self, _key: &I) -> Result<bool, Error> {
+        Err(read_only())
+    }
+
+    fn remove_verified(&mut self, _key: &I) -> Result<RemovalReport, Error> {
+        Err(read_only())
+    }
+
+    fn get_by_id(&self, id: &u64) -> Result<Option<I>, Error> {
+        self.db.get_by_id(id)
+    }
+
+    fn for_each_value(&self, f: &mut FnMut(I) -> Result<(), Error>) -> Result<(), Error> {
+        self.db.for_each_value(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use std::fs;
+    use std::collections::HashSet;
+
+    use self::quickcheck::quickcheck;
+
+    use db::{Database, Strategy};
+    use db::frozen::*;
+    use db::substitution;
+    use db::deletion;
+    use db::temp_dir::TempDir;
+    use db::typemap::{U64wU16InMemory, VecU8InMemory};
+    use error::Error;
+    use db::hashing::State;
+
+    #[test]
+    fn frozen_substitution_db_matches_original() {
+        fn prop(values: Vec<u64>, queries: Vec<u64>) -> bool {
+            let dir = TempDir::new();
+            fs::create_dir_all(dir.path()).unwrap();
+            let path = dir.path().join("db.frozen");
+
+            let mut db: substitution::DB<U64wU16InMemory> = substitution::DB::new(64, 4);
+            for value in values.into_iter() {
+                db.insert(value).unwrap();
+            }
+            db.finalize(&path).unwrap();
+
+            let frozen = FrozenDB::open_substitution::<U64wU16InMemory>(&path).unwrap();
+            queries.iter().all(|q| db.get(q).unwrap() == frozen.get(q).unwrap())
+        }
+        quickcheck(prop as fn(Vec<u64>, Vec<u64>) -> bool);
+    }
+
+    #[test]
+    fn frozen_deletion_db_matches_original() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("db.frozen");
+
+        let mut db: deletion::DB<VecU8InMemory> = deletion::DB::new(4, 1);
+        db.insert(vec![1, 2, 3, 4]).unwrap();
+        db.insert(vec![1, 2, 3, 5]).unwrap();
+        db.insert(vec![9, 9, 9, 9]).unwrap();
+        db.finalize(&path).unwrap();
+
+        let frozen = FrozenDB::open_deletion::<VecU8InMemory>(&path).unwrap();
+        assert_eq!(frozen.header().strategy, Strategy::Deletion);
+
+        let expected: HashSet<Vec<u8>, State> = vec![vec![1, 2, 3, 4], vec![1, 2, 3, 5]].into_iter().collect();
+        assert_eq!(frozen.get(&vec![1, 2, 3, 0]), Ok(Some(expected)));
+        assert_eq!(frozen.get(&vec![0, 0, 0, 0]), Ok(None));
+    }
+
+    #[test]
+    fn frozen_db_is_read_only() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("db.frozen");
+
+        let mut db: substitution::DB<U64wU16InMemory> = substitution::DB::new(64, 4);
+        db.insert(0b1111).unwrap();
+        db.finalize(&path).unwrap();
+
+        let mut frozen = FrozenDB::open_substitution::<U64wU16InMemory>(&path).unwrap();
+        assert_eq!(frozen.insert(0b0000), Err(Error::Unsupported("frozen databases are read-only".to_string())));
+        assert!(frozen.get(&0b0111).unwrap().unwrap().contains(&0b1111));
+
+        // Opening with the wrong strategy is refused
+        assert!(FrozenDB::open_deletion::<VecU8InMemory>(&path).is_err());
+    }
+}
@@ -4,6 +4,9 @@ extern crate num;
 use std::cmp::*;
 use std::clone::*;
 use std::hash::*;
+use std::mem;
+
+use db::popcount;
 
 /// HmSearch-indexable value
 ///
@@ -39,7 +42,7 @@ macro_rules! intrinsic_hamming {
             fn hamming_indices(&self, other: &$elem) -> Vec<usize> {
                 let different = *self ^ *other;
 
-                (0..8).filter(|i| (0 as $elem) != (1 as $elem) << i & different ).collect()
+                (0..(mem::size_of::<$elem>() * 8)).filter(|i| (0 as $elem) != (1 as $elem) << i & different ).collect()
             }
         }
     }
@@ -49,20 +52,46 @@ intrinsic_hamming!(u16);
 intrinsic_hamming!(u32);
 intrinsic_hamming!(u64);
 
+// Signed values (and `usize`) are compared bit-for-bit as the unsigned type of
+// the same width
+macro_rules! cast_hamming {
+    ($elem:ident, $unsigned:ident) => {
+        impl Hamming for $elem {
+            fn hamming(&self, other: &$elem) -> usize {
+                (*self as $unsigned).hamming(&(*other as $unsigned))
+            }
+            fn hamming_lte(&self, other: &$elem, bound: usize) -> bool {
+                (*self as $unsigned).hamming_lte(&(*other as $unsigned), bound)
+            }
+            fn hamming_indices(&self, other: &$elem) -> Vec<usize> {
+                (*self as $unsigned).hamming_indices(&(*other as $unsigned))
+            }
+        }
+    }
+}
+cast_hamming!(i8, u8);
+cast_hamming!(i16, u16);
+cast_hamming!(i32, u32);
+cast_hamming!(i64, u64);
+cast_hamming!(usize, u64);
+
 macro_rules! array_hamming {
     ($elem:ty) => {
         impl Hamming for $elem {
-            // `count_ones` should be faster than iterating over vectors as would happen
-            // with the default implementation
+            // Counted with SIMD instructions where available (see
+            // `db::popcount`)
             //
             fn hamming(&self, other: &$elem) -> usize {
-                self.iter().zip(other.iter()).fold(0, |h, (&a, &b)| { h + a.hamming(&b) })
+                popcount::hamming(self, other)
+            }
+            fn hamming_lte(&self, other: &$elem, bound: usize) -> bool {
+                popcount::hamming_lte(self, other, bound)
             }
             fn hamming_indices(&self, other: &$elem) -> Vec<usize> {
-                let len = self.len();
-
-                self.iter().zip(other.iter()).enumerate().fold(Vec::new(), |mut h, (i, (a, b))| {
-                    let offset = i * len;
+                // Dimensions are numbered from the last element, as they are
+                // when windowing (see `db::window`)
+                self.iter().zip(other.iter()).rev().enumerate().fold(Vec::new(), |mut h, (i, (a, b))| {
+                    let offset = i * 64;
                     let mut pair_indices = a.hamming_indices(b).iter().map(|idx| idx + offset).collect();
                     h.append(&mut pair_indices);
                     h
@@ -75,7 +104,22 @@ array_hamming!([u64; 2]);
 array_hamming!([u64; 4]);
 
 impl<T: Eq + Clone + Hash> Hamming for Vec<T> {
-    // NOTE: Optimize the bound query
+    fn hamming(&self, other: &Vec<T>) -> usize {
+        self.iter().zip(other.iter()).filter(|&(a, b)| a != b).count()
+    }
+
+    fn hamming_lte(&self, other: &Vec<T>, bound: usize) -> bool {
+        let mut h = 0;
+        for (a, b) in self.iter().zip(other.iter()) {
+            if a != b {
+                h += 1;
+                if h > bound {
+                    return false
+                }
+            }
+        }
+        true
+    }
 
     fn hamming_indices(&self, other: &Vec<T>) -> Vec<usize> {
         self.iter()
@@ -87,11 +131,59 @@ impl<T: Eq + Clone + Hash> Hamming for Vec<T> {
     }
 }
 
+/// Bit-level distance between vectors of binary values
+///
+/// Each element contributes the hamming distance between its bits, up to
+/// `cap`.  With `cap >= 1` this is never smaller than the element-wise
+/// `Hamming` distance, so it can be used to further filter the results of an
+/// element-wise index.
+///
+pub trait ElementHamming {
+    fn element_hamming(&self, rhs: &Self, cap: usize) -> usize;
+
+    /// Returns true if the capped bit-level distance between `self` and `rhs`
+    /// is less than or equal to `bound`, false otherwise
+    ///
+    fn element_hamming_lte(&self, rhs: &Self, cap: usize, bound: usize) -> bool {
+        self.element_hamming(rhs, cap) <= bound
+    }
+}
+
+impl<T: Hamming> ElementHamming for Vec<T> {
+    fn element_hamming(&self, other: &Vec<T>, cap: usize) -> usize {
+        self.iter().zip(other.iter()).fold(0, |h, (a, b)| h + min(a.hamming(b), cap))
+    }
+
+    fn element_hamming_lte(&self, other: &Vec<T>, cap: usize, bound: usize) -> bool {
+        let mut h = 0;
+        for (a, b) in self.iter().zip(other.iter()) {
+            h += min(a.hamming(b), cap);
+            if h > bound {
+                return false
+            }
+        }
+        true
+    }
+}
+
 
 #[cfg(test)] 
 mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
     use db::hamming::*;
 
+    // Signed tests
+
+    #[test]
+    fn test_hamming_signed() {
+        assert_eq!(0i64.hamming(&-1i64), 64);
+        assert_eq!((-1i32).hamming(&-2i32), 1);
+        assert_eq!(0usize.hamming(&0b101usize), 2);
+    }
+
     // Vec<u8> tests
 
     #[test]
@@ -122,6 +214,15 @@ mod test {
         assert_eq!(a.hamming(&b), 8);
     }
 
+    #[test]
+    fn test_hamming_lte_vec_matches_hamming() {
+        fn prop(a: Vec<u8>, b: Vec<u8>, bound: usize) -> bool {
+            a.hamming_lte(&b, bound) == (a.hamming(&b) <= bound) &&
+                a.hamming(&b) == a.hamming_indices(&b).len()
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<u8>, usize) -> bool);
+    }
+
 
     // u8 tests
     #[test]
@@ -182,4 +283,32 @@ mod test {
 
         assert_eq!(a.hamming(&b), 8);
     }
+
+    #[test]
+    fn test_hamming_indices_cover_every_bit() {
+        assert_eq!(0u64.hamming_indices(&((1 << 63) | 0b10)), vec![1, 63]);
+        assert_eq!([0u64; 2].hamming_indices(&[1, 1 << 40]), vec![40, 64]);
+    }
+
+    // ElementHamming tests
+
+    #[test]
+    fn test_element_hamming_counts_bits() {
+        let a = vec![0b0000u64, 0b0000u64, 0b0000u64];
+        let b = vec![0b0011u64, 0b0000u64, 0b0001u64];
+
+        assert_eq!(a.hamming(&b), 2);
+        assert_eq!(a.element_hamming(&b, 64), 3);
+        assert!(a.element_hamming_lte(&b, 64, 3));
+        assert!(!a.element_hamming_lte(&b, 64, 2));
+    }
+
+    #[test]
+    fn test_element_hamming_caps_elements() {
+        let a = vec![0u8, 0u8];
+        let b = vec![0b11111111u8, 0b00000001u8];
+
+        assert_eq!(a.element_hamming(&b, 2), 3);
+        assert_eq!(a.element_hamming(&b, 1), a.hamming(&b));
+    }
 }
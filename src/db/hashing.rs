@@ -6,9 +6,25 @@ use std::fmt::*;
 use std::borrow::*;
 use std::marker::*;
 
+use siphasher::sip::SipHasher;
+
 use db::hamming::*;
 use db::window::*;
 
+/// Hasher state used by the DB's internal hash maps and the sets returned by
+/// `Database::get` and `MapSet::get`
+///
+/// Building with the `deterministic` feature swaps the randomly-keyed std
+/// hasher for an unkeyed FNV hasher, so iteration orders (and anything derived
+/// from them, ie query results) are identical from one run to the next.  This
+/// is meant for tests and debugging - FNV is not resistant to hash flooding.
+///
+#[cfg(feature = "deterministic")]
+pub type State = BuildHasherDefault<::fnv::FnvHasher>;
+
+#[cfg(not(feature = "deterministic"))]
+pub type State = ::std::collections::hash_map::RandomState;
+
 /// Hash memoization wrapper
 ///
 /// `Hashed` wraps another type and computes its hash on creation.  Subsequent
@@ -38,6 +54,18 @@ pub struct Hashed<T, H = SipHasher> {
 impl<T,H> Hamming for Hashed<T,H>
 where T: Hamming 
 {
+    fn hamming(&self, other: &Self) -> usize {
+        let self_value: &T = &**self;
+        let other_value: &T = &**other;
+        self_value.hamming(other_value)
+    }
+
+    fn hamming_lte(&self, other: &Self, bound: usize) -> bool {
+        let self_value: &T = &**self;
+        let other_value: &T = &**other;
+        self_value.hamming_lte(other_value, bound)
+    }
+
     fn hamming_indices(&self, other: &Self) -> Vec<usize> {
         let self_value: &T = &**self;
         let other_value: &T = &**other;
@@ -116,3 +144,41 @@ impl<T,H> Borrow<T> for Hashed<T,H> {
         &self.value
     }
 }
+
+#[cfg(all(test, feature = "deterministic"))]
+mod test {
+    use std::collections::HashMap;
+
+    use db::{Database, Factory, StorageBackend};
+    use db::hashing::State;
+
+    #[test]
+    fn iteration_order_is_reproducible() {
+        let mut a: HashMap<u64, u64, State> = HashMap::default();
+        let mut b: HashMap<u64, u64, State> = HashMap::default();
+
+        for i in 0..1000u64 {
+            a.insert(i * 7919, i);
+            b.insert(i * 7919, i);
+        }
+
+        assert_eq!(a.keys().collect::<Vec<&u64>>(), b.keys().collect::<Vec<&u64>>());
+    }
+
+    #[test]
+    fn query_results_iterate_in_the_same_order() {
+        let build = || {
+            let mut db = u64::build(64, 8, StorageBackend::InMemory).unwrap();
+            for i in 0..500u64 {
+                db.insert(i * 0x0101).unwrap();
+            }
+            db
+        };
+        let (a, b) = (build(), build());
+
+        let a: Vec<u64> = a.get(&0).unwrap().unwrap().into_iter().collect();
+        let b: Vec<u64> = b.get(&0).unwrap().unwrap().into_iter().collect();
+        assert!(a.len() > 1);
+        assert_eq!(a, b);
+    }
+}
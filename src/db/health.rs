@@ -0,0 +1,211 @@
+//! Sanity checks for re-opened namespaces
+//!
+//! A persisted namespace which was damaged (by a crash mid-write, a bad disk
+//! or an incompatible upgrade) often opens cleanly and only fails when a
+//! query happens to touch a bad entry - panicking mid-request.  Running
+//! `Database::health_check` when a namespace is opened reads a bounded sample
+//! of its entries up front so the damage is found before it's served.
+//!
+//! The check is a sample, not a scrub: a healthy report means nothing was
+//! found, not that nothing is wrong.
+//!
+//! `Database::verify_and_repair` is the scrub.  A remove interrupted partway
+//! (ie by the process being killed) can leave variant entries referencing a
+//! value that's already gone from the value store, and queries touching them
+//! panic.  The repair reads every variant entry and removes those whose ID
+//! doesn't resolve.
+
+use std::fmt;
+use std::hash::Hash;
+use std::collections::HashSet;
+
+use db::map_set::MapSet;
+use db::id_map::IDMap;
+use db::hashing::State;
+use error::Error;
+
+/// Number of variant entries sampled when a namespace is opened
+pub const DEFAULT_SAMPLE: usize = 256;
+
+/// Outcome of `Database::health_check`
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    /// Entries read during the check
+    pub entries_checked: usize,
+    /// Problems found, one per line
+    pub findings: Vec<String>,
+}
+
+impl HealthReport {
+    /// Returns true if the check found nothing wrong
+    ///
+    pub fn healthy(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.healthy() {
+            write!(f, "healthy ({} entries checked)", self.entries_checked)
+        } else {
+            write!(f, "degraded ({} entries checked): {}", self.entries_checked, self.findings.join("; "))
+        }
+    }
+}
+
+/// Outcome of `Database::verify_and_repair`
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RepairReport {
+    /// Variant entries read
+    pub entries_checked: usize,
+    /// Distinct IDs which didn't resolve in the value store
+    pub dangling_ids: usize,
+    /// Variant entries removed because their ID didn't resolve
+    pub entries_removed: usize,
+    /// Variant entries removed because their value was otherwise gone from
+    /// the index (see `substitution::DB::verify_and_repair`)
+    pub orphans_removed: usize,
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} entries checked, {} removed referencing {} missing values, {} orphaned entries removed", self.entries_checked, self.entries_removed, self.dangling_ids, self.orphans_removed)
+    }
+}
+
+/// Samples up to `sample` entries of `variant_store`, checking that each
+/// decodes and that the ID it holds resolves in `value_store`
+///
+pub fn check_stores<K, ID, T, V, I>(variant_store: &V, value_store: &I, sample: usize) -> HealthReport where
+K: Clone + Eq + Hash,
+ID: Clone + Eq + Hash,
+V: MapSet<K, ID>,
+I: IDMap<ID, T>,
+{
+    let mut report: HealthReport = Default::default();
+
+    let entries = match variant_store.sample(sample) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.findings.push(e.to_string());
+            return report
+        },
+    };
+    report.entries_checked = entries.len();
+
+    // Variants of the same value share an ID, so only check each ID once
+    let ids: HashSet<ID, State> = entries.into_iter().map(|(_, id)| id).collect();
+    for id in ids.iter() {
+        if let Err(e) = value_store.check(id) {
+            report.findings.push(e.to_string());
+        }
+    }
+
+    report
+}
+
+/// Scans every entry of `variant_store`, removing those whose ID doesn't
+/// resolve in `value_store`
+///
+/// Each entry's ID is checked as it's read, so memory use is bounded by the
+/// number of dangling entries rather than the size of the store.  Only IDs
+/// the value store reports as `Error::Corrupt` are dangling - if the value
+/// store can't be read at all, the repair fails rather than removing
+/// entries which may be fine.
+///
+pub fn repair_stores<K, ID, T, V, I>(variant_store: &mut V, value_store: &I) -> Result<RepairReport, Error> where
+K: Clone + Eq + Hash,
+ID: Clone + Eq + Hash,
+V: MapSet<K, ID>,
+I: IDMap<ID, T>,
+{
+    let mut report: RepairReport = Default::default();
+    let mut dangling: Vec<(K, ID)> = Vec::new();
+    let mut failure: Option<Error> = None;
+
+    try!(variant_store.scan(&mut |key, id| {
+        report.entries_checked += 1;
+        match value_store.check(id) {
+            Ok(()) => {},
+            Err(Error::Corrupt(..)) => dangling.push((key.clone(), id.clone())),
+            Err(e) => if failure.is_none() { failure = Some(e) },
+        }
+    }));
+
+    if let Some(e) = failure {
+        return Err(e)
+    }
+
+    let ids: HashSet<&ID, State> = dangling.iter().map(|&(_, ref id)| id).collect();
+    report.dangling_ids = ids.len();
+
+    for &(ref key, ref id) in dangling.iter() {
+        if try!(variant_store.remove(key, id)) {
+            report.entries_removed += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use db::map_set::{self, MapSet};
+    use db::id_map::{self, IDMap};
+    use db::health::{check_stores, repair_stores};
+
+    #[test]
+    fn reports_unresolvable_ids() {
+        let mut variant_store: map_set::TempRocksDB<u64, u64> = map_set::TempRocksDB::new().unwrap();
+        let mut value_store: id_map::HashMap<u64, Vec<u8>> = id_map::HashMap::new();
+
+        variant_store.insert(1, 10).unwrap();
+        variant_store.insert(2, 10).unwrap();
+        value_store.insert(10, vec![1, 2, 3]).unwrap();
+
+        let report = check_stores(&variant_store, &value_store, 16);
+        assert!(report.healthy());
+        assert_eq!(report.entries_checked, 2);
+
+        variant_store.insert(3, 11).unwrap();
+
+        let report = check_stores(&variant_store, &value_store, 16);
+        assert!(!report.healthy());
+        assert_eq!(report.findings.len(), 1);
+    }
+
+    #[test]
+    fn repair_removes_dangling_entries() {
+        let mut variant_store: map_set::TempRocksDB<u64, u64> = map_set::TempRocksDB::new().unwrap();
+        let mut value_store: id_map::HashMap<u64, Vec<u8>> = id_map::HashMap::new();
+
+        variant_store.insert(1, 10).unwrap();
+        variant_store.insert(1, 11).unwrap();
+        variant_store.insert(2, 11).unwrap();
+        value_store.insert(10, vec![1, 2, 3]).unwrap();
+
+        let report = repair_stores(&mut variant_store, &value_store).unwrap();
+        assert_eq!(report.entries_checked, 3);
+        assert_eq!(report.dangling_ids, 1);
+        assert_eq!(report.entries_removed, 2);
+
+        assert_eq!(variant_store.get(&1).unwrap().unwrap().len(), 1);
+        assert_eq!(variant_store.get(&2), Ok(None));
+        assert!(check_stores(&variant_store, &value_store, 16).healthy());
+    }
+
+    #[test]
+    fn sample_is_bounded() {
+        let mut variant_store: map_set::TempRocksDB<u64, u64> = map_set::TempRocksDB::new().unwrap();
+        let value_store: id_map::Echo<u64> = id_map::Echo::new();
+
+        for i in 0..100 {
+            variant_store.insert(i, i).unwrap();
+        }
+
+        assert_eq!(check_stores(&variant_store, &value_store, 10).entries_checked, 10);
+    }
+}
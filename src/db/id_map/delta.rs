@@ -0,0 +1,189 @@
+use std::cmp::Eq;
+use std::hash::Hash;
+use std::default::Default;
+use std::collections;
+
+use db::hashing::State;
+use error::Error;
+
+use super::{IDMap, Sequencer};
+
+/// Default maximum number of representatives kept by a `DeltaHashMap`
+pub const DEFAULT_MAX_REPRESENTATIVES: usize = 256;
+
+/// A value stored as the elements where it differs from a representative
+struct Delta {
+    representative: usize,
+    len: usize,
+    /// `(index, value ^ representative[index])` for each differing element
+    diffs: Vec<(u32, u64)>,
+}
+
+/// `HashMap` storing `Vec<u64>` values as XOR deltas against representatives
+///
+/// Fingerprint corpora tend to contain many values which differ from one
+/// another in only a few elements.  Rather than storing every value in full,
+/// each value is stored as the (sparse) set of elements where it differs from
+/// the closest of a small set of representative values.
+///
+/// Representatives are chosen online: a value whose closest representative
+/// differs in more than `1 / new_cluster_ratio` of its elements becomes a
+/// new representative itself, until `max_representatives` have been chosen.
+/// Representatives are never evicted, so corpora whose clusters drift over
+/// time will compress less well than ones whose clusters are stable.
+///
+pub struct DeltaHashMap<K> {
+    data: collections::HashMap<K, (Delta, u64), State>,
+    representatives: Vec<Vec<u64>>,
+    max_representatives: usize,
+    new_cluster_ratio: usize,
+    sequencer: Sequencer,
+}
+
+impl<ID> DeltaHashMap<ID> where
+ID: Eq + Hash,
+{
+    pub fn new() -> DeltaHashMap<ID> {
+        DeltaHashMap::with_max_representatives(DEFAULT_MAX_REPRESENTATIVES)
+    }
+
+    pub fn with_max_representatives(max_representatives: usize) -> DeltaHashMap<ID> {
+        DeltaHashMap {
+            data: collections::HashMap::default(),
+            representatives: Vec::new(),
+            max_representatives: max_representatives,
+            new_cluster_ratio: 4,
+            sequencer: Sequencer::new(),
+        }
+    }
+
+    /// Number of representatives chosen so far
+    ///
+    pub fn representatives(&self) -> usize {
+        self.representatives.len()
+    }
+
+    /// Number of differing elements stored across all values
+    ///
+    pub fn stored_elements(&self) -> usize {
+        self.data.values().map(|&(ref delta, _)| delta.diffs.len()).sum::<usize>() +
+            self.representatives.iter().map(|r| r.len()).sum::<usize>()
+    }
+
+    fn encode(&mut self, value: &Vec<u64>) -> Delta {
+        let closest = self.representatives.iter()
+            .enumerate()
+            .map(|(i, r)| (i, diff(r, value)))
+            .min_by_key(|&(_, ref diffs)| diffs.len());
+
+        match closest {
+            Some((i, ref diffs)) if diffs.len() * self.new_cluster_ratio <= value.len() ||
+                self.representatives.len() >= self.max_representatives => {
+                Delta{representative: i, len: value.len(), diffs: diffs.clone()}
+            },
+            _ => {
+                self.representatives.push(value.clone());
+                Delta{representative: self.representatives.len() - 1, len: value.len(), diffs: Vec::new()}
+            },
+        }
+    }
+
+    fn decode(&self, delta: &Delta) -> Vec<u64> {
+        let representative = &self.representatives[delta.representative];
+        let mut value: Vec<u64> = (0..delta.len).map(|i| representative.get(i).cloned().unwrap_or(0)).collect();
+
+        for &(i, xor) in delta.diffs.iter() {
+            value[i as usize] ^= xor;
+        }
+
+        value
+    }
+}
+
+/// XOR of each element of `value` which differs from `representative`
+/// (elements past the end of `representative` are compared with 0)
+///
+fn diff(representative: &Vec<u64>, value: &Vec<u64>) -> Vec<(u32, u64)> {
+    value.iter()
+        .enumerate()
+        .map(|(i, v)| (i as u32, v ^ representative.get(i).cloned().unwrap_or(0)))
+        .filter(|&(_, xor)| xor != 0)
+        .collect()
+}
+
+impl<ID> Default for DeltaHashMap<ID> where
+ID: Eq + Hash,
+{
+    fn default() -> DeltaHashMap<ID> {
+        DeltaHashMap::new()
+    }
+}
+
+impl<ID> IDMap<ID, Vec<u64>> for DeltaHashMap<ID> where
+ID: Sync + Send + Eq + Hash,
+{
+    fn get(&self, id: ID) -> Result<Option<Vec<u64>>, Error> {
+        Ok(self.data.get(&id).map(|&(ref delta, _)| self.decode(delta)))
+    }
+
+    fn insert(&mut self, id: ID, value: Vec<u64>) -> Result<(), Error> {
+        let delta = self.encode(&value);
+        let sequence = self.sequencer.next();
+        self.data.insert(id, (delta, sequence));
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &ID) -> Result<(), Error> {
+        self.data.remove(id);
+        Ok(())
+    }
+
+    fn sequence(&self, id: &ID) -> Option<u64> {
+        self.data.get(id).map(|&(_, sequence)| sequence)
+    }
+
+    fn check(&self, id: &ID) -> Result<(), Error> {
+        match self.data.contains_key(id) {
+            true => Ok(()),
+            false => Err(Error::Corrupt("no value for a variant's ID".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
+    use db::id_map::{IDMap, DeltaHashMap};
+
+    #[test]
+    fn round_trips() {
+        fn prop(values: Vec<Vec<u64>>) -> bool {
+            let mut map = DeltaHashMap::with_max_representatives(2);
+            for (i, value) in values.iter().enumerate() {
+                map.insert(i, value.clone()).unwrap();
+            }
+
+            values.iter().enumerate().all(|(i, value)| map.get(i) == Ok(Some(value.clone())))
+        }
+        quickcheck(prop as fn(Vec<Vec<u64>>) -> bool);
+    }
+
+    #[test]
+    fn similar_values_share_a_representative() {
+        let mut map = DeltaHashMap::new();
+        let base: Vec<u64> = (0..32).collect();
+        map.insert(100, base.clone()).unwrap();
+
+        for i in 0..100 {
+            let mut value = base.clone();
+            value[i % 32] ^= 1;
+            map.insert(i, value).unwrap();
+        }
+
+        assert_eq!(map.representatives(), 1);
+        assert_eq!(map.stored_elements(), 32 + 100);
+    }
+}
@@ -1,6 +1,8 @@
 use std::default::Default;
 use std::marker::PhantomData;
 
+use error::Error;
+
 use super::{IDMap};
 
 pub struct Echo<T> {
@@ -20,7 +22,10 @@ impl<T> Default for Echo<T> {
 }
 
 impl<T: Sync + Send> IDMap<T, T> for Echo<T> {
-    fn get(&self, id: T) -> T { id }
-    fn insert(&mut self, _: T, _: T) {}
-    fn remove(&mut self, _: &T) {}
+    fn get(&self, id: T) -> Result<Option<T>, Error> { Ok(Some(id)) }
+    fn insert(&mut self, _: T, _: T) -> Result<(), Error> { Ok(()) }
+    fn remove(&mut self, _: &T) -> Result<(), Error> { Ok(()) }
+    fn shares_writes(&self) -> bool { true }
+    fn insert_shared(&self, _: T, _: T) -> Result<(), Error> { Ok(()) }
+    fn snapshot(&self) -> Result<Echo<T>, Error> { Ok(Echo::new()) }
 }
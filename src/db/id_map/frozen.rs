@@ -0,0 +1,77 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::encoding::{encode, decode};
+use db::frozen::Table;
+use db::stats::StoreUsage;
+use error::Error;
+
+use super::IDMap;
+
+/// Frozen reads values from the value table of a finalized database (see
+/// `db::frozen`)
+///
+/// Writes fail with `Error::Unsupported`.
+///
+pub struct Frozen {
+    table: Table,
+}
+
+impl Frozen {
+    pub fn new(table: Table) -> Frozen {
+        Frozen{table: table}
+    }
+
+    fn lookup(&self, encoded_id: &[u8]) -> Result<Option<&[u8]>, Error> {
+        let found = try!(self.table.find(encoded_id));
+        if found.start == found.end {
+            return Ok(None)
+        }
+        Ok(Some(try!(self.table.entry(found.start)).1))
+    }
+}
+
+fn read_only() -> Error {
+    Error::Unsupported("frozen stores are read-only".to_string())
+}
+
+impl<ID: Serialize, T: DeserializeOwned> IDMap<ID, T> for Frozen {
+    fn get(&self, id: ID) -> Result<Option<T>, Error> {
+        let encoded_id = try!(encode(&id));
+        match try!(self.lookup(&encoded_id)) {
+            Some(encoded_value) => Ok(Some(try!(decode(encoded_value)))),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, _id: ID, _value: T) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn remove(&mut self, _id: &ID) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn check(&self, id: &ID) -> Result<(), Error> {
+        let encoded_id = try!(encode(id));
+        match try!(self.lookup(&encoded_id)) {
+            Some(encoded_value) => {
+                decode::<T>(encoded_value)
+                    .map(|_| ())
+                    .map_err(|e| Error::Corrupt(format!("undecodable value for ID {:?}: {:?}", encoded_id, e)))
+            },
+            None => Err(Error::Corrupt(format!("no value for ID {:?}", encoded_id))),
+        }
+    }
+
+    /// The table is mapped rather than read, so only counts toward disk
+    /// usage
+    ///
+    fn usage(&self) -> StoreUsage {
+        StoreUsage{memory_bytes: 0, disk_bytes: Some(self.table.byte_len())}
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        self.table.warmup(fraction)
+    }
+}
@@ -1,25 +1,35 @@
 use std::cmp::Eq;
 use std::hash::Hash;
+use std::mem::size_of;
 use std::default::Default;
 use std::collections;
+use std::sync::Arc;
 
-use super::IDMap;
+use db::hashing::State;
+use error::Error;
+use db::stats::StoreUsage;
+
+use super::{IDMap, Sequencer};
 
 // This is sort of janky, but using a newtype causes duplicate trait 
 // implementation errors for some reason
+//
+// The map is shared with its snapshots, and copied by the first write after
+// one is taken
 pub struct HashMap<K, V> {
-    data: collections::HashMap<K, V>,
+    data: Arc<collections::HashMap<K, (V, u64), State>>,
+    sequencer: Sequencer,
 }
 
 impl<ID, T> HashMap<ID, T> where
 ID: Eq + Hash,
 {
     pub fn new() -> HashMap<ID, T> {
-        HashMap{data: collections::HashMap::new()}
+        HashMap{data: Arc::new(collections::HashMap::default()), sequencer: Sequencer::new()}
     }
 
     pub fn with_capacity(capacity: usize) -> HashMap<ID, T> {
-        HashMap{data: collections::HashMap::with_capacity(capacity)}
+        HashMap{data: Arc::new(collections::HashMap::with_capacity_and_hasher(capacity, Default::default())), sequencer: Sequencer::new()}
     }
 }
 
@@ -32,18 +42,49 @@ ID: Eq + Hash,
 }
 
 impl<ID, T> IDMap<ID, T> for HashMap<ID, T> where
-ID: Sync + Send + Eq + Hash,
+ID: Sync + Send + Clone + Eq + Hash,
 T: Sync + Send + Clone,
 {
-    fn get(&self, id: ID) -> T {
-        self.data.get(&id).unwrap().clone()
+    fn get(&self, id: ID) -> Result<Option<T>, Error> {
+        Ok(self.data.get(&id).map(|&(ref value, _)| value.clone()))
+    }
+
+    fn insert(&mut self, id: ID, value: T) -> Result<(), Error> {
+        let sequence = self.sequencer.next();
+        Arc::make_mut(&mut self.data).insert(id, (value, sequence));
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &ID) -> Result<(), Error> {
+        if self.data.contains_key(id) {
+            Arc::make_mut(&mut self.data).remove(id);
+        }
+        Ok(())
     }
 
-    fn insert(&mut self, id: ID, value: T) {
-        self.data.insert(id, value);
+    fn sequence(&self, id: &ID) -> Option<u64> {
+        self.data.get(id).map(|&(_, sequence)| sequence)
+    }
+
+    fn check(&self, id: &ID) -> Result<(), Error> {
+        match self.data.contains_key(id) {
+            true => Ok(()),
+            false => Err(Error::Corrupt("no value for a variant's ID".to_string())),
+        }
+    }
+
+    /// Counts the inline size of each ID, value & sequence - memory owned by
+    /// values (ie vector elements) isn't included
+    ///
+    fn usage(&self) -> StoreUsage {
+        let memory_bytes = self.data.len() * (size_of::<ID>() + size_of::<(T, u64)>());
+
+        StoreUsage{memory_bytes: memory_bytes as u64, disk_bytes: None}
     }
 
-    fn remove(&mut self, id: &ID) {
-        self.data.remove(id);
+    /// Shares the map until either copy is written
+    ///
+    fn snapshot(&self) -> Result<HashMap<ID, T>, Error> {
+        Ok(HashMap{data: self.data.clone(), sequencer: Sequencer::new()})
     }
 }
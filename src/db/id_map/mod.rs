@@ -1,57 +1,426 @@
+mod delta;
 mod echo;
+#[cfg(not(target_arch = "wasm32"))]
+mod frozen;
 mod hash_map;
+#[cfg(feature = "rocksdb")]
 mod rocks_db;
+#[cfg(feature = "sled")]
+mod sled_db;
 
-use std::hash::{Hash, Hasher, SipHasher};
+use std::cmp::max;
+use std::hash::{Hash, Hasher};
 use std::ops::{Deref, DerefMut};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use fnv::FnvHasher;
+use siphasher::sip::SipHasher;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
+use db::bits::Bits;
+#[cfg(feature = "rocksdb")]
+use db::column_family::Batch;
+use db::stats::StoreUsage;
+use error::Error;
+
+pub use self::delta::DeltaHashMap;
 pub use self::echo::Echo;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::frozen::Frozen;
 pub use self::hash_map::HashMap;
+#[cfg(feature = "rocksdb")]
 pub use self::rocks_db::{RocksDB, TempRocksDB};
+#[cfg(feature = "sled")]
+pub use self::sled_db::Sled;
 
 pub trait IDMap<ID, T>: Sync + Send {
-    fn get(&self, id: ID) -> T;
-    fn insert(&mut self, id: ID, value: T);
-    fn remove(&mut self, id: &ID);
+    /// The value stored under `id`, or `None` if there isn't one
+    ///
+    /// Fails if the store can't be read or the value can't be decoded
+    ///
+    fn get(&self, id: ID) -> Result<Option<T>, Error>;
+    fn insert(&mut self, id: ID, value: T) -> Result<(), Error>;
+    fn remove(&mut self, id: &ID) -> Result<(), Error>;
+
+    /// Returns the insertion sequence of `id`, if the map tracks it
+    ///
+    /// Values inserted more recently have larger sequences
+    ///
+    fn sequence(&self, _id: &ID) -> Option<u64> {
+        None
+    }
+
+    /// Bind `batch` to this map's RocksDB, returning false if the map can't
+    /// stage writes in it (see `db::atomic`)
+    ///
+    /// Maps which don't keep their values in RocksDB needn't override this;
+    /// their writes are rolled back instead.
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn join(&self, _batch: &mut Batch) -> bool {
+        false
+    }
+
+    /// Like `insert`, but adds the write to `batch` rather than making it
+    ///
+    /// Only called after the map has joined `batch`.
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn stage_insert(&mut self, _batch: &Batch, _id: ID, _value: T) -> Result<(), Error> {
+        Err(Error::Unsupported("map doesn't support batched writes".to_string()))
+    }
+
+    /// Like `remove`, but adds the delete to `batch` rather than making it
+    ///
+    /// Only called after the map has joined `batch`.
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove(&self, _batch: &Batch, _id: &ID) -> Result<(), Error> {
+        Err(Error::Unsupported("map doesn't support batched writes".to_string()))
+    }
+
+    /// Checks that `id` resolves to a readable value
+    ///
+    /// Used by health checks; a missing value is reported as
+    /// `Error::Corrupt`, as every ID checked is referenced by a variant.
+    /// Stores which can't lose or corrupt values needn't override this.
+    ///
+    fn check(&self, _id: &ID) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Space used by the map, for stats
+    ///
+    /// Maps which store nothing needn't override this.
+    ///
+    fn usage(&self) -> StoreUsage {
+        Default::default()
+    }
+
+    /// Read about `fraction` (between 0 and 1) of the map's values, so the
+    /// first queries after opening it don't wait on disk
+    ///
+    /// Returns the number of entries read.  In-memory maps needn't override
+    /// this.
+    ///
+    fn warmup(&self, _fraction: f64) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    /// Hand any buffered writes to the underlying storage
+    ///
+    /// Stores which write through needn't override this.
+    ///
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Flush, then make sure every write so far will survive a crash
+    ///
+    /// In-memory stores needn't override this.
+    ///
+    fn sync(&self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    /// Reclaim the space held by removed entries (see `db::compaction`)
+    ///
+    /// Stores which free removed entries immediately needn't override this.
+    ///
+    fn compact(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns true if the map can be written through a shared reference
+    /// (see `insert_shared`)
+    ///
+    fn shares_writes(&self) -> bool {
+        false
+    }
+
+    /// Like `insert`, through a shared reference
+    ///
+    /// Fails with `Error::Unsupported` unless `shares_writes`.
+    ///
+    fn insert_shared(&self, _id: ID, _value: T) -> Result<(), Error> {
+        Err(Error::Unsupported("map doesn't support shared writes".to_string()))
+    }
+
+    /// A read-only copy of the map as it is now, unaffected by later writes
+    /// (see `Database::snapshot`)
+    ///
+    /// Fails with `Error::Unsupported` if the map can't be copied cheaply.
+    ///
+    fn snapshot(&self) -> Result<Self, Error> where Self: Sized {
+        Err(Error::Unsupported("map doesn't support snapshots".to_string()))
+    }
 }
 
 impl<T, ID, D: Deref + DerefMut> IDMap<ID, T> for D where 
 D: Sync + Send,
 <D as Deref>::Target: IDMap<ID, T>,
 {
-    fn get(&self, id: ID) -> T {
+    fn get(&self, id: ID) -> Result<Option<T>, Error> {
         self.deref().get(id)
     }
 
-    fn insert(&mut self, id: ID, value: T) { 
+    fn insert(&mut self, id: ID, value: T) -> Result<(), Error> {
         self.deref_mut().insert(id, value)
     }
 
-    fn remove(&mut self, id: &ID) {
+    fn remove(&mut self, id: &ID) -> Result<(), Error> {
         self.deref_mut().remove(id)
     }
+
+    #[cfg(feature = "rocksdb")]
+    fn join(&self, batch: &mut Batch) -> bool {
+        self.deref().join(batch)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_insert(&mut self, batch: &Batch, id: ID, value: T) -> Result<(), Error> {
+        self.deref_mut().stage_insert(batch, id, value)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove(&self, batch: &Batch, id: &ID) -> Result<(), Error> {
+        self.deref().stage_remove(batch, id)
+    }
+
+    fn sequence(&self, id: &ID) -> Option<u64> {
+        self.deref().sequence(id)
+    }
+
+    fn check(&self, id: &ID) -> Result<(), Error> {
+        self.deref().check(id)
+    }
+
+    fn usage(&self) -> StoreUsage {
+        self.deref().usage()
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        self.deref().warmup(fraction)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.deref().flush()
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.deref().sync()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.deref_mut().compact()
+    }
+
+    fn shares_writes(&self) -> bool {
+        self.deref().shares_writes()
+    }
+
+    fn insert_shared(&self, id: ID, value: T) -> Result<(), Error> {
+        self.deref().insert_shared(id, value)
+    }
+}
+
+/// Generates insertion sequence numbers
+///
+/// Sequences are nanosecond timestamps, bumped when necessary so they're
+/// strictly increasing within a process.  Using timestamps rather than a
+/// counter keeps sequences ordered across restarts without persisting any
+/// state.
+///
+pub struct Sequencer {
+    last: u64,
+}
+
+impl Sequencer {
+    pub fn new() -> Sequencer {
+        Sequencer{last: 0}
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.last = max(now_nanos(), self.last + 1);
+        self.last
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now_nanos() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000000000 + d.subsec_nanos() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// wasm32 has no clock to read, so sequences just count up from 1
+#[cfg(target_arch = "wasm32")]
+fn now_nanos() -> u64 {
+    0
+}
+
+/// How a value which isn't its own identifier is turned into one
+///
+/// IDs are what the variant store references, so a DB's strategy can't change
+/// once anything is inserted.  DBs not given a strategy use their value
+/// type's default (`ToID::to_id`): SipHash for vectors, FNV for everything
+/// else which is hashed.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IDStrategy {
+    /// 64-bit FNV-1a hash, fastest for short values
+    Fnv,
+    /// 64-bit SipHash, better distributed for long values
+    SipHash,
+    /// The value is its own identifier, so no two values share one.  Only
+    /// possible for values no wider than their identifier.
+    Echo,
 }
 
 pub trait ToID<T> {
     fn to_id(self) -> T;
+
+    /// The identifier `strategy` gives `self`
+    ///
+    /// Only called with strategies `supports_id_strategy` accepts.  Values
+    /// which are their own identifier needn't override this.
+    ///
+    fn to_id_with(self, _strategy: IDStrategy) -> T where Self: Sized {
+        self.to_id()
+    }
+
+    /// Returns true if values can be identified with `strategy`
+    ///
+    fn supports_id_strategy(strategy: IDStrategy) -> bool where Self: Sized {
+        strategy == IDStrategy::Echo
+    }
 }
 
 impl<T> ToID<T> for T {
     fn to_id(self) -> T { self }
 }
 
+/// A 128-bit identifier
+///
+/// With billions of values, the chance of two sharing a 64-bit hash is no
+/// longer negligible.  DBs which can't risk it identify values with these
+/// instead (see the `*ID128*` typemap aliases and `DbBuilder::id128`), at
+/// the cost of 8 more bytes per stored variant.
+///
+/// These are encoded as their high then low 64 bits, which keeps them
+/// fixed-width (bincode only encodes a `u128` with its `i128` feature).
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ID128(pub u128);
+
+impl Serialize for ID128 {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        ((self.0 >> 64) as u64, self.0 as u64).serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for ID128 {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<ID128, D::Error> {
+        let (high, low) = try!(<(u64, u64)>::deserialize(d));
+        Ok(ID128(((high as u128) << 64) | low as u128))
+    }
+}
+
+/// Identifiers which can be named by the `u64` IDs `Database::get_by_id`
+/// takes
+///
+/// Values of up to 64 bits are their own identifiers, so their ID is the
+/// value's bits.  Returns `None` for IDs no value of the type could have.
+///
+pub trait FromID: Sized {
+    fn from_id(id: u64) -> Option<Self>;
+}
+
+macro_rules! from_id_bits {
+    ($id:ident, $unsigned:ident) => {
+        impl FromID for $id {
+            fn from_id(id: u64) -> Option<$id> {
+                if id > (::std::$unsigned::MAX as u64) {
+                    return None
+                }
+                Some(id as $unsigned as $id)
+            }
+        }
+    }
+}
+from_id_bits!(u8, u8);
+from_id_bits!(u16, u16);
+from_id_bits!(u32, u32);
+from_id_bits!(u64, u64);
+from_id_bits!(i8, u8);
+from_id_bits!(i16, u16);
+from_id_bits!(i32, u32);
+from_id_bits!(i64, u64);
+from_id_bits!(usize, u64);
+
+// Values wider than 64 bits are only their own identifiers when stored inline
+// (see `Factory::build_inline`), and can't be named by a `u64`
+impl FromID for [u64; 2] {
+    fn from_id(_id: u64) -> Option<[u64; 2]> {
+        None
+    }
+}
+
+impl FromID for [u64; 4] {
+    fn from_id(_id: u64) -> Option<[u64; 4]> {
+        None
+    }
+}
+
+impl<T> FromID for Vec<T> {
+    fn from_id(_id: u64) -> Option<Vec<T>> {
+        None
+    }
+}
+
+/// Only 128-bit IDs below 2^64 can be named
+///
+impl FromID for ID128 {
+    fn from_id(id: u64) -> Option<ID128> {
+        Some(ID128(id as u128))
+    }
+}
+
+/// Hashes each of `elems` into `s`
+///
+fn hash_elements<H: Hasher, T: Hash>(mut s: H, elems: &[T]) -> u64 {
+    for e in elems.iter() {
+        e.hash(&mut s);
+    }
+    s.finish()
+}
+
+/// Hashes `value` into `s`
+///
+fn hash_value<H: Hasher, T: Hash>(mut s: H, value: &T) -> u64 {
+    value.hash(&mut s);
+    s.finish()
+}
+
 // NOTE: Using SipHasher here rather than FNV because we anticipate large values,
 // and the FNV speed advantage only holds to ~20 bytes (based on some rando 
 // benchmarks on the interwebs)
 impl<T: Hash> ToID<u64> for Vec<T> {
     fn to_id(self) -> u64 {
-        let mut s = SipHasher::new();
-        for e in self.iter() {
-            e.hash(&mut s);
+        self.to_id_with(IDStrategy::SipHash)
+    }
+
+    fn to_id_with(self, strategy: IDStrategy) -> u64 {
+        match strategy {
+            IDStrategy::Fnv => hash_elements(FnvHasher::default(), &self),
+            _ => hash_elements(SipHasher::new(), &self),
         }
-        s.finish()
+    }
+
+    fn supports_id_strategy(strategy: IDStrategy) -> bool {
+        strategy != IDStrategy::Echo
     }
 }
 
@@ -59,12 +428,196 @@ macro_rules! to_id_hash_fnv {
     ($elem:ty) => {
         impl ToID<u64> for $elem {
             fn to_id(self) -> u64 {
-                let mut s = FnvHasher::default();
-                self.hash(&mut s);
-                s.finish()
+                self.to_id_with(IDStrategy::Fnv)
+            }
+
+            fn to_id_with(self, strategy: IDStrategy) -> u64 {
+                match strategy {
+                    IDStrategy::SipHash => hash_value(SipHasher::new(), &self),
+                    _ => hash_value(FnvHasher::default(), &self),
+                }
+            }
+
+            fn supports_id_strategy(strategy: IDStrategy) -> bool {
+                strategy != IDStrategy::Echo
             }
         }
     }
 }
 to_id_hash_fnv!([u64; 2]);
 to_id_hash_fnv!([u64; 4]);
+to_id_hash_fnv!(Bits);
+
+/// Hashers with a 128-bit result
+///
+trait Hasher128: Hasher {
+    fn finish128(&self) -> u128;
+}
+
+const FNV128_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV128_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// 128-bit FNV-1a
+///
+struct Fnv128Hasher(u128);
+
+impl Default for Fnv128Hasher {
+    fn default() -> Fnv128Hasher {
+        Fnv128Hasher(FNV128_OFFSET)
+    }
+}
+
+impl Hasher for Fnv128Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes.iter() {
+            self.0 ^= b as u128;
+            self.0 = self.0.wrapping_mul(FNV128_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0 as u64
+    }
+}
+
+impl Hasher128 for Fnv128Hasher {
+    fn finish128(&self) -> u128 {
+        self.0
+    }
+}
+
+/// Two differently-keyed 64-bit SipHashes
+///
+struct Sip128Hasher(SipHasher, SipHasher);
+
+impl Default for Sip128Hasher {
+    fn default() -> Sip128Hasher {
+        Sip128Hasher(SipHasher::new(), SipHasher::new_with_keys(0x736f6d6570736575, 0x646f72616e646f6d))
+    }
+}
+
+impl Hasher for Sip128Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+        self.1.write(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl Hasher128 for Sip128Hasher {
+    fn finish128(&self) -> u128 {
+        ((self.0.finish() as u128) << 64) | self.1.finish() as u128
+    }
+}
+
+fn hash_elements128<H: Hasher128, T: Hash>(mut s: H, elems: &[T]) -> ID128 {
+    for e in elems.iter() {
+        e.hash(&mut s);
+    }
+    ID128(s.finish128())
+}
+
+fn hash_value128<H: Hasher128, T: Hash>(mut s: H, value: &T) -> ID128 {
+    value.hash(&mut s);
+    ID128(s.finish128())
+}
+
+impl<T: Hash> ToID<ID128> for Vec<T> {
+    fn to_id(self) -> ID128 {
+        self.to_id_with(IDStrategy::SipHash)
+    }
+
+    fn to_id_with(self, strategy: IDStrategy) -> ID128 {
+        match strategy {
+            IDStrategy::Fnv => hash_elements128(Fnv128Hasher::default(), &self),
+            _ => hash_elements128(Sip128Hasher::default(), &self),
+        }
+    }
+
+    fn supports_id_strategy(strategy: IDStrategy) -> bool {
+        strategy != IDStrategy::Echo
+    }
+}
+
+/// 128-bit values are their own 128-bit identifier unless hashing is asked for
+///
+impl ToID<ID128> for [u64; 2] {
+    fn to_id(self) -> ID128 {
+        self.to_id_with(IDStrategy::Echo)
+    }
+
+    fn to_id_with(self, strategy: IDStrategy) -> ID128 {
+        match strategy {
+            IDStrategy::Fnv => hash_value128(Fnv128Hasher::default(), &self),
+            IDStrategy::SipHash => hash_value128(Sip128Hasher::default(), &self),
+            IDStrategy::Echo => ID128(((self[0] as u128) << 64) | self[1] as u128),
+        }
+    }
+
+    fn supports_id_strategy(_strategy: IDStrategy) -> bool {
+        true
+    }
+}
+
+macro_rules! to_id128_hash_fnv {
+    ($elem:ty) => {
+        impl ToID<ID128> for $elem {
+            fn to_id(self) -> ID128 {
+                self.to_id_with(IDStrategy::Fnv)
+            }
+
+            fn to_id_with(self, strategy: IDStrategy) -> ID128 {
+                match strategy {
+                    IDStrategy::SipHash => hash_value128(Sip128Hasher::default(), &self),
+                    _ => hash_value128(Fnv128Hasher::default(), &self),
+                }
+            }
+
+            fn supports_id_strategy(strategy: IDStrategy) -> bool {
+                strategy != IDStrategy::Echo
+            }
+        }
+    }
+}
+to_id128_hash_fnv!([u64; 4]);
+to_id128_hash_fnv!(Bits);
+
+#[cfg(test)]
+mod test {
+
+    use std::hash::Hasher;
+
+    use db::encoding::{encode, decode};
+
+    use db::id_map::{ID128, ToID, IDStrategy, Fnv128Hasher};
+
+    #[test]
+    fn id128_encodes_fixed_width() {
+        let id = ID128(0x0123456789abcdef_fedcba9876543210);
+        let encoded: Vec<u8> = encode(&id).unwrap();
+
+        assert_eq!(encoded.len(), 16);
+        assert_eq!(decode::<ID128>(&encoded).unwrap(), id);
+    }
+
+    #[test]
+    fn fnv128_matches_reference() {
+        // Test vector from the FNV reference implementation
+        let mut s = Fnv128Hasher::default();
+        s.write(b"a");
+        assert_eq!(s.0, 0xd228cb696f1a8caf78912b704e4a8964);
+    }
+
+    #[test]
+    fn wide_values_echo_into_id128() {
+        let id: ID128 = [1u64, 2].to_id();
+        assert_eq!(id, ID128((1 << 64) | 2));
+
+        let a: ID128 = vec![1u8, 2, 3].to_id_with(IDStrategy::Fnv);
+        let b: ID128 = vec![1u8, 2, 4].to_id_with(IDStrategy::Fnv);
+        assert!(a != b);
+    }
+}
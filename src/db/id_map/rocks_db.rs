@@ -1,117 +1,283 @@
-use std::env;
-use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
-use rocksdb::{DB, Writable, Options};
-use rustc_serialize::{Encodable, Decodable};
-use bincode::SizeLimit;
-use bincode::rustc_serialize::{encode, decode};
-use uuid::Uuid;
+use rocksdb::Options;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
-use super::IDMap;
+use db::encoding::{encode, decode};
+use db::column_family::{Batch, SharedRocksDB, Store};
+use db::compaction::StoreOptions;
+use db::stats::StoreUsage;
+use db::temp_dir::TempDir;
+use error::Error;
+
+use super::{IDMap, Sequencer};
+
+// Sequence keys are the encoded ID prefixed with this byte; IDs are fixed-width
+// so these can't collide with value keys
+const SEQUENCE_PREFIX: u8 = 0xff;
 
 pub struct TempRocksDB<ID, T> {
-    dir: PathBuf,
+    // Must be dropped before `dir` so RocksDB is closed before its files are
+    // removed.  Snapshots share `dir`, so it's removed with the last of them.
     db: RocksDB<ID, T>,
+    dir: Arc<TempDir>,
 }
 
 impl<ID, T> TempRocksDB<ID, T> {
-    pub fn with_opts(opts: Options) -> TempRocksDB<ID, T> {
-        let mut dir = env::temp_dir(); 
-        dir.push(&Uuid::new_v4().to_hyphenated_string());
+    pub fn with_opts(opts: Options) -> Result<TempRocksDB<ID, T>, Error> {
+        let dir = TempDir::new();
 
-        TempRocksDB{
-            dir: dir.clone(),
-            db: RocksDB::with_opts(dir.to_str().unwrap(), opts),
-        }
+        Ok(TempRocksDB{
+            db: try!(RocksDB::with_opts(dir.to_str(), opts)),
+            dir: Arc::new(dir),
+        })
     }
 
-    pub fn new() -> TempRocksDB<ID, T> {
-        let mut dir = env::temp_dir(); 
-        dir.push(&Uuid::new_v4().to_hyphenated_string());
+    pub fn new() -> Result<TempRocksDB<ID, T>, Error> {
+        let dir = TempDir::new();
 
-        TempRocksDB{
-            dir: dir.clone(),
-            db: RocksDB::new(dir.to_str().unwrap()),
-        }
+        Ok(TempRocksDB{
+            db: try!(RocksDB::new(dir.to_str())),
+            dir: Arc::new(dir),
+        })
     }
-}
 
-impl<ID, T> Drop for TempRocksDB<ID, T> {
-    fn drop(&mut self) {
-        // Nothing we can do about it here, so ¯\_(ツ)_/¯
-        let _ = fs::remove_dir_all(self.dir.to_str().unwrap());
+    pub fn path(&self) -> &Path {
+        self.dir.path()
     }
 }
 
 impl<ID, T> IDMap<ID, T> for TempRocksDB<ID, T> where
-ID: Sync + Send + Encodable + Decodable,
-T: Sync + Send + Encodable + Decodable,
+ID: Sync + Send + Serialize + DeserializeOwned,
+T: Sync + Send + Serialize + DeserializeOwned,
 {
-    fn get(&self, id: ID) -> T {
+    fn get(&self, id: ID) -> Result<Option<T>, Error> {
         self.db.get(id)
     }
 
-    fn insert(&mut self, id: ID, value: T) {
+    fn insert(&mut self, id: ID, value: T) -> Result<(), Error> {
         self.db.insert(id, value)
     }
 
-    fn remove(&mut self, id: &ID) {
+    fn remove(&mut self, id: &ID) -> Result<(), Error> {
         self.db.remove(id)
     }
+
+    fn join(&self, batch: &mut Batch) -> bool {
+        self.db.join(batch)
+    }
+
+    fn stage_insert(&mut self, batch: &Batch, id: ID, value: T) -> Result<(), Error> {
+        self.db.stage_insert(batch, id, value)
+    }
+
+    fn stage_remove(&self, batch: &Batch, id: &ID) -> Result<(), Error> {
+        self.db.stage_remove(batch, id)
+    }
+
+    fn sequence(&self, id: &ID) -> Option<u64> {
+        self.db.sequence(id)
+    }
+
+    fn check(&self, id: &ID) -> Result<(), Error> {
+        self.db.check(id)
+    }
+
+    fn usage(&self) -> StoreUsage {
+        self.db.usage()
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        self.db.warmup(fraction)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.db.sync()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.db.compact()
+    }
+
+    fn snapshot(&self) -> Result<TempRocksDB<ID, T>, Error> {
+        Ok(TempRocksDB {
+            db: try!(self.db.snapshot()),
+            dir: self.dir.clone(),
+        })
+    }
 }
 
+/// Values are kept in a RocksDB of their own, or in a column family of a
+/// `SharedRocksDB` (see `db::column_family`)
+///
 pub struct RocksDB<ID, T> {
     id: PhantomData<ID>,
     value: PhantomData<T>,
-    db: DB,
+    store: Store,
+    sequencer: Sequencer,
 }
 
-impl<ID, T> RocksDB<ID, T> {
-    pub fn with_opts(path: &str, opts: Options) -> RocksDB<ID, T> {
-        let db = DB::open(&opts, path).unwrap();
+fn sequence_key(encoded_id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(encoded_id.len() + 1);
+    key.push(SEQUENCE_PREFIX);
+    key.extend_from_slice(encoded_id);
+    key
+}
 
-        RocksDB{
+impl<ID, T> RocksDB<ID, T> {
+    pub fn with_opts(path: &str, opts: Options) -> Result<RocksDB<ID, T>, Error> {
+        Ok(RocksDB{
             id: PhantomData,
             value: PhantomData,
-            db: db,
-        }
+            store: try!(Store::dedicated(Path::new(path), StoreOptions::new(opts))),
+            sequencer: Sequencer::new(),
+        })
     }
 
-    pub fn new(path: &str) -> RocksDB<ID, T> {
-        let db = DB::open_default(path).unwrap();
+    pub fn new(path: &str) -> Result<RocksDB<ID, T>, Error> {
+        Ok(RocksDB{
+            id: PhantomData,
+            value: PhantomData,
+            store: try!(Store::dedicated(Path::new(path), StoreOptions::default())),
+            sequencer: Sequencer::new(),
+        })
+    }
 
-        RocksDB{
+    /// The store `name` in `shared`
+    ///
+    pub fn in_family(shared: Arc<SharedRocksDB>, name: &str) -> Result<RocksDB<ID, T>, Error> {
+        Ok(RocksDB{
             id: PhantomData,
             value: PhantomData,
-            db: db,
-        }
+            store: try!(Store::in_family(shared, name)),
+            sequencer: Sequencer::new(),
+        })
     }
 }
 
 impl<ID, T> IDMap<ID, T> for RocksDB<ID, T> where
-ID: Sync + Send + Encodable + Decodable,
-T: Sync + Send + Encodable + Decodable,
+ID: Sync + Send + Serialize + DeserializeOwned,
+T: Sync + Send + Serialize + DeserializeOwned,
 {
-    fn get(&self, id: ID) -> T {
-        let encoded_id: Vec<u8> = encode(&id, SizeLimit::Infinite).unwrap();
+    fn get(&self, id: ID) -> Result<Option<T>, Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+
+        match try!(self.store.with(|view| view.get(&encoded_id))) {
+            Some(encoded_value) => Ok(Some(try!(decode(&encoded_value)))),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, id: ID, value: T) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+        let encoded_value: Vec<u8> = try!(encode(&value));
+
+        let encoded_sequence: Vec<u8> = try!(encode(&self.sequencer.next()));
+
+        self.store.with(|view| -> Result<(), Error> {
+            try!(view.put(&encoded_id, &encoded_value));
+            try!(view.put(&sequence_key(&encoded_id), &encoded_sequence));
+            Ok(())
+        })
+    }
 
-        let encoded_value = self.db.get(&encoded_id).unwrap().unwrap();
+    fn remove(&mut self, id: &ID) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
 
-        decode(&encoded_value).unwrap()
+        self.store.with(|view| -> Result<(), Error> {
+            try!(view.delete(&encoded_id));
+            try!(view.delete(&sequence_key(&encoded_id)));
+            Ok(())
+        })
     }
 
-    fn insert(&mut self, id: ID, value: T) {
-        let encoded_id: Vec<u8> = encode(&id, SizeLimit::Infinite).unwrap();
-        let encoded_value: Vec<u8> = encode(&value, SizeLimit::Infinite).unwrap();
+    fn join(&self, batch: &mut Batch) -> bool {
+        self.store.join(batch)
+    }
+
+    fn stage_insert(&mut self, batch: &Batch, id: ID, value: T) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+        let encoded_value: Vec<u8> = try!(encode(&value));
+
+        let encoded_sequence: Vec<u8> = try!(encode(&self.sequencer.next()));
+
+        self.store.with(|view| -> Result<(), Error> {
+            try!(view.batch_put(batch.writes(), &encoded_id, &encoded_value));
+            try!(view.batch_put(batch.writes(), &sequence_key(&encoded_id), &encoded_sequence));
+            Ok(())
+        })
+    }
+
+    fn stage_remove(&self, batch: &Batch, id: &ID) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
 
-        self.db.put(&encoded_id, &encoded_value).unwrap();
+        self.store.with(|view| -> Result<(), Error> {
+            try!(view.batch_delete(batch.writes(), &encoded_id));
+            try!(view.batch_delete(batch.writes(), &sequence_key(&encoded_id)));
+            Ok(())
+        })
     }
 
-    fn remove(&mut self, id: &ID) {
-        let encoded_id: Vec<u8> = encode(&id, SizeLimit::Infinite).unwrap();
+    /// Sequences only order results, so one which can't be read is treated
+    /// as missing
+    ///
+    fn sequence(&self, id: &ID) -> Option<u64> {
+        let encoded_id: Vec<u8> = match encode(&id) {
+            Ok(encoded_id) => encoded_id,
+            Err(_) => return None,
+        };
 
-        self.db.delete(&encoded_id).unwrap();
+        match self.store.with(|view| view.get(&sequence_key(&encoded_id))) {
+            Ok(Some(encoded_sequence)) => decode(&encoded_sequence).ok(),
+            _ => None,
+        }
+    }
+
+    fn check(&self, id: &ID) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+
+        match self.store.with(|view| view.get(&encoded_id)) {
+            Ok(Some(encoded_value)) => {
+                decode::<T>(&encoded_value)
+                    .map(|_| ())
+                    .map_err(|e| Error::Corrupt(format!("undecodable value for ID {:?}: {:?}", encoded_id, e)))
+            },
+            Ok(None) => Err(Error::Corrupt(format!("no value for ID {:?}", encoded_id))),
+            Err(e) => Err(Error::Storage(format!("unable to read ID {:?}: {}", encoded_id, e))),
+        }
+    }
+
+    /// Only disk usage is reported, as for `map_set::RocksDB`
+    ///
+    fn usage(&self) -> StoreUsage {
+        StoreUsage{memory_bytes: 0, disk_bytes: self.store.disk_bytes()}
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        Ok(try!(self.store.warmup(fraction)))
+    }
+
+    /// Writes go straight to RocksDB, so there's nothing to flush
+    ///
+    fn sync(&self) -> Result<(), Error> {
+        Ok(try!(self.store.sync()))
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        Ok(try!(self.store.compact()))
+    }
+
+    /// Reads a RocksDB snapshot (see `Store::snapshot`)
+    ///
+    fn snapshot(&self) -> Result<RocksDB<ID, T>, Error> {
+        Ok(RocksDB{
+            id: PhantomData,
+            value: PhantomData,
+            store: try!(self.store.snapshot()),
+            sequencer: Sequencer::new(),
+        })
     }
 }
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::path::Path;
+use std::marker::PhantomData;
+
+use sled;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::encoding::{encode, decode};
+use error::Error;
+
+use super::{IDMap, Sequencer};
+
+// Sequence keys are the encoded ID prefixed with this byte, as in `RocksDB`
+const SEQUENCE_PREFIX: u8 = 0xff;
+
+/// IDMap stored in the pure-Rust sled store
+///
+pub struct Sled<ID, T> {
+    id: PhantomData<ID>,
+    value: PhantomData<T>,
+    db: sled::Db,
+    sequencer: Sequencer,
+}
+
+fn sequence_key(encoded_id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(encoded_id.len() + 1);
+    key.push(SEQUENCE_PREFIX);
+    key.extend_from_slice(encoded_id);
+    key
+}
+
+impl<ID, T> Sled<ID, T> {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Sled<ID, T>, Error> {
+        Ok(Sled{
+            id: PhantomData,
+            value: PhantomData,
+            db: try!(sled::open(path).map_err(storage_error)),
+            sequencer: Sequencer::new(),
+        })
+    }
+}
+
+/// sled reports errors as its own type
+///
+fn storage_error<E: fmt::Display>(e: E) -> Error {
+    Error::Storage(format!("{}", e))
+}
+
+impl<ID, T> IDMap<ID, T> for Sled<ID, T> where
+ID: Sync + Send + Serialize + DeserializeOwned,
+T: Sync + Send + Serialize + DeserializeOwned,
+{
+    fn get(&self, id: ID) -> Result<Option<T>, Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+
+        match try!(self.db.get(&encoded_id).map_err(storage_error)) {
+            Some(encoded_value) => Ok(Some(try!(decode(&encoded_value)))),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, id: ID, value: T) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+        let encoded_value: Vec<u8> = try!(encode(&value));
+
+        let encoded_sequence: Vec<u8> = try!(encode(&self.sequencer.next()));
+
+        try!(self.db.insert(sequence_key(&encoded_id), encoded_sequence).map_err(storage_error));
+        try!(self.db.insert(encoded_id, encoded_value).map_err(storage_error));
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &ID) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+
+        try!(self.db.remove(sequence_key(&encoded_id)).map_err(storage_error));
+        try!(self.db.remove(encoded_id).map_err(storage_error));
+        Ok(())
+    }
+
+    /// Sequences only order results, so one which can't be read is treated
+    /// as missing
+    ///
+    fn sequence(&self, id: &ID) -> Option<u64> {
+        let encoded_id: Vec<u8> = match encode(&id) {
+            Ok(encoded_id) => encoded_id,
+            Err(_) => return None,
+        };
+
+        match self.db.get(sequence_key(&encoded_id)) {
+            Ok(Some(encoded_sequence)) => decode(&encoded_sequence).ok(),
+            _ => None,
+        }
+    }
+
+    fn check(&self, id: &ID) -> Result<(), Error> {
+        let encoded_id: Vec<u8> = try!(encode(&id));
+
+        match self.db.get(&encoded_id) {
+            Ok(Some(encoded_value)) => {
+                decode::<T>(&encoded_value)
+                    .map(|_| ())
+                    .map_err(|e| Error::Corrupt(format!("undecodable value for ID {:?}: {:?}", encoded_id, e)))
+            },
+            Ok(None) => Err(Error::Corrupt(format!("no value for ID {:?}", encoded_id))),
+            Err(e) => Err(Error::Storage(format!("unable to read ID {:?}: {}", encoded_id, e))),
+        }
+    }
+
+    /// sled buffers writes in memory; flushing also fsyncs them
+    ///
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush().map(|_| ()).map_err(|e| Error::Storage(format!("unable to flush: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::id_map::{IDMap, Sled};
+    use db::temp_dir::TempDir;
+
+    #[test]
+    fn round_trips() {
+        let dir = TempDir::new();
+        let mut map: Sled<u64, Vec<u8>> = Sled::new(dir.path()).unwrap();
+
+        map.insert(1, vec![1, 2, 3]).unwrap();
+        map.insert(2, vec![4]).unwrap();
+        assert_eq!(map.get(1), Ok(Some(vec![1, 2, 3])));
+        assert!(map.sequence(&2) > map.sequence(&1));
+
+        map.remove(&1).unwrap();
+        assert!(map.check(&1).is_err());
+        assert!(map.check(&2).is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+//! Linkage queries between databases
+//!
+//! `join` finds, for each value in a stream, the values of another database
+//! within a given tolerance and yields the matched pairs.  Values are pulled
+//! from the stream in batches and each batch is queried by a pool of threads,
+//! so only one batch of the left-hand side is held in memory at a time.
+//!
+//! Databases don't expose their contents, so the left-hand side is whatever
+//! produced the values indexed in the first database (a file, another store,
+//! etc) rather than the database itself.
+//!
+//! Each lookup holds at most `spill_threshold` candidates in memory (see
+//! `Database::for_each_match`), so dense neighborhoods of the other database
+//! don't have to fit in RAM.
+//!
+//! A failed lookup is yielded as an `Err` in place of that value's pairs, and
+//! the join carries on with the next value.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut b = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+//! b.insert(0b0011);
+//! let b = Arc::new(b);
+//!
+//! let pairs: Result<Vec<(u64, u64)>, Error> = join(vec![0b0001, 0b1000], b, 1).collect();
+//! assert_eq!(pairs, Ok(vec![(0b0001, 0b0011)]));
+//! ```
+
+use std::cmp::{min, max};
+use std::clone::Clone;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::{Database, QueryOptions};
+use db::hamming::Hamming;
+use error::Error;
+
+/// Default number of left-hand values queried per batch
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// Default number of threads used to query each batch
+pub const DEFAULT_THREADS: usize = 4;
+
+/// Default number of candidates each lookup holds in memory before spilling
+pub const DEFAULT_SPILL_THRESHOLD: usize = 100000;
+
+/// Iterator over matched `(left, right)` pairs
+///
+pub struct Join<T, I>
+where   T: 'static + Clone + Send + Sync + Hamming + Serialize + DeserializeOwned,
+        I: Iterator<Item=T>,
+{
+    values: I,
+    other: Arc<Box<Database<T>>>,
+    tolerance: usize,
+    batch_size: usize,
+    threads: usize,
+    spill_threshold: usize,
+    buffer: VecDeque<Result<(T, T), Error>>,
+}
+
+/// Join each of `values` against `other`, yielding pairs within `tolerance`
+///
+/// `tolerance` may be smaller than `other`'s tolerance to narrow the matches;
+/// values further apart than `other`'s tolerance won't be found regardless.
+///
+pub fn join<T, I>(values: I, other: Arc<Box<Database<T>>>, tolerance: usize) -> Join<T, I::IntoIter>
+where   T: 'static + Clone + Send + Sync + Hamming + Serialize + DeserializeOwned,
+        I: IntoIterator<Item=T>,
+{
+    Join {
+        values: values.into_iter(),
+        other: other,
+        tolerance: tolerance,
+        batch_size: DEFAULT_BATCH_SIZE,
+        threads: DEFAULT_THREADS,
+        spill_threshold: DEFAULT_SPILL_THRESHOLD,
+        buffer: VecDeque::new(),
+    }
+}
+
+impl<T, I> Join<T, I>
+where   T: 'static + Clone + Send + Sync + Hamming + Serialize + DeserializeOwned,
+        I: Iterator<Item=T>,
+{
+    pub fn batch_size(mut self, batch_size: usize) -> Join<T, I> {
+        self.batch_size = max(batch_size, 1);
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Join<T, I> {
+        self.threads = max(threads, 1);
+        self
+    }
+
+    pub fn spill_threshold(mut self, spill_threshold: usize) -> Join<T, I> {
+        self.spill_threshold = max(spill_threshold, 1);
+        self
+    }
+
+    /// Queries the next batch, returning false once `values` is exhausted
+    ///
+    fn fill(&mut self) -> bool {
+        let batch: Vec<T> = self.values.by_ref().take(self.batch_size).collect();
+        if batch.is_empty() {
+            return false
+        }
+
+        let chunk_size = (batch.len() + self.threads - 1) / self.threads;
+        let handles: Vec<_> = batch.chunks(min(chunk_size, batch.len())).map(|chunk| {
+            let chunk = chunk.to_vec();
+            let other = self.other.clone();
+            let tolerance = self.tolerance;
+            let spill_threshold = self.spill_threshold;
+
+            thread::spawn(move || {
+                let mut pairs = Vec::new();
+                for value in chunk {
+                    let matched = other.for_each_match(&value, &QueryOptions::default(), spill_threshold, &mut |matched| {
+                        if value.hamming_lte(&matched, tolerance) {
+                            pairs.push(Ok((value.clone(), matched)));
+                        }
+                    });
+                    if let Err(e) = matched {
+                        pairs.push(Err(e));
+                    }
+                }
+                pairs
+            })
+        }).collect();
+
+        for handle in handles {
+            self.buffer.extend(handle.join().unwrap());
+        }
+
+        true
+    }
+}
+
+impl<T, I> Iterator for Join<T, I>
+where   T: 'static + Clone + Send + Sync + Hamming + Serialize + DeserializeOwned,
+        I: Iterator<Item=T>,
+{
+    type Item = Result<(T, T), Error>;
+
+    fn next(&mut self) -> Option<Result<(T, T), Error>> {
+        loop {
+            if let Some(pair) = self.buffer.pop_front() {
+                return Some(pair)
+            }
+            if !self.fill() {
+                return None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::collections::HashSet;
+
+    use db::{Factory, StorageBackend};
+    use db::join::*;
+    use db::hashing::State;
+
+    #[test]
+    fn yields_pairs_within_tolerance() {
+        let mut b = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+        b.insert(0b0011).unwrap();
+        b.insert(0b1111).unwrap();
+        let b = Arc::new(b);
+
+        let pairs: HashSet<(u64, u64), State> = join(vec![0b0001, 0b0111, 0b1000000000], b, 1)
+            .batch_size(2)
+            .threads(2)
+            .map(|pair| pair.unwrap())
+            .collect();
+
+        let expected: HashSet<(u64, u64), State> = vec![(0b0001, 0b0011), (0b0111, 0b0011), (0b0111, 0b1111)].into_iter().collect();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn spilled_lookups_yield_the_same_pairs() {
+        let mut b = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+        for v in 0..64u64 {
+            b.insert(v).unwrap();
+        }
+        let b = Arc::new(b);
+
+        let in_memory: HashSet<(u64, u64), State> = join(vec![0, 7], b.clone(), 2).map(|pair| pair.unwrap()).collect();
+        let spilled: HashSet<(u64, u64), State> = join(vec![0, 7], b, 2).spill_threshold(4).map(|pair| pair.unwrap()).collect();
+
+        assert_eq!(in_memory.len(), 2 * (1 + 6 + 15));
+        assert_eq!(spilled, in_memory);
+    }
+
+    #[test]
+    fn empty_left_yields_nothing() {
+        let b = Arc::new(u64::build(64, 4, StorageBackend::InMemory).unwrap());
+
+        assert_eq!(join(Vec::<u64>::new(), b, 4).count(), 0);
+    }
+}
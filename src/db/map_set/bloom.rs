@@ -0,0 +1,481 @@
+use std::clone::Clone;
+use std::cmp::Eq;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+use std::collections::{HashMap, HashSet};
+
+use fnv::FnvHasher;
+
+#[cfg(feature = "rocksdb")]
+use db::column_family::Batch;
+use db::hashing::State;
+use db::stats::StoreUsage;
+use db::window::Window;
+use error::Error;
+
+use super::MapSet;
+
+/// Default number of counters in each partition's filter
+pub const DEFAULT_COUNTERS: usize = 1 << 20;
+
+// Counters set (and checked) per key
+const HASHES: u64 = 4;
+
+/// Keys which belong to a partition of the DB's dimensions
+///
+pub trait Partitioned {
+    fn partition(&self) -> &Window;
+}
+
+/// Counting bloom filter over a partition's keys
+///
+/// Each entry adds one to its key's counters, and removing it takes one
+/// away, so a key whose counters are all zero has no entries.  A counter
+/// which overflows stays at its maximum for good - it can no longer tell how
+/// many entries it counts, so it must never reach zero.
+///
+struct Filter {
+    counters: Vec<u8>,
+}
+
+impl Filter {
+    fn new(counters: usize) -> Filter {
+        Filter {counters: vec![0; counters.max(1)]}
+    }
+
+    /// Indices of `key`'s counters, by double hashing
+    ///
+    fn indices<K: Hash>(&self, key: &K) -> Vec<usize> {
+        let mut hasher = FnvHasher::default();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xffffffff, (hash >> 32) | 1);
+
+        let len = self.counters.len() as u64;
+        (0..HASHES).map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize).collect()
+    }
+
+    fn add<K: Hash>(&mut self, key: &K) {
+        for i in self.indices(key) {
+            self.counters[i] = self.counters[i].saturating_add(1);
+        }
+    }
+
+    fn subtract(&mut self, indices: &[usize]) {
+        for &i in indices.iter() {
+            match self.counters[i] {
+                0 | ::std::u8::MAX => (),
+                _ => self.counters[i] -= 1,
+            }
+        }
+    }
+
+    fn may_contain<K: Hash>(&self, key: &K) -> bool {
+        self.indices(key).into_iter().all(|i| self.counters[i] > 0)
+    }
+}
+
+/// Each partition's filter, shared by a `Bloom` and its snapshots
+///
+struct Filters {
+    partitions: HashMap<Window, Filter, State>,
+    // Live snapshots; removes are deferred until there are none
+    snapshots: usize,
+    // The partition and counters of each deferred remove
+    deferred: Vec<(Window, Vec<usize>)>,
+}
+
+impl Filters {
+    fn new(partitions: HashMap<Window, Filter, State>) -> Filters {
+        Filters {
+            partitions: partitions,
+            snapshots: 0,
+            deferred: Vec::new(),
+        }
+    }
+
+    fn add<K: Hash + Partitioned>(&mut self, key: &K, counters: usize) {
+        self.partitions.entry(key.partition().clone()).or_insert_with(|| Filter::new(counters)).add(key)
+    }
+
+    fn subtract<K: Hash + Partitioned>(&mut self, key: &K) {
+        let indices = match self.partitions.get(key.partition()) {
+            Some(filter) => filter.indices(key),
+            None => return,
+        };
+
+        if self.snapshots > 0 {
+            self.deferred.push((key.partition().clone(), indices))
+        } else {
+            self.apply(key.partition(), &indices)
+        }
+    }
+
+    fn apply(&mut self, partition: &Window, indices: &[usize]) {
+        if let Some(filter) = self.partitions.get_mut(partition) {
+            filter.subtract(indices)
+        }
+    }
+
+    /// Applies the deferred removes once no snapshot is left
+    ///
+    fn release(&mut self) {
+        self.snapshots -= 1;
+        if self.snapshots == 0 {
+            let deferred = mem::replace(&mut self.deferred, Vec::new());
+            for (partition, indices) in deferred.into_iter() {
+                self.apply(&partition, &indices)
+            }
+        }
+    }
+}
+
+/// A snapshot's hold on the filters, released when it's dropped
+///
+struct Held(Arc<RwLock<Filters>>);
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.0.write().unwrap().release()
+    }
+}
+
+/// Skips reads of keys which can't be in another `MapSet`
+///
+/// Most variant keys probed by a query over sparse data don't exist, and
+/// looking each one up in a persistent store costs a point read.  `Bloom`
+/// keeps a counting bloom filter of each partition's keys in memory, and
+/// only reads keys the filter might contain.
+///
+/// The filters are kept up to date by inserts & removes.  Removes which
+/// can't report the keys they removed from (`remove_many`) or may yet be
+/// rolled back (`stage_remove`) leave the filter as it was; a stale key only
+/// costs a read.
+///
+/// Snapshots share the filters, so keys inserted after one is taken only
+/// cost it a read.  Removes made while a snapshot is held are applied to the
+/// filters once the last snapshot is dropped, since until then its store
+/// still has the removed entries.
+///
+pub struct Bloom<K, V, M> {
+    store: M,
+    counters: usize,
+    filters: Arc<RwLock<Filters>>,
+    // Set for snapshots
+    _held: Option<Held>,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K, V, M> Bloom<K, V, M> where
+K: Sync + Send + Clone + Eq + Hash + Partitioned,
+V: Sync + Send + Clone + Eq + Hash,
+M: MapSet<K, V>,
+{
+    /// Filter `store` with `DEFAULT_COUNTERS` per partition
+    ///
+    pub fn new(store: M) -> Result<Bloom<K, V, M>, Error> {
+        Bloom::with_counters(store, DEFAULT_COUNTERS)
+    }
+
+    /// Filter `store` with `counters` per partition (or not at all, if it's
+    /// 0)
+    ///
+    /// The filters are loaded from the store's existing entries, so it must
+    /// support `MapSet::scan`.  Each counter takes a byte; at 4 counters set
+    /// per key, 10 counters per key gives roughly a 1% false positive rate.
+    ///
+    pub fn with_counters(store: M, counters: usize) -> Result<Bloom<K, V, M>, Error> {
+        let mut filters: HashMap<Window, Filter, State> = HashMap::default();
+        if counters > 0 {
+            try!(store.scan(&mut |key, _| {
+                filters.entry(key.partition().clone()).or_insert_with(|| Filter::new(counters)).add(key);
+            }));
+        }
+
+        Ok(Bloom {
+            store: store,
+            counters: counters,
+            filters: Arc::new(RwLock::new(Filters::new(filters))),
+            _held: None,
+            marker: PhantomData,
+        })
+    }
+
+    /// The wrapped store
+    ///
+    pub fn store(&self) -> &M {
+        &self.store
+    }
+
+    pub fn into_store(self) -> M {
+        self.store
+    }
+
+    fn may_contain(&self, key: &K) -> bool {
+        if self.counters == 0 {
+            return true
+        }
+        self.filters.read().unwrap().partitions.get(key.partition()).map_or(false, |filter| filter.may_contain(key))
+    }
+
+    fn add(&self, key: &K) {
+        let counters = self.counters;
+        if counters == 0 {
+            return
+        }
+        self.filters.write().unwrap().add(key, counters)
+    }
+
+    fn subtract(&self, key: &K) {
+        self.filters.write().unwrap().subtract(key)
+    }
+}
+
+impl<K, V, M> MapSet<K, V> for Bloom<K, V, M> where
+K: Sync + Send + Clone + Eq + Hash + Partitioned,
+V: Sync + Send + Clone + Eq + Hash,
+M: MapSet<K, V>,
+{
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error> {
+        let inserted = try!(self.store.insert(key.clone(), value));
+        if inserted {
+            self.add(&key);
+        }
+        Ok(inserted)
+    }
+
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
+        if !self.may_contain(key) {
+            return Ok(None)
+        }
+        self.store.get(key)
+    }
+
+    /// Only keys the filters might contain are read from the store
+    ///
+    fn get_many(&self, keys: &[K]) -> Result<Vec<Option<HashSet<V, State>>>, Error> {
+        let candidates: Vec<bool> = keys.iter().map(|key| self.may_contain(key)).collect();
+        let probed: Vec<K> = keys.iter().zip(candidates.iter())
+            .filter(|&(_, &candidate)| candidate)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut found = try!(self.store.get_many(&probed)).into_iter();
+        Ok(candidates.into_iter().map(|candidate| {
+            if candidate { found.next().unwrap() } else { None }
+        }).collect())
+    }
+
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        if !self.may_contain(key) {
+            return Ok(0)
+        }
+        self.store.count(key)
+    }
+
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error> {
+        let removed = try!(self.store.remove(key, value));
+        if removed {
+            self.subtract(key);
+        }
+        Ok(removed)
+    }
+
+    fn remove_many(&mut self, keys: &[K], value: &V) -> Result<usize, Error> {
+        self.store.remove_many(keys, value)
+    }
+
+    /// Every entry is counted, whether or not it was already present; an
+    /// overcounted key is only read more often than it needs to be
+    ///
+    fn bulk_insert(&mut self, entries: Vec<(K, V)>) -> Result<(), Error> {
+        if self.counters > 0 {
+            let counters = self.counters;
+            let mut filters = self.filters.write().unwrap();
+            for &(ref key, _) in entries.iter() {
+                filters.add(key, counters);
+            }
+        }
+        self.store.bulk_insert(entries)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn join(&self, batch: &mut Batch) -> bool {
+        self.store.join(batch)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_insert(&self, batch: &Batch, key: K, value: V) -> Result<bool, Error> {
+        let inserted = try!(self.store.stage_insert(batch, key.clone(), value));
+        if inserted {
+            self.add(&key);
+        }
+        Ok(inserted)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove(&self, batch: &Batch, key: &K, value: &V) -> Result<bool, Error> {
+        self.store.stage_remove(batch, key, value)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove_many(&self, batch: &Batch, keys: &[K], value: &V) -> Result<usize, Error> {
+        self.store.stage_remove_many(batch, keys, value)
+    }
+
+    fn sample(&self, n: usize) -> Result<Vec<(K, V)>, Error> {
+        self.store.sample(n)
+    }
+
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        self.store.scan(f)
+    }
+
+    /// The store's usage, plus the filters' counters
+    ///
+    fn usage(&self) -> StoreUsage {
+        let mut usage = self.store.usage();
+        let filters = self.filters.read().unwrap();
+        usage.memory_bytes += filters.partitions.values().fold(0, |bytes, filter| bytes + filter.counters.len()) as u64;
+        usage
+    }
+
+    fn bucket_sample_rate(&self) -> usize {
+        self.store.bucket_sample_rate()
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        self.store.warmup(fraction)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.store.flush()
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.store.sync()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.store.compact()
+    }
+
+    fn set_cache_capacity(&mut self, sets: usize) -> Result<(), Error> {
+        self.store.set_cache_capacity(sets)
+    }
+
+    fn snapshot(&self) -> Result<Bloom<K, V, M>, Error> {
+        let store = try!(self.store.snapshot());
+        self.filters.write().unwrap().snapshots += 1;
+
+        Ok(Bloom {
+            store: store,
+            counters: self.counters,
+            filters: self.filters.clone(),
+            _held: Some(Held(self.filters.clone())),
+            marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
+    use db::map_set::{MapSet, InMemoryHash, ShardedInMemoryHash, Bloom};
+    use db::substitution::Key;
+    use db::window::Window;
+
+    fn key(partition: u8, variant: u8) -> Key<u8> {
+        Key::Zero(Window{start_dimension: partition as usize % 4, dimensions: 8}, variant)
+    }
+
+    #[test]
+    fn matches_unfiltered_store() {
+        fn prop(ops: Vec<(bool, u8, u8, u8)>, probes: Vec<(u8, u8)>) -> bool {
+            // Few counters, so plenty of false positives and shared counters
+            let mut filtered = Bloom::with_counters(InMemoryHash::new(), 16).unwrap();
+            let mut plain = InMemoryHash::new();
+
+            for &(insert, p, k, v) in ops.iter() {
+                if insert {
+                    assert_eq!(filtered.insert(key(p, k), v).unwrap(), plain.insert(key(p, k), v).unwrap());
+                } else {
+                    assert_eq!(filtered.remove(&key(p, k), &v).unwrap(), plain.remove(&key(p, k), &v).unwrap());
+                }
+            }
+
+            let keys: Vec<Key<u8>> = probes.iter().map(|&(p, k)| key(p, k)).collect();
+            filtered.get_many(&keys).unwrap() == plain.get_many(&keys).unwrap() &&
+                keys.iter().all(|k| filtered.count(k).unwrap() == plain.count(k).unwrap())
+        }
+        quickcheck(prop as fn(Vec<(bool, u8, u8, u8)>, Vec<(u8, u8)>) -> bool);
+    }
+
+    #[test]
+    fn loads_existing_entries() {
+        let mut store = InMemoryHash::new();
+        store.insert(key(0, 1), 1u64).unwrap();
+        store.insert(key(1, 2), 2u64).unwrap();
+
+        let filtered = Bloom::new(store).unwrap();
+        assert!(filtered.get(&key(0, 1)).unwrap().unwrap().contains(&1));
+        assert!(filtered.get(&key(1, 2)).unwrap().unwrap().contains(&2));
+        assert_eq!(filtered.get(&key(2, 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn zero_counters_filter_nothing() {
+        let mut store = InMemoryHash::new();
+        store.insert(key(0, 1), 1u64).unwrap();
+
+        let mut unfiltered = Bloom::with_counters(store, 0).unwrap();
+        unfiltered.insert(key(1, 2), 2u64).unwrap();
+        assert!(unfiltered.get(&key(0, 1)).unwrap().unwrap().contains(&1));
+        assert!(unfiltered.get(&key(1, 2)).unwrap().unwrap().contains(&2));
+        assert_eq!(unfiltered.usage().memory_bytes, unfiltered.store().usage().memory_bytes);
+    }
+
+    #[test]
+    fn removed_keys_are_filtered() {
+        let mut filtered = Bloom::new(InMemoryHash::new()).unwrap();
+        filtered.insert(key(0, 1), 1u64).unwrap();
+        filtered.remove(&key(0, 1), &1).unwrap();
+
+        assert!(!filtered.may_contain(&key(0, 1)));
+    }
+
+    #[test]
+    fn snapshots_keep_removed_keys() {
+        let mut filtered = Bloom::new(ShardedInMemoryHash::new()).unwrap();
+        filtered.insert(key(0, 1), 1u64).unwrap();
+
+        let snapshot = filtered.snapshot().unwrap();
+        filtered.remove(&key(0, 1), &1).unwrap();
+
+        assert!(snapshot.get(&key(0, 1)).unwrap().unwrap().contains(&1));
+        assert_eq!(filtered.get(&key(0, 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn removes_apply_once_snapshots_drop() {
+        let mut filtered = Bloom::new(ShardedInMemoryHash::new()).unwrap();
+        filtered.insert(key(0, 1), 1u64).unwrap();
+
+        let snapshot = filtered.snapshot().unwrap();
+        let nested = snapshot.snapshot().unwrap();
+        filtered.remove(&key(0, 1), &1).unwrap();
+        assert!(filtered.may_contain(&key(0, 1)));
+
+        drop(snapshot);
+        assert!(filtered.may_contain(&key(0, 1)));
+
+        drop(nested);
+        assert!(!filtered.may_contain(&key(0, 1)));
+    }
+}
@@ -0,0 +1,445 @@
+use std::clone::Clone;
+use std::cmp::Eq;
+use std::hash::Hash;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[cfg(feature = "rocksdb")]
+use db::column_family::Batch;
+use db::hashing::State;
+use db::stats::StoreUsage;
+use error::Error;
+
+use super::MapSet;
+
+/// Default number of sets kept by `Cached::new`
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+/// Least recently used sets, keyed like the store they're read from
+///
+/// Recency is a counter bumped on each read; `order` maps each entry's last
+/// read back to its key so the oldest can be found without a scan.
+///
+/// `writes` counts the invalidations so far, and each entry records the
+/// count it was cached at, so snapshots can tell which entries predate them
+/// (see `Cached::snapshot`).
+///
+struct Lru<K, V> {
+    entries: HashMap<K, (Option<HashSet<V, State>>, u64, u64), State>,
+    order: BTreeMap<u64, K>,
+    clock: u64,
+    writes: u64,
+}
+
+impl<K, V> Lru<K, V> where
+K: Clone + Eq + Hash,
+V: Clone + Eq + Hash,
+{
+    fn new() -> Lru<K, V> {
+        Lru {entries: HashMap::default(), order: BTreeMap::new(), clock: 0, writes: 0}
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// The cached set of `key`, if it was cached by the time `as_of` writes
+    /// had been made (or at all, if `as_of` is `None`)
+    ///
+    fn get(&mut self, key: &K, as_of: Option<u64>) -> Option<Option<HashSet<V, State>>> {
+        match (self.entries.get(key), as_of) {
+            (None, _) => return None,
+            (Some(entry), Some(as_of)) if entry.2 > as_of => return None,
+            _ => (),
+        }
+
+        let now = self.tick();
+        let (set, last) = match self.entries.get_mut(key) {
+            Some(entry) => {
+                let last = entry.1;
+                entry.1 = now;
+                (entry.0.clone(), last)
+            },
+            None => return None,
+        };
+
+        self.order.remove(&last);
+        self.order.insert(now, key.clone());
+        Some(set)
+    }
+
+    /// Caches `set` as read after `as_of` writes, unless more have been made
+    /// since (in which case it may be out of date)
+    ///
+    fn put(&mut self, key: K, set: Option<HashSet<V, State>>, capacity: usize, as_of: Option<u64>) {
+        if capacity == 0 || as_of.map_or(false, |as_of| as_of != self.writes) {
+            return
+        }
+
+        if let Some((_, last, _)) = self.entries.remove(&key) {
+            self.order.remove(&last);
+        }
+        let now = self.tick();
+        self.order.insert(now, key.clone());
+        self.entries.insert(key, (set, now, self.writes));
+        self.trim(capacity);
+    }
+
+    /// Drops the least recently read entries until at most `capacity` are
+    /// left
+    ///
+    fn trim(&mut self, capacity: usize) {
+        while self.entries.len() > capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    fn invalidate(&mut self, key: &K) {
+        self.writes += 1;
+        if let Some((_, last, _)) = self.entries.remove(key) {
+            self.order.remove(&last);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.writes += 1;
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// Read-through LRU cache of the sets in another `MapSet`
+///
+/// Query traffic tends to hit a small number of variant buckets over and
+/// over; caching them saves most of the reads a persistent store would
+/// otherwise make.  Missing sets are cached too, since most variant keys
+/// probed by a query don't exist.
+///
+/// Any write to a key (including a staged one) drops it from the cache, so
+/// reads never see a set older than the last write.  Bulk inserts clear the
+/// whole cache.
+///
+/// Snapshots (see `MapSet::snapshot`) share the cache, but only read entries
+/// cached before they were taken, and only cache what they read while no
+/// write has been made since.
+///
+pub struct Cached<K, V, M> {
+    store: M,
+    capacity: usize,
+    cache: Arc<Mutex<Lru<K, V>>>,
+    // The writes made when this snapshot was taken; unset unless a snapshot
+    as_of: Option<u64>,
+}
+
+impl<K, V, M> Cached<K, V, M> where
+K: Clone + Eq + Hash,
+V: Clone + Eq + Hash,
+{
+    /// Cache up to `DEFAULT_CAPACITY` of `store`'s sets
+    ///
+    pub fn new(store: M) -> Cached<K, V, M> {
+        Cached::with_capacity(store, DEFAULT_CAPACITY)
+    }
+
+    /// Cache up to `capacity` of `store`'s sets (none if it's 0)
+    ///
+    pub fn with_capacity(store: M, capacity: usize) -> Cached<K, V, M> {
+        Cached {
+            store: store,
+            capacity: capacity,
+            cache: Arc::new(Mutex::new(Lru::new())),
+            as_of: None,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of sets currently cached
+    ///
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    /// The wrapped store
+    ///
+    pub fn store(&self) -> &M {
+        &self.store
+    }
+
+    pub fn into_store(self) -> M {
+        self.store
+    }
+
+    fn invalidate(&self, key: &K) {
+        self.cache.lock().unwrap().invalidate(key)
+    }
+}
+
+impl<K, V, M> MapSet<K, V> for Cached<K, V, M> where
+K: Sync + Send + Clone + Eq + Hash,
+V: Sync + Send + Clone + Eq + Hash,
+M: MapSet<K, V>,
+{
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error> {
+        self.invalidate(&key);
+        self.store.insert(key, value)
+    }
+
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
+        if let Some(set) = self.cache.lock().unwrap().get(key, self.as_of) {
+            return Ok(set)
+        }
+
+        // The lock isn't held while reading the store, so a slow read
+        // doesn't hold up hits on other keys
+        let set = try!(self.store.get(key));
+        self.cache.lock().unwrap().put(key.clone(), set.clone(), self.capacity, self.as_of);
+        Ok(set)
+    }
+
+    /// Keys missing from the cache are read from the store in one batch
+    ///
+    fn get_many(&self, keys: &[K]) -> Result<Vec<Option<HashSet<V, State>>>, Error> {
+        let mut results: Vec<Option<Option<HashSet<V, State>>>> = Vec::with_capacity(keys.len());
+        let mut misses: Vec<K> = Vec::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for key in keys.iter() {
+                let found = cache.get(key, self.as_of);
+                if found.is_none() {
+                    misses.push(key.clone());
+                }
+                results.push(found);
+            }
+        }
+
+        if misses.is_empty() {
+            return Ok(results.into_iter().map(|found| found.unwrap()).collect())
+        }
+
+        let read = try!(self.store.get_many(&misses));
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for (key, set) in misses.iter().zip(read.iter()) {
+                cache.put(key.clone(), set.clone(), self.capacity, self.as_of);
+            }
+        }
+
+        let mut read = read.into_iter();
+        Ok(results.into_iter().map(|found| match found {
+            Some(set) => set,
+            None => read.next().unwrap(),
+        }).collect())
+    }
+
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        Ok(try!(self.get(key)).map_or(0, |set| set.len()))
+    }
+
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error> {
+        self.invalidate(key);
+        self.store.remove(key, value)
+    }
+
+    fn remove_many(&mut self, keys: &[K], value: &V) -> Result<usize, Error> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for key in keys.iter() {
+                cache.invalidate(key);
+            }
+        }
+        self.store.remove_many(keys, value)
+    }
+
+    fn bulk_insert(&mut self, entries: Vec<(K, V)>) -> Result<(), Error> {
+        self.cache.lock().unwrap().clear();
+        self.store.bulk_insert(entries)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn join(&self, batch: &mut Batch) -> bool {
+        self.store.join(batch)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_insert(&self, batch: &Batch, key: K, value: V) -> Result<bool, Error> {
+        self.invalidate(&key);
+        self.store.stage_insert(batch, key, value)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove(&self, batch: &Batch, key: &K, value: &V) -> Result<bool, Error> {
+        self.invalidate(key);
+        self.store.stage_remove(batch, key, value)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove_many(&self, batch: &Batch, keys: &[K], value: &V) -> Result<usize, Error> {
+        for key in keys.iter() {
+            self.invalidate(key);
+        }
+        self.store.stage_remove_many(batch, keys, value)
+    }
+
+    fn sample(&self, n: usize) -> Result<Vec<(K, V)>, Error> {
+        self.store.sample(n)
+    }
+
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        self.store.scan(f)
+    }
+
+    /// The store's usage, plus a rough estimate of the cache's
+    ///
+    fn usage(&self) -> StoreUsage {
+        let mut usage = self.store.usage();
+        let cached = self.cache.lock().unwrap().entries.values().fold(0, |bytes, &(ref set, _, _)| {
+            bytes + size_of::<K>() + size_of::<Option<HashSet<V, State>>>() + set.as_ref().map_or(0, |set| set.len() * size_of::<V>())
+        });
+        usage.memory_bytes += cached as u64;
+        usage
+    }
+
+    fn bucket_sample_rate(&self) -> usize {
+        self.store.bucket_sample_rate()
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        self.store.warmup(fraction)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.store.flush()
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.store.sync()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.store.compact()
+    }
+
+    fn set_cache_capacity(&mut self, sets: usize) -> Result<(), Error> {
+        self.capacity = sets;
+        self.cache.lock().unwrap().trim(sets);
+        Ok(())
+    }
+
+    /// Shares the cache (see `Cached`)
+    ///
+    fn snapshot(&self) -> Result<Cached<K, V, M>, Error> {
+        // Entries cached before the store's snapshot is taken stay valid for
+        // it as long as they're cached
+        let as_of = self.cache.lock().unwrap().writes;
+        Ok(Cached {
+            store: try!(self.store.snapshot()),
+            capacity: self.capacity,
+            cache: self.cache.clone(),
+            as_of: Some(as_of),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
+    use db::map_set::{MapSet, InMemoryHash, ShardedInMemoryHash, Cached};
+
+    #[test]
+    fn matches_uncached_store() {
+        fn prop(ops: Vec<(bool, u8, u8)>, keys: Vec<u8>) -> bool {
+            let mut cached = Cached::with_capacity(InMemoryHash::new(), 4);
+            let mut plain = InMemoryHash::new();
+
+            for &(insert, k, v) in ops.iter() {
+                // Read first, so writes land on cached keys
+                if cached.get(&k).unwrap() != plain.get(&k).unwrap() {
+                    return false
+                }
+
+                if insert {
+                    assert_eq!(cached.insert(k, v).unwrap(), plain.insert(k, v).unwrap());
+                } else {
+                    assert_eq!(cached.remove(&k, &v).unwrap(), plain.remove(&k, &v).unwrap());
+                }
+            }
+
+            cached.get_many(&keys).unwrap() == plain.get_many(&keys).unwrap()
+        }
+        quickcheck(prop as fn(Vec<(bool, u8, u8)>, Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut db = Cached::with_capacity(InMemoryHash::new(), 2);
+        db.insert(1u64, 1u64).unwrap();
+        db.insert(2u64, 2u64).unwrap();
+        db.insert(3u64, 3u64).unwrap();
+
+        db.get(&1).unwrap();
+        db.get(&2).unwrap();
+        db.get(&1).unwrap();
+        db.get(&3).unwrap();
+        assert_eq!(db.cached(), 2);
+
+        // 2 was read least recently, so it's the one evicted
+        let cache = db.cache.lock().unwrap();
+        assert!(cache.entries.contains_key(&1));
+        assert!(!cache.entries.contains_key(&2));
+        assert!(cache.entries.contains_key(&3));
+    }
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let mut db = Cached::with_capacity(InMemoryHash::new(), 0);
+        db.insert(1u64, 1u64).unwrap();
+
+        assert!(db.get(&1).unwrap().unwrap().contains(&1));
+        assert_eq!(db.cached(), 0);
+    }
+    #[test]
+    fn capacity_can_be_changed() {
+        let mut db = Cached::with_capacity(InMemoryHash::new(), 0);
+        for i in 0..4u64 {
+            db.insert(i, i).unwrap();
+        }
+
+        db.set_cache_capacity(3).unwrap();
+        db.get_many(&[0, 1, 2, 3]).unwrap();
+        assert_eq!((db.capacity(), db.cached()), (3, 3));
+
+        db.set_cache_capacity(1).unwrap();
+        assert_eq!(db.cached(), 1);
+        assert!(db.get(&1).unwrap().unwrap().contains(&1));
+    }
+
+    #[test]
+    fn snapshots_only_read_entries_cached_before_them() {
+        let mut db = Cached::with_capacity(ShardedInMemoryHash::new(), 10);
+        db.insert(1u64, 1u64).unwrap();
+        db.get(&1).unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+        db.insert(1, 2).unwrap();
+        db.get(&1).unwrap();
+
+        assert_eq!(snapshot.get(&1).unwrap().unwrap().len(), 1);
+        assert_eq!(db.get(&1).unwrap().unwrap().len(), 2);
+    }
+
+}
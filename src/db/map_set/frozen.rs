@@ -0,0 +1,104 @@
+use std::clone::Clone;
+use std::cmp::{min, Eq};
+use std::hash::Hash;
+use std::collections::HashSet;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::encoding::{encode, decode};
+use db::frozen::Table;
+use db::stats::StoreUsage;
+use db::hashing::State;
+use error::Error;
+
+use super::MapSet;
+
+/// Frozen reads sets from the variant table of a finalized database (see
+/// `db::frozen`)
+///
+/// Writes fail with `Error::Unsupported`.
+///
+pub struct Frozen {
+    table: Table,
+}
+
+impl Frozen {
+    pub fn new(table: Table) -> Frozen {
+        Frozen{table: table}
+    }
+}
+
+fn read_only() -> Error {
+    Error::Unsupported("frozen stores are read-only".to_string())
+}
+
+impl<K, V> MapSet<K, V> for Frozen
+where   K: Clone + Eq + Hash + Serialize + DeserializeOwned,
+        V: Clone + Eq + Hash + DeserializeOwned,
+{
+    fn insert(&mut self, _key: K, _value: V) -> Result<bool, Error> {
+        Err(read_only())
+    }
+
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
+        let encoded_key = try!(encode(key));
+        let found = try!(self.table.find(&encoded_key));
+        if found.start == found.end {
+            return Ok(None)
+        }
+
+        let mut set = HashSet::with_capacity_and_hasher(found.end - found.start, Default::default());
+        for i in found {
+            let (_, value) = try!(self.table.entry(i));
+            set.insert(try!(decode(value)));
+        }
+        Ok(Some(set))
+    }
+
+    /// Counted from the table's index without decoding values
+    ///
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        let encoded_key = try!(encode(key));
+        let found = try!(self.table.find(&encoded_key));
+        Ok(found.end - found.start)
+    }
+
+    fn remove(&mut self, _key: &K, _value: &V) -> Result<bool, Error> {
+        Err(read_only())
+    }
+
+    fn bulk_insert(&mut self, _entries: Vec<(K, V)>) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn sample(&self, n: usize) -> Result<Vec<(K, V)>, Error> {
+        let mut out = Vec::with_capacity(n);
+        for i in 0..min(n, self.table.len()) {
+            let (key, value) = try!(self.table.entry(i));
+            out.push((try!(decode(key)), try!(decode(value))));
+        }
+        Ok(out)
+    }
+
+    /// The table is sorted by key, so each key's entries are adjacent
+    ///
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        for i in 0..self.table.len() {
+            let (key, value) = try!(self.table.entry(i));
+            f(&try!(decode(key)), &try!(decode(value)));
+        }
+        Ok(())
+    }
+
+    /// The table is mapped rather than read, so only counts toward disk
+    /// usage
+    ///
+    fn usage(&self) -> StoreUsage {
+        StoreUsage{memory_bytes: 0, disk_bytes: Some(self.table.byte_len())}
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        self.table.warmup(fraction)
+    }
+}
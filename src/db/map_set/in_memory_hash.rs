@@ -2,10 +2,15 @@ use std::clone::Clone;
 use std::default::Default;
 use std::cmp::Eq;
 use std::hash::Hash;
+use std::mem::size_of;
 
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Vacant, Occupied};
 
+use db::hashing::State;
+use db::stats::StoreUsage;
+use error::Error;
+
 use super::MapSet;
 
 #[derive(Debug)]
@@ -13,7 +18,7 @@ pub struct InMemoryHash<K, V>
 where   K: Sync + Send + Clone + Eq + Hash, 
         V: Sync + Send + Clone + Eq + Hash, 
 {
-    data: HashMap<K, HashSet<V>>,
+    data: HashMap<K, HashSet<V, State>, State>,
 }
 
 impl<K, V> InMemoryHash<K, V>
@@ -21,7 +26,7 @@ where   K: Sync + Send + Clone + Eq + Hash,
         V: Sync + Send + Clone + Eq + Hash, 
 {
     pub fn new() -> InMemoryHash<K, V> {
-        InMemoryHash {data: HashMap::new()}
+        InMemoryHash {data: HashMap::default()}
     }
 }
 
@@ -38,28 +43,32 @@ impl<K, V> MapSet<K, V> for InMemoryHash<K, V>
 where   K: Sync + Send + Clone + Eq + Hash, 
         V: Sync + Send + Clone + Eq + Hash, 
 {
-    fn insert(&mut self, key: K, value: V) -> bool {
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error> {
         match self.data.entry(key) {
             Vacant(entry) => {
-                let mut set: HashSet<V> = HashSet::new();
+                let mut set: HashSet<V, State> = HashSet::default();
                 set.insert(value);
                 entry.insert(set);
-                true
+                Ok(true)
             },
             Occupied(mut entry) => {
-                entry.get_mut().insert(value)
+                Ok(entry.get_mut().insert(value))
             },
         }
     }
 
-    fn get(&self, key: &K) -> Option<HashSet<V>> {
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
         match self.data.get(key) {
-            Some(h) => Some(h.clone()),
-            None => None,
+            Some(h) => Ok(Some(h.clone())),
+            None => Ok(None),
         }
     }
 
-    fn remove(&mut self, key: &K, value: &V) -> bool {
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        Ok(self.data.get(key).map_or(0, |set| set.len()))
+    }
+
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error> {
         let mut delete_key = false;
 
         let removed = match self.data.entry(key.clone()) {
@@ -78,7 +87,27 @@ where   K: Sync + Send + Clone + Eq + Hash,
             self.data.remove(key);
         };
 
-        removed
+        Ok(removed)
+    }
+
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        for (key, set) in self.data.iter() {
+            for value in set.iter() {
+                f(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the inline size of each key, set and value - memory owned by
+    /// them (and the tables' spare capacity) isn't included
+    ///
+    fn usage(&self) -> StoreUsage {
+        let memory_bytes = self.data.values().fold(0, |bytes, set| {
+            bytes + size_of::<K>() + size_of::<HashSet<V, State>>() + set.len() * size_of::<V>()
+        });
+
+        StoreUsage{memory_bytes: memory_bytes as u64, disk_bytes: None}
     }
 }
 
@@ -94,9 +123,9 @@ mod test {
     fn inserted_exists() {
         fn prop(k: u64, v: u64) -> quickcheck::TestResult {
             let mut db = InMemoryHash::new();
-            db.insert(k.clone(), v.clone());
+            db.insert(k.clone(), v.clone()).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(results.contains(&v)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -112,9 +141,9 @@ mod test {
             }
 
             let mut db = InMemoryHash::new();
-            db.insert(k.clone(), v1.clone());
+            db.insert(k.clone(), v1.clone()).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(!results.contains(&v2)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -130,9 +159,9 @@ mod test {
             }
 
             let mut db = InMemoryHash::new();
-            db.insert(k1.clone(), v.clone());
+            db.insert(k1.clone(), v.clone()).unwrap();
 
-            match db.get(&k2) {
+            match db.get(&k2).unwrap() {
                 Some(_) => quickcheck::TestResult::failed(),
                 None => quickcheck::TestResult::passed(),
             }
@@ -148,11 +177,11 @@ mod test {
             }
 
             let mut db = InMemoryHash::new();
-            db.insert(k.clone(), v1.clone());
-            db.insert(k.clone(), v2.clone());
-            db.remove(&k, &v1);
+            db.insert(k.clone(), v1.clone()).unwrap();
+            db.insert(k.clone(), v2.clone()).unwrap();
+            db.remove(&k, &v1).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(!results.contains(&v1)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -168,11 +197,11 @@ mod test {
             }
 
             let mut db = InMemoryHash::new();
-            db.insert(k.clone(), v1.clone());
-            db.insert(k.clone(), v2.clone());
-            db.remove(&k, &v1);
+            db.insert(k.clone(), v1.clone()).unwrap();
+            db.insert(k.clone(), v2.clone()).unwrap();
+            db.remove(&k, &v1).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(results.contains(&v2)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -188,11 +217,11 @@ mod test {
             }
 
             let mut db = InMemoryHash::new();
-            db.insert(k1.clone(), v1.clone());
-            db.insert(k2.clone(), v2.clone());
-            db.remove(&k1, &v1);
+            db.insert(k1.clone(), v1.clone()).unwrap();
+            db.insert(k2.clone(), v2.clone()).unwrap();
+            db.remove(&k1, &v1).unwrap();
 
-            match db.get(&k1) {
+            match db.get(&k1).unwrap() {
                 Some(_) => quickcheck::TestResult::failed(),
                 None => quickcheck::TestResult::passed(),
             }
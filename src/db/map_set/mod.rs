@@ -5,45 +5,289 @@
 //! these two, providing insertion & removal methods with the same semantics as
 //! `HashSet` but keyed like `HashMap`.  
 //!
+//! Operations which touch storage return `Result`, failing with
+//! `Error::Storage` if the store can't be read or written and
+//! `Error::Corrupt` if a stored entry can't be decoded.
+//!
 //! # Examples
 //!
 //! ```ignore
 //! let mut sets: InMemoryHash<&'static str, &'static str> = InMemoryHash::new();
 //!
 //! // Returns true if "value" has not been inserted into the set at "key"
-//! assert_eq!(sets.insert("key", "value"), true);
+//! assert_eq!(sets.insert("key", "value"), Ok(true));
 //!
 //! // ...and false if it's already in the set
-//! assert_eq!(sets.insert("key", "value"), false);
+//! assert_eq!(sets.insert("key", "value"), Ok(false));
 //!
 //! // Returns true if "value" exists in the set at "key"
-//! assert_eq!(sets.remove("key", "value"), true);
+//! assert_eq!(sets.remove("key", "value"), Ok(true));
 //!
 //! // ...and false otherwise
-//! assert_eq!(sets.remove("key", "value"), false);
+//! assert_eq!(sets.remove("key", "value"), Ok(false));
 //!
-//! sets.insert("key", "value");
-//! assert_eq!(sets.get("key").contains("value"), true);
+//! sets.insert("key", "value").unwrap();
+//! assert_eq!(sets.get("key").unwrap().unwrap().contains("value"), true);
 //! ```
 use std::clone::Clone;
 use std::cmp::Eq;
 use std::hash::Hash;
 use std::collections::HashSet;
 
+#[cfg(feature = "rocksdb")]
+use serde::Serialize;
+#[cfg(feature = "rocksdb")]
+use serde::de::DeserializeOwned;
+
+#[cfg(feature = "rocksdb")]
+use db::RocksDBConfig;
+#[cfg(feature = "rocksdb")]
+use db::column_family::Batch;
+use db::stats::StoreUsage;
+use db::hashing::State;
+use error::Error;
+
+mod bloom;
+mod cached;
+#[cfg(not(target_arch = "wasm32"))]
+mod frozen;
 mod in_memory_hash;
+mod sharded_in_memory_hash;
+#[cfg(feature = "rocksdb")]
 mod rocks_db;
+#[cfg(feature = "sled")]
+mod sled_db;
 
+pub use self::bloom::{Bloom, Partitioned};
+pub use self::cached::Cached;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::frozen::Frozen;
 pub use self::in_memory_hash::InMemoryHash;
+pub use self::sharded_in_memory_hash::ShardedInMemoryHash;
+#[cfg(feature = "rocksdb")]
 pub use self::rocks_db::{RocksDB, TempRocksDB};
+#[cfg(feature = "sled")]
+pub use self::sled_db::Sled;
+
+/// The variant store of DBs built over RocksDB by `Factory`, layered as
+/// `layered` describes
+#[cfg(feature = "rocksdb")]
+pub type LayeredRocksDB<K, V> = Cached<K, V, Bloom<K, V, RocksDB<K, V>>>;
+
+/// Puts a bloom filter of `config.variant_filter_counters` counters per
+/// partition in front of `store`, and a cache of `config.variant_cache_sets`
+/// sets in front of that (leaving out either if it's unset)
+///
+/// Cached sets skip the filter, and reads the filter rules out skip RocksDB.
+///
+#[cfg(feature = "rocksdb")]
+pub fn layered<K, V>(store: RocksDB<K, V>, config: &RocksDBConfig) -> Result<LayeredRocksDB<K, V>, Error> where
+K: Sync + Send + Clone + Eq + Hash + Partitioned + Serialize + DeserializeOwned,
+V: Sync + Send + Clone + Eq + Hash + Serialize + DeserializeOwned,
+{
+    let filtered = try!(Bloom::with_counters(store, config.variant_filter_counters.unwrap_or(0)));
+    Ok(Cached::with_capacity(filtered, config.variant_cache_sets.unwrap_or(0)))
+}
 
 pub trait MapSet<K, V>: Sync + Send where 
 K: Clone + Eq + Hash,
 V: Clone + Eq + Hash,
 {
 
-    fn insert(&mut self, key: K, value: V) -> bool;
-    fn get(&self, key: &K) -> Option<HashSet<V>>;
-    fn remove(&mut self, key: &K, value: &V) -> bool;
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error>;
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error>;
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error>;
+
+    /// Sets at each of `keys`, in the same order
+    ///
+    /// Stores with a per-lookup overhead should override this to fetch all
+    /// the keys at once.
+    ///
+    fn get_many(&self, keys: &[K]) -> Result<Vec<Option<HashSet<V, State>>>, Error> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Number of values in the set at `key`
+    ///
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        Ok(try!(self.get(key)).map_or(0, |set| set.len()))
+    }
+
+    /// Remove `value` from the set at each of `keys`
+    ///
+    /// Returns the number of sets `value` was removed from.  Stores which can
+    /// batch deletes should override this.
+    ///
+    fn remove_many(&mut self, keys: &[K], value: &V) -> Result<usize, Error> {
+        let mut removed = 0;
+        for key in keys {
+            if try!(self.remove(key, value)) {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Insert every entry, without reporting which were already present
+    ///
+    /// Used for bulk loads.  Stores whose writes are cheaper in key order, or
+    /// which can skip checking for existing entries, should override this.
+    ///
+    fn bulk_insert(&mut self, entries: Vec<(K, V)>) -> Result<(), Error> {
+        for (key, value) in entries.into_iter() {
+            try!(self.insert(key, value));
+        }
+        Ok(())
+    }
+
+    /// Bind `batch` to this store's RocksDB, returning false if the store
+    /// can't stage writes in it (see `db::atomic`)
+    ///
+    /// Stores which don't keep their entries in RocksDB needn't override
+    /// this; their writes are rolled back instead.
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn join(&self, _batch: &mut Batch) -> bool {
+        false
+    }
+
+    /// Like `insert`, but adds the write to `batch` rather than making it
+    ///
+    /// Only called after the store has joined `batch`.
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn stage_insert(&self, _batch: &Batch, _key: K, _value: V) -> Result<bool, Error> {
+        Err(Error::Unsupported("store doesn't support batched writes".to_string()))
+    }
+
+    /// Like `remove`, but adds the delete to `batch` rather than making it
+    ///
+    /// Only called after the store has joined `batch`.
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove(&self, _batch: &Batch, _key: &K, _value: &V) -> Result<bool, Error> {
+        Err(Error::Unsupported("store doesn't support batched writes".to_string()))
+    }
+
+    /// Like `remove_many`, but adds the deletes to `batch` rather than making
+    /// them
+    ///
+    /// Returns the number of sets `value` will be removed from.  Stores which
+    /// can look up several entries at once should override this.
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn stage_remove_many(&self, batch: &Batch, keys: &[K], value: &V) -> Result<usize, Error> {
+        let mut removed = 0;
+        for key in keys {
+            if try!(self.stage_remove(batch, key, value)) {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Up to `n` entries, for health checks
+    ///
+    /// Returns an error describing the first entry which can't be read.
+    /// Stores which can't be corrupted on disk needn't override this.
+    ///
+    fn sample(&self, _n: usize) -> Result<Vec<(K, V)>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Visit every entry, for stats
+    ///
+    /// Entries sharing a key must be visited consecutively.  Stores which
+    /// can't enumerate their entries needn't override this.
+    ///
+    fn scan(&self, _f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        Err(Error::Unsupported("store doesn't support scans".to_string()))
+    }
+
+    /// Space used by the store, for stats
+    ///
+    fn usage(&self) -> StoreUsage {
+        Default::default()
+    }
+
+    /// One in this many buckets' sizes is kept by a stats walk
+    ///
+    /// Stores holding every bucket in memory needn't override this; their
+    /// bucket size percentiles are exact.  Persistent stores should return
+    /// `stats::BUCKET_SAMPLE_RATE`.
+    ///
+    fn bucket_sample_rate(&self) -> usize {
+        1
+    }
+
+    /// Read about `fraction` (between 0 and 1) of the store's entries, so
+    /// the first queries after opening it don't wait on disk
+    ///
+    /// Returns the number of entries read.  In-memory stores needn't
+    /// override this.
+    ///
+    fn warmup(&self, _fraction: f64) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    /// Hand any buffered writes to the underlying storage
+    ///
+    /// Stores which write through needn't override this.
+    ///
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Flush, then make sure every write so far will survive a crash
+    ///
+    /// In-memory stores needn't override this.
+    ///
+    fn sync(&self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    /// Reclaim the space held by removed entries (see `db::compaction`)
+    ///
+    /// Stores which free removed entries immediately needn't override this.
+    ///
+    fn compact(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Keep up to `sets` of the store's sets cached in memory (none if it's
+    /// 0), dropping the least recently read if there are already more
+    ///
+    /// Fails with `Error::Unsupported` unless the store is (or wraps) a
+    /// `Cached`.
+    ///
+    fn set_cache_capacity(&mut self, _sets: usize) -> Result<(), Error> {
+        Err(Error::Unsupported("caching sets".to_string()))
+    }
+
+    /// Returns true if the store can be written through a shared reference
+    /// (see `insert_shared`)
+    ///
+    fn shares_writes(&self) -> bool {
+        false
+    }
+
+    /// Like `insert`, through a shared reference, so concurrent writers
+    /// needn't hold the store exclusively
+    ///
+    /// Fails with `Error::Unsupported` unless `shares_writes`.
+    ///
+    fn insert_shared(&self, _key: K, _value: V) -> Result<bool, Error> {
+        Err(Error::Unsupported("store doesn't support shared writes".to_string()))
+    }
+
+    /// A read-only copy of the store as it is now, which later writes to the
+    /// store don't change
+    ///
+    /// Fails with `Error::Unsupported` unless the store can take one.
+    ///
+    fn snapshot(&self) -> Result<Self, Error> where Self: Sized {
+        Err(Error::Unsupported("store doesn't support snapshots".to_string()))
+    }
 }
 
 /*
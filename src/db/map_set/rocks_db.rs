@@ -1,71 +1,175 @@
-use std::env;
-use std::fs;
 use std::clone::Clone;
 use std::cmp::Eq;
 use std::hash::Hash;
-use std::ops::Drop;
-use std::path::PathBuf;
+use std::path::Path;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 use std::collections::HashSet;
 
-use rocksdb::{DB, Writable, Options, Direction, IteratorMode};
-use rustc_serialize::{Encodable, Decodable};
-use bincode::SizeLimit;
-use bincode::rustc_serialize::{encode, decode};
-use uuid::Uuid;
+use rocksdb::{WriteBatch, Options, Direction, IteratorMode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::encoding::{encode, decode};
+use db::column_family::{Batch, SharedRocksDB, Store, View};
+use db::compaction::StoreOptions;
+use db::params::PARAMS_KEY;
+use db::stats::{StoreUsage, BUCKET_SAMPLE_RATE};
+use db::temp_dir::TempDir;
+use db::hashing::State;
+use error::Error;
 
 use super::MapSet;
 
+// Entries written per `WriteBatch` by `bulk_insert`
+const BULK_BATCH_SIZE: usize = 100000;
+
+/// The encoded entries `(key, value)` of `keys` which exist, in key order
+///
+/// The keys are sorted and looked up by seeking one iterator forward through
+/// them, rather than with a `get` each, so neighbouring keys (ie a value's
+/// variants within a partition) are read from the same blocks.
+///
+fn existing_entries<K, V>(view: &View, keys: &[K], value: &V) -> Result<Vec<Vec<u8>>, Error> where
+K: Serialize,
+V: Serialize,
+{
+    let mut encoded_keys: Vec<Vec<u8>> = Vec::with_capacity(keys.len());
+    for key in keys {
+        encoded_keys.push(try!(encode(&(key, value))));
+    }
+    encoded_keys.sort();
+    encoded_keys.dedup();
+
+    let mut iter = try!(view.iterator(IteratorMode::Start));
+    let mut existing = Vec::new();
+    for encoded_key in encoded_keys.into_iter() {
+        iter.set_mode(IteratorMode::From(&encoded_key, Direction::forward));
+        let found = match (&mut iter).next() {
+            Some((ref k, _)) => &k[..] == &encoded_key[..],
+            None => false,
+        };
+        if found {
+            existing.push(encoded_key);
+        }
+    }
+    Ok(existing)
+}
+
 pub struct TempRocksDB<K, V> {
-    dir: PathBuf,
+    // Must be dropped before `dir` so RocksDB is closed before its files are
+    // removed.  Snapshots share `dir`, so it's removed with the last of them.
     db: RocksDB<K, V>,
+    dir: Arc<TempDir>,
 }
 
 impl<K, V> TempRocksDB<K, V> {
-    pub fn with_opts(opts: Options) -> TempRocksDB<K, V> {
-        let mut dir = env::temp_dir(); 
-        dir.push(&Uuid::new_v4().to_hyphenated_string());
+    pub fn with_opts(opts: Options) -> Result<TempRocksDB<K, V>, Error> {
+        let dir = TempDir::new();
 
-        TempRocksDB{
-            dir: dir.clone(),
-            db: RocksDB::with_opts(dir.to_str().unwrap(), opts),
-        }
+        Ok(TempRocksDB{
+            db: try!(RocksDB::with_opts(dir.to_str(), opts)),
+            dir: Arc::new(dir),
+        })
     }
 
-    pub fn new() -> TempRocksDB<K, V> {
-        let mut dir = env::temp_dir(); 
-        dir.push(&Uuid::new_v4().to_hyphenated_string());
+    pub fn new() -> Result<TempRocksDB<K, V>, Error> {
+        let dir = TempDir::new();
 
-        TempRocksDB{
-            dir: dir.clone(),
-            db: RocksDB::new(dir.to_str().unwrap()),
-        }
+        Ok(TempRocksDB{
+            db: try!(RocksDB::new(dir.to_str())),
+            dir: Arc::new(dir),
+        })
     }
-}
 
-impl<K, V> Drop for TempRocksDB<K, V> {
-    fn drop(&mut self) {
-        // Nothing we can do about it here, so ¯\_(ツ)_/¯
-        let _ = fs::remove_dir_all(self.dir.to_str().unwrap());
+    pub fn path(&self) -> &Path {
+        self.dir.path()
     }
 }
 
 impl<K, V> MapSet<K, V> for TempRocksDB<K, V>
-where   K: Sync + Send + Clone + Eq + Hash + Encodable + Decodable,
-V: Sync + Send + Clone + Eq + Hash + Encodable + Decodable,
+where   K: Sync + Send + Clone + Eq + Hash + Serialize + DeserializeOwned,
+V: Sync + Send + Clone + Eq + Hash + Serialize + DeserializeOwned,
 {
-    fn insert(&mut self, key: K, value: V) -> bool {
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error> {
         self.db.insert(key, value)
     }
 
-    fn get(&self, key: &K) -> Option<HashSet<V>> {
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
         self.db.get(key)
     }
 
-    fn remove(&mut self, key: &K, value: &V) -> bool {
+    fn get_many(&self, keys: &[K]) -> Result<Vec<Option<HashSet<V, State>>>, Error> {
+        self.db.get_many(keys)
+    }
+
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        self.db.count(key)
+    }
+
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error> {
         self.db.remove(key, value)
     }
+
+    fn remove_many(&mut self, keys: &[K], value: &V) -> Result<usize, Error> {
+        self.db.remove_many(keys, value)
+    }
+
+    fn bulk_insert(&mut self, entries: Vec<(K, V)>) -> Result<(), Error> {
+        self.db.bulk_insert(entries)
+    }
+
+    fn join(&self, batch: &mut Batch) -> bool {
+        self.db.join(batch)
+    }
+
+    fn stage_insert(&self, batch: &Batch, key: K, value: V) -> Result<bool, Error> {
+        self.db.stage_insert(batch, key, value)
+    }
+
+    fn stage_remove(&self, batch: &Batch, key: &K, value: &V) -> Result<bool, Error> {
+        self.db.stage_remove(batch, key, value)
+    }
+
+    fn stage_remove_many(&self, batch: &Batch, keys: &[K], value: &V) -> Result<usize, Error> {
+        self.db.stage_remove_many(batch, keys, value)
+    }
+
+    fn sample(&self, n: usize) -> Result<Vec<(K, V)>, Error> {
+        self.db.sample(n)
+    }
+
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        self.db.scan(f)
+    }
+
+    fn usage(&self) -> StoreUsage {
+        self.db.usage()
+    }
+
+    fn bucket_sample_rate(&self) -> usize {
+        self.db.bucket_sample_rate()
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        self.db.warmup(fraction)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.db.sync()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.db.compact()
+    }
+
+    fn snapshot(&self) -> Result<TempRocksDB<K, V>, Error> {
+        Ok(TempRocksDB {
+            db: try!(self.db.snapshot()),
+            dir: self.dir.clone(),
+        })
+    }
 }
 
 /// RocksDB uses RocksDB to store a mapping from keys to sets of values
@@ -74,87 +178,306 @@ V: Sync + Send + Clone + Eq + Hash + Encodable + Decodable,
 /// retrieved by scanning RocksDB keys whose prefix match the given key and 
 /// reconstructing the value from the "end" of the RocksDB key
 ///
+/// Entries are kept in a RocksDB of their own, or in a column family of a
+/// `SharedRocksDB` (see `db::column_family`)
+///
 pub struct RocksDB<K, V> {
     key: PhantomData<K>,
     value: PhantomData<V>,
-    db: DB,
+    store: Store,
 }
 
 impl<K, V> RocksDB<K, V> {
-    pub fn with_opts(path: &str, opts: Options) -> RocksDB<K, V> {
-        let db = DB::open(&opts, path).unwrap();
-
-        RocksDB{
+    pub fn with_opts(path: &str, opts: Options) -> Result<RocksDB<K, V>, Error> {
+        Ok(RocksDB{
             key: PhantomData,
             value: PhantomData,
-            db: db,
-        }
+            store: try!(Store::dedicated(Path::new(path), StoreOptions::new(opts))),
+        })
     }
 
-    pub fn new(path: &str) -> RocksDB<K, V> {
-        let db = DB::open_default(path).unwrap();
+    pub fn new(path: &str) -> Result<RocksDB<K, V>, Error> {
+        Ok(RocksDB{
+            key: PhantomData,
+            value: PhantomData,
+            store: try!(Store::dedicated(Path::new(path), StoreOptions::default())),
+        })
+    }
 
-        RocksDB{
+    /// The store `name` in `shared`
+    ///
+    pub fn in_family(shared: Arc<SharedRocksDB>, name: &str) -> Result<RocksDB<K, V>, Error> {
+        Ok(RocksDB{
             key: PhantomData,
             value: PhantomData,
-            db: db,
-        }
+            store: try!(Store::in_family(shared, name)),
+        })
     }
 }
 
 
 impl<K, V> MapSet<K, V> for RocksDB<K, V>
-where   K: Sync + Send + Clone + Eq + Hash + Encodable + Decodable,
-V: Sync + Send + Clone + Eq + Hash + Encodable + Decodable,
+where   K: Sync + Send + Clone + Eq + Hash + Serialize + DeserializeOwned,
+V: Sync + Send + Clone + Eq + Hash + Serialize + DeserializeOwned,
 {
-    fn insert(&mut self, key: K, value: V) -> bool {
-        let encoded_key: Vec<u8> = encode(&(key.clone(), value.clone()), SizeLimit::Infinite).unwrap();
-
-        match self.db.get(&encoded_key) {
-            Ok(Some(_)) => {
-                return false
-            },
-            Err(e) => {
-                panic!(e)
-            },
-            Ok(None) => {
-                self.db.put(&encoded_key, &[]).unwrap();
-                true
-            },
-        }
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error> {
+        let encoded_key: Vec<u8> = try!(encode(&(key.clone(), value.clone())));
+
+        self.store.with(|view| -> Result<bool, Error> {
+            match try!(view.get(&encoded_key)) {
+                Some(_) => Ok(false),
+                None => {
+                    try!(view.put(&encoded_key, &[]));
+                    Ok(true)
+                },
+            }
+        })
     }
 
-    fn get(&self, key: &K) -> Option<HashSet<V>> {
-        let mut out = HashSet::new();
-        let encoded_key_prefix: Vec<u8> = encode(&key, SizeLimit::Infinite).unwrap();
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
+        let mut out = HashSet::default();
+        let encoded_key_prefix: Vec<u8> = try!(encode(&key));
 
-        for (k, _) in self.db.iterator(IteratorMode::From(&encoded_key_prefix, Direction::forward)) {
-            let (decoded_key, decoded_value): (K, V) = decode(&k).unwrap();
+        try!(self.store.with(|view| -> Result<(), Error> {
+            for (k, _) in try!(view.iterator(IteratorMode::From(&encoded_key_prefix, Direction::forward))) {
+                let (decoded_key, decoded_value): (K, V) = try!(decode(&k));
 
-            if *key != decoded_key {
-                break
+                if *key != decoded_key {
+                    break
+                }
+                out.insert(decoded_value);
             }
-            out.insert(decoded_value);
-        }
+            Ok(())
+        }));
 
         if out.is_empty() {
-            None
+            Ok(None)
         } else {
-            Some(out)
+            Ok(Some(out))
         }
     }
 
-    fn remove(&mut self, key: &K, value: &V) -> bool {
-        let encoded_key: Vec<u8> = encode(&(key, value), SizeLimit::Infinite).unwrap();
+    /// Scans every key's prefix with a single iterator, seeking to each prefix
+    /// in key order so the scan only moves forward through the SST files
+    ///
+    fn get_many(&self, keys: &[K]) -> Result<Vec<Option<HashSet<V, State>>>, Error> {
+        let mut encoded_key_prefixes: Vec<Vec<u8>> = Vec::with_capacity(keys.len());
+        for key in keys {
+            encoded_key_prefixes.push(try!(encode(key)));
+        }
+
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| encoded_key_prefixes[a].cmp(&encoded_key_prefixes[b]));
+
+        let mut out: Vec<Option<HashSet<V, State>>> = vec![None; keys.len()];
+        try!(self.store.with(|view| -> Result<(), Error> {
+            let mut iter = try!(view.iterator(IteratorMode::Start));
+
+            for i in order {
+                let mut found = HashSet::default();
+                iter.set_mode(IteratorMode::From(&encoded_key_prefixes[i], Direction::forward));
+
+                for (k, _) in &mut iter {
+                    let (decoded_key, decoded_value): (K, V) = try!(decode(&k));
+
+                    if keys[i] != decoded_key {
+                        break
+                    }
+                    found.insert(decoded_value);
+                }
+
+                if !found.is_empty() {
+                    out[i] = Some(found);
+                }
+            }
+            Ok(())
+        }));
+
+        Ok(out)
+    }
+
+    /// Counts matching RocksDB keys without decoding their values
+    ///
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        let encoded_key_prefix: Vec<u8> = try!(encode(&key));
+
+        self.store.with(|view| -> Result<usize, Error> {
+            Ok(try!(view.iterator(IteratorMode::From(&encoded_key_prefix, Direction::forward)))
+                .take_while(|&(ref k, _)| k.starts_with(&encoded_key_prefix))
+                .count())
+        })
+    }
+
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error> {
+        let encoded_key: Vec<u8> = try!(encode(&(key, value)));
+
+        self.store.with(|view| -> Result<bool, Error> {
+            match try!(view.get(&encoded_key)) {
+                None => Ok(false),
+                Some(_) => {
+                    try!(view.delete(&encoded_key));
+                    Ok(true)
+                }
+            }
+        })
+    }
+
+    /// Existing entries are found in one pass over the sorted keys (see
+    /// `existing_entries`) and deleted in a single `WriteBatch`
+    ///
+    fn remove_many(&mut self, keys: &[K], value: &V) -> Result<usize, Error> {
+        self.store.with(|view| -> Result<usize, Error> {
+            let existing = try!(existing_entries(&view, keys, value));
+            if existing.is_empty() {
+                return Ok(0)
+            }
 
-        match self.db.get(&encoded_key) {
-            Err(e) => panic!(e),
-            Ok(None) => return false,
-            Ok(Some(_)) => {
-                self.db.delete(&encoded_key).unwrap();
-                true
+            let batch = WriteBatch::new();
+            for encoded_key in existing.iter() {
+                try!(view.batch_delete(&batch, encoded_key));
             }
+            try!(view.write(batch));
+            Ok(existing.len())
+        })
+    }
+
+    /// Entries are encoded and sorted up front, then written in key order
+    /// through `WriteBatch`es.  Re-writing an existing entry is harmless, so
+    /// unlike `insert` nothing is read first.
+    ///
+    fn bulk_insert(&mut self, entries: Vec<(K, V)>) -> Result<(), Error> {
+        let mut encoded_keys: Vec<Vec<u8>> = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            encoded_keys.push(try!(encode(entry)));
         }
+        encoded_keys.sort();
+        encoded_keys.dedup();
+
+        self.store.with(|view| -> Result<(), Error> {
+            for chunk in encoded_keys.chunks(BULK_BATCH_SIZE) {
+                let batch = WriteBatch::new();
+                for encoded_key in chunk {
+                    try!(view.batch_put(&batch, encoded_key, &[]));
+                }
+                try!(view.write(batch));
+            }
+            Ok(())
+        })
+    }
+
+    fn join(&self, batch: &mut Batch) -> bool {
+        self.store.join(batch)
+    }
+
+    /// Existing entries are read from RocksDB, so entries staged earlier in
+    /// the same batch are reported as new
+    ///
+    fn stage_insert(&self, batch: &Batch, key: K, value: V) -> Result<bool, Error> {
+        let encoded_key: Vec<u8> = try!(encode(&(key, value)));
+
+        self.store.with(|view| -> Result<bool, Error> {
+            match try!(view.get(&encoded_key)) {
+                Some(_) => Ok(false),
+                None => {
+                    try!(view.batch_put(batch.writes(), &encoded_key, &[]));
+                    Ok(true)
+                },
+            }
+        })
+    }
+
+    fn stage_remove(&self, batch: &Batch, key: &K, value: &V) -> Result<bool, Error> {
+        let encoded_key: Vec<u8> = try!(encode(&(key, value)));
+
+        self.store.with(|view| -> Result<bool, Error> {
+            match try!(view.get(&encoded_key)) {
+                None => Ok(false),
+                Some(_) => {
+                    try!(view.batch_delete(batch.writes(), &encoded_key));
+                    Ok(true)
+                }
+            }
+        })
+    }
+
+    fn stage_remove_many(&self, batch: &Batch, keys: &[K], value: &V) -> Result<usize, Error> {
+        self.store.with(|view| -> Result<usize, Error> {
+            let existing = try!(existing_entries(&view, keys, value));
+            for encoded_key in existing.iter() {
+                try!(view.batch_delete(batch.writes(), encoded_key));
+            }
+            Ok(existing.len())
+        })
+    }
+
+    /// Decodes the first `n` RocksDB keys, skipping the DB's parameters (see
+    /// `db::params`)
+    ///
+    fn sample(&self, n: usize) -> Result<Vec<(K, V)>, Error> {
+        let mut out = Vec::with_capacity(n);
+
+        try!(self.store.with(|view| -> Result<(), Error> {
+            for (k, _) in try!(view.iterator(IteratorMode::Start)).filter(|&(ref k, _)| &**k != PARAMS_KEY).take(n) {
+                match decode(&k) {
+                    Ok(entry) => out.push(entry),
+                    Err(e) => return Err(Error::Corrupt(format!("undecodable variant key {:?}: {:?}", k, e))),
+                }
+            }
+            Ok(())
+        }));
+
+        Ok(out)
+    }
+
+    /// RocksDB keys are ordered by their encoded key, so each key's entries
+    /// are adjacent
+    ///
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        self.store.with(|view| -> Result<(), Error> {
+            for (k, _) in try!(view.iterator(IteratorMode::Start)).filter(|&(ref k, _)| &**k != PARAMS_KEY) {
+                match decode::<(K, V)>(&k) {
+                    Ok((key, value)) => f(&key, &value),
+                    Err(e) => return Err(Error::Corrupt(format!("undecodable variant key {:?}: {:?}", k, e))),
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// The rocksdb bindings don't expose RocksDB's memory use (memtables &
+    /// block cache), so only disk usage is reported
+    ///
+    fn usage(&self) -> StoreUsage {
+        StoreUsage{memory_bytes: 0, disk_bytes: self.store.disk_bytes()}
+    }
+
+    fn bucket_sample_rate(&self) -> usize {
+        BUCKET_SAMPLE_RATE
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<usize, Error> {
+        Ok(try!(self.store.warmup(fraction)))
+    }
+
+    /// Writes go straight to RocksDB, so there's nothing to flush
+    ///
+    fn sync(&self) -> Result<(), Error> {
+        Ok(try!(self.store.sync()))
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        Ok(try!(self.store.compact()))
+    }
+
+    /// Reads a RocksDB snapshot, which holds the DB open until it's dropped
+    ///
+    fn snapshot(&self) -> Result<RocksDB<K, V>, Error> {
+        Ok(RocksDB {
+            key: PhantomData,
+            value: PhantomData,
+            store: try!(self.store.snapshot()),
+        })
     }
 }
 
@@ -165,15 +488,18 @@ mod test {
 
     use self::quickcheck::quickcheck;
 
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
     use db::map_set::{MapSet, TempRocksDB};
 
     #[test]
     fn inserted_exists() {
         fn prop(k: u64, v: u64) -> quickcheck::TestResult {
-            let mut db = TempRocksDB::new();
-            db.insert(k.clone(), v.clone());
+            let mut db = TempRocksDB::new().unwrap();
+            db.insert(k.clone(), v.clone()).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(results.contains(&v)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -188,10 +514,10 @@ mod test {
                 return quickcheck::TestResult::discard()
             }
 
-            let mut db = TempRocksDB::new();
-            db.insert(k.clone(), v1.clone());
+            let mut db = TempRocksDB::new().unwrap();
+            db.insert(k.clone(), v1.clone()).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(!results.contains(&v2)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -206,10 +532,10 @@ mod test {
                 return quickcheck::TestResult::discard()
             }
 
-            let mut db = TempRocksDB::new();
-            db.insert(k1.clone(), v.clone());
+            let mut db = TempRocksDB::new().unwrap();
+            db.insert(k1.clone(), v.clone()).unwrap();
 
-            match db.get(&k2) {
+            match db.get(&k2).unwrap() {
                 Some(_) => quickcheck::TestResult::failed(),
                 None => quickcheck::TestResult::passed(),
             }
@@ -224,12 +550,12 @@ mod test {
                 return quickcheck::TestResult::discard()
             }
 
-            let mut db = TempRocksDB::new();
-            db.insert(k.clone(), v1.clone());
-            db.insert(k.clone(), v2.clone());
-            db.remove(&k, &v1);
+            let mut db = TempRocksDB::new().unwrap();
+            db.insert(k.clone(), v1.clone()).unwrap();
+            db.insert(k.clone(), v2.clone()).unwrap();
+            db.remove(&k, &v1).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(!results.contains(&v1)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -244,12 +570,12 @@ mod test {
                 return quickcheck::TestResult::discard()
             }
 
-            let mut db = TempRocksDB::new();
-            db.insert(k.clone(), v1.clone());
-            db.insert(k.clone(), v2.clone());
-            db.remove(&k, &v1);
+            let mut db = TempRocksDB::new().unwrap();
+            db.insert(k.clone(), v1.clone()).unwrap();
+            db.insert(k.clone(), v2.clone()).unwrap();
+            db.remove(&k, &v1).unwrap();
 
-            match db.get(&k) {
+            match db.get(&k).unwrap() {
                 Some(results) => quickcheck::TestResult::from_bool(results.contains(&v2)),
                 None => quickcheck::TestResult::failed(),
             }
@@ -264,16 +590,126 @@ mod test {
                 return quickcheck::TestResult::discard()
             }
 
-            let mut db = TempRocksDB::new();
-            db.insert(k1.clone(), v1.clone());
-            db.insert(k2.clone(), v2.clone());
-            db.remove(&k1, &v1);
+            let mut db = TempRocksDB::new().unwrap();
+            db.insert(k1.clone(), v1.clone()).unwrap();
+            db.insert(k2.clone(), v2.clone()).unwrap();
+            db.remove(&k1, &v1).unwrap();
 
-            match db.get(&k1) {
+            match db.get(&k1).unwrap() {
                 Some(_) => quickcheck::TestResult::failed(),
                 None => quickcheck::TestResult::passed(),
             }
         }
         quickcheck(prop as fn(u64, u64, u64, u64) -> quickcheck::TestResult);
     }
+
+    #[test]
+    fn remove_many_deletes_from_each_key() {
+        fn prop(k1: u64, k2: u64, k3: u64, v1: u64, v2: u64) -> quickcheck::TestResult {
+            if k1 == k2 || k1 == k3 || k2 == k3 || v1 == v2 {
+                return quickcheck::TestResult::discard()
+            }
+
+            let mut db = TempRocksDB::new().unwrap();
+            db.insert(k1.clone(), v1.clone()).unwrap();
+            db.insert(k2.clone(), v1.clone()).unwrap();
+            db.insert(k2.clone(), v2.clone()).unwrap();
+
+            let removed = db.remove_many(&[k1, k2, k3], &v1).unwrap();
+
+            quickcheck::TestResult::from_bool(
+                removed == 2 &&
+                db.get(&k1).unwrap().is_none() &&
+                db.get(&k2) == Ok(Some(vec![v2].into_iter().collect())) &&
+                db.remove_many(&[k1, k2, k3], &v1) == Ok(0)
+            )
+        }
+        quickcheck(prop as fn(u64, u64, u64, u64, u64) -> quickcheck::TestResult);
+    }
+
+    #[test]
+    fn get_many_matches_get() {
+        fn prop(keys: Vec<u64>, v1: u64, v2: u64) -> quickcheck::TestResult {
+            let mut db = TempRocksDB::new().unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                db.insert(key.clone(), v1.clone()).unwrap();
+                if i % 2 == 0 {
+                    db.insert(key.clone(), v2.clone()).unwrap();
+                }
+            }
+
+            let mut queried = keys.clone();
+            queried.push(!0);
+
+            let individually: Vec<_> = queried.iter().map(|key| db.get(key).unwrap()).collect();
+            quickcheck::TestResult::from_bool(db.get_many(&queried) == Ok(individually))
+        }
+        quickcheck(prop as fn(Vec<u64>, u64, u64) -> quickcheck::TestResult);
+    }
+
+    #[test]
+    fn bulk_insert_matches_insert() {
+        fn prop(entries: Vec<(u8, u64)>) -> bool {
+            let mut inserted = TempRocksDB::new().unwrap();
+            let mut loaded = TempRocksDB::new().unwrap();
+
+            for &(k, v) in entries.iter() {
+                inserted.insert(k, v).unwrap();
+            }
+            loaded.bulk_insert(entries.clone()).unwrap();
+
+            entries.iter().all(|&(k, _)| inserted.get(&k) == loaded.get(&k))
+        }
+        quickcheck(prop as fn(Vec<(u8, u64)>) -> bool);
+    }
+
+    #[test]
+    fn synced_writes_are_kept() {
+        let mut db = TempRocksDB::new().unwrap();
+        db.insert(1u64, 2u64).unwrap();
+
+        assert_eq!(db.sync(), Ok(()));
+        assert_eq!(db.get(&1), Ok(Some(vec![2].into_iter().collect())));
+    }
+
+    #[test]
+    fn concurrent_temp_dbs_leave_no_directories() {
+        let handles: Vec<_> = (0..8u64).map(|t| {
+            thread::spawn(move || {
+                let db: Arc<RwLock<TempRocksDB<u64, u64>>> = Arc::new(RwLock::new(TempRocksDB::new().unwrap()));
+                let path = db.read().unwrap().path().to_path_buf();
+
+                let writers: Vec<_> = (0..4u64).map(|w| {
+                    let db = db.clone();
+                    thread::spawn(move || {
+                        for k in 0..100u64 {
+                            db.write().unwrap().insert(k, t * 4 + w).unwrap();
+                        }
+                    })
+                }).collect();
+
+                for writer in writers {
+                    writer.join().unwrap();
+                }
+
+                path
+            })
+        }).collect();
+
+        for handle in handles {
+            let path = handle.join().unwrap();
+            assert!(!path.exists(), "{:?} was not removed", path);
+        }
+    }
+
+    #[test]
+    fn warmup_reads_up_to_every_entry() {
+        let mut db: TempRocksDB<u64, u64> = TempRocksDB::new().unwrap();
+        for k in 0..100u64 {
+            db.insert(k % 10, k).unwrap();
+        }
+
+        assert_eq!(db.warmup(1.0).unwrap(), 100);
+        assert!(db.warmup(0.5).unwrap() <= 100);
+    }
 }
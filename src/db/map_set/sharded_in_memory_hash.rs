@@ -0,0 +1,353 @@
+use std::clone::Clone;
+use std::default::Default;
+use std::cmp::Eq;
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry::{Vacant, Occupied};
+
+use fnv::FnvHasher;
+
+use db::hashing::State;
+use db::stats::StoreUsage;
+use error::Error;
+
+use super::MapSet;
+
+/// Default number of shards - enough to keep 16 or so writer threads from
+/// stepping on each other most of the time
+pub const DEFAULT_SHARDS: usize = 64;
+
+/// Default number of threads writing a large `bulk_insert`
+pub const DEFAULT_BULK_THREADS: usize = 8;
+
+// Batches smaller than this aren't worth spawning threads for
+const PARALLEL_BULK_THRESHOLD: usize = 10000;
+
+// Each shard's map is shared with snapshots, and copied by the first write
+// to it after one is taken
+type Shard<K, V> = RwLock<Arc<HashMap<K, HashSet<V, State>, State>>>;
+
+/// InMemoryHash split across N independently-locked shards
+///
+/// Keys are assigned to a shard by hash, so concurrent writers touching
+/// different variant keys (even within the same partition) will usually take
+/// different locks.  `insert_shared` & `remove_shared` take `&self` so a
+/// single instance can be shared across threads behind an `Arc` rather than a
+/// `RwLock` - databases over it insert through `Database::insert_shared`
+/// while holding only a read lock.
+///
+/// Bulk loads hand the variant store every entry at once, which
+/// `bulk_insert` splits by shard and writes from several threads.
+///
+/// A snapshot shares each shard's map until either side writes to it, when
+/// the writer copies that shard.  Shards are snapshotted one at a time, so
+/// a snapshot may see only some of the variants of an `insert_shared` made
+/// while it's taken.
+///
+pub struct ShardedInMemoryHash<K, V>
+where   K: Sync + Send + Clone + Eq + Hash,
+        V: Sync + Send + Clone + Eq + Hash,
+{
+    shards: Arc<Vec<Shard<K, V>>>,
+    bulk_threads: usize,
+}
+
+impl<K, V> ShardedInMemoryHash<K, V>
+where   K: Sync + Send + Clone + Eq + Hash,
+        V: Sync + Send + Clone + Eq + Hash,
+{
+    pub fn new() -> ShardedInMemoryHash<K, V> {
+        ShardedInMemoryHash::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(shards: usize) -> ShardedInMemoryHash<K, V> {
+        assert!(shards > 0);
+
+        ShardedInMemoryHash {
+            shards: Arc::new((0..shards).map(|_| RwLock::new(Arc::new(HashMap::default()))).collect()),
+            bulk_threads: DEFAULT_BULK_THREADS,
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Set the number of threads writing a large `bulk_insert`
+    ///
+    pub fn set_bulk_threads(&mut self, threads: usize) {
+        assert!(threads > 0);
+        self.bulk_threads = threads;
+    }
+
+    fn shard_index(&self, key: &K) -> usize {
+        let mut hasher = FnvHasher::default();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &K) -> &Shard<K, V> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub fn remove_shared(&self, key: &K, value: &V) -> bool {
+        let mut shard = self.shard(key).write().unwrap();
+        if !shard.contains_key(key) {
+            return false
+        }
+
+        let data = Arc::make_mut(&mut *shard);
+        let mut delete_key = false;
+
+        let removed = match data.get_mut(key) {
+            None => {
+                false
+            },
+            Some(set) => {
+                let removed = set.remove(value);
+                if set.is_empty() { delete_key = true };
+                removed
+            },
+        };
+
+        if delete_key {
+            data.remove(key);
+        };
+
+        removed
+    }
+}
+
+impl<K, V> Default for ShardedInMemoryHash<K, V>
+where   K: Sync + Send + Clone + Eq + Hash,
+        V: Sync + Send + Clone + Eq + Hash,
+{
+    fn default() -> ShardedInMemoryHash<K, V> {
+        ShardedInMemoryHash::new()
+    }
+}
+
+/// Insert every entry of `entries` into `shard`, holding its lock throughout
+///
+fn insert_all<K, V>(shard: &Shard<K, V>, entries: Vec<(K, V)>) where
+K: Clone + Eq + Hash,
+V: Clone + Eq + Hash,
+{
+    let mut shard = shard.write().unwrap();
+    let data = Arc::make_mut(&mut *shard);
+    for (key, value) in entries.into_iter() {
+        data.entry(key).or_insert_with(HashSet::default).insert(value);
+    }
+}
+
+impl<K, V> MapSet<K, V> for ShardedInMemoryHash<K, V>
+where   K: Sync + Send + Clone + Eq + Hash + 'static,
+        V: Sync + Send + Clone + Eq + Hash + 'static,
+{
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error> {
+        self.insert_shared(key, value)
+    }
+
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
+        match self.shard(key).read().unwrap().get(key) {
+            Some(h) => Ok(Some(h.clone())),
+            None => Ok(None),
+        }
+    }
+
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        Ok(self.shard(key).read().unwrap().get(key).map_or(0, |set| set.len()))
+    }
+
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error> {
+        Ok(self.remove_shared(key, value))
+    }
+
+    /// Entries are grouped by shard, so each shard's lock is taken once.
+    /// Batches of more than a few thousand entries are split between up to
+    /// `bulk_threads` threads, each writing a distinct set of shards.
+    ///
+    fn bulk_insert(&mut self, entries: Vec<(K, V)>) -> Result<(), Error> {
+        let count = entries.len();
+        let mut groups: Vec<Vec<(K, V)>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+        for (key, value) in entries.into_iter() {
+            let i = self.shard_index(&key);
+            groups[i].push((key, value));
+        }
+
+        if count < PARALLEL_BULK_THRESHOLD || self.bulk_threads == 1 {
+            for (shard, group) in self.shards.iter().zip(groups.into_iter()) {
+                insert_all(shard, group);
+            }
+            return Ok(())
+        }
+
+        // Thread t writes shards t, t + bulk_threads, ...
+        let mut work: Vec<Vec<(usize, Vec<(K, V)>)>> = (0..self.bulk_threads).map(|_| Vec::new()).collect();
+        for (i, group) in groups.into_iter().enumerate() {
+            if !group.is_empty() {
+                work[i % self.bulk_threads].push((i, group));
+            }
+        }
+
+        let handles: Vec<_> = work.into_iter().map(|groups| {
+            let shards = self.shards.clone();
+            thread::spawn(move || {
+                for (i, group) in groups.into_iter() {
+                    insert_all(&shards[i], group);
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            try!(handle.join().map_err(|_| "bulk insert thread panicked".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Shards are scanned one at a time, so writes to other shards aren't
+    /// blocked for the whole scan
+    ///
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        for shard in self.shards.iter() {
+            for (key, set) in shard.read().unwrap().iter() {
+                for value in set.iter() {
+                    f(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimated as for `InMemoryHash`
+    ///
+    fn usage(&self) -> StoreUsage {
+        let memory_bytes = self.shards.iter().fold(0, |bytes, shard| {
+            shard.read().unwrap().values().fold(bytes, |bytes, set| {
+                bytes + size_of::<K>() + size_of::<HashSet<V, State>>() + set.len() * size_of::<V>()
+            })
+        });
+
+        StoreUsage{memory_bytes: memory_bytes as u64, disk_bytes: None}
+    }
+
+    fn shares_writes(&self) -> bool {
+        true
+    }
+
+    fn insert_shared(&self, key: K, value: V) -> Result<bool, Error> {
+        let mut shard = self.shard(&key).write().unwrap();
+
+        match Arc::make_mut(&mut *shard).entry(key) {
+            Vacant(entry) => {
+                let mut set: HashSet<V, State> = HashSet::default();
+                set.insert(value);
+                entry.insert(set);
+                Ok(true)
+            },
+            Occupied(mut entry) => {
+                Ok(entry.get_mut().insert(value))
+            },
+        }
+    }
+
+    /// Shares each shard's map until either copy next writes to it
+    ///
+    fn snapshot(&self) -> Result<ShardedInMemoryHash<K, V>, Error> {
+        let shards = self.shards.iter().map(|shard| RwLock::new(shard.read().unwrap().clone())).collect();
+        Ok(ShardedInMemoryHash {
+            shards: Arc::new(shards),
+            bulk_threads: self.bulk_threads,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    use db::map_set::{MapSet, ShardedInMemoryHash};
+
+    #[test]
+    fn inserted_exists() {
+        fn prop(k: u64, v: u64) -> quickcheck::TestResult {
+            let mut db = ShardedInMemoryHash::with_shards(4);
+            db.insert(k.clone(), v.clone()).unwrap();
+
+            match db.get(&k).unwrap() {
+                Some(results) => quickcheck::TestResult::from_bool(results.contains(&v)),
+                None => quickcheck::TestResult::failed(),
+            }
+        }
+        quickcheck(prop as fn(u64, u64) -> quickcheck::TestResult);
+    }
+
+    #[test]
+    fn key_deleted_no_exists() {
+        fn prop(k1: u64, k2: u64, v1: u64, v2: u64) -> quickcheck::TestResult {
+            if k1 == k2 {
+                return quickcheck::TestResult::discard()
+            }
+
+            let mut db = ShardedInMemoryHash::with_shards(4);
+            db.insert(k1.clone(), v1.clone()).unwrap();
+            db.insert(k2.clone(), v2.clone()).unwrap();
+            db.remove(&k1, &v1).unwrap();
+
+            match db.get(&k1).unwrap() {
+                Some(_) => quickcheck::TestResult::failed(),
+                None => quickcheck::TestResult::passed(),
+            }
+        }
+        quickcheck(prop as fn(u64, u64, u64, u64) -> quickcheck::TestResult);
+    }
+
+    #[test]
+    fn concurrent_inserts_all_visible() {
+        let db: Arc<ShardedInMemoryHash<u64, u64>> = Arc::new(ShardedInMemoryHash::with_shards(8));
+
+        let handles: Vec<_> = (0..16u64).map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for k in 0..1000u64 {
+                    db.insert_shared(k, t).unwrap();
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for k in 0..1000u64 {
+            assert_eq!(db.get(&k).unwrap().unwrap().len(), 16);
+        }
+    }
+
+    #[test]
+    fn parallel_bulk_insert_matches_inserts() {
+        let entries: Vec<(u64, u64)> = (0..50000u64).map(|i| (i % 5000, i)).collect();
+
+        let mut inserted = ShardedInMemoryHash::with_shards(16);
+        for &(k, v) in entries.iter() {
+            inserted.insert(k, v).unwrap();
+        }
+
+        let mut loaded = ShardedInMemoryHash::with_shards(16);
+        loaded.set_bulk_threads(4);
+        loaded.bulk_insert(entries).unwrap();
+
+        for k in 0..5000u64 {
+            assert_eq!(inserted.get(&k), loaded.get(&k));
+        }
+    }
+}
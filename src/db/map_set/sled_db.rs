@@ -0,0 +1,190 @@
+use std::clone::Clone;
+use std::fmt;
+use std::cmp::Eq;
+use std::hash::Hash;
+use std::path::Path;
+use std::marker::PhantomData;
+
+use std::collections::HashSet;
+
+use sled;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::encoding::{encode, decode};
+use db::stats::BUCKET_SAMPLE_RATE;
+use db::hashing::State;
+use error::Error;
+
+use super::MapSet;
+
+/// Sled uses the pure-Rust sled store to map keys to sets of values
+///
+/// Keys are laid out exactly as in `RocksDB`: each k/v pair is a single sled
+/// key, and sets are read back by scanning the keys prefixed by the encoded
+/// key.
+///
+pub struct Sled<K, V> {
+    key: PhantomData<K>,
+    value: PhantomData<V>,
+    db: sled::Db,
+}
+
+impl<K, V> Sled<K, V> {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Sled<K, V>, Error> {
+        Ok(Sled{
+            key: PhantomData,
+            value: PhantomData,
+            db: try!(sled::open(path).map_err(storage_error)),
+        })
+    }
+}
+
+/// sled reports errors as its own type
+///
+fn storage_error<E: fmt::Display>(e: E) -> Error {
+    Error::Storage(format!("{}", e))
+}
+
+impl<K, V> MapSet<K, V> for Sled<K, V>
+where   K: Sync + Send + Clone + Eq + Hash + Serialize + DeserializeOwned,
+V: Sync + Send + Clone + Eq + Hash + Serialize + DeserializeOwned,
+{
+    fn insert(&mut self, key: K, value: V) -> Result<bool, Error> {
+        let encoded_key: Vec<u8> = try!(encode(&(key, value)));
+
+        Ok(try!(self.db.insert(encoded_key, &[][..]).map_err(storage_error)).is_none())
+    }
+
+    fn get(&self, key: &K) -> Result<Option<HashSet<V, State>>, Error> {
+        let encoded_key_prefix: Vec<u8> = try!(encode(&key));
+        let mut out = HashSet::default();
+
+        for entry in self.db.scan_prefix(&encoded_key_prefix) {
+            let (k, _) = try!(entry.map_err(storage_error));
+            let (decoded_key, decoded_value): (K, V) = try!(decode(&k));
+
+            if decoded_key == *key {
+                out.insert(decoded_value);
+            }
+        }
+
+        if out.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(out))
+        }
+    }
+
+    /// Counts matching sled keys without decoding their values
+    ///
+    fn count(&self, key: &K) -> Result<usize, Error> {
+        let encoded_key_prefix: Vec<u8> = try!(encode(&key));
+
+        Ok(self.db.scan_prefix(&encoded_key_prefix).count())
+    }
+
+    fn remove(&mut self, key: &K, value: &V) -> Result<bool, Error> {
+        let encoded_key: Vec<u8> = try!(encode(&(key, value)));
+
+        Ok(try!(self.db.remove(encoded_key).map_err(storage_error)).is_some())
+    }
+
+    /// Deletes are collected into a single `Batch` rather than issued
+    /// individually
+    ///
+    fn remove_many(&mut self, keys: &[K], value: &V) -> Result<usize, Error> {
+        let mut batch = sled::Batch::default();
+        let mut removed = 0;
+
+        for key in keys {
+            let encoded_key: Vec<u8> = try!(encode(&(key, value)));
+
+            if try!(self.db.contains_key(&encoded_key).map_err(storage_error)) {
+                batch.remove(encoded_key);
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            try!(self.db.apply_batch(batch).map_err(storage_error));
+        }
+        Ok(removed)
+    }
+
+    /// Decodes the first `n` sled keys
+    ///
+    fn sample(&self, n: usize) -> Result<Vec<(K, V)>, Error> {
+        let mut out = Vec::with_capacity(n);
+
+        for entry in self.db.iter().take(n) {
+            let (k, _) = try!(entry.map_err(|e| Error::Storage(format!("unable to read variant key: {}", e))));
+            match decode(&k) {
+                Ok(entry) => out.push(entry),
+                Err(e) => return Err(Error::Corrupt(format!("undecodable variant key {:?}: {:?}", k, e))),
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// sled keys are ordered by their encoded key, so each key's entries are
+    /// adjacent
+    ///
+    fn scan(&self, f: &mut FnMut(&K, &V)) -> Result<(), Error> {
+        for entry in self.db.iter() {
+            let (k, _) = try!(entry.map_err(|e| Error::Storage(format!("unable to read variant key: {}", e))));
+            match decode::<(K, V)>(&k) {
+                Ok((key, value)) => f(&key, &value),
+                Err(e) => return Err(Error::Corrupt(format!("undecodable variant key {:?}: {:?}", k, e))),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn bucket_sample_rate(&self) -> usize {
+        BUCKET_SAMPLE_RATE
+    }
+
+    /// sled buffers writes in memory; flushing also fsyncs them
+    ///
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush().map(|_| ()).map_err(|e| Error::Storage(format!("unable to flush: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+
+    use db::map_set::{MapSet, Sled};
+    use db::temp_dir::TempDir;
+
+    #[test]
+    fn behaves_like_a_set() {
+        let dir = TempDir::new();
+        let mut db: Sled<u64, u64> = Sled::new(dir.path()).unwrap();
+
+        assert_eq!(db.insert(1, 10), Ok(true));
+        assert_eq!(db.insert(1, 10), Ok(false));
+        assert_eq!(db.insert(1, 11), Ok(true));
+        assert_eq!(db.insert(2, 10), Ok(true));
+
+        assert_eq!(db.get(&1).unwrap().unwrap().len(), 2);
+        assert_eq!(db.count(&1), Ok(2));
+        assert_eq!(db.remove_many(&[1, 2, 3], &10), Ok(2));
+        assert_eq!(db.remove(&1, &10), Ok(false));
+        assert_eq!(db.get(&2), Ok(None));
+    }
+
+    #[test]
+    fn fails_to_open_over_a_file() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("file");
+        File::create(&path).unwrap();
+
+        assert!(Sled::<u64, u64>::new(&path).is_err());
+    }
+}
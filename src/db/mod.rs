@@ -41,6 +41,10 @@
 //! u64        | 5-13    | u16
 //! u64        | 13+     | u8
 //!
+//! `planner::window_bits` applies this rule (and is what `Factory::build`
+//! uses), while `planner::recommend` also picks the strategy and backend for an
+//! expected number of values and memory budget.
+//!
 //! # Examples
 //!
 //! ```ignore
@@ -54,35 +58,101 @@
 //! db.insert(7);
 //! db.insert(1209384029384);
 //!
-//! let results = db.get(&0).iter().collect();
+//! let results = db.get(&0).unwrap().iter().collect();
 //! assert_eq!(results, vec![0,1,3,7]);
 //! ```
 //!
+//! Every operation which touches storage returns `Result<_, hammer::Error>`,
+//! so a failing disk is reported to the caller rather than panicking.
+//!
 
+pub mod atomic;
+pub mod bench;
+pub mod bits;
+pub mod builder;
+#[cfg(feature = "rocksdb")]
+pub mod column_family;
+#[cfg(feature = "rocksdb")]
+pub mod compaction;
+pub mod cost;
+pub mod counted;
 pub mod deletion;
+pub mod dimension_mask;
+pub mod disk_usage;
+pub mod encoding;
+pub mod evicting;
+pub mod expansion;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod frozen;
 pub mod hamming;
 pub mod hashing;
+pub mod health;
 pub mod id_map;
+pub mod join;
+pub mod pairwise;
+pub mod params;
+pub mod partition_mask;
+pub mod payload;
+pub mod planner;
+pub mod popcount;
+pub mod query_log;
+pub mod read_only;
+pub mod shadow;
+pub mod sink;
+pub mod snapshot;
+pub mod stats;
+pub mod string_key;
 pub mod substitution;
+pub mod temp_dir;
+pub mod testing;
 pub mod window;
 pub mod map_set;
 pub mod typemap;
+pub mod variant_budget;
+pub mod verifier;
+pub mod versioned;
 
 mod result_accumulator;
+#[cfg(feature = "rocksdb")]
+mod spilling_accumulator;
 
-// mod bench; // Uncomment to get benchmarks to run
+pub use self::builder::{DbBuilder, Strategy, BuildError};
 
+use std::fs;
+use std::io::{Read, Write};
 use std::collections::HashSet;
 use std::hash::Hash;
+use std::path::Path;
+#[cfg(any(feature = "rocksdb", feature = "sled"))]
 use std::path::PathBuf;
+use std::default::Default;
+#[cfg(feature = "rocksdb")]
+use std::sync::Arc;
+
+#[cfg(feature = "rocksdb")]
+use rocksdb::{Options, DBCompactionStyle, rocksdb_ffi};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
 use db::hamming::Hamming;
+#[cfg(feature = "rocksdb")]
+use db::column_family::SharedRocksDB;
 use db::window::{Windowable};
-use db::id_map::{ToID, IDMap};
+use db::dimension_mask::MaskDimensions;
+use db::id_map::{ToID, FromID, IDMap, IDStrategy};
+use db::map_set::MapSet;
+use db::read_only::ReadOnly;
+use db::cost::TooExpensive;
+use db::health::{HealthReport, RepairReport};
+use db::shadow::ShadowStats;
+use db::stats::Stats;
+use db::variant_budget::VariantBudget;
+use db::hashing::State;
+use error::Error;
 
 pub trait TypeMap {
     /// The data type being indexed
-    type Input: Sync + Send + Clone + Eq + Hash + Hamming + Windowable<Self::Window> + ToID<Self::Identifier>;
+    type Input: Sync + Send + Clone + Eq + Hash + Hamming + MaskDimensions + Windowable<Self::Window> + ToID<Self::Identifier>;
 
     /// The type of windows over Input.  Window types must be large
     /// enough to store dimensions/tolerance  dimensions of Input (ideally not larger)
@@ -93,7 +163,7 @@ pub trait TypeMap {
 
     /// Value identifier - balances memory use with collision probability given
     /// the cardinality of the data being indexed
-    type Identifier: Sync + Send + Clone + Eq + Hash;
+    type Identifier: Sync + Send + Clone + Eq + Ord + Hash + FromID;
 
     /// The value sture - maps Identifier -> Input
     type ValueStore: IDMap<Self::Identifier, Self::Input>;
@@ -105,19 +175,706 @@ pub trait TypeMap {
 /// Abstract interface for Hamming distance databases
 ///
 pub trait Database<T>: Sync + Send {
-    fn get(&self, key: &T) -> Option<HashSet<T>>;
-    fn insert(&mut self, key: T) -> bool;
-    fn remove(&mut self, key: &T) -> bool;
+    fn get(&self, key: &T) -> Result<Option<HashSet<T, State>>, Error> {
+        self.get_with(key, &Default::default())
+    }
+
+    /// Get all indexed values within tolerance of `key`, filtered according to
+    /// `options`
+    ///
+    fn get_with(&self, key: &T, options: &QueryOptions) -> Result<Option<HashSet<T, State>>, Error>;
+
+    /// Get all indexed values within `max_distance` of `key`
+    ///
+    /// Probes the same partitions as `get`, only verifying candidates at the
+    /// stricter bound, so an index built with a large tolerance can answer
+    /// narrower queries.  Fails with `Error::Invalid` if `max_distance`
+    /// exceeds the DB's tolerance.
+    ///
+    fn get_within(&self, key: &T, max_distance: usize) -> Result<Option<HashSet<T, State>>, Error> {
+        self.get_with(key, &QueryOptions{max_distance: Some(max_distance), ..Default::default()})
+    }
+
+    /// Call `f` with each value `get_with` would return
+    ///
+    /// Databases which can hold at most `spill_threshold` candidates in
+    /// memory, spilling the rest to a temporary RocksDB, override this; for
+    /// bulk and analytical queries (see `db::join`) whose results may not fit
+    /// in memory.  Others collect the results of `get_with`.
+    ///
+    fn for_each_match(&self, key: &T, options: &QueryOptions, _spill_threshold: usize, f: &mut FnMut(T)) -> Result<(), Error> where
+    T: Serialize + DeserializeOwned,
+    {
+        if let Some(found) = try!(self.get_with(key, options)) {
+            for value in found {
+                f(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// `get_with` for each of `keys`, in the same order
+    ///
+    /// Databases which can share store reads between queries (ie when
+    /// several keys probe the same variant bucket or find the same
+    /// candidate) should override this.
+    ///
+    fn get_many(&self, keys: &[T], options: &QueryOptions) -> Result<Vec<Option<HashSet<T, State>>>, Error> {
+        keys.iter().map(|key| self.get_with(key, options)).collect()
+    }
+
+    /// Up to `limit` of the values `get` would return, ordered by identifier
+    /// and starting after `cursor`
+    ///
+    /// `cursor` is the previous page's `next`, or `None` for the first page.
+    /// Values inserted between pages are returned if they sort after the
+    /// cursor.
+    ///
+    fn get_page(&self, key: &T, cursor: Option<&T>, limit: usize) -> Result<Page<T>, Error> {
+        self.get_page_with(key, &Default::default(), cursor, limit)
+    }
+
+    /// `get_page`, filtered according to `options`
+    ///
+    /// Pages are cut from the full set of matches, so paging bounds the size
+    /// of each result rather than the work of finding it.  Fails with
+    /// `Error::Invalid` if `limit` is zero.  Databases which don't know their
+    /// values' identifiers needn't override this.
+    ///
+    fn get_page_with(&self, _key: &T, _options: &QueryOptions, _cursor: Option<&T>, _limit: usize) -> Result<Page<T>, Error> {
+        Err(Error::Unsupported("paginated queries".to_string()))
+    }
+
+    fn insert(&mut self, key: T) -> Result<bool, Error>;
+    fn remove(&mut self, key: &T) -> Result<bool, Error>;
+
+    /// Returns true if values can be inserted through a shared reference
+    /// (see `insert_shared`)
+    ///
+    /// Only in-memory DBs whose values are their own identifiers (ie
+    /// integers) can be.  Wrappers which track what's inserted (ie counting
+    /// or evicting DBs) needn't override this.
+    ///
+    fn shares_writes(&self) -> bool {
+        false
+    }
+
+    /// Like `insert`, through a shared reference, so concurrent writers only
+    /// need a read lock on the DB
+    ///
+    /// Fails with `Error::Unsupported`, before writing anything, unless
+    /// `shares_writes`.
+    ///
+    fn insert_shared(&self, _key: T) -> Result<bool, Error> {
+        Err(Error::Unsupported("shared inserts".to_string()))
+    }
+
+    /// Insert every value in `keys`, without reporting which were already
+    /// present
+    ///
+    /// Much faster than `insert` when loading many values into persistent
+    /// storage, as the DB can hand its store every variant of the batch at
+    /// once.  Larger batches amortize better.
+    ///
+    fn bulk_insert(&mut self, keys: Vec<T>) -> Result<(), Error> {
+        for key in keys.into_iter() {
+            try!(self.insert(key));
+        }
+        Ok(())
+    }
+
+    /// Remove `key` from every index, then check that no variant still
+    /// references it
+    ///
+    /// Every variant entry is read after the remove, so references under
+    /// keys the value no longer maps to (ie left by an interrupted remove, or
+    /// written with different ignored dimensions) are found too.  Much slower
+    /// than `remove`, but provides evidence the value is gone
+    ///
+    fn remove_verified(&mut self, key: &T) -> Result<RemovalReport, Error>;
+
+    /// The indexed value whose identifier (see `id_map::ToID`) is `id`
+    ///
+    /// Values of up to 64 bits are their own identifiers, so their ID is the
+    /// value's bits (see `id_map::FromID`).
+    ///
+    fn get_by_id(&self, id: &u64) -> Result<Option<T>, Error>;
+
+    /// Remove the value whose identifier is `id`, without needing its content
+    ///
+    /// Returns true if a value was removed
+    ///
+    fn remove_id(&mut self, id: &u64) -> Result<bool, Error> {
+        match try!(self.get_by_id(id)) {
+            Some(value) => self.remove(&value),
+            None => Ok(false),
+        }
+    }
+
+    /// Get all indexed values within tolerance of `key`, most recently
+    /// inserted first
+    ///
+    /// Values whose value store doesn't track insertion order are returned
+    /// last, in no particular order
+    ///
+    fn get_recent(&self, key: &T) -> Result<Option<Vec<T>>, Error> {
+        self.get_recent_with(key, &Default::default())
+    }
+
+    fn get_recent_with(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<T>>, Error>;
+
+    /// Orders values already found by a query as `get_recent` would, so
+    /// wrappers which query the DB themselves needn't query it again
+    ///
+    /// Databases which don't track insertion order needn't override this.
+    ///
+    fn sort_by_recency(&self, found: HashSet<T, State>) -> Vec<T> {
+        found.into_iter().collect()
+    }
+
+    /// The `k` indexed values within tolerance of `key` nearest to it by
+    /// hamming distance, nearest first
+    ///
+    /// Only the `k` nearest results found so far are held while verifying
+    /// candidates, so this holds much less than `get` for queries matching
+    /// many values.  Ties at the boundary are broken arbitrarily.
+    ///
+    /// Only the output is bounded: candidates are gathered from every
+    /// variant bucket before any is verified, just as for `get`, so a query
+    /// hitting huge buckets costs as much memory as it would there.  Check
+    /// `estimate_candidates` first (as `get_bounded` does) to refuse those.
+    ///
+    fn get_topk(&self, key: &T, k: usize) -> Result<Option<Vec<T>>, Error>;
+
+    /// Upper bound on the number of candidates a query for `key` would
+    /// examine (counting a value once per bucket it appears in)
+    ///
+    fn estimate_candidates(&self, key: &T) -> Result<usize, Error>;
+
+    /// Like `get_with`, but fails with `Error::TooExpensive` without querying
+    /// if the estimated number of candidates exceeds `max_candidates`
+    ///
+    fn get_bounded(&self, key: &T, options: &QueryOptions, max_candidates: usize) -> Result<Option<HashSet<T, State>>, Error> {
+        let estimate = try!(self.estimate_candidates(key));
+        if estimate > max_candidates {
+            return Err(Error::TooExpensive(TooExpensive{estimated_candidates: estimate, limit: max_candidates}))
+        }
+
+        self.get_with(key, options)
+    }
+
+    /// Ignore differences in `dimensions` (see `db::dimension_mask`)
+    ///
+    /// Fails with `Error::Invalid` if a dimension doesn't exist, or once
+    /// values have been inserted through the database.  Databases reopened
+    /// over existing stores must be given the dimensions they were written
+    /// with.  Databases which can't mask dimensions needn't override this.
+    ///
+    fn set_ignored_dimensions(&mut self, _dimensions: Vec<usize>) -> Result<(), Error> {
+        Err(Error::Unsupported("ignoring dimensions".to_string()))
+    }
+
+    /// Identify values with `strategy` rather than their type's default (see
+    /// `id_map::IDStrategy`)
+    ///
+    /// Fails with `Error::Unsupported` if the value type can't be identified
+    /// that way, or the database doesn't choose identifiers, and with
+    /// `Error::Invalid` once values have been inserted through the database.
+    ///
+    fn set_id_strategy(&mut self, _strategy: IDStrategy) -> Result<(), Error> {
+        Err(Error::Unsupported("choosing an ID strategy".to_string()))
+    }
+
+    /// Derive the one variants of narrow partitions at query time rather than
+    /// storing them (see `substitution::DB`)
+    ///
+    /// Fails with `Error::Unsupported` if the database doesn't store one
+    /// variants, and with `Error::Invalid` once values have been inserted
+    /// through the database.
+    ///
+    fn set_compact_variants(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported("compact variants".to_string()))
+    }
+
+    /// Read up to `sample` stored variants, checking that they decode and
+    /// that the values they reference can be resolved
+    ///
+    /// Databases with nothing persisted needn't override this.
+    ///
+    fn health_check(&self, _sample: usize) -> HealthReport {
+        Default::default()
+    }
+
+    /// Read every variant entry, removing any which reference a value that
+    /// no longer exists (see `db::health`)
+    ///
+    /// Databases with nothing persisted needn't override this.
+    ///
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        Ok(Default::default())
+    }
+
+    /// Make every insert & remove so far survive a crash
+    ///
+    /// Databases with nothing persisted needn't override this.
+    ///
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Reclaim the space held by removed values (see `db::compaction`)
+    ///
+    /// Databases with nothing persisted needn't override this.
+    ///
+    fn compact(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Keep up to `sets` of the variant store's sets cached in memory (see
+    /// `map_set::Cached`), or none if it's 0
+    ///
+    /// Fails with `Error::Unsupported` if the variant store can't be cached
+    /// (only RocksDB variant stores are built with a cache).
+    ///
+    fn set_variant_cache(&mut self, _sets: usize) -> Result<(), Error> {
+        Err(Error::Unsupported("caching variant sets".to_string()))
+    }
+
+    /// Read about `fraction` (between 0 and 1) of the DB's values & variants
+    /// from disk, so the first queries after opening it aren't slowed by
+    /// cold caches
+    ///
+    /// Databases with nothing persisted needn't override this.
+    ///
+    fn warmup(&self, _fraction: f64) -> Result<WarmupReport, Error> {
+        Ok(Default::default())
+    }
+
+    /// Count the DB's values, variant keys & entries per partition, and
+    /// estimate the space its stores use
+    ///
+    /// Reads every variant entry (see `db::stats`).
+    ///
+    fn stats(&self) -> Result<Stats, Error>;
+
+    /// Differences between the DB's results and its candidate's, if it's
+    /// being shadowed (see `db::shadow`)
+    ///
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        None
+    }
+
+    /// Call `f` with every indexed value, in no particular order
+    ///
+    /// Reads every variant entry of one partition (see
+    /// `snapshot::scan_values`).  Databases which can't enumerate their values
+    /// needn't override this.
+    ///
+    fn for_each_value(&self, _f: &mut FnMut(T) -> Result<(), Error>) -> Result<(), Error> {
+        Err(Error::Unsupported("enumerating values".to_string()))
+    }
+
+    /// Write a snapshot of every indexed value to `writer` (see `db::snapshot`)
+    ///
+    /// Returns the number of values written
+    ///
+    fn export(&self, writer: &mut Write) -> Result<usize, Error> where T: Serialize {
+        snapshot::export(self, writer)
+    }
+
+    /// Insert every value in the snapshot read from `reader`
+    ///
+    /// Returns the number of values read
+    ///
+    fn import(&mut self, reader: &mut Read) -> Result<usize, Error> where T: DeserializeOwned {
+        snapshot::import(self, reader)
+    }
+    /// A read-only handle on the DB as it is now, which later writes to the
+    /// DB don't change (see `db::read_only`)
+    ///
+    /// Queries against it needn't hold the DB's lock.  Fails with
+    /// `Error::Unsupported` unless every store of the DB can take a snapshot.
+    ///
+    fn snapshot(&self) -> Result<ReadOnly<T>, Error> {
+        Err(Error::Unsupported("snapshots".to_string()))
+    }
+}
+
+/// Outcome of `Database::remove_verified`
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RemovalReport {
+    /// Variant entries deleted
+    pub variants_removed: usize,
+    /// Variant entries read after deletion (every entry of the variant store)
+    pub variants_checked: usize,
+    /// Variant entries still referencing the value after deletion
+    pub variants_remaining: usize,
+}
+
+impl RemovalReport {
+    /// Returns true if the value was present and is now fully removed
+    ///
+    pub fn verified(&self) -> bool {
+        self.variants_removed > 0 && self.variants_remaining == 0
+    }
+}
+
+/// Outcome of `Database::warmup`
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct WarmupReport {
+    /// Value store entries read
+    pub values_read: usize,
+    /// Variant store entries read
+    pub variants_read: usize,
+}
+
+/// Part of a query's results (see `Database::get_page`)
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Page<T> {
+    pub values: Vec<T>,
+    /// Cursor for the following page, or `None` if this is the last
+    pub next: Option<T>,
+}
+
+/// Per-query result filters
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryOptions {
+    /// Omit values identical to the query (ie at distance 0)
+    pub exclude_exact: bool,
+    /// Only probe the buckets of each partition's exact window, skipping the
+    /// one-variant buckets
+    ///
+    /// Much cheaper, but only finds values matching the query exactly in at
+    /// least one partition - for tolerances near the partition bound, many
+    /// matches differ in every partition.  Results are still verified, so
+    /// nothing outside the tolerance is returned.  Only substitution DBs
+    /// support this; other DBs ignore it.
+    pub approximate: bool,
+    /// Only return values within this distance of the query, rather than
+    /// the DB's tolerance (see `Database::get_within`)
+    pub max_distance: Option<usize>,
+}
+
+impl QueryOptions {
+    /// The distance candidates are verified at, for a DB with `tolerance`
+    ///
+    /// Fails with `Error::Invalid` if `max_distance` exceeds `tolerance`, as
+    /// the index can't find values beyond its tolerance.
+    ///
+    pub fn verification_tolerance(&self, tolerance: usize) -> Result<usize, Error> {
+        match self.max_distance {
+            Some(max_distance) if max_distance > tolerance => {
+                Err(Error::Invalid(format!("max distance {} exceeds the index tolerance {}", max_distance, tolerance)))
+            },
+            Some(max_distance) => Ok(max_distance),
+            None => Ok(tolerance),
+        }
+    }
+}
+
+/// Orders `found` by insertion sequence, newest first
+///
+fn order_by_recency<T, ID, S, F>(found: HashSet<T, State>, value_store: &S, id: F) -> Vec<T> where
+S: IDMap<ID, T>,
+F: Fn(&T) -> ID,
+{
+    let mut sequenced: Vec<(Option<u64>, T)> = found.into_iter()
+        .map(|value| (value_store.sequence(&id(&value)), value))
+        .collect();
+
+    sequenced.sort_by(|a, b| b.0.cmp(&a.0));
+    sequenced.into_iter().map(|(_, value)| value).collect()
+}
+
+/// Reads `fraction` of `value_store` & `variant_store` (see `Database::warmup`)
+///
+fn warmup_stores<ID, T, K, V, I, M>(value_store: &I, variant_store: &M, fraction: f64) -> Result<WarmupReport, Error> where
+K: Clone + Eq + Hash,
+V: Clone + Eq + Hash,
+I: IDMap<ID, T>,
+M: MapSet<K, V>,
+{
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err(Error::Invalid(format!("warmup fraction must be between 0 and 1, not {}", fraction)))
+    }
+
+    Ok(WarmupReport{
+        values_read: try!(value_store.warmup(fraction)),
+        variants_read: try!(variant_store.warmup(fraction)),
+    })
+}
+
+/// Reads every entry of `variant_store`, returning the number read and the
+/// number referencing `id` (see `Database::remove_verified`)
+///
+fn count_references<K, ID, M>(variant_store: &M, id: &ID) -> Result<(usize, usize), Error> where
+K: Clone + Eq + Hash,
+ID: Clone + Eq + Hash,
+M: MapSet<K, ID>,
+{
+    let (mut checked, mut remaining) = (0, 0);
+    try!(variant_store.scan(&mut |_, entry_id| {
+        checked += 1;
+        if entry_id == id {
+            remaining += 1;
+        }
+    }));
+    Ok((checked, remaining))
+}
+
+/// The page of `found` after `cursor`, ordered by `id`
+///
+fn paginate<T, ID, F>(found: HashSet<T, State>, cursor: Option<&T>, limit: usize, id: F) -> Result<Page<T>, Error> where
+T: Clone + Eq + Hash,
+ID: Ord,
+F: Fn(&T) -> ID,
+{
+    if limit == 0 {
+        return Err(Error::Invalid("page limit must be at least 1".to_string()))
+    }
+
+    let after = cursor.map(|value| id(value));
+    let mut ordered: Vec<(ID, T)> = found.into_iter()
+        .map(|value| (id(&value), value))
+        .filter(|&(ref value_id, _)| after.as_ref().map_or(true, |after| value_id > after))
+        .collect();
+    ordered.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let more = ordered.len() > limit;
+    let values: Vec<T> = ordered.into_iter().take(limit).map(|(_, value)| value).collect();
+    let next = if more { values.last().cloned() } else { None };
+
+    Ok(Page{values: values, next: next})
 }
 
 pub enum StorageBackend {
     InMemory,
+    #[cfg(feature = "rocksdb")]
     TempRocksDB,
-    RocksDB(PathBuf),
+    #[cfg(feature = "rocksdb")]
+    RocksDB(PathBuf, RocksDBConfig),
+    /// Stores in column families of a RocksDB shared with other DBs, named
+    /// with the given prefix (see `db::column_family`).  The shared RocksDB
+    /// was opened with its own options, so only the config's variant store
+    /// settings apply.
+    #[cfg(feature = "rocksdb")]
+    SharedRocksDB(Arc<SharedRocksDB>, String, RocksDBConfig),
+    /// Stores in a pure-Rust sled database under the given path
+    #[cfg(feature = "sled")]
+    Sled(PathBuf),
+}
+
+/// Settings applied to each RocksDB store opened by a DB
+///
+/// DBs open one store for the variant index and (depending on the value type)
+/// one for the value store
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct RocksDBConfig {
+    /// Maximum number of files each store may hold open (-1 is unlimited)
+    pub max_open_files: i32,
+    /// How each store merges its files in the background
+    pub compaction_style: CompactionStyle,
+    /// Size of each store's block cache, if not RocksDB's default (8MB)
+    pub block_cache_bytes: Option<usize>,
+    /// Bits per key of each store's bloom filters, if they're kept.  Filters
+    /// let point lookups skip files which don't hold the key; 10 bits gives
+    /// roughly a 1% false positive rate.
+    pub bloom_filter_bits: Option<i32>,
+    /// Variant sets each DB caches in memory, in front of its variant store
+    /// (see `map_set::Cached`), if any
+    pub variant_cache_sets: Option<usize>,
+    /// Counters of the in-memory bloom filter each DB keeps per partition of
+    /// its variant store's keys (see `map_set::Bloom`), if any.  The filters
+    /// are loaded by scanning the store when it's opened.
+    pub variant_filter_counters: Option<usize>,
+}
+
+impl Default for RocksDBConfig {
+    fn default() -> RocksDBConfig {
+        RocksDBConfig {
+            max_open_files: -1,
+            compaction_style: CompactionStyle::Level,
+            block_cache_bytes: None,
+            bloom_filter_bits: None,
+            variant_cache_sets: None,
+            variant_filter_counters: None,
+        }
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl RocksDBConfig {
+    pub fn options(&self) -> Options {
+        let mut opts = Options::new();
+        opts.create_if_missing(true);
+        opts.set_max_open_files(self.max_open_files);
+        opts.set_compaction_style(self.compaction_style.to_rocksdb());
+
+        if self.block_cache_bytes.is_some() || self.bloom_filter_bits.is_some() {
+            // The bindings don't wrap block caches or filter policies, so the
+            // table options are built directly.  RocksDB copies the cache
+            // handle and takes ownership of the filter policy.
+            unsafe {
+                let table_opts = rocksdb_ffi::rocksdb_block_based_options_create();
+                if let Some(bytes) = self.block_cache_bytes {
+                    let cache = rocksdb_ffi::new_cache(bytes);
+                    rocksdb_ffi::rocksdb_block_based_options_set_block_cache(table_opts, cache);
+                    rocksdb_ffi::rocksdb_cache_destroy(cache);
+                }
+                if let Some(bits) = self.bloom_filter_bits {
+                    rocksdb_ffi::rocksdb_block_based_options_set_filter_policy(table_opts, rocksdb_ffi::new_bloom_filter(bits));
+                }
+                rocksdb_ffi::rocksdb_options_set_block_based_table_factory(opts.inner, table_opts);
+                rocksdb_ffi::rocksdb_block_based_options_destroy(table_opts);
+            }
+        }
+
+        opts
+    }
+}
+
+/// RocksDB compaction styles (see RocksDB's wiki for the tradeoffs)
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStyle {
+    /// Files are merged into successively larger levels.  Least space
+    /// overhead, most write amplification.
+    Level,
+    /// Files of similar size are merged together.  Less write amplification,
+    /// but up to double the space while compacting.
+    Universal,
+    /// The oldest files are dropped once the store exceeds its size limit.
+    /// Only suitable for stores which can lose data.
+    Fifo,
+}
+
+impl CompactionStyle {
+    pub fn parse(style: &str) -> Result<CompactionStyle, String> {
+        match style {
+            "level" => Ok(CompactionStyle::Level),
+            "universal" => Ok(CompactionStyle::Universal),
+            "fifo" => Ok(CompactionStyle::Fifo),
+            _ => Err(format!("Unsupported compaction style '{}' (expected level, universal or fifo)", style)),
+        }
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn to_rocksdb(&self) -> DBCompactionStyle {
+        match *self {
+            CompactionStyle::Level => DBCompactionStyle::DBLevelCompaction,
+            CompactionStyle::Universal => DBCompactionStyle::DBUniversalCompaction,
+            CompactionStyle::Fifo => DBCompactionStyle::DBFifoCompaction,
+        }
+    }
+}
+
+/// Fsyncs the write-ahead logs of the RocksDB store at `path`
+///
+/// RocksDB hands each write to the OS as it's made, so a crashed process
+/// loses nothing - but the rocksdb bindings don't expose synchronous writes,
+/// so anything not yet fsynced is lost if the machine goes down.  Syncing the
+/// log files (and the directory, in case a log was just created) closes that
+/// window.
+///
+pub fn sync_rocksdb(path: &Path) -> Result<(), String> {
+    let entries = try!(fs::read_dir(path).map_err(|e| format!("unable to list {:?}: {}", path, e)));
+
+    for entry in entries {
+        let entry_path = try!(entry.map_err(|e| format!("unable to list {:?}: {}", path, e))).path();
+        if entry_path.extension().map_or(false, |ext| ext == "log") {
+            let f = try!(fs::File::open(&entry_path).map_err(|e| format!("unable to open {:?}: {}", entry_path, e)));
+            try!(f.sync_all().map_err(|e| format!("unable to sync {:?}: {}", entry_path, e)));
+        }
+    }
+
+    let dir = try!(fs::File::open(path).map_err(|e| format!("unable to open {:?}: {}", path, e)));
+    dir.sync_all().map_err(|e| format!("unable to sync {:?}: {}", path, e))
 }
 
 /// Constructor for databases over common types
 ///
+/// See `builder::DbBuilder` for a checked alternative to calling `build`
+/// directly
+///
 pub trait Factory {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<Self>>;
+    /// Opens (or creates) a DB over `backend`
+    ///
+    /// Fails if its stores can't be opened, or with `Error::Unsupported` if
+    /// no window type covers `dimensions` at `tolerance`.
+    ///
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Self>>, Error>;
+
+    /// Like `build`, but fails with `Error::ParamsMismatch` rather than
+    /// opening stores created with a different dimensions or tolerance (see
+    /// `db::params`), and with `Error::VariantExplosion` if each insert would
+    /// exceed the default `VariantBudget`
+    ///
+    /// Use `builder::DbBuilder::variant_budget` to open a DB over budget.
+    ///
+    fn open(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Self>>, Error> where Self: Sized {
+        let estimate = builder::variant_estimate::<Self>(dimensions, tolerance, Self::strategy(), false);
+        try!(VariantBudget::default().check(estimate));
+        let params = params::Params{strategy: Self::strategy(), dimensions: dimensions, tolerance: tolerance};
+        try!(params::check(&backend, &params));
+        Self::build(dimensions, tolerance, backend)
+    }
+
+    /// Indexing strategy used by `build`
+    fn strategy() -> Strategy;
+
+    /// Largest number of dimensions `build` can index, if bounded
+    fn max_dimensions() -> Option<usize> { None }
+
+    /// Returns true if `build_id128` can identify values with 128-bit IDs
+    fn supports_id128() -> bool { false }
+
+    /// Like `build`, but identifying values with 128-bit IDs (see
+    /// `id_map::ID128`)
+    ///
+    /// Fails with `Error::Unsupported` unless `supports_id128` returns true.
+    ///
+    fn build_id128(_dimensions: usize, _tolerance: usize, _backend: StorageBackend) -> Result<Box<Database<Self>>, Error> {
+        Err(Error::Unsupported("128-bit identifiers aren't supported for this type".to_string()))
+    }
+
+    /// Returns true if `build_deletion` can index values by deletion, when
+    /// `strategy` is substitution
+    fn supports_deletion() -> bool { false }
+
+    /// Like `build`, but indexing binary values by deletion rather than
+    /// substitution
+    ///
+    /// Deletion skips the exact variant substitution writes for each
+    /// partition, but queries look up a variant per dimension rather than two
+    /// per partition.
+    ///
+    /// Fails with `Error::Unsupported` unless `supports_deletion` returns
+    /// true.
+    ///
+    fn build_deletion(_dimensions: usize, _tolerance: usize, _backend: StorageBackend) -> Result<Box<Database<Self>>, Error> {
+        Err(Error::Unsupported("deletion indexing isn't supported for this type".to_string()))
+    }
+
+    /// Returns true if `build_inline` can store values inline
+    fn supports_inline_values() -> bool { false }
+
+    /// Like `build`, but with each value as its own identifier, stored inline
+    /// in the variant store
+    ///
+    /// Query candidates are read straight from the variant store rather than
+    /// looked up by ID, at the cost of a copy of the value in every variant
+    /// entry, so this only pays off for small values.  Values of 64 bits or
+    /// less are always stored this way.
+    ///
+    /// Fails with `Error::Unsupported` unless `supports_inline_values`
+    /// returns true.
+    ///
+    fn build_inline(_dimensions: usize, _tolerance: usize, _backend: StorageBackend) -> Result<Box<Database<Self>>, Error> {
+        Err(Error::Unsupported("inline values aren't supported for this type".to_string()))
+    }
 }
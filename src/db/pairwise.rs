@@ -0,0 +1,137 @@
+//! Near-duplicates within a batch of values
+//!
+//! `pairwise` indexes a batch in a throwaway in-memory DB, queries it with
+//! each of the batch's values and reports which entries are within tolerance
+//! of one another - no namespace needs to be created or cleaned up.
+//!
+//! Results refer to values by their position in the batch, so exact
+//! duplicates (which the index would otherwise collapse) are reported too.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let found = pairwise(&[0b0000u64, 0b0001, 0b1110, 0b0000], 64, 1);
+//! assert_eq!(found.pairs, vec![(0, 1), (0, 3), (1, 3)]);
+//! assert_eq!(found.clusters, vec![vec![0, 1, 3]]);
+//! ```
+
+use std::hash::Hash;
+use std::cmp::{Eq, min, max};
+use std::clone::Clone;
+use std::collections::{HashMap, BTreeMap};
+
+use db::{Factory, StorageBackend};
+
+/// Near-duplicates found in a batch, as indices into the batch
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Pairwise {
+    /// Each pair of entries within tolerance, `(i, j)` with `i < j`, sorted
+    pub pairs: Vec<(usize, usize)>,
+    /// Connected groups of two or more entries, each sorted and ordered by
+    /// their first entry
+    ///
+    /// Entries in a cluster are linked by a chain of pairs, so two entries in
+    /// the same cluster may be further apart than `tolerance`.
+    pub clusters: Vec<Vec<usize>>,
+}
+
+/// Find entries of `values` within `tolerance` of each other
+///
+/// Panics if no window type covers `dimensions` at `tolerance`.
+///
+pub fn pairwise<T>(values: &[T], dimensions: usize, tolerance: usize) -> Pairwise
+where T: Factory + Clone + Eq + Hash,
+{
+    let mut positions: HashMap<T, Vec<usize>> = HashMap::new();
+    for (i, value) in values.iter().enumerate() {
+        positions.entry(value.clone()).or_insert_with(Vec::new).push(i);
+    }
+
+    // In-memory databases never fail
+    let mut db = T::build(dimensions, tolerance, StorageBackend::InMemory).unwrap();
+    for value in positions.keys() {
+        db.insert(value.clone()).unwrap();
+    }
+
+    let mut pairs = Vec::new();
+    for (value, value_positions) in positions.iter() {
+        let found = match db.get(value).unwrap() {
+            Some(found) => found,
+            None => continue,
+        };
+
+        for other in found.iter() {
+            let other_positions = &positions[other];
+            for &i in value_positions.iter() {
+                for &j in other_positions.iter() {
+                    if i < j {
+                        pairs.push((i, j));
+                    }
+                }
+            }
+        }
+    }
+    pairs.sort();
+
+    Pairwise {
+        clusters: clusters(values.len(), &pairs),
+        pairs: pairs,
+    }
+}
+
+/// Connected components of the graph of `pairs` with two or more members
+///
+fn clusters(n: usize, pairs: &[(usize, usize)]) -> Vec<Vec<usize>> {
+    let mut parents: Vec<usize> = (0..n).collect();
+
+    fn root(parents: &mut Vec<usize>, i: usize) -> usize {
+        let mut r = i;
+        while parents[r] != r {
+            r = parents[r];
+        }
+        // Path compression
+        let mut i = i;
+        while parents[i] != r {
+            let next = parents[i];
+            parents[i] = r;
+            i = next;
+        }
+        r
+    }
+
+    for &(i, j) in pairs.iter() {
+        let (ri, rj) = (root(&mut parents, i), root(&mut parents, j));
+        if ri != rj {
+            parents[max(ri, rj)] = min(ri, rj);
+        }
+    }
+
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..n {
+        let r = root(&mut parents, i);
+        groups.entry(r).or_insert_with(Vec::new).push(i);
+    }
+
+    groups.into_iter().map(|(_, group)| group).filter(|group| group.len() > 1).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use db::pairwise::*;
+
+    #[test]
+    fn finds_pairs_and_clusters() {
+        let found = pairwise(&[0b0000u64, 0b0001, 0b11110000, 0b0000, 0b11110001], 64, 1);
+
+        assert_eq!(found.pairs, vec![(0, 1), (0, 3), (1, 3), (2, 4)]);
+        assert_eq!(found.clusters, vec![vec![0, 1, 3], vec![2, 4]]);
+    }
+
+    #[test]
+    fn clusters_follow_chains() {
+        let found = pairwise(&[0b0000u64, 0b0001, 0b0011, 0b11110000], 64, 1);
+
+        assert_eq!(found.pairs, vec![(0, 1), (1, 2)]);
+        assert_eq!(found.clusters, vec![vec![0, 1, 2]]);
+    }
+}
@@ -0,0 +1,158 @@
+//! Parameters recorded in a database's stores
+//!
+//! A RocksDB store only makes sense to a DB with the strategy, dimensions and
+//! tolerance it was written with - opened with any others, its variant keys
+//! are silently misread and queries quietly miss values.  `check` records a
+//! DB's parameters in a reserved key of its variant store when the store is
+//! created, and refuses to open the store with different parameters after
+//! that.  Stores written before parameters were recorded adopt the first
+//! ones they're checked with.
+//!
+//! The reserved key is empty, so it sorts before every entry: reads seek to
+//! an entry's key and never reach it, and only scans from the start of the
+//! store (`MapSet::scan` & `sample`) need to skip it.
+//!
+//! In-memory & temporary stores never outlive their DB, and sled stores
+//! aren't checked, so only `StorageBackend::RocksDB` & `SharedRocksDB`
+//! record parameters.
+
+use std::fmt;
+use std::error;
+
+use db::StorageBackend;
+use db::builder::Strategy;
+#[cfg(feature = "rocksdb")]
+use db::column_family::Store;
+#[cfg(feature = "rocksdb")]
+use db::compaction::StoreOptions;
+#[cfg(feature = "rocksdb")]
+use db::encoding::{encode, decode};
+use error::Error;
+
+/// Key the parameters are stored under in the variant store
+pub const PARAMS_KEY: &'static [u8] = b"";
+
+/// What a DB's stores were built for
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Params {
+    pub strategy: Strategy,
+    pub dimensions: usize,
+    pub tolerance: usize,
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:?} indexing of {} dimensions within {}", self.strategy, self.dimensions, self.tolerance)
+    }
+}
+
+/// Returned when stores are opened with parameters other than the ones they
+/// were created with
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParamsMismatch {
+    pub stored: Params,
+    pub requested: Params,
+}
+
+impl fmt::Display for ParamsMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "stores were created for {}, not {}", self.stored, self.requested)
+    }
+}
+
+impl error::Error for ParamsMismatch {
+    fn description(&self) -> &str {
+        "stores were created with different parameters"
+    }
+}
+
+/// The variant store `backend` opens, if it records parameters
+///
+#[cfg(feature = "rocksdb")]
+fn variant_store(backend: &StorageBackend) -> Result<Option<Store>, Error> {
+    match *backend {
+        StorageBackend::RocksDB(ref path, ref rocksdb_config) => {
+            let store = try!(Store::dedicated(&path.join("map_set"), StoreOptions::new(rocksdb_config.options())));
+            Ok(Some(store))
+        },
+        StorageBackend::SharedRocksDB(ref shared, ref prefix, _) => {
+            let store = try!(Store::in_family(shared.clone(), &format!("{}/map_set", prefix)));
+            Ok(Some(store))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Records `params` in the stores of `backend`, or checks them against the
+/// parameters already recorded
+///
+/// A dedicated store is opened and closed again before returning, so the
+/// DB itself can be built from `backend` afterwards.
+///
+#[cfg(feature = "rocksdb")]
+pub fn check(backend: &StorageBackend, params: &Params) -> Result<(), Error> {
+    let store = match try!(variant_store(backend)) {
+        Some(store) => store,
+        None => return Ok(()),
+    };
+
+    let stored = try!(store.with(|view| view.get(PARAMS_KEY)));
+    match stored {
+        Some(encoded) => {
+            let stored: Params = try!(decode(&encoded));
+            if stored != *params {
+                return Err(Error::ParamsMismatch(ParamsMismatch{stored: stored, requested: *params}))
+            }
+            Ok(())
+        },
+        None => {
+            let encoded = try!(encode(params));
+            Ok(try!(store.with(|view| view.put(PARAMS_KEY, &encoded))))
+        },
+    }
+}
+
+/// Without RocksDB no backend records parameters, so there's nothing to check
+///
+#[cfg(not(feature = "rocksdb"))]
+pub fn check(_backend: &StorageBackend, _params: &Params) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, StorageBackend, RocksDBConfig, Strategy};
+    use db::builder::DbBuilder;
+    use db::params::{check, Params, ParamsMismatch};
+    use db::temp_dir::TempDir;
+    use error::Error;
+
+    fn params(dimensions: usize, tolerance: usize) -> Params {
+        Params{strategy: Strategy::Substitution, dimensions: dimensions, tolerance: tolerance}
+    }
+
+    #[test]
+    fn refuses_other_params() {
+        let dir = TempDir::new();
+        let backend = StorageBackend::RocksDB(dir.path().to_path_buf(), RocksDBConfig::default());
+
+        assert_eq!(check(&backend, &params(64, 4)), Ok(()));
+        assert_eq!(check(&backend, &params(64, 4)), Ok(()));
+        assert_eq!(check(&backend, &params(64, 6)), Err(Error::ParamsMismatch(ParamsMismatch{stored: params(64, 4), requested: params(64, 6)})));
+    }
+
+    #[test]
+    fn params_are_invisible_to_the_db() {
+        let dir = TempDir::new();
+        let backend = StorageBackend::RocksDB(dir.path().to_path_buf(), RocksDBConfig::default());
+
+        let mut db = DbBuilder::<u64>::new().dimensions(64).tolerance(4).backend(backend).build().unwrap();
+        db.insert(7).unwrap();
+        let mut values = Vec::new();
+        db.for_each_value(&mut |value| { values.push(value); Ok(()) }).unwrap();
+        assert_eq!(values, vec![7]);
+        assert!(db.health_check(10).findings.is_empty());
+    }
+}
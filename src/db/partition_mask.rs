@@ -0,0 +1,112 @@
+//! Queries which ignore some partitions
+//!
+//! When part of a query is known to be unreliable (OCR noise localized to one
+//! region of a fingerprint, say) the partitions covering it can be skipped.
+//! Skipped partitions aren't probed, and differences within them don't count
+//! toward the distance: a value matches a masked query if it's within
+//! tolerance of the query on the remaining partitions.
+//!
+//! Every indexed value within tolerance on the `P` remaining partitions
+//! matches at least one of them exactly or with a single difference as long as
+//! `2P > tolerance`, so at most `partitions - min_partitions(tolerance)`
+//! partitions can be skipped without missing results.  The accumulator's
+//! match threshold is derived from `P` the same way (see
+//! `satisfies_partition_matches_of`).
+
+use std::fmt;
+use std::error;
+
+use db::window::Window;
+
+/// Returned when a set of partitions to skip can't be honoured
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskError {
+    /// `partition` isn't one of the DB's `partitions` partitions
+    NoSuchPartition{partition: usize, partitions: usize},
+    /// Skipping leaves `remaining` partitions, fewer than the `required`
+    /// needed to find every match
+    TooFewPartitions{remaining: usize, required: usize},
+}
+
+impl fmt::Display for MaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            MaskError::NoSuchPartition{partition, partitions} => {
+                write!(f, "no partition {} (DB has {} partitions)", partition, partitions)
+            },
+            MaskError::TooFewPartitions{remaining, required} => {
+                write!(f,
+                    "only {} partitions would remain but {} are needed to find every match; skip fewer partitions or use a lower tolerance",
+                    remaining,
+                    required,
+                )
+            },
+        }
+    }
+}
+
+impl error::Error for MaskError {
+    fn description(&self) -> &str {
+        "invalid partitions to skip"
+    }
+}
+
+/// Fewest partitions a query at `tolerance` can be evaluated over without
+/// missing matches
+///
+pub fn min_partitions(tolerance: usize) -> usize {
+    tolerance / 2 + 1
+}
+
+/// The windows of `partitions` left after skipping the partitions at indices
+/// `skip`
+///
+pub fn active_windows(partitions: &[Window], skip: &[usize], tolerance: usize) -> Result<Vec<Window>, MaskError> {
+    if let Some(&partition) = skip.iter().find(|&&i| i >= partitions.len()) {
+        return Err(MaskError::NoSuchPartition{partition: partition, partitions: partitions.len()})
+    }
+
+    let active: Vec<Window> = partitions.iter()
+        .enumerate()
+        .filter(|&(i, _)| !skip.contains(&i))
+        .map(|(_, window)| window.clone())
+        .collect();
+
+    if active.len() < min_partitions(tolerance) {
+        return Err(MaskError::TooFewPartitions{remaining: active.len(), required: min_partitions(tolerance)})
+    }
+
+    Ok(active)
+}
+
+#[cfg(test)]
+mod test {
+    use db::window::Window;
+    use db::partition_mask::*;
+
+    fn windows(n: usize) -> Vec<Window> {
+        (0..n).map(|i| Window{start_dimension: 8 * i, dimensions: 8}).collect()
+    }
+
+    #[test]
+    fn skips_listed_partitions() {
+        let active = active_windows(&windows(4), &[1, 3], 2).unwrap();
+
+        assert_eq!(active, vec![
+                   Window{start_dimension: 0, dimensions: 8},
+                   Window{start_dimension: 16, dimensions: 8},
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_masks() {
+        assert_eq!(
+            active_windows(&windows(4), &[4], 2),
+            Err(MaskError::NoSuchPartition{partition: 4, partitions: 4})
+        );
+        assert_eq!(
+            active_windows(&windows(4), &[0, 1, 2], 5),
+            Err(MaskError::TooFewPartitions{remaining: 1, required: 3})
+        );
+    }
+}
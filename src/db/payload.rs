@@ -0,0 +1,351 @@
+//! Metadata stored alongside indexed values
+//!
+//! A `Payload` is a value paired with an arbitrary byte payload.  It's
+//! indexed, compared, hashed and identified by its value alone, so a database
+//! of `Payload<T>` keeps each payload in the value store entry its value is
+//! already stored in, and returns it from the same read that verifies a
+//! match.  Storing a payload costs no extra lookups, and a database on
+//! RocksDB persists payloads with its values (see the `PayloadU64*`
+//! typemaps).
+//!
+//! `PayloadDB` wraps such a database, inserting and querying plain values.
+//! Values inserted without a payload are returned with an empty payload.
+//!
+//! The HTTP server doesn't expose payloads: its namespaces, oplog, manifest
+//! and snapshots are all keyed on bare values, so payload namespaces would
+//! need a registry and wire format of their own.  Clients needing metadata
+//! from the server can keep it keyed by value.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db = PayloadDB::new(Payload::<u64>::build(64, 4, StorageBackend::TempRocksDB).unwrap());
+//!
+//! db.insert_with_payload(0b1111, b"doc-1".to_vec());
+//! db.get_with_payloads(&0b0111, &QueryOptions::default());
+//! // Some([(0b1111, b"doc-1")])
+//! ```
+
+use std::hash::{Hash, Hasher};
+use std::cmp::{Eq, Ordering};
+use std::collections::HashSet;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::{Database, QueryOptions, RemovalReport, Page, WarmupReport};
+use db::hamming::Hamming;
+use db::window::Windowable;
+use db::health::{HealthReport, RepairReport};
+use db::shadow::ShadowStats;
+use db::stats::Stats;
+use db::id_map::{ToID, IDStrategy};
+use db::hashing::State;
+use error::Error;
+
+/// A value and its payload
+///
+/// Only `value` is indexed - two payloads of the same value are the same key.
+/// Payloads are bytes unless another type is given (`CountedDB` keeps a
+/// `u64`).
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Payload<T, P = Vec<u8>> {
+    pub value: T,
+    pub payload: P,
+}
+
+impl<T, P> Payload<T, P> {
+    pub fn new(value: T, payload: P) -> Payload<T, P> {
+        Payload {
+            value: value,
+            payload: payload,
+        }
+    }
+}
+
+impl<T, P: Default> Payload<T, P> {
+    /// `value` with an empty payload
+    ///
+    pub fn bare(value: T) -> Payload<T, P> {
+        Payload::new(value, P::default())
+    }
+}
+
+impl<T: PartialEq, P> PartialEq for Payload<T, P> {
+    fn eq(&self, other: &Payload<T, P>) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<T: Eq, P> Eq for Payload<T, P> {}
+
+impl<T: Hash, P> Hash for Payload<T, P> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value.hash(state)
+    }
+}
+
+impl<T: PartialOrd, P> PartialOrd for Payload<T, P> {
+    fn partial_cmp(&self, other: &Payload<T, P>) -> Option<Ordering> {
+        self.value.partial_cmp(&other.value)
+    }
+}
+
+impl<T: Ord, P> Ord for Payload<T, P> {
+    fn cmp(&self, other: &Payload<T, P>) -> Ordering {
+        self.value.cmp(&other.value)
+    }
+}
+
+impl<T: Hamming, P> Hamming for Payload<T, P> {
+    fn hamming(&self, other: &Payload<T, P>) -> usize {
+        self.value.hamming(&other.value)
+    }
+
+    fn hamming_lte(&self, other: &Payload<T, P>, bound: usize) -> bool {
+        self.value.hamming_lte(&other.value, bound)
+    }
+
+    fn hamming_indices(&self, other: &Payload<T, P>) -> Vec<usize> {
+        self.value.hamming_indices(&other.value)
+    }
+}
+
+impl<T: Windowable<W>, W, P> Windowable<W> for Payload<T, P> {
+    fn window(&self, start_dimension: usize, dimensions: usize) -> W {
+        self.value.window(start_dimension, dimensions)
+    }
+}
+
+impl<T: ToID<u64>, P> ToID<u64> for Payload<T, P> {
+    fn to_id(self) -> u64 {
+        self.value.to_id()
+    }
+
+    fn to_id_with(self, strategy: IDStrategy) -> u64 {
+        self.value.to_id_with(strategy)
+    }
+
+    fn supports_id_strategy(strategy: IDStrategy) -> bool {
+        <T as ToID<u64>>::supports_id_strategy(strategy)
+    }
+}
+
+/// Payload-less values of `found`
+///
+fn values<T: Eq + Hash, P>(found: HashSet<Payload<T, P>, State>) -> HashSet<T, State> {
+    found.into_iter().map(|p| p.value).collect()
+}
+
+pub struct PayloadDB<T, P = Vec<u8>> {
+    db: Box<Database<Payload<T, P>>>,
+}
+
+impl<T, P> PayloadDB<T, P> where
+T: Sync + Send + Clone + Eq + Hash + ToID<u64>,
+P: Clone + Default,
+{
+    pub fn new(db: Box<Database<Payload<T, P>>>) -> PayloadDB<T, P> {
+        PayloadDB {
+            db: db,
+        }
+    }
+
+    /// Inserts `key`, replacing its payload if it was already present
+    ///
+    /// Returns true if `key` wasn't already present
+    ///
+    pub fn insert_with_payload(&mut self, key: T, payload: P) -> Result<bool, Error> {
+        self.db.insert(Payload::new(key, payload))
+    }
+
+    /// Returns the payload stored with `key`, if `key` is present
+    ///
+    pub fn payload(&self, key: &T) -> Result<Option<P>, Error> {
+        match try!(self.db.get_by_id(&key.clone().to_id())) {
+            Some(ref found) if found.value == *key => Ok(Some(found.payload.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Like `get_with`, returning each match with its payload
+    ///
+    pub fn get_with_payloads(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<(T, P)>>, Error> {
+        let found = try!(self.db.get_with(&Payload::bare(key.clone()), options));
+        Ok(found.map(|found| found.into_iter().map(|p| (p.value, p.payload)).collect()))
+    }
+
+    /// Like `bulk_insert`, storing each value with its payload
+    ///
+    pub fn bulk_insert_with_payloads(&mut self, entries: Vec<(T, P)>) -> Result<(), Error> {
+        self.db.bulk_insert(entries.into_iter().map(|(key, payload)| Payload::new(key, payload)).collect())
+    }
+
+    /// Stop wrapping the database
+    ///
+    pub fn into_inner(self) -> Box<Database<Payload<T, P>>> {
+        self.db
+    }
+}
+
+impl<T, P> Database<T> for PayloadDB<T, P> where
+T: Sync + Send + Clone + Eq + Hash + ToID<u64>,
+P: Sync + Send + Clone + Default + Serialize + DeserializeOwned,
+{
+    fn get_with(&self, key: &T, options: &QueryOptions) -> Result<Option<HashSet<T, State>>, Error> {
+        Ok(try!(self.db.get_with(&Payload::bare(key.clone()), options)).map(values))
+    }
+
+    fn get_many(&self, keys: &[T], options: &QueryOptions) -> Result<Vec<Option<HashSet<T, State>>>, Error> {
+        let keys: Vec<Payload<T, P>> = keys.iter().map(|key| Payload::bare(key.clone())).collect();
+        let found = try!(self.db.get_many(&keys, options));
+        Ok(found.into_iter().map(|found| found.map(values)).collect())
+    }
+
+    fn for_each_match(&self, key: &T, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(T)) -> Result<(), Error> where
+    T: Serialize + DeserializeOwned,
+    {
+        self.db.for_each_match(&Payload::bare(key.clone()), options, spill_threshold, &mut |p| f(p.value))
+    }
+
+    fn get_page_with(&self, key: &T, options: &QueryOptions, cursor: Option<&T>, limit: usize) -> Result<Page<T>, Error> {
+        let cursor = cursor.map(|cursor| Payload::bare(cursor.clone()));
+        let page = try!(self.db.get_page_with(&Payload::bare(key.clone()), options, cursor.as_ref(), limit));
+        Ok(Page {
+            values: page.values.into_iter().map(|p| p.value).collect(),
+            next: page.next.map(|p| p.value),
+        })
+    }
+
+    fn get_recent_with(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<T>>, Error> {
+        let found = try!(self.db.get_recent_with(&Payload::bare(key.clone()), options));
+        Ok(found.map(|found| found.into_iter().map(|p| p.value).collect()))
+    }
+
+    fn sort_by_recency(&self, found: HashSet<T, State>) -> Vec<T> {
+        let found = found.into_iter().map(Payload::bare).collect();
+        self.db.sort_by_recency(found).into_iter().map(|p| p.value).collect()
+    }
+
+    fn get_topk(&self, key: &T, k: usize) -> Result<Option<Vec<T>>, Error> {
+        let found = try!(self.db.get_topk(&Payload::bare(key.clone()), k));
+        Ok(found.map(|found| found.into_iter().map(|p| p.value).collect()))
+    }
+
+    fn estimate_candidates(&self, key: &T) -> Result<usize, Error> {
+        self.db.estimate_candidates(&Payload::bare(key.clone()))
+    }
+
+    fn health_check(&self, sample: usize) -> HealthReport {
+        self.db.health_check(sample)
+    }
+
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        self.db.verify_and_repair()
+    }
+
+    fn set_ignored_dimensions(&mut self, dimensions: Vec<usize>) -> Result<(), Error> {
+        self.db.set_ignored_dimensions(dimensions)
+    }
+
+    fn set_id_strategy(&mut self, strategy: IDStrategy) -> Result<(), Error> {
+        self.db.set_id_strategy(strategy)
+    }
+
+    fn set_compact_variants(&mut self) -> Result<(), Error> {
+        self.db.set_compact_variants()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        self.db.compact()
+    }
+
+    fn set_variant_cache(&mut self, sets: usize) -> Result<(), Error> {
+        self.db.set_variant_cache(sets)
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        self.db.warmup(fraction)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        self.db.stats()
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        self.db.shadow_stats()
+    }
+
+    /// Values inserted this way have an empty payload, replacing any they
+    /// had
+    ///
+    fn insert(&mut self, key: T) -> Result<bool, Error> {
+        self.db.insert(Payload::bare(key))
+    }
+
+    /// Values inserted this way have an empty payload, replacing any they
+    /// had
+    ///
+    fn bulk_insert(&mut self, keys: Vec<T>) -> Result<(), Error> {
+        self.db.bulk_insert(keys.into_iter().map(Payload::bare).collect())
+    }
+
+    fn remove(&mut self, key: &T) -> Result<bool, Error> {
+        self.db.remove(&Payload::bare(key.clone()))
+    }
+
+    fn remove_verified(&mut self, key: &T) -> Result<RemovalReport, Error> {
+        self.db.remove_verified(&Payload::bare(key.clone()))
+    }
+
+    fn get_by_id(&self, id: &u64) -> Result<Option<T>, Error> {
+        Ok(try!(self.db.get_by_id(id)).map(|p| p.value))
+    }
+
+    /// Payloads aren't passed to `f`, so aren't exported
+    ///
+    fn for_each_value(&self, f: &mut FnMut(T) -> Result<(), Error>) -> Result<(), Error> {
+        self.db.for_each_value(&mut |p| f(p.value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, Factory, QueryOptions, StorageBackend};
+    use db::payload::*;
+
+    #[test]
+    fn returns_payloads_with_matches() {
+        let mut db = PayloadDB::new(Payload::<u64>::build(64, 2, StorageBackend::InMemory).unwrap());
+
+        assert!(db.insert_with_payload(0b0011, b"a".to_vec()).unwrap());
+        assert!(db.insert(0b1100).unwrap());
+        assert!(!db.insert_with_payload(0b0011, b"b".to_vec()).unwrap());
+
+        let mut found = db.get_with_payloads(&0b0000, &QueryOptions::default()).unwrap().unwrap();
+        found.sort();
+        assert_eq!(found, vec![(0b0011, b"b".to_vec()), (0b1100, vec![])]);
+
+        db.remove(&0b0011).unwrap();
+        assert_eq!(db.payload(&0b0011), Ok(None));
+    }
+
+    #[test]
+    fn payloads_are_stored_in_the_value_store() {
+        let mut db = Payload::<u64>::build(64, 2, StorageBackend::TempRocksDB).unwrap();
+
+        db.insert(Payload::new(0b0011, b"a".to_vec())).unwrap();
+
+        // The entry read back by ID carries the payload
+        let found = db.get_by_id(&0b0011).unwrap().unwrap();
+        assert_eq!(found.payload, b"a".to_vec());
+
+        let found = db.get(&Payload::bare(0b0001)).unwrap().unwrap();
+        assert_eq!(found.into_iter().map(|p| p.payload).collect::<Vec<_>>(), vec![b"a".to_vec()]);
+    }
+}
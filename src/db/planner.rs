@@ -0,0 +1,251 @@
+//! Capacity planning
+//!
+//! Index size is driven almost entirely by the number of variants written per
+//! value, which depends only on the DB's settings.  `estimate` projects the
+//! size of an index holding `n_values` values without building it, using the
+//! same variant counts as `VariantBudget` and the key encoding of the chosen
+//! backend.
+//!
+//! Estimates assume every variant key is distinct (the worst case, and close
+//! to the truth for well-distributed fingerprints) and ignore compression and
+//! RocksDB's caches and write-ahead log, so treat them as an upper bound on
+//! steady-state size rather than an exact figure.
+//!
+//! `recommend` goes a step further and picks the settings themselves: the
+//! indexing strategy, the window type and whether the index fits in memory.
+//! `Factory::build` uses the same `window_bits` rule to choose a window type,
+//! so a plan always names a DB the factory can build.
+
+use std::mem;
+
+use db::StorageBackend;
+use db::builder::Strategy;
+use db::deletion::Dvec;
+use db::substitution;
+use db::variant_budget::VariantEstimate;
+use db::window::Window;
+
+/// Approximate per-entry overhead of `InMemoryHash` (hash table slots at
+/// typical load, set headers and hashes)
+pub const IN_MEMORY_ENTRY_OVERHEAD: usize = 64;
+
+/// Approximate per-entry overhead of RocksDB (sequence number, length
+/// prefixes and amortized block index)
+pub const ROCKSDB_ENTRY_OVERHEAD: usize = 12;
+
+/// Element width assumed by `estimate` for vectors indexed by deletion
+pub const DEFAULT_ELEMENT_BYTES: usize = 8;
+
+/// Widths of the fixed-size window types, narrowest first (`u8` through
+/// `[u64; 4]`)
+pub const WINDOW_BITS: [usize; 6] = [8, 16, 32, 64, 128, 256];
+
+/// Projected size of an index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SizeEstimate {
+    pub partitions: usize,
+    pub variants_per_value: usize,
+    /// Variant entries across all values
+    pub entries: u64,
+    /// Encoded size of a single variant entry, including store overhead
+    pub bytes_per_entry: usize,
+    /// Space used mapping IDs back to values
+    pub value_store_bytes: u64,
+    /// Projected resident memory
+    pub memory_bytes: u64,
+    /// Projected disk usage
+    pub disk_bytes: u64,
+}
+
+/// Estimate the size of an index of `n_values` values
+///
+/// Substitution `dimensions` are bits; deletion `dimensions` are vector
+/// elements, assumed to be `DEFAULT_ELEMENT_BYTES` wide.
+///
+pub fn estimate(dimensions: usize, tolerance: usize, strategy: Strategy, n_values: u64, backend: &StorageBackend) -> SizeEstimate {
+    estimate_with_element_bytes(dimensions, tolerance, strategy, n_values, backend, DEFAULT_ELEMENT_BYTES)
+}
+
+/// Like `estimate`, for deletion-indexed vectors of `element_bytes`-wide
+/// elements (ignored for substitution)
+///
+pub fn estimate_with_element_bytes(dimensions: usize, tolerance: usize, strategy: Strategy, n_values: u64, backend: &StorageBackend, element_bytes: usize) -> SizeEstimate {
+    let (entry_overhead, in_memory) = match *backend {
+        StorageBackend::InMemory => (IN_MEMORY_ENTRY_OVERHEAD, true),
+        #[cfg(feature = "rocksdb")]
+        StorageBackend::TempRocksDB | StorageBackend::RocksDB(..) | StorageBackend::SharedRocksDB(..) => (ROCKSDB_ENTRY_OVERHEAD, false),
+        // sled stores keys much as RocksDB does; close enough for planning
+        #[cfg(feature = "sled")]
+        StorageBackend::Sled(..) => (ROCKSDB_ENTRY_OVERHEAD, false),
+    };
+    estimate_stored(dimensions, tolerance, strategy, n_values, entry_overhead, in_memory, element_bytes)
+}
+
+/// Like `estimate_with_element_bytes`, for a store adding `entry_overhead`
+/// bytes to each entry and keeping them in memory or on disk
+///
+fn estimate_stored(dimensions: usize, tolerance: usize, strategy: Strategy, n_values: u64, entry_overhead: usize, in_memory: bool, element_bytes: usize) -> SizeEstimate {
+    let window_bytes = mem::size_of::<Window>();
+
+    // (variant bytes, ID bytes, value bytes, whether values are stored separately)
+    let (variant_bytes, id_bytes, value_bytes, value_store) = match strategy {
+        Strategy::Substitution => {
+            let value_bytes = (dimensions + 7) / 8;
+            // Values up to 64 bits are their own ID
+            let id_bytes = if dimensions <= 64 { value_bytes } else { mem::size_of::<u64>() };
+            // Key::Zero/Key::One tag
+            (mem::size_of::<u32>() + value_bytes, id_bytes, value_bytes, dimensions > 64)
+        },
+        Strategy::Deletion => {
+            (mem::size_of::<Dvec>(), mem::size_of::<u64>(), dimensions * element_bytes, true)
+        },
+    };
+
+    let variants = VariantEstimate::new(dimensions, tolerance, variant_bytes + id_bytes);
+    let entries = n_values.saturating_mul(variants.variants_per_insert as u64);
+
+    let bytes_per_entry = window_bytes + variant_bytes + id_bytes + entry_overhead;
+    let value_store_bytes = if value_store {
+        n_values.saturating_mul((id_bytes + value_bytes + entry_overhead) as u64)
+    } else {
+        0
+    };
+    let total = entries.saturating_mul(bytes_per_entry as u64).saturating_add(value_store_bytes);
+
+    SizeEstimate {
+        partitions: variants.partitions,
+        variants_per_value: variants.variants_per_insert,
+        entries: entries,
+        bytes_per_entry: bytes_per_entry,
+        value_store_bytes: value_store_bytes,
+        memory_bytes: if in_memory { total } else { 0 },
+        disk_bytes: if in_memory { 0 } else { total },
+    }
+}
+
+/// Where a plan keeps its index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Backend {
+    InMemory,
+    RocksDB,
+}
+
+/// Recommended settings for an index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Plan {
+    pub strategy: Strategy,
+    /// Width of the window type, or `None` if partitions are too wide for
+    /// any fixed-size window (only `Bits` indexes those)
+    pub window_bits: Option<usize>,
+    pub backend: Backend,
+    /// Projected size of the index on the recommended backend
+    pub estimate: SizeEstimate,
+}
+
+/// The narrowest fixed-size window holding `bits` bits
+///
+fn narrowest_window(bits: usize) -> Option<usize> {
+    WINDOW_BITS.iter().cloned().find(|&w| bits <= w)
+}
+
+/// Width of the window type a substitution DB needs to hold each partition
+/// of a `dimensions`-bit value
+///
+/// Returns `None` if partitions are wider than 256 bits.
+///
+pub fn window_bits(dimensions: usize, tolerance: usize) -> Option<usize> {
+    narrowest_window(substitution::partition_width(dimensions, tolerance))
+}
+
+/// Recommend settings for indexing `n_values` binary values of `dimensions`
+/// bits within `memory_budget` bytes of memory
+///
+pub fn recommend(dimensions: usize, tolerance: usize, n_values: u64, memory_budget: u64) -> Plan {
+    recommend_for_elements(dimensions, tolerance, 1, n_values, memory_budget)
+}
+
+/// Like `recommend`, for vectors of `dimensions` elements which are each
+/// `element_bits` wide
+///
+/// Binary values (`element_bits` of 1) are indexed by substitution and
+/// anything wider by deletion, whose window is a single element.  The index
+/// is kept in memory if its projected size fits `memory_budget`, and in
+/// RocksDB otherwise.
+///
+pub fn recommend_for_elements(dimensions: usize, tolerance: usize, element_bits: usize, n_values: u64, memory_budget: u64) -> Plan {
+    let (strategy, window_bits) = if element_bits <= 1 {
+        (Strategy::Substitution, window_bits(dimensions, tolerance))
+    } else {
+        (Strategy::Deletion, narrowest_window(element_bits))
+    };
+    let element_bytes = (element_bits + 7) / 8;
+
+    let in_memory = estimate_with_element_bytes(dimensions, tolerance, strategy, n_values, &StorageBackend::InMemory, element_bytes);
+    let (backend, estimate) = if in_memory.memory_bytes <= memory_budget {
+        (Backend::InMemory, in_memory)
+    } else {
+        (Backend::RocksDB, estimate_stored(dimensions, tolerance, strategy, n_values, ROCKSDB_ENTRY_OVERHEAD, false, element_bytes))
+    };
+
+    Plan {
+        strategy: strategy,
+        window_bits: window_bits,
+        backend: backend,
+        estimate: estimate,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::StorageBackend;
+    use db::builder::Strategy;
+    use db::planner::*;
+
+    #[test]
+    fn scales_with_values() {
+        let one = estimate(64, 4, Strategy::Substitution, 1, &StorageBackend::InMemory);
+        let many = estimate(64, 4, Strategy::Substitution, 1000, &StorageBackend::InMemory);
+
+        assert_eq!(one.partitions, 3);
+        assert_eq!(one.variants_per_value, 67);
+        assert_eq!(many.entries, 67000);
+        assert_eq!(many.memory_bytes, 1000 * one.memory_bytes);
+        assert_eq!(many.disk_bytes, 0);
+    }
+
+    #[test]
+    fn rocksdb_is_on_disk() {
+        let plan = estimate(16, 2, Strategy::Deletion, 1000, &StorageBackend::TempRocksDB);
+
+        assert_eq!(plan.memory_bytes, 0);
+        assert!(plan.disk_bytes > plan.value_store_bytes);
+        assert!(plan.value_store_bytes > 0);
+    }
+
+    #[test]
+    fn picks_narrowest_window() {
+        // 64 bits in 3 partitions of at most 22 bits
+        assert_eq!(window_bits(64, 4), Some(32));
+        assert_eq!(window_bits(64, 0), Some(64));
+        assert_eq!(window_bits(64, 20), Some(8));
+        assert_eq!(window_bits(256, 0), Some(256));
+        assert_eq!(window_bits(512, 0), None);
+    }
+
+    #[test]
+    fn recommends_backend_within_budget() {
+        let small = recommend(64, 4, 1000, 1 << 30);
+        assert_eq!(small.strategy, Strategy::Substitution);
+        assert_eq!(small.window_bits, Some(32));
+        assert_eq!(small.backend, Backend::InMemory);
+        assert!(small.estimate.memory_bytes <= 1 << 30);
+
+        let large = recommend(64, 4, 1000, 1024);
+        assert_eq!(large.backend, Backend::RocksDB);
+        assert_eq!(large.estimate.memory_bytes, 0);
+
+        let vectors = recommend_for_elements(16, 2, 32, 1000, 1 << 30);
+        assert_eq!(vectors.strategy, Strategy::Deletion);
+        assert_eq!(vectors.window_bits, Some(32));
+    }
+}
@@ -0,0 +1,146 @@
+//! Bit-level hamming distance over slices of words
+//!
+//! Verifying candidates is dominated by XOR-and-popcount loops, so for
+//! multi-word values (`[u64; 2]`, `[u64; 4]` and arbitrary slices) they're
+//! done here.  With the `simd` feature on x86_64, CPUs with AVX2 count 4 words
+//! at a time using a nibble lookup table (see Mula, Kurz & Lemire, "Faster
+//! Population Counts Using AVX2 Instructions"), and CPUs with POPCNT use the
+//! instruction directly.  The CPU is checked at runtime, so a binary built
+//! with the feature still runs on machines without them.  Without the feature
+//! (or on other architectures) `count_ones` is used, which the compiler only
+//! turns into POPCNT if the target guarantees it.
+//!
+//! Both slices are compared over the length of the shorter one.
+
+/// Words counted between bound checks by `hamming_lte`
+const LTE_BLOCK_WORDS: usize = 8;
+
+/// Number of bits which differ between `a` and `b`
+///
+pub fn hamming(a: &[u64], b: &[u64]) -> usize {
+    let len = if a.len() < b.len() { a.len() } else { b.len() };
+    let (a, b) = (&a[..len], &b[..len]);
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if len >= 4 && is_x86_feature_detected!("avx2") {
+            return unsafe { x86::hamming_avx2(a, b) }
+        }
+        if is_x86_feature_detected!("popcnt") {
+            return unsafe { x86::hamming_popcnt(a, b) }
+        }
+    }
+
+    hamming_scalar(a, b)
+}
+
+/// Returns true if no more than `bound` bits differ between `a` and `b`
+///
+/// Long slices are counted a block at a time, returning as soon as the
+/// distance exceeds `bound`.
+///
+pub fn hamming_lte(a: &[u64], b: &[u64], bound: usize) -> bool {
+    let len = if a.len() < b.len() { a.len() } else { b.len() };
+
+    let mut distance = 0;
+    let mut start = 0;
+    while start < len {
+        let end = if start + LTE_BLOCK_WORDS < len { start + LTE_BLOCK_WORDS } else { len };
+        distance += hamming(&a[start..end], &b[start..end]);
+        if distance > bound {
+            return false
+        }
+        start = end;
+    }
+    true
+}
+
+/// `hamming` without any CPU-specific instructions
+///
+pub fn hamming_scalar(a: &[u64], b: &[u64]) -> usize {
+    a.iter().zip(b.iter()).fold(0, |h, (&a, &b)| h + (a ^ b).count_ones() as usize)
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "popcnt")]
+    pub unsafe fn hamming_popcnt(a: &[u64], b: &[u64]) -> usize {
+        let mut total = 0;
+        for i in 0..a.len() {
+            total += _popcnt64((*a.get_unchecked(i) ^ *b.get_unchecked(i)) as i64) as usize;
+        }
+        total
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn hamming_avx2(a: &[u64], b: &[u64]) -> usize {
+        // Bits set in each nibble, for `_mm256_shuffle_epi8` to look up
+        let lookup = _mm256_setr_epi8(
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+        );
+        let low_mask = _mm256_set1_epi8(0x0f);
+        let zero = _mm256_setzero_si256();
+
+        let chunks = a.len() / 4;
+        let mut sums = zero;
+        for i in 0..chunks {
+            let va = _mm256_loadu_si256(a.as_ptr().add(i * 4) as *const __m256i);
+            let vb = _mm256_loadu_si256(b.as_ptr().add(i * 4) as *const __m256i);
+            let x = _mm256_xor_si256(va, vb);
+
+            let low = _mm256_and_si256(x, low_mask);
+            let high = _mm256_and_si256(_mm256_srli_epi16(x, 4), low_mask);
+            let counts = _mm256_add_epi8(_mm256_shuffle_epi8(lookup, low), _mm256_shuffle_epi8(lookup, high));
+
+            // Sum each 8 byte counts into its 64-bit lane
+            sums = _mm256_add_epi64(sums, _mm256_sad_epu8(counts, zero));
+        }
+
+        let mut lanes = [0u64; 4];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sums);
+        let mut total = (lanes[0] + lanes[1] + lanes[2] + lanes[3]) as usize;
+
+        for i in (chunks * 4)..a.len() {
+            total += (a[i] ^ b[i]).count_ones() as usize;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+
+    use db::popcount::{hamming, hamming_lte, hamming_scalar};
+
+    #[test]
+    fn hamming_matches_scalar() {
+        fn prop(a: Vec<u64>, b: Vec<u64>) -> bool {
+            hamming(&a, &b) == hamming_scalar(&a, &b)
+        }
+        quickcheck(prop as fn(Vec<u64>, Vec<u64>) -> bool);
+    }
+
+    #[test]
+    fn hamming_lte_matches_hamming() {
+        fn prop(a: Vec<u64>, b: Vec<u64>, bound: usize) -> bool {
+            hamming_lte(&a, &b, bound) == (hamming(&a, &b) <= bound)
+        }
+        quickcheck(prop as fn(Vec<u64>, Vec<u64>, usize) -> bool);
+    }
+
+    #[test]
+    fn hamming_counts_every_word() {
+        let a = [0u64; 9];
+        let b = [1u64, 0, 0, 0, 0, 0, 0, 0, !0];
+
+        assert_eq!(hamming(&a, &b), 65);
+        assert!(hamming_lte(&a, &b, 65));
+        assert!(!hamming_lte(&a, &b, 64));
+    }
+}
@@ -0,0 +1,159 @@
+//! Slow query logging
+//!
+//! Queries are logged (at warn level, through the `log` facade) when they
+//! take longer than the threshold set with `set_slow_query_threshold`.  The
+//! message breaks the query's time down by partition, which is usually enough
+//! to tell a dense bucket in one partition from a generally slow store.
+//!
+//! Nothing is timed until a threshold is set.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Slow query threshold in microseconds (0 if queries aren't timed)
+static SLOW_QUERY_MICROS: AtomicUsize = AtomicUsize::new(0);
+
+/// Log queries taking longer than `threshold`, or stop timing queries if
+/// it's `None`
+///
+/// Applies to every DB in the process.
+///
+pub fn set_slow_query_threshold(threshold: Option<Duration>) {
+    let micros = threshold.map_or(0, |t| micros(t).max(1));
+    SLOW_QUERY_MICROS.store(micros, Ordering::Relaxed);
+}
+
+/// The threshold set by `set_slow_query_threshold`, if any
+///
+pub fn slow_query_threshold() -> Option<Duration> {
+    match SLOW_QUERY_MICROS.load(Ordering::Relaxed) {
+        0 => None,
+        micros => Some(Duration::new((micros / 1000000) as u64, ((micros % 1000000) * 1000) as u32)),
+    }
+}
+
+fn micros(d: Duration) -> usize {
+    (d.as_secs() as usize).saturating_mul(1000000).saturating_add(d.subsec_nanos() as usize / 1000)
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1000000.0
+}
+
+/// Times a single query, partition by partition
+///
+/// A no-op unless a slow query threshold was set when it was started.
+///
+pub struct QueryTimer {
+    start: Option<Instant>,
+    lap: Option<Instant>,
+    /// Lookups made for every partition at once (ie batched variant reads)
+    shared: Duration,
+    partitions: Vec<Duration>,
+}
+
+impl QueryTimer {
+    /// Starts timing a query probing `partitions` partitions
+    ///
+    pub fn start(partitions: usize) -> QueryTimer {
+        if slow_query_threshold().is_none() {
+            return QueryTimer{start: None, lap: None, shared: Duration::new(0, 0), partitions: Vec::new()}
+        }
+
+        let now = Instant::now();
+        QueryTimer{
+            start: Some(now),
+            lap: Some(now),
+            shared: Duration::new(0, 0),
+            partitions: vec![Duration::new(0, 0); partitions],
+        }
+    }
+
+    /// Charges the time since the last lap to `partition`
+    ///
+    pub fn lap(&mut self, partition: usize) {
+        if let Some(elapsed) = self.elapsed_lap() {
+            if let Some(total) = self.partitions.get_mut(partition) {
+                *total += elapsed;
+            }
+        }
+    }
+
+    /// Charges the time since the last lap to work shared by every partition
+    ///
+    pub fn lap_shared(&mut self) {
+        if let Some(elapsed) = self.elapsed_lap() {
+            self.shared += elapsed;
+        }
+    }
+
+    /// Starts a new lap without charging the time since the last one, which
+    /// is counted as verification
+    ///
+    pub fn skip(&mut self) {
+        self.elapsed_lap();
+    }
+
+    fn elapsed_lap(&mut self) -> Option<Duration> {
+        self.lap.map(|lap| {
+            let now = Instant::now();
+            self.lap = Some(now);
+            now - lap
+        })
+    }
+
+    /// Logs the query if it took longer than the slow query threshold
+    ///
+    /// `db` describes the DB queried (its type & parameters).  Time not
+    /// charged to a partition was spent verifying candidates.
+    ///
+    pub fn finish<D: fmt::Display>(self, db: D) {
+        let (start, threshold) = match (self.start, slow_query_threshold()) {
+            (Some(start), Some(threshold)) => (start, threshold),
+            _ => return,
+        };
+
+        let elapsed = start.elapsed();
+        if elapsed <= threshold {
+            return
+        }
+
+        let probed = self.partitions.iter().fold(self.shared, |sum, &d| sum + d);
+        let verification = if elapsed > probed { elapsed - probed } else { Duration::new(0, 0) };
+        let partitions: Vec<String> = self.partitions.iter().map(|&d| format!("{:.2}", millis(d))).collect();
+
+        warn!(
+            "slow query on {}: {:.2}ms (partitions [{}]ms, shared lookups {:.2}ms, verification {:.2}ms)",
+            db,
+            millis(elapsed),
+            partitions.join(", "),
+            millis(self.shared),
+            millis(verification),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use db::query_log::{QueryTimer, set_slow_query_threshold, slow_query_threshold};
+
+    #[test]
+    fn threshold_round_trips() {
+        set_slow_query_threshold(Some(Duration::from_millis(250)));
+        assert_eq!(slow_query_threshold(), Some(Duration::from_millis(250)));
+
+        set_slow_query_threshold(None);
+        assert_eq!(slow_query_threshold(), None);
+
+        // An untimed query does nothing
+        let mut timer = QueryTimer::start(3);
+        timer.lap(0);
+        timer.lap_shared();
+        timer.skip();
+        assert!(timer.start.is_none());
+        timer.finish("test");
+    }
+}
@@ -0,0 +1,149 @@
+//! Read-only handles on a database as it was at a point in time
+//!
+//! `Database::snapshot` returns a `ReadOnly` over a copy of the database
+//! which later writes to it don't change: RocksDB stores read a RocksDB
+//! snapshot, and in-memory stores share their maps until they're next
+//! written (see `map_set::ShardedInMemoryHash`).  Handles are cheap to clone
+//! and can be queried from several threads, so a long query needn't hold the
+//! database's lock while it runs.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let snapshot = db.read().unwrap().snapshot().unwrap();
+//!
+//! // Writers can take the lock again while the snapshot is queried
+//! db.write().unwrap().insert(0b1111);
+//! snapshot.get(&0b0111);
+//! // Results from before the insert
+//! ```
+
+use std::hash::Hash;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::{Database, QueryOptions, Page, RemovalReport, WarmupReport};
+use db::health::{HealthReport, RepairReport};
+use db::id_map::IDStrategy;
+use db::shadow::ShadowStats;
+use db::stats::Stats;
+use db::hashing::State;
+use error::Error;
+
+/// A snapshot of a database, which fails every write
+///
+#[derive(Clone)]
+pub struct ReadOnly<T> {
+    db: Arc<Box<Database<T>>>,
+}
+
+impl<T> ReadOnly<T> {
+    /// Wrap `db`, which must not change once it's wrapped
+    ///
+    pub fn new(db: Box<Database<T>>) -> ReadOnly<T> {
+        ReadOnly{db: Arc::new(db)}
+    }
+}
+
+fn read_only() -> Error {
+    Error::Unsupported("snapshots are read-only".to_string())
+}
+
+impl<T> Database<T> for ReadOnly<T> where
+T: Sync + Send + Clone + Eq + Hash,
+{
+    fn get_with(&self, key: &T, options: &QueryOptions) -> Result<Option<HashSet<T, State>>, Error> {
+        self.db.get_with(key, options)
+    }
+
+    fn get_many(&self, keys: &[T], options: &QueryOptions) -> Result<Vec<Option<HashSet<T, State>>>, Error> {
+        self.db.get_many(keys, options)
+    }
+
+    fn for_each_match(&self, key: &T, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(T)) -> Result<(), Error> where
+    T: Serialize + DeserializeOwned,
+    {
+        self.db.for_each_match(key, options, spill_threshold, f)
+    }
+
+    fn get_page_with(&self, key: &T, options: &QueryOptions, cursor: Option<&T>, limit: usize) -> Result<Page<T>, Error> {
+        self.db.get_page_with(key, options, cursor, limit)
+    }
+
+    fn get_recent_with(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<T>>, Error> {
+        self.db.get_recent_with(key, options)
+    }
+
+    fn sort_by_recency(&self, found: HashSet<T, State>) -> Vec<T> {
+        self.db.sort_by_recency(found)
+    }
+
+    fn get_topk(&self, key: &T, k: usize) -> Result<Option<Vec<T>>, Error> {
+        self.db.get_topk(key, k)
+    }
+
+    fn estimate_candidates(&self, key: &T) -> Result<usize, Error> {
+        self.db.estimate_candidates(key)
+    }
+
+    fn health_check(&self, sample: usize) -> HealthReport {
+        self.db.health_check(sample)
+    }
+
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        Err(read_only())
+    }
+
+    fn set_ignored_dimensions(&mut self, _dimensions: Vec<usize>) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn set_id_strategy(&mut self, _strategy: IDStrategy) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        self.db.warmup(fraction)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        self.db.stats()
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        self.db.shadow_stats()
+    }
+
+    fn insert(&mut self, _key: T) -> Result<bool, Error> {
+        Err(read_only())
+    }
+
+    fn bulk_insert(&mut self, _keys: Vec<T>) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn remove(&mut self, _key: &T) -> Result<bool, Error> {
+        Err(read_only())
+    }
+
+    fn remove_verified(&mut self, _key: &T) -> Result<RemovalReport, Error> {
+        Err(read_only())
+    }
+
+    fn get_by_id(&self, id: &u64) -> Result<Option<T>, Error> {
+        self.db.get_by_id(id)
+    }
+
+    fn for_each_value(&self, f: &mut FnMut(T) -> Result<(), Error>) -> Result<(), Error> {
+        self.db.for_each_value(f)
+    }
+
+    /// The snapshot never changes, so it's its own snapshot
+    ///
+    fn snapshot(&self) -> Result<ReadOnly<T>, Error> {
+        Ok(self.clone())
+    }
+}
@@ -6,22 +6,154 @@ use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
 use db::hamming::*;
+use db::hashing::State;
+use db::sink::Sink;
+
+/// Collects the values found in each partition during a query
+///
+pub trait Accumulator<V> {
+    fn insert_zero_variant(&mut self, value: &V);
+    fn insert_one_variant(&mut self, value: &V);
+}
+
+/// Returns true if a candidate with `exact_matches` 0-matching partitions and
+/// `one_matches` 1-matching partitions may be within `tolerance` of the query
+///
+pub fn satisfies_partition_matches(tolerance: usize, exact_matches: usize, one_matches: usize) -> bool {
+    if tolerance % 2 == 0 {
+        // "If k is an even number, S must have at least one exact-matching
+        // partition, or two 1-matching partitions"
+        exact_matches >= 1 || one_matches >= 2
+    } else {
+        // "If k is an odd number, S must have at least two matching partitions
+        // where at least one of the matches should be an exact match, or S
+        // must have at least three 1-matching partitions"
+        (exact_matches >= 1 && (exact_matches + one_matches) >= 2) || one_matches >= 3
+    }
+}
+
+/// Like `satisfies_partition_matches`, for a query evaluated over `partitions`
+/// partitions
+///
+/// Every partition which isn't a 0- or 1-match holds at least two
+/// differences, so a candidate within `tolerance` must have
+/// `one_matches + 2 * (partitions - exact_matches - one_matches) <= tolerance`.
+/// For the partition count `DB::with_stores` chooses (when tolerance is no
+/// larger than the dimensions) this is equivalent to
+/// `satisfies_partition_matches`.
+///
+pub fn satisfies_partition_matches_of(partitions: usize, tolerance: usize, exact_matches: usize, one_matches: usize) -> bool {
+    let unmatched = partitions.saturating_sub(exact_matches + one_matches);
+
+    one_matches + 2 * unmatched <= tolerance
+}
 
 pub struct ResultAccumulator<V> {
     tolerance: usize,
     query: V,
-    candidates: HashMap<V, (usize, usize)>,
+    exclude_exact: bool,
+    approximate: bool,
+    partitions: Option<usize>,
+    candidates: HashMap<V, (usize, usize), State>,
 }
 
 impl<V> ResultAccumulator<V>
 where V: Hash + Eq + Clone + Hamming
 {
     pub fn new(tolerance: usize, query: V) -> ResultAccumulator<V> {
-        let candidates = HashMap::new();
-        return ResultAccumulator {tolerance: tolerance, query: query, candidates: candidates};
+        let candidates = HashMap::default();
+        return ResultAccumulator {tolerance: tolerance, query: query, exclude_exact: false, approximate: false, partitions: None, candidates: candidates};
+    }
+
+    /// Drop candidates equal to the query rather than returning them
+    ///
+    pub fn set_exclude_exact(&mut self, exclude_exact: bool) {
+        self.exclude_exact = exclude_exact;
+    }
+
+    /// Accept any candidate found in an exact-window bucket, for queries
+    /// which didn't probe the one-variant buckets
+    ///
+    /// The usual partition-match requirements count one-variant matches, so
+    /// they'd reject candidates which are within tolerance.
+    ///
+    pub fn set_approximate(&mut self, approximate: bool) {
+        self.approximate = approximate;
+    }
+
+    /// Require the matches needed when only `partitions` partitions were
+    /// probed, rather than the DB's full set
+    ///
+    pub fn set_partitions(&mut self, partitions: usize) {
+        self.partitions = Some(partitions);
     }
 
-    pub fn insert_zero_variant(&mut self, value: &V) {
+    fn satisfies(&self, exact_matches: usize, one_matches: usize) -> bool {
+        if self.approximate {
+            return exact_matches >= 1
+        }
+
+        match self.partitions {
+            Some(partitions) => satisfies_partition_matches_of(partitions, self.tolerance, exact_matches, one_matches),
+            None => satisfies_partition_matches(self.tolerance, exact_matches, one_matches),
+        }
+    }
+
+    pub fn found_values(&self) -> Option<HashSet<V, State>> {
+        let tolerance = self.tolerance;
+
+        self.found_values_with(|query, candidate| query.hamming_lte(candidate, tolerance))
+    }
+
+    /// Returns candidates satisfying the partition-match requirements for which
+    /// `verify(query, candidate)` is true
+    ///
+    pub fn found_values_with<F>(&self, verify: F) -> Option<HashSet<V, State>> where
+    F: Fn(&V, &V) -> bool,
+    {
+        let mut matches: HashSet<V, State> = HashSet::default();
+        self.drain_into_with(verify, &mut matches);
+
+        match matches.len() {
+            0 => return None,
+            _ => return Some(matches),
+        }
+    }
+
+    /// Pushes each candidate within `self.tolerance` of the query into `sink`
+    ///
+    pub fn drain_into<S: Sink<V>>(&self, sink: &mut S) {
+        let tolerance = self.tolerance;
+
+        self.drain_into_with(|query, candidate| query.hamming_lte(candidate, tolerance), sink)
+    }
+
+    /// Pushes each candidate satisfying the partition-match requirements for
+    /// which `verify(query, candidate)` is true into `sink`
+    ///
+    pub fn drain_into_with<F, S>(&self, verify: F, sink: &mut S) where
+    F: Fn(&V, &V) -> bool,
+    S: Sink<V>,
+    {
+        let exclude_exact = self.exclude_exact;
+        let verify = |query: &V, candidate: &V| {
+            !(exclude_exact && query == candidate) && verify(query, candidate)
+        };
+
+        for (candidate, &(exact_matches, one_matches)) in self.candidates.iter() {
+            if self.satisfies(exact_matches, one_matches) {
+                if verify(&self.query, candidate) {
+                    sink.push(candidate.clone());
+                }
+            }
+        }
+    }
+}
+
+impl<V> Accumulator<V> for ResultAccumulator<V>
+where V: Hash + Eq + Clone + Hamming
+{
+    fn insert_zero_variant(&mut self, value: &V) {
         match self.candidates.entry(value.clone()) {
             Occupied(mut entry) => {
                 let &(exact_matches, one_matches) = entry.get();
@@ -33,7 +165,7 @@ where V: Hash + Eq + Clone + Hamming
         }
     }
 
-    pub fn insert_one_variant(&mut self, value: &V) {
+    fn insert_one_variant(&mut self, value: &V) {
         match self.candidates.entry(value.clone()) {
             Occupied(mut entry) => {
                 let &(exact_matches, one_matches) = entry.get();
@@ -44,36 +176,4 @@ where V: Hash + Eq + Clone + Hamming
             }
         }
     }
-
-    pub fn found_values(&self) -> Option<HashSet<V>> {
-        let mut matches: HashSet<V> = HashSet::new();
-
-        if self.tolerance % 2 == 0 {
-            for (candidate, &(exact_matches, one_matches)) in self.candidates.iter() {
-                // "If k is an even number, S must have at least one exact-matching
-                // partition, or two 1-matching partitions"
-                if exact_matches >= 1 || one_matches >= 2 {
-                    if self.query.hamming_lte(candidate, self.tolerance) {
-                        matches.insert(candidate.clone());
-                    }
-                }
-            }
-        } else {
-            for (candidate, &(exact_matches, one_matches)) in self.candidates.iter() {
-                // "If k is an odd number, S must have at least two matching partitions
-                // where at least one of the matches should be an exact match, or S
-                // must have at least three 1-matching partitions"
-                if (exact_matches >= 1 && (exact_matches + one_matches) >= 2) || one_matches >= 3 {
-                    if self.query.hamming_lte(candidate, self.tolerance) {
-                        matches.insert(candidate.clone());
-                    }
-                }
-            }
-        }
-
-        match matches.len() {
-            0 => return None,
-            _ => return Some(matches),
-        }
-    }
 }
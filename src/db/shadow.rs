@@ -0,0 +1,355 @@
+//! Shadow execution of a candidate configuration
+//!
+//! `ShadowDB` wraps the database currently serving traffic (the primary) and a
+//! second, differently configured database (the candidate).  Writes go to
+//! both, queries run against both, and only the primary's results are
+//! returned.  Differences between the two are accumulated in `ShadowStats`,
+//! which makes it possible to see how a change of tolerance or strategy would
+//! affect real queries before switching over.
+//!
+//! The candidate only sees values written through the `ShadowDB`, so it should
+//! either start out with the same contents as the primary (`ShadowDB::mirror`
+//! copies them) or be compared only after a full reload.
+//!
+//! The server shadows the namespaces listed by the `shadows` setting (see
+//! `http::reload`), and reports their `ShadowStats` in `/metrics`.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let primary = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+//! let candidate = u64::build(64, 6, StorageBackend::InMemory).unwrap();
+//! let mut db = ShadowDB::new(primary, candidate);
+//!
+//! db.insert(0b1111);
+//! db.get(&0);
+//! println!("{:?}", db.stats());
+//! ```
+
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::{Database, QueryOptions, RemovalReport, Page, WarmupReport};
+use db::health::{HealthReport, RepairReport};
+use db::stats::Stats;
+use db::id_map::IDStrategy;
+use db::hashing::State;
+use error::Error;
+
+/// Values copied into the candidate per `bulk_insert` by `ShadowDB::mirror`
+const MIRROR_BATCH_SIZE: usize = 10000;
+
+/// Differences observed between primary and candidate
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShadowStats {
+    /// Number of queries run against both databases
+    pub queries: usize,
+    /// Queries whose results differed
+    pub mismatched_queries: usize,
+    /// Values found by the candidate but not the primary
+    pub extra_matches: usize,
+    /// Values found by the primary but not the candidate
+    pub missing_matches: usize,
+    /// Total time spent querying the primary
+    pub primary_time: Duration,
+    /// Total time spent querying the candidate
+    pub candidate_time: Duration,
+}
+
+pub struct ShadowDB<T> {
+    primary: Box<Database<T>>,
+    candidate: Box<Database<T>>,
+    stats: Mutex<ShadowStats>,
+}
+
+impl<T> ShadowDB<T> where
+T: Clone + Eq + Hash,
+{
+    pub fn new(primary: Box<Database<T>>, candidate: Box<Database<T>>) -> ShadowDB<T> {
+        ShadowDB {
+            primary: primary,
+            candidate: candidate,
+            stats: Mutex::new(Default::default()),
+        }
+    }
+
+    /// Insert every value of the primary into the candidate, returning the
+    /// number of values copied
+    ///
+    /// Fails if the primary can't enumerate its values (see
+    /// `Database::for_each_value`).
+    ///
+    pub fn mirror(&mut self) -> Result<usize, Error> {
+        let (primary, candidate) = (&self.primary, &mut self.candidate);
+        let mut batch = Vec::new();
+        let mut copied = 0;
+        try!(primary.for_each_value(&mut |value| {
+            batch.push(value);
+            if batch.len() >= MIRROR_BATCH_SIZE {
+                copied += batch.len();
+                try!(candidate.bulk_insert(batch.split_off(0)));
+            }
+            Ok(())
+        }));
+        copied += batch.len();
+        try!(candidate.bulk_insert(batch));
+
+        Ok(copied)
+    }
+
+    pub fn stats(&self) -> ShadowStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.lock().unwrap() = Default::default();
+    }
+
+    /// Stop shadowing, returning `(primary, candidate)`
+    ///
+    pub fn into_inner(self) -> (Box<Database<T>>, Box<Database<T>>) {
+        (self.primary, self.candidate)
+    }
+
+    /// Query both databases, returning the primary's results
+    ///
+    /// The candidate failing is the candidate's problem, so only the
+    /// primary's errors are returned; a failed candidate query isn't counted
+    ///
+    fn compare(&self, key: &T, options: &QueryOptions) -> Result<Option<HashSet<T, State>>, Error> {
+        let started = Instant::now();
+        let primary = try!(self.primary.get_with(key, options));
+        let primary_time = started.elapsed();
+
+        let started = Instant::now();
+        let candidate = match self.candidate.get_with(key, options) {
+            Ok(candidate) => candidate,
+            Err(_) => return Ok(primary),
+        };
+        let candidate_time = started.elapsed();
+
+        let empty = HashSet::default();
+        let (extra, missing) = {
+            let p = primary.as_ref().unwrap_or(&empty);
+            let c = candidate.as_ref().unwrap_or(&empty);
+            (c.difference(p).count(), p.difference(c).count())
+        };
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.queries += 1;
+        if extra > 0 || missing > 0 {
+            stats.mismatched_queries += 1;
+        }
+        stats.extra_matches += extra;
+        stats.missing_matches += missing;
+        stats.primary_time = stats.primary_time + primary_time;
+        stats.candidate_time = stats.candidate_time + candidate_time;
+
+        Ok(primary)
+    }
+}
+
+impl<T> Database<T> for ShadowDB<T> where
+T: Sync + Send + Clone + Eq + Hash,
+{
+    fn get_with(&self, key: &T, options: &QueryOptions) -> Result<Option<HashSet<T, State>>, Error> {
+        self.compare(key, options)
+    }
+
+    /// Bulk queries aren't compared, only answered by the primary
+    ///
+    fn for_each_match(&self, key: &T, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(T)) -> Result<(), Error> where
+    T: Serialize + DeserializeOwned,
+    {
+        self.primary.for_each_match(key, options, spill_threshold, f)
+    }
+
+    /// Recency ordering only applies to the primary; the candidate is compared
+    /// as an unordered query
+    ///
+    fn get_page_with(&self, key: &T, options: &QueryOptions, cursor: Option<&T>, limit: usize) -> Result<Page<T>, Error> {
+        self.primary.get_page_with(key, options, cursor, limit)
+    }
+
+    /// The primary's results are ordered once compared, rather than querying
+    /// it again
+    ///
+    fn get_recent_with(&self, key: &T, options: &QueryOptions) -> Result<Option<Vec<T>>, Error> {
+        let found = try!(self.compare(key, options));
+        Ok(found.map(|found| self.primary.sort_by_recency(found)))
+    }
+
+    fn sort_by_recency(&self, found: HashSet<T, State>) -> Vec<T> {
+        self.primary.sort_by_recency(found)
+    }
+
+    /// Only the primary is queried
+    ///
+    fn get_topk(&self, key: &T, k: usize) -> Result<Option<Vec<T>>, Error> {
+        self.primary.get_topk(key, k)
+    }
+
+    fn estimate_candidates(&self, key: &T) -> Result<usize, Error> {
+        self.primary.estimate_candidates(key)
+    }
+
+    /// Only the primary is checked
+    ///
+    fn health_check(&self, sample: usize) -> HealthReport {
+        self.primary.health_check(sample)
+    }
+
+    /// Both DBs are repaired; only the primary's repair is reported
+    ///
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        try!(self.candidate.verify_and_repair());
+        self.primary.verify_and_repair()
+    }
+
+    fn set_ignored_dimensions(&mut self, dimensions: Vec<usize>) -> Result<(), Error> {
+        try!(self.candidate.set_ignored_dimensions(dimensions.clone()));
+        self.primary.set_ignored_dimensions(dimensions)
+    }
+
+    fn set_id_strategy(&mut self, strategy: IDStrategy) -> Result<(), Error> {
+        try!(self.candidate.set_id_strategy(strategy));
+        self.primary.set_id_strategy(strategy)
+    }
+
+    fn set_compact_variants(&mut self) -> Result<(), Error> {
+        try!(self.candidate.set_compact_variants());
+        self.primary.set_compact_variants()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        try!(self.candidate.flush());
+        self.primary.flush()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        try!(self.candidate.compact());
+        self.primary.compact()
+    }
+
+    fn set_variant_cache(&mut self, sets: usize) -> Result<(), Error> {
+        try!(self.candidate.set_variant_cache(sets));
+        self.primary.set_variant_cache(sets)
+    }
+
+    /// Both DBs are queried, so both are warmed; only the primary's reads
+    /// are reported
+    ///
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        try!(self.candidate.warmup(fraction));
+        self.primary.warmup(fraction)
+    }
+
+    /// Only the primary is counted (`ShadowDB::stats` reports the comparison)
+    ///
+    fn stats(&self) -> Result<Stats, Error> {
+        self.primary.stats()
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        Some(self.stats())
+    }
+
+    fn insert(&mut self, key: T) -> Result<bool, Error> {
+        try!(self.candidate.insert(key.clone()));
+        self.primary.insert(key)
+    }
+
+    fn bulk_insert(&mut self, keys: Vec<T>) -> Result<(), Error> {
+        try!(self.candidate.bulk_insert(keys.clone()));
+        self.primary.bulk_insert(keys)
+    }
+
+    fn remove(&mut self, key: &T) -> Result<bool, Error> {
+        try!(self.candidate.remove(key));
+        self.primary.remove(key)
+    }
+
+    /// Only the primary's removal is reported
+    ///
+    fn remove_verified(&mut self, key: &T) -> Result<RemovalReport, Error> {
+        try!(self.candidate.remove_verified(key));
+        self.primary.remove_verified(key)
+    }
+
+    /// Values are looked up in the primary, and removed from both
+    ///
+    fn get_by_id(&self, id: &u64) -> Result<Option<T>, Error> {
+        self.primary.get_by_id(id)
+    }
+
+    /// Only the primary's values are enumerated (so only they're exported)
+    ///
+    fn for_each_value(&self, f: &mut FnMut(T) -> Result<(), Error>) -> Result<(), Error> {
+        self.primary.for_each_value(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, Factory, StorageBackend};
+    use db::shadow::*;
+
+    #[test]
+    fn returns_primary_results_and_counts_differences() {
+        let primary = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+        let candidate = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+        let mut db = ShadowDB::new(primary, candidate);
+
+        db.insert(0b0011).unwrap();
+        db.insert(0b1111).unwrap();
+
+        let found = db.get(&0b0000).unwrap().unwrap();
+        assert!(found.contains(&0b0011));
+        assert!(!found.contains(&0b1111));
+
+        db.get(&0b0011).unwrap();
+
+        let stats = db.stats();
+        assert_eq!(stats.queries, 2);
+        assert_eq!(stats.mismatched_queries, 1);
+        assert_eq!(stats.extra_matches, 1);
+        assert_eq!(stats.missing_matches, 0);
+
+        db.reset_stats();
+        assert_eq!(db.stats().queries, 0);
+    }
+
+    #[test]
+    fn recent_queries_are_compared_once() {
+        let primary = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+        let candidate = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+        let mut db = ShadowDB::new(primary, candidate);
+
+        db.insert(0b0011).unwrap();
+        db.insert(0b0001).unwrap();
+
+        let mut found = db.get_recent(&0b0000).unwrap().unwrap();
+        found.sort();
+        assert_eq!(found, vec![0b0001, 0b0011]);
+        assert_eq!(db.stats().queries, 1);
+        assert_eq!(Database::shadow_stats(&db), Some(db.stats()));
+    }
+
+    #[test]
+    fn mirror_copies_the_primary() {
+        let mut primary = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+        primary.insert(0b0011).unwrap();
+        let candidate = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+        let mut db = ShadowDB::new(primary, candidate);
+        assert_eq!(db.mirror().unwrap(), 1);
+
+        db.get(&0b0011).unwrap();
+        assert_eq!(db.stats().mismatched_queries, 0);
+    }
+}
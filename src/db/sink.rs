@@ -0,0 +1,165 @@
+//! Destinations for verified query results
+//!
+//! Accumulators decide *which* candidates are results; a `Sink` decides what
+//! happens to them.  Collecting, counting, streaming and ranking results all
+//! share the same verification pipeline by pushing into a different sink.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::hash::{Hash, BuildHasher};
+use std::sync::mpsc::Sender;
+
+use db::hamming::Hamming;
+
+/// Receives each verified result of a query
+///
+pub trait Sink<V> {
+    fn push(&mut self, value: V);
+}
+
+impl<V: Hash + Eq, S: BuildHasher> Sink<V> for HashSet<V, S> {
+    fn push(&mut self, value: V) {
+        self.insert(value);
+    }
+}
+
+/// Counts results without keeping them
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Count(pub usize);
+
+impl<V> Sink<V> for Count {
+    fn push(&mut self, _: V) {
+        self.0 += 1;
+    }
+}
+
+/// Sends results to a channel as they're verified
+///
+/// Results are silently dropped once the receiver hangs up
+///
+impl<V> Sink<V> for Sender<V> {
+    fn push(&mut self, value: V) {
+        let _ = self.send(value);
+    }
+}
+
+/// Calls a closure with each result
+///
+pub struct ForEach<F>(pub F);
+
+impl<V, F: FnMut(V)> Sink<V> for ForEach<F> {
+    fn push(&mut self, value: V) {
+        (self.0)(value)
+    }
+}
+
+struct Ranked<V> {
+    distance: usize,
+    value: V,
+}
+
+impl<V> PartialEq for Ranked<V> {
+    fn eq(&self, other: &Ranked<V>) -> bool {
+        self.distance == other.distance
+    }
+}
+
+impl<V> Eq for Ranked<V> {}
+
+impl<V> PartialOrd for Ranked<V> {
+    fn partial_cmp(&self, other: &Ranked<V>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<V> Ord for Ranked<V> {
+    fn cmp(&self, other: &Ranked<V>) -> Ordering {
+        self.distance.cmp(&other.distance)
+    }
+}
+
+/// Keeps the `k` results nearest to the query
+///
+/// Ties at the boundary are broken arbitrarily.  Only the results are
+/// bounded; the candidates verified into the sink are gathered by the query
+/// as usual.
+///
+pub struct TopK<V> {
+    query: V,
+    k: usize,
+    heap: BinaryHeap<Ranked<V>>,
+}
+
+impl<V: Hamming> TopK<V> {
+    pub fn new(query: V, k: usize) -> TopK<V> {
+        TopK {
+            query: query,
+            k: k,
+            heap: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    /// Returns the retained results and their distance from the query,
+    /// nearest first
+    ///
+    pub fn into_sorted_vec(self) -> Vec<(usize, V)> {
+        self.heap.into_sorted_vec().into_iter().map(|r| (r.distance, r.value)).collect()
+    }
+}
+
+impl<V: Hamming> Sink<V> for TopK<V> {
+    fn push(&mut self, value: V) {
+        if self.k == 0 {
+            return
+        }
+
+        let distance = self.query.hamming(&value);
+        if self.heap.len() == self.k {
+            match self.heap.peek() {
+                Some(furthest) if furthest.distance <= distance => return,
+                _ => {},
+            }
+            self.heap.pop();
+        }
+
+        self.heap.push(Ranked{distance: distance, value: value});
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use db::sink::*;
+
+    #[test]
+    fn count_counts() {
+        let mut count = Count::default();
+        for v in 0..10u64 {
+            count.push(v);
+        }
+
+        assert_eq!(count, Count(10));
+    }
+
+    #[test]
+    fn sender_streams() {
+        let (mut tx, rx) = channel();
+        tx.push(1u64);
+        tx.push(2u64);
+        drop(tx);
+
+        assert_eq!(rx.iter().collect::<Vec<u64>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn top_k_keeps_nearest() {
+        let mut top = TopK::new(0u64, 2);
+        for v in vec![0b0111u64, 0b0001, 0b0011, 0b1111] {
+            top.push(v);
+        }
+
+        assert_eq!(top.into_sorted_vec(), vec![(1, 0b0001), (2, 0b0011)]);
+    }
+}
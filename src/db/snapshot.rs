@@ -0,0 +1,266 @@
+//! Portable snapshots of a database's values
+//!
+//! `Database::export` writes every indexed value to a stream, and
+//! `Database::import` inserts every value from one.  Only values are written -
+//! variants are rebuilt as each value is inserted - so a snapshot can be
+//! loaded into a database with a different backend (ie to move an in-memory
+//! namespace onto RocksDB) or on another machine.  The dimensions & tolerance
+//! are the importing database's; the values must be of the same type.
+//!
+//! A snapshot is the bytes `MAGIC`, the format version as a bincode `u32`,
+//! then each value as a bincode `Some(value)` followed by a single `None`.  The
+//! trailing `None` means importing a truncated snapshot returns an error
+//! rather than appearing to succeed - values inserted before the truncation
+//! is found remain inserted.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut file = File::create("/tmp/snapshot").unwrap();
+//! let exported = in_memory_db.export(&mut file).unwrap();
+//!
+//! let mut file = File::open("/tmp/snapshot").unwrap();
+//! let imported = rocksdb_db.import(&mut file).unwrap();
+//! ```
+
+use std::hash::Hash;
+#[cfg(not(feature = "rocksdb"))]
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::Database;
+use db::encoding::{encode_into, decode_from};
+use db::map_set::MapSet;
+use db::id_map::IDMap;
+#[cfg(feature = "rocksdb")]
+use db::result_accumulator::Accumulator;
+#[cfg(feature = "rocksdb")]
+use db::spilling_accumulator::SpillingAccumulator;
+use db::window::Window;
+#[cfg(not(feature = "rocksdb"))]
+use db::hashing::State;
+use error::Error;
+
+/// Leading bytes of every snapshot
+pub const MAGIC: &'static [u8] = b"HAMMERSNAP";
+
+/// Snapshot format version written by `export`
+pub const VERSION: u32 = 1;
+
+/// Values handed to `Database::bulk_insert` at once during an import
+pub const IMPORT_BATCH: usize = 1000;
+
+/// IDs held in memory while enumerating values, beyond which the rest are
+/// spilled to a temporary RocksDB
+pub const SPILL_THRESHOLD: usize = 1000000;
+
+/// Calls `f` with every value referenced by `partition`'s entries in
+/// `variant_store`
+///
+/// Every value has at least one entry in each partition, so reading a single
+/// partition finds every value.  Entries whose ID doesn't resolve (see
+/// `db::health`) are skipped.  At most `SPILL_THRESHOLD` IDs are held in
+/// memory while the partition is read.
+///
+pub fn scan_values<K, ID, T, V, I, F>(variant_store: &V, value_store: &I, partition: &Window, window: F, f: &mut FnMut(T) -> Result<(), Error>) -> Result<(), Error> where
+K: Clone + Eq + Hash,
+ID: Clone + Eq + Hash + Serialize + DeserializeOwned,
+V: MapSet<K, ID>,
+I: IDMap<ID, T>,
+F: Fn(&K) -> &Window,
+{
+    for_each_id(variant_store, partition, window, &mut |id| {
+        match try!(value_store.get(id)) {
+            Some(value) => f(value),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Calls `f` once with each ID referenced by `partition`'s entries
+///
+#[cfg(feature = "rocksdb")]
+fn for_each_id<K, ID, V, F>(variant_store: &V, partition: &Window, window: F, f: &mut FnMut(ID) -> Result<(), Error>) -> Result<(), Error> where
+K: Clone + Eq + Hash,
+ID: Clone + Eq + Hash + Serialize + DeserializeOwned,
+V: MapSet<K, ID>,
+F: Fn(&K) -> &Window,
+{
+    // Only an ID's presence matters, so every entry counts as an exact match
+    // and any ID counted at all is found
+    let mut ids: Option<SpillingAccumulator<ID>> = None;
+    try!(variant_store.scan(&mut |key, id| {
+        if window(key) == partition {
+            ids.get_or_insert_with(|| {
+                let mut ids = SpillingAccumulator::new(0, id.clone(), SPILL_THRESHOLD);
+                ids.set_approximate(true);
+                ids
+            }).insert_zero_variant(id);
+        }
+    }));
+
+    let mut result = Ok(());
+    if let Some(ids) = ids {
+        try!(ids.for_each_found(|_, _| true, |id| if result.is_ok() {
+            result = f(id);
+        }));
+    }
+    result
+}
+
+#[cfg(not(feature = "rocksdb"))]
+fn for_each_id<K, ID, V, F>(variant_store: &V, partition: &Window, window: F, f: &mut FnMut(ID) -> Result<(), Error>) -> Result<(), Error> where
+K: Clone + Eq + Hash,
+ID: Clone + Eq + Hash,
+V: MapSet<K, ID>,
+F: Fn(&K) -> &Window,
+{
+    let mut ids: HashSet<ID, State> = HashSet::default();
+    try!(variant_store.scan(&mut |key, id| {
+        if window(key) == partition {
+            ids.insert(id.clone());
+        }
+    }));
+
+    for id in ids.into_iter() {
+        try!(f(id));
+    }
+    Ok(())
+}
+
+/// Writes every value in `db` to `writer`
+///
+/// Returns the number of values written
+///
+pub fn export<T, D>(db: &D, mut writer: &mut Write) -> Result<usize, Error> where
+T: Serialize,
+D: Database<T> + ?Sized,
+{
+    try!(writer.write_all(MAGIC).map_err(|e| Error::Storage(format!("unable to write snapshot: {}", e))));
+    try!(encode_into(&VERSION, &mut writer));
+
+    let mut count = 0;
+    try!(db.for_each_value(&mut |value| {
+        try!(encode_into(&Some(value), &mut writer));
+        count += 1;
+        Ok(())
+    }));
+    try!(encode_into(&None::<T>, &mut writer));
+
+    Ok(count)
+}
+
+/// Inserts every value in the snapshot read from `reader` into `db`
+///
+/// Returns the number of values read
+///
+pub fn import<T, D>(db: &mut D, reader: &mut Read) -> Result<usize, Error> where
+T: DeserializeOwned,
+D: Database<T> + ?Sized,
+{
+    import_with(db, reader, |_| Ok(()))
+}
+
+/// Like `import`, failing at the first value `check` rejects
+///
+/// Values before the rejected one remain inserted.
+///
+pub fn import_with<T, D, F>(db: &mut D, mut reader: &mut Read, check: F) -> Result<usize, Error> where
+T: DeserializeOwned,
+D: Database<T> + ?Sized,
+F: Fn(&T) -> Result<(), Error>,
+{
+    let mut magic = vec![0; MAGIC.len()];
+    try!(reader.read_exact(&mut magic).map_err(|e| Error::Invalid(format!("unable to read snapshot header: {}", e))));
+    if &magic[..] != MAGIC {
+        return Err(Error::Invalid("not a snapshot".to_string()))
+    }
+
+    let version: u32 = try!(decode_from(&mut reader).map_err(malformed));
+    if version != VERSION {
+        return Err(Error::Invalid(format!("snapshot version {} (expected {})", version, VERSION)))
+    }
+
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(IMPORT_BATCH);
+    loop {
+        let next: Option<T> = try!(decode_from(&mut reader).map_err(malformed));
+        match next {
+            Some(value) => {
+                try!(check(&value));
+                batch.push(value);
+            },
+            None => break,
+        }
+        count += 1;
+
+        if batch.len() == IMPORT_BATCH {
+            try!(db.bulk_insert(batch));
+            batch = Vec::with_capacity(IMPORT_BATCH);
+        }
+    }
+    try!(db.bulk_insert(batch));
+
+    Ok(count)
+}
+
+/// Snapshots come from the caller, so an undecodable one is their fault
+/// rather than the store's (unlike entries read by `encoding::decode`)
+///
+fn malformed(e: Error) -> Error {
+    match e {
+        Error::Corrupt(e) => Error::Invalid(format!("malformed snapshot: {}", e)),
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, Factory, StorageBackend};
+    use db::snapshot::MAGIC;
+    use error::Error;
+
+    #[test]
+    fn round_trips_between_backends() {
+        let mut from = u64::build(64, 4, StorageBackend::InMemory).unwrap();
+        for i in 0..2500u64 {
+            from.insert(i * 0x0101_0101).unwrap();
+        }
+
+        let mut snapshot = vec![];
+        assert_eq!(from.export(&mut snapshot).unwrap(), 2500);
+        assert!(snapshot.starts_with(MAGIC));
+
+        let mut to = u64::build(64, 4, StorageBackend::TempRocksDB).unwrap();
+        assert_eq!(to.import(&mut &snapshot[..]).unwrap(), 2500);
+
+        for i in 0..2500u64 {
+            assert_eq!(to.get_by_id(&(i * 0x0101_0101)).unwrap(), Some(i * 0x0101_0101));
+        }
+        assert!(to.get(&0).unwrap().unwrap().contains(&0x0101_0101));
+    }
+
+    #[test]
+    fn rejects_truncated_snapshots() {
+        let mut from = Vec::<u64>::build(4, 1, StorageBackend::InMemory).unwrap();
+        from.insert(vec![1, 2, 3, 4]).unwrap();
+
+        let mut snapshot = vec![];
+        assert_eq!(from.export(&mut snapshot).unwrap(), 1);
+
+        let mut to = Vec::<u64>::build(4, 1, StorageBackend::InMemory).unwrap();
+        let truncated = &snapshot[..snapshot.len() - 1];
+        match to.import(&mut &truncated[..]) {
+            Err(Error::Invalid(..)) => {},
+            other => panic!("expected an invalid snapshot, got {:?}", other),
+        }
+
+        match to.import(&mut &b"garbage"[..]) {
+            Err(Error::Invalid(..)) => {},
+            other => panic!("expected an invalid snapshot, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Result accumulation with bounded memory
+//!
+//! `ResultAccumulator` keeps every candidate in memory, which is fine for
+//! interactive queries but not for analytical ones over very dense
+//! neighborhoods.  `SpillingAccumulator` keeps at most `threshold` candidates
+//! in memory; beyond that, they're written to a temporary RocksDB as a sorted
+//! run of partition-match counts, and the runs are merged by a single scan
+//! once the query completes.
+//!
+//! Spilled results are streamed to a callback rather than collected, so the
+//! caller also controls how much of the output is held at once.  Spilling
+//! fails with `Error::Storage` rather than panicking, so a full temp disk
+//! fails the query instead of the process.
+
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::clone::Clone;
+use std::collections::HashMap;
+
+use rocksdb::{DB, Writable, WriteBatch, IteratorMode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use db::encoding::{encode, decode};
+use db::hashing::State;
+use db::temp_dir::TempDir;
+use db::result_accumulator::{Accumulator, satisfies_partition_matches};
+use db::sink::{Sink, ForEach};
+use error::Error;
+
+// Bytes of the run number following each spilled candidate
+const RUN_BYTES: usize = 8;
+
+pub struct SpillingAccumulator<V> {
+    tolerance: usize,
+    query: V,
+    exclude_exact: bool,
+    approximate: bool,
+    threshold: usize,
+    candidates: HashMap<V, (usize, usize), State>,
+    // The DB is dropped before its directory is removed
+    spill: Option<(DB, TempDir)>,
+    runs: u64,
+    // The first failed spill, returned once the results are drained
+    failed: Option<Error>,
+}
+
+impl<V> SpillingAccumulator<V>
+where V: Hash + Eq + Clone + Serialize + DeserializeOwned
+{
+    /// Accumulator holding at most `threshold` candidates in memory
+    ///
+    pub fn new(tolerance: usize, query: V, threshold: usize) -> SpillingAccumulator<V> {
+        SpillingAccumulator {
+            tolerance: tolerance,
+            query: query,
+            exclude_exact: false,
+            approximate: false,
+            threshold: threshold,
+            candidates: HashMap::default(),
+            spill: None,
+            runs: 0,
+            failed: None,
+        }
+    }
+
+    /// Drop candidates equal to the query rather than returning them
+    ///
+    pub fn set_exclude_exact(&mut self, exclude_exact: bool) {
+        self.exclude_exact = exclude_exact;
+    }
+
+    /// Accept any candidate found in an exact-window bucket (see
+    /// `ResultAccumulator::set_approximate`)
+    ///
+    pub fn set_approximate(&mut self, approximate: bool) {
+        self.approximate = approximate;
+    }
+
+    /// Returns true if any candidates have been written to disk
+    ///
+    pub fn spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    fn increment(&mut self, value: &V, exact: usize, one: usize) {
+        // Once a spill has failed the query's results are lost, so there's
+        // no point counting more of them
+        if self.failed.is_some() {
+            return
+        }
+
+        {
+            let counts = self.candidates.entry(value.clone()).or_insert((0, 0));
+            counts.0 += exact;
+            counts.1 += one;
+        }
+
+        if self.candidates.len() > self.threshold {
+            if let Err(e) = self.flush() {
+                self.candidates.clear();
+                self.failed = Some(e);
+            }
+        }
+    }
+
+    /// Writes the in-memory counts to the spill DB as a new run
+    ///
+    /// Each candidate is keyed by its encoding followed by the run number, so
+    /// writing never needs to read the counts of earlier runs.  Encodings are
+    /// self-delimiting, so no candidate's encoding is a prefix of another's,
+    /// and a candidate's runs sort next to each other.
+    ///
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.spill.is_none() {
+            let dir = TempDir::new();
+            let db = try!(DB::open_default(dir.to_str()));
+            self.spill = Some((db, dir));
+        }
+
+        // Big-endian, so runs sort in the order they were written
+        let run: Vec<u8> = (0..RUN_BYTES).rev().map(|i| (self.runs >> (8 * i)) as u8).collect();
+
+        let batch = WriteBatch::new();
+        for (value, (exact, one)) in self.candidates.drain() {
+            let mut key = try!(encode(&value));
+            key.extend_from_slice(&run);
+            try!(batch.put(&key, &try!(encode(&(exact as u64, one as u64)))));
+        }
+
+        let &(ref db, _) = self.spill.as_ref().unwrap();
+        try!(db.write(batch));
+        self.runs += 1;
+        Ok(())
+    }
+
+    /// Calls `f` with each candidate satisfying the partition-match
+    /// requirements for which `verify(query, candidate)` is true
+    ///
+    pub fn for_each_found<F, G>(self, verify: F, f: G) -> Result<(), Error> where
+    F: Fn(&V, &V) -> bool,
+    G: FnMut(V),
+    {
+        self.drain_into_with(verify, &mut ForEach(f))
+    }
+
+    /// Pushes each candidate satisfying the partition-match requirements for
+    /// which `verify(query, candidate)` is true into `sink`
+    ///
+    /// Fails if any candidates couldn't be spilled or read back, in which
+    /// case some of the matches may already have been pushed.
+    ///
+    pub fn drain_into_with<F, S>(mut self, verify: F, sink: &mut S) -> Result<(), Error> where
+    F: Fn(&V, &V) -> bool,
+    S: Sink<V>,
+    {
+        if let Some(e) = self.failed.take() {
+            return Err(e)
+        }
+
+        let tolerance = self.tolerance;
+        let exclude_exact = self.exclude_exact;
+        let approximate = self.approximate;
+        let query = self.query.clone();
+        let mut emit = |candidate: V, exact_matches: usize, one_matches: usize| {
+            let satisfied = match approximate {
+                true => exact_matches >= 1,
+                false => satisfies_partition_matches(tolerance, exact_matches, one_matches),
+            };
+
+            if satisfied &&
+                !(exclude_exact && query == candidate) &&
+                verify(&query, &candidate) {
+                sink.push(candidate)
+            }
+        };
+
+        if self.spill.is_none() {
+            for (candidate, (exact_matches, one_matches)) in self.candidates.drain() {
+                emit(candidate, exact_matches, one_matches);
+            }
+            return Ok(())
+        }
+
+        try!(self.flush());
+        let &(ref db, _) = self.spill.as_ref().unwrap();
+
+        // Sums each candidate's counts across its (adjacent) runs
+        let mut current: Option<(Vec<u8>, u64, u64)> = None;
+        for (k, v) in db.iterator(IteratorMode::Start) {
+            if k.len() < RUN_BYTES {
+                return Err(Error::Corrupt(format!("spilled key of {} bytes", k.len())))
+            }
+            let encoded = &k[..k.len() - RUN_BYTES];
+            let (exact, one): (u64, u64) = try!(decode(&v));
+
+            match current {
+                Some((ref prior, ref mut prior_exact, ref mut prior_one)) if &prior[..] == encoded => {
+                    *prior_exact += exact;
+                    *prior_one += one;
+                    continue
+                },
+                _ => {},
+            }
+
+            if let Some((prior, prior_exact, prior_one)) = current.take() {
+                emit(try!(decode(&prior)), prior_exact as usize, prior_one as usize);
+            }
+            current = Some((encoded.to_vec(), exact, one));
+        }
+
+        if let Some((prior, prior_exact, prior_one)) = current {
+            emit(try!(decode(&prior)), prior_exact as usize, prior_one as usize);
+        }
+        Ok(())
+    }
+}
+
+impl<V> Accumulator<V> for SpillingAccumulator<V>
+where V: Hash + Eq + Clone + Serialize + DeserializeOwned
+{
+    fn insert_zero_variant(&mut self, value: &V) {
+        self.increment(value, 1, 0)
+    }
+
+    fn insert_one_variant(&mut self, value: &V) {
+        self.increment(value, 0, 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use db::hamming::Hamming;
+    use db::result_accumulator::Accumulator;
+    use db::spilling_accumulator::*;
+    use db::hashing::State;
+
+    fn found(acc: SpillingAccumulator<u64>) -> HashSet<u64, State> {
+        let mut out = HashSet::default();
+        acc.for_each_found(|q, c| q.hamming_lte(c, 2), |v| { out.insert(v); }).unwrap();
+        out
+    }
+
+    #[test]
+    fn matches_in_memory_results_after_spilling() {
+        let mut small = SpillingAccumulator::new(2, 0u64, 2);
+        let mut large = SpillingAccumulator::new(2, 0u64, 1000);
+
+        for acc in vec![&mut small, &mut large] {
+            for v in 0..16u64 {
+                acc.insert_one_variant(&v);
+            }
+            for v in 0..16u64 {
+                acc.insert_one_variant(&v);
+            }
+            acc.insert_zero_variant(&0b1000000);
+        }
+
+        assert!(small.spilled());
+        assert!(!large.spilled());
+
+        let small = found(small);
+        assert_eq!(small, found(large));
+        assert!(small.contains(&0b0011));
+        assert!(small.contains(&0b1000000));
+        assert!(!small.contains(&0b0111));
+    }
+
+    #[test]
+    fn sums_counts_across_runs() {
+        // Each flush writes a run of one candidate, so 0b0011's two one
+        // variants are only counted together when the runs are merged
+        let mut acc = SpillingAccumulator::new(2, 0u64, 0);
+        acc.insert_one_variant(&0b0011);
+        acc.insert_one_variant(&0b0101);
+        acc.insert_one_variant(&0b0011);
+
+        assert!(acc.spilled());
+        let found = found(acc);
+        assert!(found.contains(&0b0011));
+        assert!(!found.contains(&0b0101));
+    }
+}
@@ -0,0 +1,303 @@
+//! Size statistics for monitoring index growth
+//!
+//! `Database::stats` walks every entry of a DB's variant store, counting keys
+//! and entries per partition.  The number of distinct values is estimated
+//! with a HyperLogLog sketch over the value IDs the entries reference, so the
+//! walk needs constant memory however large the DB is - deletion DBs store
+//! many variants of each value, and counting them exactly would mean holding
+//! every ID.
+//!
+//! The walk also measures how values are spread over each partition's
+//! buckets (the sets of values sharing a variant key).  HmSearch assumes
+//! buckets stay small: when one holds a large fraction of a partition's
+//! values, every query probing it has to verify them all.  Persistent stores
+//! can hold far more buckets than fit in memory, so only one in
+//! `BUCKET_SAMPLE_RATE` of their bucket sizes is kept for percentiles (see
+//! `MapSet::bucket_sample_rate`); the largest bucket is always exact.
+//!
+//! A walk reads the entire variant store, so stats are meant to be polled
+//! occasionally rather than on every request.
+
+use std::cmp::{min, max};
+use std::hash::{Hash, Hasher};
+
+use siphasher::sip::SipHasher;
+
+use db::window::Window;
+use db::map_set::MapSet;
+use db::id_map::IDMap;
+use error::Error;
+
+// HyperLogLog registers are indexed by this many bits of each hash, giving
+// 4096 registers and a standard error of ~1.6%
+const PRECISION: u32 = 12;
+
+/// Persistent stores keep the size of one in this many buckets for
+/// `BucketSizes` percentiles
+pub const BUCKET_SAMPLE_RATE: usize = 16;
+
+/// Outcome of `Database::stats`
+///
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// Distinct values indexed (estimated)
+    pub values: usize,
+    /// Distinct variant keys
+    pub variant_keys: usize,
+    /// Variant entries (key & value ID pairs) in each partition, in
+    /// partition order
+    pub partition_entries: Vec<usize>,
+    /// Distribution of bucket sizes in each partition, in partition order
+    pub partition_buckets: Vec<BucketSizes>,
+    /// Bytes held in memory by the stores (estimated)
+    pub memory_bytes: u64,
+    /// Bytes on disk, unset if nothing is persisted
+    pub disk_bytes: Option<u64>,
+}
+
+impl Stats {
+    /// Variant entries across every partition
+    ///
+    pub fn variant_entries(&self) -> usize {
+        self.partition_entries.iter().fold(0, |sum, n| sum + n)
+    }
+}
+
+/// Distribution of the sizes of a partition's buckets
+///
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BucketSizes {
+    /// Buckets whose sizes the percentiles were computed from
+    pub sampled: usize,
+    /// One in this many buckets was sampled (1 if every bucket was)
+    pub sample_rate: usize,
+    /// Median bucket size
+    pub p50: usize,
+    /// 90th percentile bucket size
+    pub p90: usize,
+    /// 99th percentile bucket size
+    pub p99: usize,
+    /// Size of the largest bucket, whether or not it was sampled
+    pub max: usize,
+    /// Fraction of the partition's entries held by the largest bucket
+    pub max_fraction: f64,
+}
+
+/// Bucket sizes seen by a walk of one partition
+///
+struct BucketSampler {
+    rate: usize,
+    seen: usize,
+    max: usize,
+    sizes: Vec<usize>,
+}
+
+impl BucketSampler {
+    fn new(rate: usize) -> BucketSampler {
+        BucketSampler{rate: max(rate, 1), seen: 0, max: 0, sizes: Vec::new()}
+    }
+
+    fn add(&mut self, size: usize) {
+        self.max = max(self.max, size);
+        if self.seen % self.rate == 0 {
+            self.sizes.push(size);
+        }
+        self.seen += 1;
+    }
+
+    /// The distribution of the sizes seen, for a partition holding `entries`
+    ///
+    fn finish(mut self, entries: usize) -> BucketSizes {
+        self.sizes.sort();
+        let sizes = &self.sizes;
+        let percentile = |q: f64| -> usize {
+            match sizes.len() {
+                0 => 0,
+                n => sizes[min(n - 1, (q * n as f64).ceil() as usize - 1)],
+            }
+        };
+
+        BucketSizes{
+            sampled: sizes.len(),
+            sample_rate: self.rate,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: self.max,
+            max_fraction: if entries == 0 { 0.0 } else { self.max as f64 / entries as f64 },
+        }
+    }
+}
+
+/// Space used by a single store, as reported by `MapSet::usage` and
+/// `IDMap::usage`
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreUsage {
+    /// Bytes held in memory (estimated)
+    pub memory_bytes: u64,
+    /// Bytes on disk, unset if the store isn't persisted
+    pub disk_bytes: Option<u64>,
+}
+
+impl StoreUsage {
+    /// Combined usage of two stores
+    ///
+    pub fn plus(&self, other: &StoreUsage) -> StoreUsage {
+        let disk_bytes = match (self.disk_bytes, other.disk_bytes) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+
+        StoreUsage {
+            memory_bytes: self.memory_bytes + other.memory_bytes,
+            disk_bytes: disk_bytes,
+        }
+    }
+}
+
+/// HyperLogLog estimate of the number of distinct values added
+///
+pub struct DistinctCounter {
+    registers: Vec<u8>,
+}
+
+impl DistinctCounter {
+    pub fn new() -> DistinctCounter {
+        DistinctCounter{registers: vec![0; 1 << PRECISION]}
+    }
+
+    pub fn insert<T: Hash>(&mut self, value: &T) {
+        let mut hasher = SipHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // Position of the first set bit after the register bits, capped in
+        // case they're all zero
+        let rank = min((hash << PRECISION).leading_zeros() + 1, 64 - PRECISION + 1) as u8;
+
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> usize {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+        let sum = self.registers.iter().fold(0.0, |sum, &rank| sum + 2.0f64.powi(-(rank as i32)));
+        let estimate = alpha * m * m / sum;
+
+        // Linear counting is more accurate while most registers are empty
+        let empty = self.registers.iter().filter(|&&rank| rank == 0).count();
+        if estimate <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as usize
+        } else {
+            estimate.round() as usize
+        }
+    }
+}
+
+/// Walks `variant_store`, attributing each key to a partition with `window`
+///
+/// Every DB stores one or more variants of each value in each partition, so
+/// distinct values are estimated from the IDs referenced by the first
+/// partition alone.
+///
+pub fn collect<K, ID, T, V, I, F>(variant_store: &V, value_store: &I, partitions: &[Window], window: F) -> Result<Stats, Error> where
+K: Clone + Eq + Hash,
+ID: Clone + Eq + Hash,
+V: MapSet<K, ID>,
+I: IDMap<ID, T>,
+F: Fn(&K) -> &Window,
+{
+    let mut stats: Stats = Default::default();
+    stats.partition_entries = vec![0; partitions.len()];
+
+    let mut values = DistinctCounter::new();
+    let mut buckets: Vec<BucketSampler> = partitions.iter().map(|_| BucketSampler::new(variant_store.bucket_sample_rate())).collect();
+    let mut last_key: Option<K> = None;
+    // Partition & size of the bucket being walked
+    let mut bucket: Option<(usize, usize)> = None;
+
+    try!(variant_store.scan(&mut |key, id| {
+        let key_window = window(key);
+        let partition = partitions.iter().position(|partition| partition == key_window);
+
+        // Entries sharing a key are visited consecutively
+        if last_key.as_ref() != Some(key) {
+            stats.variant_keys += 1;
+            last_key = Some(key.clone());
+
+            if let Some((i, size)) = bucket.take() {
+                buckets[i].add(size);
+            }
+            bucket = partition.map(|i| (i, 0));
+        }
+
+        if let Some(i) = partition {
+            stats.partition_entries[i] += 1;
+            if let Some((_, ref mut size)) = bucket {
+                *size += 1;
+            }
+            if i == 0 {
+                values.insert(id);
+            }
+        }
+    }));
+    if let Some((i, size)) = bucket {
+        buckets[i].add(size);
+    }
+    stats.values = values.estimate();
+    stats.partition_buckets = buckets.into_iter().zip(stats.partition_entries.iter())
+        .map(|(sampler, &entries)| sampler.finish(entries))
+        .collect();
+
+    let usage = variant_store.usage().plus(&value_store.usage());
+    stats.memory_bytes = usage.memory_bytes;
+    stats.disk_bytes = usage.disk_bytes;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use db::stats::{DistinctCounter, BucketSampler};
+
+    #[test]
+    fn estimates_distinct_values() {
+        let mut counter = DistinctCounter::new();
+        assert_eq!(counter.estimate(), 0);
+
+        for _ in 0..3 {
+            for i in 0..10000u64 {
+                counter.insert(&i);
+            }
+        }
+
+        let estimate = counter.estimate() as f64;
+        assert!(estimate > 9500.0 && estimate < 10500.0, "estimated {}", estimate);
+    }
+
+    #[test]
+    fn samples_bucket_sizes() {
+        let mut every = BucketSampler::new(1);
+        let mut sampled = BucketSampler::new(4);
+        for size in 1..101 {
+            every.add(size);
+        }
+        for size in 1..100 {
+            sampled.add(size);
+        }
+        sampled.add(1000);
+
+        let every = every.finish(5050);
+        assert_eq!((every.sampled, every.p50, every.p90, every.p99, every.max), (100, 50, 90, 99, 100));
+        assert!((every.max_fraction - 100.0 / 5050.0).abs() < 1e-9);
+
+        // The largest bucket wasn't sampled, but is still reported
+        let sampled = sampled.finish(5950);
+        assert_eq!((sampled.sampled, sampled.sample_rate, sampled.max), (25, 4, 1000));
+    }
+}
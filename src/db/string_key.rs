@@ -0,0 +1,273 @@
+//! 256-bit values indexed by their string encoding
+//!
+//! Perceptual hashes and similar fingerprints are often passed around as hex
+//! or base64 strings.  `HexKey` and `Base64Key` wrap such a string along with
+//! the `[u64; 4]` it encodes, so strings can be inserted and queried directly
+//! and are returned as they were inserted.  Distances, windows and IDs are
+//! computed from the decoded value, so keys which differ only in their
+//! encoding (ie upper- vs lower-case hex) are the same key.
+//!
+//! Strings are decoded once, when the key is created - `Hamming` and
+//! `Windowable` can't report a malformed string, and each key is compared
+//! many times per query.
+//!
+//! The string encodes the value's 32 bytes most significant first, so
+//! dimension 0 is the lowest bit of the string's last byte.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db = HexKey::build(256, 8, StorageBackend::InMemory).unwrap();
+//!
+//! let key = HexKey::new("f0e1d2c3b4a59687f0e1d2c3b4a59687f0e1d2c3b4a59687f0e1d2c3b4a59687").unwrap();
+//! db.insert(key.clone()).unwrap();
+//! db.get(&key).unwrap();
+//! ```
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use base64;
+use hex;
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+
+use db::hamming::Hamming;
+use db::window::Windowable;
+use db::id_map::{ToID, IDStrategy, ID128};
+
+/// Bytes encoded by each key's string
+pub const KEY_BYTES: usize = 32;
+
+fn from_bytes(bytes: &[u8]) -> Result<[u64; 4], String> {
+    if bytes.len() != KEY_BYTES {
+        return Err(format!("expected {} bytes, not {}", KEY_BYTES, bytes.len()))
+    }
+
+    let mut value = [0u64; 4];
+    for (i, chunk) in bytes.chunks(8).enumerate() {
+        value[i] = chunk.iter().fold(0u64, |v, &b| (v << 8) | b as u64);
+    }
+    Ok(value)
+}
+
+fn to_bytes(value: &[u64; 4]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(KEY_BYTES);
+    for element in value.iter() {
+        for shift in (0..8).rev() {
+            bytes.push((element >> (8 * shift)) as u8);
+        }
+    }
+    bytes
+}
+
+fn decode_hex(string: &str) -> Result<[u64; 4], String> {
+    match hex::decode(string) {
+        Ok(bytes) => from_bytes(&bytes).map_err(|e| format!("invalid key '{}': {}", string, e)),
+        Err(e) => Err(format!("unable to hex-decode '{}': {:?}", string, e)),
+    }
+}
+
+fn encode_hex(value: &[u64; 4]) -> String {
+    hex::encode(to_bytes(value))
+}
+
+fn decode_base64(string: &str) -> Result<[u64; 4], String> {
+    match base64::decode(string) {
+        Ok(bytes) => from_bytes(&bytes).map_err(|e| format!("invalid key '{}': {}", string, e)),
+        Err(e) => Err(format!("unable to base64-decode '{}': {:?}", string, e)),
+    }
+}
+
+fn encode_base64(value: &[u64; 4]) -> String {
+    base64::encode(&to_bytes(value))
+}
+
+macro_rules! string_key {
+    ($key:ident, $decode:ident, $encode:ident) => {
+        #[derive(Clone, Debug)]
+        pub struct $key {
+            string: String,
+            value: [u64; 4],
+        }
+
+        impl $key {
+            /// Wraps `string`, failing if it doesn't encode exactly 32 bytes
+            ///
+            pub fn new<S: Into<String>>(string: S) -> Result<$key, String> {
+                let string = string.into();
+                let value = try!($decode(&string));
+
+                Ok($key{string: string, value: value})
+            }
+
+            /// The key encoding `value`
+            ///
+            pub fn from_value(value: [u64; 4]) -> $key {
+                $key{string: $encode(&value), value: value}
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.string
+            }
+
+            pub fn value(&self) -> &[u64; 4] {
+                &self.value
+            }
+        }
+
+        impl FromStr for $key {
+            type Err = String;
+
+            fn from_str(string: &str) -> Result<$key, String> {
+                $key::new(string)
+            }
+        }
+
+        impl fmt::Display for $key {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.string.fmt(f)
+            }
+        }
+
+        impl PartialEq for $key {
+            fn eq(&self, other: &$key) -> bool {
+                self.value == other.value
+            }
+        }
+
+        impl Eq for $key {}
+
+        impl Hash for $key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.value.hash(state)
+            }
+        }
+
+        impl PartialOrd for $key {
+            fn partial_cmp(&self, other: &$key) -> Option<Ordering> {
+                self.value.partial_cmp(&other.value)
+            }
+        }
+
+        impl Ord for $key {
+            fn cmp(&self, other: &$key) -> Ordering {
+                self.value.cmp(&other.value)
+            }
+        }
+
+        impl Hamming for $key {
+            fn hamming(&self, other: &$key) -> usize {
+                self.value.hamming(&other.value)
+            }
+
+            fn hamming_lte(&self, other: &$key, bound: usize) -> bool {
+                self.value.hamming_lte(&other.value, bound)
+            }
+
+            fn hamming_indices(&self, other: &$key) -> Vec<usize> {
+                self.value.hamming_indices(&other.value)
+            }
+        }
+
+        impl<W> Windowable<W> for $key where [u64; 4]: Windowable<W> {
+            fn window(&self, start_dimension: usize, dimensions: usize) -> W {
+                self.value.window(start_dimension, dimensions)
+            }
+        }
+
+        impl ToID<u64> for $key {
+            fn to_id(self) -> u64 {
+                self.value.to_id()
+            }
+
+            fn to_id_with(self, strategy: IDStrategy) -> u64 {
+                self.value.to_id_with(strategy)
+            }
+
+            fn supports_id_strategy(strategy: IDStrategy) -> bool {
+                <[u64; 4] as ToID<u64>>::supports_id_strategy(strategy)
+            }
+        }
+
+        impl ToID<ID128> for $key {
+            fn to_id(self) -> ID128 {
+                self.value.to_id()
+            }
+
+            fn to_id_with(self, strategy: IDStrategy) -> ID128 {
+                self.value.to_id_with(strategy)
+            }
+
+            fn supports_id_strategy(strategy: IDStrategy) -> bool {
+                <[u64; 4] as ToID<ID128>>::supports_id_strategy(strategy)
+            }
+        }
+
+        impl Serialize for $key {
+            fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                self.string.serialize(s)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $key {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<$key, D::Error> {
+                let string = try!(String::deserialize(d));
+                $key::new(string).map_err(de::Error::custom)
+            }
+        }
+    }
+}
+
+/// A 256-bit value encoded as 64 hex digits
+string_key!(HexKey, decode_hex, encode_hex);
+
+/// A 256-bit value encoded as base64
+string_key!(Base64Key, decode_base64, encode_base64);
+
+#[cfg(test)]
+mod test {
+    use db::string_key::*;
+    use db::{Database, Factory, StorageBackend};
+    use db::hamming::Hamming;
+    use db::window::Windowable;
+
+    const ZERO: &'static str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    #[test]
+    fn keys_decode_most_significant_first() {
+        let key = HexKey::new("00000000000000000000000000000000000000000000000000000000000001ff").unwrap();
+
+        assert_eq!(key.value(), &[0, 0, 0, 0x01ff]);
+        assert_eq!(key.hamming(&HexKey::new(ZERO).unwrap()), 9);
+        assert_eq!(Windowable::<u16>::window(&key, 0, 16), 0x01ff);
+        assert_eq!(key, HexKey::from_value([0, 0, 0, 0x01ff]));
+        assert_eq!(key, "00000000000000000000000000000000000000000000000000000000000001FF".parse::<HexKey>().unwrap());
+
+        let base64 = Base64Key::from_value(*key.value());
+        assert_eq!(Base64Key::new(base64.as_str()).unwrap().value(), key.value());
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert!(HexKey::new("01ff").is_err());
+        assert!(HexKey::new("not hex").is_err());
+        assert!(Base64Key::new("AAAA").is_err());
+    }
+
+    #[test]
+    fn finds_string_keys() {
+        let mut db = HexKey::build(256, 4, StorageBackend::InMemory).unwrap();
+        let near = HexKey::new("000000000000000000000000000000000000000000000000000000000000000f").unwrap();
+        let far = HexKey::new("00000000000000000000000000000000000000000000000000000000000000ff").unwrap();
+
+        db.insert(near.clone()).unwrap();
+        db.insert(far.clone()).unwrap();
+
+        let found = db.get(&HexKey::new(ZERO).unwrap()).unwrap().unwrap();
+        assert!(found.contains(&near));
+        assert!(!found.contains(&far));
+        assert_eq!(found.iter().next().unwrap().as_str(), near.as_str());
+    }
+}
@@ -2,6 +2,8 @@ use std::mem::size_of;
 use std::clone::Clone;
 use std::iter::Iterator;
 
+use db::bits::Bits;
+
 /// Iterator for values that can be treated as a bitmap
 ///
 pub struct BinaryIter<T> {
@@ -71,6 +73,21 @@ macro_rules! binary_array_iterator {
 binary_array_iterator!([u64; 4]);
 binary_array_iterator!([u64; 2]);
 
+impl Iterator for BinaryIter<Bits> {
+    type Item = Bits;
+
+    fn next(&mut self) -> Option<Bits> {
+        if self.index >= self.dimensions {
+            None
+        } else {
+            let mut next_value = self.source.clone();
+            next_value.flip(self.index);
+            self.index += 1;
+            Some(next_value)
+        }
+    }
+}
+
 #[cfg(test)] 
 mod test {
     use db::substitution::{SubstitutionVariant};
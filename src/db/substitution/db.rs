@@ -1,17 +1,45 @@
 use std::fmt;
 use std::cmp::{PartialEq};
 use std::clone::Clone;
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use num::rational::Ratio;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
 use db::TypeMap;
-use db::Database;
+#[cfg(not(target_arch = "wasm32"))]
+use db::Strategy;
+use db::atomic;
+use db::{Database, QueryOptions, RemovalReport, Page, WarmupReport, count_references, order_by_recency, paginate, warmup_stores};
 use db::map_set::{MapSet, InMemoryHash};
-use db::result_accumulator::ResultAccumulator;
+use db::result_accumulator::{ResultAccumulator, Accumulator};
+#[cfg(feature = "rocksdb")]
+use db::spilling_accumulator::SpillingAccumulator;
+use db::read_only::ReadOnly;
+use db::sink::{Sink, TopK};
+use db::health::{self, HealthReport, RepairReport};
+use db::snapshot;
+#[cfg(not(target_arch = "wasm32"))]
+use db::frozen;
+use db::stats::{self, Stats};
+use db::hamming::Hamming;
+use db::hashing::State;
 use db::window::{Window, Windowable};
-use db::id_map::{ToID, IDMap, Echo};
+use db::id_map::{ToID, FromID, IDMap, IDStrategy, Echo};
 use db::substitution::{Key, SubstitutionVariant};
+use db::variant_budget::{VariantBudget, VariantEstimate, VariantExplosion};
+use db::partition_mask;
+use db::query_log::QueryTimer;
+use db::dimension_mask;
+use db::verifier::{self, Verifier};
+use error::Error;
 
 type TypeMapU64 = (u64, Echo<u64>, InMemoryHash<Key<u64>, u64>);
 
@@ -35,11 +63,41 @@ pub struct DB<T: TypeMap> {
     tolerance: usize,
     partition_count: usize,
     partitions: Vec<Window>,
+    // Shared with snapshots
+    verifier: Arc<Box<Verifier<<T as TypeMap>::Input>>>,
+    ignored: Vec<usize>,
+    id_strategy: Option<IDStrategy>,
+    compact_variants: bool,
+    // Set by the first insert, after which the settings above are fixed
+    written: AtomicBool,
 
     value_store: <T as TypeMap>::ValueStore,
     variant_store: <T as TypeMap>::VariantStore,
 }
 
+/// Number of partitions the keyspace of a DB is split into
+///
+pub fn partition_count(dimensions: usize, tolerance: usize) -> usize {
+    if tolerance == 0 {
+        1
+    } else if tolerance > dimensions {
+        (dimensions + 3) / 2
+    } else {
+        (tolerance + 3) / 2
+    }
+}
+
+/// Dimensions in the widest partition of a DB, which determines the window
+/// type it needs
+///
+pub fn partition_width(dimensions: usize, tolerance: usize) -> usize {
+    Ratio::new(dimensions, partition_count(dimensions, tolerance)).ceil().to_integer()
+}
+
+/// Widest partition whose one variants `DB::set_compact_variants` stops
+/// storing
+pub const COMPACT_WINDOW_DIMENSIONS: usize = 16;
+
 impl<T: TypeMap> DB<T> where 
 <T as TypeMap>::ValueStore: Default,
 <T as TypeMap>::VariantStore: Default,
@@ -49,6 +107,13 @@ impl<T: TypeMap> DB<T> where
     pub fn new(dimensions: usize, tolerance: usize) -> DB<T> {
         DB::with_stores(dimensions, tolerance, Default::default(), Default::default())
     }
+
+    /// Create a new DB with default backing store, failing if inserts would
+    /// exceed `budget`
+    ///
+    pub fn with_budget(dimensions: usize, tolerance: usize, budget: &VariantBudget) -> Result<DB<T>, VariantExplosion> {
+        DB::try_with_stores(dimensions, tolerance, Default::default(), Default::default(), budget)
+    }
 }
 
 impl<T: TypeMap> DB<T> where 
@@ -56,6 +121,16 @@ impl<T: TypeMap> DB<T> where
 <T as TypeMap>::VariantStore: MapSet<Key<<T as TypeMap>::Variant>, <T as TypeMap>::Identifier>,
 {
 
+    /// Create a new DB with given backing store, failing if inserts would
+    /// exceed `budget`
+    ///
+    pub fn try_with_stores(dimensions: usize, tolerance: usize, value_store: <T as TypeMap>::ValueStore, variant_store: <T as TypeMap>::VariantStore, budget: &VariantBudget) -> Result<DB<T>, VariantExplosion> {
+        let estimate = VariantEstimate::for_types::<<T as TypeMap>::Variant, <T as TypeMap>::Identifier>(dimensions, tolerance);
+        try!(budget.check(estimate));
+
+        Ok(DB::with_stores(dimensions, tolerance, value_store, variant_store))
+    }
+
     /// Create a new DB with given backing store
     ///
     /// Partitions the keyspace as evenly as possible - all partitions
@@ -63,14 +138,7 @@ impl<T: TypeMap> DB<T> where
     ///
     pub fn with_stores(dimensions: usize, tolerance: usize, value_store: <T as TypeMap>::ValueStore, variant_store: <T as TypeMap>::VariantStore) -> DB<T> {
 
-        // Determine number of partitions
-        let partition_count = if tolerance == 0 {
-            1
-        } else if tolerance > dimensions {
-            (dimensions + 3) / 2
-        } else {
-            (tolerance + 3) / 2
-        };
+        let partition_count = partition_count(dimensions, tolerance);
 
         // Determine how many dimensions to allocate to each partition
         let head_width = Ratio::new(dimensions, partition_count).ceil().to_integer() as usize;
@@ -93,102 +161,731 @@ impl<T: TypeMap> DB<T> where
             partitions.push(Window{start_dimension: start_dimension, dimensions: dimensions});
         }
 
-        // Done!
-        return DB {
+        let db = DB {
             dimensions: dimensions,
             tolerance: tolerance,
             partition_count: partition_count,
             partitions: partitions,
+            verifier: Arc::new(Box::new(verifier::Hamming)),
+            ignored: Vec::new(),
+            id_strategy: None,
+            compact_variants: false,
+            written: AtomicBool::new(false),
             value_store: value_store,
             variant_store: variant_store,
         };
+        info!("Created {}", db);
+
+        db
+    }
+
+    /// The windows `key` is partitioned into, in order
+    ///
+    /// Partitions are identified by their index in this list when skipping
+    /// them with `query_masked_into`
+    ///
+    pub fn partitions(&self) -> &[Window] {
+        &self.partitions
+    }
+
+    /// Filter query candidates with `verifier` rather than by hamming
+    /// distance
+    ///
+    /// Masked queries keep verifying by hamming distance over the unmasked
+    /// partitions.
+    ///
+    pub fn set_verifier(&mut self, verifier: Box<Verifier<<T as TypeMap>::Input>>) {
+        self.verifier = Arc::new(verifier);
+    }
+
+    /// Fails with `Error::Invalid` once anything has been inserted, as
+    /// changing `setting` would orphan the values already indexed
+    ///
+    fn check_unwritten(&self, setting: &str) -> Result<(), Error> {
+        if self.written.load(Ordering::SeqCst) {
+            return Err(Error::Invalid(format!("{} can't change once values are inserted", setting)))
+        }
+        Ok(())
+    }
+
+    /// `key` with the ignored dimensions cleared
+    ///
+    fn masked<'a>(&self, key: &'a <T as TypeMap>::Input) -> Cow<'a, <T as TypeMap>::Input> {
+        dimension_mask::apply(key, &self.ignored)
+    }
+
+    /// The identifier `key` is stored under
+    ///
+    fn id(&self, key: &<T as TypeMap>::Input) -> <T as TypeMap>::Identifier {
+        match self.id_strategy {
+            Some(strategy) => key.clone().to_id_with(strategy),
+            None => key.clone().to_id(),
+        }
+    }
+
+    /// Fails with `Error::Collision` if a value other than `key` is stored
+    /// under `id`
+    ///
+    fn check_collision(&self, id: &<T as TypeMap>::Identifier, key: &<T as TypeMap>::Input) -> Result<(), Error> {
+        match try!(self.value_store.get(id.clone())) {
+            Some(ref existing) if existing != key => Err(Error::Collision("a different value is stored under this value's identifier".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `candidate` matches `query`, ignoring the ignored dimensions
+    ///
+    fn verify(&self, query: &<T as TypeMap>::Input, candidate: &<T as TypeMap>::Input, tolerance: usize) -> bool {
+        self.verifier.verify(&self.masked(query), &self.masked(candidate), tolerance)
+    }
+
+    /// Returns true if compact variants were enabled with
+    /// `set_compact_variants`
+    ///
+    pub fn compact_variants(&self) -> bool {
+        self.compact_variants
+    }
+
+    /// Whether `window`'s one variants are derived at query time rather than
+    /// stored
+    ///
+    fn is_compact(&self, window: &Window) -> bool {
+        self.compact_variants && window.dimensions <= COMPACT_WINDOW_DIMENSIONS
+    }
+
+    /// The variants `key` is inserted under, grouped by partition (see
+    /// `atomic::insert`)
+    ///
+    /// One variants are only written for partitions whose zero variant is
+    /// new.
+    ///
+    fn insert_groups(&self, key: &<T as TypeMap>::Input) -> Vec<(Key<<T as TypeMap>::Variant>, Vec<Key<<T as TypeMap>::Variant>>)> {
+        let masked = self.masked(key).into_owned();
+
+        self.partitions.iter().map(|window| {
+            let transformed_key = masked.window(window.start_dimension, window.dimensions);
+            let ones = self.stored_ones(window, &transformed_key);
+            (Key::Zero(window.clone(), transformed_key.null_variant()), ones)
+        }).collect()
+    }
+
+    /// The one variant keys stored for `window` of a value
+    ///
+    fn stored_ones(&self, window: &Window, transformed_key: &<T as TypeMap>::Window) -> Vec<Key<<T as TypeMap>::Variant>> {
+        if self.is_compact(window) {
+            return Vec::new()
+        }
+        transformed_key.substitution_variants(window.dimensions).map(|k| Key::One(window.clone(), k)).collect()
+    }
+
+    /// The buckets a query probes for `window`, each paired with whether the
+    /// values in it are one variants of the query
+    ///
+    /// Compact windows have no one variant bucket, so instead the query's own
+    /// one variants (its window with each bit flipped in turn) are looked up
+    /// among the zero variants.  One-variant buckets are skipped if
+    /// `approximate` is set.
+    ///
+    fn probes(&self, window: &Window, transformed_key: &<T as TypeMap>::Window, approximate: bool) -> Vec<(Key<<T as TypeMap>::Variant>, bool)> {
+        let mut probes = vec![(Key::Zero(window.clone(), transformed_key.null_variant()), false)];
+        if approximate {
+            return probes
+        }
+
+        if self.is_compact(window) {
+            probes.extend(transformed_key.substitution_variants(window.dimensions).map(|k| (Key::Zero(window.clone(), k), true)));
+        } else {
+            probes.push((Key::One(window.clone(), transformed_key.null_variant()), true));
+        }
+        probes
+    }
+
+    /// Every zero and one variant key `key` is indexed under
+    ///
+    fn variant_keys(&self, key: &<T as TypeMap>::Input) -> Vec<Key<<T as TypeMap>::Variant>> {
+        let key = self.masked(key);
+        let mut keys = Vec::new();
+        for window in self.partitions.iter() {
+            let transformed_key = key.window(window.start_dimension, window.dimensions);
+
+            keys.push(Key::Zero(window.clone(), transformed_key.null_variant()));
+            keys.extend(self.stored_ones(window, &transformed_key));
+        }
+        keys
+    }
+
+    /// Feed the values found in each of `windows` for `key` into `results`
+    ///
+    /// One-variant buckets are skipped if `approximate` is set.  Collecting
+    /// each window's candidate IDs is charged to its index in `windows`, and
+    /// reading the candidates (each once) to shared lookups.
+    ///
+    fn accumulate<A: Accumulator<<T as TypeMap>::Input>>(&self, key: &<T as TypeMap>::Input, windows: &[Window], approximate: bool, results: &mut A, timer: &mut QueryTimer) -> Result<(), Error> {
+        let key = self.masked(key);
+
+        // Each partition contributes a zero and a one variant key (or several
+        // zero variant keys if compact) - fetch them all at once so stores
+        // which can batch lookups only pay for one
+        let mut keys = Vec::with_capacity(2 * windows.len());
+        let mut probed = Vec::with_capacity(2 * windows.len());
+        for (i, window) in windows.iter().enumerate() {
+            let transformed_key = &key.window(window.start_dimension, window.dimensions);
+
+            for (variant_key, one) in self.probes(window, transformed_key, approximate) {
+                keys.push(variant_key);
+                probed.push((i, one));
+            }
+        }
+
+        let found = try!(self.variant_store.get_many(&keys));
+        timer.lap_shared();
+
+        // Candidates are gathered from every partition before any value is
+        // read, so values matching in several partitions are only read once
+        let mut hits: Vec<(<T as TypeMap>::Identifier, bool)> = Vec::new();
+        for (&(i, one), found) in probed.iter().zip(found) {
+            if let Some(ids) = found {
+                hits.extend(ids.iter().map(|id| (id.clone(), one)));
+            }
+            timer.lap(i);
+        }
+
+        let mut values: HashMap<<T as TypeMap>::Identifier, Option<<T as TypeMap>::Input>, State> = HashMap::default();
+        for (id, one) in hits.into_iter() {
+            if !values.contains_key(&id) {
+                let value = try!(self.value_store.get(id.clone()));
+                values.insert(id.clone(), value);
+            }
+
+            // An interrupted remove can leave entries whose value is gone
+            // (see `Database::verify_and_repair`) - they can't match
+            let value = match values.get(&id) {
+                Some(&Some(ref value)) => value,
+                _ => continue,
+            };
+
+            if one {
+                results.insert_one_variant(value)
+            } else {
+                results.insert_zero_variant(value)
+            }
+        }
+        timer.lap_shared();
+
+        Ok(())
+    }
+
+    /// Pushes each indexed value within `self.tolerance` (or
+    /// `options.max_distance`) of `key` into `sink`
+    ///
+    pub fn query_into<S: Sink<<T as TypeMap>::Input>>(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, sink: &mut S) -> Result<(), Error> {
+        let tolerance = try!(options.verification_tolerance(self.tolerance));
+
+        let mut results = ResultAccumulator::new(self.tolerance, key.clone());
+        results.set_exclude_exact(options.exclude_exact);
+        results.set_approximate(options.approximate);
+
+        let mut timer = QueryTimer::start(self.partitions.len());
+        try!(self.accumulate(key, &self.partitions, options.approximate, &mut results, &mut timer));
+
+        results.drain_into_with(|query, candidate| self.verify(query, candidate, tolerance), sink);
+        timer.finish(self);
+        Ok(())
+    }
+
+    /// Pushes each indexed value within `self.tolerance` of `key` into
+    /// `sink`, ignoring the partitions at indices `skip`
+    ///
+    /// Skipped partitions aren't probed and differences within them don't
+    /// count toward the distance (see `db::partition_mask`).  Fails with
+    /// `Error::Mask` without querying if `skip` names a partition which
+    /// doesn't exist or leaves too few partitions to find every match.
+    ///
+    pub fn query_masked_into<S>(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, skip: &[usize], sink: &mut S) -> Result<(), Error> where
+    S: Sink<<T as TypeMap>::Input>,
+    <T as TypeMap>::Window: Hamming,
+    {
+        let windows = try!(partition_mask::active_windows(&self.partitions, skip, self.tolerance));
+        let tolerance = try!(options.verification_tolerance(self.tolerance));
+
+        let mut results = ResultAccumulator::new(self.tolerance, key.clone());
+        results.set_exclude_exact(options.exclude_exact);
+        results.set_approximate(options.approximate);
+        results.set_partitions(windows.len());
+
+        let mut timer = QueryTimer::start(windows.len());
+        try!(self.accumulate(key, &windows, options.approximate, &mut results, &mut timer));
+
+        results.drain_into_with(|query, candidate| {
+            let (query, candidate) = (self.masked(query), self.masked(candidate));
+
+            // Candidates are dropped as soon as the windows seen so far exceed
+            // the bound, rather than after windowing the whole value
+            let mut distance = 0;
+            for window in windows.iter() {
+                let query_window: <T as TypeMap>::Window = query.window(window.start_dimension, window.dimensions);
+                let candidate_window: <T as TypeMap>::Window = candidate.window(window.start_dimension, window.dimensions);
+                distance += query_window.hamming(&candidate_window);
+                if distance > tolerance {
+                    return false
+                }
+            }
+            true
+        }, sink);
+        timer.finish(self);
+
+        Ok(())
+    }
+
+    /// Like `get_with`, ignoring the partitions at indices `skip`
+    ///
+    pub fn get_masked(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, skip: &[usize]) -> Result<Option<HashSet<<T as TypeMap>::Input, State>>, Error> where
+    <T as TypeMap>::Window: Hamming,
+    {
+        let mut found = HashSet::default();
+        try!(self.query_masked_into(key, options, skip, &mut found));
+
+        match found.len() {
+            0 => Ok(None),
+            _ => Ok(Some(found)),
+        }
+    }
+
+    /// Like `get`, only probing exact-window buckets
+    ///
+    /// Faster but may miss matches (see `QueryOptions::approximate`)
+    ///
+    pub fn get_approx(&self, key: &<T as TypeMap>::Input) -> Result<Option<HashSet<<T as TypeMap>::Input, State>>, Error> {
+        let mut found = HashSet::default();
+        try!(self.query_into(key, &QueryOptions{approximate: true, ..Default::default()}, &mut found));
+
+        match found.len() {
+            0 => Ok(None),
+            _ => Ok(Some(found)),
+        }
+    }
+
+    /// Write the database to a read-only file at `path`, which can be opened
+    /// with `FrozenDB::open_substitution` (see `db::frozen`)
+    ///
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn finalize(&self, path: &Path) -> Result<(), Error> where
+    <T as TypeMap>::Input: Serialize,
+    <T as TypeMap>::Identifier: Serialize,
+    Key<<T as TypeMap>::Variant>: Serialize,
+    {
+        let header = frozen::Header{
+            strategy: Strategy::Substitution,
+            dimensions: self.dimensions,
+            tolerance: self.tolerance,
+            ignored: self.ignored.clone(),
+            compact_variants: self.compact_variants,
+        };
+        frozen::finalize(&header, &self.variant_store, &self.value_store, path)
     }
 }
 
-impl<T: TypeMap> Database<<T as TypeMap>::Input> for DB<T> where
+impl<T: TypeMap + 'static> Database<<T as TypeMap>::Input> for DB<T> where
 <T as TypeMap>::Window: SubstitutionVariant<<T as TypeMap>::Variant>,
 <T as TypeMap>::VariantStore: MapSet<Key<<T as TypeMap>::Variant>, <T as TypeMap>::Identifier>,
+<T as TypeMap>::Identifier: Serialize + DeserializeOwned,
 {
     /// Get all indexed values within `self.tolerance` hamming distance of `key`
     ///
-    fn get(&self, key: &<T as TypeMap>::Input) -> Option<HashSet<<T as TypeMap>::Input>> {
-        let mut results = ResultAccumulator::new(self.tolerance, key.clone());
+    fn get_with(&self, key: &<T as TypeMap>::Input, options: &QueryOptions) -> Result<Option<HashSet<<T as TypeMap>::Input, State>>, Error> {
+        let mut found = HashSet::default();
+        try!(self.query_into(key, options, &mut found));
 
-        // Split across tasks?
-        for window in self.partitions.iter() {
-            let transformed_key = &key.window(window.start_dimension, window.dimensions);
+        match found.len() {
+            0 => Ok(None),
+            _ => Ok(Some(found)),
+        }
+    }
+
+    /// Holds at most `spill_threshold` candidates in memory, spilling the rest
+    /// to a temporary RocksDB
+    ///
+    #[cfg(feature = "rocksdb")]
+    fn for_each_match(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, spill_threshold: usize, f: &mut FnMut(<T as TypeMap>::Input)) -> Result<(), Error> where
+    <T as TypeMap>::Input: Serialize + DeserializeOwned,
+    {
+        let tolerance = try!(options.verification_tolerance(self.tolerance));
+
+        let mut results = SpillingAccumulator::new(self.tolerance, key.clone(), spill_threshold);
+        results.set_exclude_exact(options.exclude_exact);
+        results.set_approximate(options.approximate);
+
+        let mut timer = QueryTimer::start(self.partitions.len());
+        try!(self.accumulate(key, &self.partitions, options.approximate, &mut results, &mut timer));
+
+        try!(results.for_each_found(|query, candidate| self.verify(query, candidate, tolerance), |value| f(value)));
+        timer.finish(self);
+        Ok(())
+    }
 
-            match self.variant_store.get(&Key::Zero(window.clone(), transformed_key.null_variant())) {
-                Some(ids) => {
-                    for id in ids.iter() {
-                        results.insert_zero_variant(&self.value_store.get(id.clone()))
+    /// Every query's variant buckets are fetched in one `get_many`, and each
+    /// distinct bucket and candidate value is only read once however many
+    /// queries share it
+    ///
+    /// The batch is timed as a single query, each key's candidate reads
+    /// charged to the partition they were probed in.
+    ///
+    fn get_many(&self, keys: &[<T as TypeMap>::Input], options: &QueryOptions) -> Result<Vec<Option<HashSet<<T as TypeMap>::Input, State>>>, Error> {
+        let tolerance = try!(options.verification_tolerance(self.tolerance));
+        let mut timer = QueryTimer::start(self.partitions.len());
+
+        // Distinct variant keys, and for each query the partition, variant key
+        // index and one-variant flag of each bucket it probes
+        let mut variant_keys: Vec<Key<<T as TypeMap>::Variant>> = Vec::new();
+        let mut positions: HashMap<Key<<T as TypeMap>::Variant>, usize, State> = HashMap::default();
+        let mut probes: Vec<Vec<(usize, usize, bool)>> = Vec::with_capacity(keys.len());
+
+        for key in keys.iter() {
+            let key = self.masked(key);
+            let mut probe = Vec::with_capacity(2 * self.partitions.len());
+            for (p, window) in self.partitions.iter().enumerate() {
+                let transformed_key = key.window(window.start_dimension, window.dimensions);
+
+                for (variant_key, one) in self.probes(window, &transformed_key, options.approximate) {
+                    let next = variant_keys.len();
+                    let i = *positions.entry(variant_key.clone()).or_insert(next);
+                    if i == next {
+                        variant_keys.push(variant_key);
                     }
-                },
-                None => {},
+                    probe.push((p, i, one));
+                }
             }
+            probes.push(probe);
+        }
 
-            match self.variant_store.get(&Key::One(window.clone(), transformed_key.null_variant())) {
-                Some(ids) => {
-                    for id in ids.iter() {
-                        results.insert_one_variant(&self.value_store.get(id.clone()))
+        let found = try!(self.variant_store.get_many(&variant_keys));
+        timer.lap_shared();
+
+        let mut values: HashMap<<T as TypeMap>::Identifier, Option<<T as TypeMap>::Input>, State> = HashMap::default();
+        let mut out = Vec::with_capacity(keys.len());
+        for (key, probe) in keys.iter().zip(probes.into_iter()) {
+            let mut results = ResultAccumulator::new(self.tolerance, key.clone());
+            results.set_exclude_exact(options.exclude_exact);
+            results.set_approximate(options.approximate);
+
+            // The previous key's verification isn't charged to a partition
+            timer.skip();
+            for (p, i, one) in probe.into_iter() {
+                let ids = match found[i] {
+                    Some(ref ids) => ids,
+                    None => continue,
+                };
+
+                for id in ids.iter() {
+                    if !values.contains_key(id) {
+                        let value = try!(self.value_store.get(id.clone()));
+                        values.insert(id.clone(), value);
                     }
-                },
-                None => {},
+
+                    // Values missing after an interrupted remove can't match
+                    let value = match values.get(id) {
+                        Some(&Some(ref value)) => value,
+                        _ => continue,
+                    };
+
+                    if one {
+                        results.insert_one_variant(value)
+                    } else {
+                        results.insert_zero_variant(value)
+                    }
+                }
+                timer.lap(p);
+            }
+
+            let mut matches = HashSet::default();
+            results.drain_into_with(|query, candidate| self.verify(query, candidate, tolerance), &mut matches);
+
+            out.push(match matches.len() {
+                0 => None,
+                _ => Some(matches),
+            });
+        }
+        timer.finish(self);
+
+        Ok(out)
+    }
+
+    fn get_page_with(&self, key: &<T as TypeMap>::Input, options: &QueryOptions, cursor: Option<&<T as TypeMap>::Input>, limit: usize) -> Result<Page<<T as TypeMap>::Input>, Error> {
+        let found = try!(self.get_with(key, options)).unwrap_or(HashSet::default());
+        paginate(found, cursor, limit, |value| self.id(value))
+    }
+
+    fn get_recent_with(&self, key: &<T as TypeMap>::Input, options: &QueryOptions) -> Result<Option<Vec<<T as TypeMap>::Input>>, Error> {
+        Ok(try!(self.get_with(key, options)).map(|found| self.sort_by_recency(found)))
+    }
+
+    fn sort_by_recency(&self, found: HashSet<<T as TypeMap>::Input, State>) -> Vec<<T as TypeMap>::Input> {
+        order_by_recency(found, &self.value_store, |value| self.id(value))
+    }
+
+    fn get_topk(&self, key: &<T as TypeMap>::Input, k: usize) -> Result<Option<Vec<<T as TypeMap>::Input>>, Error> {
+        let mut top = TopK::new(key.clone(), k);
+        try!(self.query_into(key, &Default::default(), &mut top));
+
+        let nearest: Vec<<T as TypeMap>::Input> = top.into_sorted_vec().into_iter().map(|(_, v)| v).collect();
+        match nearest.len() {
+            0 => Ok(None),
+            _ => Ok(Some(nearest)),
+        }
+    }
+
+    fn estimate_candidates(&self, key: &<T as TypeMap>::Input) -> Result<usize, Error> {
+        let key = self.masked(key);
+        let mut count = 0;
+        for window in self.partitions.iter() {
+            let transformed_key = &key.window(window.start_dimension, window.dimensions);
+
+            for (variant_key, _) in self.probes(window, transformed_key, false) {
+                count += try!(self.variant_store.count(&variant_key));
+            }
+        }
+        Ok(count)
+    }
+
+    fn set_ignored_dimensions(&mut self, dimensions: Vec<usize>) -> Result<(), Error> {
+        try!(self.check_unwritten("ignored dimensions"));
+        if let Some(d) = dimensions.iter().find(|&&d| d >= self.dimensions) {
+            return Err(Error::Invalid(format!("dimension {} doesn't exist in a {}-dimension DB", d, self.dimensions)))
+        }
+        self.ignored = dimensions;
+        Ok(())
+    }
+
+    fn set_id_strategy(&mut self, strategy: IDStrategy) -> Result<(), Error> {
+        try!(self.check_unwritten("the ID strategy"));
+        if !<<T as TypeMap>::Input as ToID<<T as TypeMap>::Identifier>>::supports_id_strategy(strategy) {
+            return Err(Error::Unsupported(format!("{:?} identifiers for this value type", strategy)))
+        }
+        self.id_strategy = Some(strategy);
+        Ok(())
+    }
+
+    /// Stops storing one variants for partitions of up to
+    /// `COMPACT_WINDOW_DIMENSIONS` dimensions
+    ///
+    /// Queries look up the zero variants of the query window with each bit
+    /// flipped instead of a single one-variant bucket, so a partition of `D`
+    /// dimensions takes `D + 1` lookups rather than 2 but stores one entry per
+    /// value rather than `D + 1`.
+    ///
+    fn set_compact_variants(&mut self) -> Result<(), Error> {
+        try!(self.check_unwritten("compact variants"));
+        self.compact_variants = true;
+        Ok(())
+    }
+
+    fn health_check(&self, sample: usize) -> HealthReport {
+        health::check_stores(&self.variant_store, &self.value_store, sample)
+    }
+
+    /// One variants whose value has no zero variant in the same partition
+    /// are removed too (see `orphaned_ones`)
+    ///
+    fn verify_and_repair(&mut self) -> Result<RepairReport, Error> {
+        let mut report = try!(health::repair_stores(&mut self.variant_store, &self.value_store));
+
+        for (key, id) in try!(orphaned_ones(&self.variant_store)).into_iter() {
+            if try!(self.variant_store.remove(&key, &id)) {
+                report.orphans_removed += 1;
             }
         }
+        Ok(report)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        try!(self.variant_store.sync());
+        self.value_store.sync()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        try!(self.value_store.compact());
+        self.variant_store.compact()
+    }
 
-        results.found_values()
+    fn set_variant_cache(&mut self, sets: usize) -> Result<(), Error> {
+        self.variant_store.set_cache_capacity(sets)
+    }
+
+    fn warmup(&self, fraction: f64) -> Result<WarmupReport, Error> {
+        warmup_stores(&self.value_store, &self.variant_store, fraction)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        stats::collect(&self.variant_store, &self.value_store, &self.partitions, |key| match *key {
+            Key::Zero(ref window, _) | Key::One(ref window, _) => window,
+        })
+    }
+
+    fn for_each_value(&self, f: &mut FnMut(<T as TypeMap>::Input) -> Result<(), Error>) -> Result<(), Error> {
+        snapshot::scan_values(&self.variant_store, &self.value_store, &self.partitions[0], |key| match *key {
+            Key::Zero(ref window, _) | Key::One(ref window, _) => window,
+        }, f)
+    }
+
+    /// Fails with `Error::Unsupported` unless both stores can take a
+    /// snapshot
+    ///
+    fn snapshot(&self) -> Result<ReadOnly<<T as TypeMap>::Input>, Error> {
+        let db: DB<T> = DB {
+            dimensions: self.dimensions,
+            tolerance: self.tolerance,
+            partition_count: self.partition_count,
+            partitions: self.partitions.clone(),
+            verifier: self.verifier.clone(),
+            ignored: self.ignored.clone(),
+            id_strategy: self.id_strategy,
+            compact_variants: self.compact_variants,
+            written: AtomicBool::new(self.written.load(Ordering::SeqCst)),
+            value_store: try!(self.value_store.snapshot()),
+            variant_store: try!(self.variant_store.snapshot()),
+        };
+        Ok(ReadOnly::new(Box::new(db)))
     }
 
     /// Insert `key` into indices
     ///
-    /// Returns true if key was added to ANY index
+    /// Returns true if key was added to ANY index.  The value and its
+    /// variants are written together (see `db::atomic`).  Fails with
+    /// `Error::Collision` rather than overwriting a different value with the
+    /// same identifier.
     ///
-    fn insert(&mut self, key: <T as TypeMap>::Input) -> bool {
-        let id = key.clone().to_id();
-        self.value_store.insert(id.clone(), key.clone());
+    fn insert(&mut self, key: <T as TypeMap>::Input) -> Result<bool, Error> {
+        self.written.store(true, Ordering::SeqCst);
+        let id = self.id(&key);
+        try!(self.check_collision(&id, &key));
+        let groups = self.insert_groups(&key);
 
-        // Split across tasks?
-        self.partitions.clone().into_iter().map(|window| {
-            let transformed_key = key.window(window.start_dimension, window.dimensions);
+        atomic::insert(&mut self.value_store, &mut self.variant_store, id, key, groups)
+    }
+
+    fn shares_writes(&self) -> bool {
+        self.value_store.shares_writes() && self.variant_store.shares_writes()
+    }
+
+    fn insert_shared(&self, key: <T as TypeMap>::Input) -> Result<bool, Error> {
+        self.written.store(true, Ordering::SeqCst);
+        let id = self.id(&key);
+        try!(self.check_collision(&id, &key));
+        let groups = self.insert_groups(&key);
+
+        atomic::insert_shared(&self.value_store, &self.variant_store, id, key, groups)
+    }
+
+    /// Every variant of the batch is computed up front, and written to the
+    /// variant store in one `bulk_insert`.  A collision stops the batch, but
+    /// the values before it are still indexed.
+    ///
+    fn bulk_insert(&mut self, keys: Vec<<T as TypeMap>::Input>) -> Result<(), Error> {
+        self.written.store(true, Ordering::SeqCst);
+        let mut entries = Vec::new();
+
+        for key in keys.into_iter() {
+            let id = self.id(&key);
+            if let Err(e) = self.check_collision(&id, &key) {
+                try!(self.variant_store.bulk_insert(entries));
+                return Err(e)
+            }
+            let masked = self.masked(&key).into_owned();
 
-            if self.variant_store.insert(Key::Zero(window.clone(), transformed_key.null_variant()), id.clone()) {
-                for k in transformed_key.substitution_variants(window.dimensions) {
-                    self.variant_store.insert(Key::One(window.clone(), k.clone()), id.clone());
+            for window in self.partitions.iter() {
+                let transformed_key = masked.window(window.start_dimension, window.dimensions);
+
+                entries.push((Key::Zero(window.clone(), transformed_key.null_variant()), id.clone()));
+                for k in self.stored_ones(window, &transformed_key) {
+                    entries.push((k, id.clone()));
                 }
-                true
-            } else {
-                false
             }
 
-            // Collecting first to force evaluation
-        }).collect::<Vec<bool>>().iter().any(|i| *i)
+            // Values are stored before any variant references them
+            try!(self.value_store.insert(id, key));
+        }
+
+        self.variant_store.bulk_insert(entries)
     }
 
     /// Remove `key` from indices
     ///
-    /// Returns true if key was removed from ANY index
+    /// Returns true if key was removed from ANY index.  The value and its
+    /// variants are removed together (see `db::atomic`).
     ///
-    fn remove(&mut self, key: &<T as TypeMap>::Input) -> bool {
-        let id = key.clone().to_id();
-        self.value_store.remove(&id);
+    fn remove(&mut self, key: &<T as TypeMap>::Input) -> Result<bool, Error> {
+        let id = self.id(key);
+        let keys = self.variant_keys(key);
 
-        // Split across tasks?
-        self.partitions.clone().into_iter().map(|window| {
-            let transformed_key = &key.window(window.start_dimension, window.dimensions);
+        Ok(try!(atomic::remove(&mut self.value_store, &mut self.variant_store, &id, &keys)) > 0)
+    }
 
-            if self.variant_store.remove(&Key::Zero(window.clone(), transformed_key.null_variant()), &id) {
-                for ref k in transformed_key.substitution_variants(window.dimensions) {
-                    self.variant_store.remove(&Key::Zero(window.clone(), k.clone()), &id);
-                }
-                true
-            } else {
-                false
-            }
+    fn remove_verified(&mut self, key: &<T as TypeMap>::Input) -> Result<RemovalReport, Error> {
+        let id = self.id(key);
+        let keys = self.variant_keys(key);
+
+        let mut report: RemovalReport = Default::default();
+        report.variants_removed = try!(atomic::remove(&mut self.value_store, &mut self.variant_store, &id, &keys));
 
-            // Collecting first to force evaluation
-        }).collect::<Vec<bool>>().iter().any(|i| *i)
+        let (checked, remaining) = try!(count_references(&self.variant_store, &id));
+        report.variants_checked = checked;
+        report.variants_remaining = remaining;
+        Ok(report)
     }
+
+    fn get_by_id(&self, id: &u64) -> Result<Option<<T as TypeMap>::Input>, Error> {
+        let id = match <T as TypeMap>::Identifier::from_id(*id) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let value = match try!(self.value_store.get(id.clone())) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        // Echo value stores resolve every ID, so check the value is indexed
+        let window = &self.partitions[0];
+        let transformed_key = self.masked(&value).window(window.start_dimension, window.dimensions);
+        match try!(self.variant_store.get(&Key::Zero(window.clone(), transformed_key.null_variant()))) {
+            Some(ref ids) if ids.contains(&id) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// One variant entries whose ID has no zero variant in the same partition
+///
+/// Every insert writes a zero variant for each partition, so these belong to
+/// values which have since been removed.  Removes used to delete one variants
+/// under `Key::Zero` rather than `Key::One`, which left every removed value's
+/// one variants behind; values which are their own ID (see `id_map::Echo`)
+/// still resolve, so queries kept finding them.  Stores written that way are
+/// cleaned up by `verify_and_repair` (ie `--repair-on-open`).
+///
+/// Reads the variant store twice, holding each zero variant's partition & ID
+/// in memory rather than every one variant.
+///
+fn orphaned_ones<V, ID, M>(variant_store: &M) -> Result<Vec<(Key<V>, ID)>, Error> where
+V: Clone + Eq + Hash,
+ID: Clone + Eq + Hash,
+M: MapSet<Key<V>, ID>,
+{
+    let mut zeros: HashSet<(Window, ID), State> = HashSet::default();
+    try!(variant_store.scan(&mut |key, id| {
+        if let Key::Zero(ref window, _) = *key {
+            zeros.insert((window.clone(), id.clone()));
+        }
+    }));
+
+    let mut orphans = Vec::new();
+    try!(variant_store.scan(&mut |key, id| {
+        if let Key::One(ref window, _) = *key {
+            if !zeros.contains(&(window.clone(), id.clone())) {
+                orphans.push((key.clone(), id.clone()));
+            }
+        }
+    }));
+    Ok(orphans)
 }
 
 impl<T: TypeMap> fmt::Debug for DB<T> {
@@ -197,6 +894,12 @@ impl<T: TypeMap> fmt::Debug for DB<T> {
     }
 }
 
+impl<T: TypeMap> fmt::Display for DB<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "substitution DB ({} dimensions, tolerance {}, {} partitions)", self.dimensions, self.tolerance, self.partition_count)
+    }
+}
+
 impl<T: TypeMap> PartialEq for DB<T> {
     fn eq(&self, other: &DB<T>) -> bool {
         return self.dimensions == other.dimensions &&
@@ -295,25 +998,58 @@ mod test {
     use self::rand::{thread_rng, sample, Rng};
 
     use db::*;
-    use db::substitution::{DB};
+    use db::substitution::{DB, Key};
 
     use db::substitution::db::{TypeMapU64};
+    use db::substitution::{partition_count, partition_width};
+    use db::hashing::State;
+
+    #[test]
+    fn partition_width_matches_partitions() {
+        assert_eq!(partition_width(64, 4), 22);
+        assert_eq!(partition_width(8, 0), 8);
+        assert_eq!(partition_width(4, 10), 2);
+
+        let db: DB<TypeMapU64> = DB::new(64, 4);
+        assert_eq!(db.partitions().len(), partition_count(64, 4));
+        assert_eq!(db.partitions().iter().map(|w| w.dimensions).max(), Some(partition_width(64, 4)));
+    }
 
     #[test]
     fn find_missing_key() {
         let p: DB<TypeMapU64> = DB::new(8, 2);
         let a = 0b11111111u64;
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(None, keys);
     }
 
+    #[test]
+    fn repair_removes_orphaned_one_variants() {
+        let mut p: DB<TypeMapU64> = DB::new(8, 2);
+        let a = 0b11111111u64;
+        p.insert(a).unwrap();
+
+        // As removes used to: the zero variants went, the one variants stayed
+        for key in p.variant_keys(&a).into_iter() {
+            if let Key::Zero(..) = key {
+                p.variant_store.remove(&key, &a).unwrap();
+            }
+        }
+        assert!(p.get(&0b11111110).unwrap().is_some());
+
+        let report = p.verify_and_repair().unwrap();
+        assert!(report.orphans_removed > 0);
+        assert_eq!(None, p.get(&0b11111110).unwrap());
+        assert_eq!(p.verify_and_repair().unwrap().orphans_removed, 0);
+    }
+
     #[test]
     fn insert_first_key() {
         let mut p: DB<TypeMapU64> = DB::new(8, 2);
         let a = 0b11111111u64;
 
-        assert!(p.insert(a.clone()));
+        assert!(p.insert(a.clone()).unwrap());
     }
 
     #[test]
@@ -321,21 +1057,21 @@ mod test {
         let mut p: DB<TypeMapU64> = DB::new(8, 2);
         let a = 0b11111111u64;
 
-        p.insert(a.clone());
+        p.insert(a.clone()).unwrap();
 
-        assert!(!p.insert(a.clone()));
+        assert!(!p.insert(a.clone()).unwrap());
     }
 
     #[test]
     fn find_inserted_key() {
         let mut p: DB<TypeMapU64> = DB::new(8, 2);
         let a = 0b11111111u64;
-        let mut b: HashSet<u64> = HashSet::new();
+        let mut b: HashSet<u64, State> = HashSet::default();
         b.insert(a.clone());
 
-        assert!(p.insert(a.clone()));
+        assert!(p.insert(a.clone()).unwrap());
 
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(Some(b), keys);
     }
@@ -345,12 +1081,12 @@ mod test {
         let mut p: DB<TypeMapU64> = DB::new(8, 2);
         let a = 0b00001111u64;
         let b = 0b00000111u64;
-        let mut c = HashSet::new();
+        let mut c = HashSet::default();
         c.insert(a.clone());
 
-        p.insert(a.clone());
+        p.insert(a.clone()).unwrap();
 
-        let keys = p.get(&b);
+        let keys = p.get(&b).unwrap();
 
         assert_eq!(Some(c), keys);
     }
@@ -363,18 +1099,18 @@ mod test {
         let c = 0b10000001u64;
         let d = 0b11000001u64;
         let e = 0b11000011u64;
-        let mut f: HashSet<u64> = HashSet::new();
+        let mut f: HashSet<u64, State> = HashSet::default();
         f.insert(b.clone());
         f.insert(c.clone());
         f.insert(d.clone());
         f.insert(e.clone());
 
-        p.insert(b.clone());
-        p.insert(c.clone());
-        p.insert(d.clone());
-        p.insert(e.clone());
+        p.insert(b.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+        p.insert(d.clone()).unwrap();
+        p.insert(e.clone()).unwrap();
 
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(Some(f), keys);
     }
@@ -397,12 +1133,12 @@ mod test {
                 b = b ^ (0b10000000u64 >> *start_dimension);
             }
 
-            let mut c: HashSet<u64> = HashSet::new();
+            let mut c: HashSet<u64, State> = HashSet::default();
             c.insert(a.clone());
 
-            assert!(p.insert(a.clone()));
+            assert!(p.insert(a.clone()).unwrap());
 
-            let keys = p.get(&b);
+            let keys = p.get(&b).unwrap();
 
             assert_eq!(Some(c), keys);
         }
@@ -430,12 +1166,12 @@ mod test {
                 b = b & (0b10000000u64 >> *start_dimension);
             }
 
-            let mut c: HashSet<u64> = HashSet::new();
+            let mut c: HashSet<u64, State> = HashSet::default();
             c.insert(a.clone());
 
-            assert!(p.insert(a.clone()));
+            assert!(p.insert(a.clone()).unwrap());
 
-            let keys = p.get(&b);
+            let keys = p.get(&b).unwrap();
 
             assert_eq!(None, keys);
         }
@@ -446,21 +1182,69 @@ mod test {
         let mut p: DB<TypeMapU64> = DB::new(8, 2);
         let a = 0b00001111u64;
 
-        p.insert(a.clone());
+        p.insert(a.clone()).unwrap();
 
-        assert!(p.remove(&a));
+        assert!(p.remove(&a).unwrap());
 
-        let keys = p.get(&a);
+        let keys = p.get(&a).unwrap();
 
         assert_eq!(None, keys);
     }
 
+    #[test]
+    fn remove_clears_one_variants() {
+        let mut p: DB<TypeMapU64> = DB::new(8, 2);
+        let a = 0b00001111u64;
+
+        p.insert(a.clone()).unwrap();
+        assert!(p.remove(&a).unwrap());
+
+        assert_eq!(None, p.get(&0b00001110u64).unwrap());
+        assert_eq!(None, p.get(&0b00011111u64).unwrap());
+    }
+
+    #[test]
+    fn remove_verified_reports_removed_variants() {
+        let mut p: DB<TypeMapU64> = DB::new(8, 2);
+        let a = 0b00001111u64;
+        let b = 0b11110000u64;
+
+        p.insert(a.clone()).unwrap();
+        p.insert(b.clone()).unwrap();
+        let report = p.remove_verified(&a).unwrap();
+
+        // 2 partitions of 4 bits, each with a 0-variant and 4 1-variants;
+        // only b's are left to check
+        assert_eq!(report.variants_removed, 10);
+        assert_eq!(report.variants_checked, 10);
+        assert_eq!(report.variants_remaining, 0);
+        assert!(report.verified());
+        assert!(!p.remove_verified(&a).unwrap().verified());
+    }
+
+    #[test]
+    fn remove_verified_finds_references_under_other_keys() {
+        let mut p: DB<TypeMapU64> = DB::new(8, 2);
+        let a = 0b00001111u64;
+        p.insert(a.clone()).unwrap();
+
+        // An entry a's variants don't include, as an interrupted write or a
+        // change of ignored dimensions could leave
+        let stray = p.variant_keys(&0b11110000u64).remove(0);
+        p.variant_store.insert(stray, a).unwrap();
+
+        let report = p.remove_verified(&a).unwrap();
+        assert_eq!(report.variants_removed, 10);
+        assert_eq!(report.variants_remaining, 1);
+        assert!(!report.verified());
+    }
+
     #[test]
     fn remove_missing_key() {
         let mut p: DB<TypeMapU64> = DB::new(8, 2);
         let a = 0b00001111u64;
 
-        assert!(!p.remove(&a));
+        assert!(!p.remove(&a).unwrap());
     }
 
     /*
@@ -534,12 +1318,12 @@ fn idempotent_read() {
         }
 
         let mut p: DB<TypeMapU64> = DB::new(64, 4);
-        p.insert(a.clone());
-        p.insert(b.clone());
-        p.insert(c.clone());
-        p.remove(&c);
+        p.insert(a.clone()).unwrap();
+        p.insert(b.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+        p.remove(&c).unwrap();
 
-        match p.get(&a) {
+        match p.get(&a).unwrap() {
             Some(results) => quickcheck::TestResult::from_bool(results.contains(&a)),
             None => quickcheck::TestResult::failed(),
         }
@@ -556,13 +1340,465 @@ fn idempotent_delete() {
         }
 
         let mut p: DB<TypeMapU64> = DB::new(64, 4);
-        p.insert(a.clone());
-        p.insert(b.clone());
-        p.insert(c.clone());
-        p.remove(&c);
+        p.insert(a.clone()).unwrap();
+        p.insert(b.clone()).unwrap();
+        p.insert(c.clone()).unwrap();
+        p.remove(&c).unwrap();
 
-        quickcheck::TestResult::from_bool(p.remove(&a))
+        quickcheck::TestResult::from_bool(p.remove(&a).unwrap())
     }
     quickcheck(prop as fn(u64, u64, u64) -> quickcheck::TestResult);
 }
+
+#[test]
+fn exclude_exact_omits_query() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    p.insert(0b0000).unwrap();
+    p.insert(0b0001).unwrap();
+
+    let options = QueryOptions{exclude_exact: true, ..Default::default()};
+    let expected: HashSet<u64, State> = vec![0b0001].into_iter().collect();
+    assert_eq!(p.get_with(&0b0000, &options).unwrap(), Some(expected));
+    assert_eq!(p.get(&0b0000).unwrap().unwrap().len(), 2);
+    assert_eq!(p.get_with(&0b0010, &options).unwrap().unwrap().len(), 2);
+}
+
+#[test]
+fn for_each_match_agrees_with_get() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    for v in 0..64u64 {
+        p.insert(v).unwrap();
+    }
+
+    let mut spilled: HashSet<u64, State> = HashSet::default();
+    p.for_each_match(&0, &Default::default(), 4, &mut |v| { spilled.insert(v); }).unwrap();
+
+    assert_eq!(Some(spilled), p.get(&0).unwrap());
+}
+
+#[test]
+fn get_bounded_rejects_dense_queries() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    for v in 0..64u64 {
+        p.insert(v).unwrap();
+    }
+
+    let estimate = p.estimate_candidates(&0).unwrap();
+    assert!(estimate >= p.get(&0).unwrap().unwrap().len());
+    assert_eq!(p.estimate_candidates(&0xFFFFFFFFFFFFFFFF).unwrap(), 0);
+
+    assert_eq!(p.get_bounded(&0, &Default::default(), estimate), Ok(p.get(&0).unwrap()));
+    assert!(p.get_bounded(&0, &Default::default(), estimate - 1).is_err());
+}
+
+#[test]
+fn query_into_sinks_share_results() {
+    use db::sink::{Count, TopK};
+
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    for v in 0..64u64 {
+        p.insert(v).unwrap();
+    }
+
+    let mut count = Count::default();
+    p.query_into(&0, &Default::default(), &mut count).unwrap();
+    assert_eq!(count.0, p.get(&0).unwrap().unwrap().len());
+
+    let mut top = TopK::new(0u64, 3);
+    p.query_into(&0, &Default::default(), &mut top).unwrap();
+    let nearest: Vec<usize> = top.into_sorted_vec().into_iter().map(|(d, _)| d).collect();
+    assert_eq!(nearest, vec![0, 1, 1]);
+}
+
+#[test]
+fn masked_query_ignores_skipped_partitions() {
+    // 4 partitions of 16 bits; skipping the lowest leaves bits 16..64
+    fn prop(a: u64, noise: u16, flips: Vec<u8>, c: u64) -> bool {
+        let mut b = (a & !0xFFFF) | noise as u64;
+        for f in flips.iter().take(5) {
+            b ^= 1 << (16 + (*f as u64 % 48));
+        }
+
+        let mut p: DB<TypeMapU64> = DB::new(64, 5);
+        p.insert(b).unwrap();
+        p.insert(c).unwrap();
+
+        let found = p.get_masked(&a, &Default::default(), &[0]).unwrap().unwrap_or(HashSet::default());
+        let expected: HashSet<u64, State> = vec![b, c].into_iter()
+            .filter(|v| ((v ^ a) & !0xFFFF).count_ones() <= 5)
+            .collect();
+
+        found == expected
+    }
+    quickcheck(prop as fn(u64, u16, Vec<u8>, u64) -> bool);
+}
+
+#[test]
+fn masked_query_rejects_unsafe_masks() {
+    use db::partition_mask::MaskError;
+    use error::Error;
+
+    let p: DB<TypeMapU64> = DB::new(64, 5);
+    assert_eq!(p.partitions().len(), 4);
+
+    assert_eq!(p.get_masked(&0, &Default::default(), &[4]), Err(Error::Mask(MaskError::NoSuchPartition{partition: 4, partitions: 4})));
+    assert_eq!(p.get_masked(&0, &Default::default(), &[0, 1]), Err(Error::Mask(MaskError::TooFewPartitions{remaining: 2, required: 3})));
+}
+
+#[test]
+fn get_topk_returns_nearest() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    for v in vec![0b0000u64, 0b0001, 0b0011, 0b0111, 0b1111, 0b11111] {
+        p.insert(v).unwrap();
+    }
+
+    assert_eq!(p.get_topk(&0, 3).unwrap(), Some(vec![0b0000, 0b0001, 0b0011]));
+    assert_eq!(p.get_topk(&0, 10).unwrap().unwrap().len(), 5);
+    assert_eq!(p.get_topk(&0, 0).unwrap(), None);
+    assert_eq!(p.get_topk(&0xFFFFFFFF00000000, 3).unwrap(), None);
+}
+
+#[test]
+fn bulk_insert_matches_insert() {
+    let values = vec![0b0000u64, 0b0001, 0b0011, 0b1111_0000, 0xFFFF];
+
+    let mut inserted: DB<TypeMapU64> = DB::new(64, 4);
+    let mut loaded: DB<TypeMapU64> = DB::new(64, 4);
+    for v in values.iter() {
+        inserted.insert(*v).unwrap();
+    }
+    loaded.bulk_insert(values.clone()).unwrap();
+
+    for v in values.iter() {
+        assert_eq!(loaded.get(v).unwrap(), inserted.get(v).unwrap());
+    }
+    assert!(!loaded.insert(0b0011).unwrap());
+}
+
+#[test]
+fn stats_count_entries_per_partition() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    for v in vec![0b0000u64, 0b0001, 0b0011, 0b1111_0000, 0xFFFF] {
+        p.insert(v).unwrap();
+    }
+
+    let stats = p.stats().unwrap();
+    assert_eq!(stats.values, 5);
+    assert_eq!(stats.disk_bytes, None);
+    assert!(stats.memory_bytes > 0);
+
+    // Each value has a zero variant and one variant per dimension in each
+    // partition
+    let expected: Vec<usize> = p.partitions().iter().map(|w| 5 * (w.dimensions + 1)).collect();
+    assert_eq!(stats.partition_entries, expected);
+    assert!(stats.variant_keys <= stats.variant_entries());
+
+    // In-memory buckets are all measured; every value shares the zero
+    // variant of the last (all-zero) partition
+    assert_eq!(stats.partition_buckets.len(), p.partitions().len());
+    assert!(stats.partition_buckets.iter().all(|b| b.sample_rate == 1 && b.max <= 5 && b.p50 <= b.max));
+    assert_eq!(stats.partition_buckets.last().unwrap().max, 5);
+}
+
+#[test]
+fn get_approx_only_finds_exact_window_matches() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+
+    // Differs from 0 by one bit in every partition, so no window matches
+    let scattered = p.partitions().iter().fold(0u64, |v, w| v | (1 << w.start_dimension));
+    assert!(scattered.count_ones() <= 4);
+    p.insert(scattered).unwrap();
+    p.insert(0b0001).unwrap();
+
+    let expected: HashSet<u64, State> = vec![0b0001].into_iter().collect();
+    assert_eq!(p.get_approx(&0).unwrap(), Some(expected));
+    assert_eq!(p.get(&0).unwrap().unwrap().len(), 2);
+}
+
+#[test]
+fn verifier_filters_candidates() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 2);
+    p.insert(0b0001).unwrap();
+    p.insert(0b0110).unwrap();
+
+    // A difference in the lowest bit costs the whole tolerance
+    p.set_verifier(Box::new(verifier::WeightedHamming::new(vec![3])));
+
+    let expected: HashSet<u64, State> = vec![0b0110].into_iter().collect();
+    assert_eq!(p.get(&0b0000).unwrap(), Some(expected));
+}
+
+#[test]
+fn get_and_remove_by_id() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    p.insert(0b0011).unwrap();
+
+    // u64 values are their own IDs
+    assert_eq!(p.get_by_id(&0b0011).unwrap(), Some(0b0011));
+    assert_eq!(p.get_by_id(&0b0111).unwrap(), None);
+
+    assert!(!p.remove_id(&0b0111).unwrap());
+    assert!(p.remove_id(&0b0011).unwrap());
+    assert_eq!(p.get_by_id(&0b0011).unwrap(), None);
+    assert_eq!(p.get(&0b0011).unwrap(), None);
+}
+
+#[test]
+fn get_many_agrees_with_get() {
+    fn prop(values: Vec<u64>, queries: Vec<u64>) -> bool {
+        let mut p: DB<TypeMapU64> = DB::new(64, 4);
+        for v in values.iter() {
+            p.insert(*v).unwrap();
+            p.insert(*v ^ 0b0101).unwrap();
+        }
+
+        // Repeated queries share every bucket
+        let mut queries = queries.clone();
+        queries.extend(values.iter().cloned());
+        queries.extend(values.iter().cloned());
+
+        let individually: Vec<_> = queries.iter().map(|q| p.get(q).unwrap()).collect();
+        p.get_many(&queries, &Default::default()) == Ok(individually)
+    }
+    quickcheck(prop as fn(Vec<u64>, Vec<u64>) -> bool);
+}
+
+#[test]
+fn get_within_narrows_the_tolerance() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 8);
+    p.insert(0b0001).unwrap();
+    p.insert(0b0111).unwrap();
+    p.insert(0b1111_1111).unwrap();
+
+    let expected: HashSet<u64, State> = vec![0b0001, 0b0111].into_iter().collect();
+    assert_eq!(p.get_within(&0, 3).unwrap(), Some(expected));
+    assert_eq!(p.get_within(&0, 8).unwrap(), p.get(&0).unwrap());
+    assert!(p.get_within(&0, 9).is_err());
+}
+
+#[test]
+fn ignored_dimensions_dont_count() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 2);
+    p.set_ignored_dimensions(vec![0, 1, 2, 3]).unwrap();
+    p.insert(0b1111_0000).unwrap();
+    p.insert(0b0001_1111).unwrap();
+
+    // Values are returned as inserted
+    let expected: HashSet<u64, State> = vec![0b0001_1111].into_iter().collect();
+    assert_eq!(p.get(&0b0000_0111).unwrap(), Some(expected));
+    assert!(p.remove(&0b0001_1111).unwrap());
+    assert_eq!(p.get(&0b0000_0111).unwrap(), None);
+
+    assert!(p.set_ignored_dimensions(vec![64]).is_err());
+}
+
+#[test]
+fn ignored_dimensions_are_fixed_once_written() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 2);
+    p.insert(0b1111_0000).unwrap();
+
+    match p.set_ignored_dimensions(vec![0, 1]) {
+        Err(Error::Invalid(_)) => {},
+        other => panic!("expected an invalid setting, got {:?}", other),
+    }
+}
+
+#[test]
+fn id_strategy_is_fixed_once_written() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 2);
+    p.insert(0b1111_0000).unwrap();
+
+    match p.set_id_strategy(IDStrategy::Fnv) {
+        Err(Error::Invalid(_)) => {},
+        other => panic!("expected an invalid setting, got {:?}", other),
+    }
+}
+
+#[test]
+fn pages_cover_get_in_id_order() {
+    fn prop(values: Vec<u64>, limit: u8) -> bool {
+        let limit = (limit % 8) as usize + 1;
+        let mut p: DB<TypeMapU64> = DB::new(64, 4);
+        for v in values.iter() {
+            p.insert(*v & 0b1111).unwrap();
+        }
+
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = p.get_page(&0, cursor.as_ref(), limit).unwrap();
+            if page.values.len() > limit {
+                return false
+            }
+            paged.extend(page.values.into_iter());
+            cursor = page.next;
+            if cursor.is_none() {
+                break
+            }
+        }
+
+        // u64 values identify themselves
+        let mut expected: Vec<u64> = p.get(&0).unwrap().unwrap_or(HashSet::default()).into_iter().collect();
+        expected.sort();
+        paged == expected
+    }
+    quickcheck(prop as fn(Vec<u64>, u8) -> bool);
+}
+
+#[test]
+fn get_page_requires_a_limit() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 4);
+    p.insert(0b0001).unwrap();
+
+    assert!(p.get_page(&0, None, 0).is_err());
+}
+
+#[test]
+fn compact_variants_agree_with_stored_variants() {
+    fn prop(values: Vec<u64>, queries: Vec<u64>) -> bool {
+        // 64 bits in 5 partitions of at most 13 bits, all compact
+        let mut stored: DB<TypeMapU64> = DB::new(64, 8);
+        let mut compact: DB<TypeMapU64> = DB::new(64, 8);
+        compact.set_compact_variants().unwrap();
+
+        for v in values.iter() {
+            for p in vec![&mut stored, &mut compact] {
+                p.insert(*v).unwrap();
+                p.insert(*v ^ 0b0101).unwrap();
+                p.insert(*v ^ (1 << 40)).unwrap();
+            }
+        }
+
+        let mut queries = queries.clone();
+        queries.extend(values.iter().cloned());
+
+        queries.iter().all(|q| compact.get(q).unwrap() == stored.get(q).unwrap()) &&
+            compact.get_many(&queries, &Default::default()) == stored.get_many(&queries, &Default::default())
+    }
+    quickcheck(prop as fn(Vec<u64>, Vec<u64>) -> bool);
+}
+
+#[test]
+fn compact_variants_only_store_zero_variants() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 8);
+    p.set_compact_variants().unwrap();
+    for v in vec![0b0000u64, 0b0001, 0b0011] {
+        p.insert(v).unwrap();
+    }
+
+    let expected: Vec<usize> = p.partitions().iter().map(|_| 3).collect();
+    assert_eq!(p.stats().unwrap().partition_entries, expected);
+    assert!(p.get(&0b0111).unwrap().unwrap().contains(&0b0011));
+
+    assert!(p.remove(&0b0011).unwrap());
+    assert_eq!(p.stats().unwrap().partition_entries[0], 2);
+}
+
+#[test]
+fn compact_variants_are_fixed_once_written() {
+    let mut p: DB<TypeMapU64> = DB::new(64, 8);
+    p.insert(0b0001).unwrap();
+
+    match p.set_compact_variants() {
+        Err(Error::Invalid(_)) => {},
+        other => panic!("expected an invalid setting, got {:?}", other),
+    }
+}
+
+type TypeMapU64Bloom = (u64, id_map::Echo<u64>, map_set::Bloom<Key<u64>, u64, map_set::InMemoryHash<Key<u64>, u64>>);
+impl TypeMap for TypeMapU64Bloom {
+    type Input = u64;
+    type Window = u64;
+    type Variant = u64;
+    type Identifier = u64;
+    type ValueStore = id_map::Echo<u64>;
+    type VariantStore = map_set::Bloom<Key<u64>, u64, map_set::InMemoryHash<Key<u64>, u64>>;
+}
+
+#[test]
+fn bloom_filtered_variants_agree_with_get() {
+    fn prop(values: Vec<u64>, removed: Vec<u64>, queries: Vec<u64>) -> bool {
+        let mut plain: DB<TypeMapU64> = DB::new(64, 4);
+        let variant_store = map_set::Bloom::with_counters(map_set::InMemoryHash::new(), 64).unwrap();
+        let mut filtered: DB<TypeMapU64Bloom> = DB::with_stores(64, 4, Default::default(), variant_store);
+
+        for v in values.iter().chain(removed.iter()) {
+            plain.insert(*v).unwrap();
+            filtered.insert(*v).unwrap();
+        }
+        for v in removed.iter() {
+            plain.remove(v).unwrap();
+            filtered.remove(v).unwrap();
+        }
+
+        queries.iter().chain(values.iter()).chain(removed.iter()).all(|q| plain.get(q).unwrap() == filtered.get(q).unwrap())
+    }
+    quickcheck(prop as fn(Vec<u64>, Vec<u64>, Vec<u64>) -> bool);
+}
+
+type TypeMapU64Sharded = (u64, id_map::Echo<u64>, map_set::ShardedInMemoryHash<Key<u64>, u64>);
+impl TypeMap for TypeMapU64Sharded {
+    type Input = u64;
+    type Window = u64;
+    type Variant = u64;
+    type Identifier = u64;
+    type ValueStore = id_map::Echo<u64>;
+    type VariantStore = map_set::ShardedInMemoryHash<Key<u64>, u64>;
+}
+
+#[test]
+fn shared_inserts_agree_with_insert() {
+    fn prop(values: Vec<u64>, queries: Vec<u64>) -> bool {
+        let mut plain: DB<TypeMapU64> = DB::new(64, 4);
+        let shared: DB<TypeMapU64Sharded> = DB::new(64, 4);
+        assert!(shared.shares_writes() && !plain.shares_writes());
+
+        for v in values.iter() {
+            if plain.insert(*v).unwrap() != shared.insert_shared(*v).unwrap() {
+                return false
+            }
+        }
+
+        queries.iter().chain(values.iter()).all(|q| plain.get(q).unwrap() == shared.get(q).unwrap())
+    }
+    quickcheck(prop as fn(Vec<u64>, Vec<u64>) -> bool);
+
+    let plain: DB<TypeMapU64> = DB::new(64, 4);
+    assert!(plain.insert_shared(1).is_err());
+    assert_eq!(plain.get(&1).unwrap(), None);
+}
+
+#[test]
+fn snapshots_ignore_later_writes() {
+    let mut db: DB<TypeMapU64Sharded> = DB::new(64, 4);
+    db.insert(0b0001).unwrap();
+
+    let snapshot = db.snapshot().unwrap();
+    db.insert(0b0011).unwrap();
+    db.remove(&0b0001).unwrap();
+
+    assert_eq!(snapshot.get(&0b0000).unwrap().unwrap(), vec![0b0001].into_iter().collect());
+    assert_eq!(db.get(&0b0000).unwrap().unwrap(), vec![0b0011].into_iter().collect());
+    assert!(snapshot.clone().insert(0b0111).is_err());
+
+    let plain: DB<TypeMapU64> = DB::new(64, 4);
+    assert!(plain.snapshot().is_err());
+}
+
+#[cfg(feature = "rocksdb")]
+#[test]
+fn rocksdb_snapshots_ignore_later_writes() {
+    use db::typemap::U64wU16TempRocksDB;
+
+    let mut db: DB<U64wU16TempRocksDB> = DB::new(64, 4);
+    db.insert(0b0001).unwrap();
+
+    let snapshot = db.snapshot().unwrap();
+    db.insert(0b0011).unwrap();
+    db.remove(&0b0001).unwrap();
+
+    assert_eq!(snapshot.get(&0b0000).unwrap().unwrap(), vec![0b0001].into_iter().collect());
+    assert_eq!(db.get(&0b0000).unwrap().unwrap(), vec![0b0011].into_iter().collect());
+}
+
 }
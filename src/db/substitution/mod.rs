@@ -3,20 +3,29 @@ extern crate num;
 use std::cmp::Eq;
 use std::clone::Clone;
 
+use db::map_set::Partitioned;
 use db::window::Window;
 
 mod db;
 mod binary_iter;
 
-pub use self::db::DB;
+pub use self::db::{DB, partition_count, partition_width, COMPACT_WINDOW_DIMENSIONS};
 pub use self::binary_iter::BinaryIter;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, RustcDecodable, RustcEncodable)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Key<T> {
     One(Window, T),
     Zero(Window, T),
 }
 
+impl<T> Partitioned for Key<T> {
+    fn partition(&self) -> &Window {
+        match *self {
+            Key::Zero(ref window, _) | Key::One(ref window, _) => window,
+        }
+    }
+}
+
 /// Return a set of single-dimensional permutation variants
 ///
 pub trait SubstitutionVariant<V>: Sized {
@@ -0,0 +1,148 @@
+//! Temporary directories for temp-backed stores
+//!
+//! Directories are created under the system temp dir with a name recording
+//! the owning process (`hammer-<pid>-<uuid>`) and removed when their
+//! `TempDir` is dropped.  A process that is killed never gets to drop its
+//! `TempDir`s, so the first `TempDir` created by each process also sweeps
+//! away any directories left behind by processes which are no longer running.
+//!
+//! Anything holding files open inside a `TempDir` must be dropped before it -
+//! declare the `TempDir` as the *last* field of a struct, since fields are
+//! dropped in declaration order.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Once, ONCE_INIT};
+
+use uuid::Uuid;
+
+const PREFIX: &'static str = "hammer-";
+
+static SWEEP: Once = ONCE_INIT;
+
+/// A uniquely named directory, removed on drop
+///
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        SWEEP.call_once(|| { sweep_stale(&env::temp_dir()); });
+
+        let mut path = env::temp_dir();
+        path.push(format!("{}{}-{}", PREFIX, current_pid(), Uuid::new_v4().to_hyphenated_string()));
+
+        TempDir{path: path}
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Nothing we can do about it here, so ¯\_(ツ)_/¯
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Remove directories under `dir` created by processes which have exited,
+/// returning the number removed
+///
+/// Liveness is checked through `/proc`, so this does nothing on platforms
+/// without it.
+///
+pub fn sweep_stale(dir: &Path) -> usize {
+    if !Path::new("/proc/self").exists() {
+        return 0
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            match owner(&entry.file_name().to_string_lossy()) {
+                Some(pid) => !Path::new(&format!("/proc/{}", pid)).exists(),
+                None => false,
+            }
+        })
+        .filter(|entry| fs::remove_dir_all(entry.path()).is_ok())
+        .count()
+}
+
+/// PID of this process, or 0 where `/proc` isn't available
+///
+fn current_pid() -> u32 {
+    fs::read_link("/proc/self").ok()
+        .and_then(|path| path.to_str().and_then(|pid| pid.parse().ok()))
+        .unwrap_or(0)
+}
+
+/// PID of the process which created the temp dir `name`, if it is one of ours
+///
+fn owner(name: &str) -> Option<u32> {
+    if !name.starts_with(PREFIX) {
+        return None
+    }
+
+    name[PREFIX.len()..].split('-').next().and_then(|pid| pid.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use db::temp_dir::{TempDir, sweep_stale, current_pid};
+
+    #[test]
+    fn removed_on_drop() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().to_path_buf();
+
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sweeps_only_dead_owners() {
+        let mut root = env::temp_dir();
+        root.push(Uuid::new_v4().to_hyphenated_string());
+
+        // PIDs are capped well below u32::MAX, so this process can't exist
+        let mut stale = root.clone();
+        stale.push(format!("hammer-{}-abc", u32::max_value()));
+        let mut live = root.clone();
+        live.push(format!("hammer-{}-abc", current_pid()));
+        let mut other = root.clone();
+        other.push("not-ours");
+
+        for dir in vec![&stale, &live, &other] {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        sweep_stale(&root);
+        let remaining = (live.exists(), other.exists(), stale.exists());
+        let _ = fs::remove_dir_all(&root);
+
+        if Path::new("/proc/self").exists() {
+            assert_eq!(remaining, (true, true, false));
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Randomized consistency checks for `Database` implementations
+//!
+//! `check` runs a seeded workload of inserts, removes and queries against a
+//! database, comparing it with a model of the values which should be indexed
+//! (a `HashSet`).  It fails with a `Violation` if
+//!
+//! * `insert` or `remove` disagrees with the model about whether a value was
+//!   present,
+//! * a query misses an indexed value within tolerance (recall), or
+//! * a query returns a value which was removed or never inserted, or which
+//!   isn't within tolerance (ghosts).
+//!
+//! Values are drawn from a small pool of clustered values, so most are
+//! inserted & removed several times and most queries find something.  The
+//! same seed always runs the same workload, so a failure can be replayed by
+//! passing its `Violation::seed` back in.
+//!
+//! New stores can be checked by building a DB over them (ie with
+//! `substitution::DB::with_stores`); any `Factory` type can be checked on
+//! each backend:
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db = u64::build(64, 4, StorageBackend::TempRocksDB).unwrap();
+//! testing::assert_consistent(&mut *db, 64, 4, &Workload::default());
+//! ```
+
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
+use std::hash::Hash;
+
+use db::Database;
+use db::hamming::Hamming;
+use db::hashing::State;
+
+/// Seeded xorshift64* generator
+///
+/// Workloads can't use `rand`, which is only a dependency of the binaries.
+///
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // The state must never be zero
+        Rng{state: seed ^ 0x9E3779B97F4A7C15 | 1}
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// A number in `0..n`
+    ///
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Values a workload can be generated over
+///
+pub trait Generate: Sized {
+    /// A random value of `dimensions` dimensions
+    ///
+    fn generate(rng: &mut Rng, dimensions: usize) -> Self;
+
+    /// `self` with `dimension` changed, so it's one further from values
+    /// which agreed with `self` in that dimension
+    ///
+    fn mutate(&self, rng: &mut Rng, dimension: usize) -> Self;
+}
+
+macro_rules! int_generate {
+    ($elem:ident) => {
+        impl Generate for $elem {
+            fn generate(rng: &mut Rng, dimensions: usize) -> $elem {
+                let value = rng.next_u64() as $elem;
+                match dimensions < $elem::max_value().count_ones() as usize {
+                    true => value & ((1 << dimensions) - 1),
+                    false => value,
+                }
+            }
+
+            fn mutate(&self, _rng: &mut Rng, dimension: usize) -> $elem {
+                *self ^ (1 << dimension)
+            }
+        }
+    }
+}
+int_generate!(u8);
+int_generate!(u16);
+int_generate!(u32);
+int_generate!(u64);
+
+macro_rules! array_generate {
+    ($n:expr) => {
+        impl Generate for [u64; $n] {
+            fn generate(rng: &mut Rng, dimensions: usize) -> [u64; $n] {
+                let mut value = [0u64; $n];
+                for (i, word) in value.iter_mut().rev().enumerate() {
+                    *word = u64::generate(rng, dimensions.saturating_sub(i * 64).min(64));
+                }
+                value
+            }
+
+            fn mutate(&self, _rng: &mut Rng, dimension: usize) -> [u64; $n] {
+                // Dimensions are numbered from the last element (see
+                // `db::hamming`)
+                let mut value = *self;
+                value[$n - 1 - dimension / 64] ^= 1 << (dimension % 64);
+                value
+            }
+        }
+    }
+}
+array_generate!(2);
+array_generate!(4);
+
+macro_rules! vec_generate {
+    ($elem:ident) => {
+        impl Generate for Vec<$elem> {
+            fn generate(rng: &mut Rng, dimensions: usize) -> Vec<$elem> {
+                (0..dimensions).map(|_| rng.next_u64() as $elem).collect()
+            }
+
+            fn mutate(&self, rng: &mut Rng, dimension: usize) -> Vec<$elem> {
+                let mut value = self.clone();
+                // Any other element will do, so skip over the current one
+                value[dimension] = value[dimension].wrapping_add(1 + rng.below($elem::max_value() as usize) as $elem);
+                value
+            }
+        }
+    }
+}
+vec_generate!(u8);
+vec_generate!(u16);
+vec_generate!(u32);
+vec_generate!(u64);
+
+/// Shape of a randomized workload
+///
+#[derive(Clone, Debug)]
+pub struct Workload {
+    /// Seeds the values and operations
+    pub seed: u64,
+    /// Number of inserts & removes
+    pub operations: usize,
+    /// Number of distinct values inserted & removed
+    pub pool: usize,
+    /// Queries are checked after every `check_every` operations, and at the end
+    pub check_every: usize,
+    /// Number of queries each check makes
+    pub queries: usize,
+}
+
+impl Default for Workload {
+    fn default() -> Workload {
+        Workload {
+            seed: 0,
+            operations: 2000,
+            pool: 200,
+            check_every: 250,
+            queries: 50,
+        }
+    }
+}
+
+/// What a workload did
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub inserts: usize,
+    pub removes: usize,
+    pub queries: usize,
+    /// Values found across all queries
+    pub matches: usize,
+}
+
+/// Returned when a database is inconsistent with its model
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// The workload's seed
+    pub seed: u64,
+    /// Number of inserts & removes made before the violation was found
+    pub operation: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "after operation {} of workload seeded {}: {}", self.operation, self.seed, self.message)
+    }
+}
+
+impl error::Error for Violation {
+    fn description(&self) -> &str {
+        "database is inconsistent with its model"
+    }
+}
+
+/// Runs `workload` against `db`, an empty database of `dimensions`
+/// dimensions indexing values within `tolerance`
+///
+pub fn check<T, D>(db: &mut D, dimensions: usize, tolerance: usize, workload: &Workload) -> Result<Report, Violation> where
+T: Generate + Clone + Eq + Hash + Hamming + fmt::Debug,
+D: Database<T> + ?Sized,
+{
+    let mut rng = Rng::new(workload.seed);
+    let pool = generate_pool(&mut rng, dimensions, tolerance, workload.pool.max(1));
+    let mut model: HashSet<T, State> = HashSet::default();
+    let mut report = Report::default();
+
+    let violation = |operation: usize, message: String| Violation{seed: workload.seed, operation: operation, message: message};
+
+    for operation in 0..workload.operations {
+        let value = &pool[rng.below(pool.len())];
+
+        // Inserts outnumber removes, so the DB fills up over the workload
+        if rng.below(3) == 0 {
+            let removed = try!(db.remove(value).map_err(|e| violation(operation, format!("remove of {:?} failed: {}", value, e))));
+            if removed != model.remove(value) {
+                return Err(violation(operation, format!("remove of {:?} returned {}", value, removed)))
+            }
+            report.removes += 1;
+        } else {
+            let inserted = try!(db.insert(value.clone()).map_err(|e| violation(operation, format!("insert of {:?} failed: {}", value, e))));
+            if inserted != model.insert(value.clone()) {
+                return Err(violation(operation, format!("insert of {:?} returned {}", value, inserted)))
+            }
+            report.inserts += 1;
+        }
+
+        if (operation + 1) % workload.check_every.max(1) == 0 || operation + 1 == workload.operations {
+            for _ in 0..workload.queries {
+                let base = rng.below(pool.len());
+                let query = near(&mut rng, &pool[base], dimensions, tolerance + 1);
+                report.matches += try!(check_query(db, &model, &query, tolerance).map_err(|message| violation(operation + 1, message)));
+                report.queries += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Like `check`, but panics with the violation
+///
+pub fn assert_consistent<T, D>(db: &mut D, dimensions: usize, tolerance: usize, workload: &Workload) -> Report where
+T: Generate + Clone + Eq + Hash + Hamming + fmt::Debug,
+D: Database<T> + ?Sized,
+{
+    match check(db, dimensions, tolerance, workload) {
+        Ok(report) => report,
+        Err(violation) => panic!("{}", violation),
+    }
+}
+
+/// Distinct values, each either random or within `tolerance` of an earlier
+/// one
+///
+fn generate_pool<T: Generate + Clone + Eq + Hash>(rng: &mut Rng, dimensions: usize, tolerance: usize, size: usize) -> Vec<T> {
+    let mut seen = HashSet::default();
+    let mut pool = Vec::with_capacity(size);
+
+    // Small value spaces may not hold `size` distinct values
+    for _ in 0..size * 10 {
+        if pool.len() == size {
+            break
+        }
+        let value = match pool.is_empty() || rng.below(2) == 0 {
+            true => T::generate(rng, dimensions),
+            false => {
+                let base = rng.below(pool.len());
+                near(rng, &pool[base], dimensions, tolerance)
+            },
+        };
+        if seen.insert(value.clone()) {
+            pool.push(value);
+        }
+    }
+    pool
+}
+
+/// `value` with up to `max_changes` of its `dimensions` dimensions changed
+///
+pub fn near<T: Generate + Clone>(rng: &mut Rng, value: &T, dimensions: usize, max_changes: usize) -> T {
+    let mut near = value.clone();
+    for _ in 0..rng.below(max_changes + 1) {
+        let dimension = rng.below(dimensions);
+        near = near.mutate(rng, dimension);
+    }
+    near
+}
+
+/// Queries `db` for `query`, returning the number of values found
+///
+fn check_query<T, D>(db: &D, model: &HashSet<T, State>, query: &T, tolerance: usize) -> Result<usize, String> where
+T: Clone + Eq + Hash + Hamming + fmt::Debug,
+D: Database<T> + ?Sized,
+{
+    let found = try!(db.get(query).map_err(|e| format!("query for {:?} failed: {}", query, e))).unwrap_or_default();
+
+    for value in found.iter() {
+        if !model.contains(value) {
+            return Err(format!("query for {:?} returned {:?}, which isn't indexed", query, value))
+        }
+        if value.hamming(query) > tolerance {
+            return Err(format!("query for {:?} returned {:?}, {} dimensions away", query, value, value.hamming(query)))
+        }
+    }
+    for value in model.iter() {
+        if value.hamming(query) <= tolerance && !found.contains(value) {
+            return Err(format!("query for {:?} missed {:?}, {} dimensions away", query, value, value.hamming(query)))
+        }
+    }
+    Ok(found.len())
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Factory, StorageBackend};
+    use db::testing::{self, Workload};
+
+    #[test]
+    fn in_memory_dbs_are_consistent() {
+        let workload = Workload::default();
+
+        let report = testing::assert_consistent(&mut *u64::build(64, 4, StorageBackend::InMemory).unwrap(), 64, 4, &workload);
+        assert!(report.removes > 0 && report.matches > 0);
+        testing::assert_consistent(&mut *u32::build(32, 3, StorageBackend::InMemory).unwrap(), 32, 3, &workload);
+        testing::assert_consistent(&mut *<[u64; 4]>::build(256, 8, StorageBackend::InMemory).unwrap(), 256, 8, &workload);
+        testing::assert_consistent(&mut *<Vec<u8>>::build(8, 2, StorageBackend::InMemory).unwrap(), 8, 2, &workload);
+        testing::assert_consistent(&mut *<Vec<u64>>::build(16, 3, StorageBackend::InMemory).unwrap(), 16, 3, &workload);
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn rocksdb_dbs_are_consistent() {
+        let workload = Workload{operations: 500, ..Default::default()};
+
+        testing::assert_consistent(&mut *u64::build(64, 4, StorageBackend::TempRocksDB).unwrap(), 64, 4, &workload);
+        testing::assert_consistent(&mut *<[u64; 2]>::build(128, 6, StorageBackend::TempRocksDB).unwrap(), 128, 6, &workload);
+        testing::assert_consistent(&mut *<Vec<u8>>::build(8, 2, StorageBackend::TempRocksDB).unwrap(), 8, 2, &workload);
+    }
+
+    #[test]
+    fn workloads_are_reproducible() {
+        let workload = Workload{seed: 7, ..Default::default()};
+        let a = testing::assert_consistent(&mut *u64::build(64, 4, StorageBackend::InMemory).unwrap(), 64, 4, &workload);
+        let b = testing::assert_consistent(&mut *u64::build(64, 4, StorageBackend::InMemory).unwrap(), 64, 4, &workload);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn missed_values_are_violations() {
+        // The DB only finds values within 2, so checking for 4 misses some
+        let mut db = u64::build(64, 2, StorageBackend::InMemory).unwrap();
+        let violation = testing::check(&mut *db, 64, 4, &Workload::default()).unwrap_err();
+        assert!(violation.message.contains("missed"), "{}", violation);
+    }
+}
@@ -1,73 +1,114 @@
 //! TypeMap implementations for common types
 
+#[cfg(any(feature = "rocksdb", feature = "sled"))]
 use std::path::PathBuf;
-use num::rational::Ratio;
 
 use db::id_map;
 use db::map_set;
 use db::deletion;
 use db::substitution;
-use db::{TypeMap, StorageBackend, Factory, Database};
+use db::planner;
+use db::bits::Bits;
+use db::string_key::{HexKey, Base64Key};
+use db::payload::Payload;
+use db::{TypeMap, StorageBackend, Factory, Database, Strategy};
+#[cfg(feature = "rocksdb")]
+use db::compaction;
+use error::Error;
 
 macro_rules! deletion_inmemory {
-    ($t:ident, $elem:ty) => {
-        pub type $t = ($elem, id_map::HashMap<u64, $elem>, map_set::InMemoryHash<deletion::Key<deletion::Dvec>, u64>);
+    ($t:ident, $elem:ty, $id:ty) => {
+        pub type $t = ($elem, id_map::HashMap<$id, $elem>, map_set::ShardedInMemoryHash<deletion::Key<deletion::Dvec>, $id>);
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $elem;
             type Variant = deletion::Dvec;
-            type Identifier = u64;
-            type ValueStore = id_map::HashMap<u64, $elem>;
-            type VariantStore = map_set::InMemoryHash<deletion::Key<deletion::Dvec>, u64>;
+            type Identifier = $id;
+            type ValueStore = id_map::HashMap<$id, $elem>;
+            type VariantStore = map_set::ShardedInMemoryHash<deletion::Key<deletion::Dvec>, $id>;
         }
-    }
+    };
+    ($t:ident, $elem:ty) => {
+        deletion_inmemory!($t, $elem, u64);
+    };
 }
 
 macro_rules! deletion_temp_rocksdb {
-    ($t:ident, $elem:ty) => {
-        pub type $t = ($elem, id_map::TempRocksDB<u64, $elem>, map_set::TempRocksDB<deletion::Key<deletion::Dvec>, u64>);
+    ($t:ident, $elem:ty, $id:ty) => {
+        #[cfg(feature = "rocksdb")]
+        pub type $t = ($elem, id_map::TempRocksDB<$id, $elem>, map_set::TempRocksDB<deletion::Key<deletion::Dvec>, $id>);
+        #[cfg(feature = "rocksdb")]
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $elem;
             type Variant = deletion::Dvec;
-            type Identifier = u64;
-            type ValueStore = id_map::TempRocksDB<u64, $elem>;
-            type VariantStore = map_set::TempRocksDB<deletion::Key<deletion::Dvec>, u64>;
+            type Identifier = $id;
+            type ValueStore = id_map::TempRocksDB<$id, $elem>;
+            type VariantStore = map_set::TempRocksDB<deletion::Key<deletion::Dvec>, $id>;
         }
-    }
+    };
+    ($t:ident, $elem:ty) => {
+        deletion_temp_rocksdb!($t, $elem, u64);
+    };
 }
 
 macro_rules! deletion_rocksdb {
+    ($t:ident, $elem:ty, $id:ty) => {
+        #[cfg(feature = "rocksdb")]
+        pub type $t = ($elem, id_map::RocksDB<$id, $elem>, map_set::LayeredRocksDB<deletion::Key<deletion::Dvec>, $id>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $t {
+            type Input = $elem;
+            type Window = $elem;
+            type Variant = deletion::Dvec;
+            type Identifier = $id;
+            type ValueStore = id_map::RocksDB<$id, $elem>;
+            type VariantStore = map_set::LayeredRocksDB<deletion::Key<deletion::Dvec>, $id>;
+        }
+    };
     ($t:ident, $elem:ty) => {
-        pub type $t = ($elem, id_map::RocksDB<u64, $elem>, map_set::RocksDB<deletion::Key<deletion::Dvec>, u64>);
+        deletion_rocksdb!($t, $elem, u64);
+    };
+}
+
+macro_rules! deletion_sled {
+    ($t:ident, $elem:ty, $id:ty) => {
+        #[cfg(feature = "sled")]
+        pub type $t = ($elem, id_map::Sled<$id, $elem>, map_set::Sled<deletion::Key<deletion::Dvec>, $id>);
+        #[cfg(feature = "sled")]
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $elem;
             type Variant = deletion::Dvec;
-            type Identifier = u64;
-            type ValueStore = id_map::RocksDB<u64, $elem>;
-            type VariantStore = map_set::RocksDB<deletion::Key<deletion::Dvec>, u64>;
+            type Identifier = $id;
+            type ValueStore = id_map::Sled<$id, $elem>;
+            type VariantStore = map_set::Sled<deletion::Key<deletion::Dvec>, $id>;
         }
-    }
+    };
+    ($t:ident, $elem:ty) => {
+        deletion_sled!($t, $elem, u64);
+    };
 }
 
 macro_rules! substitution_echo_inmemory {
     ($t:ident, $elem:ty, $v:ty) => {
-        pub type $t = ($elem, id_map::Echo<$elem>, map_set::InMemoryHash<substitution::Key<$v>, $elem>);
+        pub type $t = ($elem, id_map::Echo<$elem>, map_set::ShardedInMemoryHash<substitution::Key<$v>, $elem>);
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $v;
             type Variant = $v;
             type Identifier = $elem;
             type ValueStore = id_map::Echo<$elem>;
-            type VariantStore = map_set::InMemoryHash<substitution::Key<$v>, $elem>;
+            type VariantStore = map_set::ShardedInMemoryHash<substitution::Key<$v>, $elem>;
         }
     }
 }
 
 macro_rules! substitution_echo_temp_rocksdb {
     ($t:ident, $elem:ty, $v:ty) => {
+        #[cfg(feature = "rocksdb")]
         pub type $t = ($elem, id_map::Echo<$elem>, map_set::TempRocksDB<substitution::Key<$v>, $elem>);
+        #[cfg(feature = "rocksdb")]
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $v;
@@ -82,35 +123,56 @@ macro_rules! substitution_echo_temp_rocksdb {
 macro_rules! substitution_echo_rocksdb {
     ($t:ident, $elem:ty, $v:ty) => {
 
-        pub type $t = ($elem, id_map::Echo<$elem>, map_set::RocksDB<substitution::Key<$v>, $elem>);
+        #[cfg(feature = "rocksdb")]
+        pub type $t = ($elem, id_map::Echo<$elem>, map_set::LayeredRocksDB<substitution::Key<$v>, $elem>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $t {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = $v;
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::LayeredRocksDB<substitution::Key<$v>, $elem>;
+        }
+    }
+}
+
+macro_rules! substitution_echo_sled {
+    ($t:ident, $elem:ty, $v:ty) => {
+
+        #[cfg(feature = "sled")]
+        pub type $t = ($elem, id_map::Echo<$elem>, map_set::Sled<substitution::Key<$v>, $elem>);
+        #[cfg(feature = "sled")]
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $v;
             type Variant = $v;
             type Identifier = $elem;
             type ValueStore = id_map::Echo<$elem>;
-            type VariantStore = map_set::RocksDB<substitution::Key<$v>, $elem>;
+            type VariantStore = map_set::Sled<substitution::Key<$v>, $elem>;
         }
     }
 }
 
 macro_rules! substitution_map_inmemory {
     ($t:ident, $elem:ty, $v:ty) => {
-        pub type $t = ($elem, id_map::HashMap<u64, $elem>, map_set::InMemoryHash<substitution::Key<$v>, u64>);
+        pub type $t = ($elem, id_map::HashMap<u64, $elem>, map_set::ShardedInMemoryHash<substitution::Key<$v>, u64>);
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $v;
             type Variant = $v;
             type Identifier = u64;
             type ValueStore = id_map::HashMap<u64, $elem>;
-            type VariantStore = map_set::InMemoryHash<substitution::Key<$v>, u64>;
+            type VariantStore = map_set::ShardedInMemoryHash<substitution::Key<$v>, u64>;
         }
     }
 }
 
 macro_rules! substitution_map_temp_rocksdb {
     ($t:ident, $elem:ty, $v:ty) => {
+        #[cfg(feature = "rocksdb")]
         pub type $t = ($elem, id_map::TempRocksDB<u64, $elem>, map_set::TempRocksDB<substitution::Key<$v>, u64>);
+        #[cfg(feature = "rocksdb")]
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $v;
@@ -124,14 +186,324 @@ macro_rules! substitution_map_temp_rocksdb {
 
 macro_rules! substitution_map_rocksdb {
     ($t:ident, $elem:ty, $v:ty) => {
-        pub type $t = ($elem, id_map::RocksDB<u64, $elem>, map_set::RocksDB<substitution::Key<$v>, u64>);
+        #[cfg(feature = "rocksdb")]
+        pub type $t = ($elem, id_map::RocksDB<u64, $elem>, map_set::LayeredRocksDB<substitution::Key<$v>, u64>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $t {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = $v;
+            type Identifier = u64;
+            type ValueStore = id_map::RocksDB<u64, $elem>;
+            type VariantStore = map_set::LayeredRocksDB<substitution::Key<$v>, u64>;
+        }
+    }
+}
+
+macro_rules! substitution_map_sled {
+    ($t:ident, $elem:ty, $v:ty) => {
+        #[cfg(feature = "sled")]
+        pub type $t = ($elem, id_map::Sled<u64, $elem>, map_set::Sled<substitution::Key<$v>, u64>);
+        #[cfg(feature = "sled")]
         impl TypeMap for $t {
             type Input = $elem;
             type Window = $v;
             type Variant = $v;
             type Identifier = u64;
+            type ValueStore = id_map::Sled<u64, $elem>;
+            type VariantStore = map_set::Sled<substitution::Key<$v>, u64>;
+        }
+    }
+}
+
+
+// Deletion typemaps over binary values, whose variants are windows with a bit
+// cleared (see `deletion::BitIter`) and which are their own identifiers
+macro_rules! deletion_echo_binary {
+    ($inmemory:ident, $temp:ident, $rocks:ident, $sled:ident, $elem:ty, $v:ty) => {
+        pub type $inmemory = ($elem, id_map::Echo<$elem>, map_set::ShardedInMemoryHash<deletion::Key<($v, u8)>, $elem>);
+        impl TypeMap for $inmemory {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::ShardedInMemoryHash<deletion::Key<($v, u8)>, $elem>;
+        }
+
+        #[cfg(feature = "rocksdb")]
+        pub type $temp = ($elem, id_map::Echo<$elem>, map_set::TempRocksDB<deletion::Key<($v, u8)>, $elem>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $temp {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::TempRocksDB<deletion::Key<($v, u8)>, $elem>;
+        }
+
+        #[cfg(feature = "rocksdb")]
+        pub type $rocks = ($elem, id_map::Echo<$elem>, map_set::LayeredRocksDB<deletion::Key<($v, u8)>, $elem>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $rocks {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::LayeredRocksDB<deletion::Key<($v, u8)>, $elem>;
+        }
+
+        #[cfg(feature = "sled")]
+        pub type $sled = ($elem, id_map::Echo<$elem>, map_set::Sled<deletion::Key<($v, u8)>, $elem>);
+        #[cfg(feature = "sled")]
+        impl TypeMap for $sled {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::Sled<deletion::Key<($v, u8)>, $elem>;
+        }
+    }
+}
+
+// Like `deletion_echo_binary`, for values too wide to be their own identifiers
+macro_rules! deletion_map_binary {
+    ($inmemory:ident, $temp:ident, $rocks:ident, $sled:ident, $elem:ty, $v:ty) => {
+        pub type $inmemory = ($elem, id_map::HashMap<u64, $elem>, map_set::ShardedInMemoryHash<deletion::Key<($v, u8)>, u64>);
+        impl TypeMap for $inmemory {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = u64;
+            type ValueStore = id_map::HashMap<u64, $elem>;
+            type VariantStore = map_set::ShardedInMemoryHash<deletion::Key<($v, u8)>, u64>;
+        }
+
+        #[cfg(feature = "rocksdb")]
+        pub type $temp = ($elem, id_map::TempRocksDB<u64, $elem>, map_set::TempRocksDB<deletion::Key<($v, u8)>, u64>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $temp {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = u64;
+            type ValueStore = id_map::TempRocksDB<u64, $elem>;
+            type VariantStore = map_set::TempRocksDB<deletion::Key<($v, u8)>, u64>;
+        }
+
+        #[cfg(feature = "rocksdb")]
+        pub type $rocks = ($elem, id_map::RocksDB<u64, $elem>, map_set::LayeredRocksDB<deletion::Key<($v, u8)>, u64>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $rocks {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = u64;
             type ValueStore = id_map::RocksDB<u64, $elem>;
-            type VariantStore = map_set::RocksDB<substitution::Key<$v>, u64>;
+            type VariantStore = map_set::LayeredRocksDB<deletion::Key<($v, u8)>, u64>;
+        }
+
+        #[cfg(feature = "sled")]
+        pub type $sled = ($elem, id_map::Sled<u64, $elem>, map_set::Sled<deletion::Key<($v, u8)>, u64>);
+        #[cfg(feature = "sled")]
+        impl TypeMap for $sled {
+            type Input = $elem;
+            type Window = $v;
+            type Variant = ($v, u8);
+            type Identifier = u64;
+            type ValueStore = id_map::Sled<u64, $elem>;
+            type VariantStore = map_set::Sled<deletion::Key<($v, u8)>, u64>;
+        }
+    }
+}
+
+// Deletion typemaps storing vectors inline in the variant store, as their own
+// identifiers
+macro_rules! deletion_inline {
+    ($inmemory:ident, $temp:ident, $rocks:ident, $sled:ident, $elem:ty) => {
+        pub type $inmemory = ($elem, id_map::Echo<$elem>, map_set::ShardedInMemoryHash<deletion::Key<deletion::Dvec>, $elem>);
+        impl TypeMap for $inmemory {
+            type Input = $elem;
+            type Window = $elem;
+            type Variant = deletion::Dvec;
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::ShardedInMemoryHash<deletion::Key<deletion::Dvec>, $elem>;
+        }
+
+        #[cfg(feature = "rocksdb")]
+        pub type $temp = ($elem, id_map::Echo<$elem>, map_set::TempRocksDB<deletion::Key<deletion::Dvec>, $elem>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $temp {
+            type Input = $elem;
+            type Window = $elem;
+            type Variant = deletion::Dvec;
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::TempRocksDB<deletion::Key<deletion::Dvec>, $elem>;
+        }
+
+        #[cfg(feature = "rocksdb")]
+        pub type $rocks = ($elem, id_map::Echo<$elem>, map_set::LayeredRocksDB<deletion::Key<deletion::Dvec>, $elem>);
+        #[cfg(feature = "rocksdb")]
+        impl TypeMap for $rocks {
+            type Input = $elem;
+            type Window = $elem;
+            type Variant = deletion::Dvec;
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::LayeredRocksDB<deletion::Key<deletion::Dvec>, $elem>;
+        }
+
+        #[cfg(feature = "sled")]
+        pub type $sled = ($elem, id_map::Echo<$elem>, map_set::Sled<deletion::Key<deletion::Dvec>, $elem>);
+        #[cfg(feature = "sled")]
+        impl TypeMap for $sled {
+            type Input = $elem;
+            type Window = $elem;
+            type Variant = deletion::Dvec;
+            type Identifier = $elem;
+            type ValueStore = id_map::Echo<$elem>;
+            type VariantStore = map_set::Sled<deletion::Key<deletion::Dvec>, $elem>;
+        }
+    }
+}
+
+// Builds a `$db` (`deletion` or `substitution`) DB of the typemap for the
+// backend, with a value store alongside the variant store
+macro_rules! map_build {
+    ($db:ident, $dimensions:expr, $tolerance:expr, $backend:expr, $inmemory:ty, $temp:ty, $rocks:ty, $sled:ty) => {
+        match $backend {
+            StorageBackend::InMemory => {
+                let db: $db::DB<$inmemory> = $db::DB::new($dimensions, $tolerance);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::TempRocksDB => {
+                let id_map = try!(id_map::TempRocksDB::new());
+                let map_set = try!(map_set::TempRocksDB::new());
+                let db: $db::DB<$temp> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::RocksDB(ref path, ref rocksdb_config) => {
+                let mut id_map_path = path.clone();
+                id_map_path.push("id_map");
+                let mut map_set_path = PathBuf::from(path);
+                map_set_path.push("map_set");
+
+                let id_map = try!(id_map::RocksDB::with_opts(try!(compaction::path_str(&id_map_path)), rocksdb_config.options()));
+                let map_set = try!(map_set::layered(try!(map_set::RocksDB::with_opts(try!(compaction::path_str(&map_set_path)), rocksdb_config.options())), rocksdb_config));
+                let db: $db::DB<$rocks> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::SharedRocksDB(ref shared, ref prefix, ref rocksdb_config) => {
+                let id_map = try!(id_map::RocksDB::in_family(shared.clone(), &format!("{}/id_map", prefix)));
+                let map_set = try!(map_set::layered(try!(map_set::RocksDB::in_family(shared.clone(), &format!("{}/map_set", prefix))), rocksdb_config));
+                let db: $db::DB<$rocks> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "sled")]
+            StorageBackend::Sled(ref path) => {
+                let mut id_map_path = path.clone();
+                id_map_path.push("id_map");
+                let mut map_set_path = PathBuf::from(path);
+                map_set_path.push("map_set");
+
+                let id_map = try!(id_map::Sled::new(&id_map_path));
+                let map_set = try!(map_set::Sled::new(&map_set_path));
+                let db: $db::DB<$sled> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+        }
+    }
+}
+
+// Like `map_build`, for typemaps whose values are their own identifiers
+macro_rules! echo_build {
+    ($db:ident, $dimensions:expr, $tolerance:expr, $backend:expr, $inmemory:ty, $temp:ty, $rocks:ty, $sled:ty) => {
+        match $backend {
+            StorageBackend::InMemory => {
+                let db: $db::DB<$inmemory> = $db::DB::new($dimensions, $tolerance);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::TempRocksDB => {
+                let id_map = id_map::Echo::new();
+                let map_set = try!(map_set::TempRocksDB::new());
+                let db: $db::DB<$temp> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::RocksDB(ref path, ref rocksdb_config) => {
+                let mut map_set_path = path.clone();
+                map_set_path.push("map_set");
+
+                let id_map = id_map::Echo::new();
+                let map_set = try!(map_set::layered(try!(map_set::RocksDB::with_opts(try!(compaction::path_str(&map_set_path)), rocksdb_config.options())), rocksdb_config));
+                let db: $db::DB<$rocks> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::SharedRocksDB(ref shared, ref prefix, ref rocksdb_config) => {
+                let id_map = id_map::Echo::new();
+                let map_set = try!(map_set::layered(try!(map_set::RocksDB::in_family(shared.clone(), &format!("{}/map_set", prefix))), rocksdb_config));
+                let db: $db::DB<$rocks> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+            #[cfg(feature = "sled")]
+            StorageBackend::Sled(ref path) => {
+                let mut map_set_path = path.clone();
+                map_set_path.push("map_set");
+
+                let id_map = id_map::Echo::new();
+                let map_set = try!(map_set::Sled::new(&map_set_path));
+                let db: $db::DB<$sled> = $db::DB::with_stores($dimensions, $tolerance, id_map, map_set);
+                Ok(Box::new(db))
+            },
+        }
+    }
+}
+
+// Builds a `$db` DB over binary values with `$build` (`map_build` or
+// `echo_build`), using whichever of the listed window types the planner picks.
+// Every windowed `Factory` method goes through here, so the window type can't
+// be chosen differently for one backend than another.
+macro_rules! windowed_build {
+    ($build:ident, $db:ident, $dimensions:expr, $tolerance:expr, $backend:expr, $( $bits:expr => ($inmemory:ty, $temp:ty, $rocks:ty, $sled:ty) ),*) => {{
+        let window_bits = planner::window_bits($dimensions, $tolerance);
+
+        $(
+            if window_bits == Some($bits) {
+                return $build!($db, $dimensions, $tolerance, $backend, $inmemory, $temp, $rocks, $sled)
+            }
+        )*
+
+        Err(Error::Unsupported(format!("no window type for {} dimensions at tolerance {}", $dimensions, $tolerance)))
+    }}
+}
+
+// Builds substitution DBs over `$elem`, using whichever of the listed window
+// types the planner picks
+macro_rules! substitution_map_factory {
+    ($elem:ty, $max:expr, $( $bits:expr => ($inmemory:ident, $temp:ident, $rocks:ident, $sled:ident) ),*) => {
+        impl Factory for $elem {
+            fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<$elem>>, Error> {
+                windowed_build!(map_build, substitution, dimensions, tolerance, backend,
+                    $( $bits => ($inmemory, $temp, $rocks, $sled) ),*)
+            }
+
+            fn strategy() -> Strategy {
+                Strategy::Substitution
+            }
+
+            fn max_dimensions() -> Option<usize> {
+                Some($max)
+            }
         }
     }
 }
@@ -144,6 +516,18 @@ deletion_inmemory!(VecU64InMemory, Vec<u64>);
 deletion_inmemory!(VecU64x2InMemory, Vec<[u64; 2]>);
 deletion_inmemory!(VecU64x4InMemory, Vec<[u64; 4]>);
 
+/// `Vec<u64>` values stored as deltas against representative values - see
+/// `id_map::DeltaHashMap`
+pub type VecU64DeltaInMemory = (Vec<u64>, id_map::DeltaHashMap<u64>, map_set::ShardedInMemoryHash<deletion::Key<deletion::Dvec>, u64>);
+impl TypeMap for VecU64DeltaInMemory {
+    type Input = Vec<u64>;
+    type Window = Vec<u64>;
+    type Variant = deletion::Dvec;
+    type Identifier = u64;
+    type ValueStore = id_map::DeltaHashMap<u64>;
+    type VariantStore = map_set::ShardedInMemoryHash<deletion::Key<deletion::Dvec>, u64>;
+}
+
 deletion_temp_rocksdb!(VecU8TempRocksDB, Vec<u8>);
 deletion_temp_rocksdb!(VecU16TempRocksDB, Vec<u16>);
 deletion_temp_rocksdb!(VecU32TempRocksDB, Vec<u32>);
@@ -158,6 +542,42 @@ deletion_rocksdb!(VecU64RocksDB, Vec<u64>);
 deletion_rocksdb!(VecU64x2RocksDB, Vec<[u64; 2]>);
 deletion_rocksdb!(VecU64x4RocksDB, Vec<[u64; 4]>);
 
+deletion_sled!(VecU8Sled, Vec<u8>);
+deletion_sled!(VecU16Sled, Vec<u16>);
+deletion_sled!(VecU32Sled, Vec<u32>);
+deletion_sled!(VecU64Sled, Vec<u64>);
+deletion_sled!(VecU64x2Sled, Vec<[u64; 2]>);
+deletion_sled!(VecU64x4Sled, Vec<[u64; 4]>);
+
+// Vectors identified by 128-bit hashes (see `id_map::ID128`)
+deletion_inmemory!(VecU8ID128InMemory, Vec<u8>, id_map::ID128);
+deletion_inmemory!(VecU16ID128InMemory, Vec<u16>, id_map::ID128);
+deletion_inmemory!(VecU32ID128InMemory, Vec<u32>, id_map::ID128);
+deletion_inmemory!(VecU64ID128InMemory, Vec<u64>, id_map::ID128);
+deletion_inmemory!(VecU64x2ID128InMemory, Vec<[u64; 2]>, id_map::ID128);
+deletion_inmemory!(VecU64x4ID128InMemory, Vec<[u64; 4]>, id_map::ID128);
+
+deletion_temp_rocksdb!(VecU8ID128TempRocksDB, Vec<u8>, id_map::ID128);
+deletion_temp_rocksdb!(VecU16ID128TempRocksDB, Vec<u16>, id_map::ID128);
+deletion_temp_rocksdb!(VecU32ID128TempRocksDB, Vec<u32>, id_map::ID128);
+deletion_temp_rocksdb!(VecU64ID128TempRocksDB, Vec<u64>, id_map::ID128);
+deletion_temp_rocksdb!(VecU64x2ID128TempRocksDB, Vec<[u64; 2]>, id_map::ID128);
+deletion_temp_rocksdb!(VecU64x4ID128TempRocksDB, Vec<[u64; 4]>, id_map::ID128);
+
+deletion_rocksdb!(VecU8ID128RocksDB, Vec<u8>, id_map::ID128);
+deletion_rocksdb!(VecU16ID128RocksDB, Vec<u16>, id_map::ID128);
+deletion_rocksdb!(VecU32ID128RocksDB, Vec<u32>, id_map::ID128);
+deletion_rocksdb!(VecU64ID128RocksDB, Vec<u64>, id_map::ID128);
+deletion_rocksdb!(VecU64x2ID128RocksDB, Vec<[u64; 2]>, id_map::ID128);
+deletion_rocksdb!(VecU64x4ID128RocksDB, Vec<[u64; 4]>, id_map::ID128);
+
+deletion_sled!(VecU8ID128Sled, Vec<u8>, id_map::ID128);
+deletion_sled!(VecU16ID128Sled, Vec<u16>, id_map::ID128);
+deletion_sled!(VecU32ID128Sled, Vec<u32>, id_map::ID128);
+deletion_sled!(VecU64ID128Sled, Vec<u64>, id_map::ID128);
+deletion_sled!(VecU64x2ID128Sled, Vec<[u64; 2]>, id_map::ID128);
+deletion_sled!(VecU64x4ID128Sled, Vec<[u64; 4]>, id_map::ID128);
+
 
 substitution_echo_inmemory!(U64wU8InMemory, u64, u8);
 substitution_echo_inmemory!(U64wU16InMemory, u64, u16);
@@ -169,6 +589,17 @@ substitution_echo_inmemory!(U32wU32InMemory, u32, u32);
 substitution_echo_inmemory!(U16wU8InMemory, u16, u8);
 substitution_echo_inmemory!(U16wU16InMemory, u16, u16);
 substitution_echo_inmemory!(U8wU8InMemory, u8, u8);
+substitution_echo_inmemory!(I64wU8InMemory, i64, u8);
+substitution_echo_inmemory!(I64wU16InMemory, i64, u16);
+substitution_echo_inmemory!(I64wU32InMemory, i64, u32);
+substitution_echo_inmemory!(I64wU64InMemory, i64, u64);
+substitution_echo_inmemory!(I32wU8InMemory, i32, u8);
+substitution_echo_inmemory!(I32wU16InMemory, i32, u16);
+substitution_echo_inmemory!(I32wU32InMemory, i32, u32);
+substitution_echo_inmemory!(UsizewU8InMemory, usize, u8);
+substitution_echo_inmemory!(UsizewU16InMemory, usize, u16);
+substitution_echo_inmemory!(UsizewU32InMemory, usize, u32);
+substitution_echo_inmemory!(UsizewU64InMemory, usize, u64);
 
 substitution_echo_temp_rocksdb!(U64wU8TempRocksDB, u64, u8);
 substitution_echo_temp_rocksdb!(U64wU16TempRocksDB, u64, u16);
@@ -180,6 +611,17 @@ substitution_echo_temp_rocksdb!(U32wU32TempRocksDB, u32, u32);
 substitution_echo_temp_rocksdb!(U16wU8TempRocksDB, u16, u8);
 substitution_echo_temp_rocksdb!(U16wU16TempRocksDB, u16, u16);
 substitution_echo_temp_rocksdb!(U8wU8TempRocksDB, u8, u8);
+substitution_echo_temp_rocksdb!(I64wU8TempRocksDB, i64, u8);
+substitution_echo_temp_rocksdb!(I64wU16TempRocksDB, i64, u16);
+substitution_echo_temp_rocksdb!(I64wU32TempRocksDB, i64, u32);
+substitution_echo_temp_rocksdb!(I64wU64TempRocksDB, i64, u64);
+substitution_echo_temp_rocksdb!(I32wU8TempRocksDB, i32, u8);
+substitution_echo_temp_rocksdb!(I32wU16TempRocksDB, i32, u16);
+substitution_echo_temp_rocksdb!(I32wU32TempRocksDB, i32, u32);
+substitution_echo_temp_rocksdb!(UsizewU8TempRocksDB, usize, u8);
+substitution_echo_temp_rocksdb!(UsizewU16TempRocksDB, usize, u16);
+substitution_echo_temp_rocksdb!(UsizewU32TempRocksDB, usize, u32);
+substitution_echo_temp_rocksdb!(UsizewU64TempRocksDB, usize, u64);
 
 substitution_echo_rocksdb!(U64wU8RocksDB, u64, u8);
 substitution_echo_rocksdb!(U64wU16RocksDB, u64, u16);
@@ -191,6 +633,39 @@ substitution_echo_rocksdb!(U32wU32RocksDB, u32, u32);
 substitution_echo_rocksdb!(U16wU8RocksDB, u16, u8);
 substitution_echo_rocksdb!(U16wU16RocksDB, u16, u16);
 substitution_echo_rocksdb!(U8wU8RocksDB, u8, u8);
+substitution_echo_rocksdb!(I64wU8RocksDB, i64, u8);
+substitution_echo_rocksdb!(I64wU16RocksDB, i64, u16);
+substitution_echo_rocksdb!(I64wU32RocksDB, i64, u32);
+substitution_echo_rocksdb!(I64wU64RocksDB, i64, u64);
+substitution_echo_rocksdb!(I32wU8RocksDB, i32, u8);
+substitution_echo_rocksdb!(I32wU16RocksDB, i32, u16);
+substitution_echo_rocksdb!(I32wU32RocksDB, i32, u32);
+substitution_echo_rocksdb!(UsizewU8RocksDB, usize, u8);
+substitution_echo_rocksdb!(UsizewU16RocksDB, usize, u16);
+substitution_echo_rocksdb!(UsizewU32RocksDB, usize, u32);
+substitution_echo_rocksdb!(UsizewU64RocksDB, usize, u64);
+
+substitution_echo_sled!(U64wU8Sled, u64, u8);
+substitution_echo_sled!(U64wU16Sled, u64, u16);
+substitution_echo_sled!(U64wU32Sled, u64, u32);
+substitution_echo_sled!(U64wU64Sled, u64, u64);
+substitution_echo_sled!(U32wU8Sled, u32, u8);
+substitution_echo_sled!(U32wU16Sled, u32, u16);
+substitution_echo_sled!(U32wU32Sled, u32, u32);
+substitution_echo_sled!(U16wU8Sled, u16, u8);
+substitution_echo_sled!(U16wU16Sled, u16, u16);
+substitution_echo_sled!(U8wU8Sled, u8, u8);
+substitution_echo_sled!(I64wU8Sled, i64, u8);
+substitution_echo_sled!(I64wU16Sled, i64, u16);
+substitution_echo_sled!(I64wU32Sled, i64, u32);
+substitution_echo_sled!(I64wU64Sled, i64, u64);
+substitution_echo_sled!(I32wU8Sled, i32, u8);
+substitution_echo_sled!(I32wU16Sled, i32, u16);
+substitution_echo_sled!(I32wU32Sled, i32, u32);
+substitution_echo_sled!(UsizewU8Sled, usize, u8);
+substitution_echo_sled!(UsizewU16Sled, usize, u16);
+substitution_echo_sled!(UsizewU32Sled, usize, u32);
+substitution_echo_sled!(UsizewU64Sled, usize, u64);
 
 
 substitution_map_inmemory!(U64x4wU8InMemory, [u64; 4], u8);
@@ -229,657 +704,740 @@ substitution_map_rocksdb!(U64x2wU32RocksDB, [u64; 2], u32);
 substitution_map_rocksdb!(U64x2wU64RocksDB, [u64; 2], u64);
 substitution_map_rocksdb!(U64x2wU64x2RocksDB, [u64; 2], [u64; 2]);
 
-impl Factory for Vec<[u64; 4]> {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<Vec<[u64; 4]>>> {
-        match backend {
-            StorageBackend::InMemory => {
-                let db: deletion::DB<VecU64x4InMemory> = deletion::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            StorageBackend::TempRocksDB => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: deletion::DB<VecU64x4TempRocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            StorageBackend::RocksDB(ref path) => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+substitution_map_inmemory!(BitsInMemory, Bits, Bits);
+substitution_map_temp_rocksdb!(BitsTempRocksDB, Bits, Bits);
+substitution_map_rocksdb!(BitsRocksDB, Bits, Bits);
+
+substitution_map_sled!(U64x4wU8Sled, [u64; 4], u8);
+substitution_map_sled!(U64x4wU16Sled, [u64; 4], u16);
+substitution_map_sled!(U64x4wU32Sled, [u64; 4], u32);
+substitution_map_sled!(U64x4wU64Sled, [u64; 4], u64);
+substitution_map_sled!(U64x4wU64x2Sled, [u64; 4], [u64; 2]);
+substitution_map_sled!(U64x4wU64x4Sled, [u64; 4], [u64; 4]);
+substitution_map_sled!(U64x2wU8Sled, [u64; 2], u8);
+substitution_map_sled!(U64x2wU16Sled, [u64; 2], u16);
+substitution_map_sled!(U64x2wU32Sled, [u64; 2], u32);
+substitution_map_sled!(U64x2wU64Sled, [u64; 2], u64);
+substitution_map_sled!(U64x2wU64x2Sled, [u64; 2], [u64; 2]);
+substitution_map_sled!(BitsSled, Bits, Bits);
+
+// Binary values indexed by deletion rather than substitution (see
+// `Factory::build_deletion`)
+deletion_map_binary!(U64x4wU8DeletionInMemory, U64x4wU8DeletionTempRocksDB, U64x4wU8DeletionRocksDB, U64x4wU8DeletionSled, [u64; 4], u8);
+deletion_map_binary!(U64x4wU16DeletionInMemory, U64x4wU16DeletionTempRocksDB, U64x4wU16DeletionRocksDB, U64x4wU16DeletionSled, [u64; 4], u16);
+deletion_map_binary!(U64x4wU32DeletionInMemory, U64x4wU32DeletionTempRocksDB, U64x4wU32DeletionRocksDB, U64x4wU32DeletionSled, [u64; 4], u32);
+deletion_map_binary!(U64x4wU64DeletionInMemory, U64x4wU64DeletionTempRocksDB, U64x4wU64DeletionRocksDB, U64x4wU64DeletionSled, [u64; 4], u64);
+deletion_map_binary!(U64x4wU64x2DeletionInMemory, U64x4wU64x2DeletionTempRocksDB, U64x4wU64x2DeletionRocksDB, U64x4wU64x2DeletionSled, [u64; 4], [u64; 2]);
+deletion_map_binary!(U64x4wU64x4DeletionInMemory, U64x4wU64x4DeletionTempRocksDB, U64x4wU64x4DeletionRocksDB, U64x4wU64x4DeletionSled, [u64; 4], [u64; 4]);
+deletion_map_binary!(U64x2wU8DeletionInMemory, U64x2wU8DeletionTempRocksDB, U64x2wU8DeletionRocksDB, U64x2wU8DeletionSled, [u64; 2], u8);
+deletion_map_binary!(U64x2wU16DeletionInMemory, U64x2wU16DeletionTempRocksDB, U64x2wU16DeletionRocksDB, U64x2wU16DeletionSled, [u64; 2], u16);
+deletion_map_binary!(U64x2wU32DeletionInMemory, U64x2wU32DeletionTempRocksDB, U64x2wU32DeletionRocksDB, U64x2wU32DeletionSled, [u64; 2], u32);
+deletion_map_binary!(U64x2wU64DeletionInMemory, U64x2wU64DeletionTempRocksDB, U64x2wU64DeletionRocksDB, U64x2wU64DeletionSled, [u64; 2], u64);
+deletion_map_binary!(U64x2wU64x2DeletionInMemory, U64x2wU64x2DeletionTempRocksDB, U64x2wU64x2DeletionRocksDB, U64x2wU64x2DeletionSled, [u64; 2], [u64; 2]);
+deletion_echo_binary!(U64wU8DeletionInMemory, U64wU8DeletionTempRocksDB, U64wU8DeletionRocksDB, U64wU8DeletionSled, u64, u8);
+deletion_echo_binary!(U64wU16DeletionInMemory, U64wU16DeletionTempRocksDB, U64wU16DeletionRocksDB, U64wU16DeletionSled, u64, u16);
+deletion_echo_binary!(U64wU32DeletionInMemory, U64wU32DeletionTempRocksDB, U64wU32DeletionRocksDB, U64wU32DeletionSled, u64, u32);
+deletion_echo_binary!(U64wU64DeletionInMemory, U64wU64DeletionTempRocksDB, U64wU64DeletionRocksDB, U64wU64DeletionSled, u64, u64);
+deletion_echo_binary!(U32wU8DeletionInMemory, U32wU8DeletionTempRocksDB, U32wU8DeletionRocksDB, U32wU8DeletionSled, u32, u8);
+deletion_echo_binary!(U32wU16DeletionInMemory, U32wU16DeletionTempRocksDB, U32wU16DeletionRocksDB, U32wU16DeletionSled, u32, u16);
+deletion_echo_binary!(U32wU32DeletionInMemory, U32wU32DeletionTempRocksDB, U32wU32DeletionRocksDB, U32wU32DeletionSled, u32, u32);
+deletion_echo_binary!(U16wU8DeletionInMemory, U16wU8DeletionTempRocksDB, U16wU8DeletionRocksDB, U16wU8DeletionSled, u16, u8);
+deletion_echo_binary!(U16wU16DeletionInMemory, U16wU16DeletionTempRocksDB, U16wU16DeletionRocksDB, U16wU16DeletionSled, u16, u16);
+deletion_echo_binary!(U8wU8DeletionInMemory, U8wU8DeletionTempRocksDB, U8wU8DeletionRocksDB, U8wU8DeletionSled, u8, u8);
+deletion_echo_binary!(I64wU8DeletionInMemory, I64wU8DeletionTempRocksDB, I64wU8DeletionRocksDB, I64wU8DeletionSled, i64, u8);
+deletion_echo_binary!(I64wU16DeletionInMemory, I64wU16DeletionTempRocksDB, I64wU16DeletionRocksDB, I64wU16DeletionSled, i64, u16);
+deletion_echo_binary!(I64wU32DeletionInMemory, I64wU32DeletionTempRocksDB, I64wU32DeletionRocksDB, I64wU32DeletionSled, i64, u32);
+deletion_echo_binary!(I64wU64DeletionInMemory, I64wU64DeletionTempRocksDB, I64wU64DeletionRocksDB, I64wU64DeletionSled, i64, u64);
+deletion_echo_binary!(I32wU8DeletionInMemory, I32wU8DeletionTempRocksDB, I32wU8DeletionRocksDB, I32wU8DeletionSled, i32, u8);
+deletion_echo_binary!(I32wU16DeletionInMemory, I32wU16DeletionTempRocksDB, I32wU16DeletionRocksDB, I32wU16DeletionSled, i32, u16);
+deletion_echo_binary!(I32wU32DeletionInMemory, I32wU32DeletionTempRocksDB, I32wU32DeletionRocksDB, I32wU32DeletionSled, i32, u32);
+deletion_echo_binary!(UsizewU8DeletionInMemory, UsizewU8DeletionTempRocksDB, UsizewU8DeletionRocksDB, UsizewU8DeletionSled, usize, u8);
+deletion_echo_binary!(UsizewU16DeletionInMemory, UsizewU16DeletionTempRocksDB, UsizewU16DeletionRocksDB, UsizewU16DeletionSled, usize, u16);
+deletion_echo_binary!(UsizewU32DeletionInMemory, UsizewU32DeletionTempRocksDB, UsizewU32DeletionRocksDB, UsizewU32DeletionSled, usize, u32);
+deletion_echo_binary!(UsizewU64DeletionInMemory, UsizewU64DeletionTempRocksDB, UsizewU64DeletionRocksDB, UsizewU64DeletionSled, usize, u64);
+
+// Values stored inline in the variant store rather than behind a hashed
+// identifier (see `Factory::build_inline`)
+substitution_echo_inmemory!(U64x4wU8InlineInMemory, [u64; 4], u8);
+substitution_echo_inmemory!(U64x4wU16InlineInMemory, [u64; 4], u16);
+substitution_echo_inmemory!(U64x4wU32InlineInMemory, [u64; 4], u32);
+substitution_echo_inmemory!(U64x4wU64InlineInMemory, [u64; 4], u64);
+substitution_echo_inmemory!(U64x4wU64x2InlineInMemory, [u64; 4], [u64; 2]);
+substitution_echo_inmemory!(U64x4wU64x4InlineInMemory, [u64; 4], [u64; 4]);
+substitution_echo_temp_rocksdb!(U64x4wU8InlineTempRocksDB, [u64; 4], u8);
+substitution_echo_temp_rocksdb!(U64x4wU16InlineTempRocksDB, [u64; 4], u16);
+substitution_echo_temp_rocksdb!(U64x4wU32InlineTempRocksDB, [u64; 4], u32);
+substitution_echo_temp_rocksdb!(U64x4wU64InlineTempRocksDB, [u64; 4], u64);
+substitution_echo_temp_rocksdb!(U64x4wU64x2InlineTempRocksDB, [u64; 4], [u64; 2]);
+substitution_echo_temp_rocksdb!(U64x4wU64x4InlineTempRocksDB, [u64; 4], [u64; 4]);
+substitution_echo_rocksdb!(U64x4wU8InlineRocksDB, [u64; 4], u8);
+substitution_echo_rocksdb!(U64x4wU16InlineRocksDB, [u64; 4], u16);
+substitution_echo_rocksdb!(U64x4wU32InlineRocksDB, [u64; 4], u32);
+substitution_echo_rocksdb!(U64x4wU64InlineRocksDB, [u64; 4], u64);
+substitution_echo_rocksdb!(U64x4wU64x2InlineRocksDB, [u64; 4], [u64; 2]);
+substitution_echo_rocksdb!(U64x4wU64x4InlineRocksDB, [u64; 4], [u64; 4]);
+substitution_echo_sled!(U64x4wU8InlineSled, [u64; 4], u8);
+substitution_echo_sled!(U64x4wU16InlineSled, [u64; 4], u16);
+substitution_echo_sled!(U64x4wU32InlineSled, [u64; 4], u32);
+substitution_echo_sled!(U64x4wU64InlineSled, [u64; 4], u64);
+substitution_echo_sled!(U64x4wU64x2InlineSled, [u64; 4], [u64; 2]);
+substitution_echo_sled!(U64x4wU64x4InlineSled, [u64; 4], [u64; 4]);
+substitution_echo_inmemory!(U64x2wU8InlineInMemory, [u64; 2], u8);
+substitution_echo_inmemory!(U64x2wU16InlineInMemory, [u64; 2], u16);
+substitution_echo_inmemory!(U64x2wU32InlineInMemory, [u64; 2], u32);
+substitution_echo_inmemory!(U64x2wU64InlineInMemory, [u64; 2], u64);
+substitution_echo_inmemory!(U64x2wU64x2InlineInMemory, [u64; 2], [u64; 2]);
+substitution_echo_temp_rocksdb!(U64x2wU8InlineTempRocksDB, [u64; 2], u8);
+substitution_echo_temp_rocksdb!(U64x2wU16InlineTempRocksDB, [u64; 2], u16);
+substitution_echo_temp_rocksdb!(U64x2wU32InlineTempRocksDB, [u64; 2], u32);
+substitution_echo_temp_rocksdb!(U64x2wU64InlineTempRocksDB, [u64; 2], u64);
+substitution_echo_temp_rocksdb!(U64x2wU64x2InlineTempRocksDB, [u64; 2], [u64; 2]);
+substitution_echo_rocksdb!(U64x2wU8InlineRocksDB, [u64; 2], u8);
+substitution_echo_rocksdb!(U64x2wU16InlineRocksDB, [u64; 2], u16);
+substitution_echo_rocksdb!(U64x2wU32InlineRocksDB, [u64; 2], u32);
+substitution_echo_rocksdb!(U64x2wU64InlineRocksDB, [u64; 2], u64);
+substitution_echo_rocksdb!(U64x2wU64x2InlineRocksDB, [u64; 2], [u64; 2]);
+substitution_echo_sled!(U64x2wU8InlineSled, [u64; 2], u8);
+substitution_echo_sled!(U64x2wU16InlineSled, [u64; 2], u16);
+substitution_echo_sled!(U64x2wU32InlineSled, [u64; 2], u32);
+substitution_echo_sled!(U64x2wU64InlineSled, [u64; 2], u64);
+substitution_echo_sled!(U64x2wU64x2InlineSled, [u64; 2], [u64; 2]);
+deletion_inline!(VecU8InlineInMemory, VecU8InlineTempRocksDB, VecU8InlineRocksDB, VecU8InlineSled, Vec<u8>);
+deletion_inline!(VecU16InlineInMemory, VecU16InlineTempRocksDB, VecU16InlineRocksDB, VecU16InlineSled, Vec<u16>);
+deletion_inline!(VecU32InlineInMemory, VecU32InlineTempRocksDB, VecU32InlineRocksDB, VecU32InlineSled, Vec<u32>);
+deletion_inline!(VecU64InlineInMemory, VecU64InlineTempRocksDB, VecU64InlineRocksDB, VecU64InlineSled, Vec<u64>);
+deletion_inline!(VecU64x2InlineInMemory, VecU64x2InlineTempRocksDB, VecU64x2InlineRocksDB, VecU64x2InlineSled, Vec<[u64; 2]>);
+deletion_inline!(VecU64x4InlineInMemory, VecU64x4InlineTempRocksDB, VecU64x4InlineRocksDB, VecU64x4InlineSled, Vec<[u64; 4]>);
+
+// String keys are windowed like the `[u64; 4]` they decode to
+substitution_map_inmemory!(HexKeywU8InMemory, HexKey, u8);
+substitution_map_inmemory!(HexKeywU16InMemory, HexKey, u16);
+substitution_map_inmemory!(HexKeywU32InMemory, HexKey, u32);
+substitution_map_inmemory!(HexKeywU64InMemory, HexKey, u64);
+substitution_map_inmemory!(HexKeywU64x2InMemory, HexKey, [u64; 2]);
+substitution_map_inmemory!(HexKeywU64x4InMemory, HexKey, [u64; 4]);
+substitution_map_temp_rocksdb!(HexKeywU8TempRocksDB, HexKey, u8);
+substitution_map_temp_rocksdb!(HexKeywU16TempRocksDB, HexKey, u16);
+substitution_map_temp_rocksdb!(HexKeywU32TempRocksDB, HexKey, u32);
+substitution_map_temp_rocksdb!(HexKeywU64TempRocksDB, HexKey, u64);
+substitution_map_temp_rocksdb!(HexKeywU64x2TempRocksDB, HexKey, [u64; 2]);
+substitution_map_temp_rocksdb!(HexKeywU64x4TempRocksDB, HexKey, [u64; 4]);
+substitution_map_rocksdb!(HexKeywU8RocksDB, HexKey, u8);
+substitution_map_rocksdb!(HexKeywU16RocksDB, HexKey, u16);
+substitution_map_rocksdb!(HexKeywU32RocksDB, HexKey, u32);
+substitution_map_rocksdb!(HexKeywU64RocksDB, HexKey, u64);
+substitution_map_rocksdb!(HexKeywU64x2RocksDB, HexKey, [u64; 2]);
+substitution_map_rocksdb!(HexKeywU64x4RocksDB, HexKey, [u64; 4]);
+substitution_map_sled!(HexKeywU8Sled, HexKey, u8);
+substitution_map_sled!(HexKeywU16Sled, HexKey, u16);
+substitution_map_sled!(HexKeywU32Sled, HexKey, u32);
+substitution_map_sled!(HexKeywU64Sled, HexKey, u64);
+substitution_map_sled!(HexKeywU64x2Sled, HexKey, [u64; 2]);
+substitution_map_sled!(HexKeywU64x4Sled, HexKey, [u64; 4]);
+
+substitution_map_inmemory!(Base64KeywU8InMemory, Base64Key, u8);
+substitution_map_inmemory!(Base64KeywU16InMemory, Base64Key, u16);
+substitution_map_inmemory!(Base64KeywU32InMemory, Base64Key, u32);
+substitution_map_inmemory!(Base64KeywU64InMemory, Base64Key, u64);
+substitution_map_inmemory!(Base64KeywU64x2InMemory, Base64Key, [u64; 2]);
+substitution_map_inmemory!(Base64KeywU64x4InMemory, Base64Key, [u64; 4]);
+substitution_map_temp_rocksdb!(Base64KeywU8TempRocksDB, Base64Key, u8);
+substitution_map_temp_rocksdb!(Base64KeywU16TempRocksDB, Base64Key, u16);
+substitution_map_temp_rocksdb!(Base64KeywU32TempRocksDB, Base64Key, u32);
+substitution_map_temp_rocksdb!(Base64KeywU64TempRocksDB, Base64Key, u64);
+substitution_map_temp_rocksdb!(Base64KeywU64x2TempRocksDB, Base64Key, [u64; 2]);
+substitution_map_temp_rocksdb!(Base64KeywU64x4TempRocksDB, Base64Key, [u64; 4]);
+substitution_map_rocksdb!(Base64KeywU8RocksDB, Base64Key, u8);
+substitution_map_rocksdb!(Base64KeywU16RocksDB, Base64Key, u16);
+substitution_map_rocksdb!(Base64KeywU32RocksDB, Base64Key, u32);
+substitution_map_rocksdb!(Base64KeywU64RocksDB, Base64Key, u64);
+substitution_map_rocksdb!(Base64KeywU64x2RocksDB, Base64Key, [u64; 2]);
+substitution_map_rocksdb!(Base64KeywU64x4RocksDB, Base64Key, [u64; 4]);
+substitution_map_sled!(Base64KeywU8Sled, Base64Key, u8);
+substitution_map_sled!(Base64KeywU16Sled, Base64Key, u16);
+substitution_map_sled!(Base64KeywU32Sled, Base64Key, u32);
+substitution_map_sled!(Base64KeywU64Sled, Base64Key, u64);
+substitution_map_sled!(Base64KeywU64x2Sled, Base64Key, [u64; 2]);
+substitution_map_sled!(Base64KeywU64x4Sled, Base64Key, [u64; 4]);
+
+// Payloads are stored in the value store entry of the `u64` they're paired with
+substitution_map_inmemory!(PayloadU64wU8InMemory, Payload<u64>, u8);
+substitution_map_inmemory!(PayloadU64wU16InMemory, Payload<u64>, u16);
+substitution_map_inmemory!(PayloadU64wU32InMemory, Payload<u64>, u32);
+substitution_map_inmemory!(PayloadU64wU64InMemory, Payload<u64>, u64);
+substitution_map_temp_rocksdb!(PayloadU64wU8TempRocksDB, Payload<u64>, u8);
+substitution_map_temp_rocksdb!(PayloadU64wU16TempRocksDB, Payload<u64>, u16);
+substitution_map_temp_rocksdb!(PayloadU64wU32TempRocksDB, Payload<u64>, u32);
+substitution_map_temp_rocksdb!(PayloadU64wU64TempRocksDB, Payload<u64>, u64);
+substitution_map_rocksdb!(PayloadU64wU8RocksDB, Payload<u64>, u8);
+substitution_map_rocksdb!(PayloadU64wU16RocksDB, Payload<u64>, u16);
+substitution_map_rocksdb!(PayloadU64wU32RocksDB, Payload<u64>, u32);
+substitution_map_rocksdb!(PayloadU64wU64RocksDB, Payload<u64>, u64);
+substitution_map_sled!(PayloadU64wU8Sled, Payload<u64>, u8);
+substitution_map_sled!(PayloadU64wU16Sled, Payload<u64>, u16);
+substitution_map_sled!(PayloadU64wU32Sled, Payload<u64>, u32);
+substitution_map_sled!(PayloadU64wU64Sled, Payload<u64>, u64);
+
+// Counts are payloads too (see `db::counted`)
+substitution_map_inmemory!(CountedU64wU8InMemory, Payload<u64, u64>, u8);
+substitution_map_inmemory!(CountedU64wU16InMemory, Payload<u64, u64>, u16);
+substitution_map_inmemory!(CountedU64wU32InMemory, Payload<u64, u64>, u32);
+substitution_map_inmemory!(CountedU64wU64InMemory, Payload<u64, u64>, u64);
+substitution_map_temp_rocksdb!(CountedU64wU8TempRocksDB, Payload<u64, u64>, u8);
+substitution_map_temp_rocksdb!(CountedU64wU16TempRocksDB, Payload<u64, u64>, u16);
+substitution_map_temp_rocksdb!(CountedU64wU32TempRocksDB, Payload<u64, u64>, u32);
+substitution_map_temp_rocksdb!(CountedU64wU64TempRocksDB, Payload<u64, u64>, u64);
+substitution_map_rocksdb!(CountedU64wU8RocksDB, Payload<u64, u64>, u8);
+substitution_map_rocksdb!(CountedU64wU16RocksDB, Payload<u64, u64>, u16);
+substitution_map_rocksdb!(CountedU64wU32RocksDB, Payload<u64, u64>, u32);
+substitution_map_rocksdb!(CountedU64wU64RocksDB, Payload<u64, u64>, u64);
+substitution_map_sled!(CountedU64wU8Sled, Payload<u64, u64>, u8);
+substitution_map_sled!(CountedU64wU16Sled, Payload<u64, u64>, u16);
+substitution_map_sled!(CountedU64wU32Sled, Payload<u64, u64>, u32);
+substitution_map_sled!(CountedU64wU64Sled, Payload<u64, u64>, u64);
+
+impl Factory for Bits {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Bits>>, Error> {
+        map_build!(substitution, dimensions, tolerance, backend, BitsInMemory, BitsTempRocksDB, BitsRocksDB, BitsSled)
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: deletion::DB<VecU64x4RocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-        }
+    fn strategy() -> Strategy {
+        Strategy::Substitution
     }
 }
 
-impl Factory for Vec<[u64; 2]> {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<Vec<[u64; 2]>>> {
-        match backend {
-            StorageBackend::InMemory => {
-                let db: deletion::DB<VecU64x2InMemory> = deletion::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            StorageBackend::TempRocksDB => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: deletion::DB<VecU64x2TempRocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            StorageBackend::RocksDB(ref path) => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+substitution_map_factory!(HexKey, 256,
+    8 => (HexKeywU8InMemory, HexKeywU8TempRocksDB, HexKeywU8RocksDB, HexKeywU8Sled),
+    16 => (HexKeywU16InMemory, HexKeywU16TempRocksDB, HexKeywU16RocksDB, HexKeywU16Sled),
+    32 => (HexKeywU32InMemory, HexKeywU32TempRocksDB, HexKeywU32RocksDB, HexKeywU32Sled),
+    64 => (HexKeywU64InMemory, HexKeywU64TempRocksDB, HexKeywU64RocksDB, HexKeywU64Sled),
+    128 => (HexKeywU64x2InMemory, HexKeywU64x2TempRocksDB, HexKeywU64x2RocksDB, HexKeywU64x2Sled),
+    256 => (HexKeywU64x4InMemory, HexKeywU64x4TempRocksDB, HexKeywU64x4RocksDB, HexKeywU64x4Sled));
+
+substitution_map_factory!(Base64Key, 256,
+    8 => (Base64KeywU8InMemory, Base64KeywU8TempRocksDB, Base64KeywU8RocksDB, Base64KeywU8Sled),
+    16 => (Base64KeywU16InMemory, Base64KeywU16TempRocksDB, Base64KeywU16RocksDB, Base64KeywU16Sled),
+    32 => (Base64KeywU32InMemory, Base64KeywU32TempRocksDB, Base64KeywU32RocksDB, Base64KeywU32Sled),
+    64 => (Base64KeywU64InMemory, Base64KeywU64TempRocksDB, Base64KeywU64RocksDB, Base64KeywU64Sled),
+    128 => (Base64KeywU64x2InMemory, Base64KeywU64x2TempRocksDB, Base64KeywU64x2RocksDB, Base64KeywU64x2Sled),
+    256 => (Base64KeywU64x4InMemory, Base64KeywU64x4TempRocksDB, Base64KeywU64x4RocksDB, Base64KeywU64x4Sled));
+
+substitution_map_factory!(Payload<u64>, 64,
+    8 => (PayloadU64wU8InMemory, PayloadU64wU8TempRocksDB, PayloadU64wU8RocksDB, PayloadU64wU8Sled),
+    16 => (PayloadU64wU16InMemory, PayloadU64wU16TempRocksDB, PayloadU64wU16RocksDB, PayloadU64wU16Sled),
+    32 => (PayloadU64wU32InMemory, PayloadU64wU32TempRocksDB, PayloadU64wU32RocksDB, PayloadU64wU32Sled),
+    64 => (PayloadU64wU64InMemory, PayloadU64wU64TempRocksDB, PayloadU64wU64RocksDB, PayloadU64wU64Sled));
+
+substitution_map_factory!(Payload<u64, u64>, 64,
+    8 => (CountedU64wU8InMemory, CountedU64wU8TempRocksDB, CountedU64wU8RocksDB, CountedU64wU8Sled),
+    16 => (CountedU64wU16InMemory, CountedU64wU16TempRocksDB, CountedU64wU16RocksDB, CountedU64wU16Sled),
+    32 => (CountedU64wU32InMemory, CountedU64wU32TempRocksDB, CountedU64wU32RocksDB, CountedU64wU32Sled),
+    64 => (CountedU64wU64InMemory, CountedU64wU64TempRocksDB, CountedU64wU64RocksDB, CountedU64wU64Sled));
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: deletion::DB<VecU64x2RocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-        }
+impl Factory for Vec<[u64; 4]> {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<[u64; 4]>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU64x4InMemory, VecU64x4TempRocksDB, VecU64x4RocksDB, VecU64x4Sled)
     }
-}
 
-impl Factory for Vec<u64> {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<Vec<u64>>> {
-        match backend {
-            StorageBackend::InMemory => {
-                let db: deletion::DB<VecU64InMemory> = deletion::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            StorageBackend::TempRocksDB => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: deletion::DB<VecU64TempRocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            StorageBackend::RocksDB(ref path) => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn strategy() -> Strategy {
+        Strategy::Deletion
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: deletion::DB<VecU64RocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-        }
+    fn supports_id128() -> bool {
+        true
     }
-}
 
-impl Factory for Vec<u32> {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<Vec<u32>>> {
-        match backend {
-            StorageBackend::InMemory => {
-                let db: deletion::DB<VecU32InMemory> = deletion::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            StorageBackend::TempRocksDB => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: deletion::DB<VecU32TempRocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            StorageBackend::RocksDB(ref path) => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn build_id128(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<[u64; 4]>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU64x4ID128InMemory, VecU64x4ID128TempRocksDB, VecU64x4ID128RocksDB, VecU64x4ID128Sled)
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: deletion::DB<VecU32RocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-        }
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<[u64; 4]>>>, Error> {
+        echo_build!(deletion, dimensions, tolerance, backend, VecU64x4InlineInMemory, VecU64x4InlineTempRocksDB, VecU64x4InlineRocksDB, VecU64x4InlineSled)
     }
 }
 
-impl Factory for Vec<u16> {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<Vec<u16>>> {
-        match backend {
-            StorageBackend::InMemory => {
-                let db: deletion::DB<VecU16InMemory> = deletion::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            StorageBackend::TempRocksDB => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: deletion::DB<VecU16TempRocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            StorageBackend::RocksDB(ref path) => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+impl Factory for Vec<[u64; 2]> {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<[u64; 2]>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU64x2InMemory, VecU64x2TempRocksDB, VecU64x2RocksDB, VecU64x2Sled)
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: deletion::DB<VecU16RocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-        }
+    fn strategy() -> Strategy {
+        Strategy::Deletion
     }
-}
 
-impl Factory for Vec<u8> {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<Vec<u8>>> {
-        match backend {
-            StorageBackend::InMemory => {
-                let db: deletion::DB<VecU8InMemory> = deletion::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            StorageBackend::TempRocksDB => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: deletion::DB<VecU8TempRocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            StorageBackend::RocksDB(ref path) => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn supports_id128() -> bool {
+        true
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: deletion::DB<VecU8RocksDB> = deletion::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-        }
+    fn build_id128(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<[u64; 2]>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU64x2ID128InMemory, VecU64x2ID128TempRocksDB, VecU64x2ID128RocksDB, VecU64x2ID128Sled)
     }
-}
 
-impl Factory for [u64; 4] {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<[u64; 4]>> {
-        let partitions = (tolerance + 3) / 2;
-        let partition_bits = Ratio::new_raw(dimensions, partitions).ceil().to_integer();
-
-        match (partition_bits, backend) {
-            (b, StorageBackend::InMemory) if b <= 8 => {
-                let db: substitution::DB<U64x4wU8InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 16 => {
-                let db: substitution::DB<U64x4wU16InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 32 => {
-                let db: substitution::DB<U64x4wU32InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 64 => {
-                let db: substitution::DB<U64x4wU64InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 128 => {
-                let db: substitution::DB<U64x4wU64x2InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 256 => {
-                let db: substitution::DB<U64x4wU64x2InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 8 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x4wU8TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 16 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x4wU16TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 32 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x4wU32TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 64 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x4wU64TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 128 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x4wU64x2TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 256 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x4wU64x4TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 8 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn supports_inline_values() -> bool {
+        true
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x4wU8RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 16 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<[u64; 2]>>>, Error> {
+        echo_build!(deletion, dimensions, tolerance, backend, VecU64x2InlineInMemory, VecU64x2InlineTempRocksDB, VecU64x2InlineRocksDB, VecU64x2InlineSled)
+    }
+}
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x4wU16RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 32 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+impl Factory for Vec<u64> {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u64>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU64InMemory, VecU64TempRocksDB, VecU64RocksDB, VecU64Sled)
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x4wU32RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 64 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn strategy() -> Strategy {
+        Strategy::Deletion
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x4wU64RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 128 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn supports_id128() -> bool {
+        true
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x4wU64x2RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 256 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn build_id128(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u64>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU64ID128InMemory, VecU64ID128TempRocksDB, VecU64ID128RocksDB, VecU64ID128Sled)
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x4wU64x4RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            _ => panic!("Unsupported tolerance"),
-        }
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u64>>>, Error> {
+        echo_build!(deletion, dimensions, tolerance, backend, VecU64InlineInMemory, VecU64InlineTempRocksDB, VecU64InlineRocksDB, VecU64InlineSled)
+    }
+}
+
+impl Factory for Vec<u32> {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u32>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU32InMemory, VecU32TempRocksDB, VecU32RocksDB, VecU32Sled)
+    }
+
+    fn strategy() -> Strategy {
+        Strategy::Deletion
+    }
+
+    fn supports_id128() -> bool {
+        true
+    }
+
+    fn build_id128(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u32>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU32ID128InMemory, VecU32ID128TempRocksDB, VecU32ID128RocksDB, VecU32ID128Sled)
+    }
+
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u32>>>, Error> {
+        echo_build!(deletion, dimensions, tolerance, backend, VecU32InlineInMemory, VecU32InlineTempRocksDB, VecU32InlineRocksDB, VecU32InlineSled)
+    }
+}
+
+impl Factory for Vec<u16> {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u16>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU16InMemory, VecU16TempRocksDB, VecU16RocksDB, VecU16Sled)
+    }
+
+    fn strategy() -> Strategy {
+        Strategy::Deletion
+    }
+
+    fn supports_id128() -> bool {
+        true
+    }
+
+    fn build_id128(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u16>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU16ID128InMemory, VecU16ID128TempRocksDB, VecU16ID128RocksDB, VecU16ID128Sled)
+    }
+
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u16>>>, Error> {
+        echo_build!(deletion, dimensions, tolerance, backend, VecU16InlineInMemory, VecU16InlineTempRocksDB, VecU16InlineRocksDB, VecU16InlineSled)
+    }
+}
+
+impl Factory for Vec<u8> {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u8>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU8InMemory, VecU8TempRocksDB, VecU8RocksDB, VecU8Sled)
+    }
+
+    fn strategy() -> Strategy {
+        Strategy::Deletion
+    }
+
+    fn supports_id128() -> bool {
+        true
+    }
+
+    fn build_id128(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u8>>>, Error> {
+        map_build!(deletion, dimensions, tolerance, backend, VecU8ID128InMemory, VecU8ID128TempRocksDB, VecU8ID128RocksDB, VecU8ID128Sled)
+    }
+
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<Vec<u8>>>, Error> {
+        echo_build!(deletion, dimensions, tolerance, backend, VecU8InlineInMemory, VecU8InlineTempRocksDB, VecU8InlineRocksDB, VecU8InlineSled)
+    }
+}
+
+impl Factory for [u64; 4] {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<[u64; 4]>>, Error> {
+        windowed_build!(map_build, substitution, dimensions, tolerance, backend,
+            8 => (U64x4wU8InMemory, U64x4wU8TempRocksDB, U64x4wU8RocksDB, U64x4wU8Sled),
+            16 => (U64x4wU16InMemory, U64x4wU16TempRocksDB, U64x4wU16RocksDB, U64x4wU16Sled),
+            32 => (U64x4wU32InMemory, U64x4wU32TempRocksDB, U64x4wU32RocksDB, U64x4wU32Sled),
+            64 => (U64x4wU64InMemory, U64x4wU64TempRocksDB, U64x4wU64RocksDB, U64x4wU64Sled),
+            128 => (U64x4wU64x2InMemory, U64x4wU64x2TempRocksDB, U64x4wU64x2RocksDB, U64x4wU64x2Sled),
+            256 => (U64x4wU64x4InMemory, U64x4wU64x4TempRocksDB, U64x4wU64x4RocksDB, U64x4wU64x4Sled))
+    }
+
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
+
+    fn supports_deletion() -> bool {
+        true
+    }
+
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<[u64; 4]>>, Error> {
+        windowed_build!(map_build, deletion, dimensions, tolerance, backend,
+            8 => (U64x4wU8DeletionInMemory, U64x4wU8DeletionTempRocksDB, U64x4wU8DeletionRocksDB, U64x4wU8DeletionSled),
+            16 => (U64x4wU16DeletionInMemory, U64x4wU16DeletionTempRocksDB, U64x4wU16DeletionRocksDB, U64x4wU16DeletionSled),
+            32 => (U64x4wU32DeletionInMemory, U64x4wU32DeletionTempRocksDB, U64x4wU32DeletionRocksDB, U64x4wU32DeletionSled),
+            64 => (U64x4wU64DeletionInMemory, U64x4wU64DeletionTempRocksDB, U64x4wU64DeletionRocksDB, U64x4wU64DeletionSled),
+            128 => (U64x4wU64x2DeletionInMemory, U64x4wU64x2DeletionTempRocksDB, U64x4wU64x2DeletionRocksDB, U64x4wU64x2DeletionSled),
+            256 => (U64x4wU64x4DeletionInMemory, U64x4wU64x4DeletionTempRocksDB, U64x4wU64x4DeletionRocksDB, U64x4wU64x4DeletionSled))
+    }
+
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<[u64; 4]>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (U64x4wU8InlineInMemory, U64x4wU8InlineTempRocksDB, U64x4wU8InlineRocksDB, U64x4wU8InlineSled),
+            16 => (U64x4wU16InlineInMemory, U64x4wU16InlineTempRocksDB, U64x4wU16InlineRocksDB, U64x4wU16InlineSled),
+            32 => (U64x4wU32InlineInMemory, U64x4wU32InlineTempRocksDB, U64x4wU32InlineRocksDB, U64x4wU32InlineSled),
+            64 => (U64x4wU64InlineInMemory, U64x4wU64InlineTempRocksDB, U64x4wU64InlineRocksDB, U64x4wU64InlineSled),
+            128 => (U64x4wU64x2InlineInMemory, U64x4wU64x2InlineTempRocksDB, U64x4wU64x2InlineRocksDB, U64x4wU64x2InlineSled),
+            256 => (U64x4wU64x4InlineInMemory, U64x4wU64x4InlineTempRocksDB, U64x4wU64x4InlineRocksDB, U64x4wU64x4InlineSled))
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(256)
     }
 }
 
 impl Factory for [u64; 2] {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<[u64; 2]>> {
-        let partitions = (tolerance + 3) / 2;
-        let partition_bits = Ratio::new_raw(dimensions, partitions).ceil().to_integer();
-
-        match (partition_bits, backend) {
-            (b, StorageBackend::InMemory) if b <= 8 => {
-                let db: substitution::DB<U64x2wU8InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 16 => {
-                let db: substitution::DB<U64x2wU16InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 32 => {
-                let db: substitution::DB<U64x2wU32InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 64 => {
-                let db: substitution::DB<U64x2wU64InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 128 => {
-                let db: substitution::DB<U64x2wU64x2InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 8 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x2wU8TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 16 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x2wU16TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 32 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x2wU32TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 64 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x2wU64TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 128 => {
-                let id_map = id_map::TempRocksDB::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64x2wU64x2TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 8 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<[u64; 2]>>, Error> {
+        windowed_build!(map_build, substitution, dimensions, tolerance, backend,
+            8 => (U64x2wU8InMemory, U64x2wU8TempRocksDB, U64x2wU8RocksDB, U64x2wU8Sled),
+            16 => (U64x2wU16InMemory, U64x2wU16TempRocksDB, U64x2wU16RocksDB, U64x2wU16Sled),
+            32 => (U64x2wU32InMemory, U64x2wU32TempRocksDB, U64x2wU32RocksDB, U64x2wU32Sled),
+            64 => (U64x2wU64InMemory, U64x2wU64TempRocksDB, U64x2wU64RocksDB, U64x2wU64Sled),
+            128 => (U64x2wU64x2InMemory, U64x2wU64x2TempRocksDB, U64x2wU64x2RocksDB, U64x2wU64x2Sled))
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x2wU8RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 16 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x2wU16RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 32 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn supports_deletion() -> bool {
+        true
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x2wU32RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 64 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<[u64; 2]>>, Error> {
+        windowed_build!(map_build, deletion, dimensions, tolerance, backend,
+            8 => (U64x2wU8DeletionInMemory, U64x2wU8DeletionTempRocksDB, U64x2wU8DeletionRocksDB, U64x2wU8DeletionSled),
+            16 => (U64x2wU16DeletionInMemory, U64x2wU16DeletionTempRocksDB, U64x2wU16DeletionRocksDB, U64x2wU16DeletionSled),
+            32 => (U64x2wU32DeletionInMemory, U64x2wU32DeletionTempRocksDB, U64x2wU32DeletionRocksDB, U64x2wU32DeletionSled),
+            64 => (U64x2wU64DeletionInMemory, U64x2wU64DeletionTempRocksDB, U64x2wU64DeletionRocksDB, U64x2wU64DeletionSled),
+            128 => (U64x2wU64x2DeletionInMemory, U64x2wU64x2DeletionTempRocksDB, U64x2wU64x2DeletionRocksDB, U64x2wU64x2DeletionSled))
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x2wU64RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 128 => {
-                let mut id_map_path = path.clone();
-                id_map_path.push("id_map");
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn supports_inline_values() -> bool {
+        true
+    }
 
-                let id_map = id_map::RocksDB::new(id_map_path.to_str().unwrap());
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64x2wU64x2RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            _ => panic!("Unsupported tolerance"),
-        }
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<[u64; 2]>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (U64x2wU8InlineInMemory, U64x2wU8InlineTempRocksDB, U64x2wU8InlineRocksDB, U64x2wU8InlineSled),
+            16 => (U64x2wU16InlineInMemory, U64x2wU16InlineTempRocksDB, U64x2wU16InlineRocksDB, U64x2wU16InlineSled),
+            32 => (U64x2wU32InlineInMemory, U64x2wU32InlineTempRocksDB, U64x2wU32InlineRocksDB, U64x2wU32InlineSled),
+            64 => (U64x2wU64InlineInMemory, U64x2wU64InlineTempRocksDB, U64x2wU64InlineRocksDB, U64x2wU64InlineSled),
+            128 => (U64x2wU64x2InlineInMemory, U64x2wU64x2InlineTempRocksDB, U64x2wU64x2InlineRocksDB, U64x2wU64x2InlineSled))
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(128)
     }
 }
 
 impl Factory for u64 {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<u64>> {
-        let partitions = (tolerance + 3) / 2;
-        let partition_bits = Ratio::new_raw(dimensions, partitions).ceil().to_integer();
-
-        match (partition_bits, backend) {
-            (b, StorageBackend::InMemory) if b <= 8 => {
-                let db: substitution::DB<U64wU8InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 16 => {
-                let db: substitution::DB<U64wU16InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 32 => {
-                let db: substitution::DB<U64wU32InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 64 => {
-                let db: substitution::DB<U64wU64InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 8 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64wU8TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 16 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64wU16TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 32 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64wU32TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 64 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U64wU64TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 8 => {
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u64>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (U64wU8InMemory, U64wU8TempRocksDB, U64wU8RocksDB, U64wU8Sled),
+            16 => (U64wU16InMemory, U64wU16TempRocksDB, U64wU16RocksDB, U64wU16Sled),
+            32 => (U64wU32InMemory, U64wU32TempRocksDB, U64wU32RocksDB, U64wU32Sled),
+            64 => (U64wU64InMemory, U64wU64TempRocksDB, U64wU64RocksDB, U64wU64Sled))
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64wU8RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 16 => {
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64wU16RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 32 => {
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn supports_deletion() -> bool {
+        true
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64wU32RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 64 => {
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u64>>, Error> {
+        windowed_build!(echo_build, deletion, dimensions, tolerance, backend,
+            8 => (U64wU8DeletionInMemory, U64wU8DeletionTempRocksDB, U64wU8DeletionRocksDB, U64wU8DeletionSled),
+            16 => (U64wU16DeletionInMemory, U64wU16DeletionTempRocksDB, U64wU16DeletionRocksDB, U64wU16DeletionSled),
+            32 => (U64wU32DeletionInMemory, U64wU32DeletionTempRocksDB, U64wU32DeletionRocksDB, U64wU32DeletionSled),
+            64 => (U64wU64DeletionInMemory, U64wU64DeletionTempRocksDB, U64wU64DeletionRocksDB, U64wU64DeletionSled))
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U64wU64RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            _ => panic!("Unsupported tolerance"),
-        }
+    // Already its own identifier
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u64>>, Error> {
+        u64::build(dimensions, tolerance, backend)
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(64)
     }
 }
 
 impl Factory for u32 {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<u32>> {
-        let partitions = (tolerance + 3) / 2;
-        let partition_bits = Ratio::new_raw(dimensions, partitions).ceil().to_integer();
-
-        match (partition_bits, backend) {
-            (b, StorageBackend::InMemory) if b <= 8 => {
-                let db: substitution::DB<U32wU8InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 16 => {
-                let db: substitution::DB<U32wU16InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 32 => {
-                let db: substitution::DB<U32wU32InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 8 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U32wU8TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 16 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U32wU16TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 32 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U32wU32TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 8 => {
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u32>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (U32wU8InMemory, U32wU8TempRocksDB, U32wU8RocksDB, U32wU8Sled),
+            16 => (U32wU16InMemory, U32wU16TempRocksDB, U32wU16RocksDB, U32wU16Sled),
+            32 => (U32wU32InMemory, U32wU32TempRocksDB, U32wU32RocksDB, U32wU32Sled))
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U32wU8RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 16 => {
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U32wU16RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 32 => {
-                let mut map_set_path = PathBuf::from(path);
-                map_set_path.push("map_set");
+    fn supports_deletion() -> bool {
+        true
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U32wU32RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            _ => panic!("Unsupported tolerance"),
-        }
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u32>>, Error> {
+        windowed_build!(echo_build, deletion, dimensions, tolerance, backend,
+            8 => (U32wU8DeletionInMemory, U32wU8DeletionTempRocksDB, U32wU8DeletionRocksDB, U32wU8DeletionSled),
+            16 => (U32wU16DeletionInMemory, U32wU16DeletionTempRocksDB, U32wU16DeletionRocksDB, U32wU16DeletionSled),
+            32 => (U32wU32DeletionInMemory, U32wU32DeletionTempRocksDB, U32wU32DeletionRocksDB, U32wU32DeletionSled))
+    }
+
+    // Already its own identifier
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u32>>, Error> {
+        u32::build(dimensions, tolerance, backend)
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(32)
     }
 }
 
 impl Factory for u16 {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<u16>> {
-        let partitions = (tolerance + 3) / 2;
-        let partition_bits = Ratio::new_raw(dimensions, partitions).ceil().to_integer();
-
-        match (partition_bits, backend) {
-            (b, StorageBackend::InMemory) if b <= 8 => {
-                let db: substitution::DB<U16wU8InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::InMemory) if b <= 16 => {
-                let db: substitution::DB<U16wU16InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 8 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U16wU8TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 16 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U16wU16TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 8 => {
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u16>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (U16wU8InMemory, U16wU8TempRocksDB, U16wU8RocksDB, U16wU8Sled),
+            16 => (U16wU16InMemory, U16wU16TempRocksDB, U16wU16RocksDB, U16wU16Sled))
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U16wU8RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 16 => {
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U16wU16RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            _ => panic!("Unsupported tolerance"),
-        }
+    fn supports_deletion() -> bool {
+        true
+    }
+
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u16>>, Error> {
+        windowed_build!(echo_build, deletion, dimensions, tolerance, backend,
+            8 => (U16wU8DeletionInMemory, U16wU8DeletionTempRocksDB, U16wU8DeletionRocksDB, U16wU8DeletionSled),
+            16 => (U16wU16DeletionInMemory, U16wU16DeletionTempRocksDB, U16wU16DeletionRocksDB, U16wU16DeletionSled))
+    }
+
+    // Already its own identifier
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u16>>, Error> {
+        u16::build(dimensions, tolerance, backend)
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(16)
     }
 }
 
 impl Factory for u8 {
-    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Box<Database<u8>> {
-        let partitions = (tolerance + 3) / 2;
-        let partition_bits = Ratio::new_raw(dimensions, partitions).ceil().to_integer();
-
-        match (partition_bits, backend) {
-            (b, StorageBackend::InMemory) if b <= 8 => {
-                let db: substitution::DB<U8wU8InMemory> = substitution::DB::new(dimensions, tolerance);
-                Box::new(db)
-            },
-            (b, StorageBackend::TempRocksDB) if b <= 8 => {
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::TempRocksDB::new();
-                let db: substitution::DB<U8wU8TempRocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            (b, StorageBackend::RocksDB(ref path)) if b <= 8 => {
-                let mut map_set_path = path.clone();
-                map_set_path.push("map_set");
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u8>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (U8wU8InMemory, U8wU8TempRocksDB, U8wU8RocksDB, U8wU8Sled))
+    }
 
-                let id_map = id_map::Echo::new();
-                let map_set = map_set::RocksDB::new(map_set_path.to_str().unwrap());
-                let db: substitution::DB<U8wU8RocksDB> = substitution::DB::with_stores(dimensions, tolerance, id_map, map_set);
-                Box::new(db)
-            },
-            _ => panic!("Unsupported tolerance"),
-        }
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
+
+    fn supports_deletion() -> bool {
+        true
+    }
+
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u8>>, Error> {
+        windowed_build!(echo_build, deletion, dimensions, tolerance, backend,
+            8 => (U8wU8DeletionInMemory, U8wU8DeletionTempRocksDB, U8wU8DeletionRocksDB, U8wU8DeletionSled))
+    }
+
+    // Already its own identifier
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<u8>>, Error> {
+        u8::build(dimensions, tolerance, backend)
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(8)
+    }
+}
+
+impl Factory for i64 {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<i64>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (I64wU8InMemory, I64wU8TempRocksDB, I64wU8RocksDB, I64wU8Sled),
+            16 => (I64wU16InMemory, I64wU16TempRocksDB, I64wU16RocksDB, I64wU16Sled),
+            32 => (I64wU32InMemory, I64wU32TempRocksDB, I64wU32RocksDB, I64wU32Sled),
+            64 => (I64wU64InMemory, I64wU64TempRocksDB, I64wU64RocksDB, I64wU64Sled))
+    }
+
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
+
+    fn supports_deletion() -> bool {
+        true
+    }
+
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<i64>>, Error> {
+        windowed_build!(echo_build, deletion, dimensions, tolerance, backend,
+            8 => (I64wU8DeletionInMemory, I64wU8DeletionTempRocksDB, I64wU8DeletionRocksDB, I64wU8DeletionSled),
+            16 => (I64wU16DeletionInMemory, I64wU16DeletionTempRocksDB, I64wU16DeletionRocksDB, I64wU16DeletionSled),
+            32 => (I64wU32DeletionInMemory, I64wU32DeletionTempRocksDB, I64wU32DeletionRocksDB, I64wU32DeletionSled),
+            64 => (I64wU64DeletionInMemory, I64wU64DeletionTempRocksDB, I64wU64DeletionRocksDB, I64wU64DeletionSled))
+    }
+
+    // Already its own identifier
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<i64>>, Error> {
+        i64::build(dimensions, tolerance, backend)
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(64)
+    }
+}
+
+impl Factory for i32 {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<i32>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (I32wU8InMemory, I32wU8TempRocksDB, I32wU8RocksDB, I32wU8Sled),
+            16 => (I32wU16InMemory, I32wU16TempRocksDB, I32wU16RocksDB, I32wU16Sled),
+            32 => (I32wU32InMemory, I32wU32TempRocksDB, I32wU32RocksDB, I32wU32Sled))
+    }
+
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
+
+    fn supports_deletion() -> bool {
+        true
+    }
+
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<i32>>, Error> {
+        windowed_build!(echo_build, deletion, dimensions, tolerance, backend,
+            8 => (I32wU8DeletionInMemory, I32wU8DeletionTempRocksDB, I32wU8DeletionRocksDB, I32wU8DeletionSled),
+            16 => (I32wU16DeletionInMemory, I32wU16DeletionTempRocksDB, I32wU16DeletionRocksDB, I32wU16DeletionSled),
+            32 => (I32wU32DeletionInMemory, I32wU32DeletionTempRocksDB, I32wU32DeletionRocksDB, I32wU32DeletionSled))
+    }
+
+    // Already its own identifier
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<i32>>, Error> {
+        i32::build(dimensions, tolerance, backend)
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(32)
+    }
+}
+
+impl Factory for usize {
+    fn build(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<usize>>, Error> {
+        windowed_build!(echo_build, substitution, dimensions, tolerance, backend,
+            8 => (UsizewU8InMemory, UsizewU8TempRocksDB, UsizewU8RocksDB, UsizewU8Sled),
+            16 => (UsizewU16InMemory, UsizewU16TempRocksDB, UsizewU16RocksDB, UsizewU16Sled),
+            32 => (UsizewU32InMemory, UsizewU32TempRocksDB, UsizewU32RocksDB, UsizewU32Sled),
+            64 => (UsizewU64InMemory, UsizewU64TempRocksDB, UsizewU64RocksDB, UsizewU64Sled))
+    }
+
+    fn strategy() -> Strategy {
+        Strategy::Substitution
+    }
+
+    fn supports_deletion() -> bool {
+        true
+    }
+
+    fn build_deletion(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<usize>>, Error> {
+        windowed_build!(echo_build, deletion, dimensions, tolerance, backend,
+            8 => (UsizewU8DeletionInMemory, UsizewU8DeletionTempRocksDB, UsizewU8DeletionRocksDB, UsizewU8DeletionSled),
+            16 => (UsizewU16DeletionInMemory, UsizewU16DeletionTempRocksDB, UsizewU16DeletionRocksDB, UsizewU16DeletionSled),
+            32 => (UsizewU32DeletionInMemory, UsizewU32DeletionTempRocksDB, UsizewU32DeletionRocksDB, UsizewU32DeletionSled),
+            64 => (UsizewU64DeletionInMemory, UsizewU64DeletionTempRocksDB, UsizewU64DeletionRocksDB, UsizewU64DeletionSled))
+    }
+
+    // Already its own identifier
+    fn supports_inline_values() -> bool {
+        true
+    }
+
+    fn build_inline(dimensions: usize, tolerance: usize, backend: StorageBackend) -> Result<Box<Database<usize>>, Error> {
+        usize::build(dimensions, tolerance, backend)
+    }
+
+    fn max_dimensions() -> Option<usize> {
+        Some(64)
     }
 }
@@ -0,0 +1,145 @@
+//! Guarding against oversized variant sets
+//!
+//! Every insert writes one variant per dimension plus one per partition, each
+//! keyed by the partition's window.  With wide windows or very high
+//! dimensional vectors this adds up quickly, and since nothing is written at
+//! construction time a bad configuration only shows up later as memory or disk
+//! exhaustion.  `VariantBudget` lets constructors reject such configurations
+//! up-front.
+
+use std::fmt;
+use std::error;
+use std::mem;
+
+use db::window::Window;
+
+/// Default upper bound on variants written per insert
+pub const DEFAULT_MAX_VARIANTS_PER_INSERT: usize = 100000;
+
+/// Default upper bound on (approximate) bytes written per insert
+pub const DEFAULT_MAX_BYTES_PER_INSERT: usize = 16 * 1024 * 1024;
+
+/// Estimated cost of a single insert
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VariantEstimate {
+    pub partitions: usize,
+    pub variants_per_insert: usize,
+    pub bytes_per_insert: usize,
+}
+
+impl VariantEstimate {
+    /// Estimate insert cost for a DB with the given settings
+    ///
+    /// `variant_bytes` is the in-memory size of a single variant and ID; on-disk
+    /// stores will differ somewhat depending on encoding
+    ///
+    pub fn new(dimensions: usize, tolerance: usize, variant_bytes: usize) -> VariantEstimate {
+        let partitions = if tolerance == 0 {
+            1
+        } else if tolerance > dimensions {
+            (dimensions + 3) / 2
+        } else {
+            (tolerance + 3) / 2
+        };
+        let variants = dimensions.saturating_add(partitions);
+        let key_bytes = variant_bytes + mem::size_of::<Window>();
+
+        VariantEstimate {
+            partitions: partitions,
+            variants_per_insert: variants,
+            bytes_per_insert: variants.saturating_mul(key_bytes),
+        }
+    }
+
+    /// Estimate insert cost for variants of type `V` identified by `ID`
+    ///
+    pub fn for_types<V, ID>(dimensions: usize, tolerance: usize) -> VariantEstimate {
+        VariantEstimate::new(dimensions, tolerance, mem::size_of::<V>() + mem::size_of::<ID>())
+    }
+}
+
+/// Limits on the cost of a single insert
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VariantBudget {
+    pub max_variants_per_insert: usize,
+    pub max_bytes_per_insert: usize,
+    /// Skip the check entirely
+    pub allow_oversized: bool,
+}
+
+impl Default for VariantBudget {
+    fn default() -> VariantBudget {
+        VariantBudget {
+            max_variants_per_insert: DEFAULT_MAX_VARIANTS_PER_INSERT,
+            max_bytes_per_insert: DEFAULT_MAX_BYTES_PER_INSERT,
+            allow_oversized: false,
+        }
+    }
+}
+
+impl VariantBudget {
+    /// A budget which accepts any configuration
+    pub fn unlimited() -> VariantBudget {
+        VariantBudget{allow_oversized: true, ..Default::default()}
+    }
+
+    pub fn check(&self, estimate: VariantEstimate) -> Result<(), VariantExplosion> {
+        if self.allow_oversized {
+            return Ok(())
+        }
+
+        if estimate.variants_per_insert > self.max_variants_per_insert ||
+            estimate.bytes_per_insert > self.max_bytes_per_insert {
+            Err(VariantExplosion{estimate: estimate, budget: *self})
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returned when a DB's estimated insert cost exceeds its budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VariantExplosion {
+    pub estimate: VariantEstimate,
+    pub budget: VariantBudget,
+}
+
+impl fmt::Display for VariantExplosion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f,
+            "each insert would write {} variants (~{} bytes), budget is {} variants ({} bytes); set allow_oversized to override",
+            self.estimate.variants_per_insert,
+            self.estimate.bytes_per_insert,
+            self.budget.max_variants_per_insert,
+            self.budget.max_bytes_per_insert,
+        )
+    }
+}
+
+impl error::Error for VariantExplosion {
+    fn description(&self) -> &str {
+        "estimated variants per insert exceeds budget"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::variant_budget::*;
+
+    #[test]
+    fn estimates_one_variant_per_dimension_and_partition() {
+        let estimate = VariantEstimate::new(64, 5, 16);
+
+        assert_eq!(estimate.partitions, 4);
+        assert_eq!(estimate.variants_per_insert, 68);
+    }
+
+    #[test]
+    fn rejects_estimates_over_budget() {
+        let budget = VariantBudget{max_variants_per_insert: 100, ..Default::default()};
+
+        assert!(budget.check(VariantEstimate::new(64, 5, 16)).is_ok());
+        assert!(budget.check(VariantEstimate::new(1000, 5, 16)).is_err());
+        assert!(VariantBudget::unlimited().check(VariantEstimate::new(1000, 5, 16)).is_ok());
+    }
+}
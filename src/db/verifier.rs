@@ -0,0 +1,147 @@
+//! Verification of query candidates
+//!
+//! The variant index finds every value within `tolerance` hamming distance of
+//! a query, along with some which aren't.  A `Verifier` decides which of
+//! those candidates a query returns.  Substitution DBs use `Hamming` unless
+//! given another with `set_verifier`, which returns exactly the values within
+//! tolerance.
+//!
+//! A verifier only sees candidates the index found, so it can narrow a
+//! query's results but never widen them - a value further than `tolerance`
+//! bits from the query isn't returned however a verifier would rate it.
+//! Distances which are never smaller than the hamming distance (such as
+//! `WeightedHamming` with every weight at least 1) lose nothing to this.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db: DB<U64wU16InMemory> = DB::new(64, 4);
+//!
+//! // Differences in the low byte count double
+//! let mut weights = vec![2; 8];
+//! weights.extend(vec![1; 56]);
+//! db.set_verifier(Box::new(WeightedHamming::new(weights)));
+//! ```
+
+use db::hamming;
+
+/// Decides whether a candidate found by the index matches a query
+///
+pub trait Verifier<V>: Sync + Send {
+    /// Returns true if `candidate` should be returned for `query` by a DB
+    /// with `tolerance`
+    ///
+    fn verify(&self, query: &V, candidate: &V, tolerance: usize) -> bool;
+}
+
+/// Accepts candidates within `tolerance` hamming distance of the query
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Hamming;
+
+impl<V: hamming::Hamming> Verifier<V> for Hamming {
+    fn verify(&self, query: &V, candidate: &V, tolerance: usize) -> bool {
+        query.hamming_lte(candidate, tolerance)
+    }
+}
+
+/// Accepts candidates whose differing dimensions' weights sum to at most
+/// `tolerance`
+///
+/// Dimensions without a weight count as 1.
+///
+#[derive(Clone, Debug)]
+pub struct WeightedHamming {
+    weights: Vec<usize>,
+}
+
+impl WeightedHamming {
+    /// `weights[i]` is the cost of a difference in dimension `i`
+    ///
+    pub fn new(weights: Vec<usize>) -> WeightedHamming {
+        WeightedHamming{weights: weights}
+    }
+
+    pub fn weight(&self, dimension: usize) -> usize {
+        self.weights.get(dimension).map(|w| *w).unwrap_or(1)
+    }
+}
+
+impl<V: hamming::Hamming> Verifier<V> for WeightedHamming {
+    fn verify(&self, query: &V, candidate: &V, tolerance: usize) -> bool {
+        let mut distance = 0;
+        for dimension in query.hamming_indices(candidate) {
+            distance += self.weight(dimension);
+            if distance > tolerance {
+                return false
+            }
+        }
+        true
+    }
+}
+
+/// Accepts candidates whose Jaccard distance from the query (treating each
+/// value as the set of its set bits) is at most `max_distance`
+///
+/// The DB's tolerance still bounds the candidates considered, so it should
+/// be large enough to admit every value within `max_distance`.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct Jaccard {
+    max_distance: f64,
+}
+
+impl Jaccard {
+    pub fn new(max_distance: f64) -> Jaccard {
+        assert!(max_distance >= 0.0 && max_distance <= 1.0, "Jaccard distance must be between 0 and 1");
+        Jaccard{max_distance: max_distance}
+    }
+}
+
+impl<V: hamming::Hamming + Default> Verifier<V> for Jaccard {
+    fn verify(&self, query: &V, candidate: &V, _tolerance: usize) -> bool {
+        let empty = V::default();
+        let different = query.hamming(candidate);
+
+        // |a ∪ b| = |a ∩ b| + |a △ b|, and |a| + |b| = 2|a ∩ b| + |a △ b|
+        let union = (query.hamming(&empty) + candidate.hamming(&empty) + different) / 2;
+        if union == 0 {
+            return true
+        }
+
+        (different as f64) / (union as f64) <= self.max_distance
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::verifier::*;
+
+    #[test]
+    fn hamming_bounds_distance() {
+        assert!(Hamming.verify(&0b0000u64, &0b0011, 2));
+        assert!(!Hamming.verify(&0b0000u64, &0b0111, 2));
+    }
+
+    #[test]
+    fn weighted_hamming_sums_weights() {
+        let verifier = WeightedHamming::new(vec![3, 1]);
+
+        assert!(verifier.verify(&0u64, &0b0010, 1));
+        assert!(!verifier.verify(&0u64, &0b0001, 2));
+        assert!(verifier.verify(&0u64, &0b0001, 3));
+
+        // Unweighted dimensions count as 1
+        assert!(verifier.verify(&0u64, &(1 << 40), 1));
+        assert!(verifier.verify(&[0u64; 2], &[0, 1 << 40], 1));
+    }
+
+    #[test]
+    fn jaccard_compares_set_bits() {
+        let verifier = Jaccard::new(0.5);
+
+        assert!(verifier.verify(&0b0000u64, &0b0000, 0));
+        assert!(verifier.verify(&0b0011u64, &0b0111, 0));
+        assert!(!verifier.verify(&0b0001u64, &0b0110, 0));
+    }
+}
@@ -0,0 +1,164 @@
+//! Values addressed by caller-supplied IDs
+//!
+//! The index itself only knows about values, so a caller tracking mutable
+//! documents would have to remember each document's previous fingerprint in
+//! order to remove it when the document changes.  `VersionedDB` keeps that
+//! mapping instead: `upsert` replaces whatever value was last stored under an
+//! ID, removing the old value's variants and inserting the new one's.
+//!
+//! Several IDs may share a value; the value stays indexed until the last of
+//! them is removed or updated.  The ID mapping is held in memory.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut db = VersionedDB::new(u64::build(64, 4, StorageBackend::InMemory).unwrap());
+//!
+//! db.upsert("doc-1", 0b0000).unwrap();
+//! db.upsert("doc-1", 0b1111).unwrap();
+//! assert_eq!(db.get_ids(&0b1110), Ok(Some(vec!["doc-1"].into_iter().collect())));
+//! ```
+
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::collections::{HashMap, HashSet};
+
+use db::Database;
+use db::hashing::State;
+use error::Error;
+
+pub struct VersionedDB<K, T> {
+    db: Box<Database<T>>,
+    values: HashMap<K, T>,
+    ids: HashMap<T, HashSet<K, State>>,
+}
+
+impl<K, T> VersionedDB<K, T> where
+K: Clone + Eq + Hash,
+T: Clone + Eq + Hash,
+{
+    /// Wrap an empty database
+    ///
+    /// Values inserted into `db` other than through `upsert` can be queried
+    /// but have no ID.
+    ///
+    pub fn new(db: Box<Database<T>>) -> VersionedDB<K, T> {
+        VersionedDB {
+            db: db,
+            values: HashMap::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Store `value` under `id`, replacing its previous value
+    ///
+    /// Returns the previous value, if any
+    ///
+    pub fn upsert(&mut self, id: K, value: T) -> Result<Option<T>, Error> {
+        if self.values.get(&id) == Some(&value) {
+            return Ok(Some(value))
+        }
+
+        let previous = try!(self.remove(&id));
+
+        try!(self.db.insert(value.clone()));
+        self.ids.entry(value.clone()).or_insert_with(HashSet::default).insert(id.clone());
+        self.values.insert(id, value);
+
+        Ok(previous)
+    }
+
+    /// Remove `id`, unindexing its value if no other ID shares it
+    ///
+    /// Returns the removed value, if any.  The ID is only forgotten once its
+    /// value has been unindexed, so a failed removal can be retried.
+    ///
+    pub fn remove(&mut self, id: &K) -> Result<Option<T>, Error> {
+        let value = match self.values.get(id) {
+            Some(value) => value.clone(),
+            None => return Ok(None),
+        };
+
+        let unreferenced = match self.ids.get(&value) {
+            Some(ids) => ids.iter().all(|other| other == id),
+            None => true,
+        };
+
+        if unreferenced {
+            try!(self.db.remove(&value));
+            self.ids.remove(&value);
+        } else if let Some(ids) = self.ids.get_mut(&value) {
+            ids.remove(id);
+        }
+        self.values.remove(id);
+
+        Ok(Some(value))
+    }
+
+    /// The value currently stored under `id`
+    ///
+    pub fn value(&self, id: &K) -> Option<&T> {
+        self.values.get(id)
+    }
+
+    /// IDs of all values within the database's tolerance of `key`
+    ///
+    pub fn get_ids(&self, key: &T) -> Result<Option<HashSet<K, State>>, Error> {
+        let found: HashSet<K, State> = match try!(self.db.get(key)) {
+            Some(values) => {
+                values.iter()
+                    .filter_map(|value| self.ids.get(value))
+                    .flat_map(|ids| ids.iter().cloned())
+                    .collect()
+            },
+            None => return Ok(None),
+        };
+
+        match found.len() {
+            0 => Ok(None),
+            _ => Ok(Some(found)),
+        }
+    }
+
+    /// The wrapped database
+    ///
+    pub fn database(&self) -> &Database<T> {
+        &*self.db
+    }
+
+    pub fn into_inner(self) -> Box<Database<T>> {
+        self.db
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db::{Database, Factory, StorageBackend};
+    use db::versioned::VersionedDB;
+
+    #[test]
+    fn upsert_replaces_previous_value() {
+        let mut db = VersionedDB::new(u64::build(64, 4, StorageBackend::InMemory).unwrap());
+
+        assert_eq!(db.upsert("a", 0b0000u64), Ok(None));
+        assert_eq!(db.upsert("a", 0xFFFF0000u64), Ok(Some(0b0000)));
+
+        assert_eq!(db.database().get(&0b0000), Ok(None));
+        assert_eq!(db.get_ids(&0xFFFF0001), Ok(Some(vec!["a"].into_iter().collect())));
+        assert_eq!(db.value(&"a"), Some(&0xFFFF0000));
+    }
+
+    #[test]
+    fn shared_values_stay_indexed() {
+        let mut db = VersionedDB::new(u64::build(64, 4, StorageBackend::InMemory).unwrap());
+
+        db.upsert("a", 0b0000u64).unwrap();
+        db.upsert("b", 0b0000u64).unwrap();
+        db.upsert("a", 0xFFFF0000u64).unwrap();
+
+        assert_eq!(db.get_ids(&0b0001), Ok(Some(vec!["b"].into_iter().collect())));
+
+        db.remove(&"b").unwrap();
+        assert_eq!(db.database().get(&0b0000), Ok(None));
+    }
+}
@@ -1,7 +1,7 @@
 use std;
 use std::mem::size_of;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, RustcDecodable, RustcEncodable)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Window {
     pub start_dimension: usize,
     pub dimensions: usize,
@@ -50,9 +50,49 @@ window_uint_to_uint!(u16, u16);
 window_uint_to_uint!(u16, u8);
 window_uint_to_uint!(u8, u8);
 
+// Signed values (and `usize`) are windowed as the unsigned type of the same
+// width, so right shifts don't sign-extend
+macro_rules! window_cast_to_uint {
+    ($elem:ident, $unsigned:ident, $out:ident) => {
+        impl Windowable<$out> for $elem {
+            fn window(&self, start_dimension: usize, dimensions: usize) -> $out {
+                (*self as $unsigned).window(start_dimension, dimensions)
+            }
+        }
+    }
+}
+window_cast_to_uint!(i64, u64, u64);
+window_cast_to_uint!(i64, u64, u32);
+window_cast_to_uint!(i64, u64, u16);
+window_cast_to_uint!(i64, u64, u8);
+window_cast_to_uint!(i32, u32, u32);
+window_cast_to_uint!(i32, u32, u16);
+window_cast_to_uint!(i32, u32, u8);
+window_cast_to_uint!(usize, u64, u64);
+window_cast_to_uint!(usize, u64, u32);
+window_cast_to_uint!(usize, u64, u16);
+window_cast_to_uint!(usize, u64, u8);
+
 impl<T: Clone> Windowable<Vec<T>> for Vec<T> {
     fn window(&self, start_dimension: usize, dimensions: usize) -> Vec<T> {
-        self[start_dimension..(start_dimension + dimensions)].to_vec()
+        self.window_ref(start_dimension, dimensions).to_vec()
+    }
+}
+
+/// Values whose windows can be borrowed rather than copied
+///
+/// A vector's window is a sub-slice of it, so borrowing it saves allocating
+/// a copy of each partition of every value inserted or queried.
+///
+pub trait WindowableRef<T: ?Sized> {
+    /// Like `Windowable::window`, borrowing the window from `self`
+    ///
+    fn window_ref(&self, start_dimension: usize, dimensions: usize) -> &T;
+}
+
+impl<T> WindowableRef<[T]> for Vec<T> {
+    fn window_ref(&self, start_dimension: usize, dimensions: usize) -> &[T] {
+        &self[start_dimension..(start_dimension + dimensions)]
     }
 }
 
@@ -307,4 +347,13 @@ mod test {
         }
         quickcheck(prop as fn(usize, usize) -> quickcheck::TestResult);
     }
+
+    #[test]
+    fn signed_windows_dont_sign_extend() {
+        let high: u8 = ::std::i64::MIN.window(56, 8);
+        assert_eq!(high, 0b1000_0000);
+
+        let low: u16 = (-1i32).window(0, 12);
+        assert_eq!(low, 0b1111_1111_1111);
+    }
 }
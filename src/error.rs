@@ -0,0 +1,138 @@
+//! Errors returned by databases and their stores
+//!
+//! Every `Database`, `MapSet` and `IDMap` operation which touches storage
+//! returns `Result<_, Error>`, so a failing disk surfaces to the caller
+//! rather than taking the process down.  In-memory stores never fail.
+
+use std::fmt;
+use std::error;
+
+use db::cost::TooExpensive;
+use db::expansion::TooManyExpansions;
+use db::partition_mask::MaskError;
+use db::params::ParamsMismatch;
+use db::variant_budget::VariantExplosion;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The storage engine failed to read or write
+    Storage(String),
+    /// A key or value couldn't be encoded for storage
+    Encoding(String),
+    /// A stored entry couldn't be decoded
+    Corrupt(String),
+    /// The operation isn't supported by the database or store
+    Unsupported(String),
+    /// The caller's input can't be used (ie a malformed snapshot)
+    Invalid(String),
+    /// A query's estimated candidate count exceeds its limit (see
+    /// `Database::get_bounded`)
+    TooExpensive(TooExpensive),
+    /// A query expansion would issue more queries than allowed (see
+    /// `expansion::expand_query`)
+    TooManyExpansions(TooManyExpansions),
+    /// A masked query's partitions to skip can't be honoured
+    Mask(MaskError),
+    /// A value's identifier is already used by a different value (see
+    /// `id_map::IDStrategy`)
+    Collision(String),
+    /// Stores were opened with other parameters than they were created with
+    /// (see `db::params`)
+    ParamsMismatch(ParamsMismatch),
+    /// Each insert would write more variants than allowed (see
+    /// `db::variant_budget`)
+    VariantExplosion(VariantExplosion),
+}
+
+impl Error {
+    /// Returns true if the error is the storage layer's fault rather than
+    /// the caller's
+    ///
+    pub fn is_storage(&self) -> bool {
+        match *self {
+            Error::Storage(..) | Error::Encoding(..) | Error::Corrupt(..) => true,
+            Error::Unsupported(..) | Error::Invalid(..) | Error::TooExpensive(..) | Error::TooManyExpansions(..) | Error::Mask(..) | Error::Collision(..) | Error::ParamsMismatch(..) | Error::VariantExplosion(..) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Error::Storage(ref e) => write!(f, "storage error: {}", e),
+            Error::Encoding(ref e) => write!(f, "encoding error: {}", e),
+            Error::Corrupt(ref e) => write!(f, "corrupt entry: {}", e),
+            Error::Unsupported(ref e) => write!(f, "unsupported: {}", e),
+            Error::Invalid(ref e) => write!(f, "invalid: {}", e),
+            Error::TooExpensive(ref e) => write!(f, "{}", e),
+            Error::TooManyExpansions(ref e) => write!(f, "{}", e),
+            Error::Mask(ref e) => write!(f, "{}", e),
+            Error::Collision(ref e) => write!(f, "identifier collision: {}", e),
+            Error::ParamsMismatch(ref e) => write!(f, "{}", e),
+            Error::VariantExplosion(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Storage(..) => "storage error",
+            Error::Encoding(..) => "encoding error",
+            Error::Corrupt(..) => "corrupt entry",
+            Error::Unsupported(..) => "unsupported operation",
+            Error::Invalid(..) => "invalid input",
+            Error::TooExpensive(..) => "query exceeds estimated cost limit",
+            Error::TooManyExpansions(..) => "query expansion exceeds maximum expansion count",
+            Error::Mask(..) => "invalid partitions to skip",
+            Error::Collision(..) => "identifier collision",
+            Error::ParamsMismatch(..) => "stores were created with different parameters",
+            Error::VariantExplosion(..) => "estimated variants per insert exceeds budget",
+        }
+    }
+}
+
+/// Errors reported as bare strings (filesystem syncs and the like) come from
+/// the storage layer
+///
+impl From<String> for Error {
+    fn from(e: String) -> Error {
+        Error::Storage(e)
+    }
+}
+
+impl From<TooExpensive> for Error {
+    fn from(e: TooExpensive) -> Error {
+        Error::TooExpensive(e)
+    }
+}
+
+impl From<TooManyExpansions> for Error {
+    fn from(e: TooManyExpansions) -> Error {
+        Error::TooManyExpansions(e)
+    }
+}
+
+impl From<MaskError> for Error {
+    fn from(e: MaskError) -> Error {
+        Error::Mask(e)
+    }
+}
+
+impl From<ParamsMismatch> for Error {
+    fn from(e: ParamsMismatch) -> Error {
+        Error::ParamsMismatch(e)
+    }
+}
+
+impl From<VariantExplosion> for Error {
+    fn from(e: VariantExplosion) -> Error {
+        Error::VariantExplosion(e)
+    }
+}
+
+impl From<Error> for String {
+    fn from(e: Error) -> String {
+        e.to_string()
+    }
+}
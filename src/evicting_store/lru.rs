@@ -0,0 +1,95 @@
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::collections::{BTreeMap, HashMap};
+
+use evicting_store::EvictingStore;
+
+/// Evicts the least-recently used token once more than `capacity` are tracked
+///
+/// Each use is stamped with an increasing tick; the token with the oldest
+/// stamp is the least recently used.
+///
+pub struct LRU<T> {
+    capacity: usize,
+    tick: u64,
+    ticks: HashMap<T, u64>,
+    tokens: BTreeMap<u64, T>,
+}
+
+impl<T> LRU<T> where
+T: Clone + Eq + Hash,
+{
+    pub fn with_capacity(capacity: usize) -> LRU<T> {
+        LRU {
+            capacity: capacity,
+            tick: 0,
+            ticks: HashMap::new(),
+            tokens: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn stamp(&mut self, token: T) {
+        self.tick += 1;
+        if let Some(previous) = self.ticks.insert(token.clone(), self.tick) {
+            self.tokens.remove(&previous);
+        }
+        self.tokens.insert(self.tick, token);
+    }
+}
+
+impl<T> EvictingStore<T> for LRU<T> where
+T: Clone + Eq + Hash,
+{
+    fn touch(&mut self, token: T) -> Option<T> {
+        self.stamp(token);
+
+        if self.ticks.len() <= self.capacity {
+            return None
+        }
+
+        let oldest = *self.tokens.keys().next().unwrap();
+        let evicted = self.tokens.remove(&oldest).unwrap();
+        self.ticks.remove(&evicted);
+        Some(evicted)
+    }
+
+    fn refresh(&mut self, token: &T) {
+        if self.ticks.contains_key(token) {
+            self.stamp(token.clone());
+        }
+    }
+
+    fn remove(&mut self, token: &T) {
+        if let Some(tick) = self.ticks.remove(token) {
+            self.tokens.remove(&tick);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use evicting_store::{EvictingStore, LRU};
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = LRU::with_capacity(2);
+
+        assert_eq!(lru.touch(1), None);
+        assert_eq!(lru.touch(2), None);
+        lru.refresh(&1);
+        assert_eq!(lru.touch(3), Some(2));
+
+        lru.remove(&1);
+        assert_eq!(lru.touch(4), None);
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.touch(5), Some(3));
+    }
+}
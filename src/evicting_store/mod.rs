@@ -1,10 +1,33 @@
-mod entry;
-mod list;
+//! Bounded stores which choose entries to evict
+//!
+//! An `EvictingStore` tracks a set of tokens and decides which to give up once
+//! it holds more than its capacity.  It holds no values itself: the caller
+//! removes evicted tokens from wherever their data lives (see
+//! `db::evicting::EvictingDB`).
 
+// The ARC implementation below is unfinished and not compiled
+// mod entry;
+// mod list;
 // mod arc;
-//mod eviction;
+// mod eviction;
 // mod ghosted_list;
+mod lru;
 
-//trait EvictingStore<T, V> {
-//    fn get(&mut self, token: T) -> (Option<V>, Vec<Eviction<T, V>>);
-//}
+pub use self::lru::LRU;
+
+pub trait EvictingStore<T> {
+    /// Records a use of `token`, adding it if it isn't tracked yet.  Returns
+    /// the token evicted to make room for it, if any
+    ///
+    fn touch(&mut self, token: T) -> Option<T>;
+
+    /// Records a use of `token` if it's tracked, without adding it
+    ///
+    fn refresh(&mut self, token: &T);
+
+    /// Stops tracking `token`
+    ///
+    fn remove(&mut self, token: &T);
+
+    fn len(&self) -> usize;
+}
@@ -0,0 +1,120 @@
+//! Messages & methods of `proto/hammer.proto`
+//!
+//! Written out rather than generated, so building the server doesn't need
+//! `protoc`.  Nested messages & enums of the proto file are declared at the
+//! top level here; their encoding is the same.  The integration tests'
+//! client includes this file too.
+
+use grpcio::{Method, MethodType, Marshaller, pr_ser, pr_de};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Namespace {
+    /// 32, 64, 128 or 256
+    #[prost(uint32, tag="1")]
+    pub bits: u32,
+    /// Vector length for vector namespaces, 0 for binary namespaces
+    #[prost(uint32, tag="2")]
+    pub dimensions: u32,
+    #[prost(uint32, tag="3")]
+    pub tolerance: u32,
+    #[prost(string, tag="4")]
+    pub name: String,
+}
+
+/// A binary value, or the elements of a vector value
+#[derive(Clone, PartialEq, Message)]
+pub struct Value {
+    #[prost(bytes, repeated, tag="1")]
+    pub elements: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AddRequest {
+    #[prost(message, optional, tag="1")]
+    pub namespace: Option<Namespace>,
+    #[prost(message, repeated, tag="2")]
+    pub values: Vec<Value>,
+}
+
+/// `AddResponse.Result`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Enumeration)]
+pub enum AddResult {
+    Ok = 0,
+    Exists = 1,
+    Failed = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AddResponse {
+    #[prost(enumeration="AddResult", repeated, tag="1")]
+    pub results: Vec<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryRequest {
+    #[prost(message, optional, tag="1")]
+    pub namespace: Option<Namespace>,
+    #[prost(message, repeated, tag="2")]
+    pub values: Vec<Value>,
+    #[prost(bool, tag="3")]
+    pub exclude_exact: bool,
+}
+
+/// `QueryResponse.Matches`
+#[derive(Clone, PartialEq, Message)]
+pub struct Matches {
+    #[prost(message, repeated, tag="1")]
+    pub values: Vec<Value>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryResponse {
+    #[prost(message, repeated, tag="1")]
+    pub results: Vec<Matches>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeleteRequest {
+    #[prost(message, optional, tag="1")]
+    pub namespace: Option<Namespace>,
+    #[prost(message, repeated, tag="2")]
+    pub values: Vec<Value>,
+}
+
+/// `DeleteResponse.Result`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Enumeration)]
+pub enum DeleteResult {
+    Ok = 0,
+    NotFound = 1,
+    Failed = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeleteResponse {
+    #[prost(enumeration="DeleteResult", repeated, tag="1")]
+    pub results: Vec<i32>,
+}
+
+/// `Hammer.Add`
+pub const METHOD_ADD: Method<AddRequest, AddResponse> = Method{
+    ty: MethodType::Unary,
+    name: "/hammer.Hammer/Add",
+    req_mar: Marshaller{ser: pr_ser, de: pr_de},
+    resp_mar: Marshaller{ser: pr_ser, de: pr_de},
+};
+
+/// `Hammer.Query`
+pub const METHOD_QUERY: Method<QueryRequest, QueryResponse> = Method{
+    ty: MethodType::Unary,
+    name: "/hammer.Hammer/Query",
+    req_mar: Marshaller{ser: pr_ser, de: pr_de},
+    resp_mar: Marshaller{ser: pr_ser, de: pr_de},
+};
+
+/// `Hammer.Delete`
+pub const METHOD_DELETE: Method<DeleteRequest, DeleteResponse> = Method{
+    ty: MethodType::Unary,
+    name: "/hammer.Hammer/Delete",
+    req_mar: Marshaller{ser: pr_ser, de: pr_de},
+    resp_mar: Marshaller{ser: pr_ser, de: pr_de},
+};
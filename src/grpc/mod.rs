@@ -0,0 +1,271 @@
+//! gRPC server
+//!
+//! Serves the `Hammer` service of `proto/hammer.proto` on `--grpc-bind`
+//! (built with `--features grpc`), for clients with generated gRPC stubs.
+//! Each call is translated into a binary protocol request and answered by
+//! `net::handle`, so it's served from the same namespaces as HTTP and the
+//! binary protocol, and its writes are logged, synced and checked against
+//! degraded namespaces in the same way.
+//!
+//! Values are the raw little-endian bytes of the binary protocol: a single
+//! element of `bits / 8` bytes for binary namespaces, or `dimensions` of them
+//! for vector namespaces.  Each value's matches are returned nearest first,
+//! ties broken by their encoded bytes, as HTTP queries return them.
+//!
+//! Requests the HTTP server would have answered with an error fail with the
+//! closest gRPC status, carrying the HTTP error's message.  Calls are served
+//! on the completion queue threads, so `--grpc-threads` bounds how many run at
+//! once.
+
+pub mod messages;
+
+use std::cmp;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use futures::Future;
+use grpcio::{Environment, RpcContext, RpcStatus, RpcStatusCode, Server, ServerBuilder, Service, ServiceBuilder, UnarySink};
+
+use hammer::db::QueryOptions;
+use http::shutdown::Shutdown;
+use net::{self, Registry};
+use net::protocol::{Op, Request, Reply};
+
+use self::messages::*;
+
+/// Serves the service on `bind` from `threads` background threads, until the
+/// returned server is dropped
+///
+pub fn serve(bind: &str, threads: usize, registry: Registry) -> Result<Server, String> {
+    let (host, port) = try!(split_bind(bind));
+    let env = Arc::new(Environment::new(threads));
+    let mut server = try!(ServerBuilder::new(env)
+        .register_service(service(registry))
+        .bind(host, port)
+        .build()
+        .map_err(|e| format!("unable to bind {}: {}", bind, e)));
+
+    server.start();
+    info!("Serving gRPC on {}", bind);
+    Ok(server)
+}
+
+fn service(registry: Registry) -> Service {
+    let hammer = Hammer{registry: registry};
+    let (add, query, delete) = (hammer.clone(), hammer.clone(), hammer);
+
+    ServiceBuilder::new()
+        .add_unary_handler(&METHOD_ADD, move |ctx, req, sink| respond(ctx, sink, add.add(req)))
+        .add_unary_handler(&METHOD_QUERY, move |ctx, req, sink| respond(ctx, sink, query.query(req)))
+        .add_unary_handler(&METHOD_DELETE, move |ctx, req, sink| respond(ctx, sink, delete.delete(req)))
+        .build()
+}
+
+fn respond<T>(ctx: RpcContext, sink: UnarySink<T>, result: Result<T, RpcStatus>) {
+    let sent = match result {
+        Ok(response) => sink.success(response),
+        Err(status) => sink.fail(status),
+    };
+    ctx.spawn(sent.map_err(|e| warn!("unable to send gRPC response: {}", e)));
+}
+
+#[derive(Clone)]
+struct Hammer {
+    registry: Registry,
+}
+
+impl Hammer {
+    fn add(&self, req: AddRequest) -> Result<AddResponse, RpcStatus> {
+        let request = try!(request(Op::Add, req.namespace.as_ref(), &req.values));
+        let reply = try!(self.handle(&request, &QueryOptions::default()));
+        Ok(AddResponse{results: reply.body.iter().map(|&code| code as i32).collect()})
+    }
+
+    fn query(&self, req: QueryRequest) -> Result<QueryResponse, RpcStatus> {
+        let request = try!(request(Op::Query, req.namespace.as_ref(), &req.values));
+        let options = QueryOptions{exclude_exact: req.exclude_exact, ..QueryOptions::default()};
+        let reply = try!(self.handle(&request, &options));
+
+        let width = request.bits / 8;
+        let elements = cmp::max(request.dimensions, 1);
+        match matches(&reply.body, width, elements) {
+            Ok(results) => Ok(QueryResponse{results: results}),
+            Err(e) => Err(RpcStatus::new(RpcStatusCode::Internal, Some(e))),
+        }
+    }
+
+    fn delete(&self, req: DeleteRequest) -> Result<DeleteResponse, RpcStatus> {
+        let request = try!(request(Op::Delete, req.namespace.as_ref(), &req.values));
+        let reply = try!(self.handle(&request, &QueryOptions::default()));
+        Ok(DeleteResponse{results: reply.body.iter().map(|&code| code as i32).collect()})
+    }
+
+    /// Answers `request` as the binary protocol server would, failing with
+    /// the status of any reply but a 200
+    ///
+    fn handle(&self, request: &Request, options: &QueryOptions) -> Result<Reply, RpcStatus> {
+        let in_progress = Shutdown::begin(&self.registry.shutdown);
+        let reply = match in_progress {
+            Some(_) => net::handle(request, options, &self.registry),
+            None => Reply::error(503, "Server is shutting down"),
+        };
+
+        match reply.status {
+            200 => Ok(reply),
+            _ => Err(status(&reply)),
+        }
+    }
+}
+
+/// The binary protocol request for `op` on `values` in `namespace`
+///
+fn request(op: Op, namespace: Option<&Namespace>, values: &[Value]) -> Result<Request, RpcStatus> {
+    let namespace = match namespace {
+        Some(namespace) => namespace,
+        None => return Err(invalid("request has no namespace".to_string())),
+    };
+    let width = match namespace.bits {
+        32 | 64 | 128 | 256 => namespace.bits as usize / 8,
+        _ => return Err(invalid("Unsuported bitsize".to_string())),
+    };
+    let elements = cmp::max(namespace.dimensions as usize, 1);
+
+    let mut raw = Vec::with_capacity(values.len() * elements * width);
+    for (i, value) in values.iter().enumerate() {
+        if value.elements.len() != elements {
+            return Err(invalid(format!("value {} has {} elements, expected {}", i, value.elements.len(), elements)))
+        }
+        for element in value.elements.iter() {
+            if element.len() != width {
+                return Err(invalid(format!("value {} has an element of {} bytes, expected {}", i, element.len(), width)))
+            }
+            raw.extend_from_slice(element);
+        }
+    }
+
+    Ok(Request{
+        op: op,
+        bits: namespace.bits as usize,
+        dimensions: namespace.dimensions as usize,
+        tolerance: namespace.tolerance as usize,
+        namespace: namespace.name.clone(),
+        values: raw,
+    })
+}
+
+/// Each value's matches from the body of a query reply: a u32 count, then
+/// that many values of `elements` elements of `width` bytes
+///
+fn matches(mut body: &[u8], width: usize, elements: usize) -> Result<Vec<Matches>, String> {
+    let mut results = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 4 {
+            return Err(format!("query reply has {} trailing bytes", body.len()))
+        }
+        let len = LittleEndian::read_u32(&body[..4]) as usize * width * elements;
+        body = &body[4..];
+        if body.len() < len {
+            return Err(format!("query reply is missing {} bytes of matches", len - body.len()))
+        }
+
+        let values = body[..len].chunks(width * elements).map(|value| {
+            Value{elements: value.chunks(width).map(|element| element.to_vec()).collect()}
+        }).collect();
+        results.push(Matches{values: values});
+        body = &body[len..];
+    }
+    Ok(results)
+}
+
+fn invalid(message: String) -> RpcStatus {
+    RpcStatus::new(RpcStatusCode::InvalidArgument, Some(message))
+}
+
+/// The gRPC status closest to the HTTP status of `reply`
+///
+fn status(reply: &Reply) -> RpcStatus {
+    let code = match reply.status {
+        400 => RpcStatusCode::InvalidArgument,
+        404 => RpcStatusCode::NotFound,
+        413 | 429 => RpcStatusCode::ResourceExhausted,
+        501 => RpcStatusCode::Unimplemented,
+        503 => RpcStatusCode::Unavailable,
+        _ => RpcStatusCode::Internal,
+    };
+    RpcStatus::new(code, Some(String::from_utf8_lossy(&reply.body).into_owned()))
+}
+
+fn split_bind(bind: &str) -> Result<(String, u16), String> {
+    let malformed = || format!("{} isn't a host:port", bind);
+    let i = try!(bind.rfind(':').ok_or_else(&malformed));
+    let port = try!(bind[i + 1..].parse().map_err(|_| malformed()));
+    Ok((bind[..i].to_string(), port))
+}
+
+#[cfg(test)]
+mod test {
+    use grpcio::RpcStatusCode;
+
+    use grpc::*;
+    use grpc::messages::*;
+    use net::protocol::{Op, Reply};
+
+    fn namespace(bits: u32, dimensions: u32) -> Namespace {
+        Namespace{bits: bits, dimensions: dimensions, tolerance: 4, name: "test".to_string()}
+    }
+
+    #[test]
+    fn requests_concatenate_elements() {
+        let values = vec![
+            Value{elements: vec![vec![1, 0, 0, 0], vec![2, 0, 0, 0]]},
+            Value{elements: vec![vec![3, 0, 0, 0], vec![4, 0, 0, 0]]},
+        ];
+        let request = request(Op::Add, Some(&namespace(32, 2)), &values).unwrap();
+
+        assert_eq!(request.op, Op::Add);
+        assert_eq!((request.bits, request.dimensions, request.tolerance), (32, 2, 4));
+        assert_eq!(request.namespace, "test");
+        assert_eq!(request.values, vec![1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]);
+    }
+
+    #[test]
+    fn requests_reject_malformed_values() {
+        let short = vec![Value{elements: vec![vec![0; 7]]}];
+        let vector = vec![Value{elements: vec![vec![0; 8], vec![0; 8]]}];
+        let value = vec![Value{elements: vec![vec![0; 8]]}];
+
+        assert_eq!(request(Op::Add, Some(&namespace(64, 0)), &short).unwrap_err().status, RpcStatusCode::InvalidArgument);
+        assert_eq!(request(Op::Add, Some(&namespace(64, 0)), &vector).unwrap_err().status, RpcStatusCode::InvalidArgument);
+        assert_eq!(request(Op::Add, Some(&namespace(48, 0)), &value).unwrap_err().status, RpcStatusCode::InvalidArgument);
+        assert_eq!(request(Op::Add, None, &value).unwrap_err().status, RpcStatusCode::InvalidArgument);
+    }
+
+    #[test]
+    fn decodes_matches() {
+        let body = vec![2, 0, 0, 0, 1, 0, 2, 0, 3, 0, 4, 0, 0, 0, 0, 0];
+        let results = matches(&body, 2, 2).unwrap();
+
+        assert_eq!(results, vec![
+            Matches{values: vec![
+                Value{elements: vec![vec![1, 0], vec![2, 0]]},
+                Value{elements: vec![vec![3, 0], vec![4, 0]]},
+            ]},
+            Matches{values: vec![]},
+        ]);
+        assert!(matches(&body[..10], 2, 2).is_err());
+    }
+
+    #[test]
+    fn maps_http_statuses() {
+        assert_eq!(status(&Reply::error(400, "bad")).status, RpcStatusCode::InvalidArgument);
+        assert_eq!(status(&Reply::error(413, "big")).status, RpcStatusCode::ResourceExhausted);
+        assert_eq!(status(&Reply::error(503, "bye")).status, RpcStatusCode::Unavailable);
+        assert_eq!(status(&Reply::error(500, "oops")).details, Some("oops".to_string()));
+    }
+
+    #[test]
+    fn splits_binds() {
+        assert_eq!(split_bind("localhost:3002"), Ok(("localhost".to_string(), 3002)));
+        assert!(split_bind("localhost").is_err());
+    }
+}
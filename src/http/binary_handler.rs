@@ -1,29 +1,45 @@
 use std::clone::Clone;
 use std::hash::Hash;
 use std::cmp::Eq;
-use std::io::Read;
+use std::fs;
+use std::io::{self, Read, Write, BufRead, BufReader, Lines};
+use std::mem;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
-use bincode;
 use iron::prelude::*;
 use iron::status;
+use iron::response::{ResponseBody, WriteBody};
 use router::Router;
 use persistent::State;
-use rustc_serialize::json;
-use rustc_serialize::base64::{FromBase64, ToBase64};
-use rustc_serialize::{Encodable, Decodable};
-use rustc_serialize::json::ToJson;
-
-use hammer::db::{Database, Factory, StorageBackend};
+use base64;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use hammer::db::{Database, Factory, QueryOptions, StorageBackend};
+use hammer::db::cost::TooExpensive;
+use hammer::db::encoding::encode;
+use hammer::Error;
+use hammer::db::health::HealthReport;
+use hammer::db::hamming::Hamming;
 use hammer::db::id_map::IDMap;
 use hammer::db::map_set::MapSet;
+use hammer::db::temp_dir::TempDir;
 use hammer::db::typemap::*;
 
-use http::{Config, ConfigKey, B32, B64, B128, B256, decode_body, BASE64_CONFIG, AddResult, QueryResult, DeleteResult};
+use http::{Config, ConfigKey, HealthKey, ManifestKey, build, check_namespace, record_namespace, degraded, sync_writes, log_mutation, recent_order, query_options, paging, page_json, Paging, canonical_order, error_response, Queried, B32, B64, B128, B256, binary_namespace, BASE64_CONFIG, AddResult, QueryResult, DeleteResult};
+use http::manifest::{self, Manifest};
+use http::metrics::{self, Registry};
+use http::oplog::{self, Oplog, OplogKey, Op};
+use http::quotas::{self, Quotas, QuotasKey};
+use http::encoding::{Values, RawValue, read_values, decode_base64};
+
+/// Lines read by `ingest` between taking the namespace's lock
+const INGEST_CHUNK_SIZE: usize = 1000;
 
 pub fn add(req: &mut Request) -> IronResult<Response> {
-    let req_body = try!(decode_body::<Vec<String>>(req));
+    let req_body = try!(read_values::<String>(req));
 
     let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
         Some(v) => v.parse::<usize>().unwrap(),
@@ -41,32 +57,76 @@ pub fn add(req: &mut Request) -> IronResult<Response> {
     };
 
     let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+    let oplog_mx = req.get::<State<OplogKey>>().unwrap();
+
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    if let Err(response) = quotas::check_rate(&quotas_mx, &config_mx, &binary_namespace(bits, tolerance, &namespace)) {
+        return Ok(response)
+    }
+
+    let registry = metrics::registry(req);
 
     match bits {
         32 => {
             let dbmap_mx = req.get::<State<B32>>().unwrap();
-            do_add(req_body, bits, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         64 => {
             let dbmap_mx = req.get::<State<B64>>().unwrap();
-            do_add(req_body, bits, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         128 => {
             let dbmap_mx = req.get::<State<B128>>().unwrap();
-            do_add(req_body, bits, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         256 => {
             let dbmap_mx = req.get::<State<B256>>().unwrap();
-            do_add(req_body, bits, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
     }
 }
 
-fn do_add<T>(req_body: Vec<String>, bits: usize, tolerance: usize, namespace: String, config_mx: Arc<RwLock<Config>>, dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>) -> IronResult<Response> where
-T: Clone + Factory + Decodable,
+fn do_add<T>(req_body: Values<String>, bits: usize, tolerance: usize, namespace: String, config_mx: Arc<RwLock<Config>>, health_mx: Arc<RwLock<HashMap<String, HealthReport>>>, manifest_mx: Arc<RwLock<Manifest>>, oplog_mx: Arc<RwLock<Option<Oplog>>>, quotas_mx: Arc<RwLock<Quotas>>, dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>, registry: &Registry) -> IronResult<Response> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + Serialize + DeserializeOwned + RawValue,
+{
+    // Decoding happens before the namespace is locked, so queries are only
+    // blocked for as long as the inserts themselves take
+    let values: Vec<Result<T, String>> = match req_body.decode() {
+        Ok(values) => values,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+
+    let ns = binary_namespace(bits, tolerance, &namespace);
+    if let Err(response) = quotas::check_add(&quotas_mx, &config_mx, &ns, values.len(), &dbmap_mx, &(tolerance, namespace.clone())) {
+        return Ok(response)
+    }
+
+    match add_values(values, bits, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &oplog_mx, &dbmap_mx) {
+        Ok(results) => {
+            quotas_mx.write().unwrap().record_added(&ns, &results);
+            registry.count_values("add", results.iter().map(|result| result.outcome()));
+            let response_body = serde_json::to_string(&results).unwrap();
+            Ok(Response::with((status::Ok, response_body)))
+        },
+        Err(response) => Ok(response),
+    }
+}
+
+/// Inserts decoded `values` into the namespace, opening it if necessary
+///
+/// Returns the result for each value in order, or the response to send if the
+/// namespace can't be written to.  Values are logged to the oplog (if one is
+/// kept) before they're inserted.
+///
+pub fn add_values<T>(values: Vec<Result<T, String>>, bits: usize, tolerance: usize, namespace: &str, config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, oplog_mx: &RwLock<Option<Oplog>>, dbmap_mx: &RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>) -> Result<Vec<AddResult>, Response> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + Serialize,
 {
-    let mut results = Vec::with_capacity(req_body.len());
+    let ns = binary_namespace(bits, tolerance, namespace);
+    let key = (tolerance, namespace.to_string());
+    let sync = config_mx.read().unwrap().sync_writes;
 
     // this is a little contorted, but the idea is to optimize for the
     // frequent case where the DB being inserted into exists and only
@@ -74,69 +134,340 @@ T: Clone + Factory + Decodable,
     let mut db_exists = true;
     loop {
         if !db_exists {
-            let config = {
-                config_mx.read().unwrap().clone()
-            };
-
-            let backend = match config.data_dir {
-                Some(ref dir) => {
-                    let mut value_store_path = dir.clone();
-                    value_store_path.push(format!("b{:03}_{:03}_{:}", bits, tolerance, namespace));
-
-                    StorageBackend::RocksDB(value_store_path)
-                },
-                None => StorageBackend::InMemory
-            };
-
-            let db = Factory::build(bits, tolerance, backend);
-
-            let mut dbmap = dbmap_mx.write().unwrap();
-            dbmap.insert((tolerance.clone(), namespace.clone()), Arc::new(RwLock::new(db)));
+            try!(open(bits, tolerance, namespace, &[], config_mx, health_mx, manifest_mx, dbmap_mx));
         }
 
         let dbmap = dbmap_mx.read().unwrap();
 
-        if !dbmap.contains_key(&(tolerance, namespace.clone())) {
+        if !dbmap.contains_key(&key) {
             db_exists = false;
             continue
         }
 
-        let db_mx = dbmap.get(&(tolerance, namespace)).unwrap();
+        let db_mx = dbmap.get(&key).unwrap();
+
+        if let Some(report) = health_mx.read().unwrap().get(&ns) {
+            return Err(degraded(&ns, report))
+        }
+
+        // DBs which can be written through a shared reference are only
+        // read-locked, so concurrent adds don't wait on each other
+        {
+            let db = db_mx.read().unwrap();
+            if db.shares_writes() {
+                try!(oplog::record(oplog_mx, Op::Add, bits, None, tolerance, namespace, &values));
+                let results = try!(insert_each(&ns, values, |value| db.insert_shared(value)));
+                try!(sync_writes(sync, &**db));
+                return Ok(results)
+            }
+        }
+
         let mut db = db_mx.write().unwrap();
+        try!(oplog::record(oplog_mx, Op::Add, bits, None, tolerance, namespace, &values));
+        let results = try!(insert_each(&ns, values, |value| db.insert(value)));
+        try!(sync_writes(sync, &**db));
 
-        'value: for value_b64 in req_body.into_iter() {
-            let value_bytes = match value_b64.from_base64() {
-                Ok(v) => v,
-                Err(e) => {
-                    results.push(AddResult::Err(format!("unable to base64-decode '{}': {:?}", value_b64, e)));
-                    continue 'value;
+        return Ok(results)
+    }
+}
+
+/// Inserts each of `values` into namespace `ns` with `insert`, returning
+/// their results in order
+///
+fn insert_each<T, F>(ns: &str, values: Vec<Result<T, String>>, mut insert: F) -> Result<Vec<AddResult>, Response> where
+T: Serialize,
+F: FnMut(T) -> Result<bool, Error>,
+{
+    let mut results = Vec::with_capacity(values.len());
+
+    for value in values.into_iter() {
+        match value {
+            Ok(value) => {
+                log_mutation("add", ns, &value);
+                match insert(value) {
+                    Ok(true) => { results.push(AddResult::Ok); },
+                    Ok(false) => { results.push(AddResult::Exists); },
+                    // Nothing was written, so the rest of the batch can go ahead
+                    Err(e @ Error::Collision(..)) => { results.push(AddResult::Err(e.to_string())); },
+                    // Values before this one are kept, as with a failed sync
+                    Err(e) => return Err(error_response(e)),
                 }
-            };
+            },
+            Err(e) => { results.push(AddResult::Err(e)); },
+        }
+    }
 
-            let value: T = match bincode::rustc_serialize::decode(&value_bytes) {
-                Ok(v) => v,
-                Err(e) => {
-                    results.push(AddResult::Err(format!("unable to decode '{}': {:?}", value_b64, e)));
-                    continue 'value;
-                },
-            };
+    Ok(results)
+}
+
+/// Adds newline-delimited base64 values
+///
+/// The body is first copied to a temporary file, so it's never held in
+/// memory, then decoded and inserted `INGEST_CHUNK_SIZE` lines at a time
+/// (releasing the namespace between chunks).  Blank lines are skipped.
+///
+/// The response has a line for each value holding the JSON result `add`
+/// would have returned for it, written as each chunk is inserted.  If the
+/// first chunk can't be added the request fails as `add` would; once results
+/// are being written, a chunk which can't be added (ie over a quota, or in a
+/// namespace which stopped accepting writes) gets the error as the result of
+/// each of its values, and the values in other chunks are kept.
+///
+pub fn ingest(req: &mut Request) -> IronResult<Response> {
+    let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
+        Some(v) => v.parse::<usize>().unwrap(),
+        None => return Ok(Response::with((status::BadRequest, "DB bitsize is required"))),
+    };
+
+    let tolerance = match req.extensions.get::<Router>().unwrap().find("tolerance") {
+        Some(v) => v.parse::<usize>().unwrap(),
+        None => return Ok(Response::with((status::BadRequest, "DB tolerance is required"))),
+    };
+
+    let namespace = match req.extensions.get::<Router>().unwrap().find("namespace") {
+        Some(v) => v.to_string(),
+        None => return Ok(Response::with((status::BadRequest, "DB namespace is required"))),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+    let oplog_mx = req.get::<State<OplogKey>>().unwrap();
+
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    if let Err(response) = quotas::check_rate(&quotas_mx, &config_mx, &binary_namespace(bits, tolerance, &namespace)) {
+        return Ok(response)
+    }
+
+    let registry = metrics::registry(req);
+
+    match bits {
+        32 => {
+            let dbmap_mx = req.get::<State<B32>>().unwrap();
+            do_ingest(&mut req.body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, registry)
+        },
+        64 => {
+            let dbmap_mx = req.get::<State<B64>>().unwrap();
+            do_ingest(&mut req.body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, registry)
+        },
+        128 => {
+            let dbmap_mx = req.get::<State<B128>>().unwrap();
+            do_ingest(&mut req.body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, registry)
+        },
+        256 => {
+            let dbmap_mx = req.get::<State<B256>>().unwrap();
+            do_ingest(&mut req.body, bits, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, registry)
+        },
+        _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    }
+}
+
+fn do_ingest<T, R: Read>(mut body: R, bits: usize, tolerance: usize, namespace: String, config_mx: Arc<RwLock<Config>>, health_mx: Arc<RwLock<HashMap<String, HealthReport>>>, manifest_mx: Arc<RwLock<Manifest>>, oplog_mx: Arc<RwLock<Option<Oplog>>>, quotas_mx: Arc<RwLock<Quotas>>, dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>, registry: Arc<Registry>) -> IronResult<Response> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + Serialize + DeserializeOwned,
+{
+    // Iron only reads the request body until the handler returns, and only
+    // writes the response after, so the body is spooled to disk for the
+    // response to read back
+    let spool = TempDir::new();
+    let path = spool.path().join("body");
+    let spooled = fs::create_dir_all(spool.path())
+        .and_then(|_| fs::File::create(&path))
+        .and_then(|mut f| io::copy(&mut body, &mut f))
+        .and_then(|_| fs::File::open(&path));
+    let lines = match spooled {
+        Ok(f) => BufReader::new(f).lines(),
+        Err(e) => return Ok(Response::with((status::BadRequest, format!("unable to read body: {}", e)))),
+    };
+
+    let mut ingest = Ingest {
+        lines: lines,
+        bits: bits,
+        tolerance: tolerance,
+        namespace: namespace,
+        config_mx: config_mx,
+        health_mx: health_mx,
+        manifest_mx: manifest_mx,
+        oplog_mx: oplog_mx,
+        quotas_mx: quotas_mx,
+        dbmap_mx: dbmap_mx,
+        registry: registry,
+        first: Vec::new(),
+        _spool: spool,
+    };
+
+    let values = match ingest.next_chunk() {
+        Ok(values) => values,
+        Err(e) => return Ok(Response::with((status::BadRequest, format!("unable to read body: {}", e)))),
+    };
+    if !values.is_empty() {
+        ingest.first = match ingest.add_chunk(values) {
+            Ok(results) => results,
+            Err(response) => return Ok(response),
+        };
+    }
+
+    let mut response = Response::with(status::Ok);
+    response.body = Some(Box::new(ingest));
+    Ok(response)
+}
+
+/// The rest of an `ingest` request, inserted as its response is written
+///
+struct Ingest<T> {
+    lines: Lines<BufReader<fs::File>>,
+    bits: usize,
+    tolerance: usize,
+    namespace: String,
+    config_mx: Arc<RwLock<Config>>,
+    health_mx: Arc<RwLock<HashMap<String, HealthReport>>>,
+    manifest_mx: Arc<RwLock<Manifest>>,
+    oplog_mx: Arc<RwLock<Option<Oplog>>>,
+    quotas_mx: Arc<RwLock<Quotas>>,
+    dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>,
+    registry: Arc<Registry>,
+    /// Results of the chunk added before the response was sent
+    first: Vec<AddResult>,
+    /// Holds the spooled body, so it must be dropped after `lines`
+    _spool: TempDir,
+}
+
+impl<T> Ingest<T> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + Serialize + DeserializeOwned,
+{
+    /// Decodes up to `INGEST_CHUNK_SIZE` more values (none once the body's
+    /// been read)
+    ///
+    fn next_chunk(&mut self) -> io::Result<Vec<Result<T, String>>> {
+        let mut values = Vec::with_capacity(INGEST_CHUNK_SIZE);
+        while values.len() < INGEST_CHUNK_SIZE {
+            match self.lines.next() {
+                Some(Ok(ref line)) if line.trim().is_empty() => {},
+                Some(Ok(line)) => values.push(decode_base64(line.trim())),
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+        Ok(values)
+    }
+
+    /// Adds a chunk of values as `add` would
+    ///
+    fn add_chunk(&self, values: Vec<Result<T, String>>) -> Result<Vec<AddResult>, Response> {
+        let ns = binary_namespace(self.bits, self.tolerance, &self.namespace);
+
+        try!(quotas::check_add(&self.quotas_mx, &self.config_mx, &ns, values.len(), &self.dbmap_mx, &(self.tolerance, self.namespace.clone())));
+        let results = try!(add_values(values, self.bits, self.tolerance, &self.namespace, &self.config_mx, &self.health_mx, &self.manifest_mx, &self.oplog_mx, &self.dbmap_mx));
+
+        self.quotas_mx.write().unwrap().record_added(&ns, &results);
+        self.registry.count_values("ingest", results.iter().map(|result| result.outcome()));
+        Ok(results)
+    }
+}
+
+fn write_results(out: &mut ResponseBody, results: &[AddResult]) -> io::Result<()> {
+    for result in results.iter() {
+        try!(writeln!(out, "{}", serde_json::to_string(result).unwrap()));
+    }
+    out.flush()
+}
+
+/// The body of a response refusing a request, as text
+///
+fn response_text(mut response: Response) -> String {
+    let mut body = Vec::new();
+    if let Some(mut write) = response.body.take() {
+        let _ = write.write_body(&mut ResponseBody::new(&mut body));
+    }
+    String::from_utf8_lossy(&body).into_owned()
+}
 
-            match db.insert(value) {
-                true => { results.push(AddResult::Ok); },
-                false => { results.push(AddResult::Exists); },
+impl<T> WriteBody for Ingest<T> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + Serialize + DeserializeOwned,
+{
+    fn write_body(&mut self, out: &mut ResponseBody) -> io::Result<()> {
+        let first = mem::replace(&mut self.first, Vec::new());
+        try!(write_results(out, &first));
+
+        loop {
+            let values = try!(self.next_chunk());
+            if values.is_empty() {
+                return Ok(())
             }
 
+            // Values which didn't decode keep their own error
+            let undecoded: Vec<Option<String>> = values.iter().map(|value| value.as_ref().err().cloned()).collect();
+            let results = match self.add_chunk(values) {
+                Ok(results) => results,
+                Err(response) => {
+                    let e = response_text(response);
+                    undecoded.into_iter().map(|undecoded| AddResult::Err(undecoded.unwrap_or(e.clone()))).collect()
+                },
+            };
+            try!(write_results(out, &results));
         }
+    }
+}
+
+/// Builds the namespace and adds it to `dbmap_mx`
+///
+/// Namespaces whose directory already exists are being re-opened and get
+/// checked before they're used.  If the namespace was opened elsewhere while
+/// it was being built, the DB already in `dbmap_mx` is kept.
+///
+/// New namespaces ignore differences in `ignored`; one already in the
+/// manifest keeps the dimensions it was created ignoring.
+///
+/// Fails with the response to send if the namespace can't be built, ie its
+/// inserts would exceed the variant budget (see `Factory::open`), or with a
+/// 500 if its stores can't be opened.
+///
+pub fn open<T>(bits: usize, tolerance: usize, namespace: &str, ignored: &[usize], config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, dbmap_mx: &RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>) -> Result<(), Response> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory,
+{
+    let ns = binary_namespace(bits, tolerance, namespace);
+    let config = {
+        config_mx.read().unwrap().clone()
+    };
+
+    // Variants already written were computed with the namespace's original
+    // ignored dimensions
+    let ignored = match manifest_mx.read().unwrap().find(&ns) {
+        Some(entry) => entry.ignored_dimensions().to_vec(),
+        None => ignored.to_vec(),
+    };
+
+    let (backend, reopened) = match config.data_dir {
+        Some(ref dir) => {
+            let mut value_store_path = dir.clone();
+            value_store_path.push(&ns);
+            let reopened = if value_store_path.exists() { Some(value_store_path.clone()) } else { None };
+
+            (config.persistent_backend(value_store_path), reopened)
+        },
+        None => (StorageBackend::InMemory, None)
+    };
 
-        break
+    // Namespace directories are named for their parameters, so a mismatch
+    // means the directory's been tampered with
+    let mut db = match build(&ns, bits, tolerance, &ignored, backend, &config) {
+        Ok(db) => db,
+        Err(e) => {
+            warn!("unable to open namespace {}: {}", ns, e);
+            return Err(error_response(e))
+        },
+    };
+    if let Some(ref dir) = reopened {
+        check_namespace(&ns, dir, &mut *db, config.repair_on_open, health_mx);
     }
+    record_namespace(&config, manifest::Entry::binary(bits, tolerance, namespace, config.storage).with_ignored_dimensions(&ignored), manifest_mx);
 
-    let response_body = json::encode(&results.to_json()).unwrap();
-    Ok(Response::with((status::Ok, response_body)))
+    let mut dbmap = dbmap_mx.write().unwrap();
+    dbmap.entry((tolerance, namespace.to_string())).or_insert(Arc::new(RwLock::new(db)));
+    Ok(())
 }
 
 pub fn query(req: &mut Request) -> IronResult<Response> {
-    let req_body = try!(decode_body::<Vec<String>>(req));
+    let req_body = try!(read_values::<String>(req));
 
     let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
         Some(v) => v.parse::<usize>().unwrap(),
@@ -153,67 +484,127 @@ pub fn query(req: &mut Request) -> IronResult<Response> {
         None => return Ok(Response::with((status::BadRequest, "DB namespace is required"))),
     };
 
+    let recent = match recent_order(req) {
+        Ok(recent) => recent,
+        Err(response) => return Ok(response),
+    };
+    let options = match query_options(req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let paging = match paging(req) {
+        Ok(paging) => paging,
+        Err(response) => return Ok(response),
+    };
+
+    let max_candidates = {
+        let config_mx = req.get::<State<ConfigKey>>().unwrap();
+        let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+        if let Err(response) = quotas::check_rate(&quotas_mx, &config_mx, &binary_namespace(bits, tolerance, &namespace)) {
+            return Ok(response)
+        }
+
+        let config = config_mx.read().unwrap();
+        config.max_query_candidates
+    };
+
+    let registry = metrics::registry(req);
+
     match bits {
         32 => {
             let dbmap_mx = req.get::<State<B32>>().unwrap();
-            do_query(req_body, tolerance, namespace, dbmap_mx)
+            do_query(req_body, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         64 => {
             let dbmap_mx = req.get::<State<B64>>().unwrap();
-            do_query(req_body, tolerance, namespace, dbmap_mx)
+            do_query(req_body, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         128 => {
             let dbmap_mx = req.get::<State<B128>>().unwrap();
-            do_query(req_body, tolerance, namespace, dbmap_mx)
+            do_query(req_body, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         256 => {
             let dbmap_mx = req.get::<State<B256>>().unwrap();
-            do_query(req_body, tolerance, namespace, dbmap_mx)
+            do_query(req_body, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
     }
 }
 
-fn do_query<T>(req_body: Vec<String>, tolerance: usize, namespace: String, dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>) -> IronResult<Response> where
-T: Eq + Hash + Clone + Encodable + Decodable,
+fn do_query<T>(req_body: Values<String>, tolerance: usize, namespace: String, recent: bool, options: QueryOptions, paging: Option<Paging>, max_candidates: Option<usize>, dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>, registry: &Registry) -> IronResult<Response> where
+T: 'static + Sync + Send + Eq + Hash + Clone + Serialize + DeserializeOwned + RawValue + Hamming,
 {
-    let mut results = Vec::with_capacity(req_body.len());
+    let values: Vec<Result<T, String>> = match req_body.decode() {
+        Ok(values) => values,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let cursor: Option<T> = match paging.as_ref().map(|paging| paging.cursor(values.len())) {
+        None => None,
+        Some(Ok(cursor)) => cursor,
+        Some(Err(response)) => return Ok(response),
+    };
+    let mut results = Vec::with_capacity(values.len());
 
     match { dbmap_mx.read().unwrap().get(&(tolerance.clone(), namespace.clone())) } {
         None => {
-            for _ in 0..req_body.len() {
+            for _ in 0..values.len() {
                 results.push(QueryResult::None);
             }
         },
         Some(db_mx) => {
-            let db = db_mx.read().unwrap();
+            let db = Queried::new(db_mx);
 
-            'value: for value_b64 in req_body.into_iter() {
-                let value_bytes = match value_b64.from_base64() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        results.push(QueryResult::Err(format!("unable to base64-decode '{}': {:?}", value_b64, e)));
-                        continue 'value;
-                    }
+            let mut queries = Vec::with_capacity(values.len());
+            for value in values.iter() {
+                let value = match *value {
+                    Ok(ref v) => v,
+                    Err(_) => continue,
                 };
 
-                let value: T = match bincode::rustc_serialize::decode(&value_bytes) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        results.push(QueryResult::Err(format!("unable to decode '{}': {:?}", value_b64, e)));
-                        continue 'value;
-                    },
-                };
+                if let Some(limit) = max_candidates {
+                    let estimate = match db.estimate_candidates(value) {
+                        Ok(estimate) => estimate,
+                        Err(e) => return Ok(error_response(e)),
+                    };
+                    if estimate > limit {
+                        return Ok(error_response(Error::TooExpensive(TooExpensive{estimated_candidates: estimate, limit: limit})))
+                    }
+                }
 
-                match db.get(&value) {
-                    Some(found) => {
-                        let found_b64s: Vec<String> = found.iter().map(|v| {
-                            let found_bytes = bincode::rustc_serialize::encode(v, bincode::SizeLimit::Infinite).unwrap();
+                queries.push(value.clone());
+            }
+
+            // Unordered queries are made together, so buckets and candidates
+            // shared between the request's keys are only read once
+            let found: Result<Vec<Option<Value>>, Error> = match paging {
+                Some(ref paging) => queries.iter().map(|query| {
+                    db.get_page_with(query, &options, cursor.as_ref(), paging.limit)
+                        .map(|page| Some(page_json(json!(encode_b64s(&page.values)), page.next)))
+                }).collect(),
+                None if recent => queries.iter().map(|query| {
+                    db.get_recent_with(query, &options).map(|found| found.map(|found| json!(encode_b64s(&found))))
+                }).collect(),
+                None => db.get_many(&queries, &options).map(|found| {
+                    found.into_iter().zip(queries.iter())
+                        .map(|(found, query)| found.map(|found| json!(encode_b64s(&canonical_order(query, found)))))
+                        .collect()
+                }),
+            };
 
-                            found_bytes.to_base64(BASE64_CONFIG)
-                        }).collect();
+            let mut found = match found {
+                Ok(found) => found.into_iter(),
+                Err(e) => return Ok(error_response(e)),
+            };
 
-                        results.push(QueryResult::Ok(found_b64s));
+            for value in values.into_iter() {
+                if let Err(e) = value {
+                    results.push(QueryResult::Err(e));
+                    continue;
+                }
+
+                match found.next().unwrap() {
+                    Some(found) => {
+                        results.push(QueryResult::Ok(found));
                     },
                     None => {
                         results.push(QueryResult::None);
@@ -223,12 +614,76 @@ T: Eq + Hash + Clone + Encodable + Decodable,
         }
     }
 
-    let response_body = json::encode(&results.to_json()).unwrap();
+    registry.count_values("query", results.iter().map(|result| result.outcome()));
+    let response_body = serde_json::to_string(&results).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
+
+/// Base64-encoded bincode of each of `values`
+fn encode_b64s<T: Serialize>(values: &[T]) -> Vec<String> {
+    values.iter().map(|v| {
+        let found_bytes = encode(v).unwrap();
+
+        base64::encode_config(&found_bytes, BASE64_CONFIG)
+    }).collect()
+}
+
+/// Near-duplicates within the submitted values, without touching a namespace
+pub fn pairwise(req: &mut Request) -> IronResult<Response> {
+    let req_body = try!(read_values::<String>(req));
+
+    let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
+        Some(v) => v.parse::<usize>().unwrap(),
+        None => return Ok(Response::with((status::BadRequest, "DB bitsize is required"))),
+    };
+
+    let tolerance = match req.extensions.get::<Router>().unwrap().find("tolerance") {
+        Some(v) => v.parse::<usize>().unwrap(),
+        None => return Ok(Response::with((status::BadRequest, "DB tolerance is required"))),
+    };
+
+    match bits {
+        32 => do_pairwise::<u32>(req_body, bits, tolerance),
+        64 => do_pairwise::<u64>(req_body, bits, tolerance),
+        128 => do_pairwise::<[u64; 2]>(req_body, bits, tolerance),
+        256 => do_pairwise::<[u64; 4]>(req_body, bits, tolerance),
+        _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    }
+}
+
+fn do_pairwise<T>(req_body: Values<String>, bits: usize, tolerance: usize) -> IronResult<Response> where
+T: Factory + Eq + Hash + Clone + DeserializeOwned + RawValue,
+{
+    let decoded: Vec<Result<T, String>> = match req_body.decode() {
+        Ok(decoded) => decoded,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+
+    let mut values: Vec<T> = Vec::with_capacity(decoded.len());
+    for value in decoded.into_iter() {
+        match value {
+            Ok(v) => values.push(v),
+            Err(e) => return Ok(Response::with((status::BadRequest, e))),
+        }
+    }
+
+    let found = ::hammer::db::pairwise::pairwise(&values, bits, tolerance);
+
+    let response_body = serde_json::to_string(&found).unwrap();
     Ok(Response::with((status::Ok, response_body)))
 }
 
 pub fn delete(req: &mut Request) -> IronResult<Response> {
-    let req_body = try!(decode_body::<Vec<String>>(req));
+    delete_values(req, false)
+}
+
+/// Deletes values and checks that no variant still references them
+pub fn delete_verified(req: &mut Request) -> IronResult<Response> {
+    delete_values(req, true)
+}
+
+fn delete_values(req: &mut Request, verified: bool) -> IronResult<Response> {
+    let req_body = try!(read_values::<String>(req));
 
     let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
         Some(v) => v.parse::<usize>().unwrap(),
@@ -245,66 +700,118 @@ pub fn delete(req: &mut Request) -> IronResult<Response> {
         None => return Ok(Response::with((status::BadRequest, "DB namespace is required"))),
     };
 
+    let ns = binary_namespace(bits, tolerance, &namespace);
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    if let Some(report) = health_mx.read().unwrap().get(&ns) {
+        return Ok(degraded(&ns, report))
+    }
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    if let Err(response) = quotas::check_rate(&quotas_mx, &config_mx, &ns) {
+        return Ok(response)
+    }
+
+    let sync = config_mx.read().unwrap().sync_writes;
+    let oplog_mx = req.get::<State<OplogKey>>().unwrap();
+
+    let registry = metrics::registry(req);
+
     match bits {
         32 => {
             let dbmap_mx = req.get::<State<B32>>().unwrap();
-            do_delete(req_body, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         64 => {
             let dbmap_mx = req.get::<State<B64>>().unwrap();
-            do_delete(req_body, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         128 => {
             let dbmap_mx = req.get::<State<B128>>().unwrap();
-            do_delete(req_body, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         256 => {
             let dbmap_mx = req.get::<State<B256>>().unwrap();
-            do_delete(req_body, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize or tolerance"))),
     }
 }
 
-fn do_delete<T>(req_body: Vec<String>, tolerance: usize, namespace: String, dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>) -> IronResult<Response> where
-T: Eq + Hash + Clone + Encodable + Decodable,
+fn do_delete<T>(req_body: Values<String>, bits: usize, tolerance: usize, namespace: String, verified: bool, sync: bool, oplog_mx: Arc<RwLock<Option<Oplog>>>, quotas_mx: Arc<RwLock<Quotas>>, dbmap_mx: Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>, registry: &Registry) -> IronResult<Response> where
+T: Eq + Hash + Clone + Serialize + DeserializeOwned + RawValue,
 {
-    let mut results = Vec::with_capacity(req_body.len());
+    let values: Vec<Result<T, String>> = match req_body.decode() {
+        Ok(values) => values,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
 
-    match { dbmap_mx.read().unwrap().get(&(tolerance.clone(), namespace.clone())) } {
+    match remove_values(values, bits, tolerance, &namespace, verified, sync, &oplog_mx, &dbmap_mx) {
+        Ok(results) => {
+            quotas_mx.write().unwrap().record_removed(&binary_namespace(bits, tolerance, &namespace), &results);
+            registry.count_values("delete", results.iter().map(|result| result.outcome()));
+            let response_body = serde_json::to_string(&results).unwrap();
+            Ok(Response::with((status::Ok, response_body)))
+        },
+        Err(response) => Ok(response),
+    }
+}
+
+/// Removes decoded `values` from the namespace, if it's open
+///
+/// Returns the result for each value in order, or the response to send if the
+/// namespace can't be written to.  Values are logged to the oplog (if one is
+/// kept) before they're removed.
+///
+pub fn remove_values<T>(values: Vec<Result<T, String>>, bits: usize, tolerance: usize, namespace: &str, verified: bool, sync: bool, oplog_mx: &RwLock<Option<Oplog>>, dbmap_mx: &RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>) -> Result<Vec<DeleteResult>, Response> where
+T: Eq + Hash + Clone + Serialize,
+{
+    let ns = binary_namespace(bits, tolerance, namespace);
+    let mut results = Vec::with_capacity(values.len());
+
+    match { dbmap_mx.read().unwrap().get(&(tolerance, namespace.to_string())) } {
         None => {
-            for _ in 0..req_body.len() {
+            for _ in 0..values.len() {
                 results.push(DeleteResult::NotFound);
             }
         },
         Some(db_mx) => {
             let mut db = db_mx.write().unwrap();
+            try!(oplog::record(oplog_mx, Op::Delete, bits, None, tolerance, namespace, &values));
 
-            'value: for value_b64 in req_body.into_iter() {
-                let value_bytes = match value_b64.from_base64() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        results.push(DeleteResult::Err(format!("unable to base64-decode '{}': {:?}", value_b64, e)));
-                        continue 'value;
-                    }
-                };
-
-                let value: T = match bincode::rustc_serialize::decode(&value_bytes) {
+            for value in values.into_iter() {
+                let value = match value {
                     Ok(v) => v,
                     Err(e) => {
-                        results.push(DeleteResult::Err(format!("unable to decode '{}': {:?}", value_b64, e)));
-                        continue 'value;
+                        results.push(DeleteResult::Err(e));
+                        continue;
                     },
                 };
+                log_mutation("delete", &ns, &value);
+
+                if verified {
+                    let report = match db.remove_verified(&value) {
+                        Ok(report) => report,
+                        Err(e) => return Err(error_response(e)),
+                    };
+                    if report.variants_removed == 0 {
+                        results.push(DeleteResult::NotFound);
+                    } else {
+                        results.push(DeleteResult::Verified(report));
+                    }
+                    continue;
+                }
 
                 match db.remove(&value) {
-                    true => { results.push(DeleteResult::Ok); },
-                    false => { results.push(DeleteResult::NotFound); },
+                    Ok(true) => { results.push(DeleteResult::Ok); },
+                    Ok(false) => { results.push(DeleteResult::NotFound); },
+                    Err(e) => return Err(error_response(e)),
                 }
             }
+
+            try!(sync_writes(sync, &**db));
         }
     }
 
-    let response_body = json::encode(&results.to_json()).unwrap();
-    Ok(Response::with((status::Ok, response_body)))
+    Ok(results)
 }
@@ -0,0 +1,61 @@
+use std::sync::{Arc, RwLock};
+
+use iron::prelude::*;
+use iron::status;
+use persistent::State;
+use serde_json;
+
+use hammer::db::disk_usage::{disk_usage, DiskUsage};
+
+use http::{Config, ConfigKey, binary_namespace_params, vector_namespace_params};
+
+#[derive(Serialize)]
+struct UsageReport {
+    usage: DiskUsage,
+    violations: Vec<String>,
+}
+
+pub fn get_binary(req: &mut Request) -> IronResult<Response> {
+    let ns = match binary_namespace_params(req) {
+        Ok(ns) => ns,
+        Err(response) => return Ok(response),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    do_get(ns, config_mx)
+}
+
+pub fn get_vector(req: &mut Request) -> IronResult<Response> {
+    let ns = match vector_namespace_params(req) {
+        Ok(ns) => ns,
+        Err(response) => return Ok(response),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    do_get(ns, config_mx)
+}
+
+fn do_get(ns: String, config_mx: Arc<RwLock<Config>>) -> IronResult<Response> {
+    let config = {
+        config_mx.read().unwrap().clone()
+    };
+
+    let mut path = match config.data_dir {
+        Some(ref dir) => dir.clone(),
+        None => return Ok(Response::with((status::NotFound, "Data is not persisted to disk"))),
+    };
+    path.push(&ns);
+
+    match disk_usage(&path) {
+        Ok(usage) => {
+            let report = UsageReport {
+                violations: config.disk_budget.violations(&usage),
+                usage: usage,
+            };
+
+            let response_body = serde_json::to_string(&report).unwrap();
+            Ok(Response::with((status::Ok, response_body)))
+        },
+        Err(e) => Ok(Response::with((status::NotFound, format!("unable to read namespace data: {}", e)))),
+    }
+}
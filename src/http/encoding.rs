@@ -0,0 +1,259 @@
+//! Request body encodings
+//!
+//! Values are posted as a JSON array of base64-encoded bincode by default.
+//! Clients sending many values can skip the double encoding by setting the
+//! request's `Content-Type`:
+//!
+//! Content-Type                      | Body
+//! ----------------------------------+---------------------------------------------
+//! `application/json` (or unset)     | JSON array of base64-encoded bincode values
+//! `application/vnd.hammer.hex+json` | JSON array of hex-encoded little-endian values
+//! `application/octet-stream`        | Little-endian values, back to back
+//!
+//! In the JSON encodings vector values are arrays of encoded elements; in the
+//! raw encoding each vector is `dimensions` consecutive elements.  Responses
+//! are always JSON holding base64-encoded bincode.
+
+use byteorder::{ByteOrder, LittleEndian};
+use iron::prelude::*;
+use iron::headers::ContentType;
+use router::Router;
+use base64;
+use hex;
+use serde::de::DeserializeOwned;
+
+use hammer::db::encoding::decode;
+
+use http::{decode_body, CURSOR_BASE64_CONFIG};
+use http::limits::{read_body, check_keys};
+
+pub const HEX_JSON: &'static str = "application/vnd.hammer.hex+json";
+pub const OCTET_STREAM: &'static str = "application/octet-stream";
+
+/// A request body's values, not yet decoded
+///
+/// `J` is the JSON representation of a single value: `String` for binary
+/// values, `Vec<String>` for vectors.
+///
+pub enum Values<J> {
+    Base64(Vec<J>),
+    Hex(Vec<J>),
+    Raw(Vec<u8>),
+}
+
+/// Values (or vector elements) with a fixed-width little-endian encoding
+///
+pub trait RawValue: Sized {
+    /// Bytes in each encoded value
+    fn raw_width() -> usize;
+
+    /// Decodes exactly `raw_width()` bytes
+    fn from_raw(bytes: &[u8]) -> Self;
+
+    /// Appends the value's `raw_width()` bytes to `out`
+    fn write_raw(&self, out: &mut Vec<u8>);
+}
+
+impl RawValue for u32 {
+    fn raw_width() -> usize { 4 }
+    fn from_raw(bytes: &[u8]) -> u32 { LittleEndian::read_u32(bytes) }
+    fn write_raw(&self, out: &mut Vec<u8>) { write_u32(out, *self) }
+}
+
+impl RawValue for u64 {
+    fn raw_width() -> usize { 8 }
+    fn from_raw(bytes: &[u8]) -> u64 { LittleEndian::read_u64(bytes) }
+    fn write_raw(&self, out: &mut Vec<u8>) { write_u64(out, *self) }
+}
+
+impl RawValue for [u64; 2] {
+    fn raw_width() -> usize { 16 }
+    fn from_raw(bytes: &[u8]) -> [u64; 2] {
+        [LittleEndian::read_u64(&bytes[0..8]), LittleEndian::read_u64(&bytes[8..16])]
+    }
+    fn write_raw(&self, out: &mut Vec<u8>) {
+        for word in self.iter() { write_u64(out, *word) }
+    }
+}
+
+impl RawValue for [u64; 4] {
+    fn raw_width() -> usize { 32 }
+    fn from_raw(bytes: &[u8]) -> [u64; 4] {
+        [
+            LittleEndian::read_u64(&bytes[0..8]),
+            LittleEndian::read_u64(&bytes[8..16]),
+            LittleEndian::read_u64(&bytes[16..24]),
+            LittleEndian::read_u64(&bytes[24..32]),
+        ]
+    }
+    fn write_raw(&self, out: &mut Vec<u8>) {
+        for word in self.iter() { write_u64(out, *word) }
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, n: u32) {
+    let mut buf = [0; 4];
+    LittleEndian::write_u32(&mut buf, n);
+    out.extend_from_slice(&buf);
+}
+
+fn write_u64(out: &mut Vec<u8>, n: u64) {
+    let mut buf = [0; 8];
+    LittleEndian::write_u64(&mut buf, n);
+    out.extend_from_slice(&buf);
+}
+
+/// Reads the request body in the encoding named by its `Content-Type`
+///
+/// Bodies holding more values than `--max-request-keys` are refused.
+///
+pub fn read_values<J: DeserializeOwned>(req: &mut Request) -> Result<Values<J>, IronError> {
+    // Parameters (ie charset) don't affect the encoding
+    let mime = req.headers.get::<ContentType>().map(|content_type| {
+        format!("{}", content_type.0).split(';').next().unwrap().trim().to_lowercase()
+    });
+
+    let values = match mime {
+        Some(ref mime) if mime == OCTET_STREAM => Values::Raw(try!(read_body(req))),
+        Some(ref mime) if mime == HEX_JSON => Values::Hex(try!(decode_body(req))),
+        _ => Values::Base64(try!(decode_body(req))),
+    };
+
+    let count = match values {
+        Values::Base64(ref values) | Values::Hex(ref values) => values.len(),
+        Values::Raw(ref bytes) => bytes.len() / raw_value_width(req),
+    };
+    try!(check_keys(req, count));
+    Ok(values)
+}
+
+/// Bytes in each raw value of the namespace identified by the request's route
+fn raw_value_width(req: &Request) -> usize {
+    let params = req.extensions.get::<Router>().unwrap();
+    let param = |name: &str| params.find(name).and_then(|v| v.parse::<usize>().ok());
+
+    let bytes = param("bits").unwrap_or(8) / 8;
+    let dimensions = param("dimensions").unwrap_or(1);
+    (bytes * dimensions).max(1)
+}
+
+impl Values<String> {
+    /// Decodes each binary value, in order
+    ///
+    /// Fails if a raw body doesn't divide evenly into values.
+    ///
+    pub fn decode<T: DeserializeOwned + RawValue>(&self) -> Result<Vec<Result<T, String>>, String> {
+        match *self {
+            Values::Base64(ref values) => Ok(values.iter().map(|value| decode_base64(value)).collect()),
+            Values::Hex(ref values) => Ok(values.iter().map(|value| decode_hex(value)).collect()),
+            Values::Raw(ref bytes) => {
+                let width = T::raw_width();
+                if bytes.len() % width != 0 {
+                    return Err(format!("body length {} isn't a multiple of the value width ({} bytes)", bytes.len(), width))
+                }
+
+                Ok(bytes.chunks(width).map(|chunk| Ok(T::from_raw(chunk))).collect())
+            },
+        }
+    }
+}
+
+impl Values<Vec<String>> {
+    /// Decodes each vector value, in order, checking that each has
+    /// `dimensions` elements
+    ///
+    /// Fails if a raw body doesn't divide evenly into vectors.
+    ///
+    pub fn decode_vectors<T: DeserializeOwned + RawValue>(&self, dimensions: usize) -> Result<Vec<Result<Vec<T>, String>>, String> {
+        match *self {
+            Values::Base64(ref vectors) => Ok(vectors.iter().map(|vector| decode_vector(vector, dimensions, decode_base64)).collect()),
+            Values::Hex(ref vectors) => Ok(vectors.iter().map(|vector| decode_vector(vector, dimensions, decode_hex)).collect()),
+            Values::Raw(ref bytes) => {
+                let width = T::raw_width() * dimensions;
+                if width == 0 || bytes.len() % width != 0 {
+                    return Err(format!("body length {} isn't a multiple of the vector width ({} bytes)", bytes.len(), width))
+                }
+
+                Ok(bytes.chunks(width).map(|chunk| {
+                    Ok(chunk.chunks(T::raw_width()).map(T::from_raw).collect())
+                }).collect())
+            },
+        }
+    }
+}
+
+/// Decodes a base64-encoded, bincode-serialized value
+///
+/// Page cursors are URL-safe base64 (see `CURSOR_BASE64_CONFIG`), so that
+/// alphabet is accepted too.
+pub fn decode_base64<T: DeserializeOwned>(value_b64: &str) -> Result<T, String> {
+    let decoded = base64::decode(value_b64).or_else(|e| base64::decode_config(value_b64, CURSOR_BASE64_CONFIG).map_err(|_| e));
+    let value_bytes = match decoded {
+        Ok(v) => v,
+        Err(e) => return Err(format!("unable to base64-decode '{}': {:?}", value_b64, e)),
+    };
+
+    decode(&value_bytes).map_err(|e| format!("unable to decode '{}': {:?}", value_b64, e))
+}
+
+/// Decodes a hex-encoded little-endian value
+fn decode_hex<T: RawValue>(value_hex: &str) -> Result<T, String> {
+    let value_bytes = match hex::decode(value_hex) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("unable to hex-decode '{}': {:?}", value_hex, e)),
+    };
+
+    if value_bytes.len() != T::raw_width() {
+        return Err(format!("expected '{}' to be {} bytes, not {}", value_hex, T::raw_width(), value_bytes.len()))
+    }
+
+    Ok(T::from_raw(&value_bytes))
+}
+
+/// Decodes each element of a vector with `decode_item`, checking that it has
+/// `dimensions` elements
+fn decode_vector<T, F>(items: &[String], dimensions: usize, decode_item: F) -> Result<Vec<T>, String> where
+F: Fn(&str) -> Result<T, String>,
+{
+    let mut vector = Vec::with_capacity(dimensions);
+
+    for item in items.iter() {
+        vector.push(try!(decode_item(item)));
+    }
+
+    if vector.len() != dimensions {
+        return Err(format!("expected vector length to be {}, not {}", dimensions, vector.len()))
+    }
+
+    Ok(vector)
+}
+
+#[cfg(test)]
+mod test {
+    use http::encoding::Values;
+
+    #[test]
+    fn encodings_agree() {
+        let base64: Values<String> = Values::Base64(vec!["AAAAAAAAAQI=".to_string()]);
+        let hex: Values<String> = Values::Hex(vec!["0201000000000000".to_string()]);
+        let raw: Values<String> = Values::Raw(vec![2, 1, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(base64.decode::<u64>(), Ok(vec![Ok(0x0102)]));
+        assert_eq!(hex.decode::<u64>(), Ok(vec![Ok(0x0102)]));
+        assert_eq!(raw.decode::<u64>(), Ok(vec![Ok(0x0102)]));
+
+        let truncated: Values<String> = Values::Raw(vec![0; 7]);
+        assert!(truncated.decode::<u64>().is_err());
+
+        let short: Values<String> = Values::Hex(vec!["0201".to_string()]);
+        assert!(short.decode::<u64>().unwrap()[0].is_err());
+    }
+
+    #[test]
+    fn raw_vectors_split_by_dimensions() {
+        let raw: Values<Vec<String>> = Values::Raw(vec![1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]);
+
+        assert_eq!(raw.decode_vectors::<u32>(2), Ok(vec![Ok(vec![1, 2]), Ok(vec![3, 4])]));
+        assert!(raw.decode_vectors::<u32>(3).is_err());
+    }
+}
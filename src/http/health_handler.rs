@@ -0,0 +1,242 @@
+//! Liveness & readiness probes
+//!
+//! `/healthz` checks the server can still write: that the data dir and the
+//! RocksDB directories under it are writable, and that a sentinel value can
+//! be added to, read back from and removed from a hidden namespace (stored
+//! like any other namespace, in `SENTINEL_DIR`).  It responds 503 if either
+//! check fails, so the server can be restarted.
+//!
+//! `/readyz` runs the same checks, and also responds 503 while any namespace
+//! is degraded or any namespace in the manifest failed to re-open, so load
+//! balancers can route requests elsewhere.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use iron::prelude::*;
+use iron::{status, typemap};
+use persistent::State;
+use rand;
+use serde_json;
+
+use hammer::db::{Database, Factory, StorageBackend};
+use hammer::db::health::HealthReport;
+
+use http::{Config, ConfigKey, HealthKey, ManifestKey, COLUMN_FAMILIES_DIR, B32, B64, B128, B256, V32, V64, V128, V256};
+
+/// Directory under `data_dir` holding the sentinel namespace
+pub const SENTINEL_DIR: &'static str = ".healthz";
+
+/// File written & removed to check a directory is writable
+const PROBE_FILE: &'static str = ".healthz-probe";
+
+/// The hidden namespace sentinel values are written to
+///
+pub struct Sentinel {
+    db: Result<Box<Database<u64>>, String>,
+}
+
+impl Sentinel {
+    /// Opens the sentinel namespace in `config`'s data dir, or in memory
+    ///
+    /// A namespace which can't be opened is reported by every check, rather
+    /// than keeping the server from starting.
+    ///
+    pub fn open(config: &Config) -> Sentinel {
+        let backend = match config.data_dir {
+            Some(ref dir) => config.persistent_backend(dir.join(SENTINEL_DIR)),
+            None => StorageBackend::InMemory,
+        };
+        let db = u64::open(64, 0, backend).map_err(|e| format!("unable to open sentinel namespace: {}", e));
+        if let Err(ref e) = db {
+            warn!("{}", e);
+        }
+        Sentinel{db: db}
+    }
+
+    /// Adds, reads back and removes a random value
+    ///
+    fn round_trip(&mut self) -> Result<(), String> {
+        let db = match self.db {
+            Ok(ref mut db) => db,
+            Err(ref e) => return Err(e.clone()),
+        };
+        let value: u64 = rand::random();
+
+        try!(db.insert(value).map_err(|e| format!("unable to add sentinel: {}", e)));
+        let found = db.get(&value).map_err(|e| format!("unable to query sentinel: {}", e));
+        let removed = db.remove(&value).map_err(|e| format!("unable to delete sentinel: {}", e));
+
+        match try!(found) {
+            Some(ref values) if values.contains(&value) => (),
+            _ => return Err("sentinel wasn't found after it was added".to_string()),
+        }
+        match try!(removed) {
+            true => Ok(()),
+            false => Err("sentinel wasn't found when it was deleted".to_string()),
+        }
+    }
+}
+
+pub struct SentinelKey;
+impl typemap::Key for SentinelKey { type Value = Sentinel; }
+
+/// Outcome of one check - unset errors mean it passed
+///
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+}
+
+impl Check {
+    fn of(errors: Vec<String>) -> Check {
+        Check{ok: errors.is_empty(), errors: errors}
+    }
+}
+
+#[derive(Serialize)]
+struct Storage {
+    /// Directories which couldn't be written to
+    writable: Check,
+    /// Adding, querying & deleting the sentinel
+    round_trip: Check,
+}
+
+impl Storage {
+    fn ok(&self) -> bool {
+        self.writable.ok && self.round_trip.ok
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    healthy: bool,
+    storage: Storage,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Health reports of degraded namespaces, by directory name
+    degraded: BTreeMap<String, HealthReport>,
+    /// Namespaces in the manifest which aren't open, by directory name
+    unopened: Vec<String>,
+    storage: Storage,
+}
+
+/// Reports whether the server can still write
+///
+pub fn healthz(req: &mut Request) -> IronResult<Response> {
+    let storage = check_storage(req);
+    let health = Health{healthy: storage.ok(), storage: storage};
+    let response_body = serde_json::to_string(&health).unwrap();
+
+    match health.healthy {
+        true => Ok(Response::with((status::Ok, response_body))),
+        false => Ok(Response::with((status::ServiceUnavailable, response_body))),
+    }
+}
+
+/// Reports whether every namespace is open and healthy, and the server can
+/// still write
+///
+/// Degraded namespaces still answer queries, but rejecting their writes makes
+/// the server unready.
+///
+pub fn readyz(req: &mut Request) -> IronResult<Response> {
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let degraded: BTreeMap<String, HealthReport> = health_mx.read().unwrap().iter()
+        .map(|(ns, report)| (ns.clone(), report.clone()))
+        .collect();
+    let unopened = unopened_namespaces(req);
+    let storage = check_storage(req);
+
+    let ready = degraded.is_empty() && unopened.is_empty() && storage.ok();
+    let readiness = Readiness{ready: ready, degraded: degraded, unopened: unopened, storage: storage};
+    let response_body = serde_json::to_string(&readiness).unwrap();
+
+    match readiness.ready {
+        true => Ok(Response::with((status::Ok, response_body))),
+        false => Ok(Response::with((status::ServiceUnavailable, response_body))),
+    }
+}
+
+fn check_storage(req: &mut Request) -> Storage {
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+    let sentinel_mx = req.get::<State<SentinelKey>>().unwrap();
+
+    let config = config_mx.read().unwrap().clone();
+    let mut dirs = Vec::new();
+    if let Some(ref data_dir) = config.data_dir {
+        dirs.push(data_dir.clone());
+        if config.column_families.is_some() {
+            dirs.push(data_dir.join(COLUMN_FAMILIES_DIR));
+        }
+        for entry in manifest_mx.read().unwrap().entries() {
+            let dir = data_dir.join(entry.dir_name());
+            if dir.exists() {
+                dirs.push(dir);
+            }
+        }
+    }
+
+    let unwritable = dirs.iter()
+        .filter_map(|dir| probe(dir).err())
+        .collect();
+    let round_trip = sentinel_mx.write().unwrap().round_trip();
+
+    Storage{
+        writable: Check::of(unwritable),
+        round_trip: Check::of(round_trip.err().into_iter().collect()),
+    }
+}
+
+/// Writes & removes a file in `dir`
+///
+fn probe(dir: &Path) -> Result<(), String> {
+    let path: PathBuf = dir.join(PROBE_FILE);
+    let written = File::create(&path)
+        .and_then(|mut f| f.write_all(b"ok").and_then(|_| f.sync_all()))
+        .and_then(|_| fs::remove_file(&path));
+    written.map_err(|e| format!("{:?} isn't writable: {}", dir, e))
+}
+
+/// Namespaces in the manifest which failed to re-open
+///
+fn unopened_namespaces(req: &mut Request) -> Vec<String> {
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+    let entries = manifest_mx.read().unwrap().entries().to_vec();
+
+    let mut unopened = Vec::new();
+    for entry in entries.iter() {
+        let binary_key = (entry.tolerance, entry.namespace.clone());
+        let vector_key = (entry.dimensions.unwrap_or(0), entry.tolerance, entry.namespace.clone());
+
+        let open = match (entry.bits, entry.dimensions) {
+            (32, None) => contains(&req.get::<State<B32>>().unwrap(), &binary_key),
+            (64, None) => contains(&req.get::<State<B64>>().unwrap(), &binary_key),
+            (128, None) => contains(&req.get::<State<B128>>().unwrap(), &binary_key),
+            (256, None) => contains(&req.get::<State<B256>>().unwrap(), &binary_key),
+            (32, Some(_)) => contains(&req.get::<State<V32>>().unwrap(), &vector_key),
+            (64, Some(_)) => contains(&req.get::<State<V64>>().unwrap(), &vector_key),
+            (128, Some(_)) => contains(&req.get::<State<V128>>().unwrap(), &vector_key),
+            (256, Some(_)) => contains(&req.get::<State<V256>>().unwrap(), &vector_key),
+            _ => false,
+        };
+        if !open {
+            unopened.push(entry.dir_name());
+        }
+    }
+    unopened
+}
+
+fn contains<K: Eq + Hash, V>(dbmap_mx: &RwLock<HashMap<K, V>>, key: &K) -> bool {
+    dbmap_mx.read().unwrap().contains_key(key)
+}
@@ -0,0 +1,234 @@
+//! Request size & rate limits
+//!
+//! Request bodies are read into memory before they're decoded, so a single
+//! oversized request could exhaust it.  With `--max-body-bytes` bodies larger
+//! than the limit are refused with `413 Payload Too Large` - as soon as the
+//! `Content-Length` header is read when it's sent, otherwise once the limit
+//! has been read - and `--max-request-keys` refuses requests for more values
+//! than the limit in the same way.  `ingest` and snapshot imports stream
+//! their bodies rather than holding them, so neither limit applies to them.
+//!
+//! With `--rate-limit` each client IP may make that many requests per
+//! second, in bursts of up to as many; requests beyond that are refused with
+//! `429 Too Many Requests`.  `/healthz`, `/readyz` and `/metrics` aren't
+//! limited, so probes keep working while clients are being refused.
+//!
+//! Limits are read from the server's config on each request, so they change
+//! when it's reloaded (see `reload`).  Changing the rate limit starts every
+//! client with a full bucket.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use iron::prelude::*;
+use iron::{status, typemap, BeforeMiddleware};
+use iron::headers::ContentLength;
+
+use http::Config;
+
+/// Clients tracked before those with full buckets are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10000;
+
+/// Paths which are never rate limited
+const UNLIMITED_PATHS: [&'static str; 3] = ["healthz", "readyz", "metrics"];
+
+/// Whether the request's handler streams its body (`ingest` and imports)
+fn streams_body(req: &Request) -> bool {
+    req.url.path.first().map_or(false, |p| p == "ingest") || req.url.path.last().map_or(false, |p| p == "import")
+}
+
+/// Limits applied to each request
+///
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub max_body_bytes: Option<usize>,
+    pub max_request_keys: Option<usize>,
+    /// Requests per second allowed from each client IP
+    pub rate_limit: Option<f64>,
+}
+
+/// A token bucket for each client
+///
+pub struct RateLimiter {
+    rate: f64,
+    clients: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    /// Allows `rate` requests per second, in bursts of up to `rate` (or one
+    /// request, if `rate` is less than 1)
+    ///
+    pub fn new(rate: f64) -> RateLimiter {
+        RateLimiter{rate: rate, clients: Mutex::new(HashMap::new())}
+    }
+
+    fn burst(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    /// Takes a token from `client`'s bucket as of `now`, returning false if
+    /// it's empty
+    ///
+    pub fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let burst = self.burst();
+        let rate = self.rate;
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(&client) {
+            // A client whose bucket has refilled is indistinguishable from
+            // one never seen
+            clients.retain(|_, &mut (tokens, last)| {
+                tokens + elapsed_secs(last, now) * rate < burst
+            });
+        }
+
+        let bucket = clients.entry(client).or_insert((burst, now));
+        bucket.0 = (bucket.0 + elapsed_secs(bucket.1, now) * rate).min(burst);
+        bucket.1 = now;
+
+        if bucket.0 < 1.0 {
+            return false
+        }
+        bucket.0 -= 1.0;
+        true
+    }
+}
+
+fn elapsed_secs(since: Instant, now: Instant) -> f64 {
+    if now <= since {
+        return 0.0
+    }
+    let elapsed = now.duration_since(since);
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+struct LimitsKey;
+impl typemap::Key for LimitsKey { type Value = Limits; }
+
+/// Middleware refusing requests over the configured limits, and making the
+/// limits available to handlers reading bodies (see `read_body`)
+///
+pub struct Guard {
+    config: Arc<RwLock<Config>>,
+    limiter: Mutex<Option<Arc<RateLimiter>>>,
+}
+
+impl Guard {
+    pub fn new(config: Arc<RwLock<Config>>) -> Guard {
+        Guard{config: config, limiter: Mutex::new(None)}
+    }
+
+    /// The limiter for `rate`, replacing the current one if its rate differs
+    ///
+    fn limiter(&self, rate: Option<f64>) -> Option<Arc<RateLimiter>> {
+        let mut limiter = self.limiter.lock().unwrap();
+        if limiter.as_ref().map(|l| l.rate) != rate {
+            *limiter = rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+        }
+        limiter.clone()
+    }
+}
+
+impl BeforeMiddleware for Guard {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let limits = self.config.read().unwrap().limits.clone();
+
+        if let Some(limiter) = self.limiter(limits.rate_limit) {
+            let unlimited = req.url.path.first().map_or(false, |p| UNLIMITED_PATHS.iter().any(|u| u == p));
+            if !unlimited && !limiter.allow(req.remote_addr.ip(), Instant::now()) {
+                return Err(refuse(status::TooManyRequests, "rate limit exceeded".to_string()))
+            }
+        }
+
+        if let (Some(max), Some(&ContentLength(len))) = (limits.max_body_bytes, req.headers.get::<ContentLength>()) {
+            if len > max as u64 && !streams_body(req) {
+                return Err(refuse(status::PayloadTooLarge, format!("body of {} bytes exceeds the limit of {}", len, max)))
+            }
+        }
+
+        req.extensions.insert::<LimitsKey>(limits);
+        Ok(())
+    }
+}
+
+fn refuse(code: status::Status, message: String) -> IronError {
+    let body = json!({
+        "error": if code == status::TooManyRequests { "rate_limited" } else { "too_large" },
+        "message": message,
+    });
+    let e = io::Error::new(io::ErrorKind::Other, message);
+    IronError::new(e, (code, body.to_string()))
+}
+
+/// Reads the request body, refusing it if it exceeds `--max-body-bytes`
+///
+pub fn read_body(req: &mut Request) -> Result<Vec<u8>, IronError> {
+    let max = req.extensions.get::<LimitsKey>().and_then(|limits| limits.max_body_bytes);
+
+    let mut bytes = Vec::new();
+    match max {
+        Some(max) => {
+            itry!((&mut req.body).take(max as u64 + 1).read_to_end(&mut bytes));
+            if bytes.len() > max {
+                return Err(refuse(status::PayloadTooLarge, format!("body exceeds the limit of {} bytes", max)))
+            }
+        },
+        None => { itry!(req.body.read_to_end(&mut bytes)); },
+    }
+    Ok(bytes)
+}
+
+/// Like `read_body`, for bodies which must be UTF-8
+///
+pub fn read_body_string(req: &mut Request) -> Result<String, IronError> {
+    let bytes = try!(read_body(req));
+    String::from_utf8(bytes).map_err(|e| IronError::new(e, (status::BadRequest, "Body isn't UTF-8")))
+}
+
+/// Refuses a request for `count` values if it exceeds `--max-request-keys`
+///
+pub fn check_keys(req: &Request, count: usize) -> Result<(), IronError> {
+    match req.extensions.get::<LimitsKey>().and_then(|limits| limits.max_request_keys) {
+        Some(max) if count > max => Err(refuse(status::PayloadTooLarge, format!("request for {} values exceeds the limit of {}", count, max))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use http::limits::RateLimiter;
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let limiter = RateLimiter::new(2.0);
+        let (a, b) = (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let start = Instant::now();
+
+        assert!(limiter.allow(a, start));
+        assert!(limiter.allow(a, start));
+        assert!(!limiter.allow(a, start));
+
+        // Clients have a bucket each
+        assert!(limiter.allow(b, start));
+
+        assert!(limiter.allow(a, start + Duration::from_millis(500)));
+        assert!(!limiter.allow(a, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn slow_rates_allow_single_requests() {
+        let limiter = RateLimiter::new(0.5);
+        let client = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let start = Instant::now();
+
+        assert!(limiter.allow(client, start));
+        assert!(!limiter.allow(client, start + Duration::from_secs(1)));
+        assert!(limiter.allow(client, start + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,164 @@
+//! Record of the namespaces persisted under `data_dir`
+//!
+//! Namespaces are created by their first write, so without a record of what
+//! exists a restarted server can't answer queries until each namespace has
+//! been written to again.  Every namespace created with a `data_dir` is
+//! recorded in the manifest, and the server re-opens everything it lists on
+//! startup.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde_json;
+
+use http::{StorageEngine, binary_namespace, vector_namespace};
+
+const MANIFEST_FILE: &'static str = "manifest.json";
+
+/// A persisted namespace
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub bits: usize,
+    /// Vector length, unset for binary namespaces
+    pub dimensions: Option<usize>,
+    pub tolerance: usize,
+    pub namespace: String,
+    /// Engine the namespace was written with (see `StorageEngine::name`)
+    pub storage: String,
+    /// Dimensions the namespace was created ignoring, unset if none
+    pub ignored_dimensions: Option<Vec<usize>>,
+}
+
+impl Entry {
+    pub fn binary(bits: usize, tolerance: usize, namespace: &str, storage: StorageEngine) -> Entry {
+        Entry{bits: bits, dimensions: None, tolerance: tolerance, namespace: namespace.to_string(), storage: storage.name().to_string(), ignored_dimensions: None}
+    }
+
+    pub fn vector(bits: usize, dimensions: usize, tolerance: usize, namespace: &str, storage: StorageEngine) -> Entry {
+        Entry{bits: bits, dimensions: Some(dimensions), tolerance: tolerance, namespace: namespace.to_string(), storage: storage.name().to_string(), ignored_dimensions: None}
+    }
+
+    pub fn with_ignored_dimensions(mut self, dimensions: &[usize]) -> Entry {
+        self.ignored_dimensions = if dimensions.is_empty() { None } else { Some(dimensions.to_vec()) };
+        self
+    }
+
+    pub fn ignored_dimensions(&self) -> &[usize] {
+        match self.ignored_dimensions {
+            Some(ref dimensions) => dimensions,
+            None => &[],
+        }
+    }
+
+    /// Name of the directory (under `data_dir`) holding the namespace
+    pub fn dir_name(&self) -> String {
+        match self.dimensions {
+            Some(dimensions) => vector_namespace(self.bits, dimensions, self.tolerance, &self.namespace),
+            None => binary_namespace(self.bits, self.tolerance, &self.namespace),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Reads the manifest in `data_dir`, which is empty if none has been
+    /// written yet
+    ///
+    pub fn load(data_dir: &Path) -> Result<Manifest, String> {
+        let path = data_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Default::default())
+        }
+
+        let mut payload = String::new();
+        let mut f = try!(fs::File::open(&path).map_err(|e| format!("{}", e)));
+        try!(f.read_to_string(&mut payload).map_err(|e| format!("{}", e)));
+
+        serde_json::from_str(&payload).map_err(|e| format!("unreadable {}: {}", MANIFEST_FILE, e))
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The entry for the namespace stored in directory `dir_name`
+    ///
+    pub fn find(&self, dir_name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.dir_name() == dir_name)
+    }
+
+    /// Adds `entry` and rewrites the manifest in `data_dir` if it wasn't
+    /// already recorded
+    ///
+    pub fn record(&mut self, data_dir: &Path, entry: Entry) -> Result<(), String> {
+        if self.entries.contains(&entry) {
+            return Ok(())
+        }
+        self.entries.push(entry);
+        self.save(data_dir)
+    }
+
+    /// Removes the entry for the namespace stored in directory `dir_name` and
+    /// rewrites the manifest in `data_dir` if it was recorded
+    ///
+    pub fn forget(&mut self, data_dir: &Path, dir_name: &str) -> Result<(), String> {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.dir_name() != dir_name);
+        if self.entries.len() == before {
+            return Ok(())
+        }
+        self.save(data_dir)
+    }
+
+    /// The manifest is written to a temporary file and renamed into place, so
+    /// a crash mid-write leaves the previous manifest intact
+    ///
+    fn save(&self, data_dir: &Path) -> Result<(), String> {
+        try!(fs::create_dir_all(data_dir).map_err(|e| format!("{}", e)));
+        let path = data_dir.join(MANIFEST_FILE);
+        let tmp_path = data_dir.join(format!("{}.tmp", MANIFEST_FILE));
+
+        let payload = try!(serde_json::to_string(self).map_err(|e| format!("{}", e)));
+        {
+            let mut f = try!(fs::File::create(&tmp_path).map_err(|e| format!("{}", e)));
+            try!(f.write_all(payload.as_bytes()).map_err(|e| format!("{}", e)));
+            try!(f.sync_all().map_err(|e| format!("{}", e)));
+        }
+        fs::rename(&tmp_path, &path).map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hammer::db::temp_dir::TempDir;
+
+    use http::StorageEngine;
+    use http::manifest::{Entry, Manifest};
+
+    #[test]
+    fn records_each_namespace_once() {
+        let dir = TempDir::new();
+        let mut manifest = Manifest::load(dir.path()).unwrap();
+        assert!(manifest.entries().is_empty());
+
+        manifest.record(dir.path(), Entry::binary(64, 4, "foo", StorageEngine::RocksDB)).unwrap();
+        manifest.record(dir.path(), Entry::vector(64, 8, 2, "foo", StorageEngine::RocksDB).with_ignored_dimensions(&[0, 3])).unwrap();
+        manifest.record(dir.path(), Entry::binary(64, 4, "foo", StorageEngine::RocksDB)).unwrap();
+
+        let reloaded = Manifest::load(dir.path()).unwrap();
+        assert_eq!(reloaded, manifest);
+        assert_eq!(reloaded.entries().len(), 2);
+        assert_eq!(reloaded.entries()[1].dir_name(), "v064_008_002_foo");
+        assert_eq!(reloaded.find("v064_008_002_foo").unwrap().ignored_dimensions(), &[0, 3]);
+        assert_eq!(reloaded.find("b064_004_foo").unwrap().ignored_dimensions(), &[] as &[usize]);
+
+        manifest.forget(dir.path(), "b064_004_foo").unwrap();
+        assert_eq!(Manifest::load(dir.path()).unwrap().entries().len(), 1);
+    }
+}
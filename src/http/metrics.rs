@@ -0,0 +1,480 @@
+//! Prometheus metrics
+//!
+//! `GET /metrics` reports the following in Prometheus' text format:
+//!
+//! Metric                                 | Type      | Labels
+//! ---------------------------------------+-----------+-------------------
+//! `hammer_http_requests_total`           | counter   | handler, code
+//! `hammer_http_request_duration_seconds` | histogram | handler
+//! `hammer_values_total`                  | counter   | operation, result
+//! `hammer_namespace_values`              | gauge     | namespace
+//! `hammer_namespace_variant_keys`        | gauge     | namespace
+//! `hammer_namespace_memory_bytes`        | gauge     | namespace
+//! `hammer_namespace_disk_bytes`          | gauge     | namespace
+//! `hammer_namespace_bucket_size`         | gauge     | namespace, partition, quantile
+//! `hammer_namespace_bucket_skew`         | gauge     | namespace, partition
+//! `hammer_rocksdb_sst_files`             | gauge     | namespace
+//! `hammer_rocksdb_open_files`            | gauge     | namespace
+//! `hammer_shadow_queries_total`          | counter   | namespace
+//! `hammer_shadow_mismatched_queries_total` | counter | namespace
+//! `hammer_shadow_matches_total`          | counter   | namespace, difference
+//! `hammer_shadow_query_seconds_total`    | counter   | namespace, db
+//!
+//! `hammer_values_total` counts each value passed to add, ingest, query and
+//! delete by its result (ie a query's values are counted as hits or misses).
+//!
+//! Namespace gauges come from `Database::stats`, which reads every entry of a
+//! namespace, so scrapes only refresh them once every `STATS_INTERVAL_SECS`.
+//! `hammer_namespace_bucket_size` reports the 0.5, 0.9 & 0.99 quantiles and
+//! maximum (quantile 1) of each partition's bucket sizes, and
+//! `hammer_namespace_bucket_skew` the fraction of a partition's entries held
+//! by its largest bucket - a partition where one bucket holds most entries
+//! makes every query probing it slow.
+//! The RocksDB gauges are only reported for namespaces persisted under
+//! `data_dir` - the rocksdb bindings don't expose RocksDB's internal
+//! statistics, so they're measured from the namespace's files.
+//!
+//! The shadow counters report `ShadowStats` for namespaces shadowed by a
+//! candidate (see `db::shadow`): `hammer_shadow_matches_total` counts values
+//! only the candidate found (difference "extra") or only the namespace found
+//! ("missing"), and `hammer_shadow_query_seconds_total` the time spent
+//! querying each db ("primary" or "candidate").  They're cheap to read, so
+//! they're refreshed on every scrape.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use iron::prelude::*;
+use iron::{status, typemap, BeforeMiddleware, AfterMiddleware};
+use iron::headers::ContentType;
+use persistent::State;
+
+use hammer::db::Database;
+use hammer::db::shadow::ShadowStats;
+use hammer::db::disk_usage::disk_usage;
+use hammer::db::stats::Stats;
+use hammer::Error;
+
+use http::{ConfigKey, binary_namespace, vector_namespace, B32, B64, B128, B256, V32, V64, V128, V256};
+
+/// Minimum time between refreshes of the namespace gauges
+const STATS_INTERVAL_SECS: u64 = 60;
+
+/// Upper bounds of the request latency histogram's buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Handler labels, so requests for unknown paths can't add label values
+const HANDLERS: [&'static str; 16] = ["add", "query", "delete", "pairwise", "ingest", "tuning", "delete_verified", "disk_usage", "export", "import", "db", "dbs", "healthz", "readyz", "metrics", "oplog"];
+
+#[derive(Clone, Default)]
+struct Histogram {
+    /// Observations in each of `LATENCY_BUCKETS`, not cumulative
+    buckets: [u64; 10],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Stats of a namespace, as of the last refresh
+struct NamespaceStats {
+    name: String,
+    stats: Result<Stats, Error>,
+}
+
+/// Metrics collected since the server started
+///
+pub struct Registry {
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    latencies: Mutex<BTreeMap<&'static str, Histogram>>,
+    values: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    namespaces: Mutex<Option<(Instant, Vec<NamespaceStats>)>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            requests: Mutex::new(BTreeMap::new()),
+            latencies: Mutex::new(BTreeMap::new()),
+            values: Mutex::new(BTreeMap::new()),
+            namespaces: Mutex::new(None),
+        }
+    }
+
+    /// Counts a value passed to `operation` by its result
+    ///
+    pub fn count_values<I: Iterator<Item=&'static str>>(&self, operation: &'static str, results: I) {
+        let mut values = self.values.lock().unwrap();
+        for result in results {
+            *values.entry((operation, result)).or_insert(0) += 1;
+        }
+    }
+
+    fn observe_request(&self, handler: &'static str, code: u16, elapsed: Duration) {
+        *self.requests.lock().unwrap().entry((handler, code)).or_insert(0) += 1;
+        self.latencies.lock().unwrap().entry(handler).or_insert_with(Default::default).observe(seconds(elapsed));
+    }
+
+    /// Renders every metric except the namespace gauges
+    ///
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP hammer_http_requests_total HTTP requests served, by handler & status code");
+        let _ = writeln!(out, "# TYPE hammer_http_requests_total counter");
+        for (&(handler, code), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "hammer_http_requests_total{{handler=\"{}\",code=\"{}\"}} {}", handler, code, count);
+        }
+
+        let _ = writeln!(out, "# HELP hammer_http_request_duration_seconds HTTP request latency, by handler");
+        let _ = writeln!(out, "# TYPE hammer_http_request_duration_seconds histogram");
+        for (handler, histogram) in self.latencies.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += *count;
+                let _ = writeln!(out, "hammer_http_request_duration_seconds_bucket{{handler=\"{}\",le=\"{}\"}} {}", handler, bound, cumulative);
+            }
+            let _ = writeln!(out, "hammer_http_request_duration_seconds_bucket{{handler=\"{}\",le=\"+Inf\"}} {}", handler, histogram.count);
+            let _ = writeln!(out, "hammer_http_request_duration_seconds_sum{{handler=\"{}\"}} {}", handler, histogram.sum);
+            let _ = writeln!(out, "hammer_http_request_duration_seconds_count{{handler=\"{}\"}} {}", handler, histogram.count);
+        }
+
+        let _ = writeln!(out, "# HELP hammer_values_total Values passed to each operation, by result");
+        let _ = writeln!(out, "# TYPE hammer_values_total counter");
+        for (&(operation, result), count) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "hammer_values_total{{operation=\"{}\",result=\"{}\"}} {}", operation, result, count);
+        }
+    }
+}
+
+pub struct MetricsKey;
+impl typemap::Key for MetricsKey { type Value = Arc<Registry>; }
+
+struct StartKey;
+impl typemap::Key for StartKey { type Value = Instant; }
+
+/// Middleware timing each request, and making the registry available to
+/// handlers through `registry`
+///
+pub struct Metrics {
+    registry: Arc<Registry>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics{registry: Arc::new(Registry::new())}
+    }
+
+    fn finish(&self, req: &mut Request, res: &Response) {
+        let start = match req.extensions.remove::<StartKey>() {
+            Some(start) => start,
+            None => return,
+        };
+
+        let code = res.status.map_or(200, |status| status.to_u16());
+        self.registry.observe_request(handler(req), code, start.elapsed());
+    }
+}
+
+impl BeforeMiddleware for Metrics {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<MetricsKey>(self.registry.clone());
+        req.extensions.insert::<StartKey>(Instant::now());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Metrics {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.finish(req, &res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.finish(req, &err.response);
+        Err(err)
+    }
+}
+
+/// The registry of the server handling `req`
+///
+pub fn registry(req: &Request) -> Arc<Registry> {
+    req.extensions.get::<MetricsKey>().unwrap().clone()
+}
+
+/// Handler label for `req`'s path
+fn handler(req: &Request) -> &'static str {
+    let first = req.url.path.first().map_or("", |p| p.as_str());
+    let last = req.url.path.last().map_or("", |p| p.as_str());
+
+    // Namespace sub-resources are labelled by the sub-resource
+    let name = match first {
+        "db" if HANDLERS.iter().any(|&handler| handler == last) => last,
+        _ => first,
+    };
+
+    HANDLERS.iter().find(|&&handler| handler == name).map_or("other", |&handler| handler)
+}
+
+/// Reports every metric
+///
+pub fn metrics(req: &mut Request) -> IronResult<Response> {
+    let registry = registry(req);
+
+    let mut out = String::new();
+    registry.render(&mut out);
+
+    {
+        let mut namespaces = registry.namespaces.lock().unwrap();
+        let stale = match *namespaces {
+            Some((refreshed, _)) => refreshed.elapsed() >= Duration::from_secs(STATS_INTERVAL_SECS),
+            None => true,
+        };
+        if stale {
+            *namespaces = Some((Instant::now(), namespace_stats(req)));
+        }
+
+        if let Some((_, ref stats)) = *namespaces {
+            render_namespaces(req, stats, &mut out);
+        }
+    }
+    render_shadows(&shadow_stats(req), &mut out);
+
+    let mut response = Response::with((status::Ok, out));
+    response.headers.set(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+    Ok(response)
+}
+
+fn namespace_stats(req: &mut Request) -> Vec<NamespaceStats> {
+    let mut stats = Vec::new();
+
+    collect(&req.get::<State<B32>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(32, tolerance, ns), &mut stats);
+    collect(&req.get::<State<B64>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(64, tolerance, ns), &mut stats);
+    collect(&req.get::<State<B128>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(128, tolerance, ns), &mut stats);
+    collect(&req.get::<State<B256>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(256, tolerance, ns), &mut stats);
+
+    collect(&req.get::<State<V32>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(32, dimensions, tolerance, ns), &mut stats);
+    collect(&req.get::<State<V64>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(64, dimensions, tolerance, ns), &mut stats);
+    collect(&req.get::<State<V128>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(128, dimensions, tolerance, ns), &mut stats);
+    collect(&req.get::<State<V256>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(256, dimensions, tolerance, ns), &mut stats);
+
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+    stats
+}
+
+fn shadow_stats(req: &mut Request) -> Vec<(String, ShadowStats)> {
+    let mut stats = Vec::new();
+
+    collect_shadows(&req.get::<State<B32>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(32, tolerance, ns), &mut stats);
+    collect_shadows(&req.get::<State<B64>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(64, tolerance, ns), &mut stats);
+    collect_shadows(&req.get::<State<B128>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(128, tolerance, ns), &mut stats);
+    collect_shadows(&req.get::<State<B256>>().unwrap(), |&(tolerance, ref ns)| binary_namespace(256, tolerance, ns), &mut stats);
+
+    collect_shadows(&req.get::<State<V32>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(32, dimensions, tolerance, ns), &mut stats);
+    collect_shadows(&req.get::<State<V64>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(64, dimensions, tolerance, ns), &mut stats);
+    collect_shadows(&req.get::<State<V128>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(128, dimensions, tolerance, ns), &mut stats);
+    collect_shadows(&req.get::<State<V256>>().unwrap(), |&(dimensions, tolerance, ref ns)| vector_namespace(256, dimensions, tolerance, ns), &mut stats);
+
+    stats.sort_by(|a, b| a.0.cmp(&b.0));
+    stats
+}
+
+fn collect_shadows<K, T, F>(dbmap_mx: &RwLock<HashMap<K, Arc<RwLock<Box<Database<T>>>>>>, name: F, out: &mut Vec<(String, ShadowStats)>) where
+K: Eq + Hash,
+F: Fn(&K) -> String,
+{
+    let dbs: Vec<(String, Arc<RwLock<Box<Database<T>>>>)> = dbmap_mx.read().unwrap().iter()
+        .map(|(key, db)| (name(key), db.clone()))
+        .collect();
+
+    for (ns, db_mx) in dbs.into_iter() {
+        if let Some(stats) = db_mx.read().unwrap().shadow_stats() {
+            out.push((ns, stats));
+        }
+    }
+}
+
+fn render_shadows(shadows: &[(String, ShadowStats)], out: &mut String) {
+    if shadows.is_empty() {
+        return
+    }
+
+    let _ = writeln!(out, "# HELP hammer_shadow_queries_total Queries compared against a shadow candidate");
+    let _ = writeln!(out, "# TYPE hammer_shadow_queries_total counter");
+    for &(ref ns, ref stats) in shadows.iter() {
+        let _ = writeln!(out, "hammer_shadow_queries_total{{namespace=\"{}\"}} {}", escape(ns), stats.queries);
+    }
+
+    let _ = writeln!(out, "# HELP hammer_shadow_mismatched_queries_total Queries whose candidate results differed");
+    let _ = writeln!(out, "# TYPE hammer_shadow_mismatched_queries_total counter");
+    for &(ref ns, ref stats) in shadows.iter() {
+        let _ = writeln!(out, "hammer_shadow_mismatched_queries_total{{namespace=\"{}\"}} {}", escape(ns), stats.mismatched_queries);
+    }
+
+    let _ = writeln!(out, "# HELP hammer_shadow_matches_total Values found by only one of the namespace & its candidate");
+    let _ = writeln!(out, "# TYPE hammer_shadow_matches_total counter");
+    for &(ref ns, ref stats) in shadows.iter() {
+        let _ = writeln!(out, "hammer_shadow_matches_total{{namespace=\"{}\",difference=\"extra\"}} {}", escape(ns), stats.extra_matches);
+        let _ = writeln!(out, "hammer_shadow_matches_total{{namespace=\"{}\",difference=\"missing\"}} {}", escape(ns), stats.missing_matches);
+    }
+
+    let _ = writeln!(out, "# HELP hammer_shadow_query_seconds_total Time spent on compared queries, by db");
+    let _ = writeln!(out, "# TYPE hammer_shadow_query_seconds_total counter");
+    for &(ref ns, ref stats) in shadows.iter() {
+        let _ = writeln!(out, "hammer_shadow_query_seconds_total{{namespace=\"{}\",db=\"primary\"}} {}", escape(ns), seconds(stats.primary_time));
+        let _ = writeln!(out, "hammer_shadow_query_seconds_total{{namespace=\"{}\",db=\"candidate\"}} {}", escape(ns), seconds(stats.candidate_time));
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn collect<K, T, F>(dbmap_mx: &RwLock<HashMap<K, Arc<RwLock<Box<Database<T>>>>>>, name: F, out: &mut Vec<NamespaceStats>) where
+K: Eq + Hash,
+F: Fn(&K) -> String,
+{
+    // Release the dbmap before reading each DB, so new namespaces can still
+    // be opened while stats are collected
+    let dbs: Vec<(String, Arc<RwLock<Box<Database<T>>>>)> = dbmap_mx.read().unwrap().iter()
+        .map(|(key, db)| (name(key), db.clone()))
+        .collect();
+
+    for (ns, db_mx) in dbs.into_iter() {
+        let stats = db_mx.read().unwrap().stats();
+        out.push(NamespaceStats{name: ns, stats: stats});
+    }
+}
+
+fn render_namespaces(req: &mut Request, namespaces: &[NamespaceStats], out: &mut String) {
+    let gauges = [
+        ("hammer_namespace_values", "Distinct values indexed (estimated)"),
+        ("hammer_namespace_variant_keys", "Distinct variant keys"),
+        ("hammer_namespace_memory_bytes", "Bytes held in memory (estimated)"),
+        ("hammer_namespace_disk_bytes", "Bytes on disk"),
+    ];
+
+    for &(metric, help) in gauges.iter() {
+        let _ = writeln!(out, "# HELP {} {}", metric, help);
+        let _ = writeln!(out, "# TYPE {} gauge", metric);
+        for namespace in namespaces.iter() {
+            // Namespaces whose stats couldn't be read are left out
+            let stats = match namespace.stats {
+                Ok(ref stats) => stats,
+                Err(_) => continue,
+            };
+
+            let value = match metric {
+                "hammer_namespace_values" => Some(stats.values as u64),
+                "hammer_namespace_variant_keys" => Some(stats.variant_keys as u64),
+                "hammer_namespace_memory_bytes" => Some(stats.memory_bytes),
+                _ => stats.disk_bytes,
+            };
+            if let Some(value) = value {
+                let _ = writeln!(out, "{}{{namespace=\"{}\"}} {}", metric, escape(&namespace.name), value);
+            }
+        }
+    }
+
+    let _ = writeln!(out, "# HELP hammer_namespace_bucket_size Values per variant bucket, by partition (sampled for persistent stores)");
+    let _ = writeln!(out, "# TYPE hammer_namespace_bucket_size gauge");
+    for (namespace, stats) in namespaces.iter().filter_map(|n| n.stats.as_ref().ok().map(|stats| (n, stats))) {
+        for (partition, buckets) in stats.partition_buckets.iter().enumerate() {
+            for &(quantile, size) in [("0.5", buckets.p50), ("0.9", buckets.p90), ("0.99", buckets.p99), ("1", buckets.max)].iter() {
+                let _ = writeln!(out, "hammer_namespace_bucket_size{{namespace=\"{}\",partition=\"{}\",quantile=\"{}\"}} {}", escape(&namespace.name), partition, quantile, size);
+            }
+        }
+    }
+
+    let _ = writeln!(out, "# HELP hammer_namespace_bucket_skew Fraction of a partition's entries in its largest variant bucket");
+    let _ = writeln!(out, "# TYPE hammer_namespace_bucket_skew gauge");
+    for (namespace, stats) in namespaces.iter().filter_map(|n| n.stats.as_ref().ok().map(|stats| (n, stats))) {
+        for (partition, buckets) in stats.partition_buckets.iter().enumerate() {
+            let _ = writeln!(out, "hammer_namespace_bucket_skew{{namespace=\"{}\",partition=\"{}\"}} {}", escape(&namespace.name), partition, buckets.max_fraction);
+        }
+    }
+
+    let data_dir = req.get::<State<ConfigKey>>().unwrap().read().unwrap().data_dir.clone();
+    let data_dir = match data_dir {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let usages: Vec<_> = namespaces.iter()
+        .filter_map(|namespace| disk_usage(&data_dir.join(&namespace.name)).ok().map(|usage| (&namespace.name, usage)))
+        .collect();
+
+    let _ = writeln!(out, "# HELP hammer_rocksdb_sst_files RocksDB SST files");
+    let _ = writeln!(out, "# TYPE hammer_rocksdb_sst_files gauge");
+    for &(name, ref usage) in usages.iter() {
+        let _ = writeln!(out, "hammer_rocksdb_sst_files{{namespace=\"{}\"}} {}", escape(name), usage.sst_files);
+    }
+
+    let _ = writeln!(out, "# HELP hammer_rocksdb_open_files File handles held open by RocksDB");
+    let _ = writeln!(out, "# TYPE hammer_rocksdb_open_files gauge");
+    for &(name, ref usage) in usages.iter() {
+        if let Some(open_files) = usage.open_files {
+            let _ = writeln!(out, "hammer_rocksdb_open_files{{namespace=\"{}\"}} {}", escape(name), open_files);
+        }
+    }
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use hammer::db::shadow::ShadowStats;
+
+    use http::metrics::{Registry, escape, render_shadows};
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let registry = Registry::new();
+        registry.count_values("add", vec!["ok", "ok", "exists"].into_iter());
+        registry.observe_request("add", 200, Duration::from_millis(3));
+        registry.observe_request("add", 200, Duration::from_secs(2));
+
+        let mut out = String::new();
+        registry.render(&mut out);
+
+        assert!(out.contains("hammer_values_total{operation=\"add\",result=\"ok\"} 2\n"));
+        assert!(out.contains("hammer_values_total{operation=\"add\",result=\"exists\"} 1\n"));
+        assert!(out.contains("hammer_http_requests_total{handler=\"add\",code=\"200\"} 2\n"));
+        assert!(out.contains("hammer_http_request_duration_seconds_bucket{handler=\"add\",le=\"0.001\"} 0\n"));
+        assert!(out.contains("hammer_http_request_duration_seconds_bucket{handler=\"add\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("hammer_http_request_duration_seconds_bucket{handler=\"add\",le=\"1\"} 1\n"));
+        assert!(out.contains("hammer_http_request_duration_seconds_bucket{handler=\"add\",le=\"+Inf\"} 2\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+
+    #[test]
+    fn renders_shadow_stats() {
+        let stats = ShadowStats{queries: 3, mismatched_queries: 1, extra_matches: 2, missing_matches: 0, primary_time: Duration::from_millis(500), candidate_time: Duration::from_secs(1)};
+
+        let mut out = String::new();
+        render_shadows(&[("b064_004_images".to_string(), stats)], &mut out);
+
+        assert!(out.contains("hammer_shadow_queries_total{namespace=\"b064_004_images\"} 3\n"));
+        assert!(out.contains("hammer_shadow_mismatched_queries_total{namespace=\"b064_004_images\"} 1\n"));
+        assert!(out.contains("hammer_shadow_matches_total{namespace=\"b064_004_images\",difference=\"extra\"} 2\n"));
+        assert!(out.contains("hammer_shadow_query_seconds_total{namespace=\"b064_004_images\",db=\"primary\"} 0.5\n"));
+    }
+}
@@ -1,31 +1,76 @@
 pub mod server;
 pub mod binary_handler;
 pub mod vector_handler;
+pub mod tuning_handler;
+pub mod disk_handler;
+pub mod encoding;
+pub mod health_handler;
+pub mod limits;
+pub mod manifest;
+pub mod metrics;
+pub mod namespace_handler;
+pub mod oplog;
+pub mod quotas;
+pub mod reload;
+pub mod shutdown;
+pub mod snapshot_handler;
+pub mod trace;
+pub mod warmup_handler;
 
+use std::fs;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::path::PathBuf;
-use std::io::Read;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::path::{Path, PathBuf};
+use std::default::Default;
+use std::hash::Hash;
 
+use log::Level;
 use iron::prelude::*;
 use iron::{status, typemap};
-use rustc_serialize::base64;
-use rustc_serialize::json;
-use rustc_serialize::Decodable;
-use rustc_serialize::json::{ToJson, Json};
-use hammer::db::Database;
+use router::Router;
+use base64;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use hammer::db::{Database, Factory, QueryOptions, RemovalReport, RocksDBConfig, StorageBackend};
+use hammer::db::column_family::SharedRocksDB;
+use hammer::db::disk_usage::DiskBudget;
+use hammer::db::encoding::encode;
+use hammer::db::cost::TooExpensive;
+use hammer::db::health::{self, HealthReport};
+use hammer::db::hamming::Hamming;
+use hammer::db::evicting::EvictingDB;
+use hammer::db::read_only::ReadOnly;
+use hammer::db::shadow::ShadowDB;
+use hammer::evicting_store::LRU;
+use hammer::Error;
+
+use http::limits::{self, Limits};
+use http::manifest::Manifest;
 
 pub enum AddResult {
     Ok,
     Exists,
     Err(String),
 }
-impl ToJson for AddResult {
-    fn to_json(&self) -> Json {
+impl AddResult {
+    /// Result label for metrics
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            &AddResult::Ok => "ok",
+            &AddResult::Exists => "exists",
+            &AddResult::Err(_) => "err",
+        }
+    }
+}
+impl Serialize for AddResult {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
         match self {
-            &AddResult::Ok => Json::String("ok".to_string()),
-            &AddResult::Exists => Json::String("exists".to_string()),
-            &AddResult::Err(ref e) => Json::String(format!("err: {}", e)),
+            &AddResult::Ok => s.serialize_str("ok"),
+            &AddResult::Exists => s.serialize_str("exists"),
+            &AddResult::Err(ref e) => s.serialize_str(&format!("err: {}", e)),
         }
     }
 }
@@ -35,27 +80,56 @@ pub enum QueryResult<T> {
     None,
     Err(String),
 }
-impl<T: ToJson> ToJson for QueryResult<T> {
-    fn to_json(&self) -> Json {
+impl<T> QueryResult<T> {
+    /// Result label for metrics
+    pub fn outcome(&self) -> &'static str {
         match self {
-            &QueryResult::Ok(ref v) => v.to_json(),
-            &QueryResult::None => Json::String("none".to_string()),
-            &QueryResult::Err(ref e) => Json::String(format!("err: {}", e)),
+            &QueryResult::Ok(_) => "hit",
+            &QueryResult::None => "miss",
+            &QueryResult::Err(_) => "err",
+        }
+    }
+}
+impl<T: Serialize> Serialize for QueryResult<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            &QueryResult::Ok(ref v) => v.serialize(s),
+            &QueryResult::None => s.serialize_str("none"),
+            &QueryResult::Err(ref e) => s.serialize_str(&format!("err: {}", e)),
         }
     }
 }
 
 pub enum DeleteResult {
     Ok,
+    Verified(RemovalReport),
     NotFound,
     Err(String),
 }
-impl ToJson for DeleteResult {
-    fn to_json(&self) -> Json {
+impl DeleteResult {
+    /// Result label for metrics
+    pub fn outcome(&self) -> &'static str {
         match self {
-            &DeleteResult::Ok => Json::String("ok".to_string()),
-            &DeleteResult::NotFound => Json::String("not_found".to_string()),
-            &DeleteResult::Err(ref e) => Json::String(format!("err: {}", e)),
+            &DeleteResult::Ok | &DeleteResult::Verified(_) => "ok",
+            &DeleteResult::NotFound => "not_found",
+            &DeleteResult::Err(_) => "err",
+        }
+    }
+}
+impl Serialize for DeleteResult {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            &DeleteResult::Ok => s.serialize_str("ok"),
+            &DeleteResult::Verified(ref report) => {
+                let mut map = try!(s.serialize_map(Some(4)));
+                try!(map.serialize_entry("variants_checked", &report.variants_checked));
+                try!(map.serialize_entry("variants_remaining", &report.variants_remaining));
+                try!(map.serialize_entry("variants_removed", &report.variants_removed));
+                try!(map.serialize_entry("verified", &report.verified()));
+                map.end()
+            },
+            &DeleteResult::NotFound => s.serialize_str("not_found"),
+            &DeleteResult::Err(ref e) => s.serialize_str(&format!("err: {}", e)),
         }
     }
 }
@@ -78,29 +152,171 @@ impl typemap::Key for V128 { type Value = HashMap<(usize, usize, String), Arc<Rw
 struct V256;
 impl typemap::Key for V256 { type Value = HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<[u64; 4]>>>>>>; }
 
-pub const BASE64_CONFIG: base64::Config = base64::Config{
-    char_set: base64::CharacterSet::Standard,
-    newline: base64::Newline::CRLF,
-    pad: true,
-    line_length: None,
-};
+pub const BASE64_CONFIG: base64::Config = base64::STANDARD;
+
+/// Encoding of page cursors, which are passed back in the query string
+pub const CURSOR_BASE64_CONFIG: base64::Config = base64::URL_SAFE_NO_PAD;
+
+/// Store used for namespaces persisted under `data_dir`
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageEngine {
+    RocksDB,
+    /// Requires the `sled` feature
+    Sled,
+}
+
+impl StorageEngine {
+    pub fn parse(name: &str) -> Result<StorageEngine, String> {
+        match name {
+            "rocksdb" => Ok(StorageEngine::RocksDB),
+            "sled" if cfg!(feature = "sled") => Ok(StorageEngine::Sled),
+            "sled" => Err("sled storage requires building with the `sled` feature".to_string()),
+            other => Err(format!("Unsupported storage engine '{}'", other)),
+        }
+    }
+
+    /// Name accepted by `parse`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StorageEngine::RocksDB => "rocksdb",
+            StorageEngine::Sled => "sled",
+        }
+    }
+
+    /// Backend for the namespace stored in `path`
+    ///
+    pub fn backend(&self, path: PathBuf, rocksdb: &RocksDBConfig) -> StorageBackend {
+        match *self {
+            StorageEngine::RocksDB => StorageBackend::RocksDB(path, rocksdb.clone()),
+            #[cfg(feature = "sled")]
+            StorageEngine::Sled => StorageBackend::Sled(path),
+            #[cfg(not(feature = "sled"))]
+            StorageEngine::Sled => unreachable!("sled storage requires the `sled` feature"),
+        }
+    }
+}
+
+/// Directory under `data_dir` holding the RocksDB shared by namespaces
+/// stored in column families
+pub const COLUMN_FAMILIES_DIR: &'static str = "rocksdb";
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub data_dir: Option<PathBuf>,
     pub bind: String,
+    pub storage: StorageEngine,
+    pub rocksdb: RocksDBConfig,
+    /// New namespaces are stored in column families of this RocksDB rather
+    /// than directories of their own
+    pub column_families: Option<Arc<SharedRocksDB>>,
+    /// Seconds between compactions of every namespace (none if unset)
+    pub compact_interval: Option<u64>,
+    /// Writes are synced to disk before being acknowledged
+    pub sync_writes: bool,
+    /// Re-opened namespaces are scanned for entries referencing missing
+    /// values, which are removed
+    pub repair_on_open: bool,
+    pub disk_budget: DiskBudget,
+    /// Queries estimated to examine more candidates than this are rejected
+    pub max_query_candidates: Option<usize>,
+    /// Namespaces are capped at this many values, evicting the least
+    /// recently queried
+    pub lru: Option<usize>,
+    /// OpenTelemetry collector to export request spans to
+    pub otlp_endpoint: Option<String>,
+    /// Adds & deletes are logged to `data_dir`'s oplog
+    pub oplog: bool,
+    /// Address to serve the binary protocol on (see `net`)
+    pub binary_bind: Option<String>,
+    /// Unix socket to serve the binary protocol on
+    pub binary_socket: Option<PathBuf>,
+    /// Address to serve the gRPC service on (see `grpc`)
+    pub grpc_bind: Option<String>,
+    /// Completion queue threads serving gRPC calls
+    pub grpc_threads: usize,
+    /// Request body size, key count & rate limits
+    pub limits: Limits,
+    /// Queries taking longer than this many milliseconds are logged
+    pub slow_query_ms: Option<u64>,
+    /// Namespaces (by directory name) shadowed by an in-memory candidate of
+    /// this tolerance when opened (see `db::shadow`)
+    pub shadows: HashMap<String, usize>,
+    /// File of settings overriding the above, re-read by `/config/reload`
+    /// (see `reload`)
+    pub config_file: Option<PathBuf>,
 }
 
 struct ConfigKey;
 impl typemap::Key for ConfigKey { type Value = Config; }
 
+impl Config {
+    /// Backend for a new namespace stored in `path`
+    ///
+    /// Namespaces already stored in RocksDB directories keep using them when
+    /// `column_families` is set.  Otherwise their directory is still created,
+    /// since it marks the namespace as persisted and holds its tuning.
+    ///
+    pub fn persistent_backend(&self, path: PathBuf) -> StorageBackend {
+        match self.column_families {
+            Some(ref shared) if !path.join("map_set").exists() => {
+                if let Err(e) = fs::create_dir_all(&path) {
+                    warn!("unable to create {:?}: {}", path, e);
+                }
+                let prefix = path.file_name().unwrap().to_string_lossy().into_owned();
+                StorageBackend::SharedRocksDB(shared.clone(), prefix, self.rocksdb.clone())
+            },
+            _ => self.storage.backend(path, &self.rocksdb),
+        }
+    }
+}
+
+/// Runtime-tunable settings for a namespace
+///
+/// Tuning is stored alongside the namespace's data (when persisted) and can be
+/// changed without restarting the server via `/db/.../tuning`
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tuning {
+    /// Variant sets cached in memory in front of RocksDB (0 disables caching;
+    /// see `Database::set_variant_cache`)
+    pub cache_size: usize,
+    /// Maximum requests per second (0 is unlimited), enforced alongside the
+    /// namespace's `max_qps` quota (see `quotas`)
+    pub rate_limit: usize,
+}
+
+struct TuningKey;
+impl typemap::Key for TuningKey { type Value = HashMap<String, Tuning>; }
+
+/// Namespaces found degraded when re-opened, by directory name
+///
+/// Degraded namespaces still serve queries, but reject writes until the
+/// server is restarted against repaired data
+///
+struct HealthKey;
+impl typemap::Key for HealthKey { type Value = HashMap<String, HealthReport>; }
+
+/// Namespaces recorded in `data_dir`'s manifest
+struct ManifestKey;
+impl typemap::Key for ManifestKey { type Value = Manifest; }
+
+/// Name of the directory (under `data_dir`) holding a binary namespace
+pub fn binary_namespace(bits: usize, tolerance: usize, namespace: &str) -> String {
+    format!("b{:03}_{:03}_{:}", bits, tolerance, namespace)
+}
+
+/// Name of the directory (under `data_dir`) holding a vector namespace
+pub fn vector_namespace(bits: usize, dimensions: usize, tolerance: usize, namespace: &str) -> String {
+    format!("v{:03}_{:03}_{:03}_{:}", bits, dimensions, tolerance, namespace)
+}
+
 fn decode_body<T>(req: &mut Request) -> Result<T, IronError> where
-T: Decodable
+T: DeserializeOwned
 {
-    let mut payload = String::new();
-    itry!(req.body.read_to_string(&mut payload));
+    let payload = try!(limits::read_body_string(req));
 
-    match json::decode::<T>(&payload) {
+    match serde_json::from_str::<T>(&payload) {
         Ok(req_body) => {
             Ok(req_body)
         },
@@ -109,3 +325,418 @@ T: Decodable
         }
     }
 }
+
+/// Directory name of the binary namespace identified by the request's route
+fn binary_namespace_params(req: &Request) -> Result<String, Response> {
+    let router = req.extensions.get::<Router>().unwrap();
+
+    let bits = match router.find("bits").map(|v| v.parse::<usize>()) {
+        Some(Ok(v)) => v,
+        _ => return Err(Response::with((status::BadRequest, "DB bitsize is required"))),
+    };
+
+    let tolerance = match router.find("tolerance").map(|v| v.parse::<usize>()) {
+        Some(Ok(v)) => v,
+        _ => return Err(Response::with((status::BadRequest, "DB tolerance is required"))),
+    };
+
+    let namespace = match router.find("namespace") {
+        Some(v) => v.to_string(),
+        None => return Err(Response::with((status::BadRequest, "DB namespace is required"))),
+    };
+
+    Ok(binary_namespace(bits, tolerance, &namespace))
+}
+
+/// Directory name of the vector namespace identified by the request's route
+fn vector_namespace_params(req: &Request) -> Result<String, Response> {
+    let router = req.extensions.get::<Router>().unwrap();
+
+    let bits = match router.find("bits").map(|v| v.parse::<usize>()) {
+        Some(Ok(v)) => v,
+        _ => return Err(Response::with((status::BadRequest, "DB bitsize is required"))),
+    };
+
+    let dimensions = match router.find("dimensions").map(|v| v.parse::<usize>()) {
+        Some(Ok(v)) => v,
+        _ => return Err(Response::with((status::BadRequest, "DB dimensions is required"))),
+    };
+
+    let tolerance = match router.find("tolerance").map(|v| v.parse::<usize>()) {
+        Some(Ok(v)) => v,
+        _ => return Err(Response::with((status::BadRequest, "DB tolerance is required"))),
+    };
+
+    let namespace = match router.find("namespace") {
+        Some(v) => v.to_string(),
+        None => return Err(Response::with((status::BadRequest, "DB namespace is required"))),
+    };
+
+    Ok(vector_namespace(bits, dimensions, tolerance, &namespace))
+}
+
+/// Returns the value of query-string parameter `name`, if present
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.url.query.as_ref().and_then(|query| {
+        query.split('&').filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v.to_string()),
+                (Some(k), None) if k == name => Some(String::new()),
+                _ => None,
+            }
+        }).next()
+    })
+}
+
+/// Parses the query options from the request's query parameters
+///
+/// `exclude_exact=true` omits results identical to the query, and
+/// `mode=approx` only probes exact-window buckets (see
+/// `QueryOptions::approximate`)
+fn query_options(req: &Request) -> Result<QueryOptions, Response> {
+    let exclude_exact = match query_param(req, "exclude_exact") {
+        None => false,
+        Some(ref v) if v == "" || v == "true" => true,
+        Some(ref v) if v == "false" => false,
+        Some(v) => return Err(Response::with((status::BadRequest, format!("Invalid exclude_exact '{}'", v)))),
+    };
+
+    let approximate = match query_param(req, "mode") {
+        None => false,
+        Some(ref v) if v == "exact" => false,
+        Some(ref v) if v == "approx" => true,
+        Some(v) => return Err(Response::with((status::BadRequest, format!("Invalid mode '{}'", v)))),
+    };
+
+    let max_distance = match query_param(req, "max_distance") {
+        None => None,
+        Some(v) => match v.parse::<usize>() {
+            Ok(d) => Some(d),
+            Err(_) => return Err(Response::with((status::BadRequest, format!("Invalid max_distance '{}'", v)))),
+        },
+    };
+
+    Ok(QueryOptions{exclude_exact: exclude_exact, approximate: approximate, max_distance: max_distance})
+}
+
+/// Paging requested by a query's `limit` & `after` parameters
+///
+pub struct Paging {
+    /// Most values returned for each key
+    pub limit: usize,
+    /// The previous page's `next` cursor, still encoded
+    pub after: Option<String>,
+}
+
+impl Paging {
+    /// Decodes the cursor for a request querying `count` keys
+    ///
+    /// A cursor comes from a single key's results, so it can't be used for a
+    /// request querying several.
+    ///
+    pub fn cursor<T: DeserializeOwned>(&self, count: usize) -> Result<Option<T>, Response> {
+        match self.after {
+            None => Ok(None),
+            Some(_) if count != 1 => Err(Response::with((status::BadRequest, "after requires querying a single key"))),
+            Some(ref after) => encoding::decode_base64(after)
+                .map(Some)
+                .map_err(|e| Response::with((status::BadRequest, format!("Invalid after: {}", e)))),
+        }
+    }
+}
+
+/// Parses the `limit` & `after` query parameters, returning `None` if results
+/// aren't paged
+///
+/// Paged results are ordered by identifier, so can't be combined with
+/// `order=recent`.
+///
+fn paging(req: &Request) -> Result<Option<Paging>, Response> {
+    let after = query_param(req, "after");
+    let limit = match query_param(req, "limit") {
+        None if after.is_some() => return Err(Response::with((status::BadRequest, "after requires limit"))),
+        None => return Ok(None),
+        Some(v) => match v.parse::<usize>() {
+            Ok(limit) if limit > 0 => limit,
+            _ => return Err(Response::with((status::BadRequest, format!("Invalid limit '{}'", v)))),
+        },
+    };
+
+    if let Some(ref order) = query_param(req, "order") {
+        if order != "" {
+            return Err(Response::with((status::BadRequest, "limit can't be combined with order")))
+        }
+    }
+
+    Ok(Some(Paging{limit: limit, after: after}))
+}
+
+/// JSON for a page of encoded values, with the cursor for the next page (if
+/// any) encoded for the `after` parameter
+///
+fn page_json<T: Serialize>(values: Value, next: Option<T>) -> Value {
+    let next = next.map(|next| base64::encode_config(&encode(&next).unwrap(), CURSOR_BASE64_CONFIG));
+
+    json!({"values": values, "next": next})
+}
+
+/// Sorts query results into canonical order: nearest to `query` first, ties
+/// broken by the values' encoded bytes
+///
+/// Results come back in the same order for the same data regardless of hash
+/// iteration order, so responses can be compared across runs.
+///
+pub fn canonical_order<T, I>(query: &T, found: I) -> Vec<T> where
+T: Hamming + Serialize,
+I: IntoIterator<Item=T>,
+{
+    let mut ranked: Vec<(usize, Vec<u8>, T)> = found.into_iter().map(|v| {
+        let encoded = encode(&v).unwrap();
+        (query.hamming(&v), encoded, v)
+    }).collect();
+    ranked.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+    ranked.into_iter().map(|(_, _, v)| v).collect()
+}
+
+/// 413 response for a query rejected by `Config::max_query_candidates`
+fn too_expensive(e: TooExpensive) -> Response {
+    let body = json!({
+        "error": "too_expensive",
+        "estimated_candidates": e.estimated_candidates,
+        "limit": e.limit,
+        "message": format!("{}", e),
+    });
+
+    let response_body = serde_json::to_string(&body).unwrap();
+    Response::with((status::PayloadTooLarge, response_body))
+}
+
+/// Response for an error returned by a namespace's DB
+///
+/// Storage failures are the server's fault, so they're reported as a 500
+/// rather than taking the server down; the rest describe a request the DB
+/// won't serve.
+///
+pub fn error_response(e: Error) -> Response {
+    let code = match e {
+        Error::TooExpensive(ref cost) => return too_expensive(cost.clone()),
+        Error::Unsupported(..) => status::NotImplemented,
+        ref e if e.is_storage() => status::InternalServerError,
+        _ => status::BadRequest,
+    };
+
+    let body = json!({
+        "error": if e.is_storage() { "storage" } else { "invalid" },
+        "message": format!("{}", e),
+    });
+
+    let response_body = serde_json::to_string(&body).unwrap();
+    Response::with((code, response_body))
+}
+
+/// Checks namespace `ns`, just re-opened from `dir`, recording it as degraded
+/// if anything is found
+///
+/// With `repair` (`--repair-on-open`) set, dangling entries are removed
+/// first, so a namespace damaged by an interrupted delete opens healthy.
+///
+fn check_namespace<T>(ns: &str, dir: &Path, db: &mut Database<T>, repair: bool, health_mx: &RwLock<HashMap<String, HealthReport>>) {
+    if repair {
+        match db.verify_and_repair() {
+            Ok(repaired) => info!("Repaired namespace {}: {}", ns, repaired),
+            Err(e) => warn!("unable to repair namespace {}: {}", ns, e),
+        }
+    }
+
+    let mut report = db.health_check(health::DEFAULT_SAMPLE);
+    if let Err(e) = tuning_handler::check_tuning(dir) {
+        report.findings.push(e);
+    }
+
+    if !report.healthy() {
+        warn!("namespace {} is {}", ns, report);
+        health_mx.write().unwrap().insert(ns.to_string(), report);
+    }
+}
+
+/// Records a namespace created under `data_dir` in the manifest
+///
+/// Failing to record a namespace doesn't fail the write - its data is still
+/// persisted, it just won't be re-opened until it's next written to
+///
+fn record_namespace(config: &Config, entry: manifest::Entry, manifest_mx: &RwLock<Manifest>) {
+    if let Some(ref dir) = config.data_dir {
+        if let Err(e) = manifest_mx.write().unwrap().record(dir, entry) {
+            warn!("unable to record namespace in manifest: {}", e);
+        }
+    }
+}
+
+/// Builds namespace `ns`'s DB ignoring differences in `ignored`, shadowing it
+/// if `config.shadows` lists it and capping it at `config.lru` values if set
+///
+/// Fails if the namespace's stores can't be opened, or were created with a
+/// different dimensions or tolerance (see `Factory::open`).
+///
+fn build<T>(ns: &str, dimensions: usize, tolerance: usize, ignored: &[usize], backend: StorageBackend, config: &Config) -> Result<Box<Database<T>>, Error> where
+T: 'static + Factory + Sync + Send + Clone + Eq + Hash,
+{
+    let mut db = try!(T::open(dimensions, tolerance, backend));
+    if !ignored.is_empty() {
+        if let Err(e) = db.set_ignored_dimensions(ignored.to_vec()) {
+            warn!("unable to ignore dimensions {:?}: {}", ignored, e);
+        }
+    }
+
+    let cache_size = tuning_handler::persisted_tuning(config.data_dir.as_ref().map(|dir| dir.as_path()), ns).map_or(0, |tuning| tuning.cache_size);
+    if cache_size > 0 {
+        if let Err(e) = db.set_variant_cache(cache_size) {
+            warn!("unable to cache {} variant sets of namespace {}: {}", cache_size, ns, e);
+        }
+    }
+
+    if let Some(&candidate_tolerance) = config.shadows.get(ns) {
+        db = match shadow(db, dimensions, candidate_tolerance, ignored) {
+            Ok(shadowed) => shadowed,
+            Err((db, e)) => {
+                warn!("unable to shadow namespace {}: {}", ns, e);
+                db
+            },
+        };
+    }
+
+    match config.lru {
+        Some(capacity) => Ok(Box::new(try!(EvictingDB::open(db, LRU::with_capacity(capacity))))),
+        None => Ok(db),
+    }
+}
+
+/// `db` shadowed by an in-memory candidate holding the same values, built
+/// with `tolerance`
+///
+/// Returns `db` unshadowed if the candidate can't be built.
+///
+fn shadow<T>(db: Box<Database<T>>, dimensions: usize, tolerance: usize, ignored: &[usize]) -> Result<Box<Database<T>>, (Box<Database<T>>, Error)> where
+T: 'static + Factory + Sync + Send + Clone + Eq + Hash,
+{
+    let mut candidate = match T::open(dimensions, tolerance, StorageBackend::InMemory) {
+        Ok(candidate) => candidate,
+        Err(e) => return Err((db, e)),
+    };
+    if !ignored.is_empty() {
+        if let Err(e) = candidate.set_ignored_dimensions(ignored.to_vec()) {
+            return Err((db, e))
+        }
+    }
+
+    let mut shadowed = ShadowDB::new(db, candidate);
+    match shadowed.mirror() {
+        Ok(_) => Ok(Box::new(shadowed)),
+        Err(e) => Err((shadowed.into_inner().0, e)),
+    }
+}
+
+/// Re-opens the namespace described by a manifest entry, checking it as when
+/// a namespace is re-opened by a write
+///
+fn reopen<T>(entry: &manifest::Entry, config: &Config, health_mx: &RwLock<HashMap<String, HealthReport>>) -> Result<Arc<RwLock<Box<Database<T>>>>, String> where
+T: 'static + Factory + Sync + Send + Clone + Eq + Hash,
+{
+    let data_dir = match config.data_dir {
+        Some(ref dir) => dir,
+        None => return Err("no data dir".to_string()),
+    };
+    let ns = entry.dir_name();
+    let path = data_dir.join(&ns);
+    if !path.exists() {
+        return Err(format!("{:?} doesn't exist", path))
+    }
+    let storage = try!(StorageEngine::parse(&entry.storage));
+
+    let mut db: Box<Database<T>> = try!(build(&ns, entry.dimensions.unwrap_or(entry.bits), entry.tolerance, entry.ignored_dimensions(), storage.backend(path.clone(), &config.rocksdb), config));
+    check_namespace(&ns, &path, &mut *db, config.repair_on_open, health_mx);
+
+    Ok(Arc::new(RwLock::new(db)))
+}
+
+/// 503 response for a write to a degraded namespace
+fn degraded(ns: &str, report: &HealthReport) -> Response {
+    let body = json!({
+        "error": "degraded",
+        "namespace": ns,
+        "findings": report.findings,
+    });
+
+    let response_body = serde_json::to_string(&body).unwrap();
+    Response::with((status::ServiceUnavailable, response_body))
+}
+
+/// Syncs a request's writes to `db` to disk if `sync` (`--sync-writes`) is
+/// set, returning a 500 response if they couldn't be
+fn sync_writes<T>(sync: bool, db: &Database<T>) -> Result<(), Response> {
+    if !sync {
+        return Ok(())
+    }
+
+    db.flush().map_err(error_response)
+}
+
+/// A namespace's DB to query
+///
+/// Queries read a snapshot of the DB (see `Database::snapshot`), so writers
+/// needn't wait for them to finish.  DBs which can't take one (ie evicting
+/// or shadowed namespaces, or in-memory stores without copy-on-write) are
+/// queried under their read lock instead.
+///
+pub enum Queried<'a, T: 'a> {
+    Snapshot(ReadOnly<T>),
+    Locked(RwLockReadGuard<'a, Box<Database<T>>>),
+}
+
+impl<'a, T> Queried<'a, T> {
+    pub fn new(db_mx: &'a RwLock<Box<Database<T>>>) -> Queried<'a, T> {
+        let db = db_mx.read().unwrap();
+        let snapshot = db.snapshot();
+        match snapshot {
+            Ok(snapshot) => Queried::Snapshot(snapshot),
+            Err(_) => Queried::Locked(db),
+        }
+    }
+}
+
+impl<'a, T> Deref for Queried<'a, T> where
+T: 'static + Sync + Send + Clone + Eq + Hash,
+{
+    type Target = Database<T>;
+
+    fn deref(&self) -> &Database<T> {
+        match *self {
+            Queried::Snapshot(ref db) => db,
+            Queried::Locked(ref db) => &***db,
+        }
+    }
+}
+
+/// Logs a write to namespace `ns` at debug level
+///
+/// Values are identified by the base64 of their bincode, as binary values
+/// are sent in requests.
+///
+pub fn log_mutation<T: Serialize>(op: &str, ns: &str, value: &T) {
+    if log_enabled!(Level::Debug) {
+        debug!("{} {} {}", op, ns, base64::encode_config(&encode(value).unwrap(), BASE64_CONFIG));
+    }
+}
+
+/// Parses the `order` query parameter, returning true if results should be
+/// ordered by insertion recency
+fn recent_order(req: &Request) -> Result<bool, Response> {
+    match query_param(req, "order") {
+        None => Ok(false),
+        Some(ref order) if order == "" => Ok(false),
+        Some(ref order) if order == "recent" => Ok(true),
+        Some(order) => Err(Response::with((status::BadRequest, format!("Unsupported order '{}'", order)))),
+    }
+}
@@ -0,0 +1,352 @@
+use std::fs;
+use std::hash::Hash;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use iron::prelude::*;
+use iron::status;
+use router::Router;
+use persistent::State;
+use serde_json;
+
+use hammer::db::{Database, Factory};
+use hammer::db::health::HealthReport;
+
+use http::{Config, ConfigKey, HealthKey, ManifestKey, TuningKey, binary_namespace, vector_namespace, query_param, B32, B64, B128, B256, V32, V64, V128, V256};
+use http::manifest::Manifest;
+use http::quotas::{Quota, QuotasKey};
+use http::binary_handler;
+use http::vector_handler;
+
+/// An opened namespace, as listed by `/dbs`
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct Namespace {
+    bits: usize,
+    /// Vector length, unset for binary namespaces
+    dimensions: Option<usize>,
+    tolerance: usize,
+    namespace: String,
+    /// True if the namespace failed its health check when re-opened
+    degraded: bool,
+}
+
+fn usize_param(req: &Request, name: &str) -> Result<usize, Response> {
+    match req.extensions.get::<Router>().unwrap().find(name).map(|v| v.parse::<usize>()) {
+        Some(Ok(v)) => Ok(v),
+        _ => Err(Response::with((status::BadRequest, format!("DB {} is required", name)))),
+    }
+}
+
+fn namespace_param(req: &Request) -> Result<String, Response> {
+    match req.extensions.get::<Router>().unwrap().find("namespace") {
+        Some(v) => Ok(v.to_string()),
+        None => Err(Response::with((status::BadRequest, "DB namespace is required"))),
+    }
+}
+
+/// Dimensions listed (comma-separated) by the `ignore` query parameter, each
+/// of which must be less than `dimensions`
+fn ignored_param(req: &Request, dimensions: usize) -> Result<Vec<usize>, Response> {
+    let param = match query_param(req, "ignore") {
+        Some(param) => param,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut ignored = Vec::new();
+    for v in param.split(',').filter(|v| !v.is_empty()) {
+        match v.parse::<usize>() {
+            Ok(d) if d < dimensions => ignored.push(d),
+            _ => return Err(Response::with((status::BadRequest, format!("Invalid ignored dimension '{}'", v)))),
+        }
+    }
+    Ok(ignored)
+}
+
+/// Bitsize, tolerance & name of the binary namespace identified by the route
+pub fn binary_params(req: &Request) -> Result<(usize, usize, String), Response> {
+    let bits = try!(usize_param(req, "bits"));
+    let tolerance = try!(usize_param(req, "tolerance"));
+    let namespace = try!(namespace_param(req));
+
+    Ok((bits, tolerance, namespace))
+}
+
+/// Bitsize, dimensions, tolerance & name of the vector namespace identified
+/// by the route
+pub fn vector_params(req: &Request) -> Result<(usize, usize, usize, String), Response> {
+    let bits = try!(usize_param(req, "bits"));
+    let dimensions = try!(usize_param(req, "dimensions"));
+    let tolerance = try!(usize_param(req, "tolerance"));
+    let namespace = try!(namespace_param(req));
+
+    Ok((bits, dimensions, tolerance, namespace))
+}
+
+/// Lists every opened namespace
+///
+pub fn list(req: &mut Request) -> IronResult<Response> {
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let health = health_mx.read().unwrap();
+    let mut namespaces = Vec::new();
+
+    {
+        let mut binary = |bits: usize, keys: Vec<(usize, String)>| {
+            for (tolerance, namespace) in keys.into_iter() {
+                let degraded = health.contains_key(&binary_namespace(bits, tolerance, &namespace));
+                namespaces.push(Namespace{bits: bits, dimensions: None, tolerance: tolerance, namespace: namespace, degraded: degraded});
+            }
+        };
+        binary(32, req.get::<State<B32>>().unwrap().read().unwrap().keys().cloned().collect());
+        binary(64, req.get::<State<B64>>().unwrap().read().unwrap().keys().cloned().collect());
+        binary(128, req.get::<State<B128>>().unwrap().read().unwrap().keys().cloned().collect());
+        binary(256, req.get::<State<B256>>().unwrap().read().unwrap().keys().cloned().collect());
+    }
+
+    {
+        let mut vector = |bits: usize, keys: Vec<(usize, usize, String)>| {
+            for (dimensions, tolerance, namespace) in keys.into_iter() {
+                let degraded = health.contains_key(&vector_namespace(bits, dimensions, tolerance, &namespace));
+                namespaces.push(Namespace{bits: bits, dimensions: Some(dimensions), tolerance: tolerance, namespace: namespace, degraded: degraded});
+            }
+        };
+        vector(32, req.get::<State<V32>>().unwrap().read().unwrap().keys().cloned().collect());
+        vector(64, req.get::<State<V64>>().unwrap().read().unwrap().keys().cloned().collect());
+        vector(128, req.get::<State<V128>>().unwrap().read().unwrap().keys().cloned().collect());
+        vector(256, req.get::<State<V256>>().unwrap().read().unwrap().keys().cloned().collect());
+    }
+
+    namespaces.sort();
+
+    let response_body = serde_json::to_string(&namespaces).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
+
+/// Opens a binary namespace without writing to it
+///
+/// Responds 201 if the namespace was opened (created, or re-opened from
+/// disk), 200 if it was already open.  A new namespace ignores the bits
+/// listed by `ignore` (ie `?ignore=0,1,63`, see `db::dimension_mask`); an
+/// existing one keeps the bits it was created ignoring.  The `max_values`,
+/// `max_disk_bytes` & `max_qps` parameters replace the namespace's quota
+/// (see `http::quotas`).
+///
+pub fn create_binary(req: &mut Request) -> IronResult<Response> {
+    let (bits, tolerance, namespace) = match binary_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let ignored = match ignored_param(req, bits) {
+        Ok(ignored) => ignored,
+        Err(response) => return Ok(response),
+    };
+    let quota = match Quota::from_request(req) {
+        Ok(quota) => quota,
+        Err(response) => return Ok(response),
+    };
+    let ns = binary_namespace(bits, tolerance, &namespace);
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+
+    let created = match bits {
+        32 => do_create_binary(bits, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<B32>>().unwrap()),
+        64 => do_create_binary(bits, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<B64>>().unwrap()),
+        128 => do_create_binary(bits, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<B128>>().unwrap()),
+        256 => do_create_binary(bits, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<B256>>().unwrap()),
+        _ => return Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    };
+
+    let created = match created {
+        Ok(created) => created,
+        Err(response) => return Ok(response),
+    };
+    if let Err(response) = set_quota(req, &ns, quota) {
+        return Ok(response)
+    }
+    Ok(created)
+}
+
+fn do_create_binary<T>(bits: usize, tolerance: usize, namespace: String, ignored: &[usize], config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, dbmap_mx: &RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>) -> Result<Response, Response> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory,
+{
+    let exists = dbmap_mx.read().unwrap().contains_key(&(tolerance, namespace.clone()));
+    if !exists {
+        try!(binary_handler::open(bits, tolerance, &namespace, ignored, config_mx, health_mx, manifest_mx, dbmap_mx));
+    }
+
+    Ok(created(!exists))
+}
+
+/// Opens a vector namespace without writing to it
+///
+/// Responds as `create_binary`, with `ignore` listing vector elements.
+///
+pub fn create_vector(req: &mut Request) -> IronResult<Response> {
+    let (bits, dimensions, tolerance, namespace) = match vector_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let ignored = match ignored_param(req, dimensions) {
+        Ok(ignored) => ignored,
+        Err(response) => return Ok(response),
+    };
+    let quota = match Quota::from_request(req) {
+        Ok(quota) => quota,
+        Err(response) => return Ok(response),
+    };
+    let ns = vector_namespace(bits, dimensions, tolerance, &namespace);
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+
+    let created = match bits {
+        32 => do_create_vector(bits, dimensions, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<V32>>().unwrap()),
+        64 => do_create_vector(bits, dimensions, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<V64>>().unwrap()),
+        128 => do_create_vector(bits, dimensions, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<V128>>().unwrap()),
+        256 => do_create_vector(bits, dimensions, tolerance, namespace, &ignored, &config_mx, &health_mx, &manifest_mx, &req.get::<State<V256>>().unwrap()),
+        _ => return Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    };
+
+    let created = match created {
+        Ok(created) => created,
+        Err(response) => return Ok(response),
+    };
+    if let Err(response) = set_quota(req, &ns, quota) {
+        return Ok(response)
+    }
+    Ok(created)
+}
+
+fn do_create_vector<T>(bits: usize, dimensions: usize, tolerance: usize, namespace: String, ignored: &[usize], config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, dbmap_mx: &RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>) -> Result<Response, Response> where
+T: 'static,
+Vec<T>: Sync + Send + Clone + Eq + Hash + Factory,
+{
+    let exists = dbmap_mx.read().unwrap().contains_key(&(dimensions, tolerance, namespace.clone()));
+    if !exists {
+        try!(vector_handler::open(bits, dimensions, tolerance, &namespace, ignored, config_mx, health_mx, manifest_mx, dbmap_mx));
+    }
+
+    Ok(created(!exists))
+}
+
+/// Replaces the quota of namespace `ns` if the request set one
+fn set_quota(req: &mut Request, ns: &str, quota: Option<Quota>) -> Result<(), Response> {
+    let quota = match quota {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    let data_dir = req.get::<State<ConfigKey>>().unwrap().read().unwrap().data_dir.clone();
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    let set = quotas_mx.write().unwrap().set(ns, data_dir.as_ref().map(|dir| dir.as_path()), quota);
+    set.map_err(|e| Response::with((status::InternalServerError, format!("unable to persist quota: {}", e))))
+}
+
+#[derive(Serialize)]
+struct Created {
+    created: bool,
+}
+
+#[derive(Serialize)]
+struct Dropped {
+    dropped: bool,
+}
+
+fn created(created: bool) -> Response {
+    let response_body = serde_json::to_string(&Created{created: created}).unwrap();
+
+    match created {
+        true => Response::with((status::Created, response_body)),
+        false => Response::with((status::Ok, response_body)),
+    }
+}
+
+/// Closes a binary namespace and deletes its data, tuning and manifest entry
+///
+/// Responds 404 if the namespace is neither open nor persisted.
+///
+pub fn drop_binary(req: &mut Request) -> IronResult<Response> {
+    let (bits, tolerance, namespace) = match binary_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let ns = binary_namespace(bits, tolerance, &namespace);
+    let key = (tolerance, namespace);
+
+    // Handlers hold their dbmap's lock while using a DB, so once it's been
+    // removed from the dbmap nothing else can be using it
+    let opened = match bits {
+        32 => req.get::<State<B32>>().unwrap().write().unwrap().remove(&key).is_some(),
+        64 => req.get::<State<B64>>().unwrap().write().unwrap().remove(&key).is_some(),
+        128 => req.get::<State<B128>>().unwrap().write().unwrap().remove(&key).is_some(),
+        256 => req.get::<State<B256>>().unwrap().write().unwrap().remove(&key).is_some(),
+        _ => return Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    };
+
+    forget(req, &ns, opened)
+}
+
+/// Closes a vector namespace and deletes its data, tuning and manifest entry
+///
+/// Responds as `drop_binary`.
+///
+pub fn drop_vector(req: &mut Request) -> IronResult<Response> {
+    let (bits, dimensions, tolerance, namespace) = match vector_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let ns = vector_namespace(bits, dimensions, tolerance, &namespace);
+    let key = (dimensions, tolerance, namespace);
+
+    let opened = match bits {
+        32 => req.get::<State<V32>>().unwrap().write().unwrap().remove(&key).is_some(),
+        64 => req.get::<State<V64>>().unwrap().write().unwrap().remove(&key).is_some(),
+        128 => req.get::<State<V128>>().unwrap().write().unwrap().remove(&key).is_some(),
+        256 => req.get::<State<V256>>().unwrap().write().unwrap().remove(&key).is_some(),
+        _ => return Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    };
+
+    forget(req, &ns, opened)
+}
+
+/// Removes everything kept about the closed namespace `ns`
+fn forget(req: &mut Request, ns: &str, opened: bool) -> IronResult<Response> {
+    let config = {
+        req.get::<State<ConfigKey>>().unwrap().read().unwrap().clone()
+    };
+
+    req.get::<State<HealthKey>>().unwrap().write().unwrap().remove(ns);
+    req.get::<State<TuningKey>>().unwrap().write().unwrap().remove(ns);
+    req.get::<State<QuotasKey>>().unwrap().write().unwrap().forget(ns);
+
+    let mut persisted = false;
+    if let Some(ref dir) = config.data_dir {
+        let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+        if let Err(e) = manifest_mx.write().unwrap().forget(dir, ns) {
+            return Ok(Response::with((status::InternalServerError, format!("unable to update manifest: {}", e))))
+        }
+
+        if let Some(ref shared) = config.column_families {
+            if let Err(e) = shared.drop_stores(&format!("{}/", ns)) {
+                return Ok(Response::with((status::InternalServerError, format!("unable to remove namespace data: {}", e))))
+            }
+        }
+
+        let path = dir.join(ns);
+        if path.exists() {
+            if let Err(e) = fs::remove_dir_all(&path) {
+                return Ok(Response::with((status::InternalServerError, format!("unable to remove namespace data: {}", e))))
+            }
+            persisted = true;
+        }
+    }
+
+    if !opened && !persisted {
+        return Ok(Response::with((status::NotFound, "No such namespace")))
+    }
+
+    let response_body = serde_json::to_string(&Dropped{dropped: true}).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
@@ -0,0 +1,494 @@
+//! Log of the adds & deletes applied to each namespace
+//!
+//! With `--oplog` every add & delete request is appended to `oplog.jsonl` in
+//! the data dir before it's applied, one JSON entry per line with an
+//! increasing sequence number.  A second server can be kept in sync by
+//! tailing the log and replaying it:
+//!
+//! ```sh
+//! curl 'primary:3000/oplog?after=0' | curl --data-binary @- secondary:3000/oplog/replay
+//! ```
+//!
+//! `GET /oplog?after=<sequence>&limit=<n>` returns up to `limit` entries
+//! (default 1000) following `after` as newline-delimited JSON.
+//! `POST /oplog/replay` applies such entries, skipping any already replayed,
+//! and `GET /oplog/replayed` returns the last sequence replayed so a follower
+//! knows where to resume.  Replayed writes are appended to the replaying
+//! server's own log (if it keeps one), so servers can be chained.
+//!
+//! Entries hold the values which decoded, so replaying an entry re-applies
+//! exactly what the request did; adds & deletes are idempotent, so replaying
+//! an entry twice is harmless.  The log isn't truncated, and dropping a
+//! namespace isn't logged.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use iron::prelude::*;
+use iron::{status, typemap};
+use persistent::State;
+use base64;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use hammer::db::{Database, Factory};
+use hammer::db::encoding::encode;
+use hammer::db::health::HealthReport;
+
+use http::{Config, ConfigKey, HealthKey, ManifestKey, BASE64_CONFIG, B32, B64, B128, B256, V32, V64, V128, V256, degraded, binary_namespace, vector_namespace, query_param};
+use http::binary_handler;
+use http::vector_handler;
+use http::encoding::decode_base64;
+use http::limits::read_body_string;
+use http::manifest::Manifest;
+
+pub const OPLOG_FILE: &'static str = "oplog.jsonl";
+const REPLAYED_FILE: &'static str = "oplog.replayed";
+
+/// Entries returned by a tail request without a `limit`
+pub const DEFAULT_TAIL_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Add,
+    Delete,
+}
+
+/// A logged add or delete request
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub sequence: u64,
+    pub op: Op,
+    pub bits: usize,
+    /// Vector length, unset for binary namespaces
+    pub dimensions: Option<usize>,
+    pub tolerance: usize,
+    pub namespace: String,
+    /// Base64-encoded bincode of each value (of each whole vector, for vector
+    /// namespaces)
+    pub values: Vec<String>,
+}
+
+/// The oplog of a data dir, open for appending
+///
+pub struct Oplog {
+    path: PathBuf,
+    file: File,
+    last_sequence: u64,
+    sync: bool,
+}
+
+impl Oplog {
+    /// Opens (or creates) the oplog in `data_dir`, syncing each append to
+    /// disk if `sync` is set
+    ///
+    /// A partial entry left at the end of the log by a crash mid-append is
+    /// discarded.
+    ///
+    pub fn open(data_dir: &Path, sync: bool) -> Result<Oplog, String> {
+        try!(fs::create_dir_all(data_dir).map_err(|e| format!("{}", e)));
+        let path = data_dir.join(OPLOG_FILE);
+
+        let mut payload = String::new();
+        if path.exists() {
+            let mut f = try!(File::open(&path).map_err(|e| format!("{}", e)));
+            try!(f.read_to_string(&mut payload).map_err(|e| format!("{}", e)));
+        }
+
+        let complete = payload.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let last_sequence = match payload[..complete].lines().last() {
+            Some(line) => try!(serde_json::from_str::<Entry>(line).map_err(|e| format!("unreadable {}: {}", OPLOG_FILE, e))).sequence,
+            None => 0,
+        };
+
+        let file = try!(OpenOptions::new().create(true).append(true).open(&path).map_err(|e| format!("{}", e)));
+        if complete < payload.len() {
+            try!(file.set_len(complete as u64).map_err(|e| format!("{}", e)));
+        }
+
+        Ok(Oplog {
+            path: path,
+            file: file,
+            last_sequence: last_sequence,
+            sync: sync,
+        })
+    }
+
+    /// Sequence of the most recent entry, or 0 if the log is empty
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Appends an entry, returning its sequence
+    ///
+    pub fn append(&mut self, op: Op, bits: usize, dimensions: Option<usize>, tolerance: usize, namespace: &str, values: Vec<String>) -> Result<u64, String> {
+        let entry = Entry {
+            sequence: self.last_sequence + 1,
+            op: op,
+            bits: bits,
+            dimensions: dimensions,
+            tolerance: tolerance,
+            namespace: namespace.to_string(),
+            values: values,
+        };
+
+        let mut line = try!(serde_json::to_string(&entry).map_err(|e| format!("{}", e)));
+        line.push('\n');
+        try!(self.file.write_all(line.as_bytes()).map_err(|e| format!("{}", e)));
+        if self.sync {
+            try!(self.file.sync_data().map_err(|e| format!("{}", e)));
+        }
+
+        self.last_sequence = entry.sequence;
+        Ok(entry.sequence)
+    }
+
+    /// Syncs appended entries to disk
+    ///
+    pub fn sync(&self) -> Result<(), String> {
+        self.file.sync_data().map_err(|e| format!("{}", e))
+    }
+
+    /// Up to `limit` entries following sequence `after`, in order
+    ///
+    pub fn tail(&self, after: u64, limit: usize) -> Result<Vec<Entry>, String> {
+        let f = try!(File::open(&self.path).map_err(|e| format!("{}", e)));
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(f).lines() {
+            if entries.len() >= limit {
+                break
+            }
+
+            let line = try!(line.map_err(|e| format!("{}", e)));
+            let entry: Entry = try!(serde_json::from_str(&line).map_err(|e| format!("unreadable {}: {}", OPLOG_FILE, e)));
+            if entry.sequence > after {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+pub struct OplogKey;
+impl typemap::Key for OplogKey { type Value = Option<Oplog>; }
+
+/// Sequence of the last entry replayed from another server's oplog
+pub struct ReplayedKey;
+impl typemap::Key for ReplayedKey { type Value = u64; }
+
+/// Reads the last replayed sequence recorded in `data_dir`
+///
+pub fn load_replayed(data_dir: &Path) -> Result<u64, String> {
+    let path = data_dir.join(REPLAYED_FILE);
+    if !path.exists() {
+        return Ok(0)
+    }
+
+    let mut payload = String::new();
+    let mut f = try!(File::open(&path).map_err(|e| format!("{}", e)));
+    try!(f.read_to_string(&mut payload).map_err(|e| format!("{}", e)));
+
+    payload.trim().parse().map_err(|e| format!("unreadable {}: {}", REPLAYED_FILE, e))
+}
+
+/// The replayed sequence is written to a temporary file and renamed into
+/// place, as with the manifest
+///
+fn save_replayed(data_dir: &Path, sequence: u64) -> Result<(), String> {
+    try!(fs::create_dir_all(data_dir).map_err(|e| format!("{}", e)));
+    let path = data_dir.join(REPLAYED_FILE);
+    let tmp_path = data_dir.join(format!("{}.tmp", REPLAYED_FILE));
+    {
+        let mut f = try!(File::create(&tmp_path).map_err(|e| format!("{}", e)));
+        try!(f.write_all(sequence.to_string().as_bytes()).map_err(|e| format!("{}", e)));
+        try!(f.sync_all().map_err(|e| format!("{}", e)));
+    }
+    fs::rename(&tmp_path, &path).map_err(|e| format!("{}", e))
+}
+
+/// Appends the values of a request which decoded to the oplog, if one is
+/// kept
+///
+/// Called with the namespace locked for writing, so entries for a namespace
+/// are logged in the order they're applied.  Returns a 500 response if the
+/// entry couldn't be appended, in which case the request must not be
+/// applied.
+///
+pub fn record<T: Serialize>(oplog_mx: &RwLock<Option<Oplog>>, op: Op, bits: usize, dimensions: Option<usize>, tolerance: usize, namespace: &str, values: &[Result<T, String>]) -> Result<(), Response> {
+    let mut oplog = oplog_mx.write().unwrap();
+    let oplog = match *oplog {
+        Some(ref mut oplog) => oplog,
+        None => return Ok(()),
+    };
+
+    let encoded: Vec<String> = values.iter()
+        .filter_map(|value| value.as_ref().ok())
+        .map(|value| base64::encode_config(&encode(value).unwrap(), BASE64_CONFIG))
+        .collect();
+    if encoded.is_empty() {
+        return Ok(())
+    }
+
+    oplog.append(op, bits, dimensions, tolerance, namespace, encoded)
+        .map(|_| ())
+        .map_err(|e| Response::with((status::InternalServerError, format!("unable to append to oplog: {}", e))))
+}
+
+/// Returns the entries following `after`, as newline-delimited JSON
+///
+pub fn tail(req: &mut Request) -> IronResult<Response> {
+    let after = match query_param(req, "after").map(|v| v.parse::<u64>()) {
+        None => 0,
+        Some(Ok(after)) => after,
+        Some(Err(_)) => return Ok(Response::with((status::BadRequest, "Invalid after"))),
+    };
+    let limit = match query_param(req, "limit").map(|v| v.parse::<usize>()) {
+        None => DEFAULT_TAIL_LIMIT,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return Ok(Response::with((status::BadRequest, "Invalid limit"))),
+    };
+
+    let oplog_mx = req.get::<State<OplogKey>>().unwrap();
+    let oplog = oplog_mx.read().unwrap();
+    let entries = match *oplog {
+        Some(ref oplog) => oplog.tail(after, limit),
+        None => return Ok(Response::with((status::NotFound, "No oplog is kept (see --oplog)"))),
+    };
+
+    match entries {
+        Ok(entries) => {
+            let mut response_body = String::new();
+            for entry in entries.iter() {
+                response_body.push_str(&serde_json::to_string(entry).unwrap());
+                response_body.push('\n');
+            }
+            Ok(Response::with((status::Ok, response_body)))
+        },
+        Err(e) => Ok(Response::with((status::InternalServerError, format!("unable to read oplog: {}", e)))),
+    }
+}
+
+#[derive(Serialize)]
+struct Replayed {
+    /// Sequence of the last entry replayed
+    replayed: u64,
+    /// Entries applied by this request
+    applied: usize,
+    /// Entries skipped as already replayed
+    skipped: usize,
+}
+
+/// Returns the sequence of the last entry replayed
+///
+pub fn replayed(req: &mut Request) -> IronResult<Response> {
+    let replayed = *req.get::<State<ReplayedKey>>().unwrap().read().unwrap();
+
+    let response_body = serde_json::to_string(&Replayed{replayed: replayed, applied: 0, skipped: 0}).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
+
+/// Applies newline-delimited oplog entries tailed from another server
+///
+/// Entries are applied in order, stopping at the first which can't be; the
+/// replayed sequence is advanced past every entry applied before it, so the
+/// follower can resume from there.
+///
+pub fn replay(req: &mut Request) -> IronResult<Response> {
+    let payload = try!(read_body_string(req));
+
+    let mut entries = Vec::new();
+    for line in payload.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<Entry>(line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => return Ok(Response::with((status::BadRequest, format!("unable to parse entry: {}", e)))),
+        }
+    }
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+    let oplog_mx = req.get::<State<OplogKey>>().unwrap();
+    let replayed_mx = req.get::<State<ReplayedKey>>().unwrap();
+    let b32 = req.get::<State<B32>>().unwrap();
+    let b64 = req.get::<State<B64>>().unwrap();
+    let b128 = req.get::<State<B128>>().unwrap();
+    let b256 = req.get::<State<B256>>().unwrap();
+    let v32 = req.get::<State<V32>>().unwrap();
+    let v64 = req.get::<State<V64>>().unwrap();
+    let v128 = req.get::<State<V128>>().unwrap();
+    let v256 = req.get::<State<V256>>().unwrap();
+
+    // Replays are applied one at a time, so entries are never skipped or
+    // applied out of order by concurrent requests
+    let mut replayed = replayed_mx.write().unwrap();
+    let data_dir = config_mx.read().unwrap().data_dir.clone();
+    let (mut applied, mut skipped) = (0, 0);
+
+    let mut failure = None;
+
+    for entry in entries.iter() {
+        if entry.sequence <= *replayed {
+            skipped += 1;
+            continue
+        }
+
+        let ctx = Context{config_mx: &config_mx, health_mx: &health_mx, manifest_mx: &manifest_mx, oplog_mx: &oplog_mx};
+        let result = match (entry.bits, entry.dimensions) {
+            (32, None) => apply_binary::<u32>(entry, &ctx, &b32),
+            (64, None) => apply_binary::<u64>(entry, &ctx, &b64),
+            (128, None) => apply_binary::<[u64; 2]>(entry, &ctx, &b128),
+            (256, None) => apply_binary::<[u64; 4]>(entry, &ctx, &b256),
+            (32, Some(dimensions)) => apply_vector::<u32>(entry, dimensions, &ctx, &v32),
+            (64, Some(dimensions)) => apply_vector::<u64>(entry, dimensions, &ctx, &v64),
+            (128, Some(dimensions)) => apply_vector::<[u64; 2]>(entry, dimensions, &ctx, &v128),
+            (256, Some(dimensions)) => apply_vector::<[u64; 4]>(entry, dimensions, &ctx, &v256),
+            (bits, _) => Err(Response::with((status::BadRequest, format!("entry {}: unsupported bitsize {}", entry.sequence, bits)))),
+        };
+
+        if let Err(response) = result {
+            failure = Some(response);
+            break
+        }
+
+        *replayed = entry.sequence;
+        applied += 1;
+    }
+
+    // Entries applied before a failure are recorded too, so they aren't
+    // applied again
+    if let Some(ref dir) = data_dir {
+        if applied > 0 {
+            if let Err(e) = save_replayed(dir, *replayed) {
+                return Ok(Response::with((status::InternalServerError, format!("unable to record replayed sequence: {}", e))))
+            }
+        }
+    }
+    if let Some(response) = failure {
+        return Ok(response)
+    }
+
+    let response_body = serde_json::to_string(&Replayed{replayed: *replayed, applied: applied, skipped: skipped}).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
+
+/// Server state needed to apply an entry
+struct Context<'a> {
+    config_mx: &'a RwLock<Config>,
+    health_mx: &'a RwLock<HashMap<String, HealthReport>>,
+    manifest_mx: &'a RwLock<Manifest>,
+    oplog_mx: &'a RwLock<Option<Oplog>>,
+}
+
+fn decode_values<T: DeserializeOwned>(entry: &Entry) -> Result<Vec<Result<T, String>>, Response> {
+    let mut values = Vec::with_capacity(entry.values.len());
+    for value in entry.values.iter() {
+        match decode_base64(value) {
+            Ok(value) => values.push(Ok(value)),
+            Err(e) => return Err(Response::with((status::BadRequest, format!("entry {}: {}", entry.sequence, e)))),
+        }
+    }
+    Ok(values)
+}
+
+fn apply_binary<T>(entry: &Entry, ctx: &Context, dbmap_mx: &RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>) -> Result<(), Response> where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + Serialize + DeserializeOwned,
+{
+    let values = try!(decode_values::<T>(entry));
+
+    match entry.op {
+        Op::Add => {
+            try!(binary_handler::add_values(values, entry.bits, entry.tolerance, &entry.namespace, ctx.config_mx, ctx.health_mx, ctx.manifest_mx, ctx.oplog_mx, dbmap_mx));
+        },
+        Op::Delete => {
+            let ns = binary_namespace(entry.bits, entry.tolerance, &entry.namespace);
+            if let Some(report) = ctx.health_mx.read().unwrap().get(&ns) {
+                return Err(degraded(&ns, report))
+            }
+
+            let sync = ctx.config_mx.read().unwrap().sync_writes;
+            try!(binary_handler::remove_values(values, entry.bits, entry.tolerance, &entry.namespace, false, sync, ctx.oplog_mx, dbmap_mx));
+        },
+    }
+    Ok(())
+}
+
+fn apply_vector<T>(entry: &Entry, dimensions: usize, ctx: &Context, dbmap_mx: &RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>) -> Result<(), Response> where
+T: 'static + Clone + Serialize + DeserializeOwned,
+Vec<T>: Sync + Send + Clone + Eq + Hash + Factory,
+{
+    let vectors = try!(decode_values::<Vec<T>>(entry));
+    for vector in vectors.iter().filter_map(|vector| vector.as_ref().ok()) {
+        if vector.len() != dimensions {
+            return Err(Response::with((status::BadRequest, format!("entry {}: expected vector length to be {}, not {}", entry.sequence, dimensions, vector.len()))))
+        }
+    }
+
+    match entry.op {
+        Op::Add => {
+            try!(vector_handler::add_vectors(vectors, entry.bits, dimensions, entry.tolerance, &entry.namespace, ctx.config_mx, ctx.health_mx, ctx.manifest_mx, ctx.oplog_mx, dbmap_mx));
+        },
+        Op::Delete => {
+            let ns = vector_namespace(entry.bits, dimensions, entry.tolerance, &entry.namespace);
+            if let Some(report) = ctx.health_mx.read().unwrap().get(&ns) {
+                return Err(degraded(&ns, report))
+            }
+
+            let sync = ctx.config_mx.read().unwrap().sync_writes;
+            try!(vector_handler::remove_vectors(vectors, entry.bits, dimensions, entry.tolerance, &entry.namespace, false, sync, ctx.oplog_mx, dbmap_mx));
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use hammer::db::temp_dir::TempDir;
+
+    use http::oplog::{Oplog, Op, OPLOG_FILE};
+
+    #[test]
+    fn appends_and_tails_in_sequence() {
+        let dir = TempDir::new();
+        let mut oplog = Oplog::open(dir.path(), false).unwrap();
+        assert_eq!(oplog.last_sequence(), 0);
+
+        assert_eq!(oplog.append(Op::Add, 64, None, 4, "foo", vec!["AA==".to_string()]), Ok(1));
+        assert_eq!(oplog.append(Op::Delete, 64, None, 4, "foo", vec!["AA==".to_string()]), Ok(2));
+        assert_eq!(oplog.append(Op::Add, 32, Some(8), 2, "bar", vec!["AQ==".to_string()]), Ok(3));
+
+        let entries = oplog.tail(1, 10).unwrap();
+        assert_eq!(entries.iter().map(|e| e.sequence).collect::<Vec<u64>>(), vec![2, 3]);
+        assert_eq!(entries[0].op, Op::Delete);
+        assert_eq!(entries[1].dimensions, Some(8));
+        assert_eq!(oplog.tail(0, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reopening_discards_partial_entries() {
+        let dir = TempDir::new();
+        {
+            let mut oplog = Oplog::open(dir.path(), false).unwrap();
+            oplog.append(Op::Add, 64, None, 4, "foo", vec![]).unwrap();
+        }
+        {
+            let mut f = OpenOptions::new().append(true).open(dir.path().join(OPLOG_FILE)).unwrap();
+            f.write_all(b"{\"sequence\":2,\"op\":").unwrap();
+        }
+
+        let mut oplog = Oplog::open(dir.path(), false).unwrap();
+        assert_eq!(oplog.last_sequence(), 1);
+        assert_eq!(oplog.append(Op::Add, 64, None, 4, "foo", vec![]), Ok(2));
+        assert_eq!(oplog.tail(0, 10).unwrap().len(), 2);
+    }
+}
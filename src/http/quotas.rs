@@ -0,0 +1,451 @@
+//! Per-namespace resource quotas
+//!
+//! A namespace created with `PUT /db/...` can be given quotas with the
+//! `max_values`, `max_disk_bytes` and `max_qps` query parameters, so one
+//! namespace ingesting aggressively can't starve the others sharing the
+//! server.  Quotas are persisted in the namespace's directory (alongside its
+//! tuning), and re-creating a namespace with quota parameters replaces them.
+//!
+//! Quotas are enforced by the HTTP handlers: `max_qps` limits every add,
+//! ingest, query & delete made to the namespace, while `max_values` and
+//! `max_disk_bytes` refuse adds once the namespace is full.  The namespace's
+//! tuned `rate_limit` is enforced like `max_qps`, and whichever is lower
+//! applies.  A request over a quota is refused with a `quota_exceeded` error
+//! naming it - `429 Too Many Requests` for `max_qps` and `rate_limit`, `403
+//! Forbidden` otherwise.  Writes made over the
+//! binary protocol or replayed from the oplog aren't limited.
+//!
+//! Values are counted from the namespace's stats when first needed, then kept
+//! up to date by the HTTP handlers' adds & deletes.  Each add is checked
+//! before it's made, so concurrent adds can overshoot `max_values` by a
+//! request.
+
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use iron::prelude::*;
+use iron::{status, typemap};
+use serde_json;
+
+use hammer::db::Database;
+use hammer::db::disk_usage::disk_usage;
+
+use http::{Config, AddResult, DeleteResult, query_param};
+use http::tuning_handler::persisted_tuning;
+
+const QUOTA_FILE: &'static str = "quota.json";
+
+/// Limits on a namespace's resources - unset limits are not checked
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Values the namespace may hold
+    pub max_values: Option<usize>,
+    /// Bytes the namespace's directory may take on disk
+    pub max_disk_bytes: Option<u64>,
+    /// Requests per second made to the namespace, in bursts of up to as many
+    pub max_qps: Option<usize>,
+}
+
+impl Quota {
+    /// Parses the quota query parameters, returning `None` if none were set
+    ///
+    pub fn from_request(req: &Request) -> Result<Option<Quota>, Response> {
+        let quota = Quota {
+            max_values: try!(limit_param(req, "max_values")),
+            max_disk_bytes: try!(limit_param(req, "max_disk_bytes")),
+            max_qps: try!(limit_param(req, "max_qps")),
+        };
+
+        if quota == Default::default() {
+            Ok(None)
+        } else {
+            Ok(Some(quota))
+        }
+    }
+}
+
+fn limit_param<T: ::std::str::FromStr + PartialOrd + Default>(req: &Request, name: &str) -> Result<Option<T>, Response> {
+    match query_param(req, name) {
+        None => Ok(None),
+        Some(v) => match v.parse::<T>() {
+            Ok(limit) if limit > T::default() => Ok(Some(limit)),
+            _ => Err(Response::with((status::BadRequest, format!("Invalid {} '{}'", name, v)))),
+        },
+    }
+}
+
+/// A request refused for exceeding one of a namespace's quotas
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub namespace: String,
+    /// Name of the quota exceeded (ie `max_values`)
+    pub quota: &'static str,
+    pub limit: u64,
+    /// Usage the request would have brought the namespace to
+    pub usage: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "namespace {} would exceed its {} quota of {} ({})", self.namespace, self.quota, self.limit, self.usage)
+    }
+}
+
+impl QuotaExceeded {
+    pub fn response(&self) -> Response {
+        let code = match self.quota {
+            "max_qps" | "rate_limit" => status::TooManyRequests,
+            _ => status::Forbidden,
+        };
+        let body = json!({
+            "error": "quota_exceeded",
+            "namespace": self.namespace,
+            "quota": self.quota,
+            "limit": self.limit,
+            "usage": self.usage,
+            "message": format!("{}", self),
+        });
+
+        Response::with((code, body.to_string()))
+    }
+}
+
+/// A namespace's quota, and its usage so far
+///
+struct Tracked {
+    quota: Quota,
+    /// The namespace's tuned `rate_limit` (0 is unlimited)
+    rate_limit: usize,
+    /// Counted when first needed
+    values: Option<usize>,
+    /// Token bucket for `max_qps` or `rate_limit`
+    tokens: f64,
+    last: Instant,
+}
+
+impl Tracked {
+    fn new(quota: Quota, rate_limit: usize) -> Tracked {
+        let mut tracked = Tracked {
+            quota: quota,
+            rate_limit: rate_limit,
+            values: None,
+            tokens: 0.0,
+            last: Instant::now(),
+        };
+        tracked.tokens = tracked.rate().map_or(0, |(_, rate)| rate) as f64;
+        tracked
+    }
+
+    /// The lower of `max_qps` & `rate_limit`, and which it is, if either is
+    /// set
+    ///
+    fn rate(&self) -> Option<(&'static str, usize)> {
+        match (self.quota.max_qps, self.rate_limit) {
+            (Some(max_qps), rate_limit) if rate_limit == 0 || max_qps <= rate_limit => Some(("max_qps", max_qps)),
+            (_, 0) => None,
+            (_, rate_limit) => Some(("rate_limit", rate_limit)),
+        }
+    }
+}
+
+/// The `rate_limit` tuned for namespace `ns`, if it's persisted
+fn persisted_rate_limit(ns: &str, data_dir: Option<&Path>) -> usize {
+    persisted_tuning(data_dir, ns).map_or(0, |tuning| tuning.rate_limit)
+}
+
+/// Quotas of the namespaces used since the server started
+///
+/// Namespaces without a quota are tracked too, so their quota file is only
+/// looked for once.
+///
+#[derive(Default)]
+pub struct Quotas {
+    namespaces: HashMap<String, Tracked>,
+}
+
+pub struct QuotasKey;
+impl typemap::Key for QuotasKey { type Value = Quotas; }
+
+impl Quotas {
+    /// The quota of namespace `ns`, loading it from the namespace's directory
+    /// the first time
+    ///
+    fn tracked(&mut self, ns: &str, data_dir: Option<&Path>) -> &mut Tracked {
+        if !self.namespaces.contains_key(ns) {
+            let quota = quota_path(data_dir, ns).and_then(|path| load_quota(&path)).unwrap_or_default();
+            self.namespaces.insert(ns.to_string(), Tracked::new(quota, persisted_rate_limit(ns, data_dir)));
+        }
+        self.namespaces.get_mut(ns).unwrap()
+    }
+
+    pub fn quota(&mut self, ns: &str, data_dir: Option<&Path>) -> Quota {
+        self.tracked(ns, data_dir).quota
+    }
+
+    /// Replaces the quota of namespace `ns`, persisting it if the namespace
+    /// is persisted
+    ///
+    pub fn set(&mut self, ns: &str, data_dir: Option<&Path>, quota: Quota) -> Result<(), String> {
+        if let Some(path) = quota_path(data_dir, ns) {
+            try!(save_quota(&path, &quota));
+        }
+
+        let (values, rate_limit) = match self.namespaces.get(ns) {
+            Some(tracked) => (tracked.values, tracked.rate_limit),
+            None => (None, persisted_rate_limit(ns, data_dir)),
+        };
+        let mut tracked = Tracked::new(quota, rate_limit);
+        tracked.values = values;
+        self.namespaces.insert(ns.to_string(), tracked);
+        Ok(())
+    }
+
+    /// Replaces the tuned `rate_limit` of namespace `ns` (see
+    /// `tuning_handler`, which persists it)
+    ///
+    pub fn set_rate_limit(&mut self, ns: &str, data_dir: Option<&Path>, rate_limit: usize) {
+        let quota = self.quota(ns, data_dir);
+        let values = self.namespaces.get(ns).and_then(|tracked| tracked.values);
+        let mut tracked = Tracked::new(quota, rate_limit);
+        tracked.values = values;
+        self.namespaces.insert(ns.to_string(), tracked);
+    }
+
+    /// Forgets namespace `ns`, which has been dropped
+    ///
+    pub fn forget(&mut self, ns: &str) {
+        self.namespaces.remove(ns);
+    }
+
+    /// Takes a token from the bucket of namespace `ns` as of `now`
+    ///
+    pub fn check_rate(&mut self, ns: &str, data_dir: Option<&Path>, now: Instant) -> Result<(), QuotaExceeded> {
+        let tracked = self.tracked(ns, data_dir);
+        let (quota, rate) = match tracked.rate() {
+            Some((quota, rate)) => (quota, rate as f64),
+            None => return Ok(()),
+        };
+
+        if now > tracked.last {
+            let elapsed = now.duration_since(tracked.last);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            tracked.tokens = (tracked.tokens + elapsed * rate).min(rate);
+            tracked.last = now;
+        }
+
+        if tracked.tokens < 1.0 {
+            return Err(QuotaExceeded{namespace: ns.to_string(), quota: quota, limit: rate as u64, usage: rate as u64 + 1})
+        }
+        tracked.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Checks that adding `adding` values to namespace `ns` keeps it within
+    /// its quota, counting the values it already holds with `count` if they
+    /// haven't been yet
+    ///
+    pub fn check_add<F: FnOnce() -> usize>(&mut self, ns: &str, data_dir: Option<&Path>, adding: usize, count: F) -> Result<(), QuotaExceeded> {
+        let path = data_dir.map(|dir| dir.join(ns));
+        let tracked = self.tracked(ns, data_dir);
+
+        if let Some(limit) = tracked.quota.max_values {
+            let values = match tracked.values {
+                Some(values) => values,
+                None => count(),
+            };
+            tracked.values = Some(values);
+
+            if values + adding > limit {
+                return Err(QuotaExceeded{namespace: ns.to_string(), quota: "max_values", limit: limit as u64, usage: (values + adding) as u64})
+            }
+        }
+
+        if let (Some(limit), Some(path)) = (tracked.quota.max_disk_bytes, path) {
+            // A namespace without a directory yet takes no space
+            let bytes = disk_usage(&path).map(|usage| usage.bytes).unwrap_or(0);
+            if bytes >= limit {
+                return Err(QuotaExceeded{namespace: ns.to_string(), quota: "max_disk_bytes", limit: limit, usage: bytes})
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts the values added to namespace `ns`
+    ///
+    pub fn record_added(&mut self, ns: &str, results: &[AddResult]) {
+        if let Some(tracked) = self.namespaces.get_mut(ns) {
+            let added = results.iter().filter(|result| result.outcome() == "ok").count();
+            tracked.values = tracked.values.map(|values| values + added);
+        }
+    }
+
+    /// Counts the values removed from namespace `ns`
+    ///
+    pub fn record_removed(&mut self, ns: &str, results: &[DeleteResult]) {
+        if let Some(tracked) = self.namespaces.get_mut(ns) {
+            let removed = results.iter().filter(|result| result.outcome() == "ok").count();
+            tracked.values = tracked.values.map(|values| values.saturating_sub(removed));
+        }
+    }
+}
+
+/// Values held by the namespace at `key` in `dbmap_mx` (none if it isn't
+/// open)
+///
+fn stored_values<K, T>(dbmap_mx: &RwLock<HashMap<K, Arc<RwLock<Box<Database<T>>>>>>, key: &K) -> usize where
+K: Eq + Hash,
+{
+    let db_mx = match dbmap_mx.read().unwrap().get(key) {
+        Some(db_mx) => db_mx.clone(),
+        None => return 0,
+    };
+
+    let stats = db_mx.read().unwrap().stats();
+    match stats {
+        Ok(stats) => stats.values,
+        Err(e) => {
+            warn!("unable to count values for quota: {}", e);
+            0
+        },
+    }
+}
+
+/// Refuses a request to namespace `ns` exceeding its `max_qps`
+///
+pub fn check_rate(quotas_mx: &RwLock<Quotas>, config_mx: &RwLock<Config>, ns: &str) -> Result<(), Response> {
+    let data_dir = config_mx.read().unwrap().data_dir.clone();
+
+    let checked = quotas_mx.write().unwrap().check_rate(ns, data_dir.as_ref().map(|dir| dir.as_path()), Instant::now());
+    checked.map_err(|e| e.response())
+}
+
+/// Refuses adding `adding` values to namespace `ns`, stored at `key` in
+/// `dbmap_mx`, if it would exceed its `max_values` or `max_disk_bytes`
+///
+/// The quotas are locked while the namespace's values are first counted.
+///
+pub fn check_add<K, T>(quotas_mx: &RwLock<Quotas>, config_mx: &RwLock<Config>, ns: &str, adding: usize, dbmap_mx: &RwLock<HashMap<K, Arc<RwLock<Box<Database<T>>>>>>, key: &K) -> Result<(), Response> where
+K: Eq + Hash,
+{
+    let data_dir = config_mx.read().unwrap().data_dir.clone();
+
+    let checked = quotas_mx.write().unwrap().check_add(ns, data_dir.as_ref().map(|dir| dir.as_path()), adding, || stored_values(dbmap_mx, key));
+    checked.map_err(|e| e.response())
+}
+
+fn quota_path(data_dir: Option<&Path>, ns: &str) -> Option<PathBuf> {
+    data_dir.map(|dir| dir.join(ns).join(QUOTA_FILE))
+}
+
+fn load_quota(path: &PathBuf) -> Option<Quota> {
+    let mut payload = String::new();
+    let mut f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+    if f.read_to_string(&mut payload).is_err() {
+        return None
+    }
+
+    match serde_json::from_str::<Quota>(&payload) {
+        Ok(quota) => Some(quota),
+        Err(e) => {
+            warn!("unreadable {:?}: {}", path, e);
+            None
+        },
+    }
+}
+
+fn save_quota(path: &PathBuf, quota: &Quota) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir).map_err(|e| format!("{}", e)));
+    }
+
+    let payload = try!(serde_json::to_string(quota).map_err(|e| format!("{}", e)));
+    let mut f = try!(fs::File::create(path).map_err(|e| format!("{}", e)));
+    f.write_all(payload.as_bytes()).map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use hammer::db::temp_dir::TempDir;
+
+    use http::{AddResult, DeleteResult};
+    use http::quotas::{Quota, Quotas};
+
+    #[test]
+    fn refuses_adds_over_max_values() {
+        let mut quotas: Quotas = Default::default();
+        quotas.set("b064_004_foo", None, Quota{max_values: Some(3), ..Default::default()}).unwrap();
+
+        assert!(quotas.check_add("b064_004_foo", None, 2, || 1).is_ok());
+        quotas.record_added("b064_004_foo", &[AddResult::Ok, AddResult::Exists]);
+
+        let e = quotas.check_add("b064_004_foo", None, 2, || unreachable!()).unwrap_err();
+        assert_eq!((e.quota, e.limit, e.usage), ("max_values", 3, 4));
+
+        quotas.record_removed("b064_004_foo", &[DeleteResult::Ok, DeleteResult::NotFound]);
+        assert!(quotas.check_add("b064_004_foo", None, 2, || unreachable!()).is_ok());
+
+        // Other namespaces aren't limited
+        assert!(quotas.check_add("b064_004_bar", None, 100, || 0).is_ok());
+    }
+
+    #[test]
+    fn limits_requests_per_second() {
+        let mut quotas: Quotas = Default::default();
+        quotas.set("b064_004_foo", None, Quota{max_qps: Some(2), ..Default::default()}).unwrap();
+        let start = Instant::now();
+
+        assert!(quotas.check_rate("b064_004_foo", None, start).is_ok());
+        assert!(quotas.check_rate("b064_004_foo", None, start).is_ok());
+        assert_eq!(quotas.check_rate("b064_004_foo", None, start).unwrap_err().quota, "max_qps");
+
+        assert!(quotas.check_rate("b064_004_foo", None, start + Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn limits_requests_to_the_lower_of_max_qps_and_rate_limit() {
+        let mut quotas: Quotas = Default::default();
+        quotas.set_rate_limit("b064_004_foo", None, 1);
+        let start = Instant::now();
+
+        assert!(quotas.check_rate("b064_004_foo", None, start).is_ok());
+        assert_eq!(quotas.check_rate("b064_004_foo", None, start).unwrap_err().quota, "rate_limit");
+
+        // Setting a higher max_qps keeps the tuned rate_limit
+        quotas.set("b064_004_foo", None, Quota{max_qps: Some(3), ..Default::default()}).unwrap();
+        assert!(quotas.check_rate("b064_004_foo", None, start).is_ok());
+        assert_eq!(quotas.check_rate("b064_004_foo", None, start).unwrap_err().quota, "rate_limit");
+
+        quotas.set_rate_limit("b064_004_foo", None, 0);
+        for _ in 0..3 {
+            assert!(quotas.check_rate("b064_004_foo", None, start).is_ok());
+        }
+        assert_eq!(quotas.check_rate("b064_004_foo", None, start).unwrap_err().quota, "max_qps");
+    }
+
+    #[test]
+    fn quotas_persist_with_the_namespace() {
+        let dir = TempDir::new();
+        let quota = Quota{max_values: Some(10), max_disk_bytes: Some(1 << 20), max_qps: None};
+
+        let mut quotas: Quotas = Default::default();
+        quotas.set("b064_004_foo", Some(dir.path()), quota).unwrap();
+
+        let mut reloaded: Quotas = Default::default();
+        assert_eq!(reloaded.quota("b064_004_foo", Some(dir.path())), quota);
+        assert_eq!(reloaded.quota("b064_004_bar", Some(dir.path())), Default::default());
+    }
+}
@@ -0,0 +1,187 @@
+//! Reloadable settings
+//!
+//! With `--config=<path>` the server reads a JSON file of settings which can
+//! change while it's running, overriding the corresponding flags:
+//!
+//! ```json
+//! {"rate_limit": 50, "max_request_keys": 1000, "lru": 100000}
+//! ```
+//!
+//! `shadows` maps namespaces (by directory name, ie `b064_004_images`) to the
+//! tolerance of a candidate shadowing them (see `db::shadow`), which the
+//! server reports in `/metrics`:
+//!
+//! ```json
+//! {"shadows": {"b064_004_images": 6}}
+//! ```
+//!
+//! `POST /config/reload` re-reads the file and replaces the server's config
+//! in one step, so each request (over HTTP or the binary protocol) sees
+//! either the old settings or the new ones, never a mix.  Settings removed
+//! from the file revert to their flags.  A file which can't be read or
+//! parsed is refused with `400 Bad Request`, leaving the config unchanged.
+//!
+//! Limits, `max_query_candidates` & `slow_query_ms` apply from the next
+//! request.  `lru`, `block_cache_bytes` & `shadows` apply to namespaces
+//! opened after the reload; namespaces already open keep the sizes they were opened with.
+//!
+//! Only the settings above reload.  The bind addresses & `data_dir` are
+//! fixed at startup: re-binding would mean handing the listeners over
+//! between servers, so changing them takes a restart.  The file is only
+//! re-read on `POST /config/reload` - it isn't watched, and `SIGHUP` isn't
+//! handled.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use iron::prelude::*;
+use iron::{status, typemap};
+use persistent::State;
+use serde_json;
+
+use hammer::db::query_log;
+
+use http::{Config, ConfigKey};
+
+/// Settings which can be changed without restarting - unset settings take
+/// the value of their flag
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub max_body_bytes: Option<usize>,
+    pub max_request_keys: Option<usize>,
+    pub rate_limit: Option<f64>,
+    pub max_query_candidates: Option<usize>,
+    pub lru: Option<usize>,
+    pub block_cache_bytes: Option<usize>,
+    pub slow_query_ms: Option<u64>,
+    pub shadows: Option<HashMap<String, usize>>,
+}
+
+impl Settings {
+    /// Reads settings from the file at `path`
+    ///
+    pub fn load(path: &Path) -> Result<Settings, String> {
+        let mut json = String::new();
+        try!(File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut json))
+            .map_err(|e| format!("unable to read {:?}: {}", path, e)));
+
+        let settings: Settings = try!(serde_json::from_str(&json).map_err(|e| format!("unable to parse {:?}: {}", path, e)));
+        if settings.rate_limit.map_or(false, |rate| !(rate > 0.0)) {
+            return Err("rate_limit must be positive".to_string())
+        }
+        Ok(settings)
+    }
+
+    /// The settings `config` is running with
+    ///
+    pub fn of(config: &Config) -> Settings {
+        Settings{
+            max_body_bytes: config.limits.max_body_bytes,
+            max_request_keys: config.limits.max_request_keys,
+            rate_limit: config.limits.rate_limit,
+            max_query_candidates: config.max_query_candidates,
+            lru: config.lru,
+            block_cache_bytes: config.rocksdb.block_cache_bytes,
+            slow_query_ms: config.slow_query_ms,
+            shadows: if config.shadows.is_empty() { None } else { Some(config.shadows.clone()) },
+        }
+    }
+
+    /// `config` with every setting that's set overriding it
+    ///
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if self.max_body_bytes.is_some() { config.limits.max_body_bytes = self.max_body_bytes }
+        if self.max_request_keys.is_some() { config.limits.max_request_keys = self.max_request_keys }
+        if self.rate_limit.is_some() { config.limits.rate_limit = self.rate_limit }
+        if self.max_query_candidates.is_some() { config.max_query_candidates = self.max_query_candidates }
+        if self.lru.is_some() { config.lru = self.lru }
+        if self.block_cache_bytes.is_some() { config.rocksdb.block_cache_bytes = self.block_cache_bytes }
+        if self.slow_query_ms.is_some() { config.slow_query_ms = self.slow_query_ms }
+        if let Some(ref shadows) = self.shadows { config.shadows = shadows.clone() }
+        config
+    }
+}
+
+/// The config file, and the config given by flags which it overrides
+///
+pub struct Reloader {
+    path: Option<PathBuf>,
+    flags: Config,
+}
+
+impl Reloader {
+    pub fn new(path: Option<PathBuf>, flags: Config) -> Reloader {
+        Reloader{path: path, flags: flags}
+    }
+
+    /// The flags' config, overridden by the config file if there is one
+    ///
+    pub fn load(&self) -> Result<Config, String> {
+        match self.path {
+            Some(ref path) => Settings::load(path).map(|settings| settings.apply(&self.flags)),
+            None => Ok(self.flags.clone()),
+        }
+    }
+}
+
+pub struct ReloaderKey;
+impl typemap::Key for ReloaderKey { type Value = Reloader; }
+
+/// Makes `config`'s settings which aren't read from it on each request take
+/// effect
+///
+pub fn activate(config: &Config) {
+    query_log::set_slow_query_threshold(config.slow_query_ms.map(Duration::from_millis));
+}
+
+/// Re-reads the config file, responding with the settings now in effect
+///
+pub fn reload(req: &mut Request) -> IronResult<Response> {
+    let reloader_mx = req.get::<State<ReloaderKey>>().unwrap();
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+
+    let reloader = reloader_mx.read().unwrap();
+    if reloader.path.is_none() {
+        return Ok(Response::with((status::NotFound, "No config file was given (see --config)")))
+    }
+
+    let config = match reloader.load() {
+        Ok(config) => config,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let settings = Settings::of(&config);
+
+    {
+        // Held while the new settings are activated, so a concurrent reload
+        // can't activate its settings in between
+        let mut current = config_mx.write().unwrap();
+        activate(&config);
+        *current = config;
+    }
+    info!("Reloaded config: {:?}", settings);
+
+    let response_body = serde_json::to_string(&settings).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
+
+#[cfg(test)]
+mod test {
+    use http::reload::Settings;
+
+    #[test]
+    fn unset_settings_keep_their_flags() {
+        let settings: Settings = ::serde_json::from_str(r#"{"rate_limit": 5, "lru": 10}"#).unwrap();
+        assert_eq!(settings, Settings{rate_limit: Some(5.0), lru: Some(10), ..Default::default()});
+        assert!(::serde_json::from_str::<Settings>(r#"{"bind": "localhost:80"}"#).is_err());
+
+        let settings: Settings = ::serde_json::from_str(r#"{"shadows": {"b064_004_images": 6}}"#).unwrap();
+        assert_eq!(settings.shadows.unwrap().get("b064_004_images"), Some(&6));
+    }
+}
@@ -1,38 +1,441 @@
 use std::clone::Clone;
 use std::collections::HashMap;
+use std::fs;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::{Arc, RwLock};
+use std::process;
+use std::thread;
+use std::time::Duration;
 
 use iron::prelude::*;
+use ctrlc;
 use router::Router;
 use persistent::State;
 
-use http::{Config, ConfigKey, B32, B64, B128, B256, V32, V64, V128, V256};
+use http::{Config, ConfigKey, COLUMN_FAMILIES_DIR, TuningKey, HealthKey, ManifestKey, reopen, B32, B64, B128, B256, V32, V64, V128, V256};
 use http::binary_handler;
 use http::vector_handler;
+use http::tuning_handler;
+use http::disk_handler;
+use http::health_handler::{self, Sentinel, SentinelKey};
+use http::namespace_handler;
+use http::snapshot_handler;
+use http::warmup_handler;
+use http::oplog::{self, Oplog, OplogKey, ReplayedKey};
+use http::trace::Tracing;
+use http::metrics::{self, Metrics};
+use http::manifest::Manifest;
+use http::shutdown::{self, Shutdown, Draining};
+use http::limits::Guard;
+use http::reload::{self, Reloader, ReloaderKey};
+use http::quotas::QuotasKey;
+use net::{self, Registry};
+#[cfg(feature = "grpc")]
+use grpc;
 
-pub fn serve(config: Config) {
-    println!("Serving with config: {:?}", config);
+use hammer::db::Database;
+use hammer::db::disk_usage::disk_usage;
+
+/// How often namespace disk usage is checked against the configured budget
+const DISK_CHECK_INTERVAL_SECS: u64 = 60;
+
+pub fn serve(mut config: Config) {
+    info!("Serving with config: {:?}", config);
 
     let mut router = Router::new();
     router.post("/add/b/:bits/:tolerance/:namespace", binary_handler::add);
     router.post("/query/b/:bits/:tolerance/:namespace", binary_handler::query);
     router.post("/delete/b/:bits/:tolerance/:namespace", binary_handler::delete);
+    router.post("/pairwise/b/:bits/:tolerance", binary_handler::pairwise);
+    router.post("/ingest/b/:bits/:tolerance/:namespace", binary_handler::ingest);
 
     router.post("/add/v/:bits/:dimensions/:tolerance/:namespace", vector_handler::add);
     router.post("/query/v/:bits/:dimensions/:tolerance/:namespace", vector_handler::query);
     router.post("/delete/v/:bits/:dimensions/:tolerance/:namespace", vector_handler::delete);
 
+    router.get("/db/b/:bits/:tolerance/:namespace/tuning", tuning_handler::get_binary);
+    router.put("/db/b/:bits/:tolerance/:namespace/tuning", tuning_handler::put_binary);
+    router.get("/db/v/:bits/:dimensions/:tolerance/:namespace/tuning", tuning_handler::get_vector);
+    router.put("/db/v/:bits/:dimensions/:tolerance/:namespace/tuning", tuning_handler::put_vector);
+
+    router.post("/db/b/:bits/:tolerance/:namespace/delete_verified", binary_handler::delete_verified);
+    router.post("/db/v/:bits/:dimensions/:tolerance/:namespace/delete_verified", vector_handler::delete_verified);
+
+    router.get("/db/b/:bits/:tolerance/:namespace/disk_usage", disk_handler::get_binary);
+    router.get("/db/v/:bits/:dimensions/:tolerance/:namespace/disk_usage", disk_handler::get_vector);
+
+    router.get("/db/b/:bits/:tolerance/:namespace/export", snapshot_handler::export_binary);
+    router.post("/db/b/:bits/:tolerance/:namespace/import", snapshot_handler::import_binary);
+    router.get("/db/v/:bits/:dimensions/:tolerance/:namespace/export", snapshot_handler::export_vector);
+    router.post("/db/v/:bits/:dimensions/:tolerance/:namespace/import", snapshot_handler::import_vector);
+
+    router.post("/db/b/:bits/:tolerance/:namespace/warmup", warmup_handler::warmup_binary);
+    router.post("/db/v/:bits/:dimensions/:tolerance/:namespace/warmup", warmup_handler::warmup_vector);
+
+    router.get("/dbs", namespace_handler::list);
+    router.put("/db/b/:bits/:tolerance/:namespace", namespace_handler::create_binary);
+    router.delete("/db/b/:bits/:tolerance/:namespace", namespace_handler::drop_binary);
+    router.put("/db/v/:bits/:dimensions/:tolerance/:namespace", namespace_handler::create_vector);
+    router.delete("/db/v/:bits/:dimensions/:tolerance/:namespace", namespace_handler::drop_vector);
+
+    router.get("/oplog", oplog::tail);
+    router.get("/oplog/replayed", oplog::replayed);
+    router.post("/oplog/replay", oplog::replay);
+
+    router.get("/healthz", health_handler::healthz);
+    router.get("/readyz", health_handler::readyz);
+    router.get("/metrics", metrics::metrics);
+    router.post("/config/reload", reload::reload);
+
+    watch_disk_usage(&config);
+
+    // Re-open every namespace recorded in the manifest so they can be
+    // queried before they're next written to
+    let manifest = load_manifest(&config);
+    if !check_last_shutdown(&config, &manifest) && !config.repair_on_open {
+        warn!("the last shutdown wasn't clean; repairing namespaces as they're re-opened");
+        config.repair_on_open = true;
+    }
+
+    // Settings in the config file override their flags from the start
+    let reloader = Reloader::new(config.config_file.clone(), config.clone());
+    let config = match reloader.load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            ::std::process::exit(1)
+        },
+    };
+    reload::activate(&config);
+
+    let health_mx = RwLock::new(HashMap::new());
+    let (mut b32, mut b64, mut b128, mut b256) = (HashMap::new(), HashMap::new(), HashMap::new(), HashMap::new());
+    let (mut v32, mut v64, mut v128, mut v256) = (HashMap::new(), HashMap::new(), HashMap::new(), HashMap::new());
+
+    for entry in manifest.entries() {
+        let binary_key = (entry.tolerance, entry.namespace.clone());
+        let vector_key = (entry.dimensions.unwrap_or(0), entry.tolerance, entry.namespace.clone());
+
+        let reopened = match (entry.bits, entry.dimensions) {
+            (32, None) => reopen::<u32>(entry, &config, &health_mx).map(|db| { b32.insert(binary_key, db); }),
+            (64, None) => reopen::<u64>(entry, &config, &health_mx).map(|db| { b64.insert(binary_key, db); }),
+            (128, None) => reopen::<[u64; 2]>(entry, &config, &health_mx).map(|db| { b128.insert(binary_key, db); }),
+            (256, None) => reopen::<[u64; 4]>(entry, &config, &health_mx).map(|db| { b256.insert(binary_key, db); }),
+            (32, Some(_)) => reopen::<Vec<u32>>(entry, &config, &health_mx).map(|db| { v32.insert(vector_key, db); }),
+            (64, Some(_)) => reopen::<Vec<u64>>(entry, &config, &health_mx).map(|db| { v64.insert(vector_key, db); }),
+            (128, Some(_)) => reopen::<Vec<[u64; 2]>>(entry, &config, &health_mx).map(|db| { v128.insert(vector_key, db); }),
+            (256, Some(_)) => reopen::<Vec<[u64; 4]>>(entry, &config, &health_mx).map(|db| { v256.insert(vector_key, db); }),
+            (bits, _) => Err(format!("unsupported bitsize {}", bits)),
+        };
+
+        match reopened {
+            Ok(()) => info!("Re-opened namespace {}", entry.dir_name()),
+            Err(e) => warn!("unable to re-open namespace {}: {}", entry.dir_name(), e),
+        }
+    }
+
+    // Shared with the binary protocol server, along with the namespace maps,
+    // and replaced as a whole when the config file is reloaded
+    let config_mx = Arc::new(RwLock::new(config.clone()));
+
     let mut chain = Chain::new(router);
-    chain.link_before(State::<ConfigKey>::one(config.clone()));
+    let tracing = Arc::new(Tracing::new(config.otlp_endpoint.clone()));
+    chain.link_before(tracing.clone());
+    chain.link_after(tracing);
+    let metrics = Arc::new(Metrics::new());
+    chain.link_before(metrics.clone());
+    chain.link_after(metrics);
+    let shutdown = Arc::new(Shutdown::new());
+    chain.link_before(Draining(shutdown.clone()));
+    chain.link_before(Guard::new(config_mx.clone()));
+
+    let health_mx = Arc::new(health_mx);
+    let manifest_mx = Arc::new(RwLock::new(manifest));
+    let oplog_mx = Arc::new(RwLock::new(open_oplog(&config)));
+
+    chain.link_before(State::<ConfigKey>::one(config_mx.clone()));
+    chain.link_before(State::<ReloaderKey>::one(reloader));
+    chain.link_before(State::<TuningKey>::one(HashMap::new()));
+    chain.link_before(State::<QuotasKey>::one(Default::default()));
+    chain.link_before(State::<HealthKey>::one(health_mx.clone()));
+    chain.link_before(State::<SentinelKey>::one(Sentinel::open(&config)));
+    chain.link_before(State::<ManifestKey>::one(manifest_mx.clone()));
+    chain.link_before(State::<OplogKey>::one(oplog_mx.clone()));
+    chain.link_before(State::<ReplayedKey>::one(load_replayed(&config)));
+
+    // The namespace maps are shared with the compaction thread
+    let (b32, b64, b128, b256) = (Arc::new(RwLock::new(b32)), Arc::new(RwLock::new(b64)), Arc::new(RwLock::new(b128)), Arc::new(RwLock::new(b256)));
+    let (v32, v64, v128, v256) = (Arc::new(RwLock::new(v32)), Arc::new(RwLock::new(v64)), Arc::new(RwLock::new(v128)), Arc::new(RwLock::new(v256)));
+
+    if let Some(interval) = config.compact_interval {
+        let (b32, b64, b128, b256) = (b32.clone(), b64.clone(), b128.clone(), b256.clone());
+        let (v32, v64, v128, v256) = (v32.clone(), v64.clone(), v128.clone(), v256.clone());
+
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(interval));
+
+                compact_all(&b32);
+                compact_all(&b64);
+                compact_all(&b128);
+                compact_all(&b256);
+                compact_all(&v32);
+                compact_all(&v64);
+                compact_all(&v128);
+                compact_all(&v256);
+            }
+        });
+    }
+
+    let registry = Registry{
+        config: config_mx,
+        health: health_mx,
+        manifest: manifest_mx,
+        oplog: oplog_mx.clone(),
+        shutdown: shutdown.clone(),
+        b32: b32.clone(), b64: b64.clone(), b128: b128.clone(), b256: b256.clone(),
+        v32: v32.clone(), v64: v64.clone(), v128: v128.clone(), v256: v256.clone(),
+    };
+    serve_binary_protocol(&config, registry.clone());
+    // Serves until dropped, once the HTTP server stops
+    let _grpc = serve_grpc(&config, registry);
+
+    {
+        let data_dir = config.data_dir.clone();
+        let (b32, b64, b128, b256) = (b32.clone(), b64.clone(), b128.clone(), b256.clone());
+        let (v32, v64, v128, v256) = (v32.clone(), v64.clone(), v128.clone(), v256.clone());
+
+        let handled = ctrlc::set_handler(move || {
+            info!("Shutting down");
+            let in_progress = shutdown.drain(Duration::from_secs(shutdown::DRAIN_TIMEOUT_SECS));
+            if in_progress > 0 {
+                warn!("{} requests still in progress after {}s", in_progress, shutdown::DRAIN_TIMEOUT_SECS);
+            }
+
+            // Every flush is attempted, even once one has failed
+            let flushed = vec![
+                flush_all(&b32), flush_all(&b64), flush_all(&b128), flush_all(&b256),
+                flush_all(&v32), flush_all(&v64), flush_all(&v128), flush_all(&v256),
+                flush_oplog(&oplog_mx),
+            ];
+
+            if let Some(ref dir) = data_dir {
+                if in_progress == 0 && flushed.iter().all(|&ok| ok) {
+                    if let Err(e) = shutdown::write_marker(dir) {
+                        warn!("unable to record clean shutdown: {}", e);
+                    }
+                }
+            }
+            process::exit(0)
+        });
+        if let Err(e) = handled {
+            warn!("unable to handle shutdown signals: {}", e);
+        }
+    }
 
-    chain.link_before(State::<B256>::one(HashMap::new()));
-    chain.link_before(State::<B128>::one(HashMap::new()));
-    chain.link_before(State::<B64>::one(HashMap::new()));
-    chain.link_before(State::<B32>::one(HashMap::new()));
+    chain.link_before(State::<B256>::one(b256));
+    chain.link_before(State::<B128>::one(b128));
+    chain.link_before(State::<B64>::one(b64));
+    chain.link_before(State::<B32>::one(b32));
 
-    chain.link_before(State::<V256>::one(HashMap::new()));
-    chain.link_before(State::<V128>::one(HashMap::new()));
-    chain.link_before(State::<V64>::one(HashMap::new()));
-    chain.link_before(State::<V32>::one(HashMap::new()));
+    chain.link_before(State::<V256>::one(v256));
+    chain.link_before(State::<V128>::one(v128));
+    chain.link_before(State::<V64>::one(v64));
+    chain.link_before(State::<V32>::one(v32));
 
     Iron::new(chain).http(&*config.bind).unwrap();
 }
+
+/// Starts the binary protocol server on `--binary-bind` and
+/// `--binary-socket`, if set
+///
+/// The server won't start if either can't be bound, since clients expect to
+/// find it there.
+///
+fn serve_binary_protocol(config: &Config, registry: Registry) {
+    let mut served = Ok(());
+    if let Some(ref bind) = config.binary_bind {
+        served = served.and_then(|_| net::serve_tcp(bind, registry.clone()));
+    }
+    if let Some(ref path) = config.binary_socket {
+        served = served.and_then(|_| net::serve_unix(path, registry.clone()));
+    }
+
+    if let Err(e) = served {
+        error!("{}", e);
+        ::std::process::exit(1)
+    }
+}
+
+/// Starts the gRPC server on `--grpc-bind`, if set
+///
+/// Like the binary protocol server, it won't start if the address can't be
+/// bound.
+///
+#[cfg(feature = "grpc")]
+fn serve_grpc(config: &Config, registry: Registry) -> Option<::grpcio::Server> {
+    config.grpc_bind.as_ref().map(|bind| {
+        match grpc::serve(bind, config.grpc_threads, registry) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("{}", e);
+                ::std::process::exit(1)
+            },
+        }
+    })
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(config: &Config, _registry: Registry) -> Option<()> {
+    if config.grpc_bind.is_some() {
+        error!("hammer was built without the `grpc` feature");
+        ::std::process::exit(1)
+    }
+    None
+}
+
+/// Takes the marker left by the last shutdown, returning false if the data
+/// dir has namespaces but the last shutdown wasn't clean
+///
+fn check_last_shutdown(config: &Config, manifest: &Manifest) -> bool {
+    let dir = match config.data_dir {
+        Some(ref dir) => dir,
+        None => return true,
+    };
+
+    match shutdown::take_marker(dir) {
+        Ok(clean) => clean || manifest.entries().is_empty(),
+        Err(e) => {
+            warn!("{}", e);
+            true
+        },
+    }
+}
+
+/// Reads the manifest in the data dir, if any
+///
+/// An unreadable manifest is logged and replaced as namespaces are written
+/// to, rather than keeping the server from starting
+///
+fn load_manifest(config: &Config) -> Manifest {
+    match config.data_dir {
+        Some(ref dir) => Manifest::load(dir).unwrap_or_else(|e| {
+            warn!("{}; namespaces will be re-opened as they're written to", e);
+            Default::default()
+        }),
+        None => Default::default(),
+    }
+}
+
+/// Opens the oplog in the data dir if `--oplog` is set
+///
+/// The server won't start with an unreadable oplog, since writes it accepted
+/// would be missing from the log.
+///
+fn open_oplog(config: &Config) -> Option<Oplog> {
+    match config.data_dir {
+        Some(ref dir) if config.oplog => match Oplog::open(dir, config.sync_writes) {
+            Ok(oplog) => {
+                info!("Logging writes to oplog (last sequence {})", oplog.last_sequence());
+                Some(oplog)
+            },
+            Err(e) => {
+                error!("unable to open oplog: {}", e);
+                ::std::process::exit(1)
+            },
+        },
+        _ => None,
+    }
+}
+
+/// Reads the sequence last replayed from another server's oplog, if any
+///
+fn load_replayed(config: &Config) -> u64 {
+    match config.data_dir {
+        Some(ref dir) => oplog::load_replayed(dir).unwrap_or_else(|e| {
+            warn!("{}; replays will start from the beginning", e);
+            0
+        }),
+        None => 0,
+    }
+}
+
+/// Compacts every namespace in `dbmap_mx` (see `hammer::db::compaction`)
+///
+/// Each namespace is write-locked while it's compacted, so requests to it wait
+/// until it's done; the map itself is only locked long enough to list them.
+///
+fn compact_all<K, T>(dbmap_mx: &RwLock<HashMap<K, Arc<RwLock<Box<Database<T>>>>>>) where
+K: Clone + Eq + Hash + Debug,
+{
+    let namespaces: Vec<(K, Arc<RwLock<Box<Database<T>>>>)> = dbmap_mx.read().unwrap()
+        .iter()
+        .map(|(k, db_mx)| (k.clone(), db_mx.clone()))
+        .collect();
+
+    for (key, db_mx) in namespaces.into_iter() {
+        if let Err(e) = db_mx.write().unwrap().compact() {
+            warn!("unable to compact namespace {:?}: {}", key, e);
+        }
+    }
+}
+
+/// Flushes every namespace in `dbmap_mx`, returning false if any failed
+///
+/// Each namespace is write-locked while it's flushed, so writes still in
+/// progress finish first.
+///
+fn flush_all<K, T>(dbmap_mx: &RwLock<HashMap<K, Arc<RwLock<Box<Database<T>>>>>>) -> bool where
+K: Clone + Eq + Hash + Debug,
+{
+    let namespaces: Vec<(K, Arc<RwLock<Box<Database<T>>>>)> = dbmap_mx.read().unwrap()
+        .iter()
+        .map(|(k, db_mx)| (k.clone(), db_mx.clone()))
+        .collect();
+
+    let mut flushed = true;
+    for (key, db_mx) in namespaces.into_iter() {
+        if let Err(e) = db_mx.write().unwrap().flush() {
+            warn!("unable to flush namespace {:?}: {}", key, e);
+            flushed = false;
+        }
+    }
+    flushed
+}
+
+fn flush_oplog(oplog_mx: &RwLock<Option<Oplog>>) -> bool {
+    match *oplog_mx.read().unwrap() {
+        Some(ref oplog) => oplog.sync().map_err(|e| warn!("unable to sync oplog: {}", e)).is_ok(),
+        None => true,
+    }
+}
+
+/// Periodically warns about namespaces exceeding the configured disk budget
+///
+fn watch_disk_usage(config: &Config) {
+    let data_dir = match config.data_dir {
+        Some(ref dir) => dir.clone(),
+        None => return,
+    };
+    let budget = config.disk_budget.clone();
+
+    thread::spawn(move || {
+        loop {
+            if let Ok(entries) = fs::read_dir(&data_dir) {
+                for entry in entries.filter_map(|e| e.ok()) {
+                    let path = entry.path();
+                    if !path.is_dir() || entry.file_name() == COLUMN_FAMILIES_DIR { continue }
+
+                    if let Ok(usage) = disk_usage(&path) {
+                        for violation in budget.violations(&usage) {
+                            warn!("namespace {:?} over disk budget: {}", entry.file_name(), violation);
+                        }
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_secs(DISK_CHECK_INTERVAL_SECS));
+        }
+    });
+}
@@ -0,0 +1,178 @@
+//! Graceful shutdown
+//!
+//! On SIGINT or SIGTERM the server stops serving requests (those arriving
+//! are answered 503, over HTTP and the binary protocol), waits up to
+//! `DRAIN_TIMEOUT_SECS` for requests in progress to finish, then flushes every
+//! namespace and the oplog before exiting.  If everything was drained and
+//! flushed, a `CLEAN_SHUTDOWN` marker is written to the data dir.
+//!
+//! The marker is removed when the server next starts.  A data dir with
+//! namespaces but no marker was left by a server which was killed or crashed,
+//! possibly partway through a delete, so re-opened namespaces are repaired
+//! as with `--repair-on-open`.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use iron::prelude::*;
+use iron::{status, typemap, BeforeMiddleware};
+
+/// Name of the marker file (under `data_dir`) written by a clean shutdown
+pub const MARKER_FILE: &'static str = "CLEAN_SHUTDOWN";
+
+/// Longest a shutdown waits for requests in progress
+pub const DRAIN_TIMEOUT_SECS: u64 = 30;
+
+/// Whether the server is shutting down, and how many requests it's serving
+///
+pub struct Shutdown {
+    stopping: AtomicBool,
+    in_progress: AtomicUsize,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown{stopping: AtomicBool::new(false), in_progress: AtomicUsize::new(0)}
+    }
+
+    /// Counts a request as in progress until the returned guard is dropped,
+    /// or returns `None` if the server is shutting down
+    ///
+    pub fn begin(shutdown: &Arc<Shutdown>) -> Option<InProgress> {
+        // Counted before checking, so `drain` can't miss a request which
+        // got in before it started waiting
+        shutdown.in_progress.fetch_add(1, Ordering::SeqCst);
+        let guard = InProgress(shutdown.clone());
+
+        if shutdown.stopping.load(Ordering::SeqCst) {
+            return None
+        }
+        Some(guard)
+    }
+
+    /// Stops new requests, then waits up to `timeout` for those in progress
+    /// to finish, returning how many are still in progress
+    ///
+    pub fn drain(&self, timeout: Duration) -> usize {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        let start = Instant::now();
+        loop {
+            let in_progress = self.in_progress.load(Ordering::SeqCst);
+            if in_progress == 0 || start.elapsed() >= timeout {
+                return in_progress
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// A request in progress (see `Shutdown::begin`)
+///
+pub struct InProgress(Arc<Shutdown>);
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.0.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct InProgressKey;
+impl typemap::Key for InProgressKey { type Value = InProgress; }
+
+/// Middleware refusing requests once the server is shutting down
+///
+/// Requests are counted as in progress until they're dropped, which is after
+/// their response has been written.
+///
+pub struct Draining(pub Arc<Shutdown>);
+
+impl BeforeMiddleware for Draining {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match Shutdown::begin(&self.0) {
+            Some(guard) => {
+                req.extensions.insert::<InProgressKey>(guard);
+                Ok(())
+            },
+            None => {
+                let e = io::Error::new(io::ErrorKind::Other, "server is shutting down");
+                Err(IronError::new(e, (status::ServiceUnavailable, "Server is shutting down")))
+            },
+        }
+    }
+}
+
+/// Removes the marker left in `data_dir` by the last shutdown, returning
+/// whether it was clean
+///
+pub fn take_marker(data_dir: &Path) -> Result<bool, String> {
+    let path = data_dir.join(MARKER_FILE);
+    if !path.exists() {
+        return Ok(false)
+    }
+
+    try!(fs::remove_file(&path).map_err(|e| format!("unable to remove {:?}: {}", path, e)));
+    Ok(true)
+}
+
+/// Records a clean shutdown in `data_dir`
+///
+pub fn write_marker(data_dir: &Path) -> Result<(), String> {
+    let path = data_dir.join(MARKER_FILE);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    let mut f = try!(File::create(&path).map_err(|e| format!("unable to create {:?}: {}", path, e)));
+    try!(writeln!(f, "{}", now).map_err(|e| format!("{}", e)));
+    f.sync_all().map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use hammer::db::temp_dir::TempDir;
+
+    use http::shutdown::*;
+
+    #[test]
+    fn drain_waits_for_requests_in_progress() {
+        let shutdown = Arc::new(Shutdown::new());
+        let guard = Shutdown::begin(&shutdown).unwrap();
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+
+        assert_eq!(shutdown.drain(Duration::from_secs(5)), 0);
+        assert!(Shutdown::begin(&shutdown).is_none());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn drain_gives_up_after_timeout() {
+        let shutdown = Arc::new(Shutdown::new());
+        let _guard = Shutdown::begin(&shutdown).unwrap();
+
+        assert_eq!(shutdown.drain(Duration::from_millis(20)), 1);
+    }
+
+    #[test]
+    fn marker_is_taken_once() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+
+        assert_eq!(take_marker(dir.path()), Ok(false));
+        write_marker(dir.path()).unwrap();
+        assert_eq!(take_marker(dir.path()), Ok(true));
+        assert_eq!(take_marker(dir.path()), Ok(false));
+    }
+}
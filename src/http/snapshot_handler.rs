@@ -0,0 +1,218 @@
+//! Per-namespace snapshots (see `hammer::db::snapshot`)
+//!
+//! `GET /db/b/:bits/:tolerance/:namespace/export` responds with a snapshot of
+//! every value in the namespace, and `POST .../import` inserts every value in
+//! the snapshot posted as the body, opening the namespace if needed.  Vector
+//! namespaces have the same endpoints under `/db/v/...`.
+//!
+//! The namespace is locked for the whole export or import.  Imported values
+//! aren't recorded in the oplog, so a replica needs the snapshot imported too.
+
+use std::io::Read;
+use std::hash::Hash;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use iron::prelude::*;
+use iron::status;
+use persistent::State;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use hammer::db::{Database, Factory};
+use hammer::db::health::HealthReport;
+use hammer::db::snapshot;
+use hammer::Error;
+
+use http::{Config, ConfigKey, HealthKey, ManifestKey, binary_namespace, vector_namespace, degraded, sync_writes, error_response, B32, B64, B128, B256, V32, V64, V128, V256};
+use http::manifest::Manifest;
+use http::binary_handler;
+use http::vector_handler;
+use http::namespace_handler::{binary_params, vector_params};
+
+#[derive(Serialize)]
+struct Imported {
+    imported: usize,
+}
+
+pub fn export_binary(req: &mut Request) -> IronResult<Response> {
+    let (bits, tolerance, namespace) = match binary_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let key = (tolerance, namespace);
+
+    let response = match bits {
+        32 => export(req.get::<State<B32>>().unwrap().read().unwrap().get(&key)),
+        64 => export(req.get::<State<B64>>().unwrap().read().unwrap().get(&key)),
+        128 => export(req.get::<State<B128>>().unwrap().read().unwrap().get(&key)),
+        256 => export(req.get::<State<B256>>().unwrap().read().unwrap().get(&key)),
+        _ => Response::with((status::BadRequest, "Unsuported bitsize")),
+    };
+    Ok(response)
+}
+
+pub fn export_vector(req: &mut Request) -> IronResult<Response> {
+    let (bits, dimensions, tolerance, namespace) = match vector_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let key = (dimensions, tolerance, namespace);
+
+    let response = match bits {
+        32 => export(req.get::<State<V32>>().unwrap().read().unwrap().get(&key)),
+        64 => export(req.get::<State<V64>>().unwrap().read().unwrap().get(&key)),
+        128 => export(req.get::<State<V128>>().unwrap().read().unwrap().get(&key)),
+        256 => export(req.get::<State<V256>>().unwrap().read().unwrap().get(&key)),
+        _ => Response::with((status::BadRequest, "Unsuported bitsize")),
+    };
+    Ok(response)
+}
+
+fn export<T: Serialize>(db_mx: Option<&Arc<RwLock<Box<Database<T>>>>>) -> Response {
+    let db_mx = match db_mx {
+        Some(db_mx) => db_mx,
+        None => return Response::with((status::NotFound, "No such namespace")),
+    };
+
+    let mut body = Vec::new();
+    let exported = db_mx.read().unwrap().export(&mut body);
+
+    match exported {
+        Ok(_) => Response::with((status::Ok, body)),
+        Err(e) => error_response(e),
+    }
+}
+
+pub fn import_binary(req: &mut Request) -> IronResult<Response> {
+    let (bits, tolerance, namespace) = match binary_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+
+    match bits {
+        32 => {
+            let dbmap_mx = req.get::<State<B32>>().unwrap();
+            Ok(do_import_binary(&mut req.body, bits, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        64 => {
+            let dbmap_mx = req.get::<State<B64>>().unwrap();
+            Ok(do_import_binary(&mut req.body, bits, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        128 => {
+            let dbmap_mx = req.get::<State<B128>>().unwrap();
+            Ok(do_import_binary(&mut req.body, bits, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        256 => {
+            let dbmap_mx = req.get::<State<B256>>().unwrap();
+            Ok(do_import_binary(&mut req.body, bits, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    }
+}
+
+fn do_import_binary<T>(body: &mut Read, bits: usize, tolerance: usize, namespace: &str, config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, dbmap_mx: &RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>) -> Response where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + DeserializeOwned,
+{
+    let key = (tolerance, namespace.to_string());
+    if !dbmap_mx.read().unwrap().contains_key(&key) {
+        if let Err(response) = binary_handler::open(bits, tolerance, namespace, &[], config_mx, health_mx, manifest_mx, dbmap_mx) {
+            return response
+        }
+    }
+
+    let ns = binary_namespace(bits, tolerance, namespace);
+    if let Some(report) = health_mx.read().unwrap().get(&ns) {
+        return degraded(&ns, report)
+    }
+
+    let sync = config_mx.read().unwrap().sync_writes;
+    let dbmap = dbmap_mx.read().unwrap();
+    let mut db = dbmap.get(&key).unwrap().write().unwrap();
+
+    let imported = db.import(body);
+    finish_import(imported, sync, &**db)
+}
+
+pub fn import_vector(req: &mut Request) -> IronResult<Response> {
+    let (bits, dimensions, tolerance, namespace) = match vector_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+
+    match bits {
+        32 => {
+            let dbmap_mx = req.get::<State<V32>>().unwrap();
+            Ok(do_import_vector(&mut req.body, bits, dimensions, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        64 => {
+            let dbmap_mx = req.get::<State<V64>>().unwrap();
+            Ok(do_import_vector(&mut req.body, bits, dimensions, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        128 => {
+            let dbmap_mx = req.get::<State<V128>>().unwrap();
+            Ok(do_import_vector(&mut req.body, bits, dimensions, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        256 => {
+            let dbmap_mx = req.get::<State<V256>>().unwrap();
+            Ok(do_import_vector(&mut req.body, bits, dimensions, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &dbmap_mx))
+        },
+        _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    }
+}
+
+/// Vectors of the wrong length are rejected, as they are by `add`
+///
+fn do_import_vector<T>(body: &mut Read, bits: usize, dimensions: usize, tolerance: usize, namespace: &str, config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, dbmap_mx: &RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>) -> Response where
+T: 'static + DeserializeOwned,
+Vec<T>: Sync + Send + Clone + Eq + Hash + Factory,
+{
+    let key = (dimensions, tolerance, namespace.to_string());
+    if !dbmap_mx.read().unwrap().contains_key(&key) {
+        if let Err(response) = vector_handler::open(bits, dimensions, tolerance, namespace, &[], config_mx, health_mx, manifest_mx, dbmap_mx) {
+            return response
+        }
+    }
+
+    let ns = vector_namespace(bits, dimensions, tolerance, namespace);
+    if let Some(report) = health_mx.read().unwrap().get(&ns) {
+        return degraded(&ns, report)
+    }
+
+    let sync = config_mx.read().unwrap().sync_writes;
+    let dbmap = dbmap_mx.read().unwrap();
+    let mut db = dbmap.get(&key).unwrap().write().unwrap();
+
+    let imported = snapshot::import_with(&mut **db, body, |vector: &Vec<T>| {
+        match vector.len() == dimensions {
+            true => Ok(()),
+            false => Err(Error::Invalid(format!("expected vector length to be {}, not {}", dimensions, vector.len()))),
+        }
+    });
+    finish_import(imported, sync, &**db)
+}
+
+/// Values imported before a failure are kept, as with a failed `add`
+///
+fn finish_import<T>(imported: Result<usize, Error>, sync: bool, db: &Database<T>) -> Response {
+    let imported = match imported {
+        Ok(imported) => imported,
+        Err(e) => return error_response(e),
+    };
+
+    if let Err(response) = sync_writes(sync, db) {
+        return response
+    }
+
+    let response_body = serde_json::to_string(&Imported{imported: imported}).unwrap();
+    Response::with((status::Ok, response_body))
+}
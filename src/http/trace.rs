@@ -0,0 +1,343 @@
+//! Request tracing
+//!
+//! Each request is recorded as a span.  If the caller sent a W3C
+//! `traceparent` header the span joins the caller's trace, otherwise a new
+//! trace is started; either way the span's own context is returned in the
+//! response's `traceparent` header so callers can find it.
+//!
+//! Finished spans are exported over OTLP/HTTP (JSON encoding) when built with
+//! `--features otlp` and `--otlp-endpoint` is set, and dropped otherwise.
+
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::BTreeMap;
+
+use iron::prelude::*;
+use iron::{typemap, BeforeMiddleware, AfterMiddleware};
+use rand;
+use router::Router;
+#[cfg(feature = "otlp")]
+use serde_json::Value;
+
+const TRACEPARENT: &'static str = "traceparent";
+
+/// Position of a span within a distributed trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: (u64, u64),
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Context for the root span of a new trace
+    ///
+    pub fn new_root() -> TraceContext {
+        TraceContext {
+            trace_id: (rand::random(), rand::random()),
+            span_id: rand::random(),
+            sampled: true,
+        }
+    }
+
+    /// Context for a new span whose parent is `self`
+    ///
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id: rand::random(),
+            sampled: self.sampled,
+        }
+    }
+
+    /// Parses a version 00 `traceparent` header value
+    ///
+    pub fn parse(header: &str) -> Option<TraceContext> {
+        let fields: Vec<&str> = header.trim().split('-').collect();
+        if fields.len() != 4 || fields[0] != "00" {
+            return None
+        }
+        if fields[1].len() != 32 || fields[2].len() != 16 || fields[3].len() != 2 {
+            return None
+        }
+
+        let trace_hi = u64::from_str_radix(&fields[1][..16], 16);
+        let trace_lo = u64::from_str_radix(&fields[1][16..], 16);
+        let span_id = u64::from_str_radix(fields[2], 16);
+        let flags = u8::from_str_radix(fields[3], 16);
+
+        match (trace_hi, trace_lo, span_id, flags) {
+            // All-zero IDs are invalid
+            (Ok(0), Ok(0), _, _) | (_, _, Ok(0), _) => None,
+            (Ok(hi), Ok(lo), Ok(span_id), Ok(flags)) => {
+                Some(TraceContext{trace_id: (hi, lo), span_id: span_id, sampled: flags & 1 == 1})
+            },
+            _ => None,
+        }
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        format!("{:016x}{:016x}", self.trace_id.0, self.trace_id.1)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id_hex(), self.span_id_hex(), if self.sampled { 1 } else { 0 })
+    }
+}
+
+/// A finished unit of work
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub name: String,
+    pub context: TraceContext,
+    pub parent_span_id: Option<u64>,
+    /// Nanoseconds since the unix epoch
+    pub start: u64,
+    pub end: u64,
+    pub attributes: BTreeMap<String, String>,
+}
+
+#[cfg(feature = "otlp")]
+impl Span {
+    /// OTLP JSON encoding of the span
+    fn to_json(&self) -> Value {
+        let attributes: Vec<Value> = self.attributes.iter().map(|(k, v)| {
+            json!({"key": k, "value": {"stringValue": v}})
+        }).collect();
+
+        let mut span = json!({
+            "traceId": self.context.trace_id_hex(),
+            "spanId": self.context.span_id_hex(),
+            "name": self.name,
+            // SPAN_KIND_SERVER
+            "kind": 2,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": self.end.to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+
+        span
+    }
+}
+
+fn now_nanos() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1000000000 + now.subsec_nanos() as u64
+}
+
+struct InFlight {
+    context: TraceContext,
+    parent_span_id: Option<u64>,
+    start: u64,
+}
+
+struct InFlightKey;
+impl typemap::Key for InFlightKey { type Value = InFlight; }
+
+/// Middleware recording a span for each request
+///
+pub struct Tracing {
+    exporter: Option<Mutex<Sender<Span>>>,
+}
+
+impl Tracing {
+    /// Spans are exported to `otlp_endpoint` if set, and dropped otherwise
+    ///
+    pub fn new(otlp_endpoint: Option<String>) -> Tracing {
+        if otlp_endpoint.is_some() && !cfg!(feature = "otlp") {
+            warn!("hammer was built without the `otlp` feature, spans will not be exported");
+            return Tracing{exporter: None}
+        }
+
+        Tracing {
+            exporter: otlp_endpoint.map(|endpoint| Mutex::new(spawn_exporter(endpoint))),
+        }
+    }
+
+    fn finish(&self, req: &mut Request, res: &mut Response) {
+        let in_flight = match req.extensions.remove::<InFlightKey>() {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
+
+        res.headers.set_raw(TRACEPARENT, vec![in_flight.context.to_traceparent().into_bytes()]);
+
+        let exporter = match self.exporter {
+            Some(ref exporter) => exporter,
+            None => return,
+        };
+        if !in_flight.context.sampled {
+            return
+        }
+
+        let mut attributes = BTreeMap::new();
+        attributes.insert("http.method".to_string(), format!("{}", req.method));
+        attributes.insert("http.target".to_string(), format!("/{}", req.url.path.join("/")));
+        if let Some(status) = res.status {
+            attributes.insert("http.status_code".to_string(), format!("{}", status.to_u16()));
+        }
+        if let Some(params) = req.extensions.get::<Router>() {
+            for name in &["bits", "dimensions", "tolerance", "namespace"] {
+                if let Some(v) = params.find(name) {
+                    attributes.insert(format!("hammer.{}", name), v.to_string());
+                }
+            }
+        }
+
+        let span = Span {
+            name: format!("{} {}", req.method, req.url.path.first().map_or("", |p| p.as_str())),
+            context: in_flight.context,
+            parent_span_id: in_flight.parent_span_id,
+            start: in_flight.start,
+            end: now_nanos(),
+            attributes: attributes,
+        };
+
+        let _ = exporter.lock().unwrap().send(span);
+    }
+}
+
+impl BeforeMiddleware for Tracing {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let parent = req.headers.get_raw(TRACEPARENT)
+            .and_then(|values| values.first())
+            .and_then(|value| String::from_utf8(value.clone()).ok())
+            .and_then(|value| TraceContext::parse(&value));
+
+        let in_flight = match parent {
+            Some(parent) => InFlight{context: parent.child(), parent_span_id: Some(parent.span_id), start: now_nanos()},
+            None => InFlight{context: TraceContext::new_root(), parent_span_id: None, start: now_nanos()},
+        };
+
+        req.extensions.insert::<InFlightKey>(in_flight);
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Tracing {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.finish(req, &mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.finish(req, &mut err.response);
+        Err(err)
+    }
+}
+
+/// Maximum number of spans sent in a single export request
+const EXPORT_BATCH_SIZE: usize = 512;
+
+/// Starts a thread exporting spans to `endpoint`, returning the channel to
+/// send them on
+///
+fn spawn_exporter(endpoint: String) -> Sender<Span> {
+    let (tx, rx) = channel::<Span>();
+
+    thread::spawn(move || {
+        // Block for the first span of each batch, then take whatever else
+        // has queued up behind it
+        while let Ok(span) = rx.recv() {
+            let mut batch = vec![span];
+            while batch.len() < EXPORT_BATCH_SIZE {
+                match rx.try_recv() {
+                    Ok(span) => batch.push(span),
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = export(&endpoint, &batch) {
+                warn!("unable to export {} spans: {}", batch.len(), e);
+            }
+        }
+    });
+
+    tx
+}
+
+/// OTLP `ExportTraceServiceRequest` containing `spans`
+///
+#[cfg(feature = "otlp")]
+fn export_request(spans: &[Span]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(|s| s.to_json()).collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": "hammer"}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "hammer"},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+#[cfg(feature = "otlp")]
+fn export(endpoint: &str, spans: &[Span]) -> Result<(), String> {
+    use std::io::Read;
+    use serde_json;
+    use hyper::Client;
+    use hyper::header::ContentType;
+
+    let body = try!(serde_json::to_string(&export_request(spans)).map_err(|e| format!("{}", e)));
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+
+    let mut res = try!(Client::new()
+        .post(&url)
+        .header(ContentType::json())
+        .body(&body)
+        .send()
+        .map_err(|e| format!("{}", e)));
+
+    if res.status.is_success() {
+        Ok(())
+    } else {
+        let mut msg = String::new();
+        let _ = res.read_to_string(&mut msg);
+        Err(format!("collector responded {}: {}", res.status, msg))
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+fn export(_: &str, _: &[Span]) -> Result<(), String> {
+    Err("hammer was built without the `otlp` feature".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use http::trace::TraceContext;
+
+    #[test]
+    fn traceparent_round_trips() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(header).unwrap();
+
+        assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), header);
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert!(child.span_id != context.span_id);
+    }
+
+    #[test]
+    fn rejects_invalid_traceparent() {
+        assert_eq!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01"), None);
+        assert_eq!(TraceContext::parse("00-4bf92f3577b34da6-00f067aa0ba902b7-01"), None);
+    }
+}
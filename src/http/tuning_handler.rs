@@ -0,0 +1,219 @@
+use std::fs;
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use iron::prelude::*;
+use iron::status;
+use persistent::State;
+use serde_json;
+
+use hammer::db::Database;
+use hammer::error::Error;
+
+use http::{Config, ConfigKey, Tuning, TuningKey, decode_body, binary_namespace, vector_namespace, B32, B64, B128, B256, V32, V64, V128, V256};
+use http::namespace_handler::{binary_params, vector_params};
+use http::quotas::{Quotas, QuotasKey};
+
+const TUNING_FILE: &'static str = "tuning.json";
+
+/// Partial update to a namespace's tuning - unset fields are left unchanged
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TuningUpdate {
+    cache_size: Option<usize>,
+    rate_limit: Option<usize>,
+}
+
+/// An open namespace, whichever its value type
+trait Tunable {
+    fn set_variant_cache(&self, sets: usize) -> Result<(), Error>;
+}
+
+impl<T> Tunable for Arc<RwLock<Box<Database<T>>>> {
+    fn set_variant_cache(&self, sets: usize) -> Result<(), Error> {
+        self.write().unwrap().set_variant_cache(sets)
+    }
+}
+
+/// The namespace at `key` in `dbmap_mx`, if it's open
+fn opened<K, T>(dbmap_mx: &RwLock<HashMap<K, Arc<RwLock<Box<Database<T>>>>>>, key: &K) -> Option<Box<Tunable>> where
+K: Eq + Hash,
+T: 'static,
+{
+    dbmap_mx.read().unwrap().get(key).map(|db_mx| Box::new(db_mx.clone()) as Box<Tunable>)
+}
+
+pub fn get_binary(req: &mut Request) -> IronResult<Response> {
+    let (bits, tolerance, namespace) = match binary_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let tuning_mx = req.get::<State<TuningKey>>().unwrap();
+    do_get(binary_namespace(bits, tolerance, &namespace), config_mx, tuning_mx)
+}
+
+pub fn put_binary(req: &mut Request) -> IronResult<Response> {
+    let update = try!(decode_body::<TuningUpdate>(req));
+    let (bits, tolerance, namespace) = match binary_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let ns = binary_namespace(bits, tolerance, &namespace);
+    let key = (tolerance, namespace);
+
+    let db = match bits {
+        32 => opened(&req.get::<State<B32>>().unwrap(), &key),
+        64 => opened(&req.get::<State<B64>>().unwrap(), &key),
+        128 => opened(&req.get::<State<B128>>().unwrap(), &key),
+        256 => opened(&req.get::<State<B256>>().unwrap(), &key),
+        _ => return Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let tuning_mx = req.get::<State<TuningKey>>().unwrap();
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    do_put(update, ns, db, config_mx, tuning_mx, quotas_mx)
+}
+
+pub fn get_vector(req: &mut Request) -> IronResult<Response> {
+    let (bits, dimensions, tolerance, namespace) = match vector_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let tuning_mx = req.get::<State<TuningKey>>().unwrap();
+    do_get(vector_namespace(bits, dimensions, tolerance, &namespace), config_mx, tuning_mx)
+}
+
+pub fn put_vector(req: &mut Request) -> IronResult<Response> {
+    let update = try!(decode_body::<TuningUpdate>(req));
+    let (bits, dimensions, tolerance, namespace) = match vector_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let ns = vector_namespace(bits, dimensions, tolerance, &namespace);
+    let key = (dimensions, tolerance, namespace);
+
+    let db = match bits {
+        32 => opened(&req.get::<State<V32>>().unwrap(), &key),
+        64 => opened(&req.get::<State<V64>>().unwrap(), &key),
+        128 => opened(&req.get::<State<V128>>().unwrap(), &key),
+        256 => opened(&req.get::<State<V256>>().unwrap(), &key),
+        _ => return Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
+    };
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let tuning_mx = req.get::<State<TuningKey>>().unwrap();
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    do_put(update, ns, db, config_mx, tuning_mx, quotas_mx)
+}
+
+fn tuning_path(data_dir: Option<&Path>, ns: &str) -> Option<PathBuf> {
+    data_dir.map(|dir| dir.join(ns).join(TUNING_FILE))
+}
+
+fn load_tuning(path: &PathBuf) -> Option<Tuning> {
+    let mut payload = String::new();
+
+    match fs::File::open(path) {
+        Ok(mut f) => {
+            if f.read_to_string(&mut payload).is_err() {
+                return None
+            }
+        },
+        Err(_) => return None,
+    }
+
+    serde_json::from_str::<Tuning>(&payload).ok()
+}
+
+/// The tuning persisted for namespace `ns` under `data_dir`, if any
+pub fn persisted_tuning(data_dir: Option<&Path>, ns: &str) -> Option<Tuning> {
+    tuning_path(data_dir, ns).and_then(|path| load_tuning(&path))
+}
+
+/// Checks that the tuning persisted in namespace directory `dir`, if any, can
+/// be read
+pub fn check_tuning(dir: &Path) -> Result<(), String> {
+    let path = dir.join(TUNING_FILE);
+
+    if path.exists() && load_tuning(&path).is_none() {
+        return Err(format!("unreadable {}", TUNING_FILE))
+    }
+    Ok(())
+}
+
+fn save_tuning(path: &PathBuf, tuning: &Tuning) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir).map_err(|e| format!("{}", e)));
+    }
+
+    let payload = try!(serde_json::to_string(tuning).map_err(|e| format!("{}", e)));
+    let mut f = try!(fs::File::create(path).map_err(|e| format!("{}", e)));
+    f.write_all(payload.as_bytes()).map_err(|e| format!("{}", e))
+}
+
+/// Returns the current tuning for `ns`, falling back to persisted tuning and
+/// then to the defaults
+fn current_tuning(ns: &str, config: &Config, tunings: &HashMap<String, Tuning>) -> Tuning {
+    match tunings.get(ns) {
+        Some(tuning) => tuning.clone(),
+        None => persisted_tuning(config.data_dir.as_ref().map(|dir| dir.as_path()), ns).unwrap_or_default(),
+    }
+}
+
+fn do_get(ns: String, config_mx: Arc<RwLock<Config>>, tuning_mx: Arc<RwLock<HashMap<String, Tuning>>>) -> IronResult<Response> {
+    let config = {
+        config_mx.read().unwrap().clone()
+    };
+
+    let tuning = {
+        let tunings = tuning_mx.read().unwrap();
+        current_tuning(&ns, &config, &tunings)
+    };
+
+    let response_body = serde_json::to_string(&tuning).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
+
+fn do_put(update: TuningUpdate, ns: String, db: Option<Box<Tunable>>, config_mx: Arc<RwLock<Config>>, tuning_mx: Arc<RwLock<HashMap<String, Tuning>>>, quotas_mx: Arc<RwLock<Quotas>>) -> IronResult<Response> {
+    let config = {
+        config_mx.read().unwrap().clone()
+    };
+    let data_dir = config.data_dir.as_ref().map(|dir| dir.as_path());
+
+    let mut tunings = tuning_mx.write().unwrap();
+    let mut tuning = current_tuning(&ns, &config, &tunings);
+
+    // A namespace which isn't open gets its cache when it's next opened (see
+    // `http::build`)
+    if let Some(cache_size) = update.cache_size {
+        if let Some(ref db) = db {
+            if cache_size != tuning.cache_size {
+                if let Err(e) = db.set_variant_cache(cache_size) {
+                    return Ok(Response::with((status::BadRequest, format!("unable to set cache_size: {}", e))));
+                }
+            }
+        }
+        tuning.cache_size = cache_size;
+    }
+    if let Some(v) = update.rate_limit { tuning.rate_limit = v; }
+
+    if let Some(path) = tuning_path(data_dir, &ns) {
+        if let Err(e) = save_tuning(&path, &tuning) {
+            return Ok(Response::with((status::InternalServerError, format!("unable to persist tuning: {}", e))));
+        }
+    }
+
+    quotas_mx.write().unwrap().set_rate_limit(&ns, data_dir, tuning.rate_limit);
+    tunings.insert(ns, tuning.clone());
+
+    let response_body = serde_json::to_string(&tuning).unwrap();
+    Ok(Response::with((status::Ok, response_body)))
+}
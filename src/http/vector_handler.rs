@@ -5,25 +5,33 @@ use std::io::Read;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
-use bincode;
 use iron::prelude::*;
 use iron::status;
 use router::Router;
 use persistent::State;
-use rustc_serialize::json;
-use rustc_serialize::base64::{FromBase64, ToBase64};
-use rustc_serialize::{Encodable, Decodable};
-use rustc_serialize::json::ToJson;
-
-use hammer::db::{Database, Factory, StorageBackend};
+use base64;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use hammer::db::{Database, Factory, QueryOptions, StorageBackend};
+use hammer::db::cost::TooExpensive;
+use hammer::db::encoding::encode;
+use hammer::Error;
+use hammer::db::health::HealthReport;
 use hammer::db::id_map::IDMap;
 use hammer::db::map_set::MapSet;
 use hammer::db::typemap::*;
 
-use http::{Config, ConfigKey, V32, V64, V128, V256, decode_body, BASE64_CONFIG, AddResult, QueryResult, DeleteResult};
+use http::{Config, ConfigKey, HealthKey, ManifestKey, build, check_namespace, record_namespace, degraded, sync_writes, log_mutation, recent_order, query_options, paging, page_json, Paging, canonical_order, error_response, Queried, V32, V64, V128, V256, vector_namespace, BASE64_CONFIG, AddResult, QueryResult, DeleteResult};
+use http::manifest::{self, Manifest};
+use http::metrics::{self, Registry};
+use http::oplog::{self, Oplog, OplogKey, Op};
+use http::quotas::{self, Quotas, QuotasKey};
+use http::encoding::{Values, RawValue, read_values};
 
 pub fn add(req: &mut Request) -> IronResult<Response> {
-    let req_body = try!(decode_body::<Vec<Vec<String>>>(req));
+    let req_body = try!(read_values::<Vec<String>>(req));
 
     let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
         Some(v) => v.parse::<usize>().unwrap(),
@@ -46,33 +54,79 @@ pub fn add(req: &mut Request) -> IronResult<Response> {
     };
 
     let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    let manifest_mx = req.get::<State<ManifestKey>>().unwrap();
+    let oplog_mx = req.get::<State<OplogKey>>().unwrap();
+
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    if let Err(response) = quotas::check_rate(&quotas_mx, &config_mx, &vector_namespace(bits, dimensions, tolerance, &namespace)) {
+        return Ok(response)
+    }
+
+    let registry = metrics::registry(req);
 
     match bits {
         32 => {
             let dbmap_mx = req.get::<State<V32>>().unwrap();
-            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         64 => {
             let dbmap_mx = req.get::<State<V64>>().unwrap();
-            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         128 => {
             let dbmap_mx = req.get::<State<V128>>().unwrap();
-            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         256 => {
             let dbmap_mx = req.get::<State<V256>>().unwrap();
-            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, dbmap_mx)
+            do_add(req_body, bits, dimensions, tolerance, namespace, config_mx, health_mx, manifest_mx, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
     }
 }
 
-fn do_add<T>(req_body: Vec<Vec<String>>, bits: usize, dimensions: usize, tolerance: usize, namespace: String, config_mx: Arc<RwLock<Config>>, dbmap_mx: Arc<RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>>) -> IronResult<Response> where
-T: Clone + Decodable,
-Vec<T>: Factory,
+fn do_add<T>(req_body: Values<Vec<String>>, bits: usize, dimensions: usize, tolerance: usize, namespace: String, config_mx: Arc<RwLock<Config>>, health_mx: Arc<RwLock<HashMap<String, HealthReport>>>, manifest_mx: Arc<RwLock<Manifest>>, oplog_mx: Arc<RwLock<Option<Oplog>>>, quotas_mx: Arc<RwLock<Quotas>>, dbmap_mx: Arc<RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>>, registry: &Registry) -> IronResult<Response> where
+T: 'static + Clone + Serialize + DeserializeOwned + RawValue,
+Vec<T>: Sync + Send + Clone + Eq + Hash + Factory,
+{
+    // Decoding happens before the namespace is locked, so queries are only
+    // blocked for as long as the inserts themselves take
+    let vectors: Vec<Result<Vec<T>, String>> = match req_body.decode_vectors(dimensions) {
+        Ok(vectors) => vectors,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+
+    let ns = vector_namespace(bits, dimensions, tolerance, &namespace);
+    if let Err(response) = quotas::check_add(&quotas_mx, &config_mx, &ns, vectors.len(), &dbmap_mx, &(dimensions, tolerance, namespace.clone())) {
+        return Ok(response)
+    }
+
+    match add_vectors(vectors, bits, dimensions, tolerance, &namespace, &config_mx, &health_mx, &manifest_mx, &oplog_mx, &dbmap_mx) {
+        Ok(results) => {
+            quotas_mx.write().unwrap().record_added(&ns, &results);
+            registry.count_values("add", results.iter().map(|result| result.outcome()));
+            let response_body = serde_json::to_string(&results).unwrap();
+            Ok(Response::with((status::Ok, response_body)))
+        },
+        Err(response) => Ok(response),
+    }
+}
+
+/// Inserts decoded `vectors` into the namespace, opening it if necessary
+///
+/// Returns the result for each vector in order, or the response to send if
+/// the namespace can't be written to.  Vectors are logged to the oplog (if
+/// one is kept) before they're inserted.
+///
+pub fn add_vectors<T>(vectors: Vec<Result<Vec<T>, String>>, bits: usize, dimensions: usize, tolerance: usize, namespace: &str, config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, oplog_mx: &RwLock<Option<Oplog>>, dbmap_mx: &RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>) -> Result<Vec<AddResult>, Response> where
+T: 'static + Clone + Serialize,
+Vec<T>: Sync + Send + Clone + Eq + Hash + Factory,
 {
-    let mut results = Vec::with_capacity(req_body.len());
+    let ns = vector_namespace(bits, dimensions, tolerance, namespace);
+    let key = (dimensions, tolerance, namespace.to_string());
+    let sync = config_mx.read().unwrap().sync_writes;
+    let mut results = Vec::with_capacity(vectors.len());
 
     // this is a little contorted, but the idea is to optimize for the
     // frequent case where the DB being inserted into exists and only
@@ -80,80 +134,108 @@ Vec<T>: Factory,
     let mut db_exists = true;
     loop {
         if !db_exists {
-            let config = {
-                config_mx.read().unwrap().clone()
-            };
-
-            let backend = match config.data_dir {
-                Some(ref dir) => {
-                    let mut value_store_path = dir.clone();
-                    value_store_path.push(format!("v{:03}_{:03}_{:03}_{:}", bits, dimensions, tolerance, namespace));
-
-                    StorageBackend::RocksDB(value_store_path)
-                },
-                None => StorageBackend::InMemory
-            };
-
-            let db = Factory::build(dimensions, tolerance, backend);
-
-            let mut dbmap = dbmap_mx.write().unwrap();
-            // NOTE: Need to verify this key wasn't inserted earlier and we lost a race
-            dbmap.insert((dimensions.clone(), tolerance.clone(), namespace.clone()), Arc::new(RwLock::new(db)));
+            try!(open(bits, dimensions, tolerance, namespace, &[], config_mx, health_mx, manifest_mx, dbmap_mx));
         }
 
         let dbmap = dbmap_mx.read().unwrap();
 
-        if !dbmap.contains_key(&(dimensions, tolerance, namespace.clone())) {
+        if !dbmap.contains_key(&key) {
             db_exists = false;
             continue
         }
 
-        let db_mx = dbmap.get(&(dimensions, tolerance, namespace)).unwrap();
-        let mut db = db_mx.write().unwrap();
+        let db_mx = dbmap.get(&key).unwrap();
 
-        'vector: for vector_b64 in req_body.into_iter() {
-            let mut vector = Vec::with_capacity(dimensions);
+        if let Some(report) = health_mx.read().unwrap().get(&ns) {
+            return Err(degraded(&ns, report))
+        }
 
-            for item_b64 in vector_b64.into_iter() {
-                let item_bytes = match item_b64.from_base64() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        results.push(AddResult::Err(format!("unable to base64-decode '{}': {:?}", item_b64, e)));
-                        continue 'vector;
+        let mut db = db_mx.write().unwrap();
+        try!(oplog::record(oplog_mx, Op::Add, bits, Some(dimensions), tolerance, namespace, &vectors));
+
+        for vector in vectors.into_iter() {
+            match vector {
+                Ok(vector) => {
+                    log_mutation("add", &ns, &vector);
+                    match db.insert(vector) {
+                        Ok(true) => { results.push(AddResult::Ok); },
+                        Ok(false) => { results.push(AddResult::Exists); },
+                        // Nothing was written, so the rest of the batch can go ahead
+                        Err(e @ Error::Collision(..)) => { results.push(AddResult::Err(e.to_string())); },
+                        // Values before this one are kept, as with a failed sync
+                        Err(e) => return Err(error_response(e)),
                     }
-                };
+                },
+                Err(e) => { results.push(AddResult::Err(e)); },
+            }
+        }
 
-                let item: T = match bincode::rustc_serialize::decode(&item_bytes) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        results.push(AddResult::Err(format!("unable to decode '{}': {:?}", item_b64, e)));
-                        continue 'vector;
-                    },
-                };
+        try!(sync_writes(sync, &**db));
 
-                vector.push(item);
-            }
+        return Ok(results)
+    }
+}
 
-            if vector.len() != dimensions {
-                results.push(AddResult::Err(format!("expected vector length to be {}, not {}", dimensions, vector.len())));
-                continue 'vector;
-            }
+/// Builds the namespace and adds it to `dbmap_mx`
+///
+/// Namespaces whose directory already exists are being re-opened and get
+/// checked before they're used.  If the namespace was opened elsewhere while
+/// it was being built, the DB already in `dbmap_mx` is kept.
+///
+/// New namespaces ignore differences in `ignored`; one already in the
+/// manifest keeps the dimensions it was created ignoring.
+///
+/// Fails with the response to send if the namespace can't be built, ie its
+/// inserts would exceed the variant budget (see `Factory::open`), or with a
+/// 500 if its stores can't be opened.
+///
+pub fn open<T>(bits: usize, dimensions: usize, tolerance: usize, namespace: &str, ignored: &[usize], config_mx: &RwLock<Config>, health_mx: &RwLock<HashMap<String, HealthReport>>, manifest_mx: &RwLock<Manifest>, dbmap_mx: &RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>) -> Result<(), Response> where
+T: 'static,
+Vec<T>: Sync + Send + Clone + Eq + Hash + Factory,
+{
+    let ns = vector_namespace(bits, dimensions, tolerance, namespace);
+    let config = {
+        config_mx.read().unwrap().clone()
+    };
 
-            match db.insert(vector) {
-                true => { results.push(AddResult::Ok); },
-                false => { results.push(AddResult::Exists); },
-            }
-        }
+    // Variants already written were computed with the namespace's original
+    // ignored dimensions
+    let ignored = match manifest_mx.read().unwrap().find(&ns) {
+        Some(entry) => entry.ignored_dimensions().to_vec(),
+        None => ignored.to_vec(),
+    };
 
-        break
+    let (backend, reopened) = match config.data_dir {
+        Some(ref dir) => {
+            let mut value_store_path = dir.clone();
+            value_store_path.push(&ns);
+            let reopened = if value_store_path.exists() { Some(value_store_path.clone()) } else { None };
+
+            (config.persistent_backend(value_store_path), reopened)
+        },
+        None => (StorageBackend::InMemory, None)
+    };
+
+    // Namespace directories are named for their parameters, so a mismatch
+    // means the directory's been tampered with
+    let mut db = match build(&ns, dimensions, tolerance, &ignored, backend, &config) {
+        Ok(db) => db,
+        Err(e) => {
+            warn!("unable to open namespace {}: {}", ns, e);
+            return Err(error_response(e))
+        },
+    };
+    if let Some(ref dir) = reopened {
+        check_namespace(&ns, dir, &mut *db, config.repair_on_open, health_mx);
     }
+    record_namespace(&config, manifest::Entry::vector(bits, dimensions, tolerance, namespace, config.storage).with_ignored_dimensions(&ignored), manifest_mx);
 
-    let response_body = json::encode(&results.to_json()).unwrap();
-    Ok(Response::with((status::Ok, response_body)))
+    let mut dbmap = dbmap_mx.write().unwrap();
+    dbmap.entry((dimensions, tolerance, namespace.to_string())).or_insert(Arc::new(RwLock::new(db)));
 }
 
 pub fn query(req: &mut Request) -> IronResult<Response> {
-    let req_body = try!(decode_body::<Vec<Vec<String>>>(req));
+    let req_body = try!(read_values::<Vec<String>>(req));
 
     let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
         Some(v) => v.parse::<usize>().unwrap(),
@@ -175,80 +257,112 @@ pub fn query(req: &mut Request) -> IronResult<Response> {
         None => return Ok(Response::with((status::BadRequest, "DB namespace is required"))),
     };
 
+    let recent = match recent_order(req) {
+        Ok(recent) => recent,
+        Err(response) => return Ok(response),
+    };
+    let options = match query_options(req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let paging = match paging(req) {
+        Ok(paging) => paging,
+        Err(response) => return Ok(response),
+    };
+
+    let max_candidates = {
+        let config_mx = req.get::<State<ConfigKey>>().unwrap();
+        let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+        if let Err(response) = quotas::check_rate(&quotas_mx, &config_mx, &vector_namespace(bits, dimensions, tolerance, &namespace)) {
+            return Ok(response)
+        }
+
+        let config = config_mx.read().unwrap();
+        config.max_query_candidates
+    };
+
+    let registry = metrics::registry(req);
+
     match bits {
         32 => {
             let dbmap_mx = req.get::<State<V32>>().unwrap();
-            do_query(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_query(req_body, dimensions, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         64 => {
             let dbmap_mx = req.get::<State<V64>>().unwrap();
-            do_query(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_query(req_body, dimensions, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         128 => {
             let dbmap_mx = req.get::<State<V128>>().unwrap();
-            do_query(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_query(req_body, dimensions, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         256 => {
             let dbmap_mx = req.get::<State<V256>>().unwrap();
-            do_query(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_query(req_body, dimensions, tolerance, namespace, recent, options, paging, max_candidates, dbmap_mx, &registry)
         },
         _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize"))),
     }
 }
 
-fn do_query<T>(req_body: Vec<Vec<String>>, dimensions: usize, tolerance: usize, namespace: String, dbmap_mx: Arc<RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>>) -> IronResult<Response> where
-T: Eq + Hash + Clone + Encodable + Decodable,
+fn do_query<T>(req_body: Values<Vec<String>>, dimensions: usize, tolerance: usize, namespace: String, recent: bool, options: QueryOptions, paging: Option<Paging>, max_candidates: Option<usize>, dbmap_mx: Arc<RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>>, registry: &Registry) -> IronResult<Response> where
+T: 'static + Sync + Send + Eq + Hash + Clone + Serialize + DeserializeOwned + RawValue,
 {
-    let mut results = Vec::with_capacity(req_body.len());
+    let vectors: Vec<Result<Vec<T>, String>> = match req_body.decode_vectors(dimensions) {
+        Ok(vectors) => vectors,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let cursor: Option<Vec<T>> = match paging.as_ref().map(|paging| paging.cursor(vectors.len())) {
+        None => None,
+        Some(Ok(cursor)) => cursor,
+        Some(Err(response)) => return Ok(response),
+    };
+    let mut results = Vec::with_capacity(vectors.len());
 
     match { dbmap_mx.read().unwrap().get(&(dimensions.clone(), tolerance.clone(), namespace.clone())) } {
         None => {
-            for _ in 0..req_body.len() {
+            for _ in 0..vectors.len() {
                 results.push(QueryResult::None);
             }
         },
         Some(db_mx) => {
-            let db = db_mx.read().unwrap();
-
-            'vector: for vector_b64 in req_body.into_iter() {
-                let mut vector = Vec::with_capacity(dimensions);
-
-                for item_b64 in vector_b64.into_iter() {
-                    let item_bytes = match item_b64.from_base64() {
-                        Ok(v) => v,
-                        Err(e) => {
-                            results.push(QueryResult::Err(format!("unable to base64-decode '{}': {:?}", item_b64, e)));
-                            continue 'vector;
-                        }
-                    };
+            let db = Queried::new(db_mx);
 
-                    let item: T = match bincode::rustc_serialize::decode(&item_bytes) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            results.push(QueryResult::Err(format!("unable to decode '{}': {:?}", item_b64, e)));
-                            continue 'vector;
-                        },
-                    };
-
-                    vector.push(item);
-                }
+            for vector in vectors.into_iter() {
+                let vector = match vector {
+                    Ok(v) => v,
+                    Err(e) => {
+                        results.push(QueryResult::Err(e));
+                        continue;
+                    },
+                };
 
-                if vector.len() != dimensions {
-                    results.push(QueryResult::Err(format!("expected vector length to be {}, not {}", dimensions, vector.len())));
-                    continue 'vector;
+                if let Some(limit) = max_candidates {
+                    let estimate = match db.estimate_candidates(&vector) {
+                        Ok(estimate) => estimate,
+                        Err(e) => return Ok(error_response(e)),
+                    };
+                    if estimate > limit {
+                        return Ok(error_response(Error::TooExpensive(TooExpensive{estimated_candidates: estimate, limit: limit})))
+                    }
                 }
 
-                match db.get(&vector) {
-                    Some(found) => {
-                        let found_b64s: Vec<Vec<String>> = found.iter().map(|v| {
-                            v.iter().map(|item| {
-                                let found_bytes = bincode::rustc_serialize::encode(item, bincode::SizeLimit::Infinite).unwrap();
+                let found: Result<Option<Value>, Error> = match paging {
+                    Some(ref paging) => db.get_page_with(&vector, &options, cursor.as_ref(), paging.limit)
+                        .map(|page| Some(page_json(json!(encode_b64s(&page.values)), page.next))),
+                    None if recent => db.get_recent_with(&vector, &options)
+                        .map(|found| found.map(|found| json!(encode_b64s(&found)))),
+                    None => db.get_with(&vector, &options)
+                        .map(|found| found.map(|found| json!(encode_b64s(&canonical_order(&vector, found))))),
+                };
 
-                                found_bytes.to_base64(BASE64_CONFIG)
-                            }).collect()
-                        }).collect();
+                let found = match found {
+                    Ok(found) => found,
+                    Err(e) => return Ok(error_response(e)),
+                };
 
-                        results.push(QueryResult::Ok(found_b64s));
+                match found {
+                    Some(found) => {
+                        results.push(QueryResult::Ok(found));
                     },
                     None => {
                         results.push(QueryResult::None);
@@ -258,12 +372,33 @@ T: Eq + Hash + Clone + Encodable + Decodable,
         }
     }
 
-    let response_body = json::encode(&results.to_json()).unwrap();
+    registry.count_values("query", results.iter().map(|result| result.outcome()));
+    let response_body = serde_json::to_string(&results).unwrap();
     Ok(Response::with((status::Ok, response_body)))
 }
 
+/// Base64-encoded bincode of each element of each of `vectors`
+fn encode_b64s<T: Serialize>(vectors: &[Vec<T>]) -> Vec<Vec<String>> {
+    vectors.iter().map(|v| {
+        v.iter().map(|item| {
+            let found_bytes = encode(item).unwrap();
+
+            base64::encode_config(&found_bytes, BASE64_CONFIG)
+        }).collect()
+    }).collect()
+}
+
 pub fn delete(req: &mut Request) -> IronResult<Response> {
-    let req_body = try!(decode_body::<Vec<Vec<String>>>(req));
+    delete_values(req, false)
+}
+
+/// Deletes values and checks that no variant still references them
+pub fn delete_verified(req: &mut Request) -> IronResult<Response> {
+    delete_values(req, true)
+}
+
+fn delete_values(req: &mut Request, verified: bool) -> IronResult<Response> {
+    let req_body = try!(read_values::<Vec<String>>(req));
 
     let bits = match req.extensions.get::<Router>().unwrap().find("bits") {
         Some(v) => v.parse::<usize>().unwrap(),
@@ -285,76 +420,118 @@ pub fn delete(req: &mut Request) -> IronResult<Response> {
         None => return Ok(Response::with((status::BadRequest, "DB namespace is required"))),
     };
 
+    let ns = vector_namespace(bits, dimensions, tolerance, &namespace);
+    let health_mx = req.get::<State<HealthKey>>().unwrap();
+    if let Some(report) = health_mx.read().unwrap().get(&ns) {
+        return Ok(degraded(&ns, report))
+    }
+
+    let config_mx = req.get::<State<ConfigKey>>().unwrap();
+    let quotas_mx = req.get::<State<QuotasKey>>().unwrap();
+    if let Err(response) = quotas::check_rate(&quotas_mx, &config_mx, &ns) {
+        return Ok(response)
+    }
+
+    let sync = config_mx.read().unwrap().sync_writes;
+    let oplog_mx = req.get::<State<OplogKey>>().unwrap();
+
+    let registry = metrics::registry(req);
+
     match bits {
         32 => {
             let dbmap_mx = req.get::<State<V32>>().unwrap();
-            do_delete(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, dimensions, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         64 => {
             let dbmap_mx = req.get::<State<V64>>().unwrap();
-            do_delete(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, dimensions, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         128 => {
             let dbmap_mx = req.get::<State<V128>>().unwrap();
-            do_delete(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, dimensions, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         256 => {
             let dbmap_mx = req.get::<State<V256>>().unwrap();
-            do_delete(req_body, dimensions, tolerance, namespace, dbmap_mx)
+            do_delete(req_body, bits, dimensions, tolerance, namespace, verified, sync, oplog_mx, quotas_mx, dbmap_mx, &registry)
         },
         _ => Ok(Response::with((status::BadRequest, "Unsuported bitsize or tolerance"))),
     }
 }
 
-fn do_delete<T>(req_body: Vec<Vec<String>>, dimensions: usize, tolerance: usize, namespace: String, dbmap_mx: Arc<RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>>) -> IronResult<Response> where
-T: Eq + Hash + Clone + Encodable + Decodable,
+fn do_delete<T>(req_body: Values<Vec<String>>, bits: usize, dimensions: usize, tolerance: usize, namespace: String, verified: bool, sync: bool, oplog_mx: Arc<RwLock<Option<Oplog>>>, quotas_mx: Arc<RwLock<Quotas>>, dbmap_mx: Arc<RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>>, registry: &Registry) -> IronResult<Response> where
+T: Eq + Hash + Clone + Serialize + DeserializeOwned + RawValue,
 {
-    let mut results = Vec::with_capacity(req_body.len());
+    let vectors: Vec<Result<Vec<T>, String>> = match req_body.decode_vectors(dimensions) {
+        Ok(vectors) => vectors,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
 
-    match { dbmap_mx.read().unwrap().get(&(dimensions.clone(), tolerance.clone(), namespace.clone())) } {
+    match remove_vectors(vectors, bits, dimensions, tolerance, &namespace, verified, sync, &oplog_mx, &dbmap_mx) {
+        Ok(results) => {
+            quotas_mx.write().unwrap().record_removed(&vector_namespace(bits, dimensions, tolerance, &namespace), &results);
+            registry.count_values("delete", results.iter().map(|result| result.outcome()));
+            let response_body = serde_json::to_string(&results).unwrap();
+            Ok(Response::with((status::Ok, response_body)))
+        },
+        Err(response) => Ok(response),
+    }
+}
+
+/// Removes decoded `vectors` from the namespace, if it's open
+///
+/// Returns the result for each vector in order, or the response to send if
+/// the namespace can't be written to.  Vectors are logged to the oplog (if
+/// one is kept) before they're removed.
+///
+pub fn remove_vectors<T>(vectors: Vec<Result<Vec<T>, String>>, bits: usize, dimensions: usize, tolerance: usize, namespace: &str, verified: bool, sync: bool, oplog_mx: &RwLock<Option<Oplog>>, dbmap_mx: &RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>) -> Result<Vec<DeleteResult>, Response> where
+T: Eq + Hash + Clone + Serialize,
+{
+    let ns = vector_namespace(bits, dimensions, tolerance, namespace);
+    let mut results = Vec::with_capacity(vectors.len());
+
+    match { dbmap_mx.read().unwrap().get(&(dimensions, tolerance, namespace.to_string())) } {
         None => {
-            for _ in 0..req_body.len() {
+            for _ in 0..vectors.len() {
                 results.push(DeleteResult::NotFound);
             }
         },
         Some(db_mx) => {
             let mut db = db_mx.write().unwrap();
+            try!(oplog::record(oplog_mx, Op::Delete, bits, Some(dimensions), tolerance, namespace, &vectors));
 
-            'vector: for vector_b64 in req_body.into_iter() {
-                let mut vector = Vec::with_capacity(dimensions);
-
-                for item_b64 in vector_b64.into_iter() {
-                    let item_bytes = match item_b64.from_base64() {
-                        Ok(v) => v,
-                        Err(e) => {
-                            results.push(DeleteResult::Err(format!("unable to base64-decode '{}': {:?}", item_b64, e)));
-                            continue 'vector;
-                        }
-                    };
+            for vector in vectors.into_iter() {
+                let vector = match vector {
+                    Ok(v) => v,
+                    Err(e) => {
+                        results.push(DeleteResult::Err(e));
+                        continue;
+                    },
+                };
+                log_mutation("delete", &ns, &vector);
 
-                    let item: T = match bincode::rustc_serialize::decode(&item_bytes) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            results.push(DeleteResult::Err(format!("unable to decode '{}': {:?}", item_b64, e)));
-                            continue 'vector;
-                        },
+                if verified {
+                    let report = match db.remove_verified(&vector) {
+                        Ok(report) => report,
+                        Err(e) => return Err(error_response(e)),
                     };
-
-                    vector.push(item);
+                    if report.variants_removed == 0 {
+                        results.push(DeleteResult::NotFound);
+                    } else {
+                        results.push(DeleteResult::Verified(report));
+                    }
+                    continue;
                 }
 
-                if vector.len() != dimensions {
-                    results.push(DeleteResult::Err(format!("expected vector length to be {}, not {}", dimensions, vector.len())));
-                    continue 'vector;
-                }
                 match db.remove(&vector) {
-                    true => { results.push(DeleteResult::Ok); },
-                    false => { results.push(DeleteResult::NotFound); },
+                    Ok(true) => { results.push(DeleteResult::Ok); },
+                    Ok(false) => { results.push(DeleteResult::NotFound); },
+                    Err(e) => return Err(error_response(e)),
                 }
             }
+
+            try!(sync_writes(sync, &**db));
         }
     }
 
-    let response_body = json::encode(&results.to_json()).unwrap();
-    Ok(Response::with((status::Ok, response_body)))
+    Ok(results)
 }
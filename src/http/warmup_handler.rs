@@ -0,0 +1,90 @@
+//! Per-namespace cache warmup (see `hammer::db::Database::warmup`)
+//!
+//! `POST /db/b/:bits/:tolerance/:namespace/warmup` reads the namespace's
+//! stores from disk, so the block cache is populated before queries arrive
+//! rather than by them.  `?fraction=0.25` reads about a quarter of each store
+//! (the default is all of it).  Vector namespaces have the same endpoint under
+//! `/db/v/...`.
+//!
+//! Only namespaces already open can be warmed; persisted namespaces are
+//! re-opened when the server starts.
+
+use std::sync::{Arc, RwLock};
+
+use iron::prelude::*;
+use iron::status;
+use persistent::State;
+use serde_json;
+
+use hammer::db::Database;
+
+use http::{query_param, error_response, B32, B64, B128, B256, V32, V64, V128, V256};
+use http::namespace_handler::{binary_params, vector_params};
+
+pub fn warmup_binary(req: &mut Request) -> IronResult<Response> {
+    let (bits, tolerance, namespace) = match binary_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let fraction = match fraction(req) {
+        Ok(fraction) => fraction,
+        Err(response) => return Ok(response),
+    };
+    let key = (tolerance, namespace);
+
+    let response = match bits {
+        32 => warmup(req.get::<State<B32>>().unwrap().read().unwrap().get(&key), fraction),
+        64 => warmup(req.get::<State<B64>>().unwrap().read().unwrap().get(&key), fraction),
+        128 => warmup(req.get::<State<B128>>().unwrap().read().unwrap().get(&key), fraction),
+        256 => warmup(req.get::<State<B256>>().unwrap().read().unwrap().get(&key), fraction),
+        _ => Response::with((status::BadRequest, "Unsuported bitsize")),
+    };
+    Ok(response)
+}
+
+pub fn warmup_vector(req: &mut Request) -> IronResult<Response> {
+    let (bits, dimensions, tolerance, namespace) = match vector_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+    let fraction = match fraction(req) {
+        Ok(fraction) => fraction,
+        Err(response) => return Ok(response),
+    };
+    let key = (dimensions, tolerance, namespace);
+
+    let response = match bits {
+        32 => warmup(req.get::<State<V32>>().unwrap().read().unwrap().get(&key), fraction),
+        64 => warmup(req.get::<State<V64>>().unwrap().read().unwrap().get(&key), fraction),
+        128 => warmup(req.get::<State<V128>>().unwrap().read().unwrap().get(&key), fraction),
+        256 => warmup(req.get::<State<V256>>().unwrap().read().unwrap().get(&key), fraction),
+        _ => Response::with((status::BadRequest, "Unsuported bitsize")),
+    };
+    Ok(response)
+}
+
+/// Parses the `fraction` query parameter, defaulting to 1
+fn fraction(req: &Request) -> Result<f64, Response> {
+    match query_param(req, "fraction") {
+        None => Ok(1.0),
+        Some(v) => match v.parse::<f64>() {
+            Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction),
+            _ => Err(Response::with((status::BadRequest, format!("Invalid fraction '{}'", v)))),
+        },
+    }
+}
+
+fn warmup<T>(db_mx: Option<&Arc<RwLock<Box<Database<T>>>>>, fraction: f64) -> Response {
+    let db_mx = match db_mx {
+        Some(db_mx) => db_mx,
+        None => return Response::with((status::NotFound, "No such namespace")),
+    };
+
+    // Warming only reads, so queries can run alongside it
+    let warmed = db_mx.read().unwrap().warmup(fraction);
+
+    match warmed {
+        Ok(report) => Response::with((status::Ok, serde_json::to_string(&report).unwrap())),
+        Err(e) => error_response(e),
+    }
+}
@@ -0,0 +1,396 @@
+extern crate docopt;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate csv;
+#[cfg(feature="parquet")]
+extern crate parquet;
+extern crate hammer;
+
+use std::io::{self, Write};
+#[cfg(feature="parquet")]
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use docopt::Docopt;
+
+use hammer::db::{Database, Factory, StorageBackend, RocksDBConfig, Strategy};
+use hammer::db::planner;
+
+const USAGE: &'static str = "
+Hammer
+
+`hammer import` streams fingerprints from a CSV or Parquet file into a
+RocksDB-backed binary database.  Values may be decimal or 0x-prefixed hex
+integers.  To load a namespace served by hammerhttp, point --db at
+<data-dir>/bBBB_TTT_<namespace> (ie b064_003_foo for 64 bits at tolerance 3).
+
+`hammer plan` projects the size of an index before building it.
+
+Usage:
+    hammer import [--format=<fmt>] --column=<name> --db=<path> --bits=<n> --tolerance=<n> [--batch-size=<n>] [--bulk] <input>
+    hammer plan --dimensions=<n> --tolerance=<n> --values=<n> [--strategy=<s>] [--backend=<b>] [--element-bytes=<n>]
+    hammer (-h | --help)
+
+Options:
+    --format=<fmt>          Input format, csv or parquet [default: csv]
+    --column=<name>         Column holding the fingerprint
+    --db=<path>             Database directory (created if missing)
+    --bits=<n>              Fingerprint width, 32 or 64
+    --tolerance=<n>         Database tolerance
+    --batch-size=<n>        Values inserted per batch [default: 10000]
+    --bulk                  Write each batch's variants in sorted order without
+                            checking for existing entries (much faster for
+                            initial loads; use a large --batch-size)
+    --dimensions=<n>        Bits (substitution) or vector elements (deletion)
+    --values=<n>            Number of values to be indexed
+    --strategy=<s>          substitution or deletion [default: substitution]
+    --backend=<b>           memory or rocksdb [default: rocksdb]
+    --element-bytes=<n>     Width of vector elements [default: 8]
+    -h --help               Show this screen.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_import: bool,
+    cmd_plan: bool,
+    flag_format: String,
+    flag_column: String,
+    flag_db: String,
+    flag_bits: Option<usize>,
+    flag_tolerance: usize,
+    flag_batch_size: usize,
+    flag_bulk: bool,
+    flag_dimensions: Option<usize>,
+    flag_values: Option<u64>,
+    flag_strategy: String,
+    flag_backend: String,
+    flag_element_bytes: usize,
+    arg_input: String,
+}
+
+/// Summary of an import
+#[derive(Debug, Default)]
+struct Report {
+    read: usize,
+    /// Values written by a bulk import, which doesn't tell new and existing
+    /// values apart
+    loaded: usize,
+    inserted: usize,
+    existing: usize,
+    invalid: usize,
+}
+
+pub fn main() {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    if args.cmd_plan {
+        return plan(&args)
+    }
+    if !args.cmd_import {
+        return
+    }
+
+    let values = match read_column(&args.arg_input, &args.flag_format, &args.flag_column) {
+        Ok(values) => values,
+        Err(e) => fail(&e),
+    };
+
+    let backend = StorageBackend::RocksDB(PathBuf::from(&args.flag_db), RocksDBConfig::default());
+    let started = Instant::now();
+
+    let report = match args.flag_bits.unwrap_or(0) {
+        32 => {
+            let mut db = u32::build(32, args.flag_tolerance, backend).unwrap_or_else(|e| fail(&format!("unable to open {}: {}", args.flag_db, e)));
+            import(values, |s| parse_int(s).and_then(|v| {
+                if v > u32::max_value() as u64 { Err(format!("{} exceeds 32 bits", s)) } else { Ok(v as u32) }
+            }), &mut db, args.flag_batch_size, args.flag_bulk)
+        },
+        64 => {
+            let mut db = u64::build(64, args.flag_tolerance, backend).unwrap_or_else(|e| fail(&format!("unable to open {}: {}", args.flag_db, e)));
+            import(values, parse_int, &mut db, args.flag_batch_size, args.flag_bulk)
+        },
+        bits => fail(&format!("Unsupported bitsize {}", bits)),
+    };
+
+    let elapsed = started.elapsed();
+    println!("Read {} values in {}.{:03}s", report.read, elapsed.as_secs(), elapsed.subsec_nanos() / 1000000);
+    if args.flag_bulk {
+        println!("  loaded: {}", report.loaded);
+    } else {
+        println!("  inserted: {}", report.inserted);
+        println!("  already present: {}", report.existing);
+    }
+    println!("  invalid: {}", report.invalid);
+}
+
+/// Print the projected size of the index described by `args`
+///
+fn plan(args: &Args) {
+    let strategy = match &*args.flag_strategy {
+        "substitution" => Strategy::Substitution,
+        "deletion" => Strategy::Deletion,
+        other => fail(&format!("Unsupported strategy '{}'", other)),
+    };
+    let backend = match &*args.flag_backend {
+        "memory" => StorageBackend::InMemory,
+        "rocksdb" => StorageBackend::TempRocksDB,
+        other => fail(&format!("Unsupported backend '{}'", other)),
+    };
+    let dimensions = args.flag_dimensions.unwrap_or(0);
+    let values = args.flag_values.unwrap_or(0);
+
+    let plan = planner::estimate_with_element_bytes(dimensions, args.flag_tolerance, strategy, values, &backend, args.flag_element_bytes);
+
+    println!("{:?} index of {} values, {} dimensions, tolerance {}", strategy, values, dimensions, args.flag_tolerance);
+    println!("  partitions: {}", plan.partitions);
+    println!("  variants per value: {}", plan.variants_per_value);
+    println!("  variant entries: {}", plan.entries);
+    println!("  bytes per entry: {}", plan.bytes_per_entry);
+    println!("  value store: {}", human_bytes(plan.value_store_bytes));
+    println!("  memory: {}", human_bytes(plan.memory_bytes));
+    println!("  disk: {}", human_bytes(plan.disk_bytes));
+}
+
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {} ({} bytes)", size, units[unit], bytes)
+}
+
+fn fail(message: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}", message);
+    ::std::process::exit(1)
+}
+
+/// Insert `values` into `db` in batches of `batch_size`, reporting progress
+/// after each batch
+///
+/// With `bulk` each batch is written with `Database::bulk_insert`.  The
+/// import stops at the first storage error, since every later write would
+/// likely fail the same way.
+///
+fn import<T, I, F>(values: I, parse: F, db: &mut Box<Database<T>>, batch_size: usize, bulk: bool) -> Report where
+I: Iterator<Item=Result<String, String>>,
+F: Fn(&str) -> Result<T, String>,
+{
+    let mut report: Report = Default::default();
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut values = values.peekable();
+
+    while values.peek().is_some() {
+        for value in values.by_ref().take(batch_size) {
+            report.read += 1;
+
+            match value.and_then(|s| parse(&s)) {
+                Ok(v) => batch.push(v),
+                Err(e) => {
+                    report.invalid += 1;
+                    let _ = writeln!(io::stderr(), "record {}: {}", report.read, e);
+                },
+            }
+        }
+
+        if bulk {
+            report.loaded += batch.len();
+            if let Err(e) = db.bulk_insert(batch.drain(..).collect()) {
+                fail(&format!("batch ending at record {}: {}", report.read, e));
+            }
+
+            let _ = writeln!(io::stderr(), "{} values read, {} loaded", report.read, report.loaded);
+            continue
+        }
+
+        for v in batch.drain(..) {
+            match db.insert(v) {
+                Ok(true) => report.inserted += 1,
+                Ok(false) => report.existing += 1,
+                Err(e) => fail(&format!("batch ending at record {}: {}", report.read, e)),
+            }
+        }
+
+        let _ = writeln!(io::stderr(), "{} values read, {} inserted", report.read, report.inserted);
+    }
+
+    report
+}
+
+/// Parses a decimal or 0x-prefixed hex integer
+///
+fn parse_int(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let parsed = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<u64>()
+    };
+
+    parsed.map_err(|e| format!("unable to parse '{}': {}", s, e))
+}
+
+fn read_column(path: &str, format: &str, column: &str) -> Result<Box<Iterator<Item=Result<String, String>>>, String> {
+    match format {
+        "csv" => read_csv(path, column),
+        "parquet" => read_parquet(path, column),
+        _ => Err(format!("Unsupported format '{}'", format)),
+    }
+}
+
+/// The `column` field of each record of the CSV file at `path`, read as
+/// they're iterated
+///
+fn read_csv(path: &str, column: &str) -> Result<Box<Iterator<Item=Result<String, String>>>, String> {
+    let mut reader = try!(csv::Reader::from_path(path).map_err(|e| format!("{}", e)));
+    let index = {
+        let headers = try!(reader.headers().map_err(|e| format!("{}", e)));
+        try!(headers.iter().position(|h| h == column).ok_or(format!("No column named '{}'", column)))
+    };
+
+    // The iterator owns the reader, so records are read as they're imported
+    let records = reader.into_records().map(move |record| {
+        record
+            .map_err(|e| format!("{}", e))
+            .and_then(|fields| fields.get(index).map(|field| field.to_string()).ok_or(format!("missing column {}", index)))
+    });
+
+    Ok(Box::new(records))
+}
+
+/// The `column` field of each row of the Parquet file at `path`, read a row
+/// group at a time as they're iterated
+///
+#[cfg(feature="parquet")]
+fn read_parquet(path: &str, column: &str) -> Result<Box<Iterator<Item=Result<String, String>>>, String> {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use parquet::record::reader::RowIter;
+
+    let file = try!(File::open(path).map_err(|e| format!("{}", e)));
+    let reader = try!(SerializedFileReader::new(file).map_err(|e| format!("{}", e)));
+    let column = column.to_string();
+
+    // The iterator owns the reader, and only decodes the row group it's in
+    let rows = RowIter::from_file_into(Box::new(reader) as Box<FileReader>).map(move |row| {
+        let row = try!(row.map_err(|e| format!("{}", e)));
+        match row.get_column_iter().find(|&(ref name, _)| **name == column) {
+            Some((_, &Field::Long(v))) => Ok(format!("{}", v as u64)),
+            Some((_, &Field::ULong(v))) => Ok(format!("{}", v)),
+            Some((_, &Field::Int(v))) => Ok(format!("{}", v as u32)),
+            Some((_, &Field::UInt(v))) => Ok(format!("{}", v)),
+            Some((_, &Field::Str(ref s))) => Ok(s.clone()),
+            Some((_, other)) => Err(format!("unsupported value {:?}", other)),
+            None => Err(format!("missing column '{}'", column)),
+        }
+    });
+
+    Ok(Box::new(rows))
+}
+
+#[cfg(not(feature="parquet"))]
+fn read_parquet(_path: &str, _column: &str) -> Result<Box<Iterator<Item=Result<String, String>>>, String> {
+    Err("Parquet support requires building with --features parquet".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use docopt::Docopt;
+
+    use hammer::db::{Database, Factory, StorageBackend};
+    use hammer::db::temp_dir::TempDir;
+
+    use super::{USAGE, Args, import, parse_int, read_csv};
+
+    fn parse_args(argv: &[&str]) -> Args {
+        Docopt::new(USAGE)
+            .and_then(|d| d.argv(argv.iter()).deserialize())
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_import_args() {
+        let args = parse_args(&["hammer", "import", "--format", "parquet", "--column", "fingerprint", "--db", "path", "--bits", "64", "--tolerance", "3", "input.parquet"]);
+
+        assert!(args.cmd_import && !args.cmd_plan);
+        assert_eq!(args.flag_format, "parquet");
+        assert_eq!(args.flag_column, "fingerprint");
+        assert_eq!(args.flag_db, "path");
+        assert_eq!(args.flag_bits, Some(64));
+        assert_eq!(args.flag_tolerance, 3);
+        assert_eq!(args.flag_batch_size, 10000);
+        assert!(!args.flag_bulk);
+        assert_eq!(args.arg_input, "input.parquet");
+    }
+
+    #[test]
+    fn defaults_to_csv() {
+        let args = parse_args(&["hammer", "import", "--column=fp", "--db=path", "--bits=32", "--tolerance=2", "--batch-size=5", "--bulk", "input.csv"]);
+
+        assert_eq!(args.flag_format, "csv");
+        assert_eq!(args.flag_batch_size, 5);
+        assert!(args.flag_bulk);
+    }
+
+    #[test]
+    fn parses_decimal_and_hex() {
+        assert_eq!(parse_int("42"), Ok(42));
+        assert_eq!(parse_int(" 0xff "), Ok(255));
+        assert!(parse_int("0xg").is_err());
+    }
+
+    fn values(strs: &[&str]) -> Box<Iterator<Item=Result<String, String>>> {
+        let values: Vec<Result<String, String>> = strs.iter().map(|s| Ok(s.to_string())).collect();
+        Box::new(values.into_iter())
+    }
+
+    #[test]
+    fn imports_in_batches() {
+        let mut db = u64::build(64, 3, StorageBackend::InMemory).unwrap();
+        let report = import(values(&["1", "0x3", "bogus", "1", "7"]), parse_int, &mut db, 2, false);
+
+        assert_eq!(report.read, 5);
+        assert_eq!(report.inserted, 3);
+        assert_eq!(report.existing, 1);
+        assert_eq!(report.invalid, 1);
+        assert!(db.get(&0).unwrap().unwrap().contains(&7));
+    }
+
+    #[test]
+    fn bulk_imports_count_loaded_values() {
+        let mut db = u64::build(64, 3, StorageBackend::InMemory).unwrap();
+        let report = import(values(&["1", "2", "bogus"]), parse_int, &mut db, 2, true);
+
+        assert_eq!(report.read, 3);
+        assert_eq!(report.loaded, 2);
+        assert_eq!(report.invalid, 1);
+        assert!(db.get(&0).unwrap().unwrap().contains(&2));
+    }
+
+    #[test]
+    fn reads_csv_column() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("input.csv");
+        File::create(&path).unwrap().write_all(b"id,fingerprint\na,1\nb,0x2\nc\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let read: Vec<Result<String, String>> = read_csv(path, "fingerprint").unwrap().collect();
+        assert_eq!(read[0], Ok("1".to_string()));
+        assert_eq!(read[1], Ok("0x2".to_string()));
+        assert!(read[2].is_err());
+        assert_eq!(read.len(), 3);
+
+        assert!(read_csv(path, "missing").is_err());
+    }
+}
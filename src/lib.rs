@@ -1,13 +1,35 @@
-// #![feature(test)]
+#[cfg(feature = "rocksdb")]
 extern crate rocksdb;
+#[cfg(feature = "sled")]
+extern crate sled;
 extern crate bincode;
-extern crate rustc_serialize;
+extern crate base64;
+extern crate hex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate uuid;
 extern crate num;
 extern crate fnv;
 extern crate murmurhash3;
+extern crate siphasher;
+extern crate smallvec;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate memmap;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "client")]
+extern crate hyper;
+#[cfg(feature = "client")]
+extern crate serde_json;
 
 pub mod bit_matrix;
 pub mod simhash;
 pub mod minhash;
 pub mod db;
+pub mod evicting_store;
+pub mod error;
+#[cfg(feature = "client")]
+pub mod client;
+
+pub use error::Error;
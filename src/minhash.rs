@@ -1,6 +1,8 @@
 use std;
 use std::marker::PhantomData;
-use std::hash::{Hasher, SipHasher};
+use std::hash::Hasher;
+
+use siphasher::sip::SipHasher;
 
 pub struct MinHash<H=SipHasher> {
     k: usize,
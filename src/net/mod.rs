@@ -0,0 +1,345 @@
+//! Binary protocol server
+//!
+//! HTTP and JSON add milliseconds to lookups which take microseconds, so
+//! clients which can speak it may use a length-prefixed binary protocol over
+//! TCP (`--binary-bind`) or a unix socket (`--binary-socket`) instead.  It
+//! serves the same namespaces as the HTTP server: values added over one are
+//! found by queries over the other, and writes are logged, synced and checked
+//! against degraded namespaces in the same way.
+//!
+//! Each connection sends request frames and reads a reply frame for each, in
+//! order.  Integers are little-endian.
+//!
+//! ```text
+//! length      u32   bytes in the rest of the frame
+//! op          u8    1 add, 2 query, 3 delete
+//! bits        u16   32, 64, 128 or 256
+//! dimensions  u16   elements per vector, or 0 for a binary namespace
+//! tolerance   u16
+//! ns_length   u16
+//! namespace   ns_length bytes of UTF-8
+//! values      the rest of the frame: values back to back, as in
+//!             `application/octet-stream` HTTP bodies
+//! ```
+//!
+//! Replies are
+//!
+//! ```text
+//! length      u32   bytes in the rest of the frame
+//! status      u16   the HTTP status the request would have been answered with
+//! body        the rest of the frame: results if status is 200, otherwise
+//!             the error the HTTP server would have responded with
+//! ```
+//!
+//! with a result for each value, in order:
+//!
+//! Op     | Result
+//! -------+-------------------------------------------------------------------
+//! add    | u8: 0 added, 1 already indexed, 2 failed
+//! query  | u32 count of values found, then the values (nearest first)
+//! delete | u8: 0 deleted, 1 not found, 2 failed
+//!
+//! Connections are served by a thread each, and frames over
+//! `protocol::MAX_FRAME_BYTES` close the connection.  Once the server starts
+//! shutting down every request is answered with a 503.
+
+pub mod protocol;
+
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::fs;
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use iron::prelude::*;
+use iron::response::{ResponseBody, WriteBody};
+use serde::Serialize;
+
+use hammer::db::{Database, Factory, QueryOptions};
+use hammer::db::cost::TooExpensive;
+use hammer::db::hamming::Hamming;
+use hammer::db::health::HealthReport;
+use hammer::Error;
+
+use http::{Config, AddResult, DeleteResult, Queried, canonical_order, error_response};
+use http::binary_handler;
+use http::vector_handler;
+use http::encoding::RawValue;
+use http::manifest::Manifest;
+use http::oplog::Oplog;
+use http::shutdown::Shutdown;
+
+use self::protocol::{Op, Request, Reply, read_frame};
+
+pub type BinaryMap<T> = Arc<RwLock<HashMap<(usize, String), Arc<RwLock<Box<Database<T>>>>>>>;
+pub type VectorMap<T> = Arc<RwLock<HashMap<(usize, usize, String), Arc<RwLock<Box<Database<Vec<T>>>>>>>>;
+
+/// The server's namespaces and the state shared by its writes, as held by
+/// the HTTP server
+///
+#[derive(Clone)]
+pub struct Registry {
+    pub config: Arc<RwLock<Config>>,
+    pub health: Arc<RwLock<HashMap<String, HealthReport>>>,
+    pub manifest: Arc<RwLock<Manifest>>,
+    pub oplog: Arc<RwLock<Option<Oplog>>>,
+    pub shutdown: Arc<Shutdown>,
+    pub b32: BinaryMap<u32>,
+    pub b64: BinaryMap<u64>,
+    pub b128: BinaryMap<[u64; 2]>,
+    pub b256: BinaryMap<[u64; 4]>,
+    pub v32: VectorMap<u32>,
+    pub v64: VectorMap<u64>,
+    pub v128: VectorMap<[u64; 2]>,
+    pub v256: VectorMap<[u64; 4]>,
+}
+
+/// Serves connections to `bind` on a background thread
+///
+pub fn serve_tcp(bind: &str, registry: Registry) -> Result<(), String> {
+    let listener = try!(TcpListener::bind(bind).map_err(|e| format!("unable to bind {}: {}", bind, e)));
+    info!("Serving binary protocol on {}", bind);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    spawn_connection(stream, registry.clone());
+                },
+                Err(e) => warn!("unable to accept binary protocol connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Serves connections to a unix socket at `path` on a background thread,
+/// replacing any socket left there by a previous server
+///
+#[cfg(unix)]
+pub fn serve_unix(path: &Path, registry: Registry) -> Result<(), String> {
+    if path.exists() {
+        try!(fs::remove_file(path).map_err(|e| format!("unable to remove {:?}: {}", path, e)));
+    }
+    let listener = try!(UnixListener::bind(path).map_err(|e| format!("unable to bind {:?}: {}", path, e)));
+    info!("Serving binary protocol on {:?}", path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => spawn_connection(stream, registry.clone()),
+                Err(e) => warn!("unable to accept binary protocol connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve_unix(_: &Path, _: Registry) -> Result<(), String> {
+    Err("unix sockets aren't supported on this platform".to_string())
+}
+
+fn spawn_connection<S: 'static + Read + Write + Send>(mut stream: S, registry: Registry) {
+    thread::spawn(move || {
+        loop {
+            let frame = match read_frame(&mut stream) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return,
+                Err(e) => {
+                    let _ = Reply::error(400, format!("{}", e)).write(&mut stream);
+                    return
+                },
+            };
+
+            // Counted as in progress until the reply is written
+            let in_progress = Shutdown::begin(&registry.shutdown);
+            let reply = match (in_progress.is_some(), Request::parse(&frame)) {
+                (false, _) => Reply::error(503, "Server is shutting down"),
+                (true, Ok(request)) => handle(&request, &QueryOptions::default(), &registry),
+                (true, Err(e)) => Reply::error(400, e),
+            };
+            if reply.write(&mut stream).is_err() {
+                return
+            }
+            drop(in_progress);
+        }
+    });
+}
+
+/// Answers a request, filtering queries' matches according to `options`
+///
+/// Binary protocol frames can't carry query options, so its queries use the
+/// defaults; the gRPC server (see `grpc`) answers its requests here too.
+///
+pub fn handle(request: &Request, options: &QueryOptions, registry: &Registry) -> Reply {
+    match (request.bits, request.dimensions) {
+        (32, 0) => binary(request, options, &registry.b32, registry),
+        (64, 0) => binary(request, options, &registry.b64, registry),
+        (128, 0) => binary(request, options, &registry.b128, registry),
+        (256, 0) => binary(request, options, &registry.b256, registry),
+        (32, _) => vector(request, options, &registry.v32, registry),
+        (64, _) => vector(request, options, &registry.v64, registry),
+        (128, _) => vector(request, options, &registry.v128, registry),
+        (256, _) => vector(request, options, &registry.v256, registry),
+        _ => Reply::error(400, "Unsuported bitsize"),
+    }
+}
+
+fn binary<T>(request: &Request, options: &QueryOptions, dbmap_mx: &BinaryMap<T>, registry: &Registry) -> Reply where
+T: 'static + Sync + Send + Clone + Eq + Hash + Factory + Serialize + RawValue + Hamming,
+{
+    let width = T::raw_width();
+    if request.values.len() % width != 0 {
+        return Reply::error(400, format!("values of {} bytes aren't a multiple of the value width ({} bytes)", request.values.len(), width))
+    }
+    let values: Vec<T> = request.values.chunks(width).map(T::from_raw).collect();
+    let (bits, tolerance, namespace) = (request.bits, request.tolerance, &request.namespace);
+
+    match request.op {
+        Op::Add => {
+            let values = values.into_iter().map(Ok).collect();
+            match binary_handler::add_values(values, bits, tolerance, namespace, &registry.config, &registry.health, &registry.manifest, &registry.oplog, dbmap_mx) {
+                Ok(results) => Reply::ok(results.iter().map(add_code).collect()),
+                Err(response) => rejected(response),
+            }
+        },
+        Op::Query => {
+            let db_mx = { dbmap_mx.read().unwrap().get(&(tolerance, namespace.clone())).cloned() };
+            query(values, options, db_mx, registry, |value, out| value.write_raw(out))
+        },
+        Op::Delete => {
+            let sync = registry.config.read().unwrap().sync_writes;
+            let values = values.into_iter().map(Ok).collect();
+            match binary_handler::remove_values(values, bits, tolerance, namespace, false, sync, &registry.oplog, dbmap_mx) {
+                Ok(results) => Reply::ok(results.iter().map(delete_code).collect()),
+                Err(response) => rejected(response),
+            }
+        },
+    }
+}
+
+fn vector<T>(request: &Request, options: &QueryOptions, dbmap_mx: &VectorMap<T>, registry: &Registry) -> Reply where
+T: 'static + Clone + Serialize + RawValue,
+Vec<T>: Sync + Send + Clone + Eq + Hash + Factory + Hamming,
+{
+    let width = T::raw_width() * request.dimensions;
+    if request.values.len() % width != 0 {
+        return Reply::error(400, format!("values of {} bytes aren't a multiple of the vector width ({} bytes)", request.values.len(), width))
+    }
+    let vectors: Vec<Vec<T>> = request.values.chunks(width).map(|chunk| {
+        chunk.chunks(T::raw_width()).map(T::from_raw).collect()
+    }).collect();
+    let (bits, dimensions, tolerance, namespace) = (request.bits, request.dimensions, request.tolerance, &request.namespace);
+
+    match request.op {
+        Op::Add => {
+            let vectors = vectors.into_iter().map(Ok).collect();
+            match vector_handler::add_vectors(vectors, bits, dimensions, tolerance, namespace, &registry.config, &registry.health, &registry.manifest, &registry.oplog, dbmap_mx) {
+                Ok(results) => Reply::ok(results.iter().map(add_code).collect()),
+                Err(response) => rejected(response),
+            }
+        },
+        Op::Query => {
+            let db_mx = { dbmap_mx.read().unwrap().get(&(dimensions, tolerance, namespace.clone())).cloned() };
+            query(vectors, options, db_mx, registry, |vector, out| {
+                for element in vector.iter() {
+                    element.write_raw(out);
+                }
+            })
+        },
+        Op::Delete => {
+            let sync = registry.config.read().unwrap().sync_writes;
+            let vectors = vectors.into_iter().map(Ok).collect();
+            match vector_handler::remove_vectors(vectors, bits, dimensions, tolerance, namespace, false, sync, &registry.oplog, dbmap_mx) {
+                Ok(results) => Reply::ok(results.iter().map(delete_code).collect()),
+                Err(response) => rejected(response),
+            }
+        },
+    }
+}
+
+/// Queries the namespace with each of `values`, as an unordered HTTP query
+/// would
+///
+fn query<T, F>(values: Vec<T>, options: &QueryOptions, db_mx: Option<Arc<RwLock<Box<Database<T>>>>>, registry: &Registry, write: F) -> Reply where
+T: 'static + Sync + Send + Clone + Eq + Hash + Serialize + Hamming,
+F: Fn(&T, &mut Vec<u8>),
+{
+    let mut body = Vec::new();
+    let db_mx = match db_mx {
+        Some(db_mx) => db_mx,
+        None => {
+            for _ in values.iter() {
+                write_u32(&mut body, 0);
+            }
+            return Reply::ok(body)
+        },
+    };
+    let max_candidates = registry.config.read().unwrap().max_query_candidates;
+    let db = Queried::new(&db_mx);
+
+    if let Some(limit) = max_candidates {
+        for value in values.iter() {
+            match db.estimate_candidates(value) {
+                Ok(estimate) if estimate > limit => {
+                    return rejected(error_response(Error::TooExpensive(TooExpensive{estimated_candidates: estimate, limit: limit})))
+                },
+                Ok(_) => (),
+                Err(e) => return rejected(error_response(e)),
+            }
+        }
+    }
+
+    let found = match db.get_many(&values, options) {
+        Ok(found) => found,
+        Err(e) => return rejected(error_response(e)),
+    };
+
+    for (found, value) in found.into_iter().zip(values.iter()) {
+        let found = found.map(|found| canonical_order(value, found)).unwrap_or(Vec::new());
+        write_u32(&mut body, found.len() as u32);
+        for v in found.iter() {
+            write(v, &mut body);
+        }
+    }
+    Reply::ok(body)
+}
+
+fn add_code(result: &AddResult) -> u8 {
+    match *result {
+        AddResult::Ok => 0,
+        AddResult::Exists => 1,
+        AddResult::Err(_) => 2,
+    }
+}
+
+fn delete_code(result: &DeleteResult) -> u8 {
+    match *result {
+        DeleteResult::Ok | DeleteResult::Verified(_) => 0,
+        DeleteResult::NotFound => 1,
+        DeleteResult::Err(_) => 2,
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, n: u32) {
+    n.write_raw(out)
+}
+
+/// Reply carrying the status & body of an HTTP response rejecting the request
+///
+fn rejected(mut response: Response) -> Reply {
+    let status = response.status.map(|status| status.to_u16()).unwrap_or(500);
+    let mut body = Vec::new();
+    if let Some(mut write) = response.body.take() {
+        let _ = write.write_body(&mut ResponseBody::new(&mut body));
+    }
+    Reply{status: status, body: body}
+}
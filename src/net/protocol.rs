@@ -0,0 +1,199 @@
+//! Framing of binary protocol requests & replies (see `net`)
+
+use std::io::{self, Read, Write};
+use std::str;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Frames larger than this are refused, and the connection closed
+pub const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+// Bytes of a request preceding its namespace
+const HEADER_BYTES: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Query,
+    Delete,
+}
+
+impl Op {
+    fn parse(code: u8) -> Result<Op, String> {
+        match code {
+            1 => Ok(Op::Add),
+            2 => Ok(Op::Query),
+            3 => Ok(Op::Delete),
+            code => Err(format!("unsupported op {}", code)),
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match *self {
+            Op::Add => 1,
+            Op::Query => 2,
+            Op::Delete => 3,
+        }
+    }
+}
+
+/// A request for the values of a frame to be added, queried or deleted
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub op: Op,
+    pub bits: usize,
+    /// Elements per vector, or 0 for a binary namespace
+    pub dimensions: usize,
+    pub tolerance: usize,
+    pub namespace: String,
+    /// Raw little-endian values, back to back
+    pub values: Vec<u8>,
+}
+
+impl Request {
+    /// Parses a request frame (without its length)
+    ///
+    pub fn parse(frame: &[u8]) -> Result<Request, String> {
+        if frame.len() < HEADER_BYTES {
+            return Err(format!("request of {} bytes is shorter than its header", frame.len()))
+        }
+
+        let op = try!(Op::parse(frame[0]));
+        let ns_len = LittleEndian::read_u16(&frame[7..9]) as usize;
+        if frame.len() - HEADER_BYTES < ns_len {
+            return Err(format!("request of {} bytes is shorter than its {} byte namespace", frame.len(), ns_len))
+        }
+        let namespace = try!(str::from_utf8(&frame[HEADER_BYTES..HEADER_BYTES + ns_len]).map_err(|e| format!("namespace isn't UTF-8: {}", e)));
+
+        Ok(Request{
+            op: op,
+            bits: LittleEndian::read_u16(&frame[1..3]) as usize,
+            dimensions: LittleEndian::read_u16(&frame[3..5]) as usize,
+            tolerance: LittleEndian::read_u16(&frame[5..7]) as usize,
+            namespace: namespace.to_string(),
+            values: frame[HEADER_BYTES + ns_len..].to_vec(),
+        })
+    }
+
+    /// Writes the request as a frame
+    ///
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut frame = vec![0; HEADER_BYTES];
+        frame[0] = self.op.code();
+        LittleEndian::write_u16(&mut frame[1..3], self.bits as u16);
+        LittleEndian::write_u16(&mut frame[3..5], self.dimensions as u16);
+        LittleEndian::write_u16(&mut frame[5..7], self.tolerance as u16);
+        LittleEndian::write_u16(&mut frame[7..9], self.namespace.len() as u16);
+        frame.extend_from_slice(self.namespace.as_bytes());
+        frame.extend_from_slice(&self.values);
+
+        write_frame(w, &frame)
+    }
+}
+
+/// The status of a request, and its results or error
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// The HTTP status the same request would have been answered with
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn ok(body: Vec<u8>) -> Reply {
+        Reply{status: 200, body: body}
+    }
+
+    pub fn error<S: Into<String>>(status: u16, message: S) -> Reply {
+        Reply{status: status, body: message.into().into_bytes()}
+    }
+
+    /// Parses a reply frame (without its length)
+    ///
+    pub fn parse(frame: &[u8]) -> Result<Reply, String> {
+        if frame.len() < 2 {
+            return Err(format!("reply of {} bytes is shorter than its status", frame.len()))
+        }
+        Ok(Reply{status: LittleEndian::read_u16(&frame[..2]), body: frame[2..].to_vec()})
+    }
+
+    /// Writes the reply as a frame
+    ///
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut frame = vec![0; 2];
+        LittleEndian::write_u16(&mut frame, self.status);
+        frame.extend_from_slice(&self.body);
+
+        write_frame(w, &frame)
+    }
+}
+
+/// Reads the next frame, returning `None` if the stream ended before one
+/// started
+///
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match try!(r.read(&mut len[read..])) {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended mid-frame")),
+            n => read += n,
+        }
+    }
+
+    let len = LittleEndian::read_u32(&len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes exceeds {}", len, MAX_FRAME_BYTES)))
+    }
+
+    let mut frame = vec![0; len];
+    try!(r.read_exact(&mut frame));
+    Ok(Some(frame))
+}
+
+fn write_frame<W: Write>(w: &mut W, frame: &[u8]) -> io::Result<()> {
+    let mut len = [0; 4];
+    LittleEndian::write_u32(&mut len, frame.len() as u32);
+    try!(w.write_all(&len));
+    try!(w.write_all(frame));
+    w.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use net::protocol::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let request = Request{
+            op: Op::Query,
+            bits: 64,
+            dimensions: 0,
+            tolerance: 4,
+            namespace: "fingerprints".to_string(),
+            values: vec![2, 1, 0, 0, 0, 0, 0, 0],
+        };
+
+        let mut buf = Vec::new();
+        request.write(&mut buf).unwrap();
+
+        let mut reader = &buf[..];
+        let frame = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(Request::parse(&frame), Ok(request));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(Request::parse(&[1, 64, 0]).is_err());
+        assert!(Request::parse(&[9, 64, 0, 0, 0, 4, 0, 0, 0]).is_err());
+        // Namespace longer than the frame
+        assert!(Request::parse(&[1, 64, 0, 0, 0, 4, 0, 8, 0, b'n']).is_err());
+
+        let mut oversized = &[0xff, 0xff, 0xff, 0xff][..];
+        assert!(read_frame(&mut oversized).is_err());
+    }
+}
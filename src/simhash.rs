@@ -1,6 +1,7 @@
 use std::marker::PhantomData;
-use std::hash::{Hasher, SipHasher};
+use std::hash::Hasher;
 
+use siphasher::sip::SipHasher;
 use murmurhash3::{murmurhash3_x64_128};
 use murmurhash3::{murmurhash3_x86_32};
 
@@ -0,0 +1,699 @@
+//! End-to-end tests of the HTTP server
+//!
+//! Each test starts the `hammerhttp` binary on an ephemeral port (with a
+//! fresh data dir when testing persistence) and talks to it over plain
+//! HTTP/1.1, exactly as a client would.
+
+extern crate hammer;
+extern crate base64;
+extern crate serde;
+extern crate serde_json;
+extern crate uuid;
+#[cfg(feature = "grpc")]
+extern crate grpcio;
+#[cfg(feature = "grpc")]
+extern crate prost;
+#[cfg(feature = "grpc")]
+#[macro_use]
+extern crate prost_derive;
+#[cfg(feature = "grpc")]
+extern crate bytes;
+
+// The server's gRPC messages, for a client of its own
+#[cfg(feature = "grpc")]
+#[path = "../src/grpc/messages.rs"]
+mod grpc_messages;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Child, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use hammer::db::encoding::encode;
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// A running `hammerhttp`, killed on drop
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start(data_dir: Option<&Path>) -> Server {
+        Server::start_with(data_dir, &[])
+    }
+
+    fn start_with(data_dir: Option<&Path>, args: &[&str]) -> Server {
+        // Find a free port by letting the OS pick one, then releasing it
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{}", port);
+
+        let mut command = Command::new(server_binary());
+        command.arg(format!("--bind={}", addr))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(dir) = data_dir {
+            command.arg(format!("--data-dir={}", dir.to_str().unwrap()));
+        }
+        command.args(args);
+
+        let server = Server{child: command.spawn().unwrap(), addr: addr};
+        for _ in 0..100 {
+            if TcpStream::connect(&*server.addr).is_ok() {
+                return server
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("hammerhttp didn't start listening on {}", server.addr)
+    }
+
+    fn request(&self, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let (status, body) = self.request_bytes(method, path, body.as_bytes());
+        let body = String::from_utf8(body).unwrap();
+        (status, serde_json::from_str(&body).unwrap_or(Value::String(body)))
+    }
+
+    /// Like `request`, for bodies which aren't text (ie snapshots)
+    fn request_bytes(&self, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(&*self.addr).unwrap();
+        write!(stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method, path, self.addr, body.len(),
+        ).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let head_len = response.windows(4).position(|w| w == b"\r\n\r\n").map_or(response.len(), |i| i + 4);
+        let head = String::from_utf8_lossy(&response[..head_len]).to_lowercase();
+        let status = head.split(' ').nth(1).and_then(|s| s.parse().ok()).unwrap();
+
+        // Streamed responses (ie from ingest) are chunked
+        if head.contains("transfer-encoding: chunked") {
+            (status, dechunk(&response[head_len..]))
+        } else {
+            (status, response[head_len..].to_vec())
+        }
+    }
+
+    fn post(&self, path: &str, body: &str) -> Value {
+        let (status, json) = self.request("POST", path, body);
+        assert_eq!(status, 200);
+        json
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The body of a chunked response
+fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line_len = chunked.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = usize::from_str_radix(String::from_utf8_lossy(&chunked[..line_len]).split(';').next().unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            return body
+        }
+        body.extend_from_slice(&chunked[line_len + 2..line_len + 2 + size]);
+        chunked = &chunked[line_len + 2 + size + 2..];
+    }
+}
+
+/// A data dir for the server, removed on drop
+struct DataDir(PathBuf);
+
+impl DataDir {
+    fn new() -> DataDir {
+        let mut path = env::temp_dir();
+        path.push(format!("hammer-http-test-{}", Uuid::new_v4().to_hyphenated_string()));
+        fs::create_dir_all(&path).unwrap();
+        DataDir(path)
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Cargo builds binaries alongside (or one level above) integration tests
+fn server_binary() -> PathBuf {
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir.join("hammerhttp")
+}
+
+/// Base64-encoded bincode, as the server expects values
+fn b64<T: Serialize>(value: &T) -> String {
+    base64::encode(&encode(value).unwrap())
+}
+
+fn json(s: &str) -> Value {
+    serde_json::from_str(s).unwrap()
+}
+
+/// Adds `a` and `far`, queries with `near`, then deletes `a`
+fn exercise_binary<T: Serialize>(server: &Server, bits: usize, a: T, near: T, far: T) {
+    let ns = format!("/b/{}/4/test", bits);
+    let (a, near, far) = (b64(&a), b64(&near), b64(&far));
+
+    assert_eq!(server.post(&format!("/add{}", ns), &format!(r#"["{}","{}"]"#, a, far)), json(r#"["ok","ok"]"#));
+    assert_eq!(server.post(&format!("/add{}", ns), &format!(r#"["{}"]"#, a)), json(r#"["exists"]"#));
+
+    assert_eq!(server.post(&format!("/query{}", ns), &format!(r#"["{}"]"#, near)), json(&format!(r#"[["{}"]]"#, a)));
+
+    assert_eq!(server.post(&format!("/delete{}", ns), &format!(r#"["{}"]"#, a)), json(r#"["ok"]"#));
+    assert_eq!(server.post(&format!("/delete{}", ns), &format!(r#"["{}"]"#, a)), json(r#"["not_found"]"#));
+    assert_eq!(server.post(&format!("/query{}", ns), &format!(r#"["{}"]"#, near)), json(r#"["none"]"#));
+}
+
+/// Like `exercise_binary`, for 4-element vectors
+fn exercise_vector<T: Serialize>(server: &Server, bits: usize, a: Vec<T>, near: Vec<T>, far: Vec<T>) {
+    let ns = format!("/v/{}/4/1/test", bits);
+    let encode = |v: &Vec<T>| format!("[{}]", v.iter().map(|e| format!(r#""{}""#, b64(e))).collect::<Vec<String>>().join(","));
+    let (a, near, far) = (encode(&a), encode(&near), encode(&far));
+
+    assert_eq!(server.post(&format!("/add{}", ns), &format!("[{},{}]", a, far)), json(r#"["ok","ok"]"#));
+    assert_eq!(server.post(&format!("/add{}", ns), &format!("[{}]", a)), json(r#"["exists"]"#));
+
+    assert_eq!(server.post(&format!("/query{}", ns), &format!("[{}]", near)), json(&format!("[[{}]]", a)));
+
+    assert_eq!(server.post(&format!("/delete{}", ns), &format!("[{}]", a)), json(r#"["ok"]"#));
+    assert_eq!(server.post(&format!("/query{}", ns), &format!("[{}]", near)), json(r#"["none"]"#));
+}
+
+fn exercise_all(server: &Server) {
+    exercise_binary(server, 32, 0u32, 0b101, !0);
+    exercise_binary(server, 64, 0u64, 0b101, !0);
+    exercise_binary(server, 128, [0u64; 2], [0b101, 0], [!0, !0]);
+    exercise_binary(server, 256, [0u64; 4], [0, 0b101, 0, 0], [!0, !0, !0, !0]);
+
+    exercise_vector(server, 32, vec![1u32, 2, 3, 4], vec![1, 2, 3, 5], vec![9, 9, 9, 9]);
+    exercise_vector(server, 64, vec![1u64, 2, 3, 4], vec![1, 2, 3, 5], vec![9, 9, 9, 9]);
+    exercise_vector(server, 128, vec![[1u64, 0], [2, 0], [3, 0], [4, 0]], vec![[1, 0], [2, 0], [3, 0], [5, 0]], vec![[9, 9]; 4]);
+    exercise_vector(server, 256, vec![[1u64, 0, 0, 0], [2, 0, 0, 0], [3, 0, 0, 0], [4, 0, 0, 0]], vec![[1, 0, 0, 0], [2, 0, 0, 0], [3, 0, 0, 0], [5, 0, 0, 0]], vec![[9, 9, 9, 9]; 4]);
+}
+
+#[test]
+fn in_memory_add_query_delete() {
+    let server = Server::start(None);
+    exercise_all(&server);
+}
+
+#[cfg(feature = "client")]
+#[test]
+fn client_add_query_delete() {
+    use hammer::client::{Client, AddResult, QueryResult, DeleteResult};
+
+    let server = Server::start(None);
+    let client = Client::new(&format!("http://{}", server.addr));
+
+    let ns = client.binary::<[u64; 2]>(4, "client");
+    assert_eq!(ns.add(&[[0, 0], [!0, !0]]), Ok(vec![AddResult::Ok, AddResult::Ok]));
+    assert_eq!(ns.add(&[[0, 0]]), Ok(vec![AddResult::Exists]));
+    assert_eq!(ns.query(&[[0b101, 0], [0, !0]]), Ok(vec![QueryResult::Ok(vec![[0, 0]]), QueryResult::None]));
+    assert_eq!(ns.delete(&[[0, 0], [0, 0]]), Ok(vec![DeleteResult::Ok, DeleteResult::NotFound]));
+
+    let vectors = client.vector::<u32>(4, 1, "client");
+    assert_eq!(vectors.add(&[vec![1, 2, 3, 4]]), Ok(vec![AddResult::Ok]));
+    assert_eq!(vectors.query(&[vec![1, 2, 3, 5]]), Ok(vec![QueryResult::Ok(vec![vec![1, 2, 3, 4]])]));
+}
+
+/// Sends a binary protocol request frame, returning the reply's status & body
+fn binary_request(stream: &mut TcpStream, op: u8, bits: u16, namespace: &str, values: &[u8]) -> (u16, Vec<u8>) {
+    let mut frame = vec![op];
+    for n in &[bits, 0, 4, namespace.len() as u16] {
+        frame.extend_from_slice(&[*n as u8, (*n >> 8) as u8]);
+    }
+    frame.extend_from_slice(namespace.as_bytes());
+    frame.extend_from_slice(values);
+
+    let len = frame.len() as u32;
+    stream.write_all(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]).unwrap();
+    stream.write_all(&frame).unwrap();
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).unwrap();
+    let len = len.iter().rev().fold(0, |n, b| (n << 8) | *b as usize);
+    let mut reply = vec![0; len];
+    stream.read_exact(&mut reply).unwrap();
+    (reply[0] as u16 | (reply[1] as u16) << 8, reply[2..].to_vec())
+}
+
+#[test]
+fn binary_protocol_shares_namespaces() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let binary_addr = format!("127.0.0.1:{}", port);
+    let server = Server::start_with(None, &[&format!("--binary-bind={}", binary_addr)]);
+    let mut stream = TcpStream::connect(&*binary_addr).unwrap();
+
+    let (a, far) = ([0u8; 8], [0xffu8; 8]);
+    let near = [5u8, 0, 0, 0, 0, 0, 0, 0];
+
+    let values: Vec<u8> = a.iter().chain(far.iter()).cloned().collect();
+    assert_eq!(binary_request(&mut stream, 1, 64, "test", &values), (200, vec![0, 0]));
+    assert_eq!(binary_request(&mut stream, 1, 64, "test", &a), (200, vec![1]));
+
+    // Found by queries over either protocol
+    assert_eq!(binary_request(&mut stream, 2, 64, "test", &near), (200, vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(server.post("/query/b/64/4/test", &format!(r#"["{}"]"#, b64(&5u64))), json(&format!(r#"[["{}"]]"#, b64(&0u64))));
+
+    assert_eq!(binary_request(&mut stream, 3, 64, "test", &a), (200, vec![0]));
+    assert_eq!(binary_request(&mut stream, 2, 64, "test", &near), (200, vec![0, 0, 0, 0]));
+    assert_eq!(binary_request(&mut stream, 2, 64, "test", &near[..3]).0, 400);
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_shares_namespaces() {
+    use std::sync::Arc;
+    use grpcio::{CallOption, ChannelBuilder, Client, EnvBuilder, RpcStatusCode};
+    use grpc_messages::{self as messages, Namespace, AddRequest, AddResult, QueryRequest, Matches, DeleteRequest, DeleteResult};
+    use grpc_messages::{METHOD_ADD, METHOD_QUERY, METHOD_DELETE};
+
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let grpc_addr = format!("127.0.0.1:{}", port);
+    let server = Server::start_with(None, &[&format!("--grpc-bind={}", grpc_addr)]);
+    let client = Client::new(ChannelBuilder::new(Arc::new(EnvBuilder::new().build())).connect(&grpc_addr));
+    let call = CallOption::default;
+
+    let namespace = Some(Namespace{bits: 64, dimensions: 0, tolerance: 4, name: "test".to_string()});
+    let value = |v: u8| messages::Value{elements: vec![vec![v, 0, 0, 0, 0, 0, 0, 0]]};
+    let far = messages::Value{elements: vec![vec![0xff; 8]]};
+
+    let add = AddRequest{namespace: namespace.clone(), values: vec![value(0), value(3), far]};
+    assert_eq!(client.unary_call(&METHOD_ADD, &add, call()).unwrap().results, vec![AddResult::Ok as i32; 3]);
+
+    // Found by queries over either protocol, nearest first (ties broken by
+    // their bytes)
+    let query = QueryRequest{namespace: namespace.clone(), values: vec![value(7), value(1)], exclude_exact: false};
+    assert_eq!(client.unary_call(&METHOD_QUERY, &query, call()).unwrap().results, vec![
+        Matches{values: vec![value(3), value(0)]},
+        Matches{values: vec![value(0), value(3)]},
+    ]);
+    assert_eq!(server.post("/query/b/64/4/test", &format!(r#"["{}"]"#, b64(&7u64))), json(&format!(r#"[["{}","{}"]]"#, b64(&3u64), b64(&0u64))));
+
+    let exact = QueryRequest{namespace: namespace.clone(), values: vec![value(0)], exclude_exact: true};
+    assert_eq!(client.unary_call(&METHOD_QUERY, &exact, call()).unwrap().results, vec![Matches{values: vec![value(3)]}]);
+
+    let delete = DeleteRequest{namespace: namespace.clone(), values: vec![value(0), value(0)]};
+    assert_eq!(client.unary_call(&METHOD_DELETE, &delete, call()).unwrap().results, vec![DeleteResult::Ok as i32, DeleteResult::NotFound as i32]);
+
+    let malformed = AddRequest{namespace: namespace.clone(), values: vec![messages::Value{elements: vec![vec![0; 3]]}]};
+    match client.unary_call(&METHOD_ADD, &malformed, call()) {
+        Err(grpcio::Error::RpcFailure(status)) => assert_eq!(status.status, RpcStatusCode::InvalidArgument),
+        other => panic!("expected InvalidArgument, got {:?}", other),
+    }
+}
+
+#[test]
+fn rocksdb_add_query_delete() {
+    let data_dir = DataDir::new();
+    let server = Server::start(Some(&data_dir.0));
+    exercise_all(&server);
+}
+
+#[test]
+fn sigterm_shuts_down_cleanly() {
+    let data_dir = DataDir::new();
+    let a = b64(&0u64);
+
+    {
+        let mut server = Server::start(Some(&data_dir.0));
+        assert_eq!(server.post("/add/b/64/4/persisted", &format!(r#"["{}"]"#, a)), json(r#"["ok"]"#));
+
+        Command::new("kill").arg("-TERM").arg(server.child.id().to_string()).status().unwrap();
+        assert!(server.child.wait().unwrap().success());
+    }
+    assert!(data_dir.0.join("CLEAN_SHUTDOWN").exists());
+
+    // The marker is taken by the next server
+    let server = Server::start(Some(&data_dir.0));
+    assert!(!data_dir.0.join("CLEAN_SHUTDOWN").exists());
+    assert_eq!(server.post("/query/b/64/4/persisted", &format!(r#"["{}"]"#, a)), json(&format!(r#"[["{}"]]"#, a)));
+}
+
+#[test]
+fn rocksdb_persists_across_restart() {
+    let data_dir = DataDir::new();
+    let (a, b, c) = (b64(&0u64), b64(&0xFFFFu64), b64(&0xFFFF0000u64));
+
+    {
+        let server = Server::start(Some(&data_dir.0));
+        assert_eq!(server.post("/add/b/64/4/persisted", &format!(r#"["{}","{}"]"#, a, b)), json(r#"["ok","ok"]"#));
+    }
+
+    let server = Server::start(Some(&data_dir.0));
+
+    // Namespaces are re-opened on startup, so can be queried before they're
+    // written to
+    assert_eq!(server.post("/query/b/64/4/persisted", &format!(r#"["{}"]"#, b64(&1u64))), json(&format!(r#"[["{}"]]"#, a)));
+
+    assert_eq!(server.post("/add/b/64/4/persisted", &format!(r#"["{}"]"#, c)), json(r#"["ok"]"#));
+    assert_eq!(server.post("/add/b/64/4/persisted", &format!(r#"["{}"]"#, a)), json(r#"["exists"]"#));
+    assert_eq!(server.post("/query/b/64/4/persisted", &format!(r#"["{}"]"#, b64(&1u64))), json(&format!(r#"[["{}"]]"#, a)));
+
+    let (status, _) = server.request("GET", "/readyz", "");
+    assert_eq!(status, 200);
+}
+
+#[test]
+fn column_family_namespaces_persist_and_drop() {
+    let data_dir = DataDir::new();
+    let (a, b) = (b64(&0u64), b64(&0xFFFFu64));
+
+    {
+        let server = Server::start_with(Some(&data_dir.0), &["--column-families"]);
+        exercise_all(&server);
+        assert_eq!(server.post("/add/b/64/4/persisted", &format!(r#"["{}","{}"]"#, a, b)), json(r#"["ok","ok"]"#));
+        assert_eq!(server.post("/add/b/64/4/dropped", &format!(r#"["{}"]"#, a)), json(r#"["ok"]"#));
+        assert_eq!(server.request("DELETE", "/db/b/64/4/dropped", ""), (200, json(r#"{"dropped":true}"#)));
+    }
+    assert!(data_dir.0.join("rocksdb").exists());
+
+    let server = Server::start_with(Some(&data_dir.0), &["--column-families"]);
+    assert_eq!(server.post("/query/b/64/4/persisted", &format!(r#"["{}"]"#, b64(&1u64))), json(&format!(r#"[["{}"]]"#, a)));
+
+    // Re-creating a dropped namespace doesn't bring back its values
+    assert_eq!(server.post("/add/b/64/4/dropped", &format!(r#"["{}"]"#, b)), json(r#"["ok"]"#));
+    assert_eq!(server.post("/query/b/64/4/dropped", &format!(r#"["{}"]"#, a)), json(r#"["none"]"#));
+}
+
+#[test]
+fn namespaces_can_be_listed_created_and_dropped() {
+    let data_dir = DataDir::new();
+    let server = Server::start(Some(&data_dir.0));
+
+    assert_eq!(server.request("PUT", "/db/b/64/4/created", ""), (201, json(r#"{"created":true}"#)));
+    assert_eq!(server.request("PUT", "/db/b/64/4/created", ""), (200, json(r#"{"created":false}"#)));
+    assert_eq!(server.post("/add/v/32/4/1/added", &format!(r#"[["{}","{}","{}","{}"]]"#, b64(&1u32), b64(&2u32), b64(&3u32), b64(&4u32))), json(r#"["ok"]"#));
+
+    let (status, dbs) = server.request("GET", "/dbs", "");
+    assert_eq!(status, 200);
+    assert_eq!(dbs, json(r#"[
+        {"bits":32,"dimensions":4,"tolerance":1,"namespace":"added","degraded":false},
+        {"bits":64,"dimensions":null,"tolerance":4,"namespace":"created","degraded":false}
+    ]"#));
+
+    assert_eq!(server.request("DELETE", "/db/v/32/4/1/added", ""), (200, json(r#"{"dropped":true}"#)));
+    assert_eq!(server.request("DELETE", "/db/v/32/4/1/added", "").0, 404);
+    assert!(!data_dir.0.join("v032_004_001_added").exists());
+
+    let (_, dbs) = server.request("GET", "/dbs", "");
+    assert_eq!(dbs.as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn namespaces_can_ignore_dimensions() {
+    let server = Server::start(None);
+    let a = b64(&0u64);
+
+    assert_eq!(server.request("PUT", "/db/b/64/4/masked?ignore=64", "").0, 400);
+    assert_eq!(server.request("PUT", "/db/b/64/4/masked?ignore=0,1,2,3,4,5,6,7", ""), (201, json(r#"{"created":true}"#)));
+    assert_eq!(server.post("/add/b/64/4/masked", &format!(r#"["{}"]"#, a)), json(r#"["ok"]"#));
+
+    assert_eq!(server.post("/query/b/64/4/masked", &format!(r#"["{}"]"#, b64(&0xFFu64))), json(&format!(r#"[["{}"]]"#, a)));
+    assert_eq!(server.post("/query/b/64/4/masked", &format!(r#"["{}"]"#, b64(&0xFF00u64))), json(r#"["none"]"#));
+}
+
+#[test]
+fn query_results_can_be_paged() {
+    let server = Server::start(None);
+    let values = [b64(&0b0001u64), b64(&0b0010u64), b64(&0b0100u64)];
+    let body = format!(r#"["{}","{}","{}"]"#, values[0], values[1], values[2]);
+    assert_eq!(server.post("/add/b/64/4/paged", &body), json(r#"["ok","ok","ok"]"#));
+
+    let query = format!(r#"["{}"]"#, b64(&0u64));
+    let (status, first) = server.request("POST", "/query/b/64/4/paged?limit=2", &query);
+    assert_eq!(status, 200);
+    let first = &first.as_array().unwrap()[0];
+    assert_eq!(first["values"].as_array().unwrap().len(), 2);
+    let next = first["next"].as_str().unwrap().to_string();
+
+    let (status, second) = server.request("POST", &format!("/query/b/64/4/paged?limit=2&after={}", next), &query);
+    assert_eq!(status, 200);
+    let second = &second.as_array().unwrap()[0];
+    assert_eq!(second["next"], Value::Null);
+
+    let mut paged: Vec<String> = first["values"].as_array().unwrap().iter()
+        .chain(second["values"].as_array().unwrap().iter())
+        .map(|v| v.as_str().unwrap().to_string())
+        .collect();
+    paged.sort();
+    let mut expected = values.to_vec();
+    expected.sort();
+    assert_eq!(paged, expected);
+
+    // Cursors belong to a single key's results
+    let queries = format!(r#"["{}","{}"]"#, b64(&0u64), b64(&0u64));
+    assert_eq!(server.request("POST", &format!("/query/b/64/4/paged?limit=2&after={}", next), &queries).0, 400);
+    assert_eq!(server.request("POST", "/query/b/64/4/paged?limit=0", &query).0, 400);
+    assert_eq!(server.request("POST", "/query/b/64/4/paged?limit=2&order=recent", &query).0, 400);
+}
+
+#[test]
+fn namespaces_can_be_warmed() {
+    let server = Server::start(None);
+    assert_eq!(server.post("/add/b/64/4/warmed", &format!(r#"["{}"]"#, b64(&0u64))), json(r#"["ok"]"#));
+
+    // In-memory namespaces have nothing to read
+    assert_eq!(server.request("POST", "/db/b/64/4/warmed/warmup?fraction=0.5", ""), (200, json(r#"{"values_read":0,"variants_read":0}"#)));
+    assert_eq!(server.request("POST", "/db/b/64/4/warmed/warmup?fraction=2", "").0, 400);
+    assert_eq!(server.request("POST", "/db/b/64/4/missing/warmup", "").0, 404);
+}
+
+#[test]
+fn ingest_adds_each_line() {
+    let server = Server::start(None);
+    let body = format!("{}\n\nnot-base64\n{}\n{}\n", b64(&0u64), b64(&0xFF00u64), b64(&0u64));
+
+    let (status, response) = server.request("POST", "/ingest/b/64/4/ingested", &body);
+    assert_eq!(status, 200);
+
+    let lines: Vec<&str> = response.as_str().unwrap().lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], r#""ok""#);
+    assert!(lines[1].starts_with(r#""err: unable to base64-decode"#));
+    assert_eq!(lines[2], r#""ok""#);
+    assert_eq!(lines[3], r#""exists""#);
+
+    assert_eq!(server.post("/query/b/64/4/ingested", &format!(r#"["{}"]"#, b64(&1u64))), json(&format!(r#"[["{}"]]"#, b64(&0u64))));
+}
+
+#[test]
+fn ingest_reports_later_chunk_failures_per_line() {
+    let server = Server::start(None);
+    assert_eq!(server.request("PUT", "/db/b/64/4/ingested?max_values=1000", "").0, 201);
+    let body: String = (0..1500u64).map(|i| format!("{}\n", b64(&(i << 8)))).collect();
+
+    let (status, response) = server.request("POST", "/ingest/b/64/4/ingested", &body);
+    assert_eq!(status, 200);
+
+    // The first chunk of 1000 fills the namespace, so the second is refused
+    let lines: Vec<&str> = response.as_str().unwrap().lines().collect();
+    assert_eq!(lines.len(), 1500);
+    assert!(lines[..1000].iter().all(|line| *line == r#""ok""#));
+    assert!(lines[1000..].iter().all(|line| line.starts_with(r#""err: "#) && line.contains("max_values")));
+}
+
+#[test]
+fn queries_keep_their_place_among_many_keys() {
+    let server = Server::start(None);
+    let a = b64(&0u64);
+    server.post("/add/b/64/4/many", &format!(r#"["{}"]"#, a));
+
+    let body = format!(r#"["{}","not-base64","{}","{}"]"#, b64(&1u64), b64(&0xFFFFFFFFu64), b64(&1u64));
+    let results = server.post("/query/b/64/4/many", &body);
+    let results = results.as_array().unwrap();
+
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0], json(&format!(r#"["{}"]"#, a)));
+    assert!(results[1].as_str().unwrap().starts_with("err: "));
+    assert_eq!(results[2], json(r#""none""#));
+    assert_eq!(results[3], json(&format!(r#"["{}"]"#, a)));
+}
+
+#[test]
+fn metrics_count_requests_and_values() {
+    let server = Server::start(None);
+    server.post("/add/b/64/4/metered", &format!(r#"["{}","{}"]"#, b64(&0u64), b64(&0u64)));
+    server.post("/query/b/64/4/metered", &format!(r#"["{}"]"#, b64(&1u64)));
+
+    let (status, body) = server.request("GET", "/metrics", "");
+    assert_eq!(status, 200);
+
+    let body = body.as_str().unwrap().to_string();
+    assert!(body.contains(r#"hammer_values_total{operation="add",result="ok"} 1"#));
+    assert!(body.contains(r#"hammer_values_total{operation="add",result="exists"} 1"#));
+    assert!(body.contains(r#"hammer_values_total{operation="query",result="hit"} 1"#));
+    assert!(body.contains(r#"hammer_http_requests_total{handler="add",code="200"} 1"#));
+    assert!(body.contains(r#"hammer_namespace_values{namespace="b064_004_metered"} 1"#));
+    assert!(body.contains(r#"hammer_namespace_bucket_size{namespace="b064_004_metered",partition="0",quantile="1"} 1"#));
+}
+
+#[test]
+fn oversized_and_excessive_requests_are_refused() {
+    let server = Server::start_with(None, &["--max-body-bytes=64", "--max-request-keys=2"]);
+
+    let (status, _) = server.request("POST", "/add/b/64/4/limited", &format!(r#"["{}"]"#, b64(&0u64)));
+    assert_eq!(status, 200);
+
+    let large = format!(r#"["{}"]"#, "A".repeat(100));
+    let (status, body) = server.request("POST", "/add/b/64/4/limited", &large);
+    assert_eq!(status, 413);
+    assert_eq!(body["error"], json(r#""too_large""#));
+
+    let many = format!(r#"["{}","{}","{}"]"#, b64(&1u32), b64(&2u32), b64(&3u32));
+    let (status, _) = server.request("POST", "/add/b/32/4/limited", &many);
+    assert_eq!(status, 413);
+}
+
+#[test]
+fn clients_are_rate_limited() {
+    let server = Server::start_with(None, &["--rate-limit=1"]);
+    let body = format!(r#"["{}"]"#, b64(&0u64));
+
+    let (status, _) = server.request("POST", "/query/b/64/4/limited", &body);
+    assert_eq!(status, 200);
+    let (status, response) = server.request("POST", "/query/b/64/4/limited", &body);
+    assert_eq!(status, 429);
+    assert_eq!(response["error"], json(r#""rate_limited""#));
+
+    let (status, _) = server.request("GET", "/readyz", "");
+    assert_eq!(status, 200);
+}
+
+#[test]
+fn tuning_limits_and_caches_namespaces() {
+    let data_dir = DataDir::new();
+    let server = Server::start(Some(&data_dir.0));
+    let body = format!(r#"["{}"]"#, b64(&0u64));
+
+    assert_eq!(server.request("PUT", "/db/b/64/4/tuned", "").0, 201);
+    assert_eq!(server.request("PUT", "/db/b/64/4/tuned/tuning", r#"{"cache_size":16,"rate_limit":1}"#), (200, json(r#"{"cache_size":16,"rate_limit":1}"#)));
+    assert_eq!(server.request("PUT", "/db/b/64/4/tuned/tuning", r#"{"query_parallelism":4}"#).0, 400);
+
+    assert_eq!(server.request("POST", "/query/b/64/4/tuned", &body).0, 200);
+    let (status, response) = server.request("POST", "/query/b/64/4/tuned", &body);
+    assert_eq!(status, 429);
+    assert_eq!(response["quota"], json(r#""rate_limit""#));
+
+    let in_memory = Server::start(None);
+    assert_eq!(in_memory.request("PUT", "/db/b/64/4/tuned", "").0, 201);
+    assert_eq!(in_memory.request("PUT", "/db/b/64/4/tuned/tuning", r#"{"cache_size":16}"#).0, 400);
+}
+
+#[test]
+fn oplog_replays_writes_on_another_server() {
+    let (primary_dir, secondary_dir) = (DataDir::new(), DataDir::new());
+    let primary = Server::start_with(Some(&primary_dir.0), &["--oplog"]);
+    let secondary = Server::start(Some(&secondary_dir.0));
+    let (a, b) = (b64(&0u64), b64(&0xFFFF0000u64));
+
+    primary.post("/add/b/64/4/replicated", &format!(r#"["{}","{}","not-base64"]"#, a, b));
+    primary.post("/delete/b/64/4/replicated", &format!(r#"["{}"]"#, b));
+    primary.post("/add/v/32/4/1/replicated", &format!(r#"[["{}","{}","{}","{}"]]"#, b64(&1u32), b64(&2u32), b64(&3u32), b64(&4u32)));
+
+    let (status, log) = primary.request("GET", "/oplog?after=0", "");
+    assert_eq!(status, 200);
+    let log = log.as_str().unwrap().to_string();
+    assert_eq!(log.lines().count(), 3);
+
+    assert_eq!(secondary.post("/oplog/replay", &log), json(r#"{"replayed":3,"applied":3,"skipped":0}"#));
+    assert_eq!(secondary.post("/oplog/replay", &log), json(r#"{"replayed":3,"applied":0,"skipped":3}"#));
+    assert_eq!(secondary.request("GET", "/oplog/replayed", "").1, json(r#"{"replayed":3,"applied":0,"skipped":0}"#));
+
+    assert_eq!(secondary.post("/query/b/64/4/replicated", &format!(r#"["{}","{}"]"#, b64(&1u64), b)), json(&format!(r#"[["{}"],"none"]"#, a)));
+    assert_eq!(secondary.post("/add/v/32/4/1/replicated", &format!(r#"[["{}","{}","{}","{}"]]"#, b64(&1u32), b64(&2u32), b64(&3u32), b64(&4u32))), json(r#"["exists"]"#));
+
+    let (status, tail) = primary.request("GET", "/oplog?after=2&limit=5", "");
+    assert_eq!(status, 200);
+    // A single entry parses as one JSON object
+    assert_eq!(tail.get("sequence").and_then(|s| s.as_u64()), Some(3));
+
+    assert_eq!(secondary.request("GET", "/oplog", "").0, 404);
+}
+
+#[test]
+fn snapshots_move_namespaces_between_servers() {
+    let data_dir = DataDir::new();
+    let source = Server::start(None);
+    let target = Server::start(Some(&data_dir.0));
+    let (a, b) = (b64(&0u64), b64(&0xFFFF0000u64));
+
+    source.post("/add/b/64/4/moved", &format!(r#"["{}","{}"]"#, a, b));
+    source.post("/add/v/32/4/1/moved", &format!(r#"[["{}","{}","{}","{}"]]"#, b64(&1u32), b64(&2u32), b64(&3u32), b64(&4u32)));
+
+    let (status, snapshot) = source.request_bytes("GET", "/db/b/64/4/moved/export", b"");
+    assert_eq!(status, 200);
+    assert_eq!(target.request_bytes("POST", "/db/b/64/4/moved/import", &snapshot).0, 200);
+    assert_eq!(target.post("/query/b/64/4/moved", &format!(r#"["{}"]"#, b64(&1u64))), json(&format!(r#"[["{}"]]"#, a)));
+    assert_eq!(target.post("/add/b/64/4/moved", &format!(r#"["{}"]"#, b)), json(r#"["exists"]"#));
+
+    // Vectors must match the importing namespace's dimensions
+    let (status, snapshot) = source.request_bytes("GET", "/db/v/32/4/1/moved/export", b"");
+    assert_eq!(status, 200);
+    assert_eq!(target.request_bytes("POST", "/db/v/32/8/1/moved/import", &snapshot).0, 400);
+    assert_eq!(target.request_bytes("POST", "/db/v/32/4/1/moved/import", &snapshot).0, 200);
+
+    assert_eq!(source.request_bytes("GET", "/db/b/64/4/missing/export", b"").0, 404);
+    assert_eq!(target.request_bytes("POST", "/db/b/64/4/moved/import", b"not a snapshot").0, 400);
+}
+
+#[test]
+fn limits_change_when_the_config_is_reloaded() {
+    let dir = DataDir::new();
+    let config_file = dir.0.join("hammer.json");
+    fs::write(&config_file, r#"{"max_request_keys": 3}"#).unwrap();
+    let server = Server::start_with(None, &[&format!("--config={}", config_file.to_str().unwrap()), "--max-request-keys=1"]);
+
+    let two = format!(r#"["{}","{}"]"#, b64(&1u64), b64(&2u64));
+    let (status, _) = server.request("POST", "/add/b/64/4/reloaded", &two);
+    assert_eq!(status, 200);
+
+    fs::write(&config_file, r#"{}"#).unwrap();
+    let settings = server.post("/config/reload", "");
+    assert_eq!(settings["max_request_keys"], json("1"));
+    let (status, _) = server.request("POST", "/add/b/64/4/reloaded", &two);
+    assert_eq!(status, 413);
+
+    fs::write(&config_file, r#"{"rate_limit": -1}"#).unwrap();
+    let (status, _) = server.request("POST", "/config/reload", "");
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn health_checks_round_trip_a_sentinel() {
+    let data_dir = DataDir::new();
+    let server = Server::start(Some(&data_dir.0));
+    server.post("/add/b/64/4/healthy", &format!(r#"["{}"]"#, b64(&0u64)));
+
+    let (status, body) = server.request("GET", "/healthz", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["storage"]["round_trip"]["ok"], json("true"));
+
+    let (status, body) = server.request("GET", "/readyz", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["unopened"], json("[]"));
+    assert_eq!(body["storage"]["writable"]["ok"], json("true"));
+
+    // The sentinel namespace isn't listed
+    let (_, dbs) = server.request("GET", "/dbs", "");
+    assert_eq!(dbs.as_array().unwrap().len(), 1);
+}
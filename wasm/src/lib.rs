@@ -0,0 +1,110 @@
+//! WebAssembly bindings
+//!
+//! Exposes hammer's in-memory databases to JavaScript, built for
+//! `wasm32-unknown-unknown` with `wasm-pack build`:
+//!
+//! ```js
+//! const db = new SubstitutionDB(4);
+//! db.insert(0b1011n);
+//! db.get(0b1001n);        // BigUint64Array [11n]
+//!
+//! const vectors = new DeletionDB(3, 1);
+//! vectors.insert(new Uint8Array([1, 2, 3]));
+//! vectors.get(new Uint8Array([1, 2, 4]));   // [Uint8Array [1, 2, 3]]
+//! ```
+//!
+//! `SubstitutionDB` indexes 64-bit values, passed as `BigInt`s.
+//! `DeletionDB` indexes byte vectors, passed as `Uint8Array`s, with each byte
+//! a dimension.  Both are kept in memory, as wasm32 has no RocksDB, and
+//! failures are thrown as JavaScript errors.
+
+use std::collections::HashSet;
+
+use js_sys::{Array, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+use hammer::db::{Database, Factory, StorageBackend};
+use hammer::db::builder::DbBuilder;
+use hammer::db::hashing::State;
+use hammer::Error;
+
+fn db_error(e: Error) -> JsValue {
+    js_sys::Error::new(&e.to_string()).into()
+}
+
+fn build<T: Factory>(dimensions: usize, tolerance: usize) -> Result<Box<dyn Database<T>>, JsValue> {
+    DbBuilder::<T>::new()
+        .dimensions(dimensions)
+        .tolerance(tolerance)
+        .backend(StorageBackend::InMemory)
+        .build()
+        .map_err(|e| js_sys::RangeError::new(&e.to_string()).into())
+}
+
+/// Values within tolerance, smallest first
+fn sorted<T: Ord>(found: Option<HashSet<T, State>>) -> Vec<T> {
+    let mut values: Vec<T> = found.map_or(Vec::new(), |found| found.into_iter().collect());
+    values.sort();
+    values
+}
+
+/// Indexes 64-bit values by substitution
+///
+#[wasm_bindgen]
+pub struct SubstitutionDB {
+    db: Box<dyn Database<u64>>,
+}
+
+#[wasm_bindgen]
+impl SubstitutionDB {
+    #[wasm_bindgen(constructor)]
+    pub fn new(tolerance: usize) -> Result<SubstitutionDB, JsValue> {
+        Ok(SubstitutionDB{db: build(64, tolerance)?})
+    }
+
+    /// Adds `value`, returning false if it was already present
+    pub fn insert(&mut self, value: u64) -> Result<bool, JsValue> {
+        self.db.insert(value).map_err(db_error)
+    }
+
+    /// Returns the values within tolerance of `value`
+    pub fn get(&self, value: u64) -> Result<Vec<u64>, JsValue> {
+        self.db.get(&value).map(sorted).map_err(db_error)
+    }
+
+    /// Removes `value`, returning false if it wasn't present
+    pub fn remove(&mut self, value: u64) -> Result<bool, JsValue> {
+        self.db.remove(&value).map_err(db_error)
+    }
+}
+
+/// Indexes byte vectors by deletion
+///
+#[wasm_bindgen]
+pub struct DeletionDB {
+    db: Box<dyn Database<Vec<u8>>>,
+}
+
+#[wasm_bindgen]
+impl DeletionDB {
+    #[wasm_bindgen(constructor)]
+    pub fn new(dimensions: usize, tolerance: usize) -> Result<DeletionDB, JsValue> {
+        Ok(DeletionDB{db: build(dimensions, tolerance)?})
+    }
+
+    /// Adds `value`, returning false if it was already present
+    pub fn insert(&mut self, value: &[u8]) -> Result<bool, JsValue> {
+        self.db.insert(value.to_vec()).map_err(db_error)
+    }
+
+    /// Returns the values within tolerance of `value`
+    pub fn get(&self, value: &[u8]) -> Result<Array, JsValue> {
+        let found = self.db.get(&value.to_vec()).map_err(db_error)?;
+        Ok(sorted(found).iter().map(|v| JsValue::from(Uint8Array::from(&v[..]))).collect())
+    }
+
+    /// Removes `value`, returning false if it wasn't present
+    pub fn remove(&mut self, value: &[u8]) -> Result<bool, JsValue> {
+        self.db.remove(&value.to_vec()).map_err(db_error)
+    }
+}
@@ -0,0 +1,32 @@
+use js_sys::Uint8Array;
+use wasm_bindgen_test::*;
+
+use hammer_wasm::{DeletionDB, SubstitutionDB};
+
+#[wasm_bindgen_test]
+fn substitution_round_trip() {
+    let mut db = SubstitutionDB::new(4).unwrap();
+    assert_eq!(db.insert(0xF).unwrap(), true);
+    assert_eq!(db.insert(0xF).unwrap(), false);
+    assert_eq!(db.insert(0xF0).unwrap(), true);
+
+    assert_eq!(db.get(0xFF).unwrap(), vec![0xF, 0xF0]);
+    assert_eq!(db.remove(0xF).unwrap(), true);
+    assert_eq!(db.get(0xF).unwrap(), Vec::<u64>::new());
+}
+
+#[wasm_bindgen_test]
+fn deletion_round_trip() {
+    let mut db = DeletionDB::new(3, 1).unwrap();
+    assert_eq!(db.insert(&[1, 2, 3]).unwrap(), true);
+
+    let found = db.get(&[1, 2, 4]).unwrap();
+    assert_eq!(found.length(), 1);
+    assert_eq!(Uint8Array::from(found.get(0)).to_vec(), vec![1, 2, 3]);
+    assert_eq!(db.get(&[4, 5, 6]).unwrap().length(), 0);
+}
+
+#[wasm_bindgen_test]
+fn invalid_params_throw() {
+    assert!(SubstitutionDB::new(64).is_err());
+}